@@ -705,6 +705,30 @@ def macos11_x86_64_test_jobs(python_versions: list[str]) -> Jobs:
     return jobs
 
 
+def windows_x86_64_test_jobs() -> Jobs:
+    # The engine does not build on Windows, but the platform-specific filesystem operations of the
+    # local store are in a crate of their own which does, so that their Windows implementations are
+    # compiled and tested.
+    return {
+        "test_store_platform_windows": {
+            "name": "Test the store platform layer (Windows)",
+            "runs-on": ["windows-2022"],
+            "timeout-minutes": 30,
+            "if": IS_PANTS_OWNER,
+            "steps": [
+                *checkout(),
+                {
+                    "name": "Test the store platform layer",
+                    "working-directory": "src/rust/engine",
+                    "shell": "bash",
+                    "run": "cargo test -p store_platform\n",
+                    "if": DONT_SKIP_RUST,
+                },
+            ],
+        },
+    }
+
+
 def build_wheels_job(platform: Platform, python_versions: list[str]) -> Jobs:
     helper = Helper(platform)
     # For manylinux compatibility, we build Linux wheels in a container rather than directly
@@ -794,6 +818,7 @@ def test_workflow_jobs(python_versions: list[str], *, cron: bool) -> Jobs:
     jobs.update(**linux_arm64_test_jobs(python_versions))
     jobs.update(**macos11_x86_64_test_jobs(python_versions))
     if not cron:
+        jobs.update(**windows_x86_64_test_jobs())
         jobs.update(**build_wheels_jobs())
     jobs.update(
         {
@@ -234,6 +234,28 @@ class DigestSubset:
     globs: PathGlobs
 
 
+class ArchiveFormat(Enum):
+    TAR_ZST = "tar.zst"
+    ZIP = "zip"
+
+
+@dataclass(frozen=True)
+class ArchiveDigest:
+    """A request to archive the contents of a digest into a single file.
+
+    The archive is streamed directly from the store, without the digest being materialized on disk.
+    File modes and symlinks are preserved, and the archive is deterministic for a given digest.
+
+    Example:
+
+        result = await Get(Digest, ArchiveDigest(output_digest, "dist/outputs.tar.zst"))
+    """
+
+    digest: Digest
+    output_path: str
+    format: ArchiveFormat = ArchiveFormat.TAR_ZST
+
+
 @dataclass(frozen=True)
 class DownloadFile:
     """Retrieve the contents of a file via an HTTP GET request or directly for local file:// URLs.
@@ -336,6 +358,7 @@ def rules():
         QueryRule(Digest, (NativeDownloadFile,)),
         QueryRule(Digest, (MergeDigests,)),
         QueryRule(Digest, (DigestSubset,)),
+        QueryRule(Digest, (ArchiveDigest,)),
         QueryRule(DigestContents, (Digest,)),
         QueryRule(Snapshot, (Digest,)),
         QueryRule(Paths, (PathGlobs,)),
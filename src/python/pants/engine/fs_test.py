@@ -11,6 +11,7 @@ import socket
 import ssl
 import tarfile
 import time
+import zipfile
 from dataclasses import dataclass
 from http.server import BaseHTTPRequestHandler
 from io import BytesIO
@@ -24,6 +25,8 @@ from pants.engine.fs import (
     EMPTY_DIGEST,
     EMPTY_SNAPSHOT,
     AddPrefix,
+    ArchiveDigest,
+    ArchiveFormat,
     CreateDigest,
     Digest,
     DigestContents,
@@ -57,6 +60,7 @@ from pants.util.dirutil import relative_symlink, safe_file_dump
 def rule_runner() -> RuleRunner:
     return RuleRunner(
         rules=[
+            QueryRule(Digest, [ArchiveDigest]),
             QueryRule(Digest, [CreateDigest]),
             QueryRule(DigestContents, [PathGlobs]),
             QueryRule(DigestEntries, [Digest]),
@@ -819,6 +823,40 @@ def test_digest_subset_nonexistent_filename_globs(rule_runner: RuleRunner) -> No
     #     )
 
 
+# -----------------------------------------------------------------------------------------------
+# `ArchiveDigest`
+# -----------------------------------------------------------------------------------------------
+
+
+def test_archive_digest_zip(rule_runner: RuleRunner) -> None:
+    digest = rule_runner.request(
+        Digest,
+        [
+            CreateDigest(
+                [
+                    FileContent("a.txt", b"a"),
+                    FileContent("subdir/run.sh", b"run", is_executable=True),
+                    SymlinkEntry("link", "a.txt"),
+                ]
+            )
+        ],
+    )
+    archive_digest = rule_runner.request(
+        Digest, [ArchiveDigest(digest, "out/archive.zip", ArchiveFormat.ZIP)]
+    )
+    contents = rule_runner.request(DigestContents, [archive_digest])
+    assert [fc.path for fc in contents] == ["out/archive.zip"]
+
+    with zipfile.ZipFile(BytesIO(contents[0].content)) as archive:
+        assert sorted(archive.namelist()) == ["a.txt", "link", "subdir/", "subdir/run.sh"]
+        assert archive.read("a.txt") == b"a"
+        assert archive.read("subdir/run.sh") == b"run"
+        assert (archive.getinfo("subdir/run.sh").external_attr >> 16) & 0o777 == 0o755
+        assert (archive.getinfo("a.txt").external_attr >> 16) & 0o777 == 0o644
+        # Symlinks are stored with their target as their content.
+        assert archive.read("link") == b"a.txt"
+
+
 # -----------------------------------------------------------------------------------------------
 # `Digest` -> `Snapshot`
 # -----------------------------------------------------------------------------------------------
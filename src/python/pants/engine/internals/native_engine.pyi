@@ -384,6 +384,14 @@ def rule_subgraph_visualize(
     scheduler: PyScheduler, param_types: Sequence[type], product_type: type, path: str
 ) -> None: ...
 def garbage_collect_store(scheduler: PyScheduler, target_size_bytes: int) -> None: ...
+def start_background_store_gc(
+    scheduler: PyScheduler,
+    session: PySession,
+    target_size_bytes: int,
+    interval_millis: int,
+    batch_size: int,
+) -> None: ...
+def stop_background_store_gc(scheduler: PyScheduler) -> None: ...
 def lease_files_in_graph(scheduler: PyScheduler, session: PySession) -> None: ...
 def strongly_connected_components(
     adjacency_lists: Sequence[Tuple[Any, Sequence[Any]]]
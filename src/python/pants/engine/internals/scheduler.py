@@ -16,6 +16,7 @@ from typing_extensions import TypedDict
 from pants.engine.collection import Collection
 from pants.engine.engine_aware import EngineAwareParameter, EngineAwareReturnType, SideEffecting
 from pants.engine.fs import (
+    ArchiveDigest,
     CreateDigest,
     Digest,
     DigestContents,
@@ -157,6 +158,7 @@ class Scheduler:
             path_globs=PathGlobs,
             create_digest=CreateDigest,
             digest_subset=DigestSubset,
+            archive_digest=ArchiveDigest,
             native_download_file=NativeDownloadFile,
             platform=Platform,
             process=Process,
@@ -178,18 +180,48 @@ class Scheduler:
             execution_process_cache_namespace=execution_options.process_execution_cache_namespace,
             instance_name=execution_options.remote_instance_name,
             root_ca_certs_path=execution_options.remote_ca_certs_path,
+            client_certs_path=execution_options.remote_client_certs_path,
+            client_key_path=execution_options.remote_client_key_path,
+            oauth_bearer_token_path=execution_options.remote_oauth_bearer_token_path,
+            oauth_bearer_token_command=(
+                list(execution_options.remote_oauth_bearer_token_command) or None
+            ),
             store_headers=execution_options.remote_store_headers,
             store_chunk_bytes=execution_options.remote_store_chunk_bytes,
             store_chunk_upload_timeout=execution_options.remote_store_chunk_upload_timeout_seconds,
             store_rpc_retries=execution_options.remote_store_rpc_retries,
+            store_write_rpc_retries=execution_options.remote_store_write_rpc_retries,
+            store_rpc_retry_initial_backoff_millis=execution_options.remote_store_rpc_retry_initial_backoff_millis,
+            store_rpc_retry_max_backoff_millis=execution_options.remote_store_rpc_retry_max_backoff_millis,
+            store_rpc_retryable_status_codes=execution_options.remote_store_rpc_retryable_status_codes,
             store_rpc_concurrency=execution_options.remote_store_rpc_concurrency,
             store_batch_api_size_limit=execution_options.remote_store_batch_api_size_limit,
+            store_small_blob_cache_address=execution_options.remote_store_small_blob_cache_address,
+            store_small_blob_cache_max_size_bytes=execution_options.remote_store_small_blob_cache_max_size_bytes,
+            store_lan_cache=execution_options.remote_store_lan_cache,
+            store_lan_cache_address=execution_options.remote_store_lan_cache_address,
+            store_lan_cache_port=execution_options.remote_store_lan_cache_port,
+            store_lan_cache_secret=execution_options.remote_store_lan_cache_secret,
+            store_read_concurrency_limit=execution_options.remote_store_read_concurrency_limit,
+            store_write_concurrency_limit=execution_options.remote_store_write_concurrency_limit,
+            store_bandwidth_limit=execution_options.remote_store_bandwidth_limit,
+            store_upload_files=execution_options.remote_store_upload_files,
+            store_upload_directories=execution_options.remote_store_upload_directories,
+            store_max_file_upload_size=execution_options.remote_store_max_file_upload_size,
+            store_presence_cache_ttl_secs=execution_options.remote_store_presence_cache_ttl_secs,
             cache_warnings_behavior=execution_options.remote_cache_warnings.value,
             cache_content_behavior=execution_options.cache_content_behavior.value,
             cache_rpc_concurrency=execution_options.remote_cache_rpc_concurrency,
             cache_read_timeout_millis=execution_options.remote_cache_read_timeout_millis,
+            cache_validate_action_results=execution_options.remote_cache_validate_action_results,
+            cache_circuit_breaker_threshold=execution_options.remote_cache_circuit_breaker_threshold,
+            cache_circuit_breaker_probe_interval_secs=execution_options.remote_cache_circuit_breaker_probe_interval_secs,
             execution_headers=execution_options.remote_execution_headers,
             execution_overall_deadline_secs=execution_options.remote_execution_overall_deadline_secs,
+            execution_rpc_retries=execution_options.remote_execution_rpc_retries,
+            execution_rpc_retry_initial_backoff_millis=execution_options.remote_execution_rpc_retry_initial_backoff_millis,
+            execution_rpc_retry_max_backoff_millis=execution_options.remote_execution_rpc_retry_max_backoff_millis,
+            execution_rpc_retryable_status_codes=execution_options.remote_execution_rpc_retryable_status_codes,
             execution_rpc_concurrency=execution_options.remote_execution_rpc_concurrency,
             append_only_caches_base_path=execution_options.remote_execution_append_only_caches_base_path,
         )
@@ -200,6 +232,8 @@ class Scheduler:
             directories_max_size_bytes=local_store_options.directories_max_size_bytes,
             lease_time_millis=LOCAL_STORE_LEASE_TIME_SECS * 1000,
             shard_count=local_store_options.shard_count,
+            large_file_size_limit=local_store_options.large_file_size_limit,
+            base_layer_dirs=local_store_options.base_layer_dirs,
         )
         exec_stategy_opts = PyExecutionStrategyOptions(
             local_cache=execution_options.local_cache,
@@ -212,6 +246,11 @@ class Scheduler:
             child_max_memory=execution_options.process_total_child_memory_usage or 0,
             child_default_memory=execution_options.process_per_child_memory_usage,
             graceful_shutdown_timeout=execution_options.process_execution_graceful_shutdown_timeout,
+            local_resource_limits=execution_options.process_execution_local_resource_limits,
+            local_total_memory_limit=execution_options.process_execution_local_total_memory_limit,
+            local_total_cpu_limit=execution_options.process_execution_local_total_cpu_limit,
+            local_total_pids_limit=execution_options.process_execution_local_total_pids_limit,
+            local_stream_output=execution_options.process_execution_local_stream_output,
         )
 
         self._py_executor = executor
@@ -621,6 +660,21 @@ class SchedulerSession:
     def garbage_collect_store(self, target_size_bytes: int) -> None:
         self._scheduler.garbage_collect_store(target_size_bytes)
 
+    def start_background_store_gc(
+        self, target_size_bytes: int, interval_secs: float, batch_size: int = 1000
+    ) -> None:
+        """Start garbage collecting the store in the background, reporting to this session."""
+        native_engine.start_background_store_gc(
+            self.py_scheduler,
+            self.py_session,
+            target_size_bytes,
+            int(interval_secs * 1000),
+            batch_size,
+        )
+
+    def stop_background_store_gc(self) -> None:
+        native_engine.stop_background_store_gc(self.py_scheduler)
+
     def get_metrics(self) -> dict[str, int]:
         return native_engine.session_get_metrics(self.py_session)
 
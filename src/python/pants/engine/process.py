@@ -62,6 +62,9 @@ class Process:
     jdk_home: str | None
     execution_slot_variable: str | None
     concurrency_available: int
+    memory_limit: int | None
+    cpu_limit: float | None
+    pids_limit: int | None
     cache_scope: ProcessCacheScope
     remote_cache_speculation_delay_millis: int
 
@@ -83,6 +86,9 @@ class Process:
         jdk_home: str | None = None,
         execution_slot_variable: str | None = None,
         concurrency_available: int = 0,
+        memory_limit: int | None = None,
+        cpu_limit: float | None = None,
+        pids_limit: int | None = None,
         cache_scope: ProcessCacheScope = ProcessCacheScope.SUCCESSFUL,
         remote_cache_speculation_delay_millis: int = 0,
     ) -> None:
@@ -111,6 +117,13 @@ class Process:
                 ProcessResult, Process(["/bin/echo", "hello world"], description="demo")
             )
             assert result.stdout == b"hello world"
+
+        The resources that the process (including its descendants) may use can be limited with
+        `memory_limit` (in bytes), `cpu_limit` (in CPUs, which may be fractional) and `pids_limit`
+        (the number of processes and threads). Limits are only applied to processes which run
+        locally, and only when `[GLOBAL].process_execution_local_resource_limits` is enabled: a
+        process which exceeds its memory limit is killed, and its `ProcessResultMetadata` will say
+        so.
         """
         if isinstance(argv, str):
             raise ValueError("argv must be a sequence of strings, but was a single string.")
@@ -137,6 +150,9 @@ class Process:
         object.__setattr__(self, "jdk_home", jdk_home)
         object.__setattr__(self, "execution_slot_variable", execution_slot_variable)
         object.__setattr__(self, "concurrency_available", concurrency_available)
+        object.__setattr__(self, "memory_limit", memory_limit)
+        object.__setattr__(self, "cpu_limit", cpu_limit)
+        object.__setattr__(self, "pids_limit", pids_limit)
         object.__setattr__(self, "cache_scope", cache_scope)
         object.__setattr__(
             self, "remote_cache_speculation_delay_millis", remote_cache_speculation_delay_millis
@@ -203,6 +219,9 @@ class ProcessResultMetadata:
     _source: str
     # The run_id in which a ProcessResult was created. See the `self.source` method.
     source_run_id: int
+    # A description of the memory limit which the process exceeded, if it was killed for exceeding
+    # one.
+    memory_limit_exceeded: str | None = None
 
     @property
     def platform(self) -> Platform:
@@ -236,6 +255,7 @@ class ProcessExecutionFailure(Exception):
         process_description: str,
         *,
         keep_sandboxes: KeepSandboxes,
+        memory_limit_exceeded: str | None = None,
     ) -> None:
         # These are intentionally "public" members.
         self.exit_code = exit_code
@@ -258,6 +278,10 @@ class ProcessExecutionFailure(Exception):
             "stderr:",
             try_decode(stderr),
         ]
+        if memory_limit_exceeded:
+            err_strings.append(
+                f"\nThe process was killed because it exceeded the {memory_limit_exceeded}."
+            )
         if keep_sandboxes == KeepSandboxes.never:
             err_strings.append(
                 "\n\nUse `--keep-sandboxes=on_failure` to preserve the process chroot for inspection."
@@ -293,6 +317,7 @@ def fallible_to_exec_result_or_raise(
         fallible_result.stderr,
         description.value,
         keep_sandboxes=keep_sandboxes,
+        memory_limit_exceeded=fallible_result.metadata.memory_limit_exceeded,
     )
 
 
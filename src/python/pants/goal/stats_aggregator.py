@@ -7,6 +7,7 @@ import base64
 import logging
 from collections import Counter
 from dataclasses import dataclass
+from typing import Mapping
 
 from pants.engine.internals.scheduler import Workunit
 from pants.engine.rules import collect_rules, rule
@@ -35,7 +36,8 @@ class StatsAggregatorSubsystem(Subsystem):
             """
             At the end of the Pants run, log all counter metrics and summaries of
             observation histograms, e.g. the number of cache hits and the time saved by
-            caching.
+            caching. If a remote cache or store was used, also log a summary of its hit rates
+            and of the bytes transferred.
 
             For histogram summaries to work, you must add `hdrhistogram` to `[GLOBAL].plugins`.
             """
@@ -57,6 +59,39 @@ class StatsAggregatorSubsystem(Subsystem):
     )
 
 
+def _rate(hits: int, misses: int, noun: str) -> str:
+    total = hits + misses
+    if not total:
+        return f"n/a (0 {noun})"
+    return f"{100 * hits / total:.1f}% ({hits} of {total} {noun})"
+
+
+def remote_cache_summary(counters: Mapping[str, int]) -> str | None:
+    """Summarize what the remote cache and store did for this run, or None if they were unused."""
+    cached = counters.get("remote_cache_requests_cached", 0)
+    uncached = counters.get("remote_cache_requests_uncached", 0)
+    read_hits = counters.get("remote_store_read_blob_hits", 0)
+    read_misses = counters.get("remote_store_read_blob_misses", 0)
+    bytes_read = counters.get("remote_store_bytes_read", 0)
+    bytes_written = counters.get("remote_store_bytes_written", 0)
+    present = counters.get("remote_store_digests_present", 0)
+    missing = counters.get("remote_store_digests_missing", 0)
+    if not any((cached, uncached, read_hits, read_misses, bytes_written, present, missing)):
+        return None
+
+    time_saved_secs = counters.get("remote_cache_total_time_saved_ms", 0) / 1000
+    return "\n".join(
+        (
+            f"  cache hit rate: {_rate(cached, uncached, 'requests')}",
+            f"  blob read hit rate: {_rate(read_hits, read_misses, 'blobs')}",
+            f"  bytes downloaded: {bytes_read}",
+            f"  bytes uploaded: {bytes_written}",
+            f"  digests already present: {_rate(present, missing, 'digests')}",
+            f"  time saved: {time_saved_secs:.3f}s",
+        )
+    )
+
+
 class StatsAggregatorCallback(WorkunitsCallback):
     def __init__(self, *, log: bool, memory: bool, has_histogram_module: bool) -> None:
         super().__init__()
@@ -95,6 +130,10 @@ class StatsAggregatorCallback(WorkunitsCallback):
             )
             logger.info(f"Counters:\n{counter_lines}")
 
+            remote_summary = remote_cache_summary(counters)
+            if remote_summary:
+                logger.info(f"Remote cache summary:\n{remote_summary}")
+
         if self.memory:
             ids: set[int] = set()
             count_by_type: Counter[type] = Counter()
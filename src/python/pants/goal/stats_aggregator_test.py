@@ -0,0 +1,36 @@
+# Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import annotations
+
+from pants.goal.stats_aggregator import remote_cache_summary
+
+
+def test_remote_cache_summary_unused() -> None:
+    assert remote_cache_summary({}) is None
+    assert remote_cache_summary({"local_cache_requests_cached": 3}) is None
+
+
+def test_remote_cache_summary() -> None:
+    summary = remote_cache_summary(
+        {
+            "remote_cache_requests_cached": 3,
+            "remote_cache_requests_uncached": 1,
+            "remote_cache_total_time_saved_ms": 1500,
+            "remote_store_read_blob_hits": 9,
+            "remote_store_read_blob_misses": 1,
+            "remote_store_bytes_read": 1024,
+            "remote_store_bytes_written": 2048,
+            "remote_store_digests_missing": 2,
+        }
+    )
+    assert summary == "\n".join(
+        (
+            "  cache hit rate: 75.0% (3 of 4 requests)",
+            "  blob read hit rate: 90.0% (9 of 10 blobs)",
+            "  bytes downloaded: 1024",
+            "  bytes uploaded: 2048",
+            "  digests already present: 0.0% (0 of 2 digests)",
+            "  time saved: 1.500s",
+        )
+    )
@@ -37,6 +37,7 @@ from pants.option.option_types import (
     FloatOption,
     IntOption,
     MemorySizeOption,
+    ShellStrListOption,
     StrListOption,
     StrOption,
     collect_options_info,
@@ -339,6 +340,18 @@ class DynamicRemoteOptions:
                     """
                 )
             )
+        if bootstrap_options.remote_oauth_bearer_token_command and (
+            remote_auth_plugin_func or bootstrap_options.remote_oauth_bearer_token_path
+        ):
+            raise OptionsError(
+                softwrap(
+                    """
+                    `[GLOBAL].remote_oauth_bearer_token_command` may not be used with
+                    `[GLOBAL].remote_oauth_bearer_token_path` or `[GLOBAL].remote_auth_plugin`.
+                    Only one of those should be set in order to provide auth information.
+                    """
+                )
+            )
         if bootstrap_options.remote_oauth_bearer_token_path:
             return cls._use_oauth_token(bootstrap_options), None
         if remote_auth_plugin_func is not None:
@@ -485,6 +498,10 @@ class ExecutionOptions:
 
     remote_instance_name: str | None
     remote_ca_certs_path: str | None
+    remote_client_certs_path: str | None
+    remote_client_key_path: str | None
+    remote_oauth_bearer_token_path: str | None
+    remote_oauth_bearer_token_command: tuple[str, ...]
 
     keep_sandboxes: KeepSandboxes
     local_cache: bool
@@ -493,6 +510,11 @@ class ExecutionOptions:
     process_execution_remote_parallelism: int
     process_execution_cache_namespace: str | None
     process_execution_graceful_shutdown_timeout: int
+    process_execution_local_resource_limits: bool
+    process_execution_local_total_memory_limit: int | None
+    process_execution_local_total_cpu_limit: float | None
+    process_execution_local_total_pids_limit: int | None
+    process_execution_local_stream_output: bool
     cache_content_behavior: CacheContentBehavior
 
     process_total_child_memory_usage: int | None
@@ -503,16 +525,40 @@ class ExecutionOptions:
     remote_store_chunk_bytes: Any
     remote_store_chunk_upload_timeout_seconds: int
     remote_store_rpc_retries: int
+    remote_store_write_rpc_retries: int | None
+    remote_store_rpc_retry_initial_backoff_millis: int
+    remote_store_rpc_retry_max_backoff_millis: int
+    remote_store_rpc_retryable_status_codes: tuple[str, ...]
     remote_store_rpc_concurrency: int
     remote_store_batch_api_size_limit: int
+    remote_store_small_blob_cache_address: str | None
+    remote_store_small_blob_cache_max_size_bytes: int
+    remote_store_lan_cache: bool
+    remote_store_lan_cache_address: str | None
+    remote_store_lan_cache_port: int
+    remote_store_lan_cache_secret: str | None
+    remote_store_read_concurrency_limit: int | None
+    remote_store_write_concurrency_limit: int | None
+    remote_store_bandwidth_limit: int | None
+    remote_store_upload_files: bool
+    remote_store_upload_directories: bool
+    remote_store_max_file_upload_size: int | None
+    remote_store_presence_cache_ttl_secs: int | None
 
     remote_cache_warnings: RemoteCacheWarningsBehavior
     remote_cache_rpc_concurrency: int
     remote_cache_read_timeout_millis: int
+    remote_cache_validate_action_results: bool
+    remote_cache_circuit_breaker_threshold: int | None
+    remote_cache_circuit_breaker_probe_interval_secs: int
 
     remote_execution_address: str | None
     remote_execution_headers: dict[str, str]
     remote_execution_overall_deadline_secs: int
+    remote_execution_rpc_retries: int
+    remote_execution_rpc_retry_initial_backoff_millis: int
+    remote_execution_rpc_retry_max_backoff_millis: int
+    remote_execution_rpc_retryable_status_codes: tuple[str, ...]
     remote_execution_rpc_concurrency: int
 
     remote_execution_append_only_caches_base_path: str | None
@@ -531,6 +577,12 @@ class ExecutionOptions:
             # General remote setup.
             remote_instance_name=dynamic_remote_options.instance_name,
             remote_ca_certs_path=bootstrap_options.remote_ca_certs_path,
+            remote_client_certs_path=bootstrap_options.remote_client_certs_path,
+            remote_client_key_path=bootstrap_options.remote_client_key_path,
+            remote_oauth_bearer_token_path=bootstrap_options.remote_oauth_bearer_token_path,
+            remote_oauth_bearer_token_command=tuple(
+                bootstrap_options.remote_oauth_bearer_token_command
+            ),
             # Process execution setup.
             keep_sandboxes=GlobalOptions.resolve_keep_sandboxes(bootstrap_options),
             local_cache=bootstrap_options.local_cache,
@@ -539,6 +591,11 @@ class ExecutionOptions:
             process_execution_cache_namespace=bootstrap_options.process_execution_cache_namespace,
             process_execution_graceful_shutdown_timeout=bootstrap_options.process_execution_graceful_shutdown_timeout,
             process_execution_local_enable_nailgun=bootstrap_options.process_execution_local_enable_nailgun,
+            process_execution_local_resource_limits=bootstrap_options.process_execution_local_resource_limits,
+            process_execution_local_total_memory_limit=bootstrap_options.process_execution_local_total_memory_limit,
+            process_execution_local_total_cpu_limit=bootstrap_options.process_execution_local_total_cpu_limit,
+            process_execution_local_total_pids_limit=bootstrap_options.process_execution_local_total_pids_limit,
+            process_execution_local_stream_output=bootstrap_options.process_execution_local_stream_output,
             cache_content_behavior=bootstrap_options.cache_content_behavior,
             process_total_child_memory_usage=bootstrap_options.process_total_child_memory_usage,
             process_per_child_memory_usage=bootstrap_options.process_per_child_memory_usage,
@@ -548,16 +605,44 @@ class ExecutionOptions:
             remote_store_chunk_bytes=bootstrap_options.remote_store_chunk_bytes,
             remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
             remote_store_rpc_retries=bootstrap_options.remote_store_rpc_retries,
+            remote_store_write_rpc_retries=bootstrap_options.remote_store_write_rpc_retries,
+            remote_store_rpc_retry_initial_backoff_millis=bootstrap_options.remote_store_rpc_retry_initial_backoff_millis,
+            remote_store_rpc_retry_max_backoff_millis=bootstrap_options.remote_store_rpc_retry_max_backoff_millis,
+            remote_store_rpc_retryable_status_codes=tuple(
+                bootstrap_options.remote_store_rpc_retryable_status_codes
+            ),
             remote_store_rpc_concurrency=dynamic_remote_options.store_rpc_concurrency,
             remote_store_batch_api_size_limit=bootstrap_options.remote_store_batch_api_size_limit,
+            remote_store_small_blob_cache_address=bootstrap_options.remote_store_small_blob_cache_address,
+            remote_store_small_blob_cache_max_size_bytes=bootstrap_options.remote_store_small_blob_cache_max_size_bytes,
+            remote_store_lan_cache=bootstrap_options.remote_store_lan_cache,
+            remote_store_lan_cache_address=bootstrap_options.remote_store_lan_cache_address,
+            remote_store_lan_cache_port=bootstrap_options.remote_store_lan_cache_port,
+            remote_store_lan_cache_secret=bootstrap_options.remote_store_lan_cache_secret,
+            remote_store_read_concurrency_limit=bootstrap_options.remote_store_read_concurrency_limit,
+            remote_store_write_concurrency_limit=bootstrap_options.remote_store_write_concurrency_limit,
+            remote_store_bandwidth_limit=bootstrap_options.remote_store_bandwidth_limit,
+            remote_store_upload_files=bootstrap_options.remote_store_upload_files,
+            remote_store_upload_directories=bootstrap_options.remote_store_upload_directories,
+            remote_store_max_file_upload_size=bootstrap_options.remote_store_max_file_upload_size,
+            remote_store_presence_cache_ttl_secs=bootstrap_options.remote_store_presence_cache_ttl_secs,
             # Remote cache setup.
             remote_cache_warnings=bootstrap_options.remote_cache_warnings,
             remote_cache_rpc_concurrency=dynamic_remote_options.cache_rpc_concurrency,
             remote_cache_read_timeout_millis=bootstrap_options.remote_cache_read_timeout_millis,
+            remote_cache_validate_action_results=bootstrap_options.remote_cache_validate_action_results,
+            remote_cache_circuit_breaker_threshold=bootstrap_options.remote_cache_circuit_breaker_threshold,
+            remote_cache_circuit_breaker_probe_interval_secs=bootstrap_options.remote_cache_circuit_breaker_probe_interval_secs,
             # Remote execution setup.
             remote_execution_address=dynamic_remote_options.execution_address,
             remote_execution_headers=dynamic_remote_options.execution_headers,
             remote_execution_overall_deadline_secs=bootstrap_options.remote_execution_overall_deadline_secs,
+            remote_execution_rpc_retries=bootstrap_options.remote_execution_rpc_retries,
+            remote_execution_rpc_retry_initial_backoff_millis=bootstrap_options.remote_execution_rpc_retry_initial_backoff_millis,
+            remote_execution_rpc_retry_max_backoff_millis=bootstrap_options.remote_execution_rpc_retry_max_backoff_millis,
+            remote_execution_rpc_retryable_status_codes=tuple(
+                bootstrap_options.remote_execution_rpc_retryable_status_codes
+            ),
             remote_execution_rpc_concurrency=dynamic_remote_options.execution_rpc_concurrency,
             remote_execution_append_only_caches_base_path=bootstrap_options.remote_execution_append_only_caches_base_path,
         )
@@ -576,6 +661,8 @@ class LocalStoreOptions:
     files_max_size_bytes: int = 256 * GIGABYTES
     directories_max_size_bytes: int = 16 * GIGABYTES
     shard_count: int = 16
+    large_file_size_limit: int = 512 * 1024
+    base_layer_dirs: tuple[str, ...] = ()
 
     def target_total_size_bytes(self) -> int:
         """Returns the target total size of all of the stores.
@@ -601,6 +688,11 @@ class LocalStoreOptions:
             files_max_size_bytes=options.local_store_files_max_size_bytes,
             directories_max_size_bytes=options.local_store_directories_max_size_bytes,
             shard_count=options.local_store_shard_count,
+            large_file_size_limit=options.local_store_large_file_size_limit,
+            base_layer_dirs=tuple(
+                str(Path(base_layer_dir).resolve())
+                for base_layer_dir in options.local_store_base_layer_dirs
+            ),
         )
 
 
@@ -615,6 +707,10 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     # General remote setup.
     remote_instance_name=None,
     remote_ca_certs_path=None,
+    remote_client_certs_path=None,
+    remote_client_key_path=None,
+    remote_oauth_bearer_token_path=None,
+    remote_oauth_bearer_token_command=(),
     # Process execution setup.
     process_total_child_memory_usage=None,
     process_per_child_memory_usage=memory_size(_PER_CHILD_MEMORY_USAGE),
@@ -626,6 +722,11 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     cache_content_behavior=CacheContentBehavior.fetch,
     process_execution_local_enable_nailgun=True,
     process_execution_graceful_shutdown_timeout=3,
+    process_execution_local_resource_limits=False,
+    process_execution_local_total_memory_limit=None,
+    process_execution_local_total_cpu_limit=None,
+    process_execution_local_total_pids_limit=None,
+    process_execution_local_stream_output=False,
     # Remote store setup.
     remote_store_address=None,
     remote_store_headers={
@@ -634,18 +735,55 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_store_chunk_bytes=1024 * 1024,
     remote_store_chunk_upload_timeout_seconds=60,
     remote_store_rpc_retries=2,
+    remote_store_write_rpc_retries=None,
+    remote_store_rpc_retry_initial_backoff_millis=20,
+    remote_store_rpc_retry_max_backoff_millis=5000,
+    remote_store_rpc_retryable_status_codes=(
+        "ABORTED",
+        "CANCELLED",
+        "INTERNAL",
+        "RESOURCE_EXHAUSTED",
+        "UNAVAILABLE",
+        "UNKNOWN",
+    ),
     remote_store_rpc_concurrency=128,
     remote_store_batch_api_size_limit=4194304,
+    remote_store_small_blob_cache_address=None,
+    remote_store_small_blob_cache_max_size_bytes=64 * 1024,
+    remote_store_lan_cache=False,
+    remote_store_lan_cache_address=None,
+    remote_store_lan_cache_port=0,
+    remote_store_lan_cache_secret=None,
+    remote_store_read_concurrency_limit=None,
+    remote_store_write_concurrency_limit=None,
+    remote_store_bandwidth_limit=None,
+    remote_store_upload_files=True,
+    remote_store_upload_directories=True,
+    remote_store_max_file_upload_size=None,
+    remote_store_presence_cache_ttl_secs=None,
     # Remote cache setup.
     remote_cache_warnings=RemoteCacheWarningsBehavior.backoff,
     remote_cache_rpc_concurrency=128,
     remote_cache_read_timeout_millis=1500,
+    remote_cache_validate_action_results=False,
+    remote_cache_circuit_breaker_threshold=None,
+    remote_cache_circuit_breaker_probe_interval_secs=30,
     # Remote execution setup.
     remote_execution_address=None,
     remote_execution_headers={
         "user-agent": f"pants/{VERSION}",
     },
     remote_execution_overall_deadline_secs=60 * 60,  # one hour
+    remote_execution_rpc_retries=5,
+    remote_execution_rpc_retry_initial_backoff_millis=100,
+    remote_execution_rpc_retry_max_backoff_millis=10000,
+    remote_execution_rpc_retryable_status_codes=(
+        "ABORTED",
+        "INTERNAL",
+        "RESOURCE_EXHAUSTED",
+        "UNAVAILABLE",
+        "UNKNOWN",
+    ),
     remote_execution_rpc_concurrency=128,
     remote_execution_append_only_caches_base_path=None,
 )
@@ -1129,6 +1267,32 @@ class BootstrapOptions:
         ),
         default=DEFAULT_LOCAL_STORE_OPTIONS.directories_max_size_bytes,
     )
+    local_store_large_file_size_limit = IntOption(
+        advanced=True,
+        help=softwrap(
+            """
+            The size in bytes at or above which a file in the local store is stored as a file on
+            disk below `--local-store-dir`, rather than in LMDB.
+
+            When this value changes, files which were stored under the previous value are left
+            where they are, and remain readable: they are looked up in both locations.
+            """
+        ),
+        default=DEFAULT_LOCAL_STORE_OPTIONS.large_file_size_limit,
+    )
+    local_store_base_layer_dirs = StrListOption(
+        advanced=True,
+        help=softwrap(
+            """
+            Directories containing prewarmed local stores (such as a store shared on a network
+            filesystem) to consult, in order, for reads which miss in `--local-store-dir`.
+
+            These stores are opened read-only: content is only ever written to
+            `--local-store-dir`, and garbage collection never removes content from them.
+            """
+        ),
+        default=list(DEFAULT_LOCAL_STORE_OPTIONS.base_layer_dirs),
+    )
     _named_caches_dir = StrOption(
         advanced=True,
         help=softwrap(
@@ -1316,6 +1480,77 @@ class BootstrapOptions:
         ),
         advanced=True,
     )
+    process_execution_local_resource_limits = BoolOption(
+        default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_resource_limits,
+        advanced=True,
+        help=softwrap(
+            f"""
+            Whether to apply resource limits to processes which run locally, using cgroups (v2),
+            which are only available on Linux.
+
+            Each process is limited to the memory, CPUs, and number of processes which it
+            requested (if any), and all processes together are limited by the
+            `--process-execution-local-total-*-limit` options. A process which exceeds its
+            memory limit is killed, and the limit which it exceeded is recorded in the
+            `memory_limit_exceeded` field of its `ProcessResultMetadata` (and reported if the
+            process failed).
+
+            The cgroup of `{bin_name()}` must have been delegated to the current user: by running
+            it via `systemd-run --user --scope -p Delegate=yes`, for example. If it has not been,
+            a warning is logged and limits are not applied.
+            """
+        ),
+    )
+    process_execution_local_total_memory_limit = MemorySizeOption(
+        default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_total_memory_limit,
+        advanced=True,
+        help=softwrap(
+            """
+            The maximum memory usage of all processes which run locally, when
+            `--process-execution-local-resource-limits` is enabled.
+
+            You can suffix with `GiB`, `MiB`, `KiB`, or `B` to indicate the unit, e.g.
+            `2GiB` or `2.12GiB`. A bare number will be in bytes.
+            """
+        ),
+    )
+    process_execution_local_total_cpu_limit = FloatOption(
+        default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_total_cpu_limit,
+        advanced=True,
+        help=softwrap(
+            """
+            The maximum number of CPUs (which may be fractional) used by all processes which run
+            locally, when `--process-execution-local-resource-limits` is enabled.
+            """
+        ),
+    )
+    process_execution_local_total_pids_limit = IntOption(
+        default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_total_pids_limit,
+        advanced=True,
+        help=softwrap(
+            """
+            The maximum number of processes (and threads) of all processes which run locally, when
+            `--process-execution-local-resource-limits` is enabled.
+            """
+        ),
+    )
+    process_execution_local_stream_output = BoolOption(
+        default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_stream_output,
+        advanced=True,
+        help=softwrap(
+            """
+            Whether to log the stdout and stderr of processes which run locally as it is written,
+            line by line, rather than only making it available once they have completed.
+
+            This is useful to follow the progress of long-running processes (such as tests). Each
+            line is recorded as a workunit which is a child of the workunit of the process (and so
+            is received by streaming workunit handlers), and is logged at the level of the process.
+            The lines of concurrent processes are interleaved, and prefixed with the description of
+            the process which wrote them. The complete output of each process is still captured
+            in its result.
+            """
+        ),
+    )
     session_end_tasks_timeout = FloatOption(
         default=3.0,
         help=softwrap(
@@ -1385,6 +1620,32 @@ class BootstrapOptions:
             """
         ),
     )
+    remote_client_certs_path = StrOption(
+        default=None,
+        advanced=True,
+        help=softwrap(
+            """
+            Path to a PEM file containing the certificate chain which Pants presents to
+            `[GLOBAL].remote_execution_address` and `[GLOBAL].remote_store_address` for mutual
+            TLS (mTLS) authentication. Requires `[GLOBAL].remote_client_key_path`.
+
+            The file is re-read whenever it changes, so short-lived certificates (such as
+            SPIFFE SVIDs) may be rotated in place without restarting `pantsd`.
+            """
+        ),
+    )
+    remote_client_key_path = StrOption(
+        default=None,
+        advanced=True,
+        help=softwrap(
+            """
+            Path to a PEM file containing the private key for the certificate in
+            `[GLOBAL].remote_client_certs_path`.
+
+            Like the certificate chain, the key is re-read whenever it changes.
+            """
+        ),
+    )
     remote_oauth_bearer_token_path = StrOption(
         default=None,
         advanced=True,
@@ -1397,6 +1658,23 @@ class BootstrapOptions:
             You can also manually add this header via `[GLOBAL].remote_execution_headers` and
             `[GLOBAL].remote_store_headers`, or use `[GLOBAL].remote_auth_plugin` to provide a plugin to
             dynamically set the relevant headers. Otherwise, no authorization will be performed.
+
+            If a server rejects the token as unauthenticated, the file is read again, and if the
+            token has changed, the request is retried with the new token. This allows the token
+            to be refreshed by an external process without restarting Pants.
+            """
+        ),
+    )
+    remote_oauth_bearer_token_command = ShellStrListOption(
+        advanced=True,
+        help=softwrap(
+            """
+            A command (and its arguments) which prints an oauth token to stdout, to use for gRPC
+            connections to `[GLOBAL].remote_execution_address` and
+            `[GLOBAL].remote_store_address`, like `[GLOBAL].remote_oauth_bearer_token_path`.
+
+            The command is run at startup, and again whenever a server rejects the token as
+            unauthenticated, in which case the request is retried with the new token.
             """
         ),
     )
@@ -1409,6 +1687,12 @@ class BootstrapOptions:
 
             Format: `scheme://host:port`. The supported schemes are `grpc` and `grpcs`, i.e. gRPC
             with TLS enabled. If `grpc` is used, TLS will be disabled.
+
+            Alternatively, a directory which is shared between machines (via NFS or SMB, for
+            example) may be used as the remote file store, without any server, by setting an
+            absolute path with the `file` scheme: `file:///path/to/cache`. Files in the directory
+            which have not been used recently (according to their modification time) may be deleted
+            at any time to bound its size.
             """
         ),
     )
@@ -1443,6 +1727,42 @@ class BootstrapOptions:
         default=DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_retries,
         help="Number of times to retry any RPC to the remote store before giving up.",
     )
+    remote_store_write_rpc_retries = IntOption(
+        advanced=True,
+        default=None,
+        help=softwrap(
+            """
+            Number of times to retry uploads to the remote store (ByteStream `Write` and
+            `BatchUpdateBlobs` RPCs) before giving up. Defaults to the value of
+            `[GLOBAL].remote_store_rpc_retries`.
+            """
+        ),
+    )
+    remote_store_rpc_retry_initial_backoff_millis = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_retry_initial_backoff_millis,
+        help=softwrap(
+            """
+            The base delay (in milliseconds) before retrying an RPC to the remote store. Before the
+            nth retry, a random delay of up to this value times 2^n is taken.
+            """
+        ),
+    )
+    remote_store_rpc_retry_max_backoff_millis = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_retry_max_backoff_millis,
+        help="The maximum delay (in milliseconds) before retrying an RPC to the remote store.",
+    )
+    remote_store_rpc_retryable_status_codes = StrListOption(
+        advanced=True,
+        default=list(DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_retryable_status_codes),
+        help=softwrap(
+            """
+            The gRPC status codes (e.g. `UNAVAILABLE`) with which a failed RPC to the remote store
+            is retried. Failures with other codes are not retried.
+            """
+        ),
+    )
     remote_store_rpc_concurrency = IntOption(
         advanced=True,
         default=DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_concurrency,
@@ -1453,6 +1773,181 @@ class BootstrapOptions:
         default=DEFAULT_EXECUTION_OPTIONS.remote_store_batch_api_size_limit,
         help="The maximum total size of blobs allowed to be sent in a single batch API call to the remote store.",
     )
+    remote_store_small_blob_cache_address = StrOption(
+        advanced=True,
+        default=cast(str, DEFAULT_EXECUTION_OPTIONS.remote_store_small_blob_cache_address),
+        help=softwrap(
+            """
+            The address of a Redis server to cache small blobs (such as directories and action
+            results) in front of `[GLOBAL].remote_store_address`, in order to avoid the latency of
+            fetching them from the remote store.
+
+            Format: `redis://[[username]:password@]host[:port][/database]`.
+
+            Blobs are written through to the cache, so the server may evict them freely: it should
+            be configured with an eviction policy such as `allkeys-lru`. Reads from the cache are
+            subject to `[GLOBAL].remote_cache_read_timeout_millis`.
+            """
+        ),
+    )
+    remote_store_small_blob_cache_max_size_bytes = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_small_blob_cache_max_size_bytes,
+        help=softwrap(
+            """
+            The maximum size of blobs to cache in `[GLOBAL].remote_store_small_blob_cache_address`.
+            """
+        ),
+    )
+    remote_store_lan_cache = BoolOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_lan_cache,
+        help=softwrap(
+            """
+            Whether to share the local store with other Pants processes on the same LAN, and to
+            read blobs from theirs before falling back to `[GLOBAL].remote_store_address`.
+
+            Peers are discovered via multicast DNS on the interface given by
+            `[GLOBAL].remote_store_lan_cache_address`, and blobs read from them are verified
+            against their digests. Reads from peers are subject to
+            `[GLOBAL].remote_cache_read_timeout_millis`.
+
+            Peers only serve reads which are signed with `[GLOBAL].remote_store_lan_cache_secret`,
+            which must be set when this option is enabled. Blobs are sent unencrypted, so only
+            enable this on trusted networks.
+            """
+        ),
+    )
+    remote_store_lan_cache_address = StrOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_lan_cache_address,
+        help=softwrap(
+            """
+            The IPv4 address of the interface on which to serve the local store to peers, and to
+            discover them, when `[GLOBAL].remote_store_lan_cache` is enabled. Required when it is
+            enabled.
+            """
+        ),
+    )
+    remote_store_lan_cache_port = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_lan_cache_port,
+        help=softwrap(
+            """
+            The port on which to serve the local store to peers when `[GLOBAL].remote_store_lan_cache`
+            is enabled, or 0 to use an ephemeral port.
+            """
+        ),
+    )
+    remote_store_lan_cache_secret = StrOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_lan_cache_secret,
+        help=softwrap(
+            """
+            The secret shared by the peers of `[GLOBAL].remote_store_lan_cache`, which is used to
+            sign reads from them (and is never itself sent). Required when the LAN cache is
+            enabled.
+
+            To keep the secret out of checked-in config, set it via an environment variable, or
+            read it from a file with the `@path/to/secret` syntax.
+            """
+        ),
+    )
+    remote_store_read_concurrency_limit = IntOption(
+        advanced=True,
+        default=None,
+        help=softwrap(
+            """
+            The maximum number of concurrent ByteStream reads (i.e. downloads of blobs too large to
+            batch) from the remote store. Unlimited by default, other than by
+            `[GLOBAL].remote_store_rpc_concurrency`.
+            """
+        ),
+    )
+    remote_store_write_concurrency_limit = IntOption(
+        advanced=True,
+        default=None,
+        help=softwrap(
+            """
+            The maximum number of concurrent ByteStream writes (i.e. uploads of blobs too large to
+            batch) to the remote store. Unlimited by default, other than by
+            `[GLOBAL].remote_store_rpc_concurrency`.
+            """
+        ),
+    )
+    remote_store_bandwidth_limit = IntOption(
+        advanced=True,
+        default=None,
+        help=softwrap(
+            """
+            The maximum aggregate rate, in bytes per second, at which blobs are uploaded to and
+            downloaded from the remote store. Unlimited by default.
+
+            This is useful to avoid saturating a slow or shared network connection, for example
+            when populating a cold cache over a VPN.
+            """
+        ),
+    )
+    remote_store_upload_files = BoolOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_upload_files,
+        help=softwrap(
+            """
+            Whether to upload file blobs (the content of process inputs and outputs) to the remote
+            store. Files which are not uploaded are still downloaded from the remote store when
+            needed.
+
+            Together with `[GLOBAL].remote_store_upload_directories`, this makes it possible to
+            upload only cache entries and directories, or (by disabling both) to use the remote
+            store in a download-only mode. Note that cache entries which refer to blobs that were
+            not uploaded will miss for other machines: use `[GLOBAL].remote_cache_write` to
+            control whether cache entries are written.
+
+            May not be disabled when `[GLOBAL].remote_execution` is enabled, because remotely
+            executed processes need their inputs to be uploaded.
+            """
+        ),
+    )
+    remote_store_upload_directories = BoolOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_upload_directories,
+        help=softwrap(
+            """
+            Whether to upload directory blobs to the remote store. See
+            `[GLOBAL].remote_store_upload_files`.
+            """
+        ),
+    )
+    remote_store_max_file_upload_size = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_file_upload_size,
+        help=softwrap(
+            """
+            The size, in bytes, of the largest file blob which will be uploaded to the remote
+            store. Larger files are not uploaded (but are still downloaded when needed). Unlimited
+            by default.
+
+            This is useful to avoid filling the remote store with large artifacts which are cheap
+            to rebuild, such as packaged binaries. See `[GLOBAL].remote_store_upload_files`.
+            """
+        ),
+    )
+    remote_store_presence_cache_ttl_secs = IntOption(
+        advanced=True,
+        default=None,
+        help=softwrap(
+            """
+            If set, the digests which the remote store is found to have are recorded in the local
+            store, and the remote store is not asked about them again (with `FindMissingBlobs`)
+            until this many seconds later. This avoids repeatedly checking for blobs which rarely
+            change, such as toolchains.
+
+            The remote store must keep blobs for at least this long after they are last used: if
+            it evicts them sooner, remote execution may fail until the record expires. Records are
+            discarded whenever an upload to the remote store fails.
+            """
+        ),
+    )
     remote_cache_warnings = EnumOption(
         default=DEFAULT_EXECUTION_OPTIONS.remote_cache_warnings,
         advanced=True,
@@ -1475,6 +1970,48 @@ class BootstrapOptions:
         default=DEFAULT_EXECUTION_OPTIONS.remote_cache_read_timeout_millis,
         help="Timeout value for remote cache lookups in milliseconds.",
     )
+    remote_cache_validate_action_results = BoolOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_cache_validate_action_results,
+        help=softwrap(
+            """
+            Whether to check that the blobs which a remote cache entry references (its stdout,
+            stderr, output files and output directory trees) exist in the remote store before
+            using it, with a single `FindMissingBlobs` request.
+
+            Entries which reference missing blobs (because the remote store evicted them before the
+            cache entry, for example) are treated as cache misses, rather than failing later when
+            the blobs are fetched. This complements `[GLOBAL].cache_content_behavior`, and is most
+            useful with its `defer` behavior.
+            """
+        ),
+    )
+    remote_cache_circuit_breaker_threshold = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_cache_circuit_breaker_threshold,
+        help=softwrap(
+            """
+            The number of consecutive failed (or timed out) remote cache reads and writes after
+            which the remote cache is bypassed, so that processes do not each wait for it to fail.
+
+            While it is bypassed, one request is made every
+            `[GLOBAL].remote_cache_circuit_breaker_probe_interval_secs` to check whether it has
+            recovered. The `remote_cache_circuit_breaker_*` metrics count how often this happens.
+
+            If unset, the remote cache is never bypassed.
+            """
+        ),
+    )
+    remote_cache_circuit_breaker_probe_interval_secs = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_cache_circuit_breaker_probe_interval_secs,
+        help=softwrap(
+            """
+            How often to check whether a bypassed remote cache has recovered, in seconds. See
+            `[GLOBAL].remote_cache_circuit_breaker_threshold`.
+            """
+        ),
+    )
     remote_execution_address = StrOption(
         advanced=True,
         default=cast(str, DEFAULT_EXECUTION_OPTIONS.remote_execution_address),
@@ -1509,6 +2046,36 @@ class BootstrapOptions:
         advanced=True,
         help="Overall timeout in seconds for each remote execution request from time of submission",
     )
+    remote_execution_rpc_retries = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_retries,
+        help="Number of times to retry a remote execution request before giving up.",
+    )
+    remote_execution_rpc_retry_initial_backoff_millis = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_retry_initial_backoff_millis,
+        help=softwrap(
+            """
+            The base delay (in milliseconds) before retrying a remote execution request. Before
+            the nth retry, a random delay of up to this value times 2^n is taken.
+            """
+        ),
+    )
+    remote_execution_rpc_retry_max_backoff_millis = IntOption(
+        advanced=True,
+        default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_retry_max_backoff_millis,
+        help="The maximum delay (in milliseconds) before retrying a remote execution request.",
+    )
+    remote_execution_rpc_retryable_status_codes = StrListOption(
+        advanced=True,
+        default=list(DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_retryable_status_codes),
+        help=softwrap(
+            """
+            The gRPC status codes (e.g. `UNAVAILABLE`) with which a failed remote execution
+            request is retried. Failures with other codes are not retried.
+            """
+        ),
+    )
     remote_execution_rpc_concurrency = IntOption(
         advanced=True,
         default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_concurrency,
@@ -1772,8 +2339,8 @@ class GlobalOptions(BootstrapOptions, Subsystem):
                 )
             )
 
-        def validate_remote_address(opt_name: str) -> None:
-            valid_schemes = [f"{scheme}://" for scheme in ("grpc", "grpcs")]
+        def validate_remote_address(opt_name: str, *extra_schemes: str) -> None:
+            valid_schemes = [f"{scheme}://" for scheme in ("grpc", "grpcs", *extra_schemes)]
             address = getattr(opts, opt_name)
             if address and not any(address.startswith(scheme) for scheme in valid_schemes):
                 raise OptionsError(
@@ -1785,8 +2352,25 @@ class GlobalOptions(BootstrapOptions, Subsystem):
                     )
                 )
 
+        if opts.remote_execution and (
+            not opts.remote_store_upload_files
+            or not opts.remote_store_upload_directories
+            or opts.remote_store_max_file_upload_size is not None
+        ):
+            raise OptionsError(
+                softwrap(
+                    """
+                    The `--remote-store-upload-files`, `--remote-store-upload-directories` and
+                    `--remote-store-max-file-upload-size` options may not restrict uploads when
+                    `--remote-execution` is enabled, because remotely executed processes need their
+                    inputs to be uploaded.
+                    """
+                )
+            )
+
         validate_remote_address("remote_execution_address")
-        validate_remote_address("remote_store_address")
+        # NB: The store may also be a shared directory, rather than a server.
+        validate_remote_address("remote_store_address", "file")
 
         # Ensure that remote headers are ASCII.
         def validate_remote_headers(opt_name: str) -> None:
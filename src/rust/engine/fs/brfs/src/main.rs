@@ -36,6 +36,7 @@ use std::time;
 
 use clap::{Arg, Command};
 use futures::future::FutureExt;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint};
 use log::{debug, error, warn};
@@ -133,6 +134,9 @@ struct BuildResultFS {
   sender: Sender<BRFSEvent>,
   runtime: task_executor::Executor,
   store: Store,
+  // If set, the Directory which is mounted as the root of the filesystem, in place of the
+  // `digest` and `directory` roots.
+  root_digest: Option<Digest>,
   inode_digest_cache: HashMap<Inode, InodeDetails>,
   digest_inode_cache: HashMap<Digest, (Inode, Inode)>,
   directory_inode_cache: HashMap<Digest, Inode>,
@@ -144,14 +148,29 @@ impl BuildResultFS {
     sender: Sender<BRFSEvent>,
     runtime: task_executor::Executor,
     store: Store,
+    root_digest: Option<Digest>,
   ) -> BuildResultFS {
+    let mut inode_digest_cache = HashMap::new();
+    let mut directory_inode_cache = HashMap::new();
+    if let Some(digest) = root_digest {
+      inode_digest_cache.insert(
+        ROOT,
+        InodeDetails {
+          digest: digest,
+          entry_type: EntryType::Directory,
+          is_executable: true,
+        },
+      );
+      directory_inode_cache.insert(digest, ROOT);
+    }
     BuildResultFS {
       sender: sender,
       runtime: runtime,
       store: store,
-      inode_digest_cache: HashMap::new(),
+      root_digest: root_digest,
+      inode_digest_cache: inode_digest_cache,
       digest_inode_cache: HashMap::new(),
-      directory_inode_cache: HashMap::new(),
+      directory_inode_cache: directory_inode_cache,
       next_inode: 4,
     }
   }
@@ -181,53 +200,57 @@ impl BuildResultFS {
     digest: Digest,
     is_executable: bool,
   ) -> Result<Option<Inode>, String> {
-    match self.digest_inode_cache.entry(digest) {
-      Occupied(entry) => {
-        let (executable_inode, non_executable_inode) = *entry.get();
-        Ok(Some(if is_executable {
-          executable_inode
-        } else {
-          non_executable_inode
-        }))
+    if !self.digest_inode_cache.contains_key(&digest) {
+      let store = self.store.clone();
+      match self
+        .runtime
+        .block_on(async move { store.load_file_bytes_with(digest, |_| ()).await })
+      {
+        Ok(()) => (),
+        Err(StoreError::MissingDigest { .. }) => return Ok(None),
+        Err(err) => return Err(err.to_string()),
       }
+    }
+    Ok(Some(self.lazy_inode_for_file(digest, is_executable)))
+  }
+
+  ///
+  /// As `inode_for_file`, but for a file which is known to exist because a Directory contains it.
+  /// Its content is not loaded until it is first read, so that mounted trees are never fully
+  /// fetched unless they are fully read.
+  ///
+  pub fn lazy_inode_for_file(&mut self, digest: Digest, is_executable: bool) -> Inode {
+    let (executable_inode, non_executable_inode) = match self.digest_inode_cache.entry(digest) {
+      Occupied(entry) => *entry.get(),
       Vacant(entry) => {
-        let store = self.store.clone();
-        match self
-          .runtime
-          .block_on(async move { store.load_file_bytes_with(digest, |_| ()).await })
-        {
-          Ok(()) => {
-            let executable_inode = self.next_inode;
-            self.next_inode += 1;
-            let non_executable_inode = self.next_inode;
-            self.next_inode += 1;
-            entry.insert((executable_inode, non_executable_inode));
-            self.inode_digest_cache.insert(
-              executable_inode,
-              InodeDetails {
-                digest: digest,
-                entry_type: EntryType::File,
-                is_executable: true,
-              },
-            );
-            self.inode_digest_cache.insert(
-              non_executable_inode,
-              InodeDetails {
-                digest: digest,
-                entry_type: EntryType::File,
-                is_executable: false,
-              },
-            );
-            Ok(Some(if is_executable {
-              executable_inode
-            } else {
-              non_executable_inode
-            }))
-          }
-          Err(StoreError::MissingDigest { .. }) => Ok(None),
-          Err(err) => Err(err.to_string()),
-        }
+        let executable_inode = self.next_inode;
+        self.next_inode += 1;
+        let non_executable_inode = self.next_inode;
+        self.next_inode += 1;
+        entry.insert((executable_inode, non_executable_inode));
+        self.inode_digest_cache.insert(
+          executable_inode,
+          InodeDetails {
+            digest: digest,
+            entry_type: EntryType::File,
+            is_executable: true,
+          },
+        );
+        self.inode_digest_cache.insert(
+          non_executable_inode,
+          InodeDetails {
+            digest: digest,
+            entry_type: EntryType::File,
+            is_executable: false,
+          },
+        );
+        (executable_inode, non_executable_inode)
       }
+    };
+    if is_executable {
+      executable_inode
+    } else {
+      non_executable_inode
     }
   }
 
@@ -287,7 +310,7 @@ impl BuildResultFS {
 
   pub fn readdir_entries(&mut self, inode: Inode) -> Result<Vec<ReaddirEntry>, i32> {
     match inode {
-      ROOT => Ok(vec![
+      ROOT if self.root_digest.is_none() => Ok(vec![
         ReaddirEntry {
           inode: ROOT,
           kind: fuser::FileType::Directory,
@@ -322,7 +345,7 @@ impl BuildResultFS {
       // All three of these end up with the same problem that readdir doesn't show things which, if
       // you were to getattr/open would actually exist. So we choose the cheapest, and most
       // consistent one: readdir is always empty.
-      DIGEST_ROOT | DIRECTORY_ROOT => Ok(vec![]),
+      DIGEST_ROOT | DIRECTORY_ROOT if self.root_digest.is_none() => Ok(vec![]),
       inode => match self.inode_digest_cache.get(&inode) {
         Some(&InodeDetails {
           digest,
@@ -343,7 +366,11 @@ impl BuildResultFS {
                   name: OsString::from("."),
                 },
                 ReaddirEntry {
-                  inode: DIRECTORY_ROOT,
+                  inode: if self.root_digest.is_some() {
+                    ROOT
+                  } else {
+                    DIRECTORY_ROOT
+                  },
                   kind: fuser::FileType::Directory,
                   name: OsString::from(".."),
                 },
@@ -373,7 +400,9 @@ impl BuildResultFS {
                 })?;
                 let maybe_child_inode = match filetype {
                   fuser::FileType::Directory => self.inode_for_directory(child_digest),
-                  fuser::FileType::RegularFile => self.inode_for_file(child_digest, is_executable),
+                  fuser::FileType::RegularFile => {
+                    Ok(Some(self.lazy_inode_for_file(child_digest, is_executable)))
+                  }
                   _ => unreachable!(),
                 };
                 match maybe_child_inode {
@@ -441,32 +470,36 @@ impl fuser::Filesystem for BuildResultFS {
     let runtime = self.runtime.clone();
     runtime.enter(|| {
       let r = match (parent, name.to_str()) {
-        (ROOT, Some("digest")) => Ok(dir_attr_for(DIGEST_ROOT)),
-        (ROOT, Some("directory")) => Ok(dir_attr_for(DIRECTORY_ROOT)),
-        (DIGEST_ROOT, Some(digest_str)) => match digest_from_filepath(digest_str) {
-          Ok(digest) => self
-            .inode_for_file(digest, true)
-            .map_err(|err| {
-              error!("Error loading file by digest {}: {}", digest_str, err);
-              libc::EINVAL
-            })
-            .and_then(|maybe_inode| {
-              maybe_inode
-                .and_then(|inode| self.file_attr_for(inode))
-                .ok_or(libc::ENOENT)
-            }),
-          Err(err) => {
-            warn!("Invalid digest for file in digest root: {}", err);
-            Err(libc::ENOENT)
+        (ROOT, Some("digest")) if self.root_digest.is_none() => Ok(dir_attr_for(DIGEST_ROOT)),
+        (ROOT, Some("directory")) if self.root_digest.is_none() => Ok(dir_attr_for(DIRECTORY_ROOT)),
+        (DIGEST_ROOT, Some(digest_str)) if self.root_digest.is_none() => {
+          match digest_from_filepath(digest_str) {
+            Ok(digest) => self
+              .inode_for_file(digest, true)
+              .map_err(|err| {
+                error!("Error loading file by digest {}: {}", digest_str, err);
+                libc::EINVAL
+              })
+              .and_then(|maybe_inode| {
+                maybe_inode
+                  .and_then(|inode| self.file_attr_for(inode))
+                  .ok_or(libc::ENOENT)
+              }),
+            Err(err) => {
+              warn!("Invalid digest for file in digest root: {}", err);
+              Err(libc::ENOENT)
+            }
           }
-        },
-        (DIRECTORY_ROOT, Some(digest_str)) => match digest_from_filepath(digest_str) {
-          Ok(digest) => self.dir_attr_for(digest),
-          Err(err) => {
-            warn!("Invalid digest for directory in directory root: {}", err);
-            Err(libc::ENOENT)
+        }
+        (DIRECTORY_ROOT, Some(digest_str)) if self.root_digest.is_none() => {
+          match digest_from_filepath(digest_str) {
+            Ok(digest) => self.dir_attr_for(digest),
+            Err(err) => {
+              warn!("Invalid digest for directory in directory root: {}", err);
+              Err(libc::ENOENT)
+            }
           }
-        },
+        }
         (parent, Some(filename)) => {
           let maybe_cache_entry = self
             .inode_digest_cache
@@ -504,17 +537,8 @@ impl fuser::Filesystem for BuildResultFS {
                   error!("Error parsing digest: {:?}", err);
                   libc::ENOENT
                 })?;
-                self
-                  .inode_for_file(digest, file_node.is_executable)
-                  .map_err(|err| {
-                    error!("Error loading file by digest {}: {}", filename, err);
-                    libc::EINVAL
-                  })
-                  .and_then(|maybe_inode| {
-                    maybe_inode
-                      .and_then(|inode| self.file_attr_for(inode))
-                      .ok_or(libc::ENOENT)
-                  })
+                let inode = self.lazy_inode_for_file(digest, file_node.is_executable);
+                self.file_attr_for(inode).ok_or(libc::ENOENT)
               }
             })
         }
@@ -531,8 +555,10 @@ impl fuser::Filesystem for BuildResultFS {
     let runtime = self.runtime.clone();
     runtime.enter(|| match inode {
       ROOT => reply.attr(&TTL, &dir_attr_for(ROOT)),
-      DIGEST_ROOT => reply.attr(&TTL, &dir_attr_for(DIGEST_ROOT)),
-      DIRECTORY_ROOT => reply.attr(&TTL, &dir_attr_for(DIRECTORY_ROOT)),
+      DIGEST_ROOT if self.root_digest.is_none() => reply.attr(&TTL, &dir_attr_for(DIGEST_ROOT)),
+      DIRECTORY_ROOT if self.root_digest.is_none() => {
+        reply.attr(&TTL, &dir_attr_for(DIRECTORY_ROOT))
+      }
       _ => match self.inode_digest_cache.get(&inode) {
         Some(&InodeDetails {
           entry_type: EntryType::File,
@@ -661,6 +687,29 @@ pub fn mount<P: AsRef<Path>>(
   mount_path: P,
   store: Store,
   runtime: task_executor::Executor,
+) -> std::io::Result<(fuser::BackgroundSession, Receiver<BRFSEvent>)> {
+  mount_with_root(mount_path, store, runtime, None)
+}
+
+///
+/// Mounts the given Directory digest read-only as the root of the filesystem. Directories are
+/// loaded as they are listed, but the content of each file is only loaded (from the local store, or
+/// the remote store if one is configured) when it is first read.
+///
+pub fn mount_directory<P: AsRef<Path>>(
+  mount_path: P,
+  store: Store,
+  runtime: task_executor::Executor,
+  digest: Digest,
+) -> std::io::Result<(fuser::BackgroundSession, Receiver<BRFSEvent>)> {
+  mount_with_root(mount_path, store, runtime, Some(digest))
+}
+
+fn mount_with_root<P: AsRef<Path>>(
+  mount_path: P,
+  store: Store,
+  runtime: task_executor::Executor,
+  root_digest: Option<Digest>,
 ) -> std::io::Result<(fuser::BackgroundSession, Receiver<BRFSEvent>)> {
   // TODO: Work out how to disable caching in the filesystem
   let options = vec![
@@ -670,7 +719,7 @@ pub fn mount<P: AsRef<Path>>(
   ];
 
   let (sender, receiver) = channel();
-  let brfs = BuildResultFS::new(sender, runtime, store);
+  let brfs = BuildResultFS::new(sender, runtime, store, root_digest);
 
   debug!("About to spawn_mount with options {:?}", options);
   let result = fuser::spawn_mount2(brfs, &mount_path, &options);
@@ -714,6 +763,12 @@ async fn main() {
         .takes_value(true)
         .long("oauth-bearer-token-file")
         .required(false)
+    ).arg(
+      Arg::new("directory-digest")
+        .help("The digest of a Directory to mount as the root of the filesystem, of the form fingerprint-size. If not set, the whole store is mounted.")
+        .takes_value(true)
+        .long("directory-digest")
+        .required(false)
     ).arg(
       Arg::new("mount-path")
         .required(true)
@@ -772,9 +827,13 @@ async fn main() {
         args.value_of("remote-instance-name").map(str::to_owned),
         tls::Config::new_without_mtls(root_ca_certs),
         headers,
+        None,
         4 * 1024 * 1024,
         std::time::Duration::from_secs(5 * 60),
-        1,
+        RetryPolicy {
+          attempts: 2,
+          ..RetryPolicy::default()
+        },
         args
           .value_of_t::<usize>("rpc-concurrency-limit")
           .expect("Bad rpc-concurrency-limit flag"),
@@ -807,7 +866,21 @@ async fn main() {
   let sigint = install_handler(SignalKind::interrupt, Sig::Int);
   let sigterm = install_handler(SignalKind::terminate, Sig::Term);
 
-  match mount(mount_path, store, runtime.clone()) {
+  let mounted = match args.value_of("directory-digest") {
+    Some(digest_str) => {
+      let digest = match digest_from_filepath(digest_str) {
+        Ok(digest) => digest,
+        Err(err) => {
+          error!("Invalid directory digest {}: {}", digest_str, err);
+          std::process::exit(1);
+        }
+      };
+      mount_directory(mount_path, store, runtime.clone(), digest)
+    }
+    None => mount(mount_path, store, runtime.clone()),
+  };
+
+  match mounted {
     Err(err) => {
       error!(
         "Store {} failed to mount at {}: {}",
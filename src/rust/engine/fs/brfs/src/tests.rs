@@ -3,7 +3,7 @@
 use tempfile;
 use testutil;
 
-use crate::mount;
+use crate::{mount, mount_directory};
 use hashing;
 use store::Store;
 use testutil::{
@@ -226,6 +226,54 @@ async fn files_are_correctly_executable() {
   assert!(!file::is_executable(&virtual_dir.join("food.ext")));
 }
 
+#[tokio::test]
+async fn mount_directory_digest_lazily() {
+  let (store_dir, mount_dir) = make_dirs();
+  let runtime = task_executor::Executor::new();
+
+  let store =
+    Store::local_only(runtime.clone(), store_dir.path()).expect("Error creating local store");
+
+  let test_bytes = TestData::roland();
+  let treat_bytes = TestData::catnip();
+  let test_directory = TestDirectory::containing_roland();
+  let recursive_directory = TestDirectory::recursive();
+
+  // NB: The content of treats.ext is never stored, and so is only missing when it is read.
+  store
+    .store_file_bytes(test_bytes.bytes(), false)
+    .await
+    .expect("Storing bytes");
+  store
+    .record_directory(&test_directory.directory(), false)
+    .await
+    .expect("Storing directory");
+  store
+    .record_directory(&recursive_directory.directory(), false)
+    .await
+    .expect("Storing directory");
+
+  let _fs = mount_directory(
+    mount_dir.path(),
+    store,
+    runtime,
+    recursive_directory.digest(),
+  )
+  .expect("Mounting");
+  let root = mount_dir.path();
+  assert_eq!(vec!["cats", "treats.ext"], file::list_dir(root));
+
+  let roland = root.join("cats").join("roland.ext");
+  assert_eq!(test_bytes.bytes(), file::contents(&roland));
+
+  let treats = root.join("treats.ext");
+  assert_eq!(
+    treat_bytes.len() as u64,
+    std::fs::metadata(&treats).unwrap().len()
+  );
+  assert!(std::fs::read(&treats).is_err());
+}
+
 pub fn digest_to_filepath(digest: &hashing::Digest) -> String {
   format!("{}-{}", digest.hash, digest.size_bytes)
 }
@@ -41,6 +41,7 @@ use fs::{
 use futures::future::{self, BoxFuture};
 use futures::FutureExt;
 use grpc_util::prost::MessageExt;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls::{CertificateCheck, MtlsConfig};
 use hashing::{Digest, Fingerprint};
 use parking_lot::Mutex;
@@ -48,7 +49,8 @@ use protos::require_digest;
 use serde_derive::Serialize;
 use std::collections::{BTreeMap, BTreeSet};
 use store::{
-  Snapshot, SnapshotOps, Store, StoreError, StoreFileByDigest, SubsetParams, UploadSummary,
+  InspectFormat, InspectOptions, Snapshot, SnapshotOps, Store, StoreError, StoreFileByDigest,
+  SubsetParams, UploadSummary,
 };
 use workunit_store::WorkunitStore;
 
@@ -65,7 +67,9 @@ impl From<StoreError> for ExitError {
   fn from(s: StoreError) -> Self {
     match s {
       md @ StoreError::MissingDigest { .. } => ExitError(md.to_string(), ExitCode::NotFound),
-      StoreError::Unclassified(s) => ExitError(s, ExitCode::UnknownError),
+      StoreError::DeadlineExceeded(s) | StoreError::Unclassified(s) => {
+        ExitError(s, ExitCode::UnknownError)
+      }
     }
   }
 }
@@ -181,6 +185,39 @@ to this directory.",
               .arg(Arg::new("size_bytes").required(true).takes_value(
                 true,
               )),
+          )
+          .subcommand(
+            Command::new("inspect")
+              .about(
+                "Output the recursive entries of a Directory addressed by fingerprint, with their \
+digests and symlink targets.",
+              )
+              .arg(
+                Arg::new("output-format")
+                  .long("output-format")
+                  .takes_value(true)
+                  .default_value("text")
+                  .possible_values(["text", "json"]),
+              )
+              .arg(
+                Arg::new("max-depth")
+                  .long("max-depth")
+                  .takes_value(true)
+                  .help("Omit entries more than this many directories deep."),
+              )
+              .arg(
+                Arg::new("glob")
+                  .long("glob")
+                  .takes_value(true)
+                  .multiple_occurrences(true)
+                  .help("Only output entries matching one of these globs, and their parent directories."),
+              )
+              .arg(Arg::new("fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::new("size_bytes").required(true).takes_value(
+                true,
+              )),
           ),
       )
       .subcommand(
@@ -220,6 +257,18 @@ Destination must not exist before this command is run.",
               .subcommand(Command::new("list"))
               .about("List all directory digests known in the local store")
         )
+        .subcommand(
+          Command::new("verify")
+              .about("Re-hash every entry in the local store, and report any whose content does not match their digest.")
+              .arg(
+                Arg::new("on-corrupt")
+                    .help("What to do with corrupt entries: report them, remove them from the store, or move them into the quarantine directory of the store.")
+                    .takes_value(true)
+                    .long("on-corrupt")
+                    .possible_values(["report", "remove", "quarantine"])
+                    .default_value("report"),
+              )
+        )
         .subcommand(
           Command::new("gc")
               .about("Garbage collect the on-disk store. Note that after running this command, any processes with an open store (e.g. a pantsd) may need to re-initialize their store.")
@@ -359,17 +408,10 @@ async fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
           top_match.value_of("mtls-client-certificate-chain-path"),
           top_match.value_of("mtls-client-key-path"),
         ) {
-          (Some(cert_chain_path), Some(key_path)) => {
-            let key = std::fs::read(key_path).map_err(|err| {
-              format!("Failed to read mtls-client-key-path from {key_path:?}: {err:?}")
-            })?;
-            let cert_chain = std::fs::read(cert_chain_path).map_err(|err| {
-              format!(
-                "Failed to read mtls-client-certificate-chain-path from {cert_chain_path:?}: {err:?}"
-              )
-            })?;
-            Some(MtlsConfig { key, cert_chain })
-          }
+          (Some(cert_chain_path), Some(key_path)) => Some(MtlsConfig::Files {
+            key_path: PathBuf::from(key_path),
+            cert_chain_path: PathBuf::from(cert_chain_path),
+          }),
           (None, None) => None,
           _ => {
             return Err("Must specify both or neither of mtls-client-certificate-chain-path and mtls-client-key-path".to_owned().into());
@@ -418,6 +460,7 @@ async fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
               .map(str::to_owned),
             tls_config,
             headers,
+            None,
             chunk_size,
             // This deadline is really only in place because otherwise DNS failures
             // leave this hanging forever.
@@ -428,9 +471,12 @@ async fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
             //
             // See https://github.com/pantsbuild/pants/pull/6433 for more context.
             Duration::from_secs(30 * 60),
-            top_match
-              .value_of_t::<usize>("rpc-attempts")
-              .expect("Bad rpc-attempts flag"),
+            RetryPolicy {
+              attempts: top_match
+                .value_of_t::<usize>("rpc-attempts")
+                .expect("Bad rpc-attempts flag"),
+              ..RetryPolicy::default()
+            },
             top_match
               .value_of_t::<usize>("rpc-concurrency-limit")
               .expect("Bad rpc-concurrency-limit flag"),
@@ -660,6 +706,44 @@ async fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
         io::stdout().write_all(&proto_bytes).unwrap();
         Ok(())
       }
+      ("inspect", args) => {
+        let fingerprint = Fingerprint::from_hex_string(args.value_of("fingerprint").unwrap())?;
+        let size_bytes = args
+          .value_of("size_bytes")
+          .unwrap()
+          .parse::<usize>()
+          .expect("size_bytes must be a non-negative number");
+        let digest = DirectoryDigest::from_persisted_digest(Digest::new(fingerprint, size_bytes));
+        let options = InspectOptions {
+          max_depth: args
+            .value_of("max-depth")
+            .map(|max_depth| {
+              max_depth
+                .parse::<usize>()
+                .map_err(|e| format!("--max-depth must be a non-negative number: {e}"))
+            })
+            .transpose()?,
+          globs: args
+            .values_of("glob")
+            .map(|globs| {
+              globs
+                .map(|glob| {
+                  glob::Pattern::new(glob).map_err(|e| format!("Invalid glob {glob}: {e}"))
+                })
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default(),
+        };
+        let format = args
+          .value_of("output-format")
+          .unwrap()
+          .parse::<InspectFormat>()?;
+
+        let rendered = store.render_directory(digest, &options, format).await?;
+        io::stdout().write_all(rendered.as_bytes()).unwrap();
+        Ok(())
+      }
       (_, _) => unimplemented!(),
     },
     ("cat", args) => {
@@ -695,6 +779,30 @@ async fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
       }
       _ => unimplemented!(),
     },
+    ("verify", args) => {
+      let action = match args.value_of("on-corrupt").unwrap() {
+        "report" => store::CorruptEntryAction::Report,
+        "remove" => store::CorruptEntryAction::Remove,
+        "quarantine" => store::CorruptEntryAction::Quarantine,
+        _ => unreachable!(),
+      };
+      let report = store.verify_local_integrity(action).await?;
+      for (entry_type, digest) in &report.corrupt {
+        println!("{entry_type:?} {} {}", digest.hash, digest.size_bytes);
+      }
+      if report.corrupt.is_empty() {
+        Ok(())
+      } else {
+        Err(
+          format!(
+            "{} of {} entries in the local store were corrupt.",
+            report.corrupt.len(),
+            report.checked
+          )
+          .into(),
+        )
+      }
+    }
     ("gc", args) => {
       let target_size_bytes = args
         .value_of_t::<usize>("target-size-bytes")
@@ -1,12 +1,14 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Debug, Display};
 use std::hash::{self, Hash};
 use std::ops::Deref;
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
 use deepsize::{known_deep_size, DeepSizeOf};
@@ -15,6 +17,7 @@ use itertools::Itertools;
 use lazy_static::lazy_static;
 use log::warn;
 use serde::Serialize;
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization as _};
 
 // TODO: Extract protobuf-specific pieces to a new crate.
 use grpc_util::prost::MessageExt;
@@ -396,6 +399,50 @@ impl<'a> From<&'a PathStat> for TypedPath<'a> {
   }
 }
 
+///
+/// The Unicode normalization form which path names are converted to when they are captured or
+/// materialized. macOS filesystems generally return decomposed (NFD) names, while Linux
+/// filesystems generally return names as they were written (usually NFC), so the "same" tree may
+/// otherwise have different digests on different platforms.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnicodeNormalization {
+  /// Names are left as they are.
+  #[default]
+  Preserve,
+  /// Names are converted to Normalization Form C (canonical composition).
+  Nfc,
+  /// Names are converted to Normalization Form D (canonical decomposition).
+  Nfd,
+}
+
+impl UnicodeNormalization {
+  fn normalize<'a>(&self, s: &'a str) -> Cow<'a, str> {
+    match self {
+      UnicodeNormalization::Preserve => Cow::Borrowed(s),
+      UnicodeNormalization::Nfc if is_nfc(s) => Cow::Borrowed(s),
+      UnicodeNormalization::Nfc => Cow::Owned(s.nfc().collect()),
+      UnicodeNormalization::Nfd if is_nfd(s) => Cow::Borrowed(s),
+      UnicodeNormalization::Nfd => Cow::Owned(s.nfd().collect()),
+    }
+  }
+}
+
+impl FromStr for UnicodeNormalization {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "preserve" => Ok(Self::Preserve),
+      "nfc" => Ok(Self::Nfc),
+      "nfd" => Ok(Self::Nfd),
+      s => Err(format!(
+        "Unsupported Unicode normalization `{s}`: expected one of `preserve`, `nfc`, or `nfd`."
+      )),
+    }
+  }
+}
+
 #[derive(Clone, DeepSizeOf)]
 pub struct DigestTrie(Arc<[Entry]>);
 
@@ -668,6 +715,118 @@ impl DigestTrie {
     }
   }
 
+  ///
+  /// Converts the names of all entries (and the targets of symlinks) to the given normalization
+  /// form, and returns the new DigestTrie along with the original and new paths of each entry whose
+  /// name changed. Fails if two entries of a directory have the same normalized name.
+  ///
+  pub fn normalize_unicode(
+    &self,
+    normalization: UnicodeNormalization,
+  ) -> Result<(DigestTrie, Vec<(PathBuf, PathBuf)>), String> {
+    let mut renamed = Vec::new();
+    if normalization == UnicodeNormalization::Preserve {
+      return Ok((self.clone(), renamed));
+    }
+    let tree =
+      self.normalize_unicode_helper(normalization, Path::new(""), Path::new(""), &mut renamed)?;
+    Ok((tree, renamed))
+  }
+
+  fn normalize_unicode_helper(
+    &self,
+    normalization: UnicodeNormalization,
+    original_prefix: &Path,
+    normalized_prefix: &Path,
+    renamed: &mut Vec<(PathBuf, PathBuf)>,
+  ) -> Result<DigestTrie, String> {
+    let mut entries = Vec::with_capacity(self.0.len());
+    for entry in self.entries() {
+      let original_name = entry.name();
+      let name = match normalization.normalize(original_name.as_ref()) {
+        Cow::Borrowed(_) => original_name,
+        Cow::Owned(normalized) => {
+          let name = Name::new(&normalized);
+          renamed.push((
+            original_prefix.join(original_name.as_ref()),
+            normalized_prefix.join(name.as_ref()),
+          ));
+          name
+        }
+      };
+      entries.push(match entry {
+        Entry::Directory(d) => Entry::Directory(Directory::from_digest_tree(
+          name,
+          d.tree().normalize_unicode_helper(
+            normalization,
+            &original_prefix.join(original_name.as_ref()),
+            &normalized_prefix.join(name.as_ref()),
+            renamed,
+          )?,
+        )),
+        Entry::File(f) => Entry::File(File {
+          name,
+          digest: f.digest,
+          is_executable: f.is_executable,
+        }),
+        Entry::Symlink(s) => {
+          let target = match s.target.to_str() {
+            Some(target) => PathBuf::from(normalization.normalize(target).as_ref()),
+            None => s.target.clone(),
+          };
+          Entry::Symlink(Symlink { name, target })
+        }
+      });
+    }
+
+    entries.sort_by_key(|e| e.name());
+    if let Some((a, _)) = entries
+      .iter()
+      .tuple_windows()
+      .find(|(a, b)| a.name() == b.name())
+    {
+      return Err(format!(
+        "Multiple entries would be named `{}` after Unicode normalization.",
+        normalized_prefix.join(a.name().as_ref()).display()
+      ));
+    }
+    Ok(DigestTrie(entries.into()))
+  }
+
+  ///
+  /// Fails if any directory in this DigestTrie has entries whose names differ only in case, which
+  /// would collide (and so materialize nondeterministically) on a case-insensitive filesystem.
+  ///
+  pub fn check_case_collisions(&self) -> Result<(), CaseCollisionError> {
+    self.check_case_collisions_helper(Path::new(""))
+  }
+
+  fn check_case_collisions_helper(&self, prefix: &Path) -> Result<(), CaseCollisionError> {
+    let mut names_by_folded_name: BTreeMap<String, Vec<&Name>> = BTreeMap::new();
+    for entry in self.entries() {
+      names_by_folded_name
+        .entry(entry.name().to_lowercase())
+        .or_default()
+        .push(entry.name());
+    }
+    if let Some(names) = names_by_folded_name.values().find(|names| names.len() > 1) {
+      return Err(CaseCollisionError {
+        paths: names
+          .iter()
+          .map(|name| prefix.join(name.as_ref()))
+          .collect(),
+      });
+    }
+
+    for entry in self.entries() {
+      if let Entry::Directory(d) = entry {
+        d.tree()
+          .check_case_collisions_helper(&prefix.join(d.name().as_ref()))?;
+      }
+    }
+    Ok(())
+  }
+
   pub fn diff(&self, other: &DigestTrie) -> DigestTrieDiff {
     let mut result = DigestTrieDiff::default();
     self.diff_helper(other, PathBuf::new(), &mut result);
@@ -1140,6 +1299,29 @@ pub struct DigestTrieDiff {
   pub changed_symlinks: Vec<PathBuf>,
 }
 
+///
+/// The paths of entries of a directory whose names differ only in case.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaseCollisionError {
+  pub paths: Vec<PathBuf>,
+}
+
+impl Display for CaseCollisionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "The paths {} differ only in case, and so would collide on a case-insensitive filesystem \
+       (such as the default filesystem on macOS).",
+      self
+        .paths
+        .iter()
+        .map(|path| format!("`{}`", path.display()))
+        .join(", ")
+    )
+  }
+}
+
 pub enum MergeError {
   Duplicates {
     parent_path: PathBuf,
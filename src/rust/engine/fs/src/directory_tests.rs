@@ -3,7 +3,9 @@
 
 use std::collections::HashMap;
 
-use crate::directory::{DigestTrie, Entry, Name, TypedPath};
+use crate::directory::{
+  CaseCollisionError, DigestTrie, Entry, Name, TypedPath, UnicodeNormalization,
+};
 use crate::MAX_LINK_DEPTH;
 use hashing::EMPTY_DIGEST;
 use std::path::{Path, PathBuf};
@@ -370,3 +372,87 @@ fn walk_too_many_links_subdir() {
     vec!["".to_string(), "a".to_string()],
   );
 }
+
+#[test]
+fn case_collisions() {
+  let tree = make_tree(vec![
+    TypedPath::File {
+      path: Path::new("README"),
+      is_executable: false,
+    },
+    TypedPath::File {
+      path: Path::new("dir/Makefile"),
+      is_executable: false,
+    },
+    TypedPath::Dir(Path::new("dir/makefile")),
+  ]);
+  assert_eq!(
+    tree.check_case_collisions(),
+    Err(CaseCollisionError {
+      paths: vec![PathBuf::from("dir/Makefile"), PathBuf::from("dir/makefile")],
+    })
+  );
+
+  let tree = make_tree(vec![
+    TypedPath::File {
+      path: Path::new("README"),
+      is_executable: false,
+    },
+    TypedPath::File {
+      path: Path::new("dir/readme"),
+      is_executable: false,
+    },
+  ]);
+  assert_eq!(tree.check_case_collisions(), Ok(()));
+}
+
+#[test]
+fn normalize_unicode() {
+  // "é" as a single code point (NFC), and as "e" followed by a combining acute accent (NFD).
+  let nfc = "caf\u{e9}";
+  let nfd = "cafe\u{301}";
+  let tree = make_tree(vec![
+    TypedPath::File {
+      path: Path::new(&format!("{nfd}/menu")),
+      is_executable: false,
+    },
+    TypedPath::Link {
+      path: Path::new("link"),
+      target: Path::new(&format!("{nfd}/menu")),
+    },
+  ]);
+
+  let (normalized, renamed) = tree.normalize_unicode(UnicodeNormalization::Nfc).unwrap();
+  assert_eq!(renamed, vec![(PathBuf::from(nfd), PathBuf::from(nfc))]);
+  assert_entry_is_some(&normalized, &format!("{nfc}/menu"));
+  assert_entry_is_none(&normalized, &format!("{nfd}/menu"));
+  let expected = make_tree(vec![
+    TypedPath::File {
+      path: Path::new(&format!("{nfc}/menu")),
+      is_executable: false,
+    },
+    TypedPath::Link {
+      path: Path::new("link"),
+      target: Path::new(&format!("{nfc}/menu")),
+    },
+  ]);
+  assert_eq!(
+    normalized.compute_root_digest(),
+    expected.compute_root_digest()
+  );
+
+  // Normalizing an already normalized tree renames nothing.
+  let (_, renamed) = expected
+    .normalize_unicode(UnicodeNormalization::Nfc)
+    .unwrap();
+  assert!(renamed.is_empty());
+
+  // Names which collide after normalization are rejected.
+  let colliding = make_tree(vec![
+    TypedPath::Dir(Path::new(nfc)),
+    TypedPath::Dir(Path::new(nfd)),
+  ]);
+  assert!(colliding
+    .normalize_unicode(UnicodeNormalization::Nfd)
+    .is_err());
+}
@@ -35,14 +35,15 @@ mod glob_matching_tests;
 mod posixfs_tests;
 
 pub use crate::directory::{
-  DigestTrie, DirectoryDigest, Entry, SymlinkBehavior, TypedPath, EMPTY_DIGEST_TREE,
-  EMPTY_DIRECTORY_DIGEST,
+  CaseCollisionError, DigestTrie, DirectoryDigest, Entry, SymlinkBehavior, TypedPath,
+  UnicodeNormalization, EMPTY_DIGEST_TREE, EMPTY_DIRECTORY_DIGEST,
 };
 pub use crate::glob_matching::{
   FilespecMatcher, GlobMatching, PathGlob, PreparedPathGlobs, DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB,
 };
 
 use std::cmp::min;
+use std::collections::HashMap;
 use std::io;
 use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
@@ -55,12 +56,14 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use deepsize::DeepSizeOf;
 use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use serde::Serialize;
 
 lazy_static! {
   static ref EMPTY_IGNORE: Arc<GitignoreStyleExcludes> = Arc::new(GitignoreStyleExcludes {
     patterns: vec![],
     gitignore: Gitignore::empty(),
+    nested_gitignores: None,
   });
 }
 
@@ -253,6 +256,59 @@ pub struct DirectoryListing(pub Vec<Stat>);
 pub struct GitignoreStyleExcludes {
   patterns: Vec<String>,
   gitignore: Gitignore,
+  nested_gitignores: Option<NestedGitignores>,
+}
+
+///
+/// The `.gitignore` files in each directory below a root, which are each read (at most once) when
+/// a path below their directory is first matched.
+///
+#[derive(Debug)]
+struct NestedGitignores {
+  root: PathBuf,
+  by_dir: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl NestedGitignores {
+  fn for_dir(&self, dir: &Path) -> Option<Arc<Gitignore>> {
+    if let Some(gitignore) = self.by_dir.lock().get(dir) {
+      return gitignore.clone();
+    }
+
+    let gitignore_path = self.root.join(dir).join(".gitignore");
+    let gitignore = if gitignore_path.is_file() {
+      let (gitignore, err) = Gitignore::new(&gitignore_path);
+      if let Some(err) = err {
+        log::warn!("Error parsing {}: {err}", gitignore_path.display());
+      }
+      Some(Arc::new(gitignore))
+    } else {
+      None
+    };
+    self.by_dir.lock().insert(dir.to_owned(), gitignore.clone());
+    gitignore
+  }
+
+  ///
+  /// Matches the given path against the `.gitignore` files of its ancestor directories. As in git,
+  /// the file in the deepest directory which has an opinion about the path takes precedence, so a
+  /// nested file may negate a pattern from a shallower one.
+  ///
+  fn matched(&self, path: &Path, is_dir: bool) -> ::ignore::Match<()> {
+    for dir in path.ancestors().skip(1) {
+      let gitignore = match self.for_dir(dir) {
+        Some(gitignore) => gitignore,
+        None => continue,
+      };
+      let relative_path = path.strip_prefix(dir).unwrap();
+      match gitignore.matched(relative_path, is_dir) {
+        ::ignore::Match::None => continue,
+        ::ignore::Match::Ignore(_) => return ::ignore::Match::Ignore(()),
+        ::ignore::Match::Whitelist(_) => return ::ignore::Match::Whitelist(()),
+      }
+    }
+    ::ignore::Match::None
+  }
 }
 
 impl GitignoreStyleExcludes {
@@ -292,6 +348,27 @@ impl GitignoreStyleExcludes {
     Ok(Arc::new(Self {
       patterns: patterns,
       gitignore,
+      nested_gitignores: None,
+    }))
+  }
+
+  ///
+  /// Creates excludes which (in addition to the given patterns) respect the `.gitignore` file in
+  /// each directory at or below the given root, which should be the root that matched paths are
+  /// relative to. The given patterns take precedence over the `.gitignore` files.
+  ///
+  pub fn create_with_nested_gitignore_files(
+    patterns: Vec<String>,
+    root: PathBuf,
+  ) -> Result<Arc<Self>, String> {
+    let excludes = Self::create(patterns.clone())?;
+    Ok(Arc::new(Self {
+      patterns,
+      gitignore: excludes.gitignore.clone(),
+      nested_gitignores: Some(NestedGitignores {
+        root,
+        by_dir: Mutex::default(),
+      }),
     }))
   }
 
@@ -306,12 +383,24 @@ impl GitignoreStyleExcludes {
 
   pub fn is_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
     match self.gitignore.matched(path, is_dir) {
-      ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
+      ::ignore::Match::Whitelist(_) => false,
       ::ignore::Match::Ignore(_) => true,
+      ::ignore::Match::None => match &self.nested_gitignores {
+        Some(nested_gitignores) => nested_gitignores.matched(path, is_dir).is_ignore(),
+        None => false,
+      },
     }
   }
 
   pub fn is_ignored_or_child_of_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
+    if self.nested_gitignores.is_some() {
+      // NB: As in git, a path cannot be re-included if one of its parent directories is ignored.
+      return self.is_ignored_path(path, is_dir)
+        || path
+          .ancestors()
+          .skip(1)
+          .any(|parent| !parent.as_os_str().is_empty() && self.is_ignored_path(parent, true));
+    }
     match self.gitignore.matched_path_or_any_parents(path, is_dir) {
       ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
       ::ignore::Match::Ignore(_) => true,
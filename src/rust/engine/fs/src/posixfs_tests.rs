@@ -451,3 +451,72 @@ async fn test_basic_gitignore_functionality() {
   assert!(!posix_fs_2.is_ignored(&stats[2]));
   assert!(posix_fs_2.is_ignored(&stats[3]));
 }
+
+#[tokio::test]
+async fn test_nested_gitignore_files() {
+  let root = tempfile::TempDir::new().unwrap();
+  let root_path = root.path();
+
+  let bytes = "content".as_bytes();
+  std::fs::create_dir_all(root_path.join("src/generated")).unwrap();
+  std::fs::create_dir_all(root_path.join("dist")).unwrap();
+  make_file(&root_path.join(".gitignore"), b"*.log\ndist/\n", 0o600);
+  make_file(&root_path.join("root.log"), bytes, 0o600);
+  make_file(&root_path.join("dist/out.bin"), bytes, 0o600);
+  // A nested file negates a pattern from the root file, and is in turn overridden by a more
+  // deeply nested file.
+  make_file(&root_path.join("src/.gitignore"), b"!keep.log\n", 0o600);
+  make_file(&root_path.join("src/keep.log"), bytes, 0o600);
+  make_file(&root_path.join("src/other.log"), bytes, 0o600);
+  make_file(&root_path.join("src/lib.rs"), bytes, 0o600);
+  make_file(
+    &root_path.join("src/generated/.gitignore"),
+    b"*\n!.gitignore\n",
+    0o600,
+  );
+  make_file(&root_path.join("src/generated/keep.log"), bytes, 0o600);
+
+  let ignorer =
+    GitignoreStyleExcludes::create_with_nested_gitignore_files(vec![], root_path.to_owned())
+      .unwrap();
+  let posix_fs = PosixFS::new(root_path, ignorer, task_executor::Executor::new()).unwrap();
+  let globs = PathGlobs::new(
+    vec!["**".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let mut paths = posix_fs
+    .expand_globs(globs, SymlinkBehavior::Oblivious, None)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|path_stat| path_stat.path().to_owned())
+    .collect::<Vec<_>>();
+  paths.sort();
+
+  assert_eq!(
+    paths,
+    vec![
+      PathBuf::from(".gitignore"),
+      PathBuf::from("src"),
+      PathBuf::from("src/.gitignore"),
+      PathBuf::from("src/generated"),
+      PathBuf::from("src/generated/.gitignore"),
+      PathBuf::from("src/keep.log"),
+      PathBuf::from("src/lib.rs"),
+    ]
+  );
+
+  // Explicit patterns take precedence over the `.gitignore` files.
+  let ignorer = GitignoreStyleExcludes::create_with_nested_gitignore_files(
+    vec!["lib.rs".to_owned()],
+    root_path.to_owned(),
+  )
+  .unwrap();
+  assert!(ignorer.is_ignored_path(Path::new("src/lib.rs"), false));
+  assert!(ignorer.is_ignored_path(Path::new("src/other.log"), false));
+  assert!(!ignorer.is_ignored_path(Path::new("src/keep.log"), false));
+  assert!(ignorer.is_ignored_or_child_of_ignored_path(Path::new("dist/out.bin"), false));
+}
@@ -34,16 +34,17 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
+use bytes::Bytes;
 use fs::{
   DirectoryDigest, File, GitignoreStyleExcludes, GlobExpansionConjunction, PathStat, Permissions,
   PosixFS, PreparedPathGlobs, StrictGlobMatching,
 };
-use hashing::EMPTY_DIGEST;
+use hashing::{Digest, EMPTY_DIGEST};
 use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use task_executor::Executor;
 use tempfile::TempDir;
 
-use store::{OneOffStoreFileByDigest, Snapshot, SnapshotOps, Store, SubsetParams};
+use store::{LocalOptions, OneOffStoreFileByDigest, Snapshot, SnapshotOps, Store, SubsetParams};
 
 fn executor() -> Executor {
   Executor::new_owned(num_cpus::get(), num_cpus::get() * 4, || ()).unwrap()
@@ -248,12 +249,118 @@ pub fn criterion_benchmark_merge(c: &mut Criterion) {
     });
 }
 
+///
+/// Compares listing shard directories with checking for each file individually when checking for
+/// the existence of large files, for batches which are sparse and dense relative to the number of
+/// shards.
+///
+pub fn criterion_benchmark_exists_batch(c: &mut Criterion) {
+  let executor = executor();
+
+  let mut cgroup = c.benchmark_group("exists_batch");
+
+  for count in vec![64, 16384] {
+    // NB: Existence checks for large files only consult the filesystem, so rather than storing
+    // gigabytes of content, create empty files in the store's layout for large file digests.
+    let storedir = TempDir::new().unwrap();
+    let digests = (0..count)
+      .map(|i: u64| {
+        let digest = Digest::new(Digest::of_bytes(&i.to_le_bytes()).hash, 1024 * 1024);
+        let hex = digest.hash.to_hex();
+        let shard = storedir
+          .path()
+          .join("immutable")
+          .join("files")
+          .join(&hex[0..2]);
+        std::fs::create_dir_all(&shard).unwrap();
+        std::fs::write(shard.join(hex), b"").unwrap();
+        digest
+      })
+      .collect::<Vec<_>>();
+
+    for (strategy, fsdb_exists_listing_density) in vec![("listing", 0), ("metadata", usize::MAX)] {
+      let store = Store::local_only_with_options(
+        executor.clone(),
+        storedir.path(),
+        storedir.path(),
+        LocalOptions {
+          fsdb_exists_listing_density,
+          ..LocalOptions::default()
+        },
+      )
+      .unwrap();
+      cgroup
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10))
+        .bench_function(format!("exists_batch({}, {})", strategy, count), |b| {
+          b.iter(|| {
+            assert!(executor
+              .block_on(store.exists_recursive(vec![], digests.clone()))
+              .unwrap());
+          })
+        });
+    }
+  }
+}
+
+pub fn criterion_benchmark_lease(c: &mut Criterion) {
+  let executor = executor();
+  let num_files: usize = 10000;
+
+  let storedir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), storedir.path()).unwrap();
+  let items = (0..num_files)
+    .map(|i| Bytes::from(format!("file {i}")))
+    .collect::<Vec<_>>();
+  let digests = items
+    .iter()
+    .map(|bytes| Digest::of_bytes(bytes))
+    .collect::<Vec<_>>();
+  executor
+    .block_on(
+      store.store_file_bytes_batch(
+        digests
+          .iter()
+          .zip(items)
+          .map(|(digest, bytes)| (digest.hash, bytes))
+          .collect(),
+        false,
+      ),
+    )
+    .unwrap();
+
+  let mut cgroup = c.benchmark_group("lease");
+  cgroup
+    .sample_size(10)
+    .measurement_time(Duration::from_secs(10))
+    // NB: Leasing digests one at a time uses one write transaction per digest, whereas leasing
+    // them together uses one write transaction per shard.
+    .bench_function(format!("lease_individually({num_files})"), |b| {
+      b.iter(|| {
+        for digest in &digests {
+          executor
+            .block_on(store.lease_all_recursively(std::iter::once(digest)))
+            .unwrap();
+        }
+      })
+    })
+    .bench_function(format!("lease_batched({num_files})"), |b| {
+      b.iter(|| {
+        executor
+          .block_on(store.lease_all_recursively(digests.iter()))
+          .unwrap();
+      })
+    });
+}
+
 criterion_group!(
   benches,
   criterion_benchmark_materialize,
   criterion_benchmark_snapshot_capture,
   criterion_benchmark_subset_wildcard,
-  criterion_benchmark_merge
+  criterion_benchmark_merge,
+  criterion_benchmark_exists_batch,
+  criterion_benchmark_lease
 );
 criterion_main!(benches);
 
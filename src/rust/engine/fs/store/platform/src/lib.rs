@@ -0,0 +1,501 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The platform-specific filesystem operations which the local store relies on: marking files as
+//! immutable, identifying the device which holds a file, locking files, copying files within the
+//! kernel, and inspecting the filesystem.
+//!
+//! Leases of large files are file modification times, which `fs_set_times` already updates
+//! portably.
+//!
+//! These live in their own crate (rather than in the `store` crate) so that they can be built and
+//! tested on Windows, where the rest of the engine does not build.
+//!
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be copied and pasted across crates, but there doesn't appear to be a way to include inner attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::unseparated_literal_suffix,
+  clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(
+  clippy::len_without_is_empty,
+  clippy::redundant_field_names,
+  clippy::too_many_arguments
+)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default, clippy::new_ret_no_self)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+#[cfg(test)]
+mod tests;
+
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+
+///
+/// Makes the given file read-only, which is how files in the store are marked as immutable.
+///
+#[cfg(unix)]
+pub async fn make_read_only(path: &Path) -> io::Result<()> {
+  tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o555)).await
+}
+
+#[cfg(windows)]
+pub async fn make_read_only(path: &Path) -> io::Result<()> {
+  let mut permissions = tokio::fs::metadata(path).await?.permissions();
+  permissions.set_readonly(true);
+  tokio::fs::set_permissions(path, permissions).await
+}
+
+///
+/// True if nobody may write to the file with the given metadata.
+///
+#[cfg(unix)]
+pub fn is_read_only(metadata: &Metadata) -> bool {
+  metadata.permissions().mode() & 0o222 == 0
+}
+
+#[cfg(windows)]
+pub fn is_read_only(metadata: &Metadata) -> bool {
+  metadata.permissions().readonly()
+}
+
+///
+/// Sets the permissions of the given file to the given unix mode. On Windows, only whether the
+/// mode is writable is meaningful.
+///
+/// NB: This method is blocking, and should be called on a blocking thread.
+///
+#[cfg(unix)]
+pub fn set_mode(file: &std::fs::File, mode: u32) -> io::Result<()> {
+  file.set_permissions(std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+pub fn set_mode(file: &std::fs::File, mode: u32) -> io::Result<()> {
+  let mut permissions = file.metadata()?.permissions();
+  permissions.set_readonly(mode & 0o222 == 0);
+  file.set_permissions(permissions)
+}
+
+///
+/// As `set_mode`, but for the file at the given path.
+///
+/// NB: This method is blocking, and should be called on a blocking thread.
+///
+#[cfg(unix)]
+pub fn set_path_mode(path: &Path, mode: u32) -> io::Result<()> {
+  std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+pub fn set_path_mode(path: &Path, mode: u32) -> io::Result<()> {
+  let mut permissions = std::fs::metadata(path)?.permissions();
+  permissions.set_readonly(mode & 0o222 == 0);
+  std::fs::set_permissions(path, permissions)
+}
+
+///
+/// As `set_path_mode`, but runs on a blocking thread.
+///
+pub async fn set_path_mode_async(path: &Path, mode: u32) -> io::Result<()> {
+  let path = path.to_owned();
+  tokio::task::spawn_blocking(move || set_path_mode(&path, mode))
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+///
+/// Sets the unix mode with which `options` will create a file, where the platform supports it.
+///
+#[cfg(unix)]
+pub fn create_with_mode(options: &mut OpenOptions, mode: u32) -> &mut OpenOptions {
+  options.mode(mode)
+}
+
+#[cfg(windows)]
+pub fn create_with_mode(options: &mut OpenOptions, _mode: u32) -> &mut OpenOptions {
+  options
+}
+
+///
+/// Identifies the device which holds the file with the given metadata, if it is known. Files may
+/// only be linked between paths on the same (known) device.
+///
+#[cfg(unix)]
+pub fn device_id(metadata: &Metadata) -> Option<u64> {
+  Some(metadata.dev())
+}
+
+///
+/// NB: The volume serial number is not available from the metadata of a file on stable `std` on
+/// Windows, so the device of every file is unknown, and nothing is linked on the grounds that it is
+/// on the same device.
+///
+#[cfg(windows)]
+pub fn device_id(_metadata: &Metadata) -> Option<u64> {
+  None
+}
+
+///
+/// True if files outside of the store may be hardlinked into it. On Windows, read-only files cannot
+/// be replaced or removed without first being made writable, which would make a linked source
+/// file writable too.
+///
+pub const SUPPORTS_LINKING_INTO_STORE: bool = cfg!(unix);
+
+///
+/// Takes a shared advisory lock on the given file, which excludes exclusive locks taken via other
+/// open files (in this process or any other), and which waits for any which are held. A lock which
+/// is already held via this file is converted.
+///
+#[cfg(unix)]
+pub fn lock_shared(file: &File) -> io::Result<()> {
+  flock(file, libc::LOCK_SH)
+}
+
+///
+/// NB: Locks on Windows are mandatory rather than advisory, but the lock files are never read or
+/// written, so that is not observable.
+///
+#[cfg(windows)]
+pub fn lock_shared(file: &File) -> io::Result<()> {
+  lock_file(file, 0)
+}
+
+///
+/// Attempts to take an exclusive advisory lock on the given file without waiting, returning false
+/// if a lock is held via another open file. A lock which is already held via this file is converted.
+///
+#[cfg(unix)]
+pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+  match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+    Ok(()) => Ok(true),
+    Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+    Err(e) => Err(e),
+  }
+}
+
+#[cfg(windows)]
+pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+  use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+  use windows_sys::Win32::Storage::FileSystem::{
+    LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+  };
+
+  match lock_file(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY) {
+    Ok(()) => Ok(true),
+    Err(e) if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) => Ok(false),
+    Err(e) => Err(e),
+  }
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+  // SAFETY: The file descriptor is valid for as long as `file` is borrowed.
+  if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+///
+/// Locks the whole of the given file with `LockFileEx`, first releasing any lock which is held via
+/// it, since (unlike `flock`) `LockFileEx` does not convert locks.
+///
+#[cfg(windows)]
+fn lock_file(file: &File, flags: u32) -> io::Result<()> {
+  use std::os::windows::io::AsRawHandle;
+  use windows_sys::Win32::Storage::FileSystem::{LockFileEx, UnlockFileEx};
+  use windows_sys::Win32::System::IO::OVERLAPPED;
+
+  let handle = file.as_raw_handle() as isize;
+  // SAFETY: The handle is valid for as long as `file` is borrowed, and the zeroed `OVERLAPPED`
+  // locks from offset zero. Unlocking fails harmlessly if no lock is held.
+  unsafe {
+    let mut overlapped: OVERLAPPED = std::mem::zeroed();
+    UnlockFileEx(handle, 0, u32::MAX, u32::MAX, &mut overlapped);
+    let mut overlapped: OVERLAPPED = std::mem::zeroed();
+    if LockFileEx(handle, flags, 0, u32::MAX, u32::MAX, &mut overlapped) != 0 {
+      Ok(())
+    } else {
+      Err(io::Error::last_os_error())
+    }
+  }
+}
+
+///
+/// Removes a file from the store, which will have been made read-only.
+///
+#[cfg(unix)]
+pub async fn remove_read_only(path: &Path) -> io::Result<()> {
+  tokio::fs::remove_file(path).await
+}
+
+#[cfg(windows)]
+pub async fn remove_read_only(path: &Path) -> io::Result<()> {
+  make_writable(path).await?;
+  tokio::fs::remove_file(path).await
+}
+
+///
+/// True if the given error (from linking a file) indicates that the link would have crossed
+/// devices, in which case the caller should copy the file instead.
+///
+#[cfg(unix)]
+pub fn is_cross_device(e: &io::Error) -> bool {
+  e.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+pub fn is_cross_device(e: &io::Error) -> bool {
+  use windows_sys::Win32::Foundation::ERROR_NOT_SAME_DEVICE;
+  e.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE as i32)
+}
+
+///
+/// Creates a symlink at `path` which points to `target`. On Windows, whether the link is to a file
+/// or a directory is decided by what `target` (relative to the parent of `path`) is now.
+///
+/// NB: This method is blocking, and should be called on a blocking thread.
+///
+#[cfg(unix)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+  std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+  let resolved = path.parent().unwrap_or(path).join(target);
+  if resolved.is_dir() {
+    std::os::windows::fs::symlink_dir(target, path)
+  } else {
+    std::os::windows::fs::symlink_file(target, path)
+  }
+}
+
+///
+/// Renames `src` to `dest`, replacing `dest` if it exists (even if it is read-only).
+///
+#[cfg(unix)]
+pub async fn replace(src: &Path, dest: &Path) -> io::Result<()> {
+  tokio::fs::rename(src, dest).await
+}
+
+#[cfg(windows)]
+pub async fn replace(src: &Path, dest: &Path) -> io::Result<()> {
+  match make_writable(dest).await {
+    Ok(()) => (),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+    Err(e) => return Err(e),
+  }
+  tokio::fs::rename(src, dest).await
+}
+
+#[cfg(windows)]
+async fn make_writable(path: &Path) -> io::Result<()> {
+  let mut permissions = tokio::fs::metadata(path).await?.permissions();
+  #[allow(clippy::permissions_set_readonly_false)]
+  permissions.set_readonly(false);
+  tokio::fs::set_permissions(path, permissions).await
+}
+
+///
+/// Copies the content of `src` into `dest` within the kernel, rather than through user space:
+/// using `copy_file_range` on Linux, and `fcopyfile` on macOS. Both files are read and written
+/// from their current offsets. Fails with `io::ErrorKind::Unsupported` (having copied nothing) if
+/// the platform or filesystem cannot copy the files this way.
+///
+/// NB: This method is blocking, and should be called on a blocking thread.
+///
+#[cfg(target_os = "linux")]
+pub fn offloaded_copy(src: &File, dest: &File) -> io::Result<u64> {
+  use std::os::unix::io::AsRawFd;
+
+  let len = src.metadata()?.len();
+  let mut copied = 0;
+  while copied < len {
+    // Limit each call to 1 GiB, since the length is a `size_t` and copies may be partial anyway.
+    let chunk_len = (len - copied).min(1 << 30) as usize;
+    // SAFETY: both file descriptors are owned by open files for the duration of the call, and null
+    // offsets cause the offsets of the files to be used (and updated).
+    let res = unsafe {
+      libc::copy_file_range(
+        src.as_raw_fd(),
+        std::ptr::null_mut(),
+        dest.as_raw_fd(),
+        std::ptr::null_mut(),
+        chunk_len,
+        0,
+      )
+    };
+    if res < 0 {
+      let e = io::Error::last_os_error();
+      return match e.raw_os_error() {
+        Some(libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL) if copied == 0 => {
+          Err(io::Error::new(io::ErrorKind::Unsupported, e))
+        }
+        _ => Err(e),
+      };
+    }
+    if res == 0 {
+      // The source was truncated concurrently.
+      break;
+    }
+    copied += res as u64;
+  }
+  Ok(copied)
+}
+
+#[cfg(target_os = "macos")]
+pub fn offloaded_copy(src: &File, dest: &File) -> io::Result<u64> {
+  use std::os::unix::io::AsRawFd;
+
+  // SAFETY: both file descriptors are owned by open files for the duration of the call, and no
+  // copy state is used.
+  let res = unsafe {
+    libc::fcopyfile(
+      src.as_raw_fd(),
+      dest.as_raw_fd(),
+      std::ptr::null_mut(),
+      libc::COPYFILE_DATA,
+    )
+  };
+  if res != 0 {
+    let e = io::Error::last_os_error();
+    return match e.raw_os_error() {
+      Some(libc::ENOTSUP) => Err(io::Error::new(io::ErrorKind::Unsupported, e)),
+      _ => Err(e),
+    };
+  }
+  Ok(src.metadata()?.len())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn offloaded_copy(_src: &File, _dest: &File) -> io::Result<u64> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "Copying files within the kernel is not supported on this platform.",
+  ))
+}
+
+///
+/// True if the given error (from creating an entry in the given directory) indicates that the
+/// directory cannot hold any more entries: either because it has reached a limit on its number of
+/// entries, or because its index is full, which some filesystems report as being out of space
+/// while the filesystem itself still has space available.
+///
+#[cfg(unix)]
+pub fn is_directory_full(e: &io::Error, dir: &Path) -> bool {
+  match e.raw_os_error() {
+    Some(libc::EMLINK) => true,
+    Some(libc::ENOSPC) => available_bytes(dir).map_or(false, |available| available > 0),
+    _ => false,
+  }
+}
+
+///
+/// NB: Directories have no limit on their number of entries on Windows, so an error there means
+/// that the volume is full.
+///
+#[cfg(windows)]
+pub fn is_directory_full(_e: &io::Error, _dir: &Path) -> bool {
+  false
+}
+
+///
+/// Returns true if the given path is on a network filesystem (such as NFS or SMB), which may not
+/// provide the consistency guarantees which the store otherwise relies on.
+///
+#[cfg(target_os = "linux")]
+pub fn is_network_filesystem(path: &Path) -> Result<bool, String> {
+  use std::os::unix::ffi::OsStrExt;
+
+  // See `man 2 statfs`.
+  const NETWORK_FILESYSTEM_TYPES: &[u32] = &[
+    0x6969,     // NFS_SUPER_MAGIC
+    0x517b,     // SMB_SUPER_MAGIC
+    0xfe534d42, // SMB2_MAGIC_NUMBER
+    0xff534d42, // CIFS_MAGIC_NUMBER
+    0x5346414f, // AFS_SUPER_MAGIC
+    0x73757245, // CODA_SUPER_MAGIC
+    0x564c,     // NCP_SUPER_MAGIC
+    0x0bd00bd0, // LUSTRE_SUPER_MAGIC
+    0x47504653, // GPFS_SUPER_MAGIC
+    0x00c36400, // CEPH_SUPER_MAGIC
+  ];
+
+  let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+    .map_err(|e| format!("Invalid path {}: {e}", path.display()))?;
+  let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+  // SAFETY: `c_path` is a valid nul-terminated string, and `stat` is only read if the call succeeds.
+  let stat = unsafe {
+    if libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+      return Err(format!(
+        "Failed to determine the filesystem type of {}: {}",
+        path.display(),
+        io::Error::last_os_error()
+      ));
+    }
+    stat.assume_init()
+  };
+  // NB: The width and signedness of `f_type` vary by platform, but the magic numbers fit in 32 bits.
+  Ok(NETWORK_FILESYSTEM_TYPES.contains(&(stat.f_type as u32)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_network_filesystem(_path: &Path) -> Result<bool, String> {
+  Ok(false)
+}
+
+///
+/// Returns the number of bytes which are available to unprivileged users on the filesystem which
+/// contains the given path.
+///
+#[cfg(unix)]
+pub fn available_bytes(path: &Path) -> Result<u64, String> {
+  use std::os::unix::ffi::OsStrExt;
+
+  let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+    .map_err(|e| format!("Invalid path {}: {e}", path.display()))?;
+  let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+  // SAFETY: `c_path` is a valid nul-terminated string, and `stat` is only read if the call succeeds.
+  let stat = unsafe {
+    if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+      return Err(format!(
+        "Failed to determine the available space of {}: {}",
+        path.display(),
+        io::Error::last_os_error()
+      ));
+    }
+    stat.assume_init()
+  };
+  // NB: The widths of these fields vary by platform.
+  #[allow(clippy::useless_conversion)]
+  Ok(u64::from(stat.f_bavail).saturating_mul(u64::from(stat.f_frsize)))
+}
+
+#[cfg(windows)]
+pub fn available_bytes(path: &Path) -> Result<u64, String> {
+  Err(format!(
+    "Determining the available space of {} is not supported on Windows.",
+    path.display()
+  ))
+}
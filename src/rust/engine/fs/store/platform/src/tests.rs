@@ -0,0 +1,103 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use tempfile::TempDir;
+
+use crate as platform;
+
+#[tokio::test]
+async fn read_only_files_can_be_replaced_and_removed() {
+  let dir = TempDir::new().unwrap();
+  let path = dir.path().join("file");
+  std::fs::write(&path, "original").unwrap();
+  assert!(!platform::is_read_only(&std::fs::metadata(&path).unwrap()));
+
+  platform::make_read_only(&path).await.unwrap();
+  assert!(platform::is_read_only(&std::fs::metadata(&path).unwrap()));
+
+  let replacement = dir.path().join("replacement");
+  std::fs::write(&replacement, "replacement").unwrap();
+  platform::replace(&replacement, &path).await.unwrap();
+  assert_eq!(std::fs::read_to_string(&path).unwrap(), "replacement");
+  assert!(!replacement.exists());
+
+  platform::make_read_only(&path).await.unwrap();
+  platform::remove_read_only(&path).await.unwrap();
+  assert!(!path.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn available_bytes() {
+  let dir = TempDir::new().unwrap();
+  assert!(platform::available_bytes(dir.path()).unwrap() > 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_full() {
+  let dir = TempDir::new().unwrap();
+  let error = |errno| std::io::Error::from_raw_os_error(errno);
+  assert!(platform::is_directory_full(
+    &error(libc::EMLINK),
+    dir.path()
+  ));
+  // The filesystem of the temporary directory has space available, so running out of space must
+  // have been specific to the directory.
+  assert!(platform::is_directory_full(
+    &error(libc::ENOSPC),
+    dir.path()
+  ));
+  assert!(!platform::is_directory_full(
+    &error(libc::EACCES),
+    dir.path()
+  ));
+}
+
+#[test]
+fn offloaded_copy() {
+  let dir = TempDir::new().unwrap();
+  let src = dir.path().join("src");
+  let content = "abc".repeat(100_000);
+  std::fs::write(&src, &content).unwrap();
+  let dest = dir.path().join("dest");
+  let copied = platform::offloaded_copy(
+    &std::fs::File::open(&src).unwrap(),
+    &std::fs::File::create(&dest).unwrap(),
+  );
+  match copied {
+    Ok(len) => {
+      assert_eq!(len, content.len() as u64);
+      assert_eq!(std::fs::read_to_string(&dest).unwrap(), content);
+    }
+    // The filesystem of the temporary directory may not support copying within the kernel.
+    Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported, "{e}"),
+  }
+}
+
+#[test]
+fn exclusive_locks_exclude_shared_locks() {
+  let dir = TempDir::new().unwrap();
+  let path = dir.path().join("lock");
+  let open = || {
+    std::fs::OpenOptions::new()
+      .create(true)
+      .write(true)
+      .open(&path)
+      .unwrap()
+  };
+  let (first, second) = (open(), open());
+
+  platform::lock_shared(&first).unwrap();
+  platform::lock_shared(&second).unwrap();
+  assert!(!platform::try_lock_exclusive(&first).unwrap());
+
+  // Closing the second file releases its lock, after which the first lock can be converted.
+  drop(second);
+  assert!(platform::try_lock_exclusive(&first).unwrap());
+  assert!(!platform::try_lock_exclusive(&open()).unwrap());
+
+  // And converted back again.
+  platform::lock_shared(&first).unwrap();
+  let third = open();
+  platform::lock_shared(&third).unwrap();
+}
@@ -0,0 +1,524 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use fs::{directory, DigestTrie, DirectoryDigest, RelativePath, SymlinkBehavior, TypedPath};
+use hashing::{Digest, DigestFunction, Fingerprint, WriterHasher, EMPTY_DIGEST};
+use parking_lot::Mutex;
+use tempfile::NamedTempFile;
+
+use crate::local::ByteStore;
+use crate::{EntryType, Snapshot, Store, StoreError};
+
+///
+/// The formats which a Directory may be exported to with `Store::export_archive`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+  TarZst,
+  Zip,
+}
+
+impl FromStr for ArchiveFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "tar.zst" => Ok(Self::TarZst),
+      "zip" => Ok(Self::Zip),
+      s => Err(format!(
+        "Unsupported archive format `{s}`: expected one of `tar.zst` or `zip`."
+      )),
+    }
+  }
+}
+
+impl fmt::Display for ArchiveFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::TarZst => write!(f, "tar.zst"),
+      Self::Zip => write!(f, "zip"),
+    }
+  }
+}
+
+enum ArchiveEntry {
+  Directory(PathBuf),
+  File(PathBuf, Digest, bool),
+  Symlink(PathBuf, PathBuf),
+}
+
+///
+/// Writes entries to an archive. All entries are written with fixed ownership and timestamps, so
+/// that archives of the same Directory are identical.
+///
+enum ArchiveWriter<W: Write + Seek> {
+  TarZst(tar::Builder<zstd::stream::write::Encoder<'static, W>>),
+  Zip(zip::ZipWriter<W>),
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+  fn new(format: ArchiveFormat, writer: W) -> io::Result<Self> {
+    Ok(match format {
+      ArchiveFormat::TarZst => Self::TarZst(tar::Builder::new(zstd::stream::write::Encoder::new(
+        writer,
+        zstd::DEFAULT_COMPRESSION_LEVEL,
+      )?)),
+      ArchiveFormat::Zip => Self::Zip(zip::ZipWriter::new(writer)),
+    })
+  }
+
+  fn tar_header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+  }
+
+  fn zip_options(mode: u32) -> zip::write::FileOptions {
+    zip::write::FileOptions::default()
+      .compression_method(zip::CompressionMethod::Deflated)
+      .last_modified_time(zip::DateTime::default())
+      .unix_permissions(mode)
+  }
+
+  fn zip_name(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} cannot be stored in a zip file.", path.display()),
+      )
+    })
+  }
+
+  fn append_directory(&mut self, path: &Path) -> io::Result<()> {
+    match self {
+      Self::TarZst(builder) => {
+        let mut header = Self::tar_header(tar::EntryType::Directory, 0o755, 0);
+        builder.append_data(&mut header, path, io::empty())
+      }
+      Self::Zip(writer) => writer
+        .add_directory(Self::zip_name(path)?, Self::zip_options(0o755))
+        .map_err(io::Error::from),
+    }
+  }
+
+  fn append_file(&mut self, path: &Path, is_executable: bool, bytes: &[u8]) -> io::Result<()> {
+    let mode = if is_executable { 0o755 } else { 0o644 };
+    match self {
+      Self::TarZst(builder) => {
+        let mut header = Self::tar_header(tar::EntryType::Regular, mode, bytes.len() as u64);
+        builder.append_data(&mut header, path, bytes)
+      }
+      Self::Zip(writer) => {
+        writer.start_file(Self::zip_name(path)?, Self::zip_options(mode))?;
+        writer.write_all(bytes)
+      }
+    }
+  }
+
+  fn append_symlink(&mut self, path: &Path, target: &Path) -> io::Result<()> {
+    match self {
+      Self::TarZst(builder) => {
+        let mut header = Self::tar_header(tar::EntryType::Symlink, 0o777, 0);
+        builder.append_link(&mut header, path, target)
+      }
+      Self::Zip(writer) => writer
+        .add_symlink(
+          Self::zip_name(path)?,
+          Self::zip_name(target)?,
+          Self::zip_options(0o777),
+        )
+        .map_err(io::Error::from),
+    }
+  }
+
+  fn finish(self) -> io::Result<W> {
+    match self {
+      Self::TarZst(builder) => builder.into_inner()?.finish(),
+      Self::Zip(mut writer) => writer.finish().map_err(io::Error::from),
+    }
+  }
+}
+
+impl Store {
+  ///
+  /// Writes the given Directory to `writer` as an archive of the given format, preserving the
+  /// executable bit of files and symlinks. The content of each file is streamed from the store
+  /// into the archive (backfilling from the remote if necessary), without the Directory being
+  /// materialized on disk.
+  ///
+  /// Returns the writer once the archive has been completed.
+  ///
+  pub async fn export_archive<W: Write + Seek + Send + 'static>(
+    &self,
+    digest: DirectoryDigest,
+    format: ArchiveFormat,
+    writer: W,
+  ) -> Result<W, StoreError> {
+    let tree = self.load_digest_trie(digest).await?;
+    let mut entries = Vec::new();
+    tree.walk(SymlinkBehavior::Aware, &mut |path, entry| match entry {
+      // The root is implied by the archive itself.
+      directory::Entry::Directory(_) if path.as_os_str().is_empty() => (),
+      directory::Entry::Directory(_) => entries.push(ArchiveEntry::Directory(path.to_owned())),
+      directory::Entry::File(f) => entries.push(ArchiveEntry::File(
+        path.to_owned(),
+        f.digest(),
+        f.is_executable(),
+      )),
+      directory::Entry::Symlink(s) => entries.push(ArchiveEntry::Symlink(
+        path.to_owned(),
+        s.target().to_owned(),
+      )),
+    });
+
+    let archive_err =
+      |e: io::Error| -> StoreError { format!("Failed to write {format} archive: {e}").into() };
+    let archive = Arc::new(Mutex::new(
+      ArchiveWriter::new(format, writer).map_err(archive_err)?,
+    ));
+    for entry in entries {
+      match entry {
+        ArchiveEntry::Directory(path) => archive
+          .lock()
+          .append_directory(&path)
+          .map_err(archive_err)?,
+        ArchiveEntry::File(path, file_digest, is_executable) => {
+          let archive = archive.clone();
+          self
+            .load_file_bytes_with(file_digest, move |bytes| {
+              archive.lock().append_file(&path, is_executable, bytes)
+            })
+            .await?
+            .map_err(archive_err)?
+        }
+        ArchiveEntry::Symlink(path, target) => archive
+          .lock()
+          .append_symlink(&path, &target)
+          .map_err(archive_err)?,
+      }
+    }
+
+    let archive = Arc::try_unwrap(archive)
+      .unwrap_or_else(|_| panic!("No other references to the archive should remain."))
+      .into_inner();
+    archive.finish().map_err(archive_err)
+  }
+}
+
+enum ImportedMember {
+  Directory,
+  File(Digest, bool),
+  Symlink(PathBuf),
+}
+
+///
+/// The members of an archive which has been read, and the content of its files, which has been
+/// hashed but not yet stored.
+///
+#[derive(Default)]
+struct ImportedArchive {
+  // NB: Later members of an archive replace earlier members at the same path, as when extracting.
+  members: BTreeMap<PathBuf, ImportedMember>,
+  small_files: Vec<(Fingerprint, Bytes)>,
+  large_files: Vec<(Digest, NamedTempFile)>,
+}
+
+impl ImportedArchive {
+  ///
+  /// Reads the given tar.zst archive, hashing the content of each file as it is read. Small files
+  /// are buffered in memory, while files which will be stored in the FSDB are spooled to temporary
+  /// files.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn read_tar_zst<R: Read>(local: &ByteStore, reader: R) -> Result<ImportedArchive, String> {
+    let mut imported = ImportedArchive::default();
+    let decoder = zstd::stream::read::Decoder::new(reader)
+      .map_err(|e| format!("Failed to decompress archive: {e}"))?;
+    let mut archive = tar::Archive::new(decoder);
+    let entries = archive
+      .entries()
+      .map_err(|e| format!("Failed to read archive: {e}"))?;
+    for entry in entries {
+      let mut entry = entry.map_err(|e| format!("Failed to read archive member: {e}"))?;
+      let entry_type = entry.header().entry_type();
+      // Global pax headers hold metadata which applies to the whole archive, rather than being
+      // members. (Per-member pax and GNU headers are applied by `tar` itself.)
+      if entry_type == tar::EntryType::XGlobalHeader {
+        continue;
+      }
+      let raw_path = entry
+        .path()
+        .map_err(|e| format!("Failed to read archive member path: {e}"))?
+        .into_owned();
+      let path = match Self::member_path(&raw_path)? {
+        Some(path) => path,
+        None => continue,
+      };
+      let member = match entry_type {
+        tar::EntryType::Directory => ImportedMember::Directory,
+        tar::EntryType::Regular | tar::EntryType::Continuous => {
+          let mode = entry
+            .header()
+            .mode()
+            .map_err(|e| format!("Failed to read mode of {}: {e}", path.display()))?;
+          let size = entry.size();
+          let digest = imported
+            .read_file(local, &mut entry, size)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+          ImportedMember::File(digest, mode & 0o111 != 0)
+        }
+        tar::EntryType::Symlink => {
+          let target = entry
+            .link_name()
+            .map_err(|e| format!("Failed to read target of {}: {e}", path.display()))?
+            .ok_or_else(|| format!("Symlink {} had no target.", path.display()))?
+            .into_owned();
+          ImportedMember::Symlink(Self::symlink_target(&path, target)?)
+        }
+        tar::EntryType::Link => {
+          // A hard link names an earlier member of the archive, whose content it shares.
+          let raw_target = entry
+            .link_name()
+            .map_err(|e| format!("Failed to read target of {}: {e}", path.display()))?
+            .ok_or_else(|| format!("Hard link {} had no target.", path.display()))?
+            .into_owned();
+          let target = Self::member_path(&raw_target)?;
+          match target.and_then(|target| imported.members.get(&target)) {
+            Some(ImportedMember::File(digest, is_executable)) => {
+              ImportedMember::File(*digest, *is_executable)
+            }
+            _ => {
+              return Err(format!(
+                "Hard link {} does not refer to an earlier file in the archive: {}",
+                path.display(),
+                raw_target.display()
+              ))
+            }
+          }
+        }
+        t => {
+          return Err(format!(
+            "Archive member {} has an unsupported type: {t:?}",
+            path.display()
+          ))
+        }
+      };
+      imported.members.insert(path, member);
+    }
+    Ok(imported)
+  }
+
+  ///
+  /// Reads the given zip archive, as for `read_tar_zst`.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn read_zip<R: Read + Seek>(local: &ByteStore, reader: R) -> Result<ImportedArchive, String> {
+    let mut imported = ImportedArchive::default();
+    let mut archive =
+      zip::ZipArchive::new(reader).map_err(|e| format!("Failed to read archive: {e}"))?;
+    for i in 0..archive.len() {
+      let mut file = archive
+        .by_index(i)
+        .map_err(|e| format!("Failed to read archive member: {e}"))?;
+      let raw_path = PathBuf::from(file.name());
+      let path = match Self::member_path(&raw_path)? {
+        Some(path) => path,
+        None => continue,
+      };
+      let mode = file.unix_mode().unwrap_or(0o644);
+      let member = if file.is_dir() {
+        ImportedMember::Directory
+      } else if mode & 0o170000 == 0o120000 {
+        let mut target = String::new();
+        file
+          .read_to_string(&mut target)
+          .map_err(|e| format!("Failed to read target of {}: {e}", path.display()))?;
+        ImportedMember::Symlink(Self::symlink_target(&path, PathBuf::from(target))?)
+      } else {
+        let size = file.size();
+        let digest = imported
+          .read_file(local, &mut file, size)
+          .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        ImportedMember::File(digest, mode & 0o111 != 0)
+      };
+      imported.members.insert(path, member);
+    }
+    Ok(imported)
+  }
+
+  ///
+  /// Validates the target of the symlink at the given path, failing for targets which are
+  /// absolute, or which would resolve outside of the archive.
+  ///
+  fn symlink_target(path: &Path, target: PathBuf) -> Result<PathBuf, String> {
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    if target.is_absolute() || RelativePath::new(parent.join(&target)).is_err() {
+      return Err(format!(
+        "Symlink {} has a target outside of the archive: {}",
+        path.display(),
+        target.display()
+      ));
+    }
+    Ok(target)
+  }
+
+  ///
+  /// Normalizes the path of an archive member, returning None for the root of the archive, and
+  /// failing for paths which would escape it.
+  ///
+  fn member_path(raw_path: &Path) -> Result<Option<PathBuf>, String> {
+    let path = RelativePath::new(raw_path)
+      .map_err(|e| format!("Archive member {} is invalid: {e}", raw_path.display()))?;
+    if path.as_os_str().is_empty() {
+      return Ok(None);
+    }
+    Ok(Some(path.to_path_buf()))
+  }
+
+  fn read_file(
+    &mut self,
+    local: &ByteStore,
+    reader: &mut impl Read,
+    size: u64,
+  ) -> io::Result<Digest> {
+    let digest_function: DigestFunction = local.digest_function();
+    if local.should_use_fsdb(EntryType::File, size as usize) {
+      let mut file = NamedTempFile::new()?;
+      let mut hasher = WriterHasher::new_with(digest_function, file.as_file_mut());
+      io::copy(reader, &mut hasher)?;
+      let (digest, _) = hasher.finish();
+      self.large_files.push((digest, file));
+      Ok(digest)
+    } else {
+      let mut hasher = WriterHasher::new_with(digest_function, Vec::with_capacity(size as usize));
+      io::copy(reader, &mut hasher)?;
+      let (digest, bytes) = hasher.finish();
+      if digest != EMPTY_DIGEST {
+        self.small_files.push((digest.hash, Bytes::from(bytes)));
+      }
+      Ok(digest)
+    }
+  }
+}
+
+impl Store {
+  ///
+  /// Reads an archive of the given format, and stores its content as a Snapshot, without the
+  /// archive being extracted to disk. The content of each file is hashed as it is read, and the
+  /// executable bit of files and symlinks are preserved.
+  ///
+  /// Symlinks whose targets are absolute or outside of the archive are rejected, and tar hard
+  /// links are imported as copies of the files which they link to.
+  ///
+  pub async fn import_archive<R: Read + Seek + Send + 'static>(
+    &self,
+    format: ArchiveFormat,
+    reader: R,
+  ) -> Result<Snapshot, StoreError> {
+    match format {
+      ArchiveFormat::TarZst => self.import_tar_zst_archive(reader).await,
+      ArchiveFormat::Zip => {
+        self
+          .import_with(format, move |local| {
+            ImportedArchive::read_zip(local, reader)
+          })
+          .await
+      }
+    }
+  }
+
+  ///
+  /// As `import_archive`, for a tar.zst archive, which (unlike a zip archive) is read
+  /// sequentially, so that it may be imported from a stream.
+  ///
+  pub async fn import_tar_zst_archive<R: Read + Send + 'static>(
+    &self,
+    reader: R,
+  ) -> Result<Snapshot, StoreError> {
+    self
+      .import_with(ArchiveFormat::TarZst, move |local| {
+        ImportedArchive::read_tar_zst(local, reader)
+      })
+      .await
+  }
+
+  async fn import_with(
+    &self,
+    format: ArchiveFormat,
+    read: impl FnOnce(&ByteStore) -> Result<ImportedArchive, String> + Send + 'static,
+  ) -> Result<Snapshot, StoreError> {
+    let local = self.local.clone();
+    let imported = self
+      .local
+      .executor()
+      .spawn_blocking(
+        move || read(&local),
+        |e| Err(format!("Archive reading task failed: {e}")),
+      )
+      .await
+      .map_err(|e| format!("Failed to import {format} archive: {e}"))?;
+
+    self
+      .store_file_bytes_batch(imported.small_files, true)
+      .await?;
+    futures::future::try_join_all(
+      imported
+        .large_files
+        .iter()
+        .map(|(digest, file)| async move {
+          // The temporary file will not be modified, so it may be linked into the store.
+          let stored_digest = self.store_file(true, true, file.path().to_owned()).await?;
+          if stored_digest != *digest {
+            return Err(format!(
+              "Archive member content changed while being stored: expected {digest:?}, got \
+              {stored_digest:?}"
+            ));
+          }
+          Ok(())
+        }),
+    )
+    .await?;
+
+    let mut typed_paths = Vec::with_capacity(imported.members.len());
+    let mut file_digests = HashMap::with_capacity(imported.members.len());
+    for (path, member) in &imported.members {
+      match member {
+        ImportedMember::Directory => {
+          typed_paths.push(TypedPath::Dir(path));
+          file_digests.insert(path.clone(), EMPTY_DIGEST);
+        }
+        ImportedMember::File(digest, is_executable) => {
+          typed_paths.push(TypedPath::File {
+            path,
+            is_executable: *is_executable,
+          });
+          file_digests.insert(path.clone(), *digest);
+        }
+        ImportedMember::Symlink(target) => {
+          typed_paths.push(TypedPath::Link { path, target });
+          file_digests.insert(path.clone(), EMPTY_DIGEST);
+        }
+      }
+    }
+    let tree = DigestTrie::from_unique_paths(typed_paths, &file_digests)?;
+    let digest = self.record_digest_trie(tree.clone(), true).await?;
+    Ok(Snapshot {
+      digest: digest.as_digest(),
+      tree,
+    })
+  }
+}
@@ -0,0 +1,383 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+
+use fs::DirectoryDigest;
+use protos::gen::build::bazel::remote::execution::v2 as remexec;
+use tempfile::TempDir;
+use testutil::data::{TestData, TestDirectory};
+
+use crate::{ArchiveFormat, Store};
+
+#[derive(Debug, Eq, PartialEq)]
+enum Exported {
+  Directory,
+  File(Vec<u8>, u32),
+  Symlink(PathBuf),
+}
+
+///
+/// Stores and returns a Directory containing an executable file, a subdirectory, and a symlink.
+///
+async fn setup(store: &Store) -> DirectoryDigest {
+  let inner = TestDirectory::containing_roland();
+  let outer = remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: "run.sh".to_owned(),
+      digest: Some((&TestData::catnip().digest()).into()),
+      is_executable: true,
+      ..remexec::FileNode::default()
+    }],
+    directories: vec![remexec::DirectoryNode {
+      name: "cats".to_owned(),
+      digest: Some((&inner.digest()).into()),
+    }],
+    symlinks: vec![remexec::SymlinkNode {
+      name: "link".to_owned(),
+      target: "cats/roland.ext".to_owned(),
+      ..remexec::SymlinkNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+
+  for data in [TestData::roland(), TestData::catnip()] {
+    store
+      .store_file_bytes(data.bytes(), false)
+      .await
+      .expect("Error saving file bytes");
+  }
+  store
+    .record_directory(&inner.directory(), false)
+    .await
+    .expect("Error saving Directory");
+  let digest = store
+    .record_directory(&outer, false)
+    .await
+    .expect("Error saving Directory");
+  DirectoryDigest::from_persisted_digest(digest)
+}
+
+fn expected() -> BTreeMap<PathBuf, Exported> {
+  vec![
+    (PathBuf::from("cats"), Exported::Directory),
+    (
+      PathBuf::from("cats/roland.ext"),
+      Exported::File(TestData::roland().bytes().to_vec(), 0o644),
+    ),
+    (
+      PathBuf::from("link"),
+      Exported::Symlink(PathBuf::from("cats/roland.ext")),
+    ),
+    (
+      PathBuf::from("run.sh"),
+      Exported::File(TestData::catnip().bytes().to_vec(), 0o755),
+    ),
+  ]
+  .into_iter()
+  .collect()
+}
+
+#[tokio::test]
+async fn export_tar_zst() {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+
+  let archive = store
+    .export_archive(digest, ArchiveFormat::TarZst, Cursor::new(Vec::new()))
+    .await
+    .unwrap()
+    .into_inner();
+
+  let mut exported = BTreeMap::new();
+  let mut tar = tar::Archive::new(zstd::stream::read::Decoder::new(&archive[..]).unwrap());
+  for entry in tar.entries().unwrap() {
+    let mut entry = entry.unwrap();
+    let path = entry.path().unwrap().into_owned();
+    let value = match entry.header().entry_type() {
+      tar::EntryType::Directory => Exported::Directory,
+      tar::EntryType::Regular => {
+        let mode = entry.header().mode().unwrap();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        Exported::File(content, mode)
+      }
+      tar::EntryType::Symlink => {
+        Exported::Symlink(entry.link_name().unwrap().unwrap().into_owned())
+      }
+      t => panic!("Unexpected entry type {t:?}"),
+    };
+    exported.insert(path, value);
+  }
+  assert_eq!(exported, expected());
+}
+
+#[tokio::test]
+async fn export_zip() {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+
+  let archive = store
+    .export_archive(digest, ArchiveFormat::Zip, Cursor::new(Vec::new()))
+    .await
+    .unwrap();
+
+  let mut exported = BTreeMap::new();
+  let mut zip = zip::ZipArchive::new(archive).unwrap();
+  for i in 0..zip.len() {
+    let mut entry = zip.by_index(i).unwrap();
+    let mode = entry.unix_mode().unwrap();
+    let mut content = Vec::new();
+    entry.read_to_end(&mut content).unwrap();
+    let path = PathBuf::from(entry.name().trim_end_matches('/'));
+    let value = if entry.is_dir() {
+      Exported::Directory
+    } else if mode & 0o170000 == 0o120000 {
+      Exported::Symlink(PathBuf::from(String::from_utf8(content).unwrap()))
+    } else {
+      Exported::File(content, mode & 0o777)
+    };
+    exported.insert(path, value);
+  }
+  assert_eq!(exported, expected());
+}
+
+#[tokio::test]
+async fn export_is_deterministic() {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+
+  for format in [ArchiveFormat::TarZst, ArchiveFormat::Zip] {
+    let export = || async {
+      store
+        .export_archive(digest.clone(), format, Cursor::new(Vec::new()))
+        .await
+        .unwrap()
+        .into_inner()
+    };
+    assert_eq!(export().await, export().await);
+  }
+}
+
+#[test]
+fn parse_format() {
+  assert_eq!(
+    "tar.zst".parse::<ArchiveFormat>(),
+    Ok(ArchiveFormat::TarZst)
+  );
+  assert_eq!("zip".parse::<ArchiveFormat>(), Ok(ArchiveFormat::Zip));
+  assert!("tar.gz".parse::<ArchiveFormat>().is_err());
+}
+
+#[tokio::test]
+async fn import_roundtrip() {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+
+  for format in [ArchiveFormat::TarZst, ArchiveFormat::Zip] {
+    let mut archive = store
+      .export_archive(digest.clone(), format, Cursor::new(Vec::new()))
+      .await
+      .unwrap();
+    archive.set_position(0);
+
+    // Import into an empty store, so that all content must come from the archive.
+    let import_dir = TempDir::new().unwrap();
+    let import_store =
+      Store::local_only(task_executor::Executor::new(), import_dir.path()).unwrap();
+    let snapshot = import_store.import_archive(format, archive).await.unwrap();
+    assert_eq!(snapshot.digest, digest.as_digest());
+    assert_eq!(
+      import_store
+        .load_file_bytes_with(TestData::catnip().digest(), |b| b.to_vec())
+        .await
+        .unwrap(),
+      TestData::catnip().bytes().to_vec()
+    );
+  }
+}
+
+#[tokio::test]
+async fn import_large_file() {
+  let large = TestData::new(&"abcdefgh".repeat(128 * 1024));
+  let directory = remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: "large".to_owned(),
+      digest: Some((&large.digest()).into()),
+      ..remexec::FileNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  store.store_file_bytes(large.bytes(), false).await.unwrap();
+  let digest = store.record_directory(&directory, false).await.unwrap();
+  let mut archive = store
+    .export_archive(
+      DirectoryDigest::from_persisted_digest(digest),
+      ArchiveFormat::TarZst,
+      Cursor::new(Vec::new()),
+    )
+    .await
+    .unwrap();
+  archive.set_position(0);
+
+  let import_dir = TempDir::new().unwrap();
+  let import_store = Store::local_only(task_executor::Executor::new(), import_dir.path()).unwrap();
+  let snapshot = import_store
+    .import_archive(ArchiveFormat::TarZst, archive)
+    .await
+    .unwrap();
+  assert_eq!(snapshot.digest, digest);
+  assert_eq!(
+    import_store
+      .load_file_bytes_with(large.digest(), |b| b.to_vec())
+      .await
+      .unwrap(),
+    large.bytes().to_vec()
+  );
+}
+
+#[tokio::test]
+async fn import_rejects_escaping_paths() {
+  let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+  writer
+    .start_file("../escaped", zip::write::FileOptions::default())
+    .unwrap();
+  writer.write_all(b"content").unwrap();
+  let mut archive = writer.finish().unwrap();
+  archive.set_position(0);
+
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let err = store
+    .import_archive(ArchiveFormat::Zip, archive)
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{err}").contains("escape the root"),
+    "Unexpected error: {err}"
+  );
+}
+
+///
+/// Builds a tar.zst archive by appending members with the given function.
+///
+fn tar_zst(append: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+  let mut builder = tar::Builder::new(Vec::new());
+  append(&mut builder);
+  zstd::stream::encode_all(&builder.into_inner().unwrap()[..], 0).unwrap()
+}
+
+fn tar_header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+  let mut header = tar::Header::new_ustar();
+  header.set_entry_type(entry_type);
+  header.set_mode(mode);
+  header.set_size(size);
+  header
+}
+
+#[tokio::test]
+async fn import_tar_zst_hard_links_and_global_headers() {
+  let roland = TestData::roland();
+  let archive = tar_zst(|builder| {
+    let comment = b"17 comment=hello\n";
+    let mut header = tar_header(tar::EntryType::XGlobalHeader, 0o644, comment.len() as u64);
+    builder
+      .append_data(&mut header, "pax_global_header", &comment[..])
+      .unwrap();
+    let mut header = tar_header(tar::EntryType::Regular, 0o755, roland.len() as u64);
+    builder
+      .append_data(&mut header, "original", &roland.bytes()[..])
+      .unwrap();
+    let mut header = tar_header(tar::EntryType::Link, 0o644, 0);
+    builder
+      .append_link(&mut header, "linked", "original")
+      .unwrap();
+  });
+
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  // NB: Chaining the archive onto an empty reader hides its `Seek` implementation.
+  let snapshot = store
+    .import_tar_zst_archive(io::empty().chain(Cursor::new(archive)))
+    .await
+    .unwrap();
+
+  let file = |name: &str| remexec::FileNode {
+    name: name.to_owned(),
+    digest: Some((&roland.digest()).into()),
+    is_executable: true,
+    ..remexec::FileNode::default()
+  };
+  let expected = remexec::Directory {
+    files: vec![file("linked"), file("original")],
+    ..remexec::Directory::default()
+  };
+  assert_eq!(
+    snapshot.digest,
+    store.record_directory(&expected, false).await.unwrap()
+  );
+}
+
+#[tokio::test]
+async fn import_rejects_hard_links_to_missing_files() {
+  let archive = tar_zst(|builder| {
+    let mut header = tar_header(tar::EntryType::Link, 0o644, 0);
+    builder
+      .append_link(&mut header, "linked", "missing")
+      .unwrap();
+  });
+
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let err = store
+    .import_tar_zst_archive(Cursor::new(archive))
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{err}").contains("does not refer to an earlier file"),
+    "Unexpected error: {err}"
+  );
+}
+
+#[tokio::test]
+async fn import_rejects_escaping_symlinks() {
+  for target in ["/etc/passwd", "../escaped", "dir/../../escaped"] {
+    let archive = tar_zst(|builder| {
+      let mut header = tar_header(tar::EntryType::Symlink, 0o777, 0);
+      builder.append_link(&mut header, "link", target).unwrap();
+    });
+
+    let dir = TempDir::new().unwrap();
+    let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+    let err = store
+      .import_archive(ArchiveFormat::TarZst, Cursor::new(archive))
+      .await
+      .unwrap_err();
+    assert!(
+      format!("{err}").contains("has a target outside of the archive"),
+      "Unexpected error for {target}: {err}"
+    );
+  }
+
+  // Targets which stay within the archive are preserved.
+  let archive = tar_zst(|builder| {
+    let mut header = tar_header(tar::EntryType::Symlink, 0o777, 0);
+    builder
+      .append_link(&mut header, "dir/link", "../sibling")
+      .unwrap();
+  });
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  store
+    .import_archive(ArchiveFormat::TarZst, Cursor::new(archive))
+    .await
+    .unwrap();
+}
@@ -0,0 +1,238 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use hashing::{Digest, DigestFunction, Hasher};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::FsdbShardLayout;
+
+const SCHEME: &str = "file://";
+
+/// The prefix of the names of the temporary files which blobs are written to before being renamed
+/// into place. Stale temporary files (left by interrupted writes) may be deleted freely.
+const TEMPFILE_PREFIX: &str = ".tmp";
+
+/// Reads and writes of blobs renew their leases at most this often, to avoid updating the mtime of
+/// a file (which is relatively expensive on network filesystems) on every access.
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const CHUNK_SIZE_BYTES: usize = 1024 * 1024;
+
+///
+/// A remote store which holds blobs as files in a plain directory, which is typically shared
+/// between machines via NFS or SMB, and is addressed as `file:///path/to/directory`.
+///
+/// Blobs are laid out in the same sharded structure as the large files of a local store (see
+/// `FsdbShardLayout`), with blobs hashed by functions other than SHA-256 under a subdirectory
+/// named for the function. Each blob is written to a temporary file which is then atomically
+/// renamed into place, so readers never observe partial blobs, and concurrent writers of a blob
+/// (whose content is identical) do not conflict.
+///
+/// The mtime of each file is its lease: it is renewed when the blob is read, written, or checked
+/// for, so that the directory can be pruned of blobs which have not been used recently (with
+/// `find -mtime`, for example) without any server.
+///
+#[derive(Debug)]
+pub(crate) struct DirectoryStore {
+  root: PathBuf,
+  layout: FsdbShardLayout,
+}
+
+impl DirectoryStore {
+  pub fn supports_address(address: &str) -> bool {
+    address.starts_with(SCHEME)
+  }
+
+  pub fn new(address: &str) -> Result<Self, String> {
+    let root = PathBuf::from(
+      address
+        .strip_prefix(SCHEME)
+        .ok_or_else(|| format!("A directory store address must begin with {SCHEME}: {address}"))?,
+    );
+    if !root.is_absolute() {
+      return Err(format!(
+        "A directory store address must name an absolute path (as in `file:///path`): {address}"
+      ));
+    }
+    Ok(Self {
+      root,
+      layout: FsdbShardLayout::default(),
+    })
+  }
+
+  fn path(&self, digest_function: DigestFunction, digest: Digest) -> PathBuf {
+    let root = match digest_function {
+      DigestFunction::Sha256 => self.root.clone(),
+      digest_function => self.root.join(digest_function.to_string()),
+    };
+    let hex = digest.hash.to_hex();
+    self.layout.shard_dir(&root, &hex).join(hex)
+  }
+
+  ///
+  /// Returns true if the given blob exists, and renews its lease.
+  ///
+  pub async fn exists(
+    &self,
+    digest_function: DigestFunction,
+    digest: Digest,
+  ) -> Result<bool, String> {
+    let path = self.path(digest_function, digest);
+    match tokio::fs::metadata(&path).await {
+      Ok(metadata) if metadata.len() == digest.size_bytes as u64 => {
+        renew_lease(path, metadata.modified().ok()).await;
+        Ok(true)
+      }
+      // A file of the wrong size cannot hold the blob, and will be replaced if it is written.
+      Ok(_) => Ok(false),
+      Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+      Err(e) => Err(format!("Failed to check for {path:?}: {e}")),
+    }
+  }
+
+  ///
+  /// Writes the given blob to the destination, and returns true, if it exists. Renews its lease.
+  ///
+  /// A file which does not hold the blob (because it was corrupted, for example) is removed, so
+  /// that it will be replaced when the blob is next written.
+  ///
+  pub async fn load<W: AsyncWrite + Unpin + ?Sized>(
+    &self,
+    digest_function: DigestFunction,
+    digest: Digest,
+    destination: &mut W,
+  ) -> Result<bool, String> {
+    let path = self.path(digest_function, digest);
+    let mut file = match tokio::fs::File::open(&path).await {
+      Ok(file) => file,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+      Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+    };
+    let modified = file
+      .metadata()
+      .await
+      .ok()
+      .and_then(|metadata| metadata.modified().ok());
+
+    let mut hasher = Hasher::new_with(digest_function);
+    let mut buf = vec![0; CHUNK_SIZE_BYTES];
+    loop {
+      let len = file
+        .read(&mut buf)
+        .await
+        .map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+      if len == 0 {
+        break;
+      }
+      hasher.update(&buf[..len]);
+      destination
+        .write_all(&buf[..len])
+        .await
+        .map_err(|e| format!("Failed to write {digest:?}: {e}"))?;
+    }
+    destination
+      .shutdown()
+      .await
+      .map_err(|e| format!("Failed to write {digest:?}: {e}"))?;
+
+    let actual_digest = hasher.finish();
+    if actual_digest != digest {
+      log::warn!(
+        "Removing {path:?} from the directory store, because it holds {actual_digest:?} rather \
+        than {digest:?}."
+      );
+      if let Err(e) = tokio::fs::remove_file(&path).await {
+        log::debug!("Failed to remove {path:?}: {e}");
+      }
+      return Ok(false);
+    }
+    renew_lease(path, modified).await;
+    Ok(true)
+  }
+
+  ///
+  /// Stores the given bytes (of the given length) as the given blob, unless it already exists.
+  ///
+  pub async fn store<ByteSource>(
+    &self,
+    digest_function: DigestFunction,
+    digest: Digest,
+    bytes: &ByteSource,
+  ) -> Result<(), String>
+  where
+    ByteSource: Fn(Range<usize>) -> Bytes,
+  {
+    if self.exists(digest_function, digest).await? {
+      return Ok(());
+    }
+
+    let path = self.path(digest_function, digest);
+    let dir = path.parent().unwrap();
+    tokio::fs::create_dir_all(dir)
+      .await
+      .map_err(|e| format!("Failed to create {dir:?}: {e}"))?;
+    // NB: The temporary file is in the same directory as the final file, so that the rename is
+    // atomic (including on network filesystems).
+    let tmp_path = dir.join(format!("{TEMPFILE_PREFIX}{}", Uuid::new_v4().simple()));
+    let result = write_file(&tmp_path, digest.size_bytes, bytes).await;
+    let result = match result {
+      Ok(()) => tokio::fs::rename(&tmp_path, &path)
+        .await
+        .map_err(|e| format!("Failed to rename {tmp_path:?} to {path:?}: {e}")),
+      Err(e) => Err(e),
+    };
+    if result.is_err() {
+      let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    result
+  }
+}
+
+async fn write_file<ByteSource>(path: &Path, len: usize, bytes: &ByteSource) -> Result<(), String>
+where
+  ByteSource: Fn(Range<usize>) -> Bytes,
+{
+  let mut file = tokio::fs::File::create(path)
+    .await
+    .map_err(|e| format!("Failed to create {path:?}: {e}"))?;
+  for start in (0..len).step_by(CHUNK_SIZE_BYTES) {
+    file
+      .write_all(&bytes(start..(start + CHUNK_SIZE_BYTES).min(len)))
+      .await
+      .map_err(|e| format!("Failed to write {path:?}: {e}"))?;
+  }
+  // Ensure that the content is durable before the file is renamed into place.
+  file
+    .sync_all()
+    .await
+    .map_err(|e| format!("Failed to sync {path:?}: {e}"))
+}
+
+///
+/// Renews the lease of the file at the given path (whose mtime is given, if known), unless it was
+/// renewed recently. Failures are logged, because the store may be read-only.
+///
+async fn renew_lease(path: PathBuf, modified: Option<SystemTime>) {
+  let renewed_recently = modified
+    .and_then(|modified| modified.elapsed().ok())
+    .map_or(false, |elapsed| elapsed < LEASE_RENEWAL_INTERVAL);
+  if renewed_recently {
+    return;
+  }
+  let result = tokio::task::spawn_blocking(move || {
+    fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::SymbolicNow)
+      .map_err(|e| format!("Failed to renew the lease of {path:?}: {e}"))
+  })
+  .await;
+  match result {
+    Ok(Ok(())) => (),
+    Ok(Err(e)) => log::debug!("{e}"),
+    Err(e) => log::debug!("Failed to renew a lease: {e}"),
+  }
+}
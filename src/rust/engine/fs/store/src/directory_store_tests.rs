@@ -0,0 +1,123 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use hashing::{Digest, DigestFunction};
+use tempfile::TempDir;
+use testutil::data::TestData;
+
+use crate::directory_store::DirectoryStore;
+
+fn new_store(dir: &Path) -> DirectoryStore {
+  DirectoryStore::new(&format!("file://{}", dir.display())).unwrap()
+}
+
+fn blob_path(dir: &Path, digest: Digest) -> PathBuf {
+  let hex = digest.hash.to_hex();
+  dir.join(&hex[..2]).join(hex)
+}
+
+#[tokio::test]
+async fn store_and_load() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+  let bytes = testdata.bytes();
+
+  assert!(!store
+    .exists(DigestFunction::Sha256, testdata.digest())
+    .await
+    .unwrap());
+  let mut destination = Vec::new();
+  assert!(!store
+    .load(DigestFunction::Sha256, testdata.digest(), &mut destination)
+    .await
+    .unwrap());
+
+  store
+    .store(DigestFunction::Sha256, testdata.digest(), &|range| {
+      bytes.slice(range)
+    })
+    .await
+    .unwrap();
+  // The blob is laid out in the same way as the large files of a local store.
+  assert_eq!(
+    std::fs::read(blob_path(dir.path(), testdata.digest())).unwrap(),
+    testdata.bytes()
+  );
+  assert!(store
+    .exists(DigestFunction::Sha256, testdata.digest())
+    .await
+    .unwrap());
+  assert!(store
+    .load(DigestFunction::Sha256, testdata.digest(), &mut destination)
+    .await
+    .unwrap());
+  assert_eq!(destination, testdata.bytes());
+}
+
+#[tokio::test]
+async fn other_digest_functions_are_namespaced() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let bytes = TestData::roland().bytes();
+  let digest = Digest::of_bytes_with(DigestFunction::Blake3, &bytes);
+
+  store
+    .store(DigestFunction::Blake3, digest, &|range| bytes.slice(range))
+    .await
+    .unwrap();
+  assert!(blob_path(&dir.path().join("blake3"), digest).exists());
+  assert!(!store.exists(DigestFunction::Sha256, digest).await.unwrap());
+  assert!(store.exists(DigestFunction::Blake3, digest).await.unwrap());
+}
+
+#[tokio::test]
+async fn reads_renew_leases() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+  let bytes = testdata.bytes();
+  store
+    .store(DigestFunction::Sha256, testdata.digest(), &|range| {
+      bytes.slice(range)
+    })
+    .await
+    .unwrap();
+
+  let path = blob_path(dir.path(), testdata.digest());
+  let a_day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+  fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(a_day_ago)).unwrap();
+
+  let mut destination = Vec::new();
+  assert!(store
+    .load(DigestFunction::Sha256, testdata.digest(), &mut destination)
+    .await
+    .unwrap());
+  let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+  assert!(mtime.elapsed().unwrap() < Duration::from_secs(60 * 60));
+}
+
+#[tokio::test]
+async fn corrupt_files_are_removed() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+  let path = blob_path(dir.path(), testdata.digest());
+  std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+  std::fs::write(&path, TestData::catnip().bytes()).unwrap();
+
+  let mut destination = Vec::new();
+  assert!(!store
+    .load(DigestFunction::Sha256, testdata.digest(), &mut destination)
+    .await
+    .unwrap());
+  assert!(!path.exists());
+}
+
+#[test]
+fn invalid_addresses() {
+  let err = DirectoryStore::new("file://relative/path").unwrap_err();
+  assert!(err.contains("absolute path"), "Bad error: {err}");
+}
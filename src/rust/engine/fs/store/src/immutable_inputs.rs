@@ -1,16 +1,17 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_oncecell::OnceCell;
-use fs::{DirectoryDigest, Permissions, RelativePath};
+use fs::{directory, DirectoryDigest, Permissions, RelativePath, SymlinkBehavior};
 use hashing::Digest;
 use parking_lot::Mutex;
 use tempfile::TempDir;
 
-use crate::{Store, StoreError};
+use crate::{platform, Store, StoreError};
 
 /// A symlink from a relative src to an absolute dst (outside of the workdir).
 #[derive(Debug)]
@@ -19,13 +20,31 @@ pub struct WorkdirSymlink {
   pub dst: PathBuf,
 }
 
+///
+/// How the directories of immutable inputs are materialized.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ImmutableInputsMode {
+  /// Each directory is materialized in full, with its own copy of each of its files.
+  #[default]
+  Materialize,
+  /// Each directory is materialized as a tree of symlinks into a cache of extracted files, which
+  /// is shared between all directories. Each file is extracted (and its digest validated) once,
+  /// however many directories contain it.
+  SymlinkFarm,
+}
+
 struct Inner {
   store: Store,
+  mode: ImmutableInputsMode,
   // The TempDir that digests are materialized in.
   workdir: TempDir,
   // A map from Digest to the location it has been materialized at. The OnceCell allows
   // for cooperation between threads attempting to create Digests.
   contents: Mutex<HashMap<Digest, Arc<OnceCell<PathBuf>>>>,
+  // In `ImmutableInputsMode::SymlinkFarm`, a map from file Digest and executable bit to the
+  // location the file has been extracted at.
+  files: Mutex<HashMap<(Digest, bool), Arc<OnceCell<PathBuf>>>>,
 }
 
 ///
@@ -36,14 +55,24 @@ pub struct ImmutableInputs(Arc<Inner>);
 
 impl ImmutableInputs {
   pub fn new(store: Store, base: &Path) -> Result<Self, String> {
+    Self::new_with_mode(store, base, ImmutableInputsMode::default())
+  }
+
+  pub fn new_with_mode(
+    store: Store,
+    base: &Path,
+    mode: ImmutableInputsMode,
+  ) -> Result<Self, String> {
     let workdir = tempfile::Builder::new()
       .prefix("immutable_inputs")
       .tempdir_in(base)
       .map_err(|e| format!("Failed to create temporary directory for immutable inputs: {e}"))?;
     Ok(Self(Arc::new(Inner {
       store,
+      mode,
       workdir,
       contents: Mutex::default(),
+      files: Mutex::default(),
     })))
   }
 
@@ -97,21 +126,167 @@ impl ImmutableInputs {
           )
         })?;
 
+        let dest = chroot.path().join(digest.hash.to_hex());
+        match self.0.mode {
+          ImmutableInputsMode::Materialize => {
+            self
+              .0
+              .store
+              .materialize_directory(
+                dest.clone(),
+                directory_digest,
+                false,
+                &BTreeSet::new(),
+                Permissions::ReadOnly,
+              )
+              .await?
+          }
+          ImmutableInputsMode::SymlinkFarm => {
+            self
+              .materialize_symlink_farm(dest.clone(), directory_digest)
+              .await?
+          }
+        }
+
+        // Now that we've successfully initialized the destination, forget the TempDir so that it
+        // is not cleaned up.
+        let _ = chroot.into_path();
+
+        Ok(dest)
+      })
+      .await
+      .cloned()
+  }
+
+  ///
+  /// Materializes the given directory at `dest` as read-only directories containing symlinks to
+  /// the extracted files in the shared cache.
+  ///
+  async fn materialize_symlink_farm(
+    &self,
+    dest: PathBuf,
+    directory_digest: DirectoryDigest,
+  ) -> Result<(), StoreError> {
+    let tree = self.0.store.load_digest_trie(directory_digest).await?;
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut symlinks = Vec::new();
+    tree.walk(SymlinkBehavior::Aware, &mut |path, entry| match entry {
+      directory::Entry::Directory(_) => dirs.push(dest.join(path)),
+      directory::Entry::File(f) => files.push((dest.join(path), f.digest(), f.is_executable())),
+      directory::Entry::Symlink(s) => symlinks.push((dest.join(path), s.target().to_owned())),
+    });
+
+    let targets = futures::future::try_join_all(
+      files
+        .iter()
+        .map(|(_, digest, is_executable)| self.path_for_file(*digest, *is_executable))
+        .collect::<Vec<_>>(),
+    )
+    .await?;
+    symlinks.extend(
+      files
+        .into_iter()
+        .zip(targets)
+        .map(|((path, _, _), target)| (path, target)),
+    );
+
+    self
+      .0
+      .store
+      .local
+      .executor()
+      .spawn_blocking(
+        move || -> io::Result<()> {
+          // The walk visits parents before their children.
+          for dir in &dirs {
+            std::fs::create_dir(dir)?;
+          }
+          for (path, target) in &symlinks {
+            platform::symlink(target, path)?;
+          }
+          for dir in dirs.iter().rev() {
+            platform::set_path_mode(dir, 0o555)?;
+          }
+          Ok(())
+        },
+        |e| Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+      )
+      .await
+      .map_err(|e| {
+        format!(
+          "Failed to materialize a symlink farm for immutable input digest {:?} at {}: {e}",
+          directory_digest.as_digest(),
+          dest.display()
+        )
+      })?;
+    Ok(())
+  }
+
+  ///
+  /// Returns an absolute Path to the extracted copy of the given file in the shared cache,
+  /// extracting it (and validating its digest) if it has not been already.
+  ///
+  async fn path_for_file(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+  ) -> Result<PathBuf, StoreError> {
+    let cell = self
+      .0
+      .files
+      .lock()
+      .entry((digest, is_executable))
+      .or_default()
+      .clone();
+
+    // As in `path_for_dir`, anonymous destination paths make initialization cancellation safe.
+    cell
+      .get_or_try_init(async {
+        let chroot = TempDir::new_in(self.0.workdir.path()).map_err(|e| {
+          format!(
+            "Failed to create a temporary directory for extraction of immutable input file \
+            {digest:?}: {e}"
+          )
+        })?;
+
         let dest = chroot.path().join(digest.hash.to_hex());
         self
           .0
           .store
-          .materialize_directory(
-            dest.clone(),
-            directory_digest,
-            false,
-            &BTreeSet::new(),
-            Permissions::ReadOnly,
-          )
+          .materialize_file(dest.clone(), digest, Permissions::ReadOnly, is_executable)
           .await?;
 
-        // Now that we've successfully initialized the destination, forget the TempDir so that it
-        // is not cleaned up.
+        // Every symlink to the file trusts its content, so validate it once here.
+        let digest_function = self.0.store.local.digest_function();
+        let actual_digest = self
+          .0
+          .store
+          .local
+          .executor()
+          .spawn_blocking(
+            {
+              let dest = dest.clone();
+              move || {
+                let mut file = std::fs::File::open(&dest)?;
+                hashing::sync_copy_and_hash_with(digest_function, &mut file, &mut io::sink())
+              }
+            },
+            |e| Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+          )
+          .await
+          .map_err(|e| format!("Failed to validate extracted file {}: {e}", dest.display()))?;
+        if actual_digest != digest {
+          return Err(
+            format!(
+              "Extracted immutable input file {} had digest {actual_digest:?} rather than the \
+              expected {digest:?}",
+              dest.display()
+            )
+            .into(),
+          );
+        }
+
         let _ = chroot.into_path();
 
         Ok(dest)
@@ -0,0 +1,246 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use fs::{directory, DirectoryDigest, SymlinkBehavior};
+use glob::{MatchOptions, Pattern};
+use hashing::Digest;
+use serde_derive::Serialize;
+
+use crate::{Store, StoreError};
+
+///
+/// The formats which `Store::render_directory` may render a Directory in.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InspectFormat {
+  /// An indented tree, with one entry per line.
+  Text,
+  /// A JSON object holding the root digest and a list of entries.
+  Json,
+}
+
+impl FromStr for InspectFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Self::Text),
+      "json" => Ok(Self::Json),
+      s => Err(format!(
+        "Unsupported inspection format `{s}`: expected one of `text` or `json`."
+      )),
+    }
+  }
+}
+
+///
+/// Filters the entries which `Store::inspect_directory` reports.
+///
+#[derive(Clone, Debug, Default)]
+pub struct InspectOptions {
+  /// If set, entries more than this many directories deep are omitted: 1 reports only the entries
+  /// of the root.
+  pub max_depth: Option<usize>,
+  /// If non-empty, only entries whose paths match one of the globs (and their parent directories)
+  /// are reported. As in PathGlobs, `*` does not match `/`, but `**` does.
+  pub globs: Vec<Pattern>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InspectedEntryType {
+  Directory,
+  File,
+  Symlink,
+}
+
+///
+/// An entry of an inspected Directory. Directories and files have digests, while symlinks have
+/// targets.
+///
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InspectedEntry {
+  pub path: PathBuf,
+  #[serde(rename = "type")]
+  pub entry_type: InspectedEntryType,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fingerprint: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size_bytes: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub is_executable: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target: Option<PathBuf>,
+}
+
+///
+/// A Directory, as reported by `Store::inspect_directory`.
+///
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InspectedDirectory {
+  pub fingerprint: String,
+  pub size_bytes: usize,
+  pub entries: Vec<InspectedEntry>,
+}
+
+impl InspectedDirectory {
+  pub fn render(&self, format: InspectFormat) -> String {
+    match format {
+      InspectFormat::Text => self.render_text(),
+      InspectFormat::Json => {
+        // NB: The inspected types contain only strings, numbers and booleans, and so always
+        // serialize.
+        serde_json::to_string_pretty(self).unwrap()
+      }
+    }
+  }
+
+  fn render_text(&self) -> String {
+    let mut out = format!("{} {}\n", self.fingerprint, self.size_bytes);
+    for entry in &self.entries {
+      let depth = entry.path.components().count();
+      let name = entry
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+      let indent = "  ".repeat(depth);
+      // NB: Writing to a String cannot fail.
+      let _ = match entry.entry_type {
+        InspectedEntryType::Directory => writeln!(
+          out,
+          "{indent}{name}/ {} {}",
+          entry.fingerprint.as_deref().unwrap_or_default(),
+          entry.size_bytes.unwrap_or_default(),
+        ),
+        InspectedEntryType::File => writeln!(
+          out,
+          "{indent}{name} {} {}{}",
+          entry.fingerprint.as_deref().unwrap_or_default(),
+          entry.size_bytes.unwrap_or_default(),
+          if entry.is_executable == Some(true) {
+            " (executable)"
+          } else {
+            ""
+          },
+        ),
+        InspectedEntryType::Symlink => writeln!(
+          out,
+          "{indent}{name} -> {}",
+          entry
+            .target
+            .as_deref()
+            .map(|target| target.display().to_string())
+            .unwrap_or_default(),
+        ),
+      };
+    }
+    out
+  }
+}
+
+impl InspectedEntry {
+  fn new(path: &Path, entry: &directory::Entry) -> Self {
+    let with_digest = |entry_type, digest: Digest, is_executable| Self {
+      path: path.to_owned(),
+      entry_type,
+      fingerprint: Some(digest.hash.to_hex()),
+      size_bytes: Some(digest.size_bytes),
+      is_executable,
+      target: None,
+    };
+    match entry {
+      directory::Entry::Directory(d) => {
+        with_digest(InspectedEntryType::Directory, d.digest(), None)
+      }
+      directory::Entry::File(f) => with_digest(
+        InspectedEntryType::File,
+        f.digest(),
+        Some(f.is_executable()),
+      ),
+      directory::Entry::Symlink(s) => Self {
+        path: path.to_owned(),
+        entry_type: InspectedEntryType::Symlink,
+        fingerprint: None,
+        size_bytes: None,
+        is_executable: None,
+        target: Some(s.target().to_owned()),
+      },
+    }
+  }
+}
+
+impl Store {
+  ///
+  /// Lists the entries of the given Directory (in depth-first order) with their digests and
+  /// symlink targets, filtered by the given options. Useful to explain why two Directories which
+  /// are expected to be identical have different digests.
+  ///
+  pub async fn inspect_directory(
+    &self,
+    digest: DirectoryDigest,
+    options: &InspectOptions,
+  ) -> Result<InspectedDirectory, StoreError> {
+    let root_digest = digest.as_digest();
+    let tree = self.load_digest_trie(digest).await?;
+
+    let mut entries = Vec::new();
+    tree.walk(SymlinkBehavior::Aware, &mut |path, entry| {
+      let depth = path.components().count();
+      // Skip the root, which is reported separately.
+      if depth == 0
+        || options
+          .max_depth
+          .map_or(false, |max_depth| depth > max_depth)
+      {
+        return;
+      }
+      entries.push(InspectedEntry::new(path, entry));
+    });
+
+    if !options.globs.is_empty() {
+      let match_options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::default()
+      };
+      let mut included = HashSet::new();
+      for entry in &entries {
+        if options
+          .globs
+          .iter()
+          .any(|glob| glob.matches_path_with(&entry.path, match_options))
+        {
+          included.extend(entry.path.ancestors().map(Path::to_path_buf));
+        }
+      }
+      entries.retain(|entry| included.contains(&entry.path));
+    }
+
+    Ok(InspectedDirectory {
+      fingerprint: root_digest.hash.to_hex(),
+      size_bytes: root_digest.size_bytes,
+      entries,
+    })
+  }
+
+  ///
+  /// Renders the given Directory in the given format. See `Store::inspect_directory`.
+  ///
+  pub async fn render_directory(
+    &self,
+    digest: DirectoryDigest,
+    options: &InspectOptions,
+    format: InspectFormat,
+  ) -> Result<String, StoreError> {
+    Ok(
+      self
+        .inspect_directory(digest, options)
+        .await?
+        .render(format),
+    )
+  }
+}
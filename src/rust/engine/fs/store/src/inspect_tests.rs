@@ -0,0 +1,149 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::PathBuf;
+
+use fs::DirectoryDigest;
+use glob::Pattern;
+use protos::gen::build::bazel::remote::execution::v2 as remexec;
+use tempfile::TempDir;
+use testutil::data::{TestData, TestDirectory};
+
+use crate::{InspectFormat, InspectOptions, InspectedEntryType, Store};
+
+async fn setup(store: &Store) -> DirectoryDigest {
+  let inner = TestDirectory::containing_roland();
+  let outer = remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: "run.sh".to_owned(),
+      digest: Some((&TestData::catnip().digest()).into()),
+      is_executable: true,
+      ..remexec::FileNode::default()
+    }],
+    directories: vec![remexec::DirectoryNode {
+      name: "cats".to_owned(),
+      digest: Some((&inner.digest()).into()),
+    }],
+    symlinks: vec![remexec::SymlinkNode {
+      name: "link".to_owned(),
+      target: "cats/roland.ext".to_owned(),
+      ..remexec::SymlinkNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+  store
+    .record_directory(&inner.directory(), false)
+    .await
+    .expect("Error saving Directory");
+  let digest = store
+    .record_directory(&outer, false)
+    .await
+    .expect("Error saving Directory");
+  DirectoryDigest::from_persisted_digest(digest)
+}
+
+async fn paths(options: InspectOptions) -> Vec<PathBuf> {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+  store
+    .inspect_directory(digest, &options)
+    .await
+    .unwrap()
+    .entries
+    .into_iter()
+    .map(|entry| entry.path)
+    .collect()
+}
+
+#[tokio::test]
+async fn inspect_all() {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  let digest = setup(&store).await;
+
+  let inspected = store
+    .inspect_directory(digest.clone(), &InspectOptions::default())
+    .await
+    .unwrap();
+  assert_eq!(inspected.fingerprint, digest.as_digest().hash.to_hex());
+  let entries = inspected
+    .entries
+    .iter()
+    .map(|entry| (entry.path.to_str().unwrap(), entry.entry_type))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    entries,
+    vec![
+      ("cats", InspectedEntryType::Directory),
+      ("cats/roland.ext", InspectedEntryType::File),
+      ("link", InspectedEntryType::Symlink),
+      ("run.sh", InspectedEntryType::File),
+    ]
+  );
+
+  let roland = TestData::roland().digest();
+  assert_eq!(
+    inspected.render(InspectFormat::Text),
+    format!(
+      "{} {}\n  cats/ {} {}\n    roland.ext {} {}\n  link -> cats/roland.ext\n  run.sh {} {} (executable)\n",
+      digest.as_digest().hash,
+      digest.as_digest().size_bytes,
+      TestDirectory::containing_roland().digest().hash,
+      TestDirectory::containing_roland().digest().size_bytes,
+      roland.hash,
+      roland.size_bytes,
+      TestData::catnip().digest().hash,
+      TestData::catnip().digest().size_bytes,
+    )
+  );
+
+  let json: serde_json::Value =
+    serde_json::from_str(&inspected.render(InspectFormat::Json)).unwrap();
+  assert_eq!(json["entries"][2]["type"], "symlink");
+  assert_eq!(json["entries"][2]["target"], "cats/roland.ext");
+  assert_eq!(json["entries"][3]["is_executable"], true);
+}
+
+#[tokio::test]
+async fn inspect_max_depth() {
+  assert_eq!(
+    paths(InspectOptions {
+      max_depth: Some(1),
+      ..InspectOptions::default()
+    })
+    .await,
+    vec![
+      PathBuf::from("cats"),
+      PathBuf::from("link"),
+      PathBuf::from("run.sh")
+    ]
+  );
+}
+
+#[tokio::test]
+async fn inspect_globs() {
+  // Parent directories of matching entries are included, and `*` does not match `/`.
+  assert_eq!(
+    paths(InspectOptions {
+      globs: vec![Pattern::new("*/*.ext").unwrap()],
+      ..InspectOptions::default()
+    })
+    .await,
+    vec![PathBuf::from("cats"), PathBuf::from("cats/roland.ext")]
+  );
+  assert_eq!(
+    paths(InspectOptions {
+      globs: vec![Pattern::new("*.sh").unwrap(), Pattern::new("link").unwrap()],
+      ..InspectOptions::default()
+    })
+    .await,
+    vec![PathBuf::from("link"), PathBuf::from("run.sh")]
+  );
+}
+
+#[test]
+fn parse_format() {
+  assert_eq!("text".parse::<InspectFormat>(), Ok(InspectFormat::Text));
+  assert_eq!("json".parse::<InspectFormat>(), Ok(InspectFormat::Json));
+  assert!("yaml".parse::<InspectFormat>().is_err());
+}
@@ -26,27 +26,43 @@
 #![allow(clippy::mutex_atomic)]
 #![recursion_limit = "256"]
 
+mod archive;
+pub use crate::archive::ArchiveFormat;
+#[cfg(test)]
+mod archive_tests;
 mod immutable_inputs;
-pub use crate::immutable_inputs::{ImmutableInputs, WorkdirSymlink};
+mod inspect;
+pub use crate::inspect::{
+  InspectFormat, InspectOptions, InspectedDirectory, InspectedEntry, InspectedEntryType,
+};
+#[cfg(test)]
+mod inspect_tests;
+pub use crate::immutable_inputs::{ImmutableInputs, ImmutableInputsMode, WorkdirSymlink};
+mod node_properties;
+pub use crate::node_properties::NodeProperties;
+#[cfg(test)]
+mod node_properties_tests;
 mod snapshot;
-pub use crate::snapshot::{OneOffStoreFileByDigest, Snapshot, StoreFileByDigest};
+pub use crate::snapshot::{OneOffStoreFileByDigest, Snapshot, SnapshotDiff, StoreFileByDigest};
 mod snapshot_ops;
 #[cfg(test)]
 mod snapshot_ops_tests;
 #[cfg(test)]
 mod snapshot_tests;
 pub use crate::snapshot_ops::{SnapshotOps, SubsetParams};
+mod streaming_capture;
+#[cfg(test)]
+mod streaming_capture_tests;
 
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Debug, Display};
 use std::fs::OpenOptions;
-use std::fs::Permissions as FSPermissions;
 use std::future::Future;
 use std::io::{self, Write};
-use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Weak};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use async_oncecell::OnceCell;
 use async_trait::async_trait;
@@ -54,12 +70,13 @@ use bytes::Bytes;
 use fs::{
   default_cache_path, directory, DigestEntry, DigestTrie, Dir, DirectoryDigest, File, FileContent,
   FileEntry, Link, PathStat, Permissions, RelativePath, SymlinkBehavior, SymlinkEntry,
-  EMPTY_DIRECTORY_DIGEST,
+  UnicodeNormalization, EMPTY_DIRECTORY_DIGEST,
 };
 use futures::future::{self, BoxFuture, Either, FutureExt, TryFutureExt};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use grpc_util::prost::MessageExt;
-use hashing::{Digest, Fingerprint};
-use local::ByteStore;
+use grpc_util::retry::RetryPolicy;
+use hashing::{AgedFingerprint, Digest, DigestFunction, Fingerprint, EMPTY_DIGEST};
 use parking_lot::Mutex;
 use prost::Message;
 use protos::gen::build::bazel::remote::execution::v2 as remexec;
@@ -67,11 +84,11 @@ use protos::require_digest;
 use remexec::{ServerCapabilities, Tree};
 use serde_derive::Serialize;
 use sharded_lmdb::DEFAULT_LEASE_TIME;
+use store_platform as platform;
 #[cfg(target_os = "macos")]
 use tokio::fs::copy;
 #[cfg(not(target_os = "macos"))]
 use tokio::fs::hard_link;
-use tokio::fs::symlink;
 use tryfuture::try_future;
 use workunit_store::{in_workunit, Level, Metric};
 
@@ -79,19 +96,152 @@ const KILOBYTES: usize = 1024;
 const MEGABYTES: usize = 1024 * KILOBYTES;
 const GIGABYTES: usize = 1024 * MEGABYTES;
 
+/// The maximum number of distinct digests which `Store::materialize_many` will materialize
+/// concurrently.
+const MATERIALIZE_MANY_CONCURRENCY: usize = 32;
+
+mod directory_store;
+#[cfg(test)]
+mod directory_store_tests;
+
 mod local;
 #[cfg(test)]
 pub mod local_tests;
 
+mod mdns;
+#[cfg(test)]
+mod mdns_tests;
+
+mod object_store;
+#[cfg(test)]
+mod object_store_tests;
+
+mod peer_cache;
+#[cfg(test)]
+mod peer_cache_tests;
+
 mod remote;
 #[cfg(test)]
 mod remote_tests;
 
+mod small_blob_cache;
+
+mod transfer_limits;
+#[cfg(test)]
+mod transfer_limits_tests;
+
 pub struct LocalOptions {
   pub files_max_size_bytes: usize,
   pub directories_max_size_bytes: usize,
   pub lease_time: Duration,
   pub shard_count: u8,
+  /// How big a file must be to be stored as a file on disk rather than in LMDB. If this changes
+  /// for an existing store, `on_threshold_change` decides what happens to its existing files.
+  pub large_file_size_limit: usize,
+  /// Whether to cache the `DirectoryStats` computed for directory digests.
+  pub directory_stats_cache: bool,
+  /// What to do when opening a store which was written with a different threshold between the
+  /// small (LMDB) and large (on-disk) file backends. Defaults to `Fallback`, since a `Rebalance`
+  /// is not coordinated with other processes which might have the store open.
+  pub on_threshold_change: ThresholdChangeBehavior,
+  /// If set, store, load, lease and remove operations which take longer than this are logged and
+  /// counted as slow.
+  pub slow_op_threshold: Option<Duration>,
+  /// The average number of large files per shard directory at or above which an existence check
+  /// lists each shard directory involved, rather than checking for each file individually.
+  pub fsdb_exists_listing_density: usize,
+  /// If set, `shrink` will not evict entries which were stored by this process less than this long
+  /// ago, even if they are not leased. This protects entries which were just stored (without a
+  /// lease) from a concurrent garbage collection.
+  pub min_entry_age_before_eviction: Option<Duration>,
+  /// Whether to store a checksum with each value stored in LMDB, and verify it on load. See
+  /// `ShardedLmdb::with_value_checksums`.
+  pub checksum_lmdb_values: bool,
+  /// If set, values stored in LMDB are compressed with zstd at this level. See
+  /// `ShardedLmdb::with_compression_level`.
+  pub lmdb_compression_level: Option<i32>,
+  /// What to do when a large file cannot be stored because its shard directory cannot hold any
+  /// more entries.
+  pub fsdb_full_shard_policy: FullShardPolicy,
+  /// If true, the store root is assumed to be on a local filesystem, even if it is detected to be
+  /// on a network filesystem. See `local::ByteStore::is_on_network_filesystem`.
+  pub assume_local_fs: bool,
+  /// Whether to maintain a `ContentSummary` of the store as it is mutated. See
+  /// `local::ByteStore::content_summary_fast`.
+  pub maintain_content_summary: bool,
+  /// If set, shard directories of large files which contain more than this many entries are logged
+  /// when the store is opened, and by `local::ByteStore::shard_entry_counts`.
+  pub max_entries_per_shard: Option<usize>,
+  /// Whether to memory map large files to read them, rather than reading them into memory. This
+  /// should be disabled on filesystems where files may be truncated while they are mapped, which
+  /// would crash the process. It is always disabled on detected network filesystems.
+  pub fsdb_mmap_reads: bool,
+  /// How large files are sharded into directories. If this changes for an existing store, its
+  /// large files are moved into the new layout in the background when it is next opened while no
+  /// other process has it open. Until then, the layout that it was written with is used.
+  pub fsdb_shard_layout: FsdbShardLayout,
+  /// Which expired entries `local::ByteStore::shrink` (and background garbage collection) evicts
+  /// first.
+  pub eviction_policy: EvictionPolicy,
+  /// If set, up to this many bytes of blobs which are loaded from LMDB are cached in memory, so
+  /// that repeated loads of the same (small) blobs do not need to read them again. The least
+  /// recently used blobs are evicted first.
+  ///
+  /// NB: Blobs which are removed from the store by another process may continue to be served from
+  /// the cache.
+  pub memory_cache_bytes: Option<usize>,
+  /// If true, the store is opened without writing to its directory in any way, so that a
+  /// prewarmed store may be shared read-only (for example, mounted into containers). The store
+  /// must already exist. Storing, removing, pinning and shrinking fail, while leasing does nothing.
+  ///
+  /// NB: The store must not be written to by another process while it is open read-only.
+  pub read_only: bool,
+  /// The function which computes the fingerprints of entries in the store. Stores for functions
+  /// other than SHA-256 live in a subdirectory of the store directory named for the function, so
+  /// that their entries are never confused with one another. A remote store must also support the
+  /// function.
+  ///
+  /// NB: Digests of directories computed outside of the store (for example, by `fs::DigestTrie`)
+  /// are always SHA-256, so other functions are currently only suitable for file content.
+  pub digest_function: DigestFunction,
+  /// How big a mutable file must be (in addition to being large enough to be stored on disk) for
+  /// `local::ByteStore::store` to read, hash and copy it concurrently in a single pass, rather than
+  /// hashing it before copying it.
+  pub pipelined_store_min_bytes: usize,
+  /// If true, the leases of large files are recorded in a small LMDB database, rather than by
+  /// updating the modification times of the files. This should be enabled on filesystems whose
+  /// modification times are unreliable (such as some network filesystems), which would otherwise
+  /// cause leased files to be evicted. A file is leased until the later of its recorded lease and
+  /// its modification time plus the lease time, so this may be enabled for an existing store. It
+  /// is always enabled on detected network filesystems.
+  pub fsdb_lease_database: bool,
+  /// If set, the existence of large files is cached in memory for (at most) this long, which
+  /// avoids checking the filesystem for most files which are absent. Files which are stored by
+  /// other processes may not be seen to exist until this long after they are stored, which is
+  /// safe (at worst they are fetched or stored again), but wasteful if the TTL is long.
+  pub fsdb_existence_cache_ttl: Option<Duration>,
+  /// If true, the `Provenance` of each entry is recorded when it is first stored by a task which
+  /// is running within `scope_task_origin`. See `local::ByteStore::provenance`.
+  pub record_provenance: bool,
+  /// If set, large files whose leases have lapsed (but which have not been evicted) are compressed
+  /// with zstd at this level by each pass of background garbage collection, and are decompressed
+  /// again when they are next used. See `local::ByteStore::compress_cold_files`.
+  pub fsdb_cold_compression_level: Option<i32>,
+  /// If true, the content of each entry is hashed when it is loaded, and entries whose content
+  /// does not match their digest (e.g. due to bit rot) are quarantined and treated as missing. By
+  /// default only the length of loaded content is checked, which catches truncation but not
+  /// corruption.
+  pub verify_reads: bool,
+  /// If set, the store holds only the entries of this namespace (e.g. of one repository), in a
+  /// directory below the store root, so that stores for several namespaces can share a root without
+  /// intermixing their directories or leases. Large files whose content is identical are linked
+  /// between the stores which share a root, rather than being held once per namespace.
+  pub namespace: Option<String>,
+  /// If set, the digests which a remote store was found to have (by FindMissingBlobs, or by
+  /// uploading them) are recorded in a small LMDB database, and the remote store is not asked
+  /// about them again until this long after they were recorded. A digest whose upload fails is
+  /// forgotten. See `local::ByteStore::known_remotely`.
+  pub remote_presence_ttl: Option<Duration>,
 }
 
 ///
@@ -105,14 +255,193 @@ impl Default for LocalOptions {
       directories_max_size_bytes: 2 * 4 * GIGABYTES,
       lease_time: DEFAULT_LEASE_TIME,
       shard_count: 16,
+      large_file_size_limit: local::DEFAULT_LARGE_FILE_SIZE_LIMIT,
+      directory_stats_cache: false,
+      on_threshold_change: ThresholdChangeBehavior::Fallback,
+      slow_op_threshold: None,
+      fsdb_exists_listing_density: 8,
+      min_entry_age_before_eviction: None,
+      checksum_lmdb_values: false,
+      lmdb_compression_level: None,
+      fsdb_full_shard_policy: FullShardPolicy::Fail,
+      assume_local_fs: false,
+      maintain_content_summary: false,
+      max_entries_per_shard: None,
+      fsdb_mmap_reads: true,
+      fsdb_shard_layout: FsdbShardLayout::default(),
+      eviction_policy: EvictionPolicy::Oldest,
+      memory_cache_bytes: None,
+      read_only: false,
+      digest_function: DigestFunction::Sha256,
+      pipelined_store_min_bytes: 64 * 1024 * 1024,
+      fsdb_lease_database: false,
+      fsdb_existence_cache_ttl: None,
+      record_provenance: false,
+      fsdb_cold_compression_level: None,
+      verify_reads: false,
+      namespace: None,
+      remote_presence_ttl: None,
+    }
+  }
+}
+
+///
+/// The order in which garbage collection evicts expired entries. Entries whose leases have not
+/// expired are never evicted, regardless of the policy.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+  /// Evict the entries whose leases expired longest ago first.
+  Oldest,
+  /// Evict the entries with the largest product of their size and how long ago their lease
+  /// expired first. This prefers large cold entries, which frees more space per eviction than
+  /// evicting many small entries which expired slightly earlier.
+  LargestAndOldest,
+}
+
+impl EvictionPolicy {
+  ///
+  /// Scores an entry for eviction: entries with higher scores are evicted first. Unexpired entries
+  /// always score zero.
+  ///
+  pub(crate) fn score(&self, aged_fingerprint: &AgedFingerprint) -> u128 {
+    let expired_seconds_ago = u128::from(aged_fingerprint.expired_seconds_ago);
+    match self {
+      Self::Oldest => expired_seconds_ago,
+      Self::LargestAndOldest => expired_seconds_ago * aged_fingerprint.size_bytes as u128,
     }
   }
 }
 
+///
+/// How large files are sharded into directories below the root of the on-disk store: each file is
+/// stored below `depth` nested directories, each named by the next `prefix_len` hex characters of
+/// its fingerprint. The default is a single level of 256 directories, which may hold too many
+/// files each for very large stores.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FsdbShardLayout {
+  pub prefix_len: usize,
+  pub depth: usize,
+}
+
+impl FsdbShardLayout {
+  /// The maximum number of hex characters of a fingerprint which may be used to name directories,
+  /// which bounds the number of directories at 16^4: enough for hundreds of millions of files,
+  /// while keeping the directories which are created (see `ByteStore::precreate_shards`) and listed
+  /// per shard manageable.
+  const MAX_TOTAL_PREFIX_LEN: usize = 4;
+
+  pub fn new(prefix_len: usize, depth: usize) -> Result<Self, String> {
+    if prefix_len == 0 || depth == 0 {
+      return Err(format!(
+        "The shard prefix length and depth must be positive: got {prefix_len} and {depth}."
+      ));
+    }
+    if prefix_len * depth > Self::MAX_TOTAL_PREFIX_LEN {
+      return Err(format!(
+        "A shard prefix length of {prefix_len} and depth of {depth} would use more than {} hex \
+         characters of each fingerprint.",
+        Self::MAX_TOTAL_PREFIX_LEN
+      ));
+    }
+    Ok(Self { prefix_len, depth })
+  }
+
+  ///
+  /// The number of leading hex characters of a fingerprint which name the directories it is stored
+  /// in.
+  ///
+  pub(crate) fn total_prefix_len(&self) -> usize {
+    self.prefix_len * self.depth
+  }
+
+  ///
+  /// Returns the directory under the given root which holds the files whose fingerprints begin
+  /// with the given hex prefix, which must be at least `Self::total_prefix_len` characters long.
+  ///
+  pub(crate) fn shard_dir(&self, root: &Path, hex: &str) -> PathBuf {
+    let mut dir = root.to_path_buf();
+    for level in 0..self.depth {
+      let start = level * self.prefix_len;
+      dir.push(&hex[start..start + self.prefix_len]);
+    }
+    dir
+  }
+}
+
+impl Default for FsdbShardLayout {
+  fn default() -> Self {
+    Self {
+      prefix_len: 2,
+      depth: 1,
+    }
+  }
+}
+
+impl Display for FsdbShardLayout {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}x{}", self.prefix_len, self.depth)
+  }
+}
+
+impl FromStr for FsdbShardLayout {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parse = |part: Option<&str>| {
+      part
+        .and_then(|part| part.parse::<usize>().ok())
+        .ok_or_else(|| format!("Invalid shard layout {s:?}: expected `<prefix_len>x<depth>`."))
+    };
+    let mut parts = s.trim().splitn(2, 'x');
+    let prefix_len = parse(parts.next())?;
+    let depth = parse(parts.next())?;
+    Self::new(prefix_len, depth)
+  }
+}
+
+///
+/// Some filesystems (such as ext4 without `dir_index`) limit the number of entries in a directory,
+/// which a shard directory of a large store may reach.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FullShardPolicy {
+  /// Fail to store the file. A deeper `FsdbShardLayout` will spread the files of the store across
+  /// more directories.
+  Fail,
+  /// Store the file in a sub-shard of its shard directory, named by the next two hex characters of
+  /// its fingerprint: which is where a layout one level deeper would store it. Lookups of files
+  /// which are missing from their shard directory also check its sub-shard.
+  Subshard,
+}
+
+///
+/// Files are routed to one of two local backends by size, so if the threshold between them changes
+/// while a store is at rest, existing files will be looked up in the wrong backend.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThresholdChangeBehavior {
+  /// Refuse to open the store.
+  Error,
+  /// Move existing files into the backend chosen by the new threshold in the background, looking
+  /// in both backends until that has completed.
+  ///
+  /// NB: This should only be chosen when no other process is using the store, since the move is
+  /// not coordinated with them.
+  Rebalance,
+  /// Leave existing files where they are, and look in both backends whenever a file is missing
+  /// from the backend chosen by the threshold. Once garbage collection finds that no files remain
+  /// in the wrong backend, the new threshold is recorded, and lookups stop falling back.
+  Fallback,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum StoreError {
   /// A Digest was not present in either of the local or remote Stores.
   MissingDigest(String, Digest),
+  /// An operation did not complete before its deadline.
+  DeadlineExceeded(String),
   /// All other error types.
   Unclassified(String),
 }
@@ -121,6 +450,7 @@ impl StoreError {
   pub fn enrich(self, prefix: &str) -> Self {
     match self {
       Self::MissingDigest(s, d) => Self::MissingDigest(format!("{prefix}: {s}"), d),
+      Self::DeadlineExceeded(s) => Self::DeadlineExceeded(format!("{prefix}: {s}")),
       Self::Unclassified(s) => Self::Unclassified(format!("{prefix}: {s}")),
     }
   }
@@ -132,7 +462,7 @@ impl Display for StoreError {
       Self::MissingDigest(s, d) => {
         write!(f, "{s}: {d:?}")
       }
-      Self::Unclassified(s) => write!(f, "{s}"),
+      Self::DeadlineExceeded(s) | Self::Unclassified(s) => write!(f, "{s}"),
     }
   }
 }
@@ -174,14 +504,21 @@ pub struct UploadSummary {
 #[derive(Clone, Debug)]
 struct RemoteStore {
   store: remote::ByteStore,
+  // Recorded as the `Origin` of downloaded entries, and identifies the store in the local record
+  // of the digests which it has.
+  address: String,
+  // See `Store::into_with_remote_upload_policy`.
+  upload_policy: RemoteUploadPolicy,
   in_flight_uploads: Arc<Mutex<HashMap<Digest, Weak<OnceCell<()>>>>>,
   in_flight_downloads: Arc<Mutex<HashMap<Digest, Weak<OnceCell<()>>>>>,
 }
 
 impl RemoteStore {
-  fn new(store: remote::ByteStore) -> Self {
+  fn new(store: remote::ByteStore, address: String) -> Self {
     Self {
       store,
+      address,
+      upload_policy: RemoteUploadPolicy::default(),
       in_flight_uploads: Arc::default(),
       in_flight_downloads: Arc::default(),
     }
@@ -204,6 +541,85 @@ impl RemoteStore {
     }
   }
 
+  ///
+  /// Identifies this store in the local store's record of the digests which it has: see
+  /// `local::ByteStore::known_remotely`.
+  ///
+  fn presence_key(&self) -> String {
+    format!(
+      "{}#{}",
+      self.address,
+      self.store.instance_name().unwrap_or_default()
+    )
+  }
+
+  ///
+  /// Returns those of the given digests which this store does not have, without asking it about
+  /// those which the local store recently recorded that it has. Those which it is found to have are
+  /// recorded.
+  ///
+  async fn list_missing_digests(
+    &self,
+    local: &local::ByteStore,
+    digests: impl IntoIterator<Item = Digest>,
+  ) -> Result<HashSet<Digest>, String> {
+    let presence_key = self.presence_key();
+    let mut digests = digests.into_iter().collect::<HashSet<_>>();
+    let known = local
+      .known_remotely(&presence_key, digests.iter().cloned())
+      .await;
+    digests.retain(|digest| !known.contains(digest));
+    if digests.is_empty() {
+      return Ok(HashSet::new());
+    }
+
+    let missing = self
+      .store
+      .list_missing_digests(
+        self
+          .store
+          .find_missing_blobs_request(digests.iter().cloned()),
+      )
+      .await?;
+    local
+      .record_known_remotely(
+        &presence_key,
+        digests.difference(&missing).cloned().collect(),
+      )
+      .await;
+    Ok(missing)
+  }
+
+  ///
+  /// Returns those of the given digests (with their types, if they exist locally) which this
+  /// store's upload policy allows to be uploaded. Digests which do not exist locally are kept,
+  /// because their class is unknown.
+  ///
+  fn uploadable_digests(
+    &self,
+    digests: &HashMap<Digest, Option<EntryType>>,
+  ) -> HashMap<Digest, Option<EntryType>> {
+    let uploadable = digests
+      .iter()
+      .filter(|(digest, maybe_type)| {
+        maybe_type.map_or(true, |entry_type| {
+          self.upload_policy.allows(entry_type, **digest)
+        })
+      })
+      .map(|(digest, maybe_type)| (*digest, *maybe_type))
+      .collect::<HashMap<_, _>>();
+    let excluded = digests.len() - uploadable.len();
+    if excluded > 0 {
+      log::debug!("Not uploading {excluded} blobs, which the remote upload policy excludes.");
+      if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+        workunit_store_handle
+          .store
+          .increment_counter(Metric::RemoteStoreUploadsExcluded, excluded as u64);
+      }
+    }
+    uploadable
+  }
+
   ///
   /// Guards an attempt to upload the given `Digest`, skipping the upload if another attempt has
   /// been successful. Will not return until either an attempt has succeed, or this attempt has
@@ -247,6 +663,7 @@ impl RemoteStore {
     f_remote: Option<&(dyn Fn(Bytes) -> Result<(), String> + Send + Sync + 'static)>,
   ) -> Result<(), StoreError> {
     let remote_store = self.store.clone();
+    let origin = Origin::RemoteFetch(self.address.clone());
     let create_missing = || {
       StoreError::MissingDigest(
         "Was not present in either the local or remote store".to_owned(),
@@ -254,35 +671,88 @@ impl RemoteStore {
       )
     };
     self
-      .maybe_download(digest, async move {
-        let store_into_fsdb =
-          f_remote.is_none() && ByteStore::should_use_fsdb(entry_type, digest.size_bytes);
-        if store_into_fsdb {
-          let tempfile = local_store
-            .get_file_fsdb()
-            .get_tempfile(digest.hash)
-            .await?;
-          remote_store
-            .load_file(digest, tempfile.open().await?)
-            .await?
-            .ok_or_else(create_missing)?;
-          tempfile.persist().await?;
-        } else {
-          let bytes = remote_store
-            .load_bytes(digest)
-            .await?
-            .ok_or_else(create_missing)?;
-          if let Some(f_remote) = f_remote {
-            f_remote(bytes.clone())?;
+      .maybe_download(
+        digest,
+        scope_task_origin(origin, async move {
+          let store_into_fsdb =
+            f_remote.is_none() && local_store.should_use_fsdb(entry_type, digest.size_bytes);
+          if store_into_fsdb {
+            // Large files are streamed directly into their temporary file in the FSDB, so that
+            // they never need to fit in memory.
+            let tempfile = local_store
+              .get_file_fsdb()
+              .get_tempfile(digest.hash)
+              .await?;
+            let result: Result<(), StoreError> = async {
+              remote_store
+                .load_file(digest, tempfile.open().await?)
+                .await?
+                .ok_or_else(create_missing)?;
+              tempfile.persist().await?;
+              Ok(())
+            }
+            .await;
+            if result.is_err() {
+              tempfile.discard().await;
+            }
+            result?;
+            local_store.record_provenance(vec![digest.hash]).await;
+          } else {
+            let bytes = remote_store
+              .load_bytes(digest)
+              .await?
+              .ok_or_else(create_missing)?;
+            if let Some(f_remote) = f_remote {
+              f_remote(bytes.clone())?;
+            }
+            local_store
+              .store_bytes(entry_type, digest.hash, bytes, true)
+              .await?;
           }
-          local_store
-            .store_bytes(entry_type, digest.hash, bytes, true)
-            .await?;
-        }
-        Ok(())
-      })
+          Ok(())
+        }),
+      )
       .await
   }
+
+  ///
+  /// Downloads the given digests to the local byte store from this remote store, with as few
+  /// batch requests as possible. Unlike `download_digest_to_local`, the digests are held in memory
+  /// (so they should be small), and concurrent attempts to download them are not deduplicated.
+  ///
+  async fn download_digests_batch_to_local(
+    &self,
+    local_store: local::ByteStore,
+    digests: Vec<Digest>,
+    entry_type: EntryType,
+  ) -> Result<(), StoreError> {
+    if digests.is_empty() {
+      return Ok(());
+    }
+    let origin = Origin::RemoteFetch(self.address.clone());
+    scope_task_origin(origin, async move {
+      let mut loaded = self.store.load_bytes_batch(digests.clone()).await?;
+      let items = digests
+        .into_iter()
+        .map(|digest| {
+          loaded
+            .remove(&digest)
+            .map(|bytes| (digest.hash, bytes))
+            .ok_or_else(|| {
+              StoreError::MissingDigest(
+                "Was not present in either the local or remote store".to_owned(),
+                digest,
+              )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      local_store
+        .store_bytes_batch(entry_type, items, true)
+        .await?;
+      Ok(())
+    })
+    .await
+  }
 }
 
 ///
@@ -302,6 +772,79 @@ pub struct Store {
   local: local::ByteStore,
   remote: Option<RemoteStore>,
   immutable_inputs_base: Option<PathBuf>,
+  materialization_strategy: MaterializationStrategy,
+  unicode_normalization: UnicodeNormalization,
+}
+
+///
+/// Which classes of blobs a Store uploads to its remote storage. See
+/// `Store::into_with_remote_upload_policy`.
+///
+/// The default policy uploads all blobs. A policy which uploads no blobs makes the remote storage
+/// download-only.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RemoteUploadPolicy {
+  pub files: bool,
+  pub directories: bool,
+  /// Files larger than this are not uploaded, even if `files` is set.
+  pub max_file_size_bytes: Option<usize>,
+}
+
+impl RemoteUploadPolicy {
+  pub fn download_only() -> Self {
+    Self {
+      files: false,
+      directories: false,
+      max_file_size_bytes: None,
+    }
+  }
+
+  ///
+  /// True if the given blob, of the given class, may be uploaded.
+  ///
+  pub fn allows(&self, entry_type: EntryType, digest: Digest) -> bool {
+    match entry_type {
+      EntryType::File => {
+        self.files
+          && self
+            .max_file_size_bytes
+            .map_or(true, |max_size_bytes| digest.size_bytes <= max_size_bytes)
+      }
+      EntryType::Directory => self.directories,
+    }
+  }
+}
+
+impl Default for RemoteUploadPolicy {
+  fn default() -> Self {
+    Self {
+      files: true,
+      directories: true,
+      max_file_size_bytes: None,
+    }
+  }
+}
+
+///
+/// How `Store::materialize_directory` materializes the large files which the local store holds on
+/// disk. See `Store::into_with_materialization_strategy`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MaterializationStrategy {
+  ///
+  /// Files which may be immutable are hardlinked from the store (which persists them read-only)
+  /// rather than being copied. Files are copied instead when the destination is on a different
+  /// filesystem than the store.
+  ///
+  #[default]
+  Hardlink,
+
+  ///
+  /// Files are always copied: within the kernel, where possible. See
+  /// `local::ByteStore::materialize_from_fs`.
+  ///
+  Copy,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -320,6 +863,287 @@ pub enum ShrinkBehavior {
   /// operation, as the underlying files may have been re-written.
   ///
   Compact,
+
+  ///
+  /// As with Compact, but also tidy the directories of large files: empty shard directories are
+  /// removed, and if the large file size limit has changed, files are moved into the backend chosen
+  /// by the current limit.
+  ///
+  CompactAndRebalance,
+}
+
+///
+/// Aggregate statistics about the recursive contents of a directory digest.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DirectoryStats {
+  /// The total size of all files in the tree.
+  pub total_bytes: usize,
+  /// The number of files in the tree.
+  pub file_count: usize,
+  /// The maximum number of nested directories below the root.
+  pub max_depth: usize,
+}
+
+///
+/// The outcome of an attempt to remove an entry which respects active leases.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemoveOutcome {
+  /// The entry existed, and was removed.
+  Removed,
+  /// The entry did not exist.
+  NotFound,
+  /// The entry had an active lease, and so was not removed.
+  WasLeased,
+}
+
+///
+/// Files which are stored in the wrong local backend for their size. See
+/// `local::ByteStore::verify_placement`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PlacementReport {
+  /// Files which are smaller than the large file size limit, but are stored on disk.
+  pub small_files_in_fsdb: Vec<Digest>,
+  /// Files which are at least as large as the large file size limit, but are stored in LMDB.
+  pub large_files_in_lmdb: Vec<Digest>,
+}
+
+impl PlacementReport {
+  pub fn is_empty(&self) -> bool {
+    self.small_files_in_fsdb.is_empty() && self.large_files_in_lmdb.is_empty()
+  }
+}
+
+///
+/// What `local::ByteStore::verify_integrity` does with entries whose content does not match their
+/// digest.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CorruptEntryAction {
+  /// Only report corrupt entries.
+  Report,
+  /// Remove corrupt entries from the store.
+  Remove,
+  /// Move corrupt entries out of the store into its quarantine directory, where they can be
+  /// inspected until garbage collection removes them (after the lease time, or sooner if the store
+  /// is over its target size).
+  Quarantine,
+}
+
+///
+/// The result of checking the content of every entry in a local store. See
+/// `local::ByteStore::verify_integrity`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IntegrityReport {
+  /// The number of entries which were checked.
+  pub checked: usize,
+  /// Entries whose content did not match their digest.
+  pub corrupt: Vec<(EntryType, Digest)>,
+}
+
+///
+/// How a store is garbage collected in the background. See
+/// `local::ByteStore::start_background_gc`.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct BackgroundGcOptions {
+  /// The size in bytes that each pass attempts to shrink the store to.
+  pub target_bytes: usize,
+  /// How long to wait after each pass before starting the next one.
+  pub interval: Duration,
+  /// The number of entries to evict before yielding to other work (and checking whether collection
+  /// has been stopped).
+  pub batch_size: usize,
+}
+
+///
+/// What produced an entry in the local store. See `scope_task_origin`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Origin {
+  /// The output of a process, with its description.
+  Process(String),
+  /// Computed by a rule, with its name.
+  Rule(String),
+  /// Fetched from a remote store, with its address.
+  RemoteFetch(String),
+}
+
+///
+/// Where an entry in the local store came from, and when. See `local::ByteStore::provenance`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Provenance {
+  pub origin: Origin,
+  /// When the entry was stored, to millisecond precision.
+  pub recorded_at: SystemTime,
+}
+
+tokio::task_local! {
+  static TASK_ORIGIN: Origin;
+}
+
+///
+/// Runs the given future with the given `Origin`, which is recorded as the provenance of the
+/// entries that it stores (if `LocalOptions::record_provenance` is set). Scopes may be nested, in
+/// which case the innermost origin is recorded.
+///
+pub async fn scope_task_origin<F: Future>(origin: Origin, f: F) -> F::Output {
+  TASK_ORIGIN.scope(origin, f).await
+}
+
+///
+/// The `Origin` of the current task, if it is running within `scope_task_origin`.
+///
+fn task_origin() -> Option<Origin> {
+  TASK_ORIGIN.try_with(|origin| origin.clone()).ok()
+}
+
+///
+/// How the free space of the filesystem which holds a local store is monitored. See
+/// `local::ByteStore::start_disk_space_watchdog`.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct DiskSpaceWatchdogOptions {
+  /// When less than this much space is available, expired entries are evicted from the store until
+  /// this much is available again (or no more entries may be evicted).
+  pub low_water_free_bytes: u64,
+  /// When less than this much space is available, writes to the store fail with `LocalStoreFull`.
+  /// Should be smaller than `low_water_free_bytes`, so that eviction has a chance to free space
+  /// before writes fail.
+  pub min_free_bytes: u64,
+  /// How long to wait after each check of the available space before checking again.
+  pub interval: Duration,
+}
+
+///
+/// The error which writes to a local store fail with when the filesystem which holds it has too
+/// little space available. See `DiskSpaceWatchdogOptions`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LocalStoreFull {
+  /// The root of the store.
+  pub root: PathBuf,
+  /// The space which was available on its filesystem when it was last checked.
+  pub available_bytes: u64,
+  /// `DiskSpaceWatchdogOptions::min_free_bytes`.
+  pub min_free_bytes: u64,
+}
+
+impl Display for LocalStoreFull {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "The local store at {} is full: only {} bytes are available on its filesystem, which is \
+       less than the minimum of {} bytes. Expired entries have already been evicted, so to \
+       continue, either free up space on the filesystem, reduce the size of the store (by \
+       shrinking it to a smaller target, or by shortening the lease time so that more entries may \
+       be evicted), or move the store to a larger filesystem.",
+      self.root.display(),
+      self.available_bytes,
+      self.min_free_bytes
+    )
+  }
+}
+
+///
+/// Identifies one shard of the local store. Every locally stored entry belongs to exactly one
+/// shard, so independent workers can each process a disjoint set of shards. See
+/// `local::ByteStore::shards`.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ShardId {
+  /// An LMDB shard, identified by the masked high order bits of the fingerprints it contains.
+  Lmdb(u8),
+  /// Large files, identified by the first byte of the fingerprints they contain. Depending on the
+  /// `FsdbShardLayout`, these may be spread over several directories (or share one).
+  Fsdb(u8),
+}
+
+///
+/// An order-independent summary of the entries in a local store: the XOR of the fingerprints of
+/// its entries of each type. Stores which contain the same entries have equal summaries, regardless
+/// of the order in which the entries were added or removed.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ContentSummary {
+  pub files: Fingerprint,
+  pub directories: Fingerprint,
+}
+
+impl ContentSummary {
+  pub fn empty() -> Self {
+    Self {
+      files: Fingerprint([0; hashing::FINGERPRINT_SIZE]),
+      directories: Fingerprint([0; hashing::FINGERPRINT_SIZE]),
+    }
+  }
+
+  ///
+  /// Adds the given fingerprint to the summary if it is absent, or removes it if it is present.
+  ///
+  pub fn toggle(&mut self, entry_type: EntryType, fingerprint: Fingerprint) {
+    let summary = match entry_type {
+      EntryType::File => &mut self.files,
+      EntryType::Directory => &mut self.directories,
+    };
+    for (summary_byte, byte) in summary.0.iter_mut().zip(fingerprint.0) {
+      *summary_byte ^= byte;
+    }
+  }
+}
+
+///
+/// Usage of each backend of a local store. See `local::ByteStore::stats`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+  pub file_lmdb: BackendStats,
+  pub directory_lmdb: BackendStats,
+  pub file_fsdb: BackendStats,
+}
+
+impl StoreStats {
+  pub fn total_bytes(&self) -> usize {
+    self.file_lmdb.total_bytes + self.directory_lmdb.total_bytes + self.file_fsdb.total_bytes
+  }
+
+  pub fn expired_bytes(&self) -> usize {
+    self.file_lmdb.expired_bytes + self.directory_lmdb.expired_bytes + self.file_fsdb.expired_bytes
+  }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BackendStats {
+  pub entries: usize,
+  pub total_bytes: usize,
+  /// The total size of entries whose leases have expired, and which garbage collection may
+  /// therefore remove.
+  pub expired_bytes: usize,
+  /// The usage of each non-empty shard of the backend.
+  pub shards: BTreeMap<ShardId, ShardStats>,
+}
+
+impl BackendStats {
+  fn add(&mut self, shard: ShardId, aged_fingerprint: &AgedFingerprint) {
+    self.entries += 1;
+    self.total_bytes += aged_fingerprint.size_bytes;
+    if aged_fingerprint.expired_seconds_ago > 0 {
+      self.expired_bytes += aged_fingerprint.size_bytes;
+    }
+    let shard_stats = self.shards.entry(shard).or_default();
+    shard_stats.entries += 1;
+    shard_stats.total_bytes += aged_fingerprint.size_bytes;
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShardStats {
+  pub entries: usize,
+  pub total_bytes: usize,
 }
 
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
@@ -328,79 +1152,291 @@ pub enum ShrinkBehavior {
 // We may want to re-visit this if we end up wanting to handle local/remote/merged interchangeably.
 impl Store {
   ///
-  /// Make a store which only uses its local storage.
+  /// Make a store which only uses its local storage.
+  ///
+  pub fn local_only<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+  ) -> Result<Store, String> {
+    Ok(Store {
+      local: local::ByteStore::new(executor, path)?,
+      remote: None,
+      immutable_inputs_base: None,
+      materialization_strategy: MaterializationStrategy::default(),
+      unicode_normalization: UnicodeNormalization::default(),
+    })
+  }
+
+  pub fn local_only_with_options<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    immutable_inputs_base: &Path,
+    options: LocalOptions,
+  ) -> Result<Store, String> {
+    Ok(Store {
+      local: local::ByteStore::new_with_options(executor, path, options)?,
+      remote: None,
+      immutable_inputs_base: Some(immutable_inputs_base.to_path_buf()),
+      materialization_strategy: MaterializationStrategy::default(),
+      unicode_normalization: UnicodeNormalization::default(),
+    })
+  }
+
+  ///
+  /// Converts this (copy of) a Store to local only by dropping the remote half.
+  ///
+  /// Because both underlying stores are reference counted, this is cheap, and has no effect on
+  /// other clones of the Store.
+  ///
+  pub fn into_local_only(self) -> Store {
+    Store {
+      local: self.local,
+      remote: None,
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    }
+  }
+
+  ///
+  /// Add remote storage to a Store. If it is missing a value which it tries to load, it will
+  /// attempt to back-fill its local storage from the remote storage. Failed requests to the remote
+  /// storage are retried with the given policy (see also `Self::into_with_remote_write_retry_policy`).
+  ///
+  pub fn into_with_remote(
+    self,
+    cas_address: &str,
+    instance_name: Option<String>,
+    tls_config: grpc_util::tls::Config,
+    headers: BTreeMap<String, String>,
+    token_provider: Option<Arc<grpc_util::auth::TokenProvider>>,
+    chunk_size_bytes: usize,
+    upload_timeout: Duration,
+    retry_policy: RetryPolicy,
+    rpc_concurrency_limit: usize,
+    capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
+    batch_api_size_limit: usize,
+  ) -> Result<Store, String> {
+    let digest_function = self.local.digest_function();
+    Ok(Store {
+      local: self.local,
+      remote: Some(RemoteStore::new(
+        remote::ByteStore::new(
+          cas_address,
+          instance_name,
+          tls_config,
+          headers,
+          token_provider,
+          chunk_size_bytes,
+          upload_timeout,
+          retry_policy,
+          rpc_concurrency_limit,
+          capabilities_cell_opt,
+          batch_api_size_limit,
+        )?
+        .with_digest_function(digest_function),
+        cas_address.to_owned(),
+      )),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    })
+  }
+
+  ///
+  /// Share this Store's local storage with the engines on the same LAN, and read blobs from theirs
+  /// before falling back to this Store's remote storage. The local storage is served on the given
+  /// address and port (or an ephemeral port, if zero), to peers which share the given secret. See
+  /// `peer_cache::PeerCache`.
+  ///
+  pub fn into_with_peer_cache(
+    self,
+    address: std::net::Ipv4Addr,
+    port: u16,
+    secret: String,
+    timeout: Duration,
+  ) -> Result<Store, String> {
+    let remote = self.remote.ok_or("A LAN cache requires a remote store.")?;
+    if address.is_unspecified() {
+      return Err("A LAN cache must be served on the address of a specific interface.".to_owned());
+    }
+    if secret.is_empty() {
+      return Err("A LAN cache requires a non-empty secret.".to_owned());
+    }
+    let peer_cache = peer_cache::PeerCache::new(self.local.clone(), address, port, secret, timeout);
+    Ok(Store {
+      local: self.local,
+      remote: Some(RemoteStore {
+        store: remote.store.with_peer_cache(peer_cache),
+        ..remote
+      }),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    })
+  }
+
+  ///
+  /// Add a Redis-backed cache in front of this Store's remote storage for blobs no larger than
+  /// the given size, which will be read from it first and written through to it. See
+  /// `small_blob_cache::SmallBlobCache`.
+  ///
+  pub fn into_with_small_blob_cache(
+    self,
+    address: &str,
+    max_size_bytes: usize,
+    timeout: Duration,
+  ) -> Result<Store, String> {
+    let remote = self
+      .remote
+      .ok_or("A small blob cache requires a remote store.")?;
+    let small_blob_cache = small_blob_cache::SmallBlobCache::new(address, max_size_bytes, timeout)?;
+    Ok(Store {
+      local: self.local,
+      remote: Some(RemoteStore {
+        store: remote.store.with_small_blob_cache(small_blob_cache),
+        ..remote
+      }),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    })
+  }
+
+  ///
+  /// Sets the policy with which failed uploads to this Store's remote storage are retried, in place
+  /// of the policy given to `Self::into_with_remote`.
+  ///
+  pub fn into_with_remote_write_retry_policy(
+    self,
+    write_retry_policy: RetryPolicy,
+  ) -> Result<Store, String> {
+    let remote = self
+      .remote
+      .ok_or("A write retry policy requires a remote store.")?;
+    Ok(Store {
+      local: self.local,
+      remote: Some(RemoteStore {
+        store: remote.store.with_write_retry_policy(write_retry_policy),
+        ..remote
+      }),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    })
+  }
+
+  ///
+  /// Limits the number of concurrent reads and writes to this Store's remote storage, and the
+  /// aggregate rate (in bytes per second) of the blob content transferred to and from it.
   ///
-  pub fn local_only<P: AsRef<Path>>(
-    executor: task_executor::Executor,
-    path: P,
+  pub fn into_with_remote_transfer_limits(
+    self,
+    read_concurrency_limit: Option<usize>,
+    write_concurrency_limit: Option<usize>,
+    bytes_per_second: Option<usize>,
   ) -> Result<Store, String> {
+    let remote = self
+      .remote
+      .ok_or("Transfer limits require a remote store.")?;
     Ok(Store {
-      local: local::ByteStore::new(executor, path)?,
-      remote: None,
-      immutable_inputs_base: None,
+      local: self.local,
+      remote: Some(RemoteStore {
+        store: remote.store.with_transfer_limits(
+          read_concurrency_limit,
+          write_concurrency_limit,
+          bytes_per_second,
+        ),
+        ..remote
+      }),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
     })
   }
 
-  pub fn local_only_with_options<P: AsRef<Path>>(
-    executor: task_executor::Executor,
-    path: P,
-    immutable_inputs_base: &Path,
-    options: LocalOptions,
+  ///
+  /// Sets which classes of blobs this Store uploads to its remote storage. Blobs which the policy
+  /// excludes are neither uploaded nor checked for, but are still downloaded when needed.
+  ///
+  pub fn into_with_remote_upload_policy(
+    self,
+    upload_policy: RemoteUploadPolicy,
   ) -> Result<Store, String> {
+    let remote = self
+      .remote
+      .ok_or("An upload policy requires a remote store.")?;
     Ok(Store {
-      local: local::ByteStore::new_with_options(executor, path, options)?,
-      remote: None,
-      immutable_inputs_base: Some(immutable_inputs_base.to_path_buf()),
+      local: self.local,
+      remote: Some(RemoteStore {
+        upload_policy,
+        ..remote
+      }),
+      immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
     })
   }
 
   ///
-  /// Converts this (copy of) a Store to local only by dropping the remote half.
-  ///
-  /// Because both underlying stores are reference counted, this is cheap, and has no effect on
-  /// other clones of the Store.
+  /// Add a faster local store in front of this Store's local storage: reads will be attempted
+  /// against it first, and blobs which miss in it but are found locally will be copied into it in
+  /// the background. See `local::ByteStore::with_read_warm_target`.
   ///
-  pub fn into_local_only(self) -> Store {
+  pub fn into_with_local_read_warm_target(self, faster: &Store) -> Store {
     Store {
-      local: self.local,
-      remote: None,
+      local: self.local.with_read_warm_target(faster.local.clone()),
+      remote: self.remote,
       immutable_inputs_base: self.immutable_inputs_base,
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
     }
   }
 
   ///
-  /// Add remote storage to a Store. If it is missing a value which it tries to load, it will
-  /// attempt to back-fill its local storage from the remote storage.
+  /// Add the local storage of the given Stores beneath this Store's local storage, to be consulted
+  /// in order for reads which miss in it. Writes continue to go only to this Store's local storage.
+  /// See `local::ByteStore::with_base_layers`.
   ///
-  pub fn into_with_remote(
-    self,
-    cas_address: &str,
-    instance_name: Option<String>,
-    tls_config: grpc_util::tls::Config,
-    headers: BTreeMap<String, String>,
-    chunk_size_bytes: usize,
-    upload_timeout: Duration,
-    rpc_retries: usize,
-    rpc_concurrency_limit: usize,
-    capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
-    batch_api_size_limit: usize,
-  ) -> Result<Store, String> {
-    Ok(Store {
-      local: self.local,
-      remote: Some(RemoteStore::new(remote::ByteStore::new(
-        cas_address,
-        instance_name,
-        tls_config,
-        headers,
-        chunk_size_bytes,
-        upload_timeout,
-        rpc_retries,
-        rpc_concurrency_limit,
-        capabilities_cell_opt,
-        batch_api_size_limit,
-      )?)),
+  pub fn into_with_local_base_layers(self, base_layers: &[Store]) -> Store {
+    Store {
+      local: self.local.with_base_layers(
+        base_layers
+          .iter()
+          .map(|base_layer| base_layer.local.clone())
+          .collect(),
+      ),
+      remote: self.remote,
       immutable_inputs_base: self.immutable_inputs_base,
-    })
+      materialization_strategy: self.materialization_strategy,
+      unicode_normalization: self.unicode_normalization,
+    }
+  }
+
+  ///
+  /// Sets how large files are materialized out of this Store's local storage. See
+  /// `MaterializationStrategy`.
+  ///
+  pub fn into_with_materialization_strategy(self, strategy: MaterializationStrategy) -> Store {
+    Store {
+      materialization_strategy: strategy,
+      ..self
+    }
+  }
+
+  ///
+  /// Sets the Unicode normalization form which path names are converted to when Snapshots are
+  /// captured for and Directories are materialized by this Store. See `UnicodeNormalization`.
+  ///
+  pub fn into_with_unicode_normalization(self, normalization: UnicodeNormalization) -> Store {
+    Store {
+      unicode_normalization: normalization,
+      ..self
+    }
+  }
+
+  pub fn unicode_normalization(&self) -> UnicodeNormalization {
+    self.unicode_normalization
   }
 
   // This default suffix is also hard-coded into the Python options code in global_options.py
@@ -408,8 +1444,8 @@ impl Store {
     default_cache_path().join("lmdb_store")
   }
 
-  /// Return the device ID that the local Store is hosted on.
-  pub fn local_filesystem_device(&self) -> u64 {
+  /// Return the device ID that the local Store is hosted on, if it is known.
+  pub fn local_filesystem_device(&self) -> Option<u64> {
     self.local.filesystem_device()
   }
 
@@ -420,6 +1456,31 @@ impl Store {
     self.local.remove(EntryType::File, digest).await
   }
 
+  ///
+  /// Remove a file locally, unless it is currently leased.
+  ///
+  pub async fn remove_file_unless_leased(&self, digest: Digest) -> Result<RemoveOutcome, String> {
+    self
+      .local
+      .remove_unless_leased(EntryType::File, digest)
+      .await
+  }
+
+  ///
+  /// Attempts to claim the given file digest for `claim_duration`, returning true if this caller
+  /// won the claim. See `local::ByteStore::try_claim`.
+  ///
+  pub async fn try_claim_file(
+    &self,
+    digest: Digest,
+    claim_duration: Duration,
+  ) -> Result<bool, String> {
+    self
+      .local
+      .try_claim(EntryType::File, digest, claim_duration)
+      .await
+  }
+
   ///
   /// A convenience method for storing small files.
   ///
@@ -431,7 +1492,7 @@ impl Store {
     bytes: Bytes,
     initial_lease: bool,
   ) -> Result<Digest, String> {
-    let digest = Digest::of_bytes(&bytes);
+    let digest = Digest::of_bytes_with(self.local.digest_function(), &bytes);
     self
       .local
       .store_bytes(EntryType::File, digest.hash, bytes, initial_lease)
@@ -472,6 +1533,91 @@ impl Store {
       .await
   }
 
+  ///
+  /// Store a file locally by streaming its contents, giving up if that has not completed by the
+  /// given deadline. See `local::ByteStore::store_with_deadline`.
+  ///
+  pub async fn store_file_with_deadline(
+    &self,
+    initial_lease: bool,
+    data_is_immutable: bool,
+    src: PathBuf,
+    deadline: Instant,
+  ) -> Result<Digest, StoreError> {
+    self
+      .local
+      .store_with_deadline(
+        EntryType::File,
+        initial_lease,
+        data_is_immutable,
+        src,
+        deadline,
+      )
+      .await
+  }
+
+  ///
+  /// Store a file locally by streaming its contents, and link it to another variant of its content
+  /// (such as its decompressed form) so that they are garbage collected together. See
+  /// `local::ByteStore::store_with_variant`.
+  ///
+  pub async fn store_file_with_variant(
+    &self,
+    initial_lease: bool,
+    data_is_immutable: bool,
+    src: PathBuf,
+    variant_digest: Digest,
+  ) -> Result<Digest, String> {
+    self
+      .local
+      .store_with_variant(
+        EntryType::File,
+        initial_lease,
+        data_is_immutable,
+        src,
+        variant_digest,
+      )
+      .await
+  }
+
+  ///
+  /// Returns the locally stored variant which the given file was linked to, if any. See
+  /// `local::ByteStore::load_variant`.
+  ///
+  pub async fn load_file_variant(&self, digest: Digest) -> Result<Option<Digest>, String> {
+    self.local.load_variant(EntryType::File, digest).await
+  }
+
+  ///
+  /// Store a file locally by streaming its contents, and then read it back to verify that it was
+  /// stored correctly before flushing it to disk. See `local::ByteStore::store_verified`.
+  ///
+  pub async fn store_file_verified(
+    &self,
+    initial_lease: bool,
+    data_is_immutable: bool,
+    src: PathBuf,
+  ) -> Result<Digest, String> {
+    self
+      .local
+      .store_verified(EntryType::File, initial_lease, data_is_immutable, src)
+      .await
+  }
+
+  ///
+  /// Streams the content of a locally stored file in chunks of at most `chunk_size` bytes. See
+  /// `local::ByteStore::stream_chunks`.
+  ///
+  pub fn stream_local_file_chunks(
+    &self,
+    digest: Digest,
+    chunk_size: usize,
+  ) -> impl futures::Stream<Item = Result<Bytes, String>> + Send + 'static {
+    self
+      .local
+      .stream_chunks(EntryType::File, digest, chunk_size)
+  }
+
   /// Store a digest under a given file path, returning a Snapshot
   pub async fn snapshot_of_one_file(
     &self,
@@ -571,7 +1717,7 @@ impl Store {
   ) -> Result<Digest, String> {
     let local = self.local.clone();
     let bytes = directory.to_bytes();
-    let digest = Digest::of_bytes(&bytes);
+    let digest = Digest::of_bytes_with(local.digest_function(), &bytes);
     local
       .store_bytes(
         EntryType::Directory,
@@ -657,6 +1803,45 @@ impl Store {
     Ok(tree)
   }
 
+  ///
+  /// Computes aggregate statistics for the recursive contents of the given directory.
+  ///
+  /// Because directory digests are immutable, the result may be cached in the local store (see
+  /// `LocalOptions::directory_stats_cache`), in which case repeated queries do not need to load the
+  /// tree.
+  ///
+  pub async fn directory_stats(
+    &self,
+    digest: DirectoryDigest,
+  ) -> Result<DirectoryStats, StoreError> {
+    let fingerprint = digest.as_digest().hash;
+    if let Some(stats) = self.local.load_directory_stats(fingerprint).await? {
+      return Ok(stats);
+    }
+
+    let mut stats = DirectoryStats {
+      total_bytes: 0,
+      file_count: 0,
+      max_depth: 0,
+    };
+    self.load_digest_trie(digest).await?.walk(
+      SymlinkBehavior::Aware,
+      &mut |path, entry| match entry {
+        directory::Entry::File(f) => {
+          stats.total_bytes += f.digest().size_bytes;
+          stats.file_count += 1;
+        }
+        directory::Entry::Directory(_) => {
+          stats.max_depth = std::cmp::max(stats.max_depth, path.components().count());
+        }
+        directory::Entry::Symlink(_) => (),
+      },
+    );
+
+    self.local.store_directory_stats(fingerprint, stats).await?;
+    Ok(stats)
+  }
+
   ///
   /// Loads the given directory Digest as a DirectoryDigest, eagerly fetching its tree from
   /// storage. To convert non-eagerly, use `DirectoryDigest::from_persisted_digest`.
@@ -796,54 +1981,76 @@ impl Store {
     let remote = remote_store.store.clone();
     async move {
       let ingested_digests = store.expand_local_digests(digests.iter()).await?;
+      let uploadable_digests = remote_store.uploadable_digests(&ingested_digests);
       let digests_to_upload =
-        if Store::upload_is_faster_than_checking_whether_to_upload(ingested_digests.iter()) {
-          ingested_digests.keys().cloned().collect()
+        if Store::upload_is_faster_than_checking_whether_to_upload(uploadable_digests.iter()) {
+          uploadable_digests.keys().cloned().collect()
         } else {
-          remote
-            .list_missing_digests(
-              remote.find_missing_blobs_request(ingested_digests.keys().cloned()),
-            )
+          remote_store
+            .list_missing_digests(&store.local, uploadable_digests.keys().cloned())
             .await?
         };
 
-      future::try_join_all(
-        digests_to_upload
-          .iter()
-          .cloned()
+      let entry_type_of = |digest: Digest| {
+        ingested_digests[&digest].ok_or_else(|| {
+          StoreError::MissingDigest(
+            "Did not exist either locally or remotely".to_owned(),
+            digest,
+          )
+        })
+      };
+      // TODO(John Sirois): Consider allowing configuration of when to buffer large blobs
+      // to disk to be independent of the remote store wire chunk size.
+      let (large_digests, small_digests): (Vec<_>, Vec<_>) = digests_to_upload
+        .iter()
+        .cloned()
+        .partition(|digest| digest.size_bytes > remote.chunk_size_bytes());
+
+      // Small blobs are uploaded together (in as few batch requests as possible), while large
+      // blobs are uploaded individually.
+      let small_blobs = small_digests
+        .into_iter()
+        .map(|digest| Ok((entry_type_of(digest)?, digest)))
+        .collect::<Result<Vec<_>, StoreError>>()?;
+      let small_uploads = Self::store_small_blobs_remote(store.local.clone(), remote, small_blobs);
+      let large_uploads = future::try_join_all(
+        large_digests
+          .into_iter()
           .map(|digest| {
             let local = store.local.clone();
             let remote_store = remote_store.clone();
-            let maybe_entry_type: Option<EntryType> = ingested_digests[&digest];
+            let maybe_entry_type = entry_type_of(digest);
             async move {
-              let entry_type = if let Some(et) = maybe_entry_type {
-                et
-              } else {
-                return Err(StoreError::MissingDigest(
-                  "Did not exist either locally or remotely".to_owned(),
-                  digest,
-                ));
-              };
-
+              let entry_type = maybe_entry_type?;
               remote_store
                 .clone()
                 .maybe_upload(digest, async move {
-                  // TODO(John Sirois): Consider allowing configuration of when to buffer large blobs
-                  // to disk to be independent of the remote store wire chunk size.
-                  if digest.size_bytes > remote_store.store.chunk_size_bytes() {
-                    Self::store_large_blob_remote(local, remote_store.store, entry_type, digest)
-                      .await
-                  } else {
-                    Self::store_small_blob_remote(local, remote_store.store, entry_type, digest)
-                      .await
-                  }
+                  Self::store_large_blob_remote(local, remote_store.store, entry_type, digest).await
                 })
                 .await
             }
           })
           .collect::<Vec<_>>(),
-      )
-      .await?;
+      );
+      // If any upload fails, the remote store may have lost blobs which it was recorded as having,
+      // so it is asked about all of them again next time.
+      if let Err(e) = future::try_join(small_uploads, large_uploads).await {
+        store
+          .local
+          .forget_known_remotely(
+            &remote_store.presence_key(),
+            ingested_digests.keys().cloned().collect(),
+          )
+          .await;
+        return Err(e);
+      }
+      store
+        .local
+        .record_known_remotely(
+          &remote_store.presence_key(),
+          digests_to_upload.iter().cloned().collect(),
+        )
+        .await;
 
       let ingested_file_sizes = ingested_digests.keys().map(|digest| digest.size_bytes);
       let uploaded_file_sizes = digests_to_upload.iter().map(|digest| digest.size_bytes);
@@ -859,30 +2066,38 @@ impl Store {
     .boxed()
   }
 
-  async fn store_small_blob_remote(
+  async fn store_small_blobs_remote(
     local: local::ByteStore,
     remote: remote::ByteStore,
-    entry_type: EntryType,
-    digest: Digest,
+    blobs: Vec<(EntryType, Digest)>,
   ) -> Result<(), StoreError> {
+    if blobs.is_empty() {
+      return Ok(());
+    }
     // We need to copy the bytes into memory so that they may be used safely in an async
     // future. While this unfortunately increases memory consumption, we prioritize
-    // being able to run `remote.store_bytes()` as async.
+    // being able to run `remote.store_bytes_batch()` as async.
     //
     // See https://github.com/pantsbuild/pants/pull/9793 for an earlier implementation
     // that used `Executor.block_on`, which avoided the clone but was blocking.
-    let maybe_bytes = local
-      .load_bytes_with(entry_type, digest, move |bytes| {
-        Bytes::copy_from_slice(bytes)
-      })
-      .await?;
-    match maybe_bytes {
-      Some(bytes) => Ok(remote.store_bytes(bytes).await?),
-      None => Err(StoreError::MissingDigest(
-        format!("Failed to upload {entry_type:?}: Not found in local store",),
-        digest,
-      )),
-    }
+    let items = future::try_join_all(blobs.into_iter().map(|(entry_type, digest)| {
+      let local = local.clone();
+      async move {
+        let maybe_bytes = local
+          .load_bytes_with(entry_type, digest, move |bytes| {
+            Bytes::copy_from_slice(bytes)
+          })
+          .await?;
+        maybe_bytes.ok_or_else(|| {
+          StoreError::MissingDigest(
+            format!("Failed to upload {entry_type:?}: Not found in local store",),
+            digest,
+          )
+        })
+      }
+    }))
+    .await?;
+    Ok(remote.store_bytes_batch(items).await?)
   }
 
   async fn store_large_blob_remote(
@@ -959,13 +2174,36 @@ impl Store {
       return Ok(false);
     };
     let missing = remote
-      .store
-      .list_missing_digests(remote.store.find_missing_blobs_request(missing_locally))
+      .list_missing_digests(&self.local, missing_locally)
       .await?;
 
     Ok(missing.is_empty())
   }
 
+  ///
+  /// Returns those of the given digests which are present in neither the local store (as files)
+  /// nor the remote store (if one is configured), without loading any of them. Unlike
+  /// `exists_recursive`, directories are not expanded, so this makes at most one request to the
+  /// remote store.
+  ///
+  pub async fn missing_digests(
+    &self,
+    digests: impl IntoIterator<Item = Digest>,
+  ) -> Result<HashSet<Digest>, StoreError> {
+    let missing_locally = self
+      .local
+      .get_missing_digests(EntryType::File, digests.into_iter().collect())
+      .await?;
+    match &self.remote {
+      Some(remote) if !missing_locally.is_empty() => Ok(
+        remote
+          .list_missing_digests(&self.local, missing_locally)
+          .await?,
+      ),
+      _ => Ok(missing_locally),
+    }
+  }
+
   /// Ensure that the files are locally loadable. This will download them from the remote store as
   /// a side effect, if one is configured.
   pub async fn ensure_downloaded(
@@ -1002,32 +2240,100 @@ impl Store {
         *missing_file_digests.iter().next().unwrap(),
       )
     })?;
-    let _ = future::try_join_all(
-      missing_file_digests
-        .into_iter()
-        .map(|file_digest| async move {
-          if let Err(e) = remote
-            .download_digest_to_local(self.local.clone(), file_digest, EntryType::File, None)
-            .await
-          {
-            log::debug!("Missing file digest from remote store: {:?}", file_digest);
-            in_workunit!(
-              "missing_file_counter",
-              Level::Trace,
-              |workunit| async move {
-                workunit.increment_counter(Metric::RemoteStoreMissingDigest, 1);
-              },
-            )
-            .await;
-            return Err(e);
-          }
-          Ok(())
-        }),
-    )
-    .await?;
+    // Files which will be held in memory by the local store are downloaded in batches.
+    let (large_file_digests, small_file_digests): (Vec<_>, Vec<_>) =
+      missing_file_digests.into_iter().partition(|digest| {
+        self
+          .local
+          .should_use_fsdb(EntryType::File, digest.size_bytes)
+      });
+    let record_missing = |result: Result<(), StoreError>| async move {
+      if let Err(e) = &result {
+        log::debug!("Missing file digest from remote store: {e}");
+        in_workunit!(
+          "missing_file_counter",
+          Level::Trace,
+          |workunit| async move {
+            workunit.increment_counter(Metric::RemoteStoreMissingDigest, 1);
+          },
+        )
+        .await;
+      }
+      result
+    };
+    let small_downloads = remote
+      .download_digests_batch_to_local(self.local.clone(), small_file_digests, EntryType::File)
+      .then(record_missing);
+    let large_downloads = future::try_join_all(large_file_digests.into_iter().map(|file_digest| {
+      remote
+        .download_digest_to_local(self.local.clone(), file_digest, EntryType::File, None)
+        .then(record_missing)
+    }));
+    future::try_join(small_downloads, large_downloads).await?;
     Ok(())
   }
 
+  ///
+  /// Ensures that the given Directory and all of the files below it are locally loadable, by
+  /// downloading any which are missing from the remote store (if one is configured) with at most
+  /// `concurrency` downloads in flight. Subsequent materializations of the Directory (or process
+  /// runs which consume it) will then not stall on fetching individual files.
+  ///
+  /// Returns the number of files which were downloaded.
+  ///
+  pub async fn ensure_remote_prefetched(
+    &self,
+    digest: DirectoryDigest,
+    concurrency: usize,
+  ) -> Result<usize, StoreError> {
+    let trie = self.load_digest_trie(digest).await?;
+    let mut file_digests = HashSet::new();
+    trie.walk(SymlinkBehavior::Aware, &mut |_, entry| match entry {
+      directory::Entry::File(f) => {
+        file_digests.insert(f.digest());
+      }
+      directory::Entry::Symlink(_) | directory::Entry::Directory(_) => (),
+    });
+    // Also ensure that the directory trie is persisted to disk, not only its file entries.
+    self.record_digest_trie(trie, true).await?;
+
+    let missing_file_digests = self
+      .local
+      .get_missing_digests(EntryType::File, file_digests)
+      .await?;
+    if missing_file_digests.is_empty() {
+      return Ok(0);
+    }
+
+    let remote = &self.remote.clone().ok_or_else(|| {
+      StoreError::MissingDigest(
+        "Was not present in the local store".to_owned(),
+        *missing_file_digests.iter().next().unwrap(),
+      )
+    })?;
+    let downloaded = missing_file_digests.len();
+    // Files which will be held in memory by the local store are downloaded in batches.
+    let (large_file_digests, small_file_digests): (Vec<_>, Vec<_>) =
+      missing_file_digests.into_iter().partition(|digest| {
+        self
+          .local
+          .should_use_fsdb(EntryType::File, digest.size_bytes)
+      });
+    let small_downloads = remote.download_digests_batch_to_local(
+      self.local.clone(),
+      small_file_digests,
+      EntryType::File,
+    );
+    let large_downloads = stream::iter(large_file_digests)
+      .map(|file_digest| {
+        remote.download_digest_to_local(self.local.clone(), file_digest, EntryType::File, None)
+      })
+      .buffer_unordered(concurrency.max(1))
+      .try_collect::<Vec<()>>();
+    future::try_join(small_downloads, large_downloads).await?;
+    Ok(downloaded)
+  }
+
   /// Load a REv2 Tree from a remote CAS _without_ persisting the embedded Directory protos in
   /// the local store. Tree is used by the REv2 protocol as an optimization for encoding the
   /// the Directory protos that comprise the output directories from a remote execution
@@ -1058,6 +2364,55 @@ impl Store {
     }
   }
 
+  ///
+  /// Records all of the Directory protos of the given REv2 Tree in the local store in a single
+  /// batch, and returns the DirectoryDigest of its root. Fails if the Tree does not contain all of
+  /// the Directories reachable from its root.
+  ///
+  pub async fn record_tree(
+    &self,
+    tree: Tree,
+    initial_lease: bool,
+  ) -> Result<DirectoryDigest, StoreError> {
+    let directories = tree
+      .root
+      .iter()
+      .chain(tree.children.iter())
+      .map(|directory| {
+        let bytes = directory.to_bytes();
+        (Digest::of_bytes(&bytes).hash, bytes)
+      })
+      .collect::<HashMap<_, _>>();
+    // Validates that the Tree is complete before anything is stored.
+    let trie = DigestTrie::try_from(tree)?;
+    self
+      .local
+      .store_bytes_batch(
+        EntryType::Directory,
+        directories.into_iter().collect(),
+        initial_lease,
+      )
+      .await?;
+    Ok(trie.into())
+  }
+
+  ///
+  /// Loads the REv2 Tree with the given digest (locally, or from the remote if it is not present
+  /// locally), and records its Directories locally with `Store::record_tree`. Unlike loading the
+  /// root Directory by digest, this fetches a deep output directory with a single request, rather
+  /// than with one request per level.
+  ///
+  pub async fn load_and_record_tree(
+    &self,
+    tree_digest: Digest,
+  ) -> Result<DirectoryDigest, StoreError> {
+    let tree = self
+      .load_file_bytes_with(tree_digest, |b| Tree::decode(b))
+      .await?
+      .map_err(|e| format!("protobuf decode error for Tree {tree_digest:?}: {e:?}"))?;
+    self.record_tree(tree, true).await
+  }
+
   pub async fn lease_all_recursively<'a, Ds: Iterator<Item = &'a Digest>>(
     &self,
     digests: Ds,
@@ -1096,6 +2451,45 @@ impl Store {
     }
   }
 
+  ///
+  /// Starts garbage collecting the local store in the background. See
+  /// `local::ByteStore::start_background_gc`.
+  ///
+  pub async fn start_background_gc(&self, options: BackgroundGcOptions) {
+    self.local.start_background_gc(options).await
+  }
+
+  ///
+  /// Stops background garbage collection of the local store, if it is running.
+  ///
+  pub async fn stop_background_gc(&self) {
+    self.local.stop_background_gc().await
+  }
+
+  ///
+  /// Starts monitoring the free space of the filesystem of the local store. See
+  /// `local::ByteStore::start_disk_space_watchdog`.
+  ///
+  pub async fn start_disk_space_watchdog(&self, options: DiskSpaceWatchdogOptions) {
+    self.local.start_disk_space_watchdog(options).await
+  }
+
+  ///
+  /// Stops monitoring the free space of the filesystem of the local store, if it is being
+  /// monitored.
+  ///
+  pub async fn stop_disk_space_watchdog(&self) {
+    self.local.stop_disk_space_watchdog().await
+  }
+
+  ///
+  /// Fails if writes to the local store would fail because its filesystem is nearly full. See
+  /// `local::ByteStore::check_free_space`.
+  ///
+  pub async fn check_local_free_space(&self) -> Result<(), LocalStoreFull> {
+    self.local.check_free_space().await
+  }
+
   ///
   /// To check if it might be faster to upload the digests recursively
   /// vs checking if the files are present first.
@@ -1220,6 +2614,14 @@ impl Store {
     // parent path and its children.
     let mut parent_to_child = HashMap::new();
     let tree = self.load_digest_trie(digest).await?;
+    let (tree, renamed) = tree.normalize_unicode(self.unicode_normalization)?;
+    for (original, normalized) in renamed {
+      log::warn!(
+        "Materializing {} as {} due to Unicode normalization.",
+        original.display(),
+        normalized.display()
+      );
+    }
     tree.walk(SymlinkBehavior::Aware, &mut |path, entry| {
       if let Some(parent) = path.parent() {
         parent_to_child
@@ -1234,7 +2636,9 @@ impl Store {
       mutable_path_ancestors.extend(relpath.ancestors().map(|p| destination.join(p)));
     }
 
-    // Create the root, and determine what filesystem it and the store are on.
+    // Create the root, and determine what filesystem it and the store are on. Files are never linked
+    // out of a store on a network filesystem.
+    let store_on_network_filesystem = self.local.is_on_network_filesystem();
     let materializing_to_same_filesystem = {
       let store_filesystem_device = self.local_filesystem_device();
       self
@@ -1245,16 +2649,17 @@ impl Store {
             let destination = destination.clone();
             move || {
               fs::safe_create_dir_all(&destination)?;
-              let dest_device = destination
-                .metadata()
-                .map_err(|e| {
-                  format!(
-                    "Failed to get metadata for destination {}: {e}",
-                    destination.display()
-                  )
-                })?
-                .dev();
-              Ok(dest_device == store_filesystem_device)
+              let dest_device = platform::device_id(&destination.metadata().map_err(|e| {
+                format!(
+                  "Failed to get metadata for destination {}: {e}",
+                  destination.display()
+                )
+              })?);
+              Ok(
+                !store_on_network_filesystem
+                  && dest_device.is_some()
+                  && dest_device == store_filesystem_device,
+              )
             }
           },
           |e| Err(format!("Directory creation task failed: {e}")),
@@ -1263,12 +2668,14 @@ impl Store {
         .map_err(|e| format!("Failed to create directory {}: {e}", destination.display()))?
     };
 
+    let can_hardlink = materializing_to_same_filesystem
+      && self.materialization_strategy == MaterializationStrategy::Hardlink;
     self
       .materialize_directory_children(
         destination.clone(),
         true,
         force_mutable,
-        materializing_to_same_filesystem,
+        can_hardlink,
         &parent_to_child,
         &mutable_path_ancestors,
         perms,
@@ -1276,6 +2683,158 @@ impl Store {
       .await
   }
 
+  ///
+  /// Materializes the given Digest at the destination as either a file or a directory.
+  ///
+  /// Because the empty Digest is both the empty file and the empty Directory, the store cannot
+  /// determine which of the two an empty Digest represents: callers which hold a Digest that might
+  /// be empty should carry its EntryType through to this method rather than relying on
+  /// `ByteStore::entry_type`.
+  ///
+  pub async fn materialize(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    entry_type: EntryType,
+    perms: Permissions,
+    is_executable: bool,
+  ) -> Result<(), StoreError> {
+    match entry_type {
+      EntryType::File => {
+        self
+          .materialize_file(destination, digest, perms, is_executable)
+          .await
+      }
+      EntryType::Directory => {
+        self
+          .materialize_directory(
+            destination,
+            DirectoryDigest::from_persisted_digest(digest),
+            false,
+            &BTreeSet::new(),
+            perms,
+          )
+          .await
+      }
+    }
+  }
+
+  ///
+  /// Materializes each of the given (EntryType, Digest, destination, mode) items.
+  ///
+  /// Files whose Digest appears more than once are sourced from the store only once: each later
+  /// occurrence is hardlinked to the first if it has the same mode and is on the same filesystem,
+  /// and is otherwise copied from it. Because hardlinked paths share their content, callers which
+  /// will mutate materialized files should give them distinct modes or materialize them separately.
+  ///
+  /// Directories are materialized individually, as read-only if their mode has no write bits.
+  ///
+  pub async fn materialize_many(
+    &self,
+    items: Vec<(EntryType, Digest, PathBuf, u32)>,
+  ) -> Result<(), StoreError> {
+    let mut destinations_by_entry: HashMap<(EntryType, Digest), Vec<(PathBuf, u32)>> =
+      HashMap::new();
+    for (entry_type, digest, destination, mode) in items {
+      destinations_by_entry
+        .entry((entry_type, digest))
+        .or_default()
+        .push((destination, mode));
+    }
+
+    stream::iter(destinations_by_entry)
+      .map(|((entry_type, digest), destinations)| async move {
+        match entry_type {
+          EntryType::File => self.materialize_file_copies(digest, destinations).await,
+          EntryType::Directory => {
+            for (destination, mode) in destinations {
+              let perms = if mode & 0o222 == 0 {
+                Permissions::ReadOnly
+              } else {
+                Permissions::Writable
+              };
+              self
+                .materialize_directory(
+                  destination,
+                  DirectoryDigest::from_persisted_digest(digest),
+                  false,
+                  &BTreeSet::new(),
+                  perms,
+                )
+                .await?;
+            }
+            Ok(())
+          }
+        }
+      })
+      .buffer_unordered(MATERIALIZE_MANY_CONCURRENCY)
+      .try_collect::<Vec<()>>()
+      .await?;
+    Ok(())
+  }
+
+  ///
+  /// Materializes the first of the given destinations from the store, and the rest from the first.
+  ///
+  async fn materialize_file_copies(
+    &self,
+    digest: Digest,
+    destinations: Vec<(PathBuf, u32)>,
+  ) -> Result<(), StoreError> {
+    let mut destinations = destinations.into_iter();
+    let (first, first_mode) = if let Some(first) = destinations.next() {
+      first
+    } else {
+      return Ok(());
+    };
+    self
+      .materialize_file_with_mode(first.clone(), digest, first_mode)
+      .await?;
+    let first_device = Self::parent_device(&first)?;
+
+    for (destination, mode) in destinations {
+      if mode == first_mode
+        && first_device.is_some()
+        && Self::parent_device(&destination)? == first_device
+      {
+        // NB: Link by path rather than via `materialize_hardlink`, which requires UTF-8 paths.
+        Self::link_from_store(&first, &destination)
+          .await
+          .map_err(|e| {
+            format!(
+              "Error linking {} to {}: {e}",
+              destination.display(),
+              first.display()
+            )
+          })?;
+      } else {
+        tokio::fs::copy(&first, &destination).await.map_err(|e| {
+          format!(
+            "Error copying bytes from {} to {}: {e}",
+            first.display(),
+            destination.display()
+          )
+        })?;
+        platform::set_path_mode_async(&destination, mode)
+          .await
+          .map_err(|e| {
+            format!(
+              "Error setting permissions on {}: {e}",
+              destination.display()
+            )
+          })?;
+      }
+    }
+    Ok(())
+  }
+
+  fn parent_device(path: &Path) -> Result<Option<u64>, String> {
+    let parent = path.parent().unwrap_or(path);
+    Ok(platform::device_id(&parent.metadata().map_err(|e| {
+      format!("Failed to get metadata for {}: {e}", parent.display())
+    })?))
+  }
+
   fn materialize_directory_children<'a>(
     &self,
     destination: PathBuf,
@@ -1346,7 +2905,7 @@ impl Store {
       }
 
       if perms == Permissions::ReadOnly {
-        tokio::fs::set_permissions(&destination, std::fs::Permissions::from_mode(0o555))
+        platform::set_path_mode_async(&destination, 0o555)
           .await
           .map_err(|e| {
             format!(
@@ -1374,18 +2933,24 @@ impl Store {
     } else {
       None
     };
-    match hardlink_tgt {
-      Some(path) => {
-        self
-          .materialize_hardlink(destination, path.to_str().unwrap().to_string())
-          .await
-      }
-      None => {
-        self
-          .materialize_file(destination, digest, perms, is_executable)
-          .await
+    if let Some(path) = hardlink_tgt {
+      match Self::link_from_store(&path, &destination).await {
+        Ok(()) => return Ok(()),
+        // The destination is on a different filesystem than the root of the materialization (e.g.
+        // because it is below a mount point): fall back to copying.
+        Err(e) if platform::is_cross_device(&e) => {
+          log::debug!(
+            "Could not link {} to {}: {e}",
+            path.display(),
+            destination.display()
+          );
+        }
+        Err(e) => return Err(e.into()),
       }
     }
+    self
+      .materialize_file(destination, digest, perms, is_executable)
+      .await
   }
 
   async fn materialize_file(
@@ -1401,43 +2966,60 @@ impl Store {
       Permissions::Writable if is_executable => 0o755,
       Permissions::Writable => 0o644,
     };
-    match self.local.load_from_fs(digest).await? {
-      Some(path) => {
-        tokio::fs::copy(&path, &destination).await.map_err(|e| {
+    self
+      .materialize_file_with_mode(destination, digest, mode)
+      .await
+  }
+
+  async fn materialize_file_with_mode(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    mode: u32,
+  ) -> Result<(), StoreError> {
+    if digest == EMPTY_DIGEST {
+      // Avoid I/O for this case: the empty file need not (and cannot unambiguously) be stored.
+      platform::create_with_mode(
+        OpenOptions::new().create(true).write(true).truncate(true),
+        mode,
+      )
+      .open(&destination)
+      .map_err(|e| {
+        format!(
+          "Error opening file {} for writing: {:?}",
+          destination.display(),
+          e
+        )
+      })?;
+      return Ok(());
+    }
+    // Large files are copied from disk (within the kernel, where possible).
+    if self
+      .local
+      .materialize_from_fs(digest, destination.clone(), mode, false)
+      .await?
+    {
+      return Ok(());
+    }
+    self
+      .load_file_bytes_with(digest, move |bytes| {
+        let mut f = platform::create_with_mode(
+          OpenOptions::new().create(true).write(true).truncate(true),
+          mode,
+        )
+        .open(&destination)
+        .map_err(|e| {
           format!(
-            "Error copying bytes from {} to {}: {e}",
-            path.display(),
-            destination.display()
+            "Error opening file {} for writing: {:?}",
+            destination.display(),
+            e
           )
         })?;
-        tokio::fs::set_permissions(destination, FSPermissions::from_mode(mode))
-          .await
-          .map_err(|e| format!("Error setting permissions on {}: {e}", path.display()))?;
+        f.write_all(bytes)
+          .map_err(|e| format!("Error writing file {}: {:?}", destination.display(), e))?;
         Ok(())
-      }
-      None => {
-        self
-          .load_file_bytes_with(digest, move |bytes| {
-            let mut f = OpenOptions::new()
-              .create(true)
-              .write(true)
-              .truncate(true)
-              .mode(mode)
-              .open(&destination)
-              .map_err(|e| {
-                format!(
-                  "Error opening file {} for writing: {:?}",
-                  destination.display(),
-                  e
-                )
-              })?;
-            f.write_all(bytes)
-              .map_err(|e| format!("Error writing file {}: {:?}", destination.display(), e))?;
-            Ok(())
-          })
-          .await?
-      }
-    }
+      })
+      .await?
   }
 
   pub async fn materialize_symlink(
@@ -1445,7 +3027,14 @@ impl Store {
     destination: PathBuf,
     target: String,
   ) -> Result<(), StoreError> {
-    symlink(target, destination).await?;
+    self
+      .local
+      .executor()
+      .spawn_blocking(
+        move || platform::symlink(Path::new(&target), &destination),
+        |e| Err(io::Error::new(io::ErrorKind::Other, e)),
+      )
+      .await?;
     Ok(())
   }
 
@@ -1454,6 +3043,11 @@ impl Store {
     destination: PathBuf,
     target: String,
   ) -> Result<(), StoreError> {
+    Self::link_from_store(Path::new(&target), &destination).await?;
+    Ok(())
+  }
+
+  async fn link_from_store(target: &Path, destination: &Path) -> io::Result<()> {
     // On macOS, copy uses a copy-on-write syscall (fclonefileat) which creates a disconnected
     // clone. It is more defensive than a hardlink, but has the same requirement that the source
     // and destination filesystem are the same.
@@ -1629,6 +3223,180 @@ impl Store {
   pub async fn all_local_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
     self.local.all_digests(entry_type).await
   }
+
+  ///
+  /// Writes the given digests, and everything which is reachable from those which are directories,
+  /// to `writer` as an archive which `Self::import_local` can load into another store. See
+  /// `local::ByteStore::export`.
+  ///
+  pub async fn export_local<W: tokio::io::AsyncWrite + Unpin>(
+    &self,
+    digests: &[Digest],
+    writer: &mut W,
+  ) -> Result<(), StoreError> {
+    let mut entries = Vec::new();
+    for (digest, maybe_type) in self.expand_local_digests(digests.iter()).await? {
+      let entry_type = maybe_type.ok_or_else(|| {
+        StoreError::MissingDigest("Was not present in the local store".to_owned(), digest)
+      })?;
+      entries.push((digest, entry_type));
+    }
+    Ok(self.local.export(entries, writer).await?)
+  }
+
+  ///
+  /// Stores the entries of an archive written by `Self::export_local` into the local store, and
+  /// returns their digests. See `local::ByteStore::import`.
+  ///
+  pub async fn import_local<R: tokio::io::AsyncRead + Send + Unpin>(
+    &self,
+    reader: &mut R,
+    initial_lease: bool,
+  ) -> Result<Vec<Digest>, String> {
+    let entries = self.local.import(reader, initial_lease).await?;
+    Ok(entries.into_iter().map(|(digest, _)| digest).collect())
+  }
+
+  ///
+  /// Re-hashes every entry in the local store, and handles corrupt entries according to `action`.
+  /// See `local::ByteStore::verify_integrity`.
+  ///
+  pub async fn verify_local_integrity(
+    &self,
+    action: CorruptEntryAction,
+  ) -> Result<IntegrityReport, String> {
+    self.local.verify_integrity(action).await
+  }
+
+  ///
+  /// Exempts the given digests from local garbage collection until they are unpinned. See
+  /// `local::ByteStore::pin`.
+  ///
+  pub async fn pin_local(&self, digests: impl IntoIterator<Item = Digest>) -> Result<(), String> {
+    self.local.pin(digests).await
+  }
+
+  ///
+  /// Returns where the given digest came from, if that was recorded when it was stored locally.
+  /// See `local::ByteStore::provenance`.
+  ///
+  pub async fn local_provenance(&self, digest: Digest) -> Result<Option<Provenance>, String> {
+    self.local.provenance(digest).await
+  }
+
+  ///
+  /// Unpins digests which were pinned by `Self::pin_local`. See `local::ByteStore::unpin`.
+  ///
+  pub async fn unpin_local(&self, digests: impl IntoIterator<Item = Digest>) -> Result<(), String> {
+    self.local.unpin(digests).await
+  }
+
+  ///
+  /// Stops any tasks which the local store has spawned in the background, and waits for them to
+  /// complete. See `local::ByteStore::shutdown`.
+  ///
+  pub async fn shutdown(self) {
+    self.local.shutdown().await
+  }
+
+  ///
+  /// Creates all local shard directories up front. See `local::ByteStore::precreate_shards`.
+  ///
+  pub async fn precreate_local_shards(&self) -> Result<(), String> {
+    self.local.precreate_shards().await
+  }
+
+  ///
+  /// Reports any local files which are stored in the wrong backend for their size. See
+  /// `local::ByteStore::verify_placement`.
+  ///
+  pub async fn verify_local_placement(&self) -> Result<PlacementReport, String> {
+    self.local.verify_placement().await
+  }
+
+  ///
+  /// Returns true if the given digest is present locally with the expected content. See
+  /// `local::ByteStore::verify_present`.
+  ///
+  pub async fn verify_local_present(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<bool, String> {
+    self.local.verify_present(entry_type, digest).await
+  }
+
+  ///
+  /// Loads the given file digest from the local store into a file at `destination`, which the
+  /// caller may then memory map. Returns false if the digest is not present locally. See
+  /// `local::ByteStore::load_into_mmap`.
+  ///
+  pub async fn load_file_into_mmap(
+    &self,
+    digest: Digest,
+    destination: PathBuf,
+    mode: u32,
+  ) -> Result<bool, String> {
+    self
+      .local
+      .load_into_mmap(EntryType::File, digest, destination, mode)
+      .await
+  }
+
+  ///
+  /// Returns the number of entries in each on-disk shard of the local store. See
+  /// `local::ByteStore::shard_entry_counts`.
+  ///
+  pub async fn local_shard_entry_counts(&self) -> Result<Vec<(ShardId, usize)>, String> {
+    self.local.shard_entry_counts().await
+  }
+
+  ///
+  /// Returns the maintained summary of the local store's content, if enabled. See
+  /// `local::ByteStore::content_summary_fast`.
+  ///
+  pub fn local_content_summary_fast(&self) -> Option<ContentSummary> {
+    self.local.content_summary_fast()
+  }
+
+  ///
+  /// Computes a summary of the local store's content by listing it. See
+  /// `local::ByteStore::content_summary`.
+  ///
+  pub async fn local_content_summary(&self) -> Result<ContentSummary, String> {
+    self.local.content_summary().await
+  }
+
+  ///
+  /// Lists the shards of the local store. See `local::ByteStore::shards`.
+  ///
+  pub fn local_shards(&self) -> Vec<ShardId> {
+    self.local.shards()
+  }
+
+  ///
+  /// Returns the Digests of all local entries of the given type in the given shard. See
+  /// `local::ByteStore::digests_in_shard`.
+  ///
+  pub async fn local_digests_in_shard(
+    &self,
+    entry_type: EntryType,
+    shard: ShardId,
+  ) -> Result<Vec<Digest>, String> {
+    self.local.digests_in_shard(entry_type, shard).await
+  }
+
+  ///
+  /// Returns the Digests of all local entries of the given type which were modified (or had their
+  /// lease extended) after `since`. See `local::ByteStore::entries_modified_since`.
+  ///
+  pub async fn local_digests_modified_since(
+    &self,
+    entry_type: EntryType,
+    since: SystemTime,
+  ) -> Result<Vec<Digest>, String> {
+    self.local.entries_modified_since(entry_type, since).await
+  }
 }
 
 #[async_trait]
@@ -1,37 +1,143 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
-use super::{EntryType, ShrinkBehavior};
+use super::platform;
+use super::{
+  BackendStats, BackgroundGcOptions, ContentSummary, CorruptEntryAction, DirectoryStats,
+  DiskSpaceWatchdogOptions, EntryType, EvictionPolicy, FsdbShardLayout, FullShardPolicy,
+  IntegrityReport, LocalStoreFull, Origin, PlacementReport, Provenance, RemoveOutcome, ShardId,
+  ShrinkBehavior, StoreError, StoreStats, ThresholdChangeBehavior,
+};
 
-use std::collections::{BinaryHeap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::fmt::Debug;
-use std::os::unix::fs::MetadataExt;
+use std::future::Future;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant, SystemTime};
 
+use async_oncecell::OnceCell;
+use async_stream::try_stream;
 use async_trait::async_trait;
-use bytes::Bytes;
-use futures::future::{self, join_all, try_join, try_join_all};
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, join_all, try_join, try_join3, try_join_all};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use futures::Stream;
 use hashing::{
-  async_copy_and_hash, async_verified_copy, AgedFingerprint, Digest, Fingerprint, EMPTY_DIGEST,
+  async_copy_and_hash_with, async_verified_copy_with, AgedFingerprint, Digest, DigestFunction,
+  Fingerprint, Hasher, WriterHasher, EMPTY_DIGEST,
 };
-use sharded_lmdb::ShardedLmdb;
-use std::os::unix::fs::PermissionsExt;
+use parking_lot::Mutex;
+use sharded_lmdb::{ContentObserver, ShardedLmdb};
 use task_executor::Executor;
 use tempfile::NamedTempFile;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use workunit_store::ObservationMetric;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use workunit_store::{in_workunit, Level, Metric, ObservationMetric};
 
-/// How big a file must be to be stored as a file on disk.
+/// The default for how big a file must be to be stored as a file on disk. See
+/// `LocalOptions::large_file_size_limit`.
 // NB: These numbers were chosen after micro-benchmarking the code on one machine at the time of
 // writing. They were chosen using a rough equation from the microbenchmarks that are optimized
 // for somewhere between 2 and 3 uses of the corresponding entry to "break even".
-const LARGE_FILE_SIZE_LIMIT: usize = 512 * 1024;
+pub(crate) const DEFAULT_LARGE_FILE_SIZE_LIMIT: usize = 512 * 1024;
+
+/// The name of a file in the store root which records the large file size limit that the store
+/// was written with.
+const LARGE_FILE_SIZE_LIMIT_MARKER: &str = "large_file_size_limit";
+
+/// The name of a file in the store root which records the `FsdbShardLayout` that the store was
+/// written with.
+const FSDB_SHARD_LAYOUT_MARKER: &str = "fsdb_shard_layout";
+
+/// The name of a file in the store root which writable stores lock while they are open, so that
+/// the shard layout is only migrated by a store which has the root to itself. See
+/// `ByteStore::open_shard_layout`.
+const FSDB_SHARD_LAYOUT_LOCK: &str = "fsdb_shard_layout.lock";
+
+/// The prefix of the names of temporary files created by `NamedTempFile`, which are written and
+/// then persisted into the FSDB. See `ShardedFSDB::remove_stale_tempfiles`.
+const TEMPFILE_PREFIX: &str = ".tmp";
+
+/// The suffix of the names of large files which have been compressed with zstd because their
+/// leases lapsed. Each such file begins with the length of its uncompressed content as a
+/// little-endian u64, as values compressed by `ShardedLmdb` do. See `ShardedFSDB::compress`.
+const COMPRESSED_SUFFIX: &str = ".zst";
+const COMPRESSED_HEADER_SIZE: usize = 8;
+
+/// The name of a directory in the store root into which corrupt entries are moved. See
+/// `ByteStore::verify_integrity`.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// The name of a directory in the store root which holds the stores for each namespace. See
+/// `LocalOptions::namespace`.
+const NAMESPACES_DIR: &str = "namespaces";
+
+/// The name of a file in the store root which records the `ContentSummary` of the store as of the
+/// last time it was shut down. See `ByteStore::content_summary_fast`.
+const CONTENT_SUMMARY_MARKER: &str = "content_summary";
+
+/// The maximum size of the (optional) database of cached `DirectoryStats`. Each entry is small, so
+/// this is far larger than should be necessary.
+const DIRECTORY_STATS_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The maximum size of the database of links between variants of an entry. See
+/// `ByteStore::store_with_variant`.
+const VARIANTS_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The maximum size of the (optional) database of the provenance of entries. See
+/// `ByteStore::provenance`.
+const PROVENANCE_MAX_SIZE_BYTES: usize = 256 * 1024 * 1024;
+
+/// The maximum size of the (optional) database of digests which remote stores are known to have.
+/// See `ByteStore::known_remotely`.
+const REMOTE_PRESENCE_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The number of entries which the disk space watchdog evicts before checking whether it has been
+/// stopped. See `ByteStore::start_disk_space_watchdog`.
+const DISK_SPACE_WATCHDOG_BATCH_SIZE: usize = 1000;
+
+/// The maximum size of the database of pinned entries. See `ByteStore::pin`.
+const PINS_MAX_SIZE_BYTES: usize = 16 * 1024 * 1024;
+
+/// The maximum size of the database of leases of large files. See
+/// `LocalOptions::fsdb_lease_database`.
+const FSDB_LEASES_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The maximum size of the database of large files which are hardlinks to files outside of the
+/// store. See `ShardedFSDB::link_to`.
+const FSDB_LINKED_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The maximum number of entries which garbage collection removes at once. See
+/// `ByteStore::remove_batch`.
+const MAX_EVICTION_BATCH_SIZE: usize = 1000;
+
+/// The maximum number of blocking tasks which scan shard directories of large files, or extend the
+/// leases of large files, concurrently. See `ShardedFSDB::scan_entries`.
+const FSDB_SCAN_CONCURRENCY: usize = 16;
+
+/// The number of large files whose leases `ShardedFSDB::lease_batch` extends on each blocking task.
+const FSDB_LEASE_BATCH_SIZE: usize = 1000;
+
+/// The size of the chunks in which `ShardedFSDB::store_pipelined` reads a file, and how many read
+/// chunks may be waiting to be hashed or written at once.
+const PIPELINE_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+const PIPELINE_DEPTH: usize = 4;
+
+/// Identifies an archive written by `ByteStore::export`, and the version of its format.
+const ARCHIVE_MAGIC: &[u8; 8] = b"PANTSCAS";
+const ARCHIVE_VERSION: u32 = 1;
 
 #[derive(Debug, Clone)]
 pub(crate) struct TempImmutableLargeFile {
   tmp_path: PathBuf,
   final_path: PathBuf,
+  // See `ShardedFSDB::content_observer`.
+  content_observer: Option<ContentObserver>,
 }
 
 impl TempImmutableLargeFile {
@@ -39,15 +145,169 @@ impl TempImmutableLargeFile {
     tokio::fs::File::create(self.tmp_path.clone()).await
   }
 
+  async fn open_for_read(&self) -> tokio::io::Result<tokio::fs::File> {
+    tokio::fs::File::open(self.tmp_path.clone()).await
+  }
+
+  ///
+  /// Removes the temporary file, if it exists. Used to clean up after a failed write.
+  ///
+  pub async fn discard(&self) {
+    if let Err(e) = tokio::fs::remove_file(&self.tmp_path).await {
+      if e.kind() != io::ErrorKind::NotFound {
+        log::warn!("Failed to remove temporary file {:?}: {e}", self.tmp_path);
+      }
+    }
+  }
+
   pub async fn persist(&self) -> Result<(), String> {
-    tokio::fs::rename(self.tmp_path.clone(), self.final_path.clone())
-      .await
-      .map_err(|e| format!("Error while renaming: {e}."))?;
-    tokio::fs::set_permissions(&self.final_path, std::fs::Permissions::from_mode(0o555))
+    self.move_into_place().await?;
+    platform::make_read_only(&self.final_path)
       .await
       .map_err(|e| e.to_string())?;
     Ok(())
   }
+
+  ///
+  /// As `Self::persist`, but leaves the permissions of the file unchanged. Used for temporary files
+  /// which are links to (read-only) files outside of the store, whose permissions the store should
+  /// not modify. See `ShardedFSDB::link_to` and `ShardedFSDB::link_from_shared`.
+  ///
+  async fn persist_linked(&self) -> Result<(), String> {
+    self.move_into_place().await
+  }
+
+  async fn move_into_place(&self) -> Result<(), String> {
+    match self.move_into_place_once().await {
+      // The shard directory may have been removed (while it was empty) by
+      // `ShardedFSDB::tidy_shard_dirs` since it was created: create it again.
+      Err(e) if !self.final_path.parent().unwrap().exists() => {
+        log::debug!("Retrying the move of {:?}: {e}", self.tmp_path);
+        tokio::fs::create_dir_all(self.final_path.parent().unwrap())
+          .await
+          .map_err(|e| format!("Failed to create local store subdirectory: {e}"))?;
+        self.move_into_place_once().await
+      }
+      result => result,
+    }
+  }
+
+  async fn move_into_place_once(&self) -> Result<(), String> {
+    if let Some(content_observer) = &self.content_observer {
+      if self.persist_if_absent().await? {
+        content_observer.notify(Fingerprint::from_hex_string(
+          self.final_path.file_name().unwrap().to_str().unwrap(),
+        )?);
+      }
+    } else {
+      platform::replace(&self.tmp_path, &self.final_path)
+        .await
+        .map_err(|e| format!("Error while renaming: {e}."))?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Moves the temporary file into place, returning true if no file was already present there.
+  ///
+  /// Linking the file into place (rather than renaming it) fails if a file is already present,
+  /// which atomically determines whether this call added it. An existing file is still replaced,
+  /// as it would be by `persist`.
+  ///
+  async fn persist_if_absent(&self) -> Result<bool, String> {
+    match tokio::fs::hard_link(&self.tmp_path, &self.final_path).await {
+      Ok(()) => {
+        tokio::fs::remove_file(&self.tmp_path)
+          .await
+          .map_err(|e| format!("Failed to remove {:?}: {e}", self.tmp_path))?;
+        return Ok(true);
+      }
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+      Err(e) => {
+        // The filesystem may not support links: fall back to a (non-atomic) existence check.
+        log::debug!("Failed to link {:?}: {e}", self.tmp_path);
+        if tokio::fs::metadata(&self.final_path).await.is_err() {
+          tokio::fs::rename(&self.tmp_path, &self.final_path)
+            .await
+            .map_err(|e| format!("Error while renaming: {e}."))?;
+          return Ok(true);
+        }
+      }
+    }
+    platform::replace(&self.tmp_path, &self.final_path)
+      .await
+      .map_err(|e| format!("Error while renaming: {e}."))?;
+    Ok(false)
+  }
+}
+
+/// A deadline for storing a blob, which records whether any read failed because it had passed, so
+/// that failures which it caused can be told apart from other failures which merely happen late.
+#[derive(Clone, Debug)]
+struct Deadline {
+  at: Instant,
+  exceeded: Arc<AtomicBool>,
+}
+
+impl Deadline {
+  fn new(at: Instant) -> Self {
+    Self {
+      at,
+      exceeded: Arc::default(),
+    }
+  }
+
+  fn was_exceeded(&self) -> bool {
+    self.exceeded.load(atomic::Ordering::SeqCst)
+  }
+}
+
+/// A reader which fails with `io::ErrorKind::TimedOut` once its (optional) deadline has passed. The
+/// deadline is checked before each read, so a read which is already blocked is not interrupted.
+#[derive(Debug)]
+struct DeadlineReader<R> {
+  inner: R,
+  deadline: Option<Deadline>,
+}
+
+impl<R> DeadlineReader<R> {
+  fn new(inner: R, deadline: Option<Deadline>) -> Self {
+    Self { inner, deadline }
+  }
+
+  fn check_deadline(&self) -> io::Result<()> {
+    match &self.deadline {
+      Some(deadline) if Instant::now() >= deadline.at => {
+        deadline.exceeded.store(true, atomic::Ordering::SeqCst);
+        Err(io::Error::new(
+          io::ErrorKind::TimedOut,
+          "Deadline exceeded while reading.",
+        ))
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+impl<R: io::Read> io::Read for DeadlineReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.check_deadline()?;
+    self.inner.read(buf)
+  }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DeadlineReader<R> {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    if let Err(e) = this.check_deadline() {
+      return Poll::Ready(Err(e));
+    }
+    Pin::new(&mut this.inner).poll_read(cx, buf)
+  }
 }
 
 /// Trait for the underlying storage, which is either a ShardedLMDB or a ShardedFS.
@@ -65,8 +325,17 @@ trait UnderlyingByteStore {
 
   async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String>;
 
+  /// Returns true if the given fingerprint is present and has an unexpired lease.
+  async fn is_leased(&self, fingerprint: Fingerprint) -> Result<bool, String>;
+
   async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String>;
 
+  /// Removes the given fingerprints, returning those which were present.
+  async fn remove_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String>;
+
   async fn store_bytes_batch(
     &self,
     items: Vec<(Fingerprint, Bytes)>,
@@ -79,6 +348,7 @@ trait UnderlyingByteStore {
     src_is_immutable: bool,
     expected_digest: Digest,
     src: PathBuf,
+    deadline: Option<Deadline>,
   ) -> Result<(), String>;
 
   async fn load_bytes_with<
@@ -90,19 +360,37 @@ trait UnderlyingByteStore {
     mut f: F,
   ) -> Result<Option<T>, String>;
 
-  async fn aged_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String>;
+  /// Flush any buffered writes for the given fingerprint to disk.
+  async fn sync(&self, fingerprint: Fingerprint) -> Result<(), String>;
+
+  /// Streams the fingerprints of all entries (along with how long ago their leases expired) in
+  /// batches of one shard each, so that callers need not hold every entry in memory at once.
+  ///
+  /// The size of each entry is the space that it occupies in the store, which may be less than the
+  /// size of its content. Use `Self::all_digests` for the latter.
+  fn aged_fingerprint_batches(&self) -> BoxStream<'static, Result<Vec<AgedFingerprint>, String>>;
+
+  async fn aged_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String> {
+    self.aged_fingerprint_batches().try_concat().await
+  }
+
+  /// Return the Digests of all entries which were last modified (or leased) after `since`.
+  async fn modified_since(&self, since: SystemTime) -> Result<Vec<Digest>, String>;
 
   async fn all_digests(&self) -> Result<Vec<Digest>, String> {
-    let fingerprints = self.aged_fingerprints().await?;
-    Ok(
-      fingerprints
-        .into_iter()
-        .map(|fingerprint| Digest {
-          hash: fingerprint.fingerprint,
-          size_bytes: fingerprint.size_bytes,
-        })
-        .collect(),
-    )
+    self
+      .aged_fingerprint_batches()
+      .map_ok(|batch| {
+        stream::iter(batch.into_iter().map(|fingerprint| {
+          Ok(Digest {
+            hash: fingerprint.fingerprint,
+            size_bytes: fingerprint.size_bytes,
+          })
+        }))
+      })
+      .try_flatten()
+      .try_collect()
+      .await
   }
 }
 
@@ -119,10 +407,21 @@ impl UnderlyingByteStore for ShardedLmdb {
     self.lease(fingerprint).await
   }
 
+  async fn is_leased(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    self.is_leased(fingerprint).await
+  }
+
   async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
     self.remove(fingerprint).await
   }
 
+  async fn remove_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    self.remove_batch(fingerprints).await
+  }
+
   async fn store_bytes_batch(
     &self,
     items: Vec<(Fingerprint, Bytes)>,
@@ -136,13 +435,14 @@ impl UnderlyingByteStore for ShardedLmdb {
     src_is_immutable: bool,
     expected_digest: Digest,
     src: PathBuf,
+    deadline: Option<Deadline>,
   ) -> Result<(), String> {
     self
       .store(
         initial_lease,
         src_is_immutable,
         expected_digest,
-        move || std::fs::File::open(&src),
+        move || std::fs::File::open(&src).map(|file| DeadlineReader::new(file, deadline.clone())),
       )
       .await
   }
@@ -158,9 +458,27 @@ impl UnderlyingByteStore for ShardedLmdb {
     self.load_bytes_with(fingerprint, f).await
   }
 
+  async fn sync(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    self.sync(fingerprint).await
+  }
+
+  fn aged_fingerprint_batches(&self) -> BoxStream<'static, Result<Vec<AgedFingerprint>, String>> {
+    let lmdb = self.clone();
+    stream::iter(self.shard_ids())
+      .then(move |shard_id| {
+        let lmdb = lmdb.clone();
+        async move { lmdb.all_fingerprints_in_shard(shard_id).await }
+      })
+      .boxed()
+  }
+
   async fn aged_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String> {
     self.all_fingerprints().await
   }
+
+  async fn modified_since(&self, since: SystemTime) -> Result<Vec<Digest>, String> {
+    self.modified_since(since).await
+  }
 }
 
 // We shard so there isn't a plethora of entries in one single dir.
@@ -169,55 +487,337 @@ pub(crate) struct ShardedFSDB {
   root: PathBuf,
   executor: Executor,
   lease_time: Duration,
+  // See `LocalOptions::fsdb_exists_listing_density`.
+  exists_listing_density: usize,
+  // True once all shard directories are known to exist, meaning that stores need not create them.
+  shards_precreated: Arc<AtomicBool>,
+  // See `LocalOptions::fsdb_full_shard_policy`.
+  full_shard_policy: FullShardPolicy,
+  // If set, notified of each file which is added to or removed from the store.
+  content_observer: Option<ContentObserver>,
+  // See `LocalOptions::fsdb_mmap_reads`.
+  mmap_reads: bool,
+  // The device of the store root, if immutable sources (and the files of stores which share its
+  // root) may be hardlinked into it. Never set for a store on a network filesystem. See
+  // `ShardedFSDB::link_to` and `ShardedFSDB::link_from_shared`.
+  link_device: Option<u64>,
+  // See `LocalOptions::fsdb_shard_layout`.
+  layout: FsdbShardLayout,
+  // If set, the layout that the store was written with, from which files are being moved in the
+  // background. See `ShardedFSDB::migrate_layout`.
+  previous_layout: Option<FsdbShardLayout>,
+  // True until the migration from `previous_layout` has completed.
+  migrating: Arc<AtomicBool>,
+  // See `LocalOptions::read_only`. If set, no temporary files are created.
+  read_only: bool,
+  // See `LocalOptions::digest_function`.
+  digest_function: DigestFunction,
+  // Present if `LocalOptions::fsdb_lease_database` is set, in which case leases are recorded here
+  // rather than by bumping the mtimes of files. See `ShardedFSDB::apply_recorded_leases`.
+  lease_lmdb: Option<Result<Arc<ShardedLmdb>, String>>,
+  // Records the files which are hardlinks to sources outside of the store, along with their
+  // leases, so that leasing them never touches the mtimes of their sources. Opened when the
+  // store is opened if it exists, and otherwise when the first source is linked. See
+  // `ShardedFSDB::link_to`.
+  linked_lmdb: Arc<Mutex<Option<Result<Arc<ShardedLmdb>, String>>>>,
+  linked_lmdb_root: PathBuf,
+  // Present if `LocalOptions::fsdb_existence_cache_ttl` is set.
+  existence_cache: Option<Arc<Mutex<ExistenceCache>>>,
+  // If `LocalOptions::namespace` is set, the root which this store shares with the stores for other
+  // namespaces. See `ShardedFSDB::link_from_shared`.
+  shared_root: Option<PathBuf>,
+  // Files which did not shrink when they were compressed, and so are not compressed again. See
+  // `ShardedFSDB::compress`.
+  incompressible: Arc<Mutex<HashSet<Fingerprint>>>,
+}
+
+///
+/// A file in a `ShardedFSDB`, as listed by `ShardedFSDB::list_shard_entries`.
+///
+#[derive(Clone, Copy)]
+struct FsdbEntry {
+  fingerprint: Fingerprint,
+  // The size of the file on disk, unless the length of its content was requested.
+  len: u64,
+  // The mtime of the file, or its recorded lease. See `ShardedFSDB::apply_recorded_leases`.
+  mtime: SystemTime,
+  // True if the file was compressed by `ShardedFSDB::compress`.
+  compressed: bool,
 }
 
 impl ShardedFSDB {
+  ///
+  /// Returns the path of the file for the given fingerprint. While the layout is being migrated,
+  /// that is its path in the previous layout if it has not been moved yet. And if its shard
+  /// directory was full when it was stored, that is its path in the sub-shard of that directory.
+  /// See `FullShardPolicy::Subshard`.
+  ///
   pub(crate) fn get_path(&self, fingerprint: Fingerprint) -> PathBuf {
+    let path = self.layout_path(fingerprint);
+    if self.full_shard_policy == FullShardPolicy::Subshard && !Self::entry_exists(&path) {
+      let subshard_path = self.subshard_path(fingerprint);
+      if Self::entry_exists(&subshard_path) {
+        return subshard_path;
+      }
+    }
+    match self.previous_layout {
+      Some(previous_layout)
+        if self.migrating.load(atomic::Ordering::SeqCst) && !Self::entry_exists(&path) =>
+      {
+        let hex = fingerprint.to_hex();
+        let previous_path = previous_layout.shard_dir(&self.root, &hex).join(hex);
+        if Self::entry_exists(&previous_path) {
+          previous_path
+        } else {
+          path
+        }
+      }
+      _ => path,
+    }
+  }
+
+  ///
+  /// Returns the path of the file for the given fingerprint in the current layout, which is where
+  /// new files are written.
+  ///
+  fn layout_path(&self, fingerprint: Fingerprint) -> PathBuf {
     let hex = fingerprint.to_hex();
-    self.root.join(hex.get(0..2).unwrap()).join(hex)
+    self.shard_dir(&hex).join(hex)
   }
 
-  pub(crate) async fn get_tempfile(
+  ///
+  /// Returns the path of the file for the given fingerprint in the sub-shard of its shard
+  /// directory, which is used if that directory is full. See `FullShardPolicy::Subshard`.
+  ///
+  fn subshard_path(&self, fingerprint: Fingerprint) -> PathBuf {
+    let hex = fingerprint.to_hex();
+    let len = self.layout.total_prefix_len();
+    self.shard_dir(&hex).join(&hex[len..len + 2]).join(hex)
+  }
+
+  fn entry_exists(path: &Path) -> bool {
+    path.exists() || Self::compressed_path_of(path).exists()
+  }
+
+  ///
+  /// Returns the path at which the file for the given fingerprint is held while it is compressed.
+  /// See `Self::compress`.
+  ///
+  fn get_compressed_path(&self, fingerprint: Fingerprint) -> PathBuf {
+    Self::compressed_path_of(&self.get_path(fingerprint))
+  }
+
+  fn compressed_path_of(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(COMPRESSED_SUFFIX);
+    path.with_file_name(file_name)
+  }
+
+  ///
+  /// Extends the lease of the file at the given path (or of its compressed copy, if it has been
+  /// compressed) by bumping its mtime.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn bump_mtime(path: &Path) -> Result<(), String> {
+    match fs_set_times::set_mtime(path, fs_set_times::SystemTimeSpec::SymbolicNow) {
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        let compressed_path = Self::compressed_path_of(path);
+        fs_set_times::set_mtime(&compressed_path, fs_set_times::SystemTimeSpec::SymbolicNow)
+          .map_err(|_| format!("Failed to extend mtime of {path:?}: {e}"))
+      }
+      result => result.map_err(|e| format!("Failed to extend mtime of {path:?}: {e}")),
+    }
+  }
+
+  ///
+  /// Returns the path of the file for the given fingerprint, first decompressing the file if it
+  /// was compressed by `Self::compress`, so that callers which need a path see its content. The
+  /// file may not exist.
+  ///
+  pub(crate) async fn readable_path(&self, fingerprint: Fingerprint) -> Result<PathBuf, String> {
+    self.readable_path_in(self, fingerprint).await
+  }
+
+  ///
+  /// As `Self::readable_path`, but a compressed file is decompressed into `dest`, and its path
+  /// there is returned. This allows for files which were compressed in a read-only store (which
+  /// cannot be decompressed in place) to be used via a writable store.
+  ///
+  pub(crate) async fn readable_path_in(
     &self,
+    dest: &ShardedFSDB,
     fingerprint: Fingerprint,
-  ) -> Result<TempImmutableLargeFile, String> {
-    let dest_path = self.get_path(fingerprint);
-    tokio::fs::create_dir_all(dest_path.parent().unwrap())
-      .await
-      .map_err(|e| format! {"Failed to create local store subdirectory {dest_path:?}: {e}"})?;
+  ) -> Result<PathBuf, String> {
+    let path = self.get_path(fingerprint);
+    if tokio::fs::metadata(&path).await.is_ok() {
+      return Ok(path);
+    }
+    if self.rehydrate_into(dest, fingerprint).await? {
+      Ok(dest.get_path(fingerprint))
+    } else {
+      Ok(path)
+    }
+  }
 
-    let dest_path2 = dest_path.clone();
-    // Make the tempfile in the same dir as the final file so that materializing the final file doesn't
-    // have to worry about parent dirs.
-    let named_temp_file = self
+  fn lease_lmdb(&self) -> Result<Option<Arc<ShardedLmdb>>, String> {
+    self.lease_lmdb.clone().transpose()
+  }
+
+  fn linked_lmdb(&self) -> Result<Option<Arc<ShardedLmdb>>, String> {
+    self.linked_lmdb.lock().clone().transpose()
+  }
+
+  ///
+  /// Returns those of the given fingerprints whose files are hardlinks to sources outside of the
+  /// store. See `Self::link_to`.
+  ///
+  async fn linked_fingerprints(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    match self.linked_lmdb()? {
+      Some(linked_lmdb) => linked_lmdb.exists_batch(fingerprints).await,
+      None => Ok(HashSet::new()),
+    }
+  }
+
+  pub(crate) async fn is_linked(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    Ok(
+      !self
+        .linked_fingerprints(vec![fingerprint])
+        .await?
+        .is_empty(),
+    )
+  }
+
+  fn record_present(&self, fingerprints: impl IntoIterator<Item = Fingerprint>) {
+    if let Some(existence_cache) = &self.existence_cache {
+      let mut existence_cache = existence_cache.lock();
+      for fingerprint in fingerprints {
+        existence_cache.record_present(fingerprint);
+      }
+    }
+  }
+
+  ///
+  /// Starts a scan to rebuild the Bloom filter of the existence cache in the background, unless it
+  /// is fresh or is already being rebuilt.
+  ///
+  fn maybe_rebuild_existence_filter(&self) {
+    let existence_cache = match &self.existence_cache {
+      Some(existence_cache) if existence_cache.lock().start_rebuild() => existence_cache.clone(),
+      _ => return,
+    };
+    let entries = self.scan_entries(false);
+    let _join = self.executor.native_spawn(async move {
+      let started_at = Instant::now();
+      let fingerprints = entries
+        .map_ok(|entries| {
+          entries
+            .into_iter()
+            .map(|entry| entry.fingerprint)
+            .collect::<Vec<_>>()
+        })
+        .try_concat()
+        .await;
+      let filter = match fingerprints {
+        Ok(fingerprints) => {
+          // Leave room for the files which will be stored before the filter is next rebuilt.
+          let mut filter = BloomFilter::with_capacity(fingerprints.len() * 2);
+          for fingerprint in &fingerprints {
+            filter.insert(fingerprint);
+          }
+          Some((filter, started_at))
+        }
+        Err(e) => {
+          log::warn!("Failed to scan large files to cache their existence: {e}");
+          None
+        }
+      };
+      existence_cache.lock().finish_rebuild(filter);
+    });
+  }
+
+  ///
+  /// Replaces the mtime of each of the given (fingerprint, length, mtime) entries with the time
+  /// that it was last leased: the later of its mtime and (if there is a lease database, or the
+  /// file is linked from outside of the store) the time at which its recorded lease began. Taking
+  /// the later of the two means that a file which was just stored (and so has a new mtime) is
+  /// leased even if its recorded lease has expired, and that files stored before the lease
+  /// database was enabled remain leased.
+  ///
+  async fn apply_recorded_leases(&self, entries: &mut [FsdbEntry]) -> Result<(), String> {
+    let lmdbs = [self.lease_lmdb()?, self.linked_lmdb()?];
+    for lmdb in lmdbs.into_iter().flatten() {
+      let leased_until = lmdb
+        .leased_until_batch(entries.iter().map(|entry| entry.fingerprint).collect())
+        .await?;
+      for entry in entries.iter_mut() {
+        if let Some(leased_at) = leased_until
+          .get(&entry.fingerprint)
+          .and_then(|until| until.checked_sub(self.lease_time))
+        {
+          entry.mtime = leased_at.max(entry.mtime);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn check_writable(&self) -> Result<(), String> {
+    if self.read_only {
+      return Err(format!(
+        "Cannot write to the read-only local store at {}.",
+        self.root.display()
+      ));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Returns the directory which holds the files whose fingerprints begin with the given hex
+  /// prefix, which must be at least `FsdbShardLayout::total_prefix_len` characters long.
+  ///
+  fn shard_dir(&self, hex: &str) -> PathBuf {
+    self.layout.shard_dir(&self.root, hex)
+  }
+
+  async fn precreate_shards(&self) -> Result<(), String> {
+    let len = self.layout.total_prefix_len();
+    let shard_paths = (0..16_usize.pow(len as u32))
+      .map(|prefix| self.shard_dir(&format!("{prefix:0len$x}")))
+      .collect::<Vec<_>>();
+    self
       .executor
       .spawn_blocking(
         move || {
-          NamedTempFile::new_in(dest_path2.parent().unwrap())
-            .map_err(|e| format!("Failed to create temp file: {e}"))
+          for shard_path in shard_paths {
+            std::fs::create_dir_all(&shard_path).map_err(|e| {
+              format!("Failed to create local store subdirectory {shard_path:?}: {e}")
+            })?;
+          }
+          Ok(())
         },
-        |e| Err(format!("temp file creation task failed: {e}")),
+        |e| Err(format!("`precreate_shards` task failed: {e}")),
       )
       .await?;
-    let (_, tmp_path) = named_temp_file.keep().map_err(|e| e.to_string())?;
-    Ok(TempImmutableLargeFile {
-      tmp_path,
-      final_path: dest_path,
-    })
+    self.shards_precreated.store(true, atomic::Ordering::SeqCst);
+    Ok(())
   }
-}
 
-#[async_trait]
-impl UnderlyingByteStore for ShardedFSDB {
-  async fn exists_batch(
+  async fn exists_batch_by_metadata(
     &self,
     fingerprints: Vec<Fingerprint>,
   ) -> Result<HashSet<Fingerprint>, String> {
-    let results = join_all(
-      fingerprints
-        .iter()
-        .map(|fingerprint| tokio::fs::metadata(self.get_path(*fingerprint))),
-    )
+    let results = join_all(fingerprints.iter().map(|fingerprint| async move {
+      match tokio::fs::metadata(self.get_path(*fingerprint)).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+          tokio::fs::metadata(self.get_compressed_path(*fingerprint)).await
+        }
+        result => result,
+      }
+    }))
     .await;
     let existing = results
       .iter()
@@ -234,67 +834,397 @@ impl UnderlyingByteStore for ShardedFSDB {
     Ok(HashSet::from_iter(existing))
   }
 
-  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String> {
-    let path = self.get_path(fingerprint);
-    self
-      .executor
-      .spawn_blocking(
-        move || {
-          fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::SymbolicNow)
-            .map_err(|e| format!("Failed to extend mtime of {path:?}: {e}"))
-        },
-        |e| Err(format!("`lease` task failed: {e}")),
-      )
-      .await
-  }
-
-  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
-    Ok(
-      tokio::fs::remove_file(self.get_path(fingerprint))
+  ///
+  /// Checks for the existence of the given fingerprints by listing each shard directory which
+  /// would contain them once, which is cheaper than a `metadata` call per fingerprint when many
+  /// fingerprints fall in the same shard.
+  ///
+  async fn exists_batch_by_listing(
+    &self,
+    fingerprints_by_shard: HashMap<PathBuf, Vec<Fingerprint>>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    let existing_by_shard = try_join_all(fingerprints_by_shard.into_iter().map(
+      |(shard, fingerprints)| async move {
+        let mut entries = match tokio::fs::read_dir(&shard).await {
+          Ok(entries) => entries,
+          Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+          Err(e) => return Err(format!("Failed to list {}: {e}", shard.display())),
+        };
+        let mut names = HashSet::new();
+        while let Some(entry) = entries
+          .next_entry()
+          .await
+          .map_err(|e| format!("Failed to list {}: {e}", shard.display()))?
+        {
+          names.insert(entry.file_name());
+        }
+        Ok::<_, String>(
+          fingerprints
+            .into_iter()
+            .filter(|fingerprint| {
+              let hex = fingerprint.to_hex();
+              names.contains(std::ffi::OsStr::new(&hex))
+                || names.contains(std::ffi::OsStr::new(&format!("{hex}{COMPRESSED_SUFFIX}")))
+            })
+            .collect::<Vec<_>>(),
+        )
+      },
+    ))
+    .await?;
+
+    Ok(existing_by_shard.into_iter().flatten().collect())
+  }
+
+  pub(crate) async fn get_tempfile(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Result<TempImmutableLargeFile, String> {
+    self.check_writable()?;
+    let subshard_path = match self.full_shard_policy {
+      FullShardPolicy::Fail => None,
+      FullShardPolicy::Subshard => Some(self.subshard_path(fingerprint)),
+    };
+    // NB: A file which is already in its sub-shard is replaced there, rather than duplicated.
+    let dest_path = match &subshard_path {
+      Some(subshard_path) if Self::entry_exists(subshard_path) => subshard_path.clone(),
+      _ => self.layout_path(fingerprint),
+    };
+    if !self.shards_precreated.load(atomic::Ordering::SeqCst) {
+      tokio::fs::create_dir_all(dest_path.parent().unwrap())
         .await
-        .is_ok(),
-    )
+        .map_err(|e| format! {"Failed to create local store subdirectory {dest_path:?}: {e}"})?;
+    }
+
+    let dest_path2 = dest_path.clone();
+    // Make the tempfile in the same dir as the final file so that materializing the final file doesn't
+    // have to worry about parent dirs.
+    let (named_temp_file, dest_path) = self
+      .executor
+      .spawn_blocking(
+        move || {
+          let shard = dest_path2.parent().unwrap();
+          let named_temp_file = match NamedTempFile::new_in(shard) {
+            // The shard directory may have been removed (while it was empty) by
+            // `Self::tidy_shard_dirs`: create it again.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+              std::fs::create_dir_all(shard).and_then(|()| NamedTempFile::new_in(shard))
+            }
+            result => result,
+          };
+          match named_temp_file {
+            Ok(named_temp_file) => Ok((named_temp_file, dest_path2)),
+            Err(e) if platform::is_directory_full(&e, shard) => {
+              let subshard_path = subshard_path.ok_or_else(|| {
+                format!(
+                  "Failed to create temp file in {shard:?}, which cannot hold any more entries \
+                  ({e}). Use a deeper shard layout, or store files in sub-shards of full shard \
+                  directories, to avoid this."
+                )
+              })?;
+              // NB: The file will be moved into place within the sub-shard, so that the move cannot
+              // fail because the shard directory is full, or cross a filesystem boundary.
+              let subshard = subshard_path.parent().unwrap();
+              log::debug!("Shard directory {shard:?} is full: using {subshard:?}.");
+              std::fs::create_dir_all(subshard)
+                .and_then(|()| NamedTempFile::new_in(subshard))
+                .map(|named_temp_file| (named_temp_file, subshard_path.clone()))
+                .map_err(|e| format!("Failed to create temp file in sub-shard {subshard:?}: {e}"))
+            }
+            Err(e) => Err(format!("Failed to create temp file: {e}")),
+          }
+        },
+        |e| Err(format!("temp file creation task failed: {e}")),
+      )
+      .await?;
+    let (_, tmp_path) = named_temp_file.keep().map_err(|e| e.to_string())?;
+    Ok(TempImmutableLargeFile {
+      tmp_path,
+      final_path: dest_path,
+      content_observer: self.content_observer.clone(),
+    })
   }
 
-  async fn store_bytes_batch(
+  ///
+  /// Copies the content of `reader` into the store, hashing it along the way, and returns its
+  /// digest.
+  ///
+  async fn store_from_reader<R: AsyncRead + Send + Unpin>(
     &self,
-    items: Vec<(Fingerprint, Bytes)>,
-    _initial_lease: bool,
-  ) -> Result<(), String> {
-    try_join_all(items.iter().map(|(fingerprint, bytes)| async move {
+    reader: &mut R,
+  ) -> Result<Digest, String> {
+    let digest_function = self.digest_function;
+    self
+      .store_via_root_tempfile(move |tmp_path| async move {
+        let mut dest = tokio::fs::File::create(&tmp_path)
+          .await
+          .map_err(|e| format!("Failed to open {tmp_path:?}: {e}"))?;
+        let digest = async_copy_and_hash_with(digest_function, reader, &mut dest)
+          .await
+          .map_err(|e| format!("Failed to write {tmp_path:?}: {e}"))?;
+        dest
+          .flush()
+          .await
+          .map_err(|e| format!("Failed to write {tmp_path:?}: {e}"))?;
+        Ok(digest)
+      })
+      .await
+  }
+
+  ///
+  /// As `Self::store_from_reader`, but for the (mutable) file at `src`, which is read in chunks on
+  /// one blocking task while the chunks are hashed on a second and written into the store on a
+  /// third. This reads the file only once, whereas `Self::store` must already know its digest,
+  /// and so reads a mutable file twice: once to hash it and once to copy it.
+  ///
+  async fn store_pipelined(
+    &self,
+    src: PathBuf,
+    deadline: Option<Deadline>,
+  ) -> Result<Digest, String> {
+    let executor = self.executor.clone();
+    let digest_function = self.digest_function;
+    self
+      .store_via_root_tempfile(move |tmp_path| async move {
+        let (hash_sender, hash_receiver) = std::sync::mpsc::sync_channel::<Bytes>(PIPELINE_DEPTH);
+        let (write_sender, write_receiver) = std::sync::mpsc::sync_channel::<Bytes>(PIPELINE_DEPTH);
+
+        let read = executor.spawn_blocking(
+          move || {
+            use std::io::Read;
+            let file =
+              std::fs::File::open(&src).map_err(|e| format!("Failed to open {src:?}: {e}"))?;
+            let mut reader = DeadlineReader::new(file, deadline);
+            loop {
+              let mut chunk = Vec::with_capacity(PIPELINE_CHUNK_BYTES);
+              (&mut reader)
+                .take(PIPELINE_CHUNK_BYTES as u64)
+                .read_to_end(&mut chunk)
+                .map_err(|e| format!("Failed to read {src:?}: {e}"))?;
+              if chunk.is_empty() {
+                return Ok(());
+              }
+              let chunk = Bytes::from(chunk);
+              // If either of the other tasks has hung up, it has failed, and will report why.
+              if hash_sender.send(chunk.clone()).is_err() || write_sender.send(chunk).is_err() {
+                return Ok(());
+              }
+            }
+          },
+          |e| Err(format!("Read task failed: {e}")),
+        );
+        let hash = executor.spawn_blocking(
+          move || {
+            let mut hasher = Hasher::new_with(digest_function);
+            for chunk in hash_receiver {
+              hasher.update(&chunk);
+            }
+            Ok(hasher.finish())
+          },
+          |e| Err(format!("Hash task failed: {e}")),
+        );
+        let write = executor.spawn_blocking(
+          move || {
+            use std::io::Write;
+            let mut dest = std::fs::File::create(&tmp_path)
+              .map_err(|e| format!("Failed to open {tmp_path:?}: {e}"))?;
+            for chunk in write_receiver {
+              dest
+                .write_all(&chunk)
+                .map_err(|e| format!("Failed to write {tmp_path:?}: {e}"))?;
+            }
+            dest
+              .flush()
+              .map_err(|e| format!("Failed to write {tmp_path:?}: {e}"))
+          },
+          |e| Err(format!("Write task failed: {e}")),
+        );
+
+        // A failure to read or write stops the other tasks early, so report it in preference to the
+        // (partial) digest.
+        let (read, digest, write) = future::join3(read, hash, write).await;
+        read?;
+        write?;
+        digest
+      })
+      .await
+  }
+
+  ///
+  /// Stores content whose fingerprint is not known until it has been written: `write` is called
+  /// with the path of a temporary file in the root of the store, and returns the digest of the
+  /// content it wrote there, which is then moved into its shard.
+  ///
+  async fn store_via_root_tempfile<F, Fut>(&self, write: F) -> Result<Digest, String>
+  where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<Digest, String>>,
+  {
+    self.check_writable()?;
+    tokio::fs::create_dir_all(&self.root).await.map_err(|e| {
+      format!(
+        "Failed to create local store directory {:?}: {e}",
+        self.root
+      )
+    })?;
+    let root = self.root.clone();
+    let named_temp_file = self
+      .executor
+      .spawn_blocking(
+        move || {
+          NamedTempFile::new_in(&root).map_err(|e| format!("Failed to create temp file: {e}"))
+        },
+        |e| Err(format!("temp file creation task failed: {e}")),
+      )
+      .await?;
+    let (_, tmp_path) = named_temp_file.keep().map_err(|e| e.to_string())?;
+
+    let result = async {
+      let digest = write(tmp_path.clone()).await?;
+
+      let final_path = self.layout_path(digest.hash);
+      if !self.shards_precreated.load(atomic::Ordering::SeqCst) {
+        tokio::fs::create_dir_all(final_path.parent().unwrap())
+          .await
+          .map_err(|e| format!("Failed to create local store subdirectory {final_path:?}: {e}"))?;
+      }
+      let persisted = TempImmutableLargeFile {
+        tmp_path: tmp_path.clone(),
+        final_path,
+        content_observer: self.content_observer.clone(),
+      }
+      .persist()
+      .await;
+      match persisted {
+        // The shard directory may be full: see `FullShardPolicy::Subshard`.
+        Err(e) if self.full_shard_policy == FullShardPolicy::Subshard => {
+          let subshard_path = self.subshard_path(digest.hash);
+          log::debug!("Failed to persist {tmp_path:?} ({e}): moving it to {subshard_path:?}.");
+          tokio::fs::create_dir_all(subshard_path.parent().unwrap())
+            .await
+            .map_err(|e| format!("Failed to create sub-shard for {subshard_path:?}: {e}"))?;
+          TempImmutableLargeFile {
+            tmp_path: tmp_path.clone(),
+            final_path: subshard_path,
+            content_observer: self.content_observer.clone(),
+          }
+          .persist()
+          .await?;
+        }
+        result => result?,
+      }
+      Ok::<_, String>(digest)
+    }
+    .await;
+    if result.is_err() {
+      let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    if let Ok(digest) = &result {
+      self.record_present([digest.hash]);
+    }
+    result
+  }
+
+  ///
+  /// Writes each of the given items to a temporary file, without moving any of them into place. If
+  /// any of the writes fails, all of the temporary files are discarded.
+  ///
+  async fn stage_bytes_batch(
+    &self,
+    items: &[(Fingerprint, Bytes)],
+  ) -> Result<Vec<(Fingerprint, TempImmutableLargeFile)>, String> {
+    let staged = join_all(items.iter().map(|(fingerprint, bytes)| async move {
       let tempfile = self.get_tempfile(*fingerprint).await?;
-      let mut dest = tempfile
-        .open()
+      if self
+        .link_from_shared(&tempfile, Digest::new(*fingerprint, bytes.len()))
         .await
-        .map_err(|e| format!("Failed to open {tempfile:?}: {e}"))?;
-      dest.write_all(bytes).await.map_err(|e| e.to_string())?;
-      tempfile.persist().await?;
-      Ok::<(), String>(())
+      {
+        return Ok((*fingerprint, tempfile));
+      }
+      let written = async {
+        let mut dest = tempfile
+          .open()
+          .await
+          .map_err(|e| format!("Failed to open {tempfile:?}: {e}"))?;
+        dest.write_all(bytes).await.map_err(|e| e.to_string())
+      }
+      .await;
+      if let Err(e) = written {
+        tempfile.discard().await;
+        return Err(e);
+      }
+      Ok((*fingerprint, tempfile))
     }))
-    .await?;
+    .await;
+    if staged.iter().any(Result::is_err) {
+      join_all(
+        staged
+          .iter()
+          .flatten()
+          .map(|(_, tempfile)| tempfile.discard()),
+      )
+      .await;
+    }
+    staged.into_iter().collect()
+  }
 
+  ///
+  /// Moves temporary files which were written by `Self::stage_bytes_batch` into place. If this
+  /// fails, the temporary files which had not yet been moved into place are discarded.
+  ///
+  async fn commit_staged(
+    &self,
+    staged: &[(Fingerprint, TempImmutableLargeFile)],
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    // NB: Every file is attempted (rather than stopping at the first failure) so that none is moved
+    // into place after this returns, which would race with a caller which rolls the batch back.
+    let persisted = join_all(staged.iter().map(|(_, tempfile)| tempfile.persist()))
+      .await
+      .into_iter()
+      .collect::<Result<Vec<()>, String>>();
+    if persisted.is_err() {
+      join_all(staged.iter().map(|(_, tempfile)| tempfile.discard())).await;
+    }
+    persisted?;
+    self.record_present(staged.iter().map(|(fingerprint, _)| *fingerprint));
+
+    // NB: Without a lease database, the new mtime of each file serves as its initial lease.
+    if let (true, Some(lease_lmdb)) = (initial_lease, self.lease_lmdb()?) {
+      lease_lmdb
+        .lease_batch(staged.iter().map(|(fingerprint, _)| *fingerprint).collect())
+        .await?;
+    }
     Ok(())
   }
 
-  async fn store(
+  ///
+  /// Copies `src` into the given temporary file, verifying it against `expected_digest`, and then
+  /// persists the temporary file. The caller is responsible for discarding the temporary file if
+  /// this fails.
+  ///
+  async fn store_to(
     &self,
-    _initial_lease: bool,
+    dest: &TempImmutableLargeFile,
     src_is_immutable: bool,
     expected_digest: Digest,
     src: PathBuf,
+    deadline: Option<Deadline>,
   ) -> Result<(), String> {
-    let dest = self.get_tempfile(expected_digest.hash).await?;
     let mut attempts = 0;
     loop {
-      let (mut reader, mut writer) = try_join(tokio::fs::File::open(src.clone()), dest.open())
+      let (reader, mut writer) = try_join(tokio::fs::File::open(src.clone()), dest.open())
         .await
         .map_err(|e| e.to_string())?;
-      // TODO: Consider using `fclonefileat` on macOS, which would skip actual copying (read+write), and
-      // instead just require verifying the resulting content after the syscall (read only).
-      let should_retry =
-        !async_verified_copy(expected_digest, src_is_immutable, &mut reader, &mut writer)
-          .await
-          .map_err(|e| e.to_string())?;
+      let mut reader = DeadlineReader::new(reader, deadline.clone());
+      let should_retry = !async_verified_copy_with(
+        self.digest_function,
+        expected_digest,
+        src_is_immutable,
+        &mut reader,
+        &mut writer,
+      )
+      .await
+      .map_err(|e| e.to_string())?;
 
       if should_retry {
         attempts += 1;
@@ -313,533 +1243,5657 @@ impl UnderlyingByteStore for ShardedFSDB {
     Ok(())
   }
 
-  async fn load_bytes_with<
-    T: Send + 'static,
-    F: FnMut(&[u8]) -> Result<T, String> + Send + Sync + 'static,
-  >(
-    &self,
-    fingerprint: Fingerprint,
-    mut f: F,
-  ) -> Result<Option<T>, String> {
-    if let Ok(mut file) = tokio::fs::File::open(self.get_path(fingerprint)).await {
-      // TODO: Use mmap instead of copying into user-space.
-      let mut contents: Vec<u8> = vec![];
-      file
-        .read_to_end(&mut contents)
-        .await
-        .map_err(|e| format!("Failed to load large file into memory: {e}"))?;
-      Ok(Some(f(&contents[..])?))
-    } else {
-      Ok(None)
-    }
-  }
-
-  async fn aged_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String> {
-    // NB: The ShardLmdb implementation stores a lease time in the future, and then compares the
-    // current time to the stored lease time for a fingerprint to determine how long ago it
-    // expired. Rather than setting `mtimes` in the future, this implementation instead considers a
-    // file to be expired if its mtime is outside of the lease time window.
-    let root = self.root.clone();
-    let expiration_time = SystemTime::now() - self.lease_time;
-    self
+  ///
+  /// If this store is namespaced, and a file with the given digest is present in another store
+  /// which shares its root (either the un-namespaced store, or the store for another namespace),
+  /// links that file into `dest`, so that identical content is only held once. Returns true if the
+  /// file was linked, in which case it should be persisted with `persist_linked`.
+  ///
+  /// The files of other stores are trusted as this store trusts its own: by name and length. The
+  /// linked file shares its mtime (and so its lease) with the file in the other store, so its
+  /// mtime is bumped.
+  ///
+  async fn link_from_shared(&self, dest: &TempImmutableLargeFile, digest: Digest) -> bool {
+    let shared_root = match (&self.shared_root, self.link_device) {
+      (Some(shared_root), Some(_)) => shared_root.clone(),
+      _ => return false,
+    };
+    let relative_path = self
+      .get_path(digest.hash)
+      .strip_prefix(&self.root)
+      .unwrap()
+      .to_owned();
+    let own_root = self.root.clone();
+    let tmp_path = dest.tmp_path.clone();
+    let linked = self
       .executor
       .spawn_blocking(
         move || {
-          let maybe_shards = std::fs::read_dir(&root);
-          let mut fingerprints = vec![];
-          if let Ok(shards) = maybe_shards {
-            for entry in shards {
-              let shard = entry.map_err(|e| format!("Error iterating dir {root:?}: {e}."))?;
-              let large_files = std::fs::read_dir(shard.path())
-                .map_err(|e| format!("Failed to read shard directory: {e}."))?;
-              for entry in large_files {
-                let large_file = entry.map_err(|e| {
-                  format!("Error iterating dir {:?}: {e}", shard.path().file_name())
-                })?;
-                let path = large_file.path();
-                let hash = path.file_name().unwrap().to_str().unwrap();
-                let (length, mtime) = large_file
-                  .metadata()
-                  .and_then(|metadata| {
-                    let length = metadata.len();
-                    let mtime = metadata.modified()?;
-                    Ok((length, mtime))
-                  })
-                  .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?;
-
-                let expired_seconds_ago = expiration_time
-                  .duration_since(mtime)
-                  .map(|t| t.as_secs())
-                  // 0 indicates unexpired.
-                  .unwrap_or(0);
-
-                fingerprints.push(AgedFingerprint {
-                  expired_seconds_ago,
-                  fingerprint: Fingerprint::from_hex_string(hash)
-                    .map_err(|e| format!("Invalid file store entry at {path:?}: {e}"))?,
-                  size_bytes: length as usize,
-                });
-              }
-            }
+          let fsdb_root = |root: PathBuf| root.join("immutable").join("files");
+          let mut roots = vec![fsdb_root(shared_root.clone())];
+          if let Ok(namespaces) = std::fs::read_dir(shared_root.join(NAMESPACES_DIR)) {
+            roots.extend(
+              namespaces
+                .flatten()
+                .map(|namespace| fsdb_root(namespace.path())),
+            );
           }
-          Ok(fingerprints)
+          let src = roots
+            .into_iter()
+            .filter(|root| root != &own_root)
+            .map(|root| root.join(&relative_path))
+            .find(|src| {
+              std::fs::metadata(src).map_or(false, |metadata| {
+                metadata.is_file() && metadata.len() == digest.size_bytes as u64
+              })
+            });
+          let src = match src {
+            Some(src) => src,
+            None => return Ok(false),
+          };
+          // NB: The temporary file must be removed before a link can be created at its path.
+          std::fs::remove_file(&tmp_path)?;
+          if let Err(e) = std::fs::hard_link(&src, &tmp_path) {
+            // Recreate the temporary file, so that the caller can write to it instead.
+            std::fs::File::create(&tmp_path)?;
+            log::debug!("Could not link {src:?} into the store: {e}");
+            return Ok(false);
+          }
+          fs_set_times::set_mtime(&tmp_path, fs_set_times::SystemTimeSpec::SymbolicNow)?;
+          Ok(true)
         },
-        |e| Err(format!("`aged_fingerprints` task failed: {e}")),
+        |e| Err(io::Error::new(io::ErrorKind::Other, e)),
       )
-      .await
+      .await;
+    linked.unwrap_or_else(|e| {
+      log::debug!("Failed to link {digest:?} from another namespace: {e}");
+      false
+    })
   }
-}
 
-#[derive(Debug, Clone)]
-pub struct ByteStore {
-  inner: Arc<InnerStore>,
-}
+  ///
+  /// Attempts to hardlink `src` into the given temporary file, which is only possible if `src` is a
+  /// read-only regular file on the same device as the store. The linked file is then verified
+  /// against `expected_digest`. Returns false if the file could not be linked, or did not match, in
+  /// which case the caller should fall back to cloning or copying.
+  ///
+  /// NB: The stored file shares an inode with `src`, so the caller must have promised that `src`
+  /// is immutable. Since the store does not own that inode, the caller must record the link with
+  /// `Self::record_linked`: linked files are leased in the database of linked files (rather than
+  /// by bumping the mtime of `src`), and are never memory mapped, so that a source which is
+  /// truncated despite that promise cannot fault a read.
+  ///
+  async fn link_to(
+    &self,
+    dest: &TempImmutableLargeFile,
+    src: &Path,
+    expected_digest: Digest,
+    deadline: Option<Deadline>,
+  ) -> bool {
+    let link_device = match self.link_device {
+      Some(link_device) => link_device,
+      None => return false,
+    };
+    match tokio::fs::metadata(src).await {
+      Ok(metadata)
+        if metadata.is_file()
+          && platform::device_id(&metadata) == Some(link_device)
+          && platform::is_read_only(&metadata) => {}
+      Ok(_) => return false,
+      Err(e) => {
+        log::debug!("Could not access metadata for {src:?}: {e}");
+        return false;
+      }
+    }
 
-#[derive(Debug)]
-struct InnerStore {
-  // Store directories separately from files because:
-  //  1. They may have different lifetimes.
-  //  2. It's nice to know whether we should be able to parse something as a proto.
-  file_lmdb: Result<Arc<ShardedLmdb>, String>,
-  directory_lmdb: Result<Arc<ShardedLmdb>, String>,
-  file_fsdb: ShardedFSDB,
-  executor: task_executor::Executor,
-  filesystem_device: u64,
-}
+    // NB: The temporary file must be removed before a link can be created at its path.
+    let linked = async {
+      tokio::fs::remove_file(&dest.tmp_path).await?;
+      tokio::fs::hard_link(src, &dest.tmp_path).await
+    }
+    .await;
+    if let Err(e) = linked {
+      log::debug!("Could not link {src:?} into the store: {e}");
+      return false;
+    }
 
-impl ByteStore {
-  pub fn new<P: AsRef<Path>>(
-    executor: task_executor::Executor,
-    path: P,
-  ) -> Result<ByteStore, String> {
-    Self::new_with_options(executor, path, super::LocalOptions::default())
+    let verified = async {
+      let mut reader = DeadlineReader::new(dest.open_for_read().await?, deadline);
+      async_copy_and_hash_with(self.digest_function, &mut reader, &mut tokio::io::sink()).await
+    }
+    .await;
+    let matches = match verified {
+      Ok(digest) if digest == expected_digest => true,
+      Ok(digest) => {
+        log::debug!("Link to {src:?} had digest {digest:?}, rather than {expected_digest:?}.");
+        false
+      }
+      Err(e) => {
+        log::debug!("Failed to verify link to {src:?}: {e}");
+        false
+      }
+    };
+    if !matches {
+      // Replace the link with an empty temporary file, so that the caller does not write through
+      // it into `src`.
+      dest.discard().await;
+      if let Err(e) = dest.open().await {
+        log::debug!("Failed to recreate temporary file {:?}: {e}", dest.tmp_path);
+      }
+    }
+    matches
   }
 
-  pub fn new_with_options<P: AsRef<Path>>(
-    executor: task_executor::Executor,
-    path: P,
-    options: super::LocalOptions,
-  ) -> Result<ByteStore, String> {
-    let root = path.as_ref();
-    let lmdb_files_root = root.join("files");
-    let lmdb_directories_root = root.join("directories");
-    let fsdb_files_root = root.join("immutable").join("files");
-
-    fs::safe_create_dir_all(path.as_ref())?;
-
-    let filesystem_device = root
-      .metadata()
-      .map_err(|e| {
-        format!(
-          "Failed to get metadata for store root {}: {e}",
-          root.display()
-        )
-      })?
-      .dev();
-
-    Ok(ByteStore {
-      inner: Arc::new(InnerStore {
-        file_lmdb: ShardedLmdb::new(
-          lmdb_files_root,
-          options.files_max_size_bytes,
-          executor.clone(),
-          options.lease_time,
-          options.shard_count,
-        )
-        .map(Arc::new),
-        directory_lmdb: ShardedLmdb::new(
-          lmdb_directories_root,
-          options.directories_max_size_bytes,
-          executor.clone(),
-          options.lease_time,
-          options.shard_count,
+  ///
+  /// Records that the file for the given fingerprint is a hardlink to a source outside of the
+  /// store, and leases it. See `Self::link_to`.
+  ///
+  /// NB: The file is always leased, since it has the (possibly old) mtime of its source, whereas the
+  /// new mtime of a file which was copied into the store serves as its initial lease.
+  ///
+  async fn record_linked(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    let linked_lmdb = self
+      .linked_lmdb
+      .lock()
+      .get_or_insert_with(|| {
+        ShardedLmdb::new(
+          self.linked_lmdb_root.clone(),
+          FSDB_LINKED_MAX_SIZE_BYTES,
+          self.executor.clone(),
+          self.lease_time,
+          1,
         )
-        .map(Arc::new),
-        file_fsdb: ShardedFSDB {
-          executor: executor.clone(),
-          root: fsdb_files_root,
-          lease_time: options.lease_time,
-        },
-        executor,
-        filesystem_device,
-      }),
-    })
-  }
-
-  pub fn executor(&self) -> &task_executor::Executor {
-    &self.inner.executor
-  }
-
-  pub fn filesystem_device(&self) -> u64 {
-    self.inner.filesystem_device
+        .map(Arc::new)
+      })
+      .clone()?;
+    linked_lmdb
+      .store_bytes_batch(vec![(fingerprint, Bytes::new())], true)
+      .await
   }
 
-  pub async fn entry_type(&self, fingerprint: Fingerprint) -> Result<Option<EntryType>, String> {
-    if fingerprint == EMPTY_DIGEST.hash {
+  ///
+  /// Attempts to clone `src` into the given temporary file using the filesystem (without copying
+  /// it through user-space), and then verifies the content of the clone. Returns false if the
+  /// filesystem could not clone the file, or if the clone did not match `expected_digest`, in
+  /// which case the caller should fall back to copying.
+  ///
+  async fn clone_to(
+    &self,
+    dest: &TempImmutableLargeFile,
+    src: &Path,
+    expected_digest: Digest,
+    deadline: Option<Deadline>,
+  ) -> bool {
+    let (src2, tmp_path) = (src.to_path_buf(), dest.tmp_path.clone());
+    let cloned = self
+      .executor
+      .spawn_blocking(
+        move || Self::clone_file(&src2, &tmp_path),
+        |e| Err(io::Error::new(io::ErrorKind::Other, e)),
+      )
+      .await;
+    if let Err(e) = cloned {
+      log::debug!("Could not clone {src:?} into the store: {e}");
+      return false;
+    }
+
+    let verified = async {
+      let mut reader = DeadlineReader::new(dest.open_for_read().await?, deadline);
+      async_copy_and_hash_with(self.digest_function, &mut reader, &mut tokio::io::sink()).await
+    }
+    .await;
+    match verified {
+      Ok(digest) if digest == expected_digest => {
+        if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+          workunit_store_handle
+            .store
+            .increment_counter(Metric::LocalStoreFilesystemClones, 1);
+        }
+        true
+      }
+      Ok(digest) => {
+        log::debug!("Clone of {src:?} had digest {digest:?}, rather than {expected_digest:?}.");
+        false
+      }
+      Err(e) => {
+        log::debug!("Failed to verify clone of {src:?}: {e}");
+        false
+      }
+    }
+  }
+
+  ///
+  /// Clones `src` to `dest` using `FICLONE`, failing if the filesystem cannot clone it (in which
+  /// case the caller should fall back to copying).
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  #[cfg(target_os = "linux")]
+  fn clone_file(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = std::fs::File::open(src)?;
+    let dest_file = std::fs::OpenOptions::new()
+      .write(true)
+      .truncate(true)
+      .open(dest)?;
+    // Safety: both file descriptors are owned by open files for the duration of the call.
+    let res = unsafe {
+      libc::ioctl(
+        dest_file.as_raw_fd(),
+        libc::FICLONE as _,
+        src_file.as_raw_fd(),
+      )
+    };
+    if res == 0 {
+      Ok(())
+    } else {
+      Err(io::Error::last_os_error())
+    }
+  }
+
+  ///
+  /// Clones `src` to `dest` using `fclonefileat`, which requires that `dest` does not exist.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  #[cfg(target_os = "macos")]
+  fn clone_file(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = std::fs::File::open(src)?;
+    let c_dest = CString::new(dest.as_os_str().as_bytes())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    std::fs::remove_file(dest)?;
+    // Safety: the file descriptor is owned by an open file, and the path is NUL terminated.
+    let res =
+      unsafe { libc::fclonefileat(src_file.as_raw_fd(), libc::AT_FDCWD, c_dest.as_ptr(), 0) };
+    if res == 0 {
+      Ok(())
+    } else {
+      Err(io::Error::last_os_error())
+    }
+  }
+
+  #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+  fn clone_file(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+      io::ErrorKind::Other,
+      "Cloning files is not supported on this platform.",
+    ))
+  }
+
+  ///
+  /// Lists every file below the given root, with the sizes of the files on disk.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn list_entries(root: &Path) -> Result<Vec<FsdbEntry>, String> {
+    let mut entries = vec![];
+    if root.is_dir() {
+      Self::list_shard_entries(root, false, &mut entries)?;
+    }
+    Ok(entries)
+  }
+
+  ///
+  /// Extends the leases of all of the given fingerprints by bumping the mtimes of their files in
+  /// chunks of `FSDB_LEASE_BATCH_SIZE`, with up to `FSDB_SCAN_CONCURRENCY` chunks in flight on
+  /// blocking threads at once, rather than spawning a task per file.
+  ///
+  async fn lease_batch(&self, mut fingerprints: Vec<Fingerprint>) -> Result<(), String> {
+    if let Some(lease_lmdb) = self.lease_lmdb()? {
+      return lease_lmdb.lease_batch(fingerprints).await;
+    }
+    // NB: The mtimes of linked files are those of their sources, which the store does not own.
+    let linked = self.linked_fingerprints(fingerprints.clone()).await?;
+    if !linked.is_empty() {
+      fingerprints.retain(|fingerprint| !linked.contains(fingerprint));
+      self
+        .linked_lmdb()?
+        .unwrap()
+        .lease_batch(linked.into_iter().collect())
+        .await?;
+    }
+    let chunks = fingerprints
+      .chunks(FSDB_LEASE_BATCH_SIZE)
+      .map(|chunk| {
+        Ok(
+          chunk
+            .iter()
+            .map(|fingerprint| self.get_path(*fingerprint))
+            .collect::<Vec<_>>(),
+        )
+      })
+      .collect::<Vec<_>>();
+    let executor = self.executor.clone();
+    stream::iter(chunks)
+      .map_ok(move |paths| {
+        executor.spawn_blocking(
+          move || {
+            for path in paths {
+              Self::bump_mtime(&path)?;
+            }
+            Ok(())
+          },
+          |e| Err(format!("`lease_batch` task failed: {e}")),
+        )
+      })
+      .try_buffer_unordered(FSDB_SCAN_CONCURRENCY)
+      .try_collect::<Vec<()>>()
+      .await?;
+    Ok(())
+  }
+
+  ///
+  /// Lists every file in the store, scanning up to `FSDB_SCAN_CONCURRENCY` top-level shard
+  /// directories at once on blocking threads. Each item of the stream holds the entries of one shard
+  /// directory, in the order that the scans complete. See `Self::list_shard_entries` for the meaning
+  /// of `content_lengths`.
+  ///
+  fn scan_entries(
+    &self,
+    content_lengths: bool,
+  ) -> impl Stream<Item = Result<Vec<FsdbEntry>, String>> + Send + 'static {
+    let root = self.root.clone();
+    let executor = self.executor.clone();
+    let shards = self.executor.spawn_blocking(
+      move || {
+        let entries = match std::fs::read_dir(&root) {
+          Ok(entries) => entries,
+          Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+          Err(e) => return Err(format!("Failed to read {root:?}: {e}")),
+        };
+        let mut shards = vec![];
+        for entry in entries {
+          let entry = entry.map_err(|e| format!("Error iterating dir {root:?}: {e}."))?;
+          if !Self::is_tempfile(&entry.file_name()) && entry.path().is_dir() {
+            shards.push(entry.path());
+          }
+        }
+        Ok(shards)
+      },
+      |e| Err(format!("`scan_entries` task failed: {e}")),
+    );
+    stream::once(shards)
+      .map_ok(|shards| stream::iter(shards.into_iter().map(Ok)))
+      .try_flatten()
+      .map_ok(move |shard| {
+        executor.spawn_blocking(
+          move || {
+            let mut entries = vec![];
+            Self::list_shard_entries(&shard, content_lengths, &mut entries)?;
+            Ok(entries)
+          },
+          |e| Err(format!("`scan_entries` task failed: {e}")),
+        )
+      })
+      .try_buffer_unordered(FSDB_SCAN_CONCURRENCY)
+  }
+
+  ///
+  /// As `Self::scan_entries`, with the sizes of files on disk, and with the mtime of each entry
+  /// replaced by its recorded lease, if that is later. See `Self::apply_recorded_leases`.
+  ///
+  fn leased_entry_batches(&self) -> BoxStream<'static, Result<Vec<FsdbEntry>, String>> {
+    let fsdb = self.clone();
+    self
+      .scan_entries(false)
+      .and_then(move |mut entries| {
+        let fsdb = fsdb.clone();
+        async move {
+          fsdb.apply_recorded_leases(&mut entries).await?;
+          // Each scanned shard directory is counted so that the progress of a long scan is visible.
+          if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+            workunit_store_handle
+              .store
+              .increment_counter(Metric::LocalStoreShardsScanned, 1);
+          }
+          Ok(entries)
+        }
+      })
+      .boxed()
+  }
+
+  ///
+  /// Removes empty shard directories, returning the number of directories which were removed.
+  ///
+  /// NB: Stores which race with the removal of a shard directory create it again.
+  ///
+  async fn tidy_shard_dirs(&self) -> Result<usize, String> {
+    let root = self.root.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut dirs = Self::list_dirs(&root)?;
+          // Deepest first, so that a directory which is only left containing empty directories is
+          // removed too.
+          dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+          let mut removed = 0;
+          for dir in dirs.into_iter().filter(|dir| dir != &root) {
+            // NB: Removal fails if a concurrent store has created a file in the directory.
+            if std::fs::remove_dir(&dir).is_ok() {
+              removed += 1;
+            }
+          }
+          Ok(removed)
+        },
+        |e| Err(format!("`tidy_shard_dirs` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Lists the given directory and every directory below it, regardless of the layout that they
+  /// were created with.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn list_dirs(root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut dirs = vec![];
+    let mut to_visit = vec![root.to_path_buf()];
+    while let Some(dir) = to_visit.pop() {
+      let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(format!("Failed to read {dir:?}: {e}")),
+      };
+      for entry in entries {
+        let entry = entry.map_err(|e| format!("Error iterating dir {dir:?}: {e}."))?;
+        let is_dir = entry
+          .file_type()
+          .map_err(|e| format!("Could not access metadata for {:?}: {e}", entry.path()))?
+          .is_dir();
+        if is_dir && !Self::is_tempfile(&entry.file_name()) {
+          to_visit.push(entry.path());
+        }
+      }
+      dirs.push(dir);
+    }
+    Ok(dirs)
+  }
+
+  ///
+  /// Moves any files which are not at their path in the current layout (because the store was
+  /// written with a different layout) into place, and then removes any directories which were left
+  /// empty. Returns the number of files which were moved, or None if `should_stop` returned true
+  /// (which is checked between directories) before the migration completed.
+  ///
+  /// A migration which is interrupted is completed the next time that it runs, since files are
+  /// found wherever they are below the root. Files with names which are not fingerprints are left
+  /// where they are.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn migrate_layout(&self, should_stop: impl Fn() -> bool) -> Result<Option<usize>, String> {
+    let dirs = Self::list_dirs(&self.root)?;
+    let mut moved = 0;
+    for dir in &dirs {
+      if should_stop() {
+        return Ok(None);
+      }
+      let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(format!("Failed to read {dir:?}: {e}")),
+      };
+      for entry in entries {
+        let entry = entry.map_err(|e| format!("Error iterating dir {dir:?}: {e}."))?;
+        let is_dir = entry
+          .file_type()
+          .map_err(|e| format!("Could not access metadata for {:?}: {e}", entry.path()))?
+          .is_dir();
+        if is_dir || Self::is_tempfile(&entry.file_name()) {
+          continue;
+        }
+        let path = entry.path();
+        let (fingerprint, compressed) = match Self::parse_entry_name(&entry.file_name()) {
+          Some(parsed) => parsed,
+          None => {
+            log::debug!("Not moving {path:?}, which is not a file store entry.");
+            continue;
+          }
+        };
+        let (dest, subshard_dest) = if compressed {
+          (
+            Self::compressed_path_of(&self.layout_path(fingerprint)),
+            Self::compressed_path_of(&self.subshard_path(fingerprint)),
+          )
+        } else {
+          (
+            self.layout_path(fingerprint),
+            self.subshard_path(fingerprint),
+          )
+        };
+        // NB: A file in the sub-shard of its shard directory was put there because the directory
+        // was full. See `FullShardPolicy::Subshard`.
+        if dest == path
+          || (self.full_shard_policy == FullShardPolicy::Subshard && subshard_dest == path)
+        {
+          continue;
+        }
+        std::fs::create_dir_all(dest.parent().unwrap())
+          .map_err(|e| format!("Failed to create local store subdirectory {dest:?}: {e}"))?;
+        match std::fs::rename(&path, &dest) {
+          Ok(()) => moved += 1,
+          // The file may have been removed concurrently.
+          Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+          Err(e) => return Err(format!("Failed to move {path:?} to {dest:?}: {e}")),
+        }
+      }
+    }
+
+    // Remove the directories of the old layout (deepest first), which will now be empty. Removal
+    // fails for any directory which is still in use.
+    let mut dirs = Self::list_dirs(&self.root)?;
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs.into_iter().filter(|dir| dir != &self.root) {
+      let _ = std::fs::remove_dir(&dir);
+    }
+    Ok(Some(moved))
+  }
+
+  ///
+  /// Parses the fingerprint of a file store entry from its file name, and whether it is
+  /// compressed. Returns None for a name which is not that of an entry.
+  ///
+  fn parse_entry_name(file_name: &std::ffi::OsStr) -> Option<(Fingerprint, bool)> {
+    let name = file_name.to_str()?;
+    let (hex, compressed) = match name.strip_suffix(COMPRESSED_SUFFIX) {
+      Some(hex) => (hex, true),
+      None => (name, false),
+    };
+    Some((Fingerprint::from_hex_string(hex).ok()?, compressed))
+  }
+
+  ///
+  /// True if the given directory entry is a temporary file, which is either still being written,
+  /// or was orphaned by a process which died before it could persist it.
+  ///
+  fn is_tempfile(file_name: &std::ffi::OsStr) -> bool {
+    file_name.to_string_lossy().starts_with(TEMPFILE_PREFIX)
+  }
+
+  ///
+  /// Removes temporary files which were last modified longer ago than the lease time: these were
+  /// orphaned by a process which died between creating and persisting them. Returns the number of
+  /// files which were removed.
+  ///
+  async fn remove_stale_tempfiles(&self) -> Result<usize, String> {
+    let root = self.root.clone();
+    let expiration_time = SystemTime::now() - self.lease_time;
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          if !root.exists() {
+            return Ok(0);
+          }
+          let dirs = Self::list_dirs(&root)?;
+
+          let mut removed = 0;
+          for dir in dirs {
+            let entries = match std::fs::read_dir(&dir) {
+              Ok(entries) => entries,
+              Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+              Err(e) => return Err(format!("Failed to read {dir:?}: {e}")),
+            };
+            for entry in entries {
+              let entry = entry.map_err(|e| format!("Error iterating dir {dir:?}: {e}."))?;
+              if !Self::is_tempfile(&entry.file_name()) {
+                continue;
+              }
+              let path = entry.path();
+              let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // The file may have been persisted or discarded concurrently.
+                Err(_) => continue,
+              };
+              let is_stale = metadata
+                .modified()
+                .map(|mtime| mtime < expiration_time)
+                .unwrap_or(false);
+              if is_stale {
+                // Temporary directories are not created by the store, but may have been left by
+                // older versions of it.
+                let remove_result = if metadata.is_dir() {
+                  std::fs::remove_dir_all(&path)
+                } else {
+                  std::fs::remove_file(&path)
+                };
+                match remove_result {
+                  Ok(()) => removed += 1,
+                  Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                  Err(e) => return Err(format!("Failed to remove {path:?}: {e}")),
+                }
+              }
+            }
+          }
+          Ok(removed)
+        },
+        |e| Err(format!("`remove_stale_tempfiles` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Lists every file in the given shard directory, and in any directories below it.
+  ///
+  /// Files are listed with their sizes on disk, unless `content_lengths` is set, in which case the
+  /// header of each compressed file is read to list the length of its content instead.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn list_shard_entries(
+    shard: &Path,
+    content_lengths: bool,
+    entries: &mut Vec<FsdbEntry>,
+  ) -> Result<(), String> {
+    let large_files =
+      std::fs::read_dir(shard).map_err(|e| format!("Failed to read shard directory: {e}."))?;
+    for entry in large_files {
+      let large_file =
+        entry.map_err(|e| format!("Error iterating dir {:?}: {e}", shard.file_name()))?;
+      if Self::is_tempfile(&large_file.file_name()) {
+        continue;
+      }
+      let path = large_file.path();
+      let is_dir = large_file
+        .file_type()
+        .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?
+        .is_dir();
+      if is_dir {
+        Self::list_shard_entries(&path, content_lengths, entries)?;
+        continue;
+      }
+      let (fingerprint, compressed) = match Self::parse_entry_name(&large_file.file_name()) {
+        Some(parsed) => parsed,
+        None => {
+          log::debug!("Ignoring {path:?}, which is not a file store entry.");
+          continue;
+        }
+      };
+      let (len, mtime) = large_file
+        .metadata()
+        .and_then(|metadata| {
+          let len = if compressed && content_lengths {
+            Self::read_compressed_header(&mut std::fs::File::open(&path)?)?
+          } else {
+            metadata.len()
+          };
+          Ok((len, metadata.modified()?))
+        })
+        .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?;
+
+      entries.push(FsdbEntry {
+        fingerprint,
+        len,
+        mtime,
+        compressed,
+      });
+    }
+    Ok(())
+  }
+
+  fn shard_ids() -> impl Iterator<Item = u8> {
+    // NB: Each shard contains the files whose fingerprints begin with a particular byte.
+    0..=u8::MAX
+  }
+
+  ///
+  /// Returns the directories which hold the files in the given shard. If the layout names
+  /// directories by fewer than two hex characters, the single directory returned also holds the
+  /// files of other shards.
+  ///
+  fn shard_dirs(&self, shard_id: u8) -> Vec<PathBuf> {
+    let shard_hex = format!("{shard_id:02x}");
+    let len = self.layout.total_prefix_len();
+    if len <= 2 {
+      return vec![self.shard_dir(&shard_hex[..len])];
+    }
+    let suffix_len = len - 2;
+    (0..16_usize.pow(suffix_len as u32))
+      .map(|suffix| self.shard_dir(&format!("{shard_hex}{suffix:0suffix_len$x}")))
+      .collect()
+  }
+
+  ///
+  /// Returns true if a file with the given digest exists, and has the expected length and content.
+  /// The file is hashed as it is read, rather than being loaded into memory.
+  ///
+  async fn verify(&self, digest: Digest) -> Result<bool, String> {
+    let path = self.get_path(digest.hash);
+    let mut file = match tokio::fs::File::open(&path).await {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return self.verify_compressed(digest).await,
+      Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+    };
+    let len = file
+      .metadata()
+      .await
+      .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?
+      .len();
+    if len != digest.size_bytes as u64 {
+      return Ok(false);
+    }
+    let actual_digest =
+      async_copy_and_hash_with(self.digest_function, &mut file, &mut tokio::io::sink())
+        .await
+        .map_err(|e| format!("Failed to hash {path:?}: {e}"))?;
+    Ok(actual_digest == digest)
+  }
+
+  ///
+  /// Moves the file with the given fingerprint (if it exists) to `dest`, rather than deleting it.
+  /// Its mtime is bumped to record when it was quarantined: see
+  /// `ByteStore::expire_quarantined_entries`.
+  ///
+  /// A file which is linked from outside of the store is copied to `dest` instead, so that neither
+  /// its source nor its mtime is touched. See `Self::link_to`.
+  ///
+  async fn quarantine(&self, fingerprint: Fingerprint, dest: &Path) -> Result<(), String> {
+    let path = self.get_path(fingerprint);
+    if self.is_linked(fingerprint).await? {
+      return match tokio::fs::copy(&path, dest).await {
+        Ok(_) => UnderlyingByteStore::remove(self, fingerprint)
+          .await
+          .map(|_| ()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to copy {path:?} to {dest:?}: {e}")),
+      };
+    }
+    let result = match tokio::fs::rename(&path, dest).await {
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        tokio::fs::rename(
+          self.get_compressed_path(fingerprint),
+          Self::compressed_path_of(dest),
+        )
+        .await
+      }
+      result => result,
+    };
+    match result {
+      Ok(()) => {
+        if let Some(content_observer) = &self.content_observer {
+          content_observer.notify(fingerprint);
+        }
+        let dest = dest.to_owned();
+        self
+          .executor
+          .spawn_blocking(
+            move || Self::bump_mtime(&dest),
+            |e| Err(format!("`quarantine` task failed: {e}")),
+          )
+          .await
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+      Err(e) => Err(format!("Failed to move {path:?} to {dest:?}: {e}")),
+    }
+  }
+
+  ///
+  /// Replaces the file for the given fingerprint with a copy which is compressed with zstd at the
+  /// given level, and named with `COMPRESSED_SUFFIX`. Returns the number of bytes saved, which is
+  /// zero if the file does not exist (or is already compressed), or would not shrink. A file which
+  /// would not shrink is recorded, so that `ByteStore::compress_cold_files` skips it thereafter.
+  ///
+  /// The compressed copy keeps the mtime (and so the lease) of the original. It is decompressed in
+  /// memory by `load_bytes_with`, and on disk by `Self::rehydrate_into` when a caller needs a path.
+  ///
+  /// NB: If the file is concurrently rehydrated, both copies may be removed. Only files whose
+  /// leases have lapsed are compressed, so this is equivalent to the file having been evicted.
+  ///
+  async fn compress(&self, fingerprint: Fingerprint, level: i32) -> Result<u64, String> {
+    let path = self.get_path(fingerprint);
+    let dest = TempImmutableLargeFile {
+      final_path: self.get_compressed_path(fingerprint),
+      // The fingerprint remains present in the store, so observers are not notified.
+      content_observer: None,
+      ..self.get_tempfile(fingerprint).await?
+    };
+    let src = path.clone();
+    let tmp_path = dest.tmp_path.clone();
+    let compressed = self
+      .executor
+      .spawn_blocking(
+        move || {
+          use std::io::Write;
+
+          let mut src_file = match std::fs::File::open(&src) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to open {src:?}: {e}")),
+          };
+          let (len, mtime) = src_file
+            .metadata()
+            .and_then(|metadata| Ok((metadata.len(), metadata.modified()?)))
+            .map_err(|e| format!("Could not access metadata for {src:?}: {e}"))?;
+          let mut dest_file = std::fs::File::create(&tmp_path)
+            .map_err(|e| format!("Failed to open {tmp_path:?}: {e}"))?;
+          dest_file
+            .write_all(&len.to_le_bytes())
+            .and_then(|()| zstd::stream::copy_encode(&mut src_file, &mut dest_file, level))
+            .map_err(|e| format!("Failed to compress {src:?}: {e}"))?;
+          let compressed_len = dest_file
+            .metadata()
+            .map_err(|e| format!("Could not access metadata for {tmp_path:?}: {e}"))?
+            .len();
+          fs_set_times::set_mtime(&tmp_path, fs_set_times::SystemTimeSpec::Absolute(mtime))
+            .map_err(|e| format!("Failed to set mtime of {tmp_path:?}: {e}"))?;
+          Ok(Some((len, compressed_len)))
+        },
+        |e| Err(format!("`compress` task failed: {e}")),
+      )
+      .await;
+    let saved = match compressed {
+      Ok(Some((len, compressed_len))) if compressed_len < len => len - compressed_len,
+      Ok(Some(_)) => {
+        self.incompressible.lock().insert(fingerprint);
+        dest.discard().await;
+        return Ok(0);
+      }
+      result => {
+        dest.discard().await;
+        return result.map(|_| 0);
+      }
+    };
+    if let Err(e) = dest.persist().await {
+      dest.discard().await;
+      return Err(e);
+    }
+    match platform::remove_read_only(&path).await {
+      Ok(()) => Ok(saved),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(saved),
+      Err(e) => Err(format!("Failed to remove {path:?}: {e}")),
+    }
+  }
+
+  ///
+  /// True if the file for the given fingerprint did not shrink when it was last compressed by this
+  /// process, and so should not be compressed again.
+  ///
+  pub(crate) fn is_incompressible(&self, fingerprint: Fingerprint) -> bool {
+    self.incompressible.lock().contains(&fingerprint)
+  }
+
+  ///
+  /// Decompresses the file for the given fingerprint into `dest` if it was compressed by
+  /// `Self::compress`, returning true if it was. The decompressed file keeps the mtime of the
+  /// compressed file, which is removed if `dest` is this store.
+  ///
+  async fn rehydrate_into(
+    &self,
+    dest: &ShardedFSDB,
+    fingerprint: Fingerprint,
+  ) -> Result<bool, String> {
+    let compressed_path = self.get_compressed_path(fingerprint);
+    match tokio::fs::metadata(&compressed_path).await {
+      Ok(_) => (),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+      Err(e) => {
+        return Err(format!(
+          "Could not access metadata for {compressed_path:?}: {e}"
+        ))
+      }
+    }
+    let in_place = dest.root == self.root;
+    let dest = TempImmutableLargeFile {
+      // If the fingerprint was already present in the store, observers are not notified.
+      content_observer: if in_place {
+        None
+      } else {
+        dest.content_observer.clone()
+      },
+      ..dest.get_tempfile(fingerprint).await?
+    };
+    let src = compressed_path.clone();
+    let tmp_path = dest.tmp_path.clone();
+    let decompressed = self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut src_file = match std::fs::File::open(&src) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(format!("Failed to open {src:?}: {e}")),
+          };
+          let mtime = src_file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| format!("Could not access metadata for {src:?}: {e}"))?;
+          let mut dest_file = std::fs::File::create(&tmp_path)
+            .map_err(|e| format!("Failed to open {tmp_path:?}: {e}"))?;
+          Self::decompress(&mut src_file, &mut dest_file)
+            .map_err(|e| format!("Failed to decompress {src:?}: {e}"))?;
+          fs_set_times::set_mtime(&tmp_path, fs_set_times::SystemTimeSpec::Absolute(mtime))
+            .map_err(|e| format!("Failed to set mtime of {tmp_path:?}: {e}"))?;
+          Ok(true)
+        },
+        |e| Err(format!("`rehydrate` task failed: {e}")),
+      )
+      .await;
+    match decompressed {
+      Ok(true) => (),
+      result => {
+        dest.discard().await;
+        return result;
+      }
+    }
+    if let Err(e) = dest.persist().await {
+      dest.discard().await;
+      return Err(e);
+    }
+    if !in_place {
+      return Ok(true);
+    }
+    match platform::remove_read_only(&compressed_path).await {
+      Ok(()) => Ok(true),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+      Err(e) => Err(format!("Failed to remove {compressed_path:?}: {e}")),
+    }
+  }
+
+  ///
+  /// Loads the content of the file for the given fingerprint into memory, if it was compressed by
+  /// `Self::compress`.
+  ///
+  async fn load_compressed(&self, fingerprint: Fingerprint) -> Result<Option<Vec<u8>>, String> {
+    let path = self.get_compressed_path(fingerprint);
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+          };
+          let mut contents = vec![];
+          Self::decompress(&mut file, &mut contents)
+            .map_err(|e| format!("Failed to decompress {path:?}: {e}"))?;
+          Ok(Some(contents))
+        },
+        |e| Err(format!("`load_compressed` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// As `Self::verify`, for a file which was compressed by `Self::compress`. A file which cannot be
+  /// decompressed is reported to be corrupt.
+  ///
+  async fn verify_compressed(&self, digest: Digest) -> Result<bool, String> {
+    let path = self.get_compressed_path(digest.hash);
+    let digest_function = self.digest_function;
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+          };
+          let mut hasher = WriterHasher::new_with(digest_function, io::sink());
+          if let Err(e) = Self::decompress(&mut file, &mut hasher) {
+            log::debug!("Failed to decompress {path:?}: {e}");
+            return Ok(false);
+          }
+          let (actual_digest, _) = hasher.finish();
+          Ok(actual_digest == digest)
+        },
+        |e| Err(format!("`verify_compressed` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Reads the header of a file written by `Self::compress`, which is the length of its content.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn read_compressed_header(src: &mut impl io::Read) -> io::Result<u64> {
+    let mut header = [0; COMPRESSED_HEADER_SIZE];
+    src.read_exact(&mut header)?;
+    Ok(u64::from_le_bytes(header))
+  }
+
+  ///
+  /// Decompresses the content of a file written by `Self::compress` into `dest`, failing if it does
+  /// not have the length recorded in its header.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn decompress(src: &mut std::fs::File, dest: &mut impl io::Write) -> io::Result<()> {
+    let expected_len = Self::read_compressed_header(src)?;
+    let len = io::copy(&mut zstd::stream::read::Decoder::new(src)?, dest)?;
+    if len != expected_len {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("expected {expected_len} bytes of content, but found {len}"),
+      ));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Returns the number of directory entries in each shard directory, including any temporary
+  /// files which are being written, since those count toward filesystem limits too.
+  ///
+  async fn shard_entry_counts(&self) -> Result<Vec<(u8, usize)>, String> {
+    let shards = Self::shard_ids()
+      .map(|shard_id| (shard_id, self.shard_dirs(shard_id)))
+      .collect::<Vec<_>>();
+    // Directories which hold several shards are divided between them by name.
+    let shared_dirs = self.layout.total_prefix_len() < 2;
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          shards
+            .into_iter()
+            .map(|(shard_id, shard_dirs)| {
+              let shard_hex = format!("{shard_id:02x}");
+              let mut count = 0;
+              for shard in shard_dirs {
+                let entries = match std::fs::read_dir(&shard) {
+                  Ok(entries) => entries,
+                  Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                  Err(e) => return Err(format!("Failed to read shard directory {shard:?}: {e}")),
+                };
+                count += entries
+                  .filter(|entry| {
+                    !shared_dirs
+                      || entry.as_ref().map_or(false, |entry| {
+                        entry.file_name().to_string_lossy().starts_with(&shard_hex)
+                      })
+                  })
+                  .count();
+              }
+              Ok((shard_id, count))
+            })
+            .collect()
+        },
+        |e| Err(format!("`shard_entry_counts` task failed: {e}")),
+      )
+      .await
+  }
+
+  async fn digests_in_shard(&self, shard_id: u8) -> Result<Vec<Digest>, String> {
+    let shard_dirs = self.shard_dirs(shard_id);
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut entries = vec![];
+          for shard in shard_dirs {
+            match std::fs::metadata(&shard) {
+              Ok(_) => ShardedFSDB::list_shard_entries(&shard, true, &mut entries)?,
+              Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+              Err(e) => return Err(format!("Failed to access {shard:?}: {e}")),
+            }
+          }
+          Ok(
+            entries
+              .into_iter()
+              .filter(|entry| entry.fingerprint.as_bytes()[0] == shard_id)
+              .map(|entry| Digest::new(entry.fingerprint, entry.len as usize))
+              .collect(),
+          )
+        },
+        |e| Err(format!("`digests_in_shard` task failed: {e}")),
+      )
+      .await
+  }
+}
+
+#[async_trait]
+impl UnderlyingByteStore for ShardedFSDB {
+  async fn exists_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    let fingerprints = if let Some(existence_cache) = &self.existence_cache {
+      self.maybe_rebuild_existence_filter();
+      let existence_cache = existence_cache.lock();
+      fingerprints
+        .into_iter()
+        .filter(|fingerprint| !existence_cache.is_absent(fingerprint))
+        .collect()
+    } else {
+      fingerprints
+    };
+
+    let mut fingerprints_by_shard: HashMap<PathBuf, Vec<Fingerprint>> = HashMap::new();
+    for fingerprint in &fingerprints {
+      let path = self.get_path(*fingerprint);
+      fingerprints_by_shard
+        .entry(path.parent().unwrap().to_path_buf())
+        .or_default()
+        .push(*fingerprint);
+    }
+
+    // Listing a shard is more expensive than checking for a single file in it, so only list when
+    // the batch is dense relative to the number of shards it touches.
+    let dense = !fingerprints.is_empty()
+      && fingerprints.len()
+        >= fingerprints_by_shard
+          .len()
+          .saturating_mul(self.exists_listing_density);
+    let existing = if dense {
+      self.exists_batch_by_listing(fingerprints_by_shard).await?
+    } else {
+      self.exists_batch_by_metadata(fingerprints.clone()).await?
+    };
+
+    if let Some(existence_cache) = &self.existence_cache {
+      let mut existence_cache = existence_cache.lock();
+      for fingerprint in fingerprints {
+        if existing.contains(&fingerprint) {
+          existence_cache.record_present(fingerprint);
+        } else {
+          existence_cache.record_absent(fingerprint);
+        }
+      }
+    }
+    Ok(existing)
+  }
+
+  async fn lease(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    if let Some(lease_lmdb) = self.lease_lmdb()? {
+      return lease_lmdb.lease(fingerprint).await;
+    }
+    if self.is_linked(fingerprint).await? {
+      // NB: The mtime of a linked file is that of its source, which the store does not own.
+      return self.linked_lmdb()?.unwrap().lease(fingerprint).await;
+    }
+    let path = self.get_path(fingerprint);
+    self
+      .executor
+      .spawn_blocking(
+        move || Self::bump_mtime(&path),
+        |e| Err(format!("`lease` task failed: {e}")),
+      )
+      .await
+  }
+
+  async fn is_leased(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    // NB: As in `aged_fingerprints`, a file is considered leased if its mtime (or recorded lease)
+    // is within the lease time window.
+    let metadata = match tokio::fs::metadata(self.get_path(fingerprint)).await {
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        tokio::fs::metadata(self.get_compressed_path(fingerprint)).await
+      }
+      result => result,
+    };
+    match metadata {
+      Ok(metadata) => {
+        let mtime = metadata
+          .modified()
+          .map_err(|e| format!("Could not access mtime for {fingerprint:?}: {e}"))?;
+        let mut entries = [FsdbEntry {
+          fingerprint,
+          len: metadata.len(),
+          mtime,
+          compressed: false,
+        }];
+        self.apply_recorded_leases(&mut entries).await?;
+        Ok(entries[0].mtime + self.lease_time > SystemTime::now())
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+      Err(e) => Err(format!(
+        "Could not access metadata for {fingerprint:?}: {e}"
+      )),
+    }
+  }
+
+  async fn remove(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    let (removed, removed_compressed) = future::join(
+      platform::remove_read_only(&self.get_path(fingerprint)),
+      platform::remove_read_only(&self.get_compressed_path(fingerprint)),
+    )
+    .await;
+    let removed = removed.is_ok() || removed_compressed.is_ok();
+    if let Some(existence_cache) = &self.existence_cache {
+      existence_cache.lock().record_absent(fingerprint);
+    }
+    if removed {
+      if let Some(content_observer) = &self.content_observer {
+        content_observer.notify(fingerprint);
+      }
+      if let Some(lease_lmdb) = self.lease_lmdb()? {
+        lease_lmdb.remove_leases(vec![fingerprint]).await?;
+      }
+      if let Some(linked_lmdb) = self.linked_lmdb()? {
+        linked_lmdb.remove(fingerprint).await?;
+      }
+    }
+    Ok(removed)
+  }
+
+  async fn remove_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    let removed = try_join_all(fingerprints.into_iter().map(|fingerprint| async move {
+      let removed = UnderlyingByteStore::remove(self, fingerprint).await?;
+      Ok::<_, String>(removed.then_some(fingerprint))
+    }))
+    .await?;
+    Ok(removed.into_iter().flatten().collect())
+  }
+
+  async fn store_bytes_batch(
+    &self,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    let staged = self.stage_bytes_batch(&items).await?;
+    self.commit_staged(&staged, initial_lease).await
+  }
+
+  async fn store(
+    &self,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    expected_digest: Digest,
+    src: PathBuf,
+    deadline: Option<Deadline>,
+  ) -> Result<(), String> {
+    let dest = self.get_tempfile(expected_digest.hash).await?;
+    let result = if self.link_from_shared(&dest, expected_digest).await {
+      dest.persist_linked().await
+    } else if src_is_immutable
+      && self
+        .link_to(&dest, &src, expected_digest, deadline.clone())
+        .await
+    {
+      // NB: The link is recorded before it is moved into place, so that it is never leased by
+      // bumping the mtime of its source, nor memory mapped.
+      match self.record_linked(expected_digest.hash).await {
+        Ok(()) => dest.persist_linked().await,
+        Err(e) => Err(e),
+      }
+    } else if src_is_immutable
+      && self
+        .clone_to(&dest, &src, expected_digest, deadline.clone())
+        .await
+    {
+      dest.persist().await
+    } else {
+      self
+        .store_to(&dest, src_is_immutable, expected_digest, src, deadline)
+        .await
+    };
+    if result.is_err() {
+      dest.discard().await;
+      return result;
+    }
+    self.record_present([expected_digest.hash]);
+    if let (true, Some(lease_lmdb)) = (initial_lease, self.lease_lmdb()?) {
+      lease_lmdb.lease(expected_digest.hash).await?;
+    }
+    result
+  }
+
+  async fn load_bytes_with<
+    T: Send + 'static,
+    F: FnMut(&[u8]) -> Result<T, String> + Send + Sync + 'static,
+  >(
+    &self,
+    fingerprint: Fingerprint,
+    mut f: F,
+  ) -> Result<Option<T>, String> {
+    // NB: A linked file shares its inode with a source which the store does not own, and which
+    // could be truncated while it was mapped: so it is always read into memory.
+    if self.mmap_reads && !self.is_linked(fingerprint).await? {
+      let path = self.get_path(fingerprint);
+      let mapped = self
+        .executor
+        .spawn_blocking(
+          move || {
+            let file = match std::fs::File::open(&path) {
+              Ok(file) => file,
+              // Return `f` unused, so that it can be applied to the compressed file (if any).
+              Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Err(f)),
+              Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+            };
+            let len = file
+              .metadata()
+              .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?
+              .len();
+            if len == 0 {
+              // Empty files cannot be mapped.
+              return Ok(Ok(f(&[])?));
+            }
+            // Unsafety: the mapping is only valid for as long as the file is not truncated or
+            // modified. Files are made read-only and are never modified in place once stored, and
+            // unlinking a mapped file (e.g. during garbage collection) leaves the mapping valid.
+            let mapping = unsafe { memmap::Mmap::map(&file) }
+              .map_err(|e| format!("Failed to memory map {path:?}: {e}"))?;
+            Ok(Ok(f(&mapping[..])?))
+          },
+          |e| Err(format!("`load_bytes_with` task failed: {e}")),
+        )
+        .await?;
+      match mapped {
+        Ok(t) => return Ok(Some(t)),
+        Err(unused) => f = unused,
+      }
+    } else if let Ok(mut file) = tokio::fs::File::open(self.get_path(fingerprint)).await {
+      let mut contents: Vec<u8> = vec![];
+      file
+        .read_to_end(&mut contents)
+        .await
+        .map_err(|e| format!("Failed to load large file into memory: {e}"))?;
+      return Ok(Some(f(&contents[..])?));
+    }
+
+    // The file may have been compressed: if so, decompress it into memory, but leave it compressed
+    // on disk, since it may not be used again.
+    match self.load_compressed(fingerprint).await? {
+      Some(contents) => Ok(Some(f(&contents[..])?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn sync(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    let path = self.get_path(fingerprint);
+    let file = match tokio::fs::File::open(&path).await {
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        tokio::fs::File::open(self.get_compressed_path(fingerprint)).await
+      }
+      result => result,
+    };
+    file
+      .map_err(|e| format!("Failed to open {path:?}: {e}"))?
+      .sync_all()
+      .await
+      .map_err(|e| format!("Failed to sync {path:?}: {e}"))
+  }
+
+  fn aged_fingerprint_batches(&self) -> BoxStream<'static, Result<Vec<AgedFingerprint>, String>> {
+    // NB: The ShardLmdb implementation stores a lease time in the future, and then compares the
+    // current time to the stored lease time for a fingerprint to determine how long ago it
+    // expired. Rather than setting `mtimes` in the future, this implementation instead considers a
+    // file to be expired if its mtime is outside of the lease time window.
+    let expiration_time = SystemTime::now() - self.lease_time;
+    self
+      .leased_entry_batches()
+      .map_ok(move |entries| {
+        entries
+          .into_iter()
+          .map(|entry| {
+            let expired_seconds_ago = expiration_time
+              .duration_since(entry.mtime)
+              .map(|t| t.as_secs())
+              // 0 indicates unexpired.
+              .unwrap_or(0);
+
+            AgedFingerprint {
+              expired_seconds_ago,
+              fingerprint: entry.fingerprint,
+              size_bytes: entry.len as usize,
+            }
+          })
+          .collect()
+      })
+      .boxed()
+  }
+
+  async fn all_digests(&self) -> Result<Vec<Digest>, String> {
+    // NB: Unlike `Self::aged_fingerprint_batches`, this lists the lengths of the content of
+    // compressed files, which are the sizes of their digests.
+    self
+      .scan_entries(true)
+      .map_ok(|entries| {
+        stream::iter(
+          entries
+            .into_iter()
+            .map(|entry| Ok(Digest::new(entry.fingerprint, entry.len as usize))),
+        )
+      })
+      .try_flatten()
+      .try_collect()
+      .await
+  }
+
+  async fn aged_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String> {
+    if workunit_store::get_workunit_store_handle().is_some() {
+      in_workunit!(
+        "local_store_scan_shards",
+        Level::Debug,
+        desc = Some(format!("Scanning large files in {}", self.root.display())),
+        |_workunit| self.aged_fingerprint_batches().try_concat()
+      )
+      .await
+    } else {
+      self.aged_fingerprint_batches().try_concat().await
+    }
+  }
+
+  async fn modified_since(&self, since: SystemTime) -> Result<Vec<Digest>, String> {
+    Ok(
+      self
+        .scan_entries(true)
+        .map_ok(|entries| {
+          stream::iter(
+            entries
+              .into_iter()
+              .filter(|entry| entry.mtime > since)
+              .map(|entry| Ok(Digest::new(entry.fingerprint, entry.len as usize))),
+          )
+        })
+        .try_flatten()
+        .try_collect::<Vec<_>>()
+        .await?,
+    )
+  }
+}
+
+/// The times at which entries were recently stored by this process, used to avoid evicting them
+/// before they are `min_age` old.
+#[derive(Debug)]
+struct RecentlyStored {
+  min_age: Duration,
+  stored_at: HashMap<Fingerprint, Instant>,
+  // The size at which entries which are no longer recent will next be pruned.
+  prune_at_len: usize,
+}
+
+impl RecentlyStored {
+  const MIN_PRUNE_AT_LEN: usize = 1024;
+
+  fn new(min_age: Duration) -> Self {
+    Self {
+      min_age,
+      stored_at: HashMap::new(),
+      prune_at_len: Self::MIN_PRUNE_AT_LEN,
+    }
+  }
+
+  fn record(&mut self, fingerprint: Fingerprint) {
+    self.stored_at.insert(fingerprint, Instant::now());
+    if self.stored_at.len() >= self.prune_at_len {
+      self.prune();
+    }
+  }
+
+  fn prune(&mut self) {
+    let min_age = self.min_age;
+    self
+      .stored_at
+      .retain(|_, stored_at| stored_at.elapsed() < min_age);
+    self.prune_at_len = std::cmp::max(self.stored_at.len() * 2, Self::MIN_PRUNE_AT_LEN);
+  }
+
+  fn is_recent(&self, fingerprint: &Fingerprint) -> bool {
+    self
+      .stored_at
+      .get(fingerprint)
+      .map(|stored_at| stored_at.elapsed() < self.min_age)
+      .unwrap_or(false)
+  }
+}
+
+/// The state of a running disk space watchdog. See `ByteStore::start_disk_space_watchdog`.
+#[derive(Debug)]
+struct DiskSpaceWatchdog {
+  min_free_bytes: u64,
+  stop_sender: watch::Sender<bool>,
+  task: JoinHandle<()>,
+  // The result of the most recent check of the available space.
+  free_space: watch::Receiver<FreeSpace>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FreeSpace {
+  available_bytes: u64,
+  // True while entries are being evicted to free space, after which `available_bytes` is updated.
+  evicting: bool,
+}
+
+///
+/// A cache of which large files exist, which allows `ShardedFSDB::exists_batch` to skip checking
+/// the filesystem for most absent files. See `LocalOptions::fsdb_existence_cache_ttl`.
+///
+/// A Bloom filter of every file in the store (rebuilt by a scan once it is older than the TTL)
+/// rules out most absent files, and files which were checked and found to be absent are
+/// remembered for the TTL. Neither will report a file stored by this process as absent, but files
+/// stored by other processes may be reported as absent until the TTL has elapsed.
+///
+#[derive(Debug)]
+struct ExistenceCache {
+  ttl: Duration,
+  // The filter, and the time at which the scan which built it began.
+  filter: Option<(BloomFilter, Instant)>,
+  // Set while the filter is being rebuilt, to collect the files which are stored during the scan
+  // (which it might not see).
+  stored_during_rebuild: Option<Vec<Fingerprint>>,
+  absent_at: HashMap<Fingerprint, Instant>,
+  // The size at which absent entries which have expired will next be pruned.
+  prune_at_len: usize,
+}
+
+impl ExistenceCache {
+  const MIN_PRUNE_AT_LEN: usize = 1024;
+
+  fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      filter: None,
+      stored_during_rebuild: None,
+      absent_at: HashMap::new(),
+      prune_at_len: Self::MIN_PRUNE_AT_LEN,
+    }
+  }
+
+  fn is_absent(&self, fingerprint: &Fingerprint) -> bool {
+    if let Some((filter, built_at)) = &self.filter {
+      if built_at.elapsed() < self.ttl && !filter.may_contain(fingerprint) {
+        return true;
+      }
+    }
+    self
+      .absent_at
+      .get(fingerprint)
+      .map(|absent_at| absent_at.elapsed() < self.ttl)
+      .unwrap_or(false)
+  }
+
+  fn record_present(&mut self, fingerprint: Fingerprint) {
+    self.absent_at.remove(&fingerprint);
+    if let Some((filter, _)) = &mut self.filter {
+      filter.insert(&fingerprint);
+    }
+    if let Some(stored) = &mut self.stored_during_rebuild {
+      stored.push(fingerprint);
+    }
+  }
+
+  fn record_absent(&mut self, fingerprint: Fingerprint) {
+    self.absent_at.insert(fingerprint, Instant::now());
+    if self.absent_at.len() >= self.prune_at_len {
+      let ttl = self.ttl;
+      self
+        .absent_at
+        .retain(|_, absent_at| absent_at.elapsed() < ttl);
+      self.prune_at_len = std::cmp::max(self.absent_at.len() * 2, Self::MIN_PRUNE_AT_LEN);
+    }
+  }
+
+  ///
+  /// Returns true (and marks a rebuild as started) if the filter is missing or stale, and is not
+  /// already being rebuilt.
+  ///
+  fn start_rebuild(&mut self) -> bool {
+    let stale = self
+      .filter
+      .as_ref()
+      .map(|(_, built_at)| built_at.elapsed() >= self.ttl)
+      .unwrap_or(true);
+    if !stale || self.stored_during_rebuild.is_some() {
+      return false;
+    }
+    self.stored_during_rebuild = Some(vec![]);
+    true
+  }
+
+  fn finish_rebuild(&mut self, filter: Option<(BloomFilter, Instant)>) {
+    let stored = self.stored_during_rebuild.take().unwrap_or_default();
+    if let Some((mut filter, built_at)) = filter {
+      for fingerprint in &stored {
+        filter.insert(fingerprint);
+      }
+      self.filter = Some((filter, built_at));
+    }
+  }
+}
+
+///
+/// A Bloom filter of fingerprints. Fingerprints are already uniformly distributed, so their bytes
+/// are used to choose bits directly rather than being hashed again.
+///
+#[derive(Debug)]
+struct BloomFilter {
+  bits: Vec<u64>,
+}
+
+impl BloomFilter {
+  // With ten bits per entry and seven bits set for each, about 1% of lookups are false positives.
+  const BITS_PER_ENTRY: usize = 10;
+  const BITS_SET_PER_ENTRY: u64 = 7;
+  const MIN_CAPACITY: usize = 1024;
+
+  fn with_capacity(entries: usize) -> Self {
+    let bits = std::cmp::max(entries, Self::MIN_CAPACITY) * Self::BITS_PER_ENTRY;
+    Self {
+      bits: vec![0; (bits + 63) / 64],
+    }
+  }
+
+  fn bit_indexes(&self, fingerprint: &Fingerprint) -> impl Iterator<Item = usize> {
+    let bytes = fingerprint.as_bytes();
+    let h1 = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+    // NB: Odd, so that successive bits differ.
+    let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) | 1;
+    let len = self.bits.len() as u64 * 64;
+    (0..Self::BITS_SET_PER_ENTRY).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+  }
+
+  fn insert(&mut self, fingerprint: &Fingerprint) {
+    for index in self.bit_indexes(fingerprint) {
+      self.bits[index / 64] |= 1 << (index % 64);
+    }
+  }
+
+  fn may_contain(&self, fingerprint: &Fingerprint) -> bool {
+    self
+      .bit_indexes(fingerprint)
+      .all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+  }
+}
+
+///
+/// A bounded in-memory cache of blobs, which evicts the least recently used blobs once their total
+/// size exceeds its capacity. See `LocalOptions::memory_cache_bytes`.
+///
+#[derive(Debug)]
+struct MemoryCache {
+  capacity_bytes: usize,
+  used_bytes: usize,
+  // A logical clock which orders uses of entries.
+  next_use: u64,
+  entries: HashMap<(EntryType, Digest), (Bytes, u64)>,
+  // The keys of the entries, ordered by their last use.
+  by_last_use: BTreeMap<u64, (EntryType, Digest)>,
+}
+
+impl MemoryCache {
+  fn new(capacity_bytes: usize) -> Self {
+    Self {
+      capacity_bytes,
+      used_bytes: 0,
+      next_use: 0,
+      entries: HashMap::new(),
+      by_last_use: BTreeMap::new(),
+    }
+  }
+
+  fn get(&mut self, key: (EntryType, Digest)) -> Option<Bytes> {
+    let (bytes, last_use) = self.entries.get_mut(&key)?;
+    self.by_last_use.remove(last_use);
+    *last_use = self.next_use;
+    self.by_last_use.insert(self.next_use, key);
+    self.next_use += 1;
+    Some(bytes.clone())
+  }
+
+  fn insert(&mut self, key: (EntryType, Digest), bytes: Bytes) {
+    if bytes.len() > self.capacity_bytes {
+      return;
+    }
+    self.remove(key);
+    self.used_bytes += bytes.len();
+    self.entries.insert(key, (bytes, self.next_use));
+    self.by_last_use.insert(self.next_use, key);
+    self.next_use += 1;
+    while self.used_bytes > self.capacity_bytes {
+      let (_, key) = self.by_last_use.pop_first().unwrap();
+      let (bytes, _) = self.entries.remove(&key).unwrap();
+      self.used_bytes -= bytes.len();
+    }
+  }
+
+  fn remove(&mut self, key: (EntryType, Digest)) {
+    if let Some((bytes, last_use)) = self.entries.remove(&key) {
+      self.by_last_use.remove(&last_use);
+      self.used_bytes -= bytes.len();
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ByteStore {
+  inner: Arc<InnerStore>,
+  // A faster store which is consulted first for reads, and which is warmed in the background with
+  // blobs which miss in it but are found in this store.
+  read_warm_target: Option<Arc<ByteStore>>,
+  // Stores which are consulted in order for reads which miss in this store. Writes only ever go to
+  // this store. See `ByteStore::with_base_layers`.
+  base_layers: Arc<[ByteStore]>,
+}
+
+#[derive(Debug)]
+struct InnerStore {
+  // Store directories separately from files because:
+  //  1. They may have different lifetimes.
+  //  2. It's nice to know whether we should be able to parse something as a proto.
+  file_lmdb: Result<Arc<ShardedLmdb>, String>,
+  directory_lmdb: Result<Arc<ShardedLmdb>, String>,
+  file_fsdb: ShardedFSDB,
+  // Cached aggregates for directory digests, if enabled. Since directory digests are immutable,
+  // entries never need to be invalidated: only removed along with their directory.
+  directory_stats_lmdb: Option<Result<Arc<ShardedLmdb>, String>>,
+  // Links between entries which are variants of one another (e.g. compressed and decompressed), in
+  // both directions. See `ByteStore::store_with_variant`. Only opened once a link has been stored
+  // (in this or an earlier run), so that stores which do not use variants never consult it.
+  variants_lmdb: Mutex<Option<Result<Arc<ShardedLmdb>, String>>>,
+  // Present if `LocalOptions::record_provenance` is set. See `ByteStore::provenance`.
+  provenance_lmdb: Option<Result<Arc<ShardedLmdb>, String>>,
+  // Present if `LocalOptions::remote_presence_ttl` is set. See `ByteStore::known_remotely`.
+  remote_presence_lmdb: Option<(Result<Arc<ShardedLmdb>, String>, Duration)>,
+  // Entries which are exempt from garbage collection, regardless of their leases. See
+  // `ByteStore::pin`. Only opened once an entry has been pinned (in this or an earlier run), so that
+  // stores which do not use pins never consult it.
+  pins_lmdb: Mutex<Option<Result<Arc<ShardedLmdb>, String>>>,
+  // See `LocalOptions::fsdb_cold_compression_level`.
+  cold_compression_level: Option<i32>,
+  // See `LocalOptions::verify_reads`.
+  verify_reads: bool,
+  // See `LocalOptions::large_file_size_limit`.
+  large_file_size_limit: usize,
+  // True if files may be stored in the backend not chosen by `large_file_size_limit`, because the
+  // store was written with a different limit.
+  fallback_lookups: AtomicBool,
+  slow_op_threshold: Option<Duration>,
+  max_entries_per_shard: Option<usize>,
+  // See `LocalOptions::eviction_policy`.
+  eviction_policy: EvictionPolicy,
+  // Present if `LocalOptions::memory_cache_bytes` is set. Only blobs stored in LMDB are cached.
+  memory_cache: Option<Mutex<MemoryCache>>,
+  // Present if `LocalOptions::min_entry_age_before_eviction` is set.
+  recently_stored: Option<Mutex<RecentlyStored>>,
+  // Tasks which were spawned in the background by this store, and a flag which asks them to stop.
+  // See `ByteStore::shutdown`.
+  background_tasks: Mutex<Vec<JoinHandle<()>>>,
+  shutting_down: AtomicBool,
+  // Present while background garbage collection is running. See `ByteStore::start_background_gc`.
+  background_gc: Mutex<Option<(watch::Sender<bool>, JoinHandle<()>)>>,
+  // Held while evicting entries. See `ByteStore::evict`.
+  eviction_lock: tokio::sync::Mutex<()>,
+  // Present while the disk space watchdog is running. See `ByteStore::start_disk_space_watchdog`.
+  disk_space_watchdog: Mutex<Option<DiskSpaceWatchdog>>,
+  // Stores into the FSDB which are currently running, so that concurrent stores of the same
+  // fingerprint can await one another rather than racing. See `ByteStore::single_flight_store`.
+  in_flight_fsdb_stores: Mutex<HashMap<Fingerprint, Weak<OnceCell<()>>>>,
+  // Present if `LocalOptions::maintain_content_summary` is set. See
+  // `ByteStore::content_summary_fast`.
+  content_summary: Option<Arc<Mutex<ContentSummary>>>,
+  root: PathBuf,
+  executor: task_executor::Executor,
+  // The device of the store root, if it is known. See `platform::device_id`.
+  filesystem_device: Option<u64>,
+  // True if the store root is on a network filesystem. See `LocalOptions::assume_local_fs`.
+  network_filesystem: bool,
+  // See `LocalOptions::read_only`.
+  read_only: bool,
+  // See `LocalOptions::digest_function`.
+  digest_function: DigestFunction,
+  // See `LocalOptions::pipelined_store_min_bytes`.
+  pipelined_store_min_bytes: usize,
+  // Held (shared, or exclusively while the shard layout is migrated) for as long as the store is
+  // open. Absent for a read-only store. See `ByteStore::open_shard_layout`.
+  shard_layout_lock: Option<std::fs::File>,
+}
+
+impl ByteStore {
+  pub fn new<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+  ) -> Result<ByteStore, String> {
+    Self::new_with_options(executor, path, super::LocalOptions::default())
+  }
+
+  pub fn new_with_options<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    options: super::LocalOptions,
+  ) -> Result<ByteStore, String> {
+    Self::new_with_filesystem_detection(executor, path, options, platform::is_network_filesystem)
+  }
+
+  ///
+  /// As `Self::new_with_options`, but with the given function used to detect whether the store root
+  /// is on a network filesystem.
+  ///
+  pub(crate) fn new_with_filesystem_detection<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    options: super::LocalOptions,
+    is_network_filesystem: impl FnOnce(&Path) -> Result<bool, String>,
+  ) -> Result<ByteStore, String> {
+    // Stores for each digest function live side by side, so that switching between functions
+    // neither mixes their entries nor discards either store. SHA-256 stores remain at the root, as
+    // they were before other functions were supported.
+    let root = match options.digest_function {
+      DigestFunction::Sha256 => path.as_ref().to_path_buf(),
+      digest_function => path.as_ref().join(digest_function.to_string()),
+    };
+    // Stores for each namespace live below the (un-namespaced) store root, and link identical large
+    // files between one another.
+    let (root, shared_root) = match &options.namespace {
+      Some(namespace) => {
+        if namespace.is_empty()
+          || namespace == "."
+          || namespace == ".."
+          || namespace.contains(['/', '\\'])
+        {
+          return Err(format!("Invalid local store namespace: {namespace:?}"));
+        }
+        (root.join(NAMESPACES_DIR).join(namespace), Some(root))
+      }
+      None => (root, None),
+    };
+    let root = root.as_path();
+    let lmdb_files_root = root.join("files");
+    let lmdb_directories_root = root.join("directories");
+    let fsdb_files_root = root.join("immutable").join("files");
+    let lmdb_directory_stats_root = root.join("directory_stats");
+    let lmdb_variants_root = root.join("variants");
+    let lmdb_pins_root = root.join("pins");
+    let lmdb_provenance_root = root.join("provenance");
+    let lmdb_remote_presence_root = root.join("remote_presence");
+    let lmdb_fsdb_leases_root = root.join("fsdb_leases");
+    let lmdb_fsdb_linked_root = root.join("fsdb_linked");
+
+    // NB: The files root is created when the store is first opened.
+    let is_new_store = !lmdb_files_root.exists();
+    if options.read_only {
+      if is_new_store {
+        return Err(format!(
+          "Cannot open {} as a read-only local store, because it does not contain a store.",
+          root.display()
+        ));
+      }
+    } else {
+      fs::safe_create_dir_all(root)?;
+    }
+
+    let filesystem_device = root.metadata().map_err(|e| {
+      format!(
+        "Failed to get metadata for store root {}: {e}",
+        root.display()
+      )
+    })?;
+    let filesystem_device = platform::device_id(&filesystem_device);
+
+    // NB: A failure to detect the filesystem is not fatal: the store behaves as it did before
+    // detection existed.
+    let network_filesystem = !options.assume_local_fs
+      && is_network_filesystem(root).unwrap_or_else(|e| {
+        log::warn!("{e}. Assuming that the local store is on a local filesystem.");
+        false
+      });
+    if network_filesystem {
+      log::warn!(
+        "The local store at {} appears to be on a network filesystem. Materialization will copy \
+        rather than link files from the store, and the leases of large files will be recorded in a \
+        database rather than in their modification times. If the filesystem behaves like a local \
+        filesystem, this detection can be overridden.",
+        root.display()
+      );
+    }
+
+    let marker_path = root.join(LARGE_FILE_SIZE_LIMIT_MARKER);
+    let recorded_limit = match std::fs::read_to_string(&marker_path) {
+      Ok(content) => Some(content.trim().parse::<usize>().map_err(|e| {
+        format!(
+          "Failed to parse large file size limit marker {}: {e}",
+          marker_path.display()
+        )
+      })?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => {
+        return Err(format!(
+          "Failed to read large file size limit marker {}: {e}",
+          marker_path.display()
+        ))
+      }
+    };
+    let marker_missing = recorded_limit.is_none();
+    // A store which was written before the marker existed was written with the default limit.
+    let recorded_limit =
+      recorded_limit.or_else(|| (!is_new_store).then_some(DEFAULT_LARGE_FILE_SIZE_LIMIT));
+    let limit_changed = match recorded_limit {
+      Some(limit) if limit != options.large_file_size_limit => match options.on_threshold_change {
+        ThresholdChangeBehavior::Error => {
+          return Err(format!(
+            "The store at {} was written with a large file size limit of {limit} bytes, but the \
+             current limit is {} bytes.",
+            root.display(),
+            options.large_file_size_limit
+          ))
+        }
+        ThresholdChangeBehavior::Rebalance | ThresholdChangeBehavior::Fallback => true,
+      },
+      _ => false,
+    };
+    if marker_missing && !limit_changed && !options.read_only {
+      Self::write_large_file_size_limit_marker(root, options.large_file_size_limit)?;
+    }
+
+    let (shard_layout_lock, shard_layout, previous_shard_layout) =
+      Self::open_shard_layout(root, &options, is_new_store)?;
+
+    let content_summary = if options.maintain_content_summary {
+      Some(Arc::new(Mutex::new(ContentSummary::empty())))
+    } else {
+      None
+    };
+    let content_observer = |entry_type: EntryType| {
+      content_summary.clone().map(|content_summary| {
+        ContentObserver::new(move |fingerprint| {
+          content_summary.lock().toggle(entry_type, fingerprint)
+        })
+      })
+    };
+    let open_lmdb = |root: PathBuf, max_size: usize, shard_count: u8| {
+      if options.read_only {
+        ShardedLmdb::new_read_only(
+          root,
+          max_size,
+          executor.clone(),
+          options.lease_time,
+          shard_count,
+        )
+      } else {
+        ShardedLmdb::new(
+          root,
+          max_size,
+          executor.clone(),
+          options.lease_time,
+          shard_count,
+        )
+      }
+    };
+    let configure_lmdb = |lmdb: ShardedLmdb, entry_type: EntryType| {
+      let lmdb = lmdb
+        .with_value_checksums(options.checksum_lmdb_values)
+        .with_compression_level(options.lmdb_compression_level)
+        .with_digest_function(options.digest_function);
+      Arc::new(match content_observer(entry_type) {
+        Some(content_observer) => lmdb.with_content_observer(content_observer),
+        None => lmdb,
+      })
+    };
+
+    let store = ByteStore {
+      inner: Arc::new(InnerStore {
+        file_lmdb: open_lmdb(
+          lmdb_files_root,
+          options.files_max_size_bytes,
+          options.shard_count,
+        )
+        .map(|lmdb| configure_lmdb(lmdb, EntryType::File)),
+        directory_lmdb: open_lmdb(
+          lmdb_directories_root,
+          options.directories_max_size_bytes,
+          options.shard_count,
+        )
+        .map(|lmdb| configure_lmdb(lmdb, EntryType::Directory)),
+        file_fsdb: ShardedFSDB {
+          executor: executor.clone(),
+          root: fsdb_files_root,
+          lease_time: options.lease_time,
+          exists_listing_density: options.fsdb_exists_listing_density,
+          shards_precreated: Arc::new(AtomicBool::new(false)),
+          full_shard_policy: options.fsdb_full_shard_policy,
+          content_observer: content_observer(EntryType::File),
+          // NB: A mapped file which is modified on another host could fault while it is being read.
+          mmap_reads: options.fsdb_mmap_reads && !network_filesystem,
+          link_device: filesystem_device
+            .filter(|_| !network_filesystem && platform::SUPPORTS_LINKING_INTO_STORE),
+          layout: shard_layout,
+          previous_layout: previous_shard_layout,
+          migrating: Arc::new(AtomicBool::new(previous_shard_layout.is_some())),
+          incompressible: Arc::new(Mutex::new(HashSet::new())),
+          read_only: options.read_only,
+          digest_function: options.digest_function,
+          // NB: Entries are never evicted from a read-only store, so it needs no leases. The
+          // modification times of files on network filesystems are unreliable, so leases are
+          // always recorded in the database there.
+          lease_lmdb: ((options.fsdb_lease_database || network_filesystem) && !options.read_only)
+            .then(|| open_lmdb(lmdb_fsdb_leases_root, FSDB_LEASES_MAX_SIZE_BYTES, 1).map(Arc::new)),
+          linked_lmdb: Arc::new(Mutex::new(lmdb_fsdb_linked_root.exists().then(|| {
+            open_lmdb(lmdb_fsdb_linked_root.clone(), FSDB_LINKED_MAX_SIZE_BYTES, 1).map(Arc::new)
+          }))),
+          linked_lmdb_root: lmdb_fsdb_linked_root,
+          existence_cache: options
+            .fsdb_existence_cache_ttl
+            .map(|ttl| Arc::new(Mutex::new(ExistenceCache::new(ttl)))),
+          shared_root,
+        },
+        directory_stats_lmdb: if options.directory_stats_cache {
+          Some(
+            open_lmdb(lmdb_directory_stats_root, DIRECTORY_STATS_MAX_SIZE_BYTES, 1).map(Arc::new),
+          )
+        } else {
+          None
+        },
+        variants_lmdb: Mutex::new(
+          lmdb_variants_root
+            .exists()
+            .then(|| open_lmdb(lmdb_variants_root, VARIANTS_MAX_SIZE_BYTES, 1).map(Arc::new)),
+        ),
+        provenance_lmdb: (options.record_provenance && !options.read_only)
+          .then(|| open_lmdb(lmdb_provenance_root, PROVENANCE_MAX_SIZE_BYTES, 1).map(Arc::new)),
+        remote_presence_lmdb: options
+          .remote_presence_ttl
+          .filter(|_| !options.read_only)
+          .map(|ttl| {
+            (
+              open_lmdb(lmdb_remote_presence_root, REMOTE_PRESENCE_MAX_SIZE_BYTES, 1).map(Arc::new),
+              ttl,
+            )
+          }),
+        pins_lmdb: Mutex::new(
+          lmdb_pins_root
+            .exists()
+            .then(|| open_lmdb(lmdb_pins_root, PINS_MAX_SIZE_BYTES, 1).map(Arc::new)),
+        ),
+        cold_compression_level: options.fsdb_cold_compression_level,
+        verify_reads: options.verify_reads,
+        large_file_size_limit: options.large_file_size_limit,
+        fallback_lookups: AtomicBool::new(limit_changed),
+        slow_op_threshold: options.slow_op_threshold,
+        max_entries_per_shard: options.max_entries_per_shard,
+        eviction_policy: options.eviction_policy,
+        memory_cache: options
+          .memory_cache_bytes
+          .map(|capacity_bytes| Mutex::new(MemoryCache::new(capacity_bytes))),
+        recently_stored: options
+          .min_entry_age_before_eviction
+          .map(|min_age| Mutex::new(RecentlyStored::new(min_age))),
+        background_tasks: Mutex::new(Vec::new()),
+        shutting_down: AtomicBool::new(false),
+        background_gc: Mutex::new(None),
+        eviction_lock: tokio::sync::Mutex::new(()),
+        disk_space_watchdog: Mutex::new(None),
+        in_flight_fsdb_stores: Mutex::default(),
+        content_summary,
+        root: root.to_path_buf(),
+        executor,
+        filesystem_device,
+        network_filesystem,
+        read_only: options.read_only,
+        digest_function: options.digest_function,
+        pipelined_store_min_bytes: options.pipelined_store_min_bytes,
+        shard_layout_lock,
+      }),
+      read_warm_target: None,
+      base_layers: Arc::new([]),
+    };
+
+    if let Some(content_summary) = &store.inner.content_summary {
+      // NB: No other references to the store exist yet, so it cannot be mutated concurrently.
+      *content_summary.lock() = store.initial_content_summary()?;
+    }
+
+    if let Some(previous_shard_layout) = previous_shard_layout {
+      let migrating_store = store.clone();
+      store.spawn_background(async move {
+        if let Err(e) = migrating_store
+          .migrate_shard_layout(previous_shard_layout)
+          .await
+        {
+          log::warn!(
+            "Failed to move large files in {} from shard layout {previous_shard_layout}: {e}",
+            migrating_store.inner.root.display()
+          );
+        }
+      });
+    }
+
+    // NB: A read-only store cannot be rebalanced, so it falls back to looking up files in both
+    // backends if its limit has changed.
+    if limit_changed && !options.read_only {
+      let rebalancing_store = store.clone();
+      let rebalance = options.on_threshold_change == ThresholdChangeBehavior::Rebalance;
+      store.spawn_background(async move {
+        // NB: When falling back, files are not moved, but if none are misplaced (because there were
+        // none in the range between the limits), the new limit can be recorded immediately.
+        let (result, action) = if rebalance {
+          (
+            rebalancing_store.rebalance_and_record().await,
+            "rebalance files",
+          )
+        } else {
+          (
+            rebalancing_store.record_limit_if_placed().await,
+            "check the placement of files",
+          )
+        };
+        if let Err(e) = result {
+          log::warn!(
+            "Failed to {action} in {}: {e}",
+            rebalancing_store.inner.root.display()
+          );
+        }
+      });
+    }
+
+    if !options.read_only {
+      let sweeping_store = store.clone();
+      store.spawn_background(async move {
+        match sweeping_store
+          .inner
+          .file_fsdb
+          .remove_stale_tempfiles()
+          .await
+        {
+          Ok(0) => (),
+          Ok(removed) => log::debug!("Removed {removed} orphaned temporary files from the store."),
+          Err(e) => log::warn!("Failed to remove orphaned temporary files: {e}"),
+        }
+      });
+    }
+
+    if store.inner.max_entries_per_shard.is_some() {
+      let checking_store = store.clone();
+      store.spawn_background(async move {
+        // NB: Shards which are over the limit are logged by `shard_entry_counts`.
+        if let Err(e) = checking_store.shard_entry_counts().await {
+          log::warn!("Failed to count the entries in each shard: {e}");
+        }
+      });
+    }
+
+    Ok(store)
+  }
+
+  ///
+  /// Spawns a task which runs in the background on behalf of this store, and which will be awaited
+  /// by `Self::shutdown`. Tasks should check `Self::is_shutting_down` between units of work.
+  ///
+  fn spawn_background<F: Future<Output = ()> + Send + 'static>(&self, task: F) {
+    // NB: The flag is checked while holding the lock so that a concurrent `Self::shutdown` cannot
+    // miss this task.
+    let mut background_tasks = self.inner.background_tasks.lock();
+    if self.is_shutting_down() {
+      return;
+    }
+    background_tasks.retain(|handle| !handle.is_finished());
+    background_tasks.push(self.inner.executor.native_spawn(task));
+  }
+
+  fn is_shutting_down(&self) -> bool {
+    self.inner.shutting_down.load(atomic::Ordering::SeqCst)
+  }
+
+  ///
+  /// Asks all tasks which this store has spawned in the background (such as rebalancing files
+  /// after a change to the large file size limit, or warming a faster store) to stop, and waits
+  /// for them to do so. No new background tasks will be spawned afterward.
+  ///
+  /// Because `Drop` cannot wait for tasks to complete, embedders should call `shutdown().await`
+  /// rather than just dropping a store which might have spawned background tasks: otherwise those
+  /// tasks may continue to run (and to hold the store open) after it has been dropped.
+  ///
+  pub async fn shutdown(self) {
+    self.stop_background_gc().await;
+    self.stop_disk_space_watchdog().await;
+    let background_tasks = {
+      let mut background_tasks = self.inner.background_tasks.lock();
+      self
+        .inner
+        .shutting_down
+        .store(true, atomic::Ordering::SeqCst);
+      std::mem::take(&mut *background_tasks)
+    };
+    for result in join_all(background_tasks).await {
+      if let Err(e) = result {
+        log::warn!("Background task of the local store failed: {e}");
+      }
+    }
+
+    if let Some(content_summary) = self
+      .content_summary_fast()
+      .filter(|_| !self.inner.read_only)
+    {
+      let marker_path = self.inner.root.join(CONTENT_SUMMARY_MARKER);
+      let content = format!(
+        "{}\n{}\n",
+        content_summary.files, content_summary.directories
+      );
+      if let Err(e) = std::fs::write(&marker_path, content) {
+        log::warn!(
+          "Failed to write content summary marker {}: {e}",
+          marker_path.display()
+        );
+      }
+    }
+  }
+
+  ///
+  /// Returns a summary of the entries in the store which is maintained as the store is mutated, if
+  /// `LocalOptions::maintain_content_summary` is set. Comparing the summaries of two stores is a
+  /// cheap way to determine whether they (very likely) contain the same entries.
+  ///
+  /// The summary is persisted by `Self::shutdown`, and recomputed by listing the store when a store
+  /// which was not shut down is next opened. Only mutations made via this instance are observed:
+  /// if another process mutates the store concurrently, the summary will diverge from
+  /// `Self::content_summary` until the store is reopened without a persisted summary.
+  ///
+  pub fn content_summary_fast(&self) -> Option<ContentSummary> {
+    self
+      .inner
+      .content_summary
+      .as_ref()
+      .map(|content_summary| *content_summary.lock())
+  }
+
+  ///
+  /// Computes a summary of the entries in the store by listing all of them. See
+  /// `Self::content_summary_fast`.
+  ///
+  pub async fn content_summary(&self) -> Result<ContentSummary, String> {
+    let store = self.clone();
+    self
+      .inner
+      .executor
+      .spawn_blocking(
+        move || store.compute_content_summary(),
+        |e| Err(format!("`content_summary` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn compute_content_summary(&self) -> Result<ContentSummary, String> {
+    let mut content_summary = ContentSummary::empty();
+    for aged_fingerprint in self.inner.file_lmdb.clone()?.all_fingerprints_blocking()? {
+      content_summary.toggle(EntryType::File, aged_fingerprint.fingerprint);
+    }
+    for entry in ShardedFSDB::list_entries(&self.inner.file_fsdb.root)? {
+      content_summary.toggle(EntryType::File, entry.fingerprint);
+    }
+    for aged_fingerprint in self
+      .inner
+      .directory_lmdb
+      .clone()?
+      .all_fingerprints_blocking()?
+    {
+      content_summary.toggle(EntryType::Directory, aged_fingerprint.fingerprint);
+    }
+    Ok(content_summary)
+  }
+
+  ///
+  /// Consumes the content summary persisted by `Self::shutdown`, or computes one if it is absent.
+  /// The persisted summary is removed so that it will not be used if this store is not shut down.
+  ///
+  fn initial_content_summary(&self) -> Result<ContentSummary, String> {
+    let marker_path = self.inner.root.join(CONTENT_SUMMARY_MARKER);
+    let content = match std::fs::read_to_string(&marker_path) {
+      Ok(content) => content,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return self.compute_content_summary(),
+      Err(e) => {
+        return Err(format!(
+          "Failed to read content summary marker {}: {e}",
+          marker_path.display()
+        ))
+      }
+    };
+    // NB: A read-only store cannot be mutated, so its marker remains valid.
+    if !self.inner.read_only {
+      std::fs::remove_file(&marker_path).map_err(|e| {
+        format!(
+          "Failed to remove content summary marker {}: {e}",
+          marker_path.display()
+        )
+      })?;
+    }
+    let mut lines = content.lines().map(Fingerprint::from_hex_string);
+    match (lines.next(), lines.next(), lines.next()) {
+      (Some(Ok(files)), Some(Ok(directories)), None) => Ok(ContentSummary { files, directories }),
+      _ => {
+        log::warn!(
+          "Ignoring invalid content summary marker {}.",
+          marker_path.display()
+        );
+        self.compute_content_summary()
+      }
+    }
+  }
+
+  ///
+  /// If a slow op threshold is configured and the given operation (which began at `start`) has
+  /// exceeded it, logs a warning and records it as slow.
+  ///
+  fn check_slow_op(
+    &self,
+    operation: &'static str,
+    backend: &'static str,
+    digest: Digest,
+    start: Instant,
+  ) {
+    let threshold = if let Some(threshold) = self.inner.slow_op_threshold {
+      threshold
+    } else {
+      return;
+    };
+    let elapsed = start.elapsed();
+    if elapsed <= threshold {
+      return;
+    }
+
+    log::warn!(
+      "Slow local store operation: {operation} of {digest:?} in {backend} took {elapsed:?} \
+       (threshold: {threshold:?})"
+    );
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .increment_counter(Metric::LocalStoreSlowOperations, 1);
+    }
+  }
+
+  ///
+  /// Records metrics for a write of blobs with the given sizes into one backend, which started at
+  /// `start`.
+  ///
+  fn record_writes(&self, uses_fsdb: bool, sizes: &[usize], start: Instant) {
+    if sizes.is_empty() {
+      return;
+    }
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      let counter = if uses_fsdb {
+        Metric::LocalStoreFsdbWrites
+      } else {
+        Metric::LocalStoreLmdbWrites
+      };
+      workunit_store_handle
+        .store
+        .increment_counter(counter, sizes.len() as u64);
+      for size in sizes {
+        workunit_store_handle
+          .store
+          .record_observation(ObservationMetric::LocalStoreWriteBlobSize, *size as u64);
+      }
+      workunit_store_handle.store.record_observation(
+        ObservationMetric::LocalStoreWriteBlobTimeMicros,
+        start.elapsed().as_micros() as u64,
+      );
+    }
+  }
+
+  fn is_recently_stored(&self, fingerprint: &Fingerprint) -> bool {
+    self
+      .inner
+      .recently_stored
+      .as_ref()
+      .map(|recently_stored| recently_stored.lock().is_recent(fingerprint))
+      .unwrap_or(false)
+  }
+
+  fn record_stored(&self, fingerprints: impl IntoIterator<Item = Fingerprint>) {
+    if let Some(recently_stored) = &self.inner.recently_stored {
+      let mut recently_stored = recently_stored.lock();
+      for fingerprint in fingerprints {
+        recently_stored.record(fingerprint);
+      }
+    }
+  }
+
+  ///
+  /// Records the origin of the current task (if any: see `scope_task_origin`) as the provenance of
+  /// the given fingerprints, unless they already have one. Failures are logged rather than failing
+  /// the store.
+  ///
+  pub(crate) async fn record_provenance(&self, fingerprints: Vec<Fingerprint>) {
+    let provenance_lmdb = match &self.inner.provenance_lmdb {
+      Some(provenance_lmdb) => provenance_lmdb,
+      None => return,
+    };
+    let origin = match super::task_origin() {
+      Some(origin) => origin,
+      None => return,
+    };
+    let (kind, description) = match &origin {
+      Origin::Process(description) => (0_u8, description),
+      Origin::Rule(description) => (1_u8, description),
+      Origin::RemoteFetch(description) => (2_u8, description),
+    };
+    let recorded_at = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_millis() as u64)
+      .unwrap_or(0);
+    let mut bytes = BytesMut::with_capacity(9 + description.len());
+    bytes.extend_from_slice(&[kind]);
+    bytes.extend_from_slice(&recorded_at.to_le_bytes());
+    bytes.extend_from_slice(description.as_bytes());
+    let bytes = bytes.freeze();
+    let result = async {
+      // NB: Existing values are not overwritten, so the first recorded provenance is kept.
+      provenance_lmdb
+        .clone()?
+        .store_bytes_batch(
+          fingerprints
+            .into_iter()
+            .map(|fingerprint| (fingerprint, bytes.clone()))
+            .collect(),
+          false,
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = result {
+      log::warn!("Failed to record the provenance of stored entries: {e}");
+    }
+  }
+
+  ///
+  /// Returns where the given digest came from, if it was stored by a task with an `Origin` while
+  /// `LocalOptions::record_provenance` was set. The first recorded provenance of an entry is kept
+  /// until the entry is removed.
+  ///
+  pub async fn provenance(&self, digest: Digest) -> Result<Option<Provenance>, String> {
+    let provenance_lmdb = match &self.inner.provenance_lmdb {
+      Some(provenance_lmdb) => provenance_lmdb.clone()?,
+      None => return Ok(None),
+    };
+    let fingerprint = digest.hash;
+    provenance_lmdb
+      .load_bytes_with(fingerprint, move |bytes| {
+        if bytes.len() < 9 {
+          return Err(format!(
+            "Provenance of {fingerprint:?} had an unexpected length: {}",
+            bytes.len()
+          ));
+        }
+        let description = std::str::from_utf8(&bytes[9..])
+          .map_err(|e| format!("Provenance of {fingerprint:?} was not valid UTF-8: {e}"))?
+          .to_owned();
+        let origin = match bytes[0] {
+          0 => Origin::Process(description),
+          1 => Origin::Rule(description),
+          2 => Origin::RemoteFetch(description),
+          kind => {
+            return Err(format!(
+              "Provenance of {fingerprint:?} had an unknown kind: {kind}"
+            ))
+          }
+        };
+        let mut array = [0_u8; 8];
+        array.copy_from_slice(&bytes[1..9]);
+        Ok(Provenance {
+          origin,
+          recorded_at: SystemTime::UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(array)),
+        })
+      })
+      .await
+  }
+
+  ///
+  /// The key under which the presence of the given digest in the given remote store is recorded.
+  ///
+  fn remote_presence_key(remote: &str, digest: Digest) -> Fingerprint {
+    let mut hasher = Hasher::new();
+    hasher.update(remote.as_bytes());
+    hasher.update(digest.hash.as_bytes());
+    hasher.update(&(digest.size_bytes as u64).to_le_bytes());
+    hasher.finish().hash
+  }
+
+  ///
+  /// Returns those of the given digests which the given remote store (identified by its address
+  /// and instance name) was recorded as having within `LocalOptions::remote_presence_ttl`, and
+  /// which need not be asked about again. Failures are logged, and treated as the digests not
+  /// being known.
+  ///
+  pub(crate) async fn known_remotely(
+    &self,
+    remote: &str,
+    digests: impl IntoIterator<Item = Digest>,
+  ) -> HashSet<Digest> {
+    let remote_presence_lmdb = match &self.inner.remote_presence_lmdb {
+      Some((Ok(remote_presence_lmdb), _)) => remote_presence_lmdb,
+      _ => return HashSet::new(),
+    };
+    let now = Self::millis_since_epoch(SystemTime::now());
+    let lookups = digests.into_iter().map(|digest| async move {
+      let expires_at = remote_presence_lmdb
+        .load_bytes_with(Self::remote_presence_key(remote, digest), |bytes| {
+          let bytes: [u8; 8] = bytes
+            .try_into()
+            .map_err(|_| format!("Unexpected length: {}", bytes.len()))?;
+          Ok(u64::from_le_bytes(bytes))
+        })
+        .await;
+      (digest, expires_at)
+    });
+
+    let mut known = HashSet::new();
+    let mut expired = Vec::new();
+    for (digest, expires_at) in join_all(lookups).await {
+      match expires_at {
+        Ok(Some(expires_at)) if expires_at > now => {
+          known.insert(digest);
+        }
+        Ok(Some(_)) => expired.push(digest),
+        Ok(None) => (),
+        Err(e) => log::debug!("Failed to look up whether {remote} has {digest:?}: {e}"),
+      }
+    }
+    self.forget_known_remotely(remote, expired).await;
+    known
+  }
+
+  ///
+  /// Records that the given remote store has the given digests, if
+  /// `LocalOptions::remote_presence_ttl` is set. Failures are logged rather than returned.
+  ///
+  pub(crate) async fn record_known_remotely(&self, remote: &str, digests: Vec<Digest>) {
+    let (remote_presence_lmdb, ttl) = match &self.inner.remote_presence_lmdb {
+      Some((Ok(remote_presence_lmdb), ttl)) => (remote_presence_lmdb, ttl),
+      _ => return,
+    };
+    if digests.is_empty() {
+      return;
+    }
+    let expires_at =
+      Bytes::copy_from_slice(&Self::millis_since_epoch(SystemTime::now() + *ttl).to_le_bytes());
+    let keys = digests
+      .into_iter()
+      .map(|digest| Self::remote_presence_key(remote, digest))
+      .collect::<Vec<_>>();
+    let result = async {
+      // NB: Existing values are not overwritten by stores, so any earlier expiry is removed first.
+      remote_presence_lmdb.remove_batch(keys.clone()).await?;
+      remote_presence_lmdb
+        .store_bytes_batch(
+          keys
+            .into_iter()
+            .map(|key| (key, expires_at.clone()))
+            .collect(),
+          false,
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = result {
+      log::warn!("Failed to record the digests which {remote} has: {e}");
+    }
+  }
+
+  ///
+  /// Removes any record that the given remote store has the given digests, so that it will be
+  /// asked about them again.
+  ///
+  pub(crate) async fn forget_known_remotely(&self, remote: &str, digests: Vec<Digest>) {
+    let remote_presence_lmdb = match &self.inner.remote_presence_lmdb {
+      Some((Ok(remote_presence_lmdb), _)) => remote_presence_lmdb,
+      _ => return,
+    };
+    if digests.is_empty() {
+      return;
+    }
+    let keys = digests
+      .into_iter()
+      .map(|digest| Self::remote_presence_key(remote, digest))
+      .collect();
+    if let Err(e) = remote_presence_lmdb.remove_batch(keys).await {
+      log::warn!("Failed to forget the digests which {remote} has: {e}");
+    }
+  }
+
+  fn millis_since_epoch(time: SystemTime) -> u64 {
+    time
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_millis() as u64)
+      .unwrap_or(0)
+  }
+
+  fn backend_name(uses_fsdb: bool) -> &'static str {
+    if uses_fsdb {
+      "fsdb"
+    } else {
+      "lmdb"
+    }
+  }
+
+  ///
+  /// Decides the `FsdbShardLayout` that the store at the given root will use, returning the lock
+  /// which must be held for as long as the store is open, the layout, and the layout that the
+  /// store was written with (if its files must be moved).
+  ///
+  /// Every writable store holds a shared lock on the root while it is open, and the layout is only
+  /// migrated by a store which can take an exclusive lock: i.e., while no other store (in any
+  /// process) has the root open. Otherwise the store continues to use the layout that it was
+  /// written with, so that stores which are configured with different layouts do not move files
+  /// out from under one another.
+  ///
+  fn open_shard_layout(
+    root: &Path,
+    options: &super::LocalOptions,
+    is_new_store: bool,
+  ) -> Result<
+    (
+      Option<std::fs::File>,
+      FsdbShardLayout,
+      Option<FsdbShardLayout>,
+    ),
+    String,
+  > {
+    let lock = if options.read_only {
+      None
+    } else {
+      let lock_path = root.join(FSDB_SHARD_LAYOUT_LOCK);
+      let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Failed to open {}: {e}", lock_path.display()))?;
+      let exclusive = platform::try_lock_exclusive(&lock)
+        .map_err(|e| format!("Failed to lock {}: {e}", lock_path.display()))?;
+      if !exclusive {
+        // NB: This waits for any migration by another store to complete.
+        platform::lock_shared(&lock)
+          .map_err(|e| format!("Failed to lock {}: {e}", lock_path.display()))?;
+      }
+      Some((lock, exclusive))
+    };
+
+    // A store which was written before the marker existed was written with the default layout.
+    let layout_marker_path = root.join(FSDB_SHARD_LAYOUT_MARKER);
+    let recorded_layout = match std::fs::read_to_string(&layout_marker_path) {
+      Ok(content) => Some(content.parse::<FsdbShardLayout>().map_err(|e| {
+        format!(
+          "Failed to parse shard layout marker {}: {e}",
+          layout_marker_path.display()
+        )
+      })?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        (!is_new_store).then(FsdbShardLayout::default)
+      }
+      Err(e) => {
+        return Err(format!(
+          "Failed to read shard layout marker {}: {e}",
+          layout_marker_path.display()
+        ))
+      }
+    };
+
+    // Unless a migration is needed, the exclusive lock is downgraded before returning, so that
+    // other stores may open concurrently.
+    let downgrade = |lock: std::fs::File| -> Result<Option<std::fs::File>, String> {
+      platform::lock_shared(&lock)
+        .map_err(|e| format!("Failed to downgrade shard layout lock: {e}"))?;
+      Ok(Some(lock))
+    };
+    let configured_layout = options.fsdb_shard_layout;
+    match (lock, recorded_layout) {
+      (None, Some(recorded_layout)) if recorded_layout != configured_layout => Err(format!(
+        "The read-only store at {} was written with shard layout {recorded_layout}, but the \
+         current layout is {configured_layout}.",
+        root.display(),
+      )),
+      (None, _) => Ok((None, configured_layout, None)),
+      (Some((lock, _)), None) => {
+        Self::write_shard_layout_marker(root, configured_layout)?;
+        Ok((downgrade(lock)?, configured_layout, None))
+      }
+      (Some((lock, _)), Some(recorded_layout)) if recorded_layout == configured_layout => {
+        Ok((downgrade(lock)?, configured_layout, None))
+      }
+      (Some((lock, true)), Some(recorded_layout)) => {
+        Ok((Some(lock), configured_layout, Some(recorded_layout)))
+      }
+      (Some((lock, false)), Some(recorded_layout)) => {
+        log::warn!(
+          "The store at {} was written with shard layout {recorded_layout}, and is in use by \
+           another process, so it will continue to use that layout rather than {configured_layout} \
+           until it is next opened while it is not in use.",
+          root.display()
+        );
+        Ok((Some(lock), recorded_layout, None))
+      }
+    }
+  }
+
+  ///
+  /// Moves the large files of this store from the given layout into its current layout, and then
+  /// records the current layout and releases the exclusive lock that was taken to migrate it. See
+  /// `Self::open_shard_layout`.
+  ///
+  async fn migrate_shard_layout(&self, previous_layout: FsdbShardLayout) -> Result<(), String> {
+    let store = self.clone();
+    let moved = self
+      .inner
+      .executor
+      .spawn_blocking(
+        move || {
+          let should_stop = || store.is_shutting_down();
+          store.inner.file_fsdb.migrate_layout(should_stop)
+        },
+        |e| Err(format!("`migrate_layout` task failed: {e}")),
+      )
+      .await?;
+    let moved = match moved {
+      Some(moved) => moved,
+      // The migration will be completed the next time that the store is opened.
+      None => return Ok(()),
+    };
+    let layout = self.inner.file_fsdb.layout;
+    Self::write_shard_layout_marker(&self.inner.root, layout)?;
+    self
+      .inner
+      .file_fsdb
+      .migrating
+      .store(false, atomic::Ordering::SeqCst);
+    if let Some(lock) = &self.inner.shard_layout_lock {
+      platform::lock_shared(lock)
+        .map_err(|e| format!("Failed to release shard layout lock: {e}"))?;
+    }
+    log::info!(
+      "Moved {moved} large files in {} from shard layout {previous_layout} to {layout}.",
+      self.inner.root.display(),
+    );
+    Ok(())
+  }
+
+  ///
+  /// Records the shard layout of the store at the given root. The marker is replaced atomically,
+  /// so that a store which is interrupted while writing it does not leave it truncated.
+  ///
+  fn write_shard_layout_marker(root: &Path, layout: FsdbShardLayout) -> Result<(), String> {
+    let marker_path = root.join(FSDB_SHARD_LAYOUT_MARKER);
+    let mut marker = NamedTempFile::new_in(root)
+      .map_err(|e| format!("Failed to create temp file in {}: {e}", root.display()))?;
+    io::Write::write_all(&mut marker, layout.to_string().as_bytes())
+      .map_err(|e| format!("Failed to write {:?}: {e}", marker.path()))?;
+    marker.persist(&marker_path).map_err(|e| {
+      format!(
+        "Failed to write shard layout marker {}: {e}",
+        marker_path.display()
+      )
+    })?;
+    Ok(())
+  }
+
+  fn write_large_file_size_limit_marker(root: &Path, limit: usize) -> Result<(), String> {
+    let marker_path = root.join(LARGE_FILE_SIZE_LIMIT_MARKER);
+    std::fs::write(&marker_path, limit.to_string()).map_err(|e| {
+      format!(
+        "Failed to write large file size limit marker {}: {e}",
+        marker_path.display()
+      )
+    })
+  }
+
+  ///
+  /// Rebalances files with `Self::rebalance_files`, and if that completes, records the current
+  /// large file size limit in the store, and stops falling back to the other backend on lookups.
+  ///
+  async fn rebalance_and_record(&self) -> Result<(), String> {
+    if self.rebalance_files().await? {
+      Self::write_large_file_size_limit_marker(&self.inner.root, self.inner.large_file_size_limit)?;
+      self
+        .inner
+        .fallback_lookups
+        .store(false, atomic::Ordering::SeqCst);
+    }
+    Ok(())
+  }
+
+  ///
+  /// If this store is falling back to the other backend on lookups, but no files remain in the
+  /// backend not chosen by the large file size limit (because they have been evicted, removed or
+  /// moved), records the current limit in the store and stops falling back, so that the store does
+  /// not fall back when it is next opened either.
+  ///
+  async fn record_limit_if_placed(&self) -> Result<(), String> {
+    if self.inner.read_only || !self.inner.fallback_lookups.load(atomic::Ordering::SeqCst) {
+      return Ok(());
+    }
+    if self.verify_placement().await?.is_empty() {
+      Self::write_large_file_size_limit_marker(&self.inner.root, self.inner.large_file_size_limit)?;
+      self
+        .inner
+        .fallback_lookups
+        .store(false, atomic::Ordering::SeqCst);
+    }
+    Ok(())
+  }
+
+  ///
+  /// Moves any files which are stored in the backend not chosen by the large file size limit into
+  /// the backend which is. Files remain readable throughout, since each is stored in its new
+  /// backend before being removed from its old one.
+  ///
+  /// Returns false if the store began shutting down before all files were moved.
+  ///
+  async fn rebalance_files(&self) -> Result<bool, String> {
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let file_fsdb = &self.inner.file_fsdb;
+
+    for aged_fingerprint in file_lmdb.aged_fingerprints().await? {
+      if !self.should_use_fsdb(EntryType::File, aged_fingerprint.size_bytes) {
+        continue;
+      }
+      if self.is_shutting_down() {
+        return Ok(false);
+      }
+      let fingerprint = aged_fingerprint.fingerprint;
+      if let Some(bytes) = file_lmdb
+        .load_bytes_with(fingerprint, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+        .await?
+      {
+        file_fsdb
+          .store_bytes_batch(vec![(fingerprint, bytes)], true)
+          .await?;
+        file_lmdb.remove(fingerprint).await?;
+      }
+    }
+
+    for digest in file_fsdb.all_digests().await? {
+      if self.should_use_fsdb(EntryType::File, digest.size_bytes) {
+        continue;
+      }
+      if self.is_shutting_down() {
+        return Ok(false);
+      }
+      let fingerprint = digest.hash;
+      if let Some(bytes) = file_fsdb
+        .load_bytes_with(fingerprint, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+        .await?
+      {
+        file_lmdb
+          .store_bytes_batch(vec![(fingerprint, bytes)], true)
+          .await?;
+        file_fsdb.remove(fingerprint).await?;
+      }
+    }
+
+    Ok(true)
+  }
+
+  ///
+  /// Reports files which are stored in the wrong backend for their size: i.e., small files in the
+  /// FSDB, or large files in LMDB. These indicate either a change to the large file size limit which
+  /// has not (yet) been rebalanced, or a bug.
+  ///
+  pub async fn verify_placement(&self) -> Result<PlacementReport, String> {
+    let (lmdb_digests, fsdb_digests) = try_join(
+      self.inner.file_lmdb.clone()?.all_digests(),
+      self.inner.file_fsdb.all_digests(),
+    )
+    .await?;
+
+    let misplaced = |digests: Vec<Digest>, in_fsdb: bool| {
+      digests
+        .into_iter()
+        .filter(|digest| self.should_use_fsdb(EntryType::File, digest.size_bytes) != in_fsdb)
+        .collect::<Vec<_>>()
+    };
+    Ok(PlacementReport {
+      small_files_in_fsdb: misplaced(fsdb_digests, true),
+      large_files_in_lmdb: misplaced(lmdb_digests, false),
+    })
+  }
+
+  ///
+  /// Returns true if the given file digest is (or should be) stored in the FSDB rather than in
+  /// LMDB. This is decided by size, unless the store was written with a different size limit, in
+  /// which case whichever backend actually contains the digest wins.
+  ///
+  async fn file_uses_fsdb(&self, digest: Digest) -> Result<bool, String> {
+    let routed_to_fsdb = self.should_use_fsdb(EntryType::File, digest.size_bytes);
+    if !self.inner.fallback_lookups.load(atomic::Ordering::SeqCst) {
+      return Ok(routed_to_fsdb);
+    }
+
+    let in_fsdb = self.inner.file_fsdb.exists(digest.hash).await?;
+    if in_fsdb == routed_to_fsdb {
+      return Ok(routed_to_fsdb);
+    }
+    // Not in the routed backend: use the other one if it contains the digest.
+    let in_lmdb = self.inner.file_lmdb.clone()?.exists(digest.hash).await?;
+    Ok(if routed_to_fsdb { !in_lmdb } else { in_fsdb })
+  }
+
+  ///
+  /// Returns a copy of this store which first attempts reads from the given (presumably faster)
+  /// store, and which copies blobs that miss in it but hit in this store into it in the
+  /// background. Warming is best-effort: failures are logged rather than returned.
+  ///
+  /// NB: Warm targets are not chained: the warm target of the given store is not consulted.
+  ///
+  pub fn with_read_warm_target(&self, faster: ByteStore) -> ByteStore {
+    ByteStore {
+      inner: self.inner.clone(),
+      read_warm_target: Some(Arc::new(faster)),
+      base_layers: self.base_layers.clone(),
+    }
+  }
+
+  ///
+  /// Returns a copy of this store which is the top layer of an overlay of stores: reads which miss
+  /// in this store fall through to each of the given stores in order, and `get_missing_digests`
+  /// only reports digests which are missing from all of them. Writes, leases, removals and garbage
+  /// collection only ever apply to this store, so base layers are usually opened read-only (see
+  /// `LocalOptions::read_only`) against a shared, prewarmed store.
+  ///
+  /// NB: Base layers are not chained: the base layers (and read warm targets) of the given stores
+  /// are not consulted.
+  ///
+  pub fn with_base_layers(&self, base_layers: Vec<ByteStore>) -> ByteStore {
+    ByteStore {
+      inner: self.inner.clone(),
+      read_warm_target: self.read_warm_target.clone(),
+      base_layers: base_layers.into(),
+    }
+  }
+
+  ///
+  /// This store, followed by its base layers, in the order in which they are consulted for reads.
+  ///
+  fn layers(&self) -> impl Iterator<Item = &ByteStore> {
+    std::iter::once(self).chain(self.base_layers.iter())
+  }
+
+  pub fn executor(&self) -> &task_executor::Executor {
+    &self.inner.executor
+  }
+
+  pub fn filesystem_device(&self) -> Option<u64> {
+    self.inner.filesystem_device
+  }
+
+  ///
+  /// True if the store root was detected to be on a network filesystem, on which filesystem-level
+  /// fast paths (such as linking files out of the store) should not be used.
+  ///
+  pub fn is_on_network_filesystem(&self) -> bool {
+    self.inner.network_filesystem
+  }
+
+  ///
+  /// True if the store was opened read-only. See `LocalOptions::read_only`.
+  ///
+  pub fn is_read_only(&self) -> bool {
+    self.inner.read_only
+  }
+
+  ///
+  /// The function which computes the fingerprints of entries in this store. See
+  /// `LocalOptions::digest_function`.
+  ///
+  pub fn digest_function(&self) -> DigestFunction {
+    self.inner.digest_function
+  }
+
+  fn check_writable(&self) -> Result<(), String> {
+    if self.inner.read_only {
+      return Err(format!(
+        "Cannot write to the read-only local store at {}.",
+        self.inner.root.display()
+      ));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Returns the type of the entry with the given Fingerprint, if it exists.
+  ///
+  /// NB: The empty Fingerprint is reported as a Directory, although it is also the empty file.
+  /// Callers which know which of the two they hold should carry that through instead.
+  ///
+  pub async fn entry_type(&self, fingerprint: Fingerprint) -> Result<Option<EntryType>, String> {
+    for layer in self.layers() {
+      if let Some(entry_type) = layer.entry_type_inner(fingerprint).await? {
+        return Ok(Some(entry_type));
+      }
+    }
+    Ok(None)
+  }
+
+  async fn entry_type_inner(&self, fingerprint: Fingerprint) -> Result<Option<EntryType>, String> {
+    if fingerprint == EMPTY_DIGEST.hash {
       // Technically this is valid as both; choose Directory in case a caller is checking whether
       // it _can_ be a Directory.
       return Ok(Some(EntryType::Directory));
     }
 
-    // In parallel, check for the given fingerprint in all databases.
-    let directory_lmdb = self.inner.directory_lmdb.clone()?;
-    let is_lmdb_dir = directory_lmdb.exists(fingerprint);
-    let file_lmdb = self.inner.file_lmdb.clone()?;
-    let is_lmdb_file = file_lmdb.exists(fingerprint);
-    let is_fsdb_file = self.inner.file_fsdb.exists(fingerprint);
+    // In parallel, check for the given fingerprint in all databases.
+    let directory_lmdb = self.inner.directory_lmdb.clone()?;
+    let is_lmdb_dir = directory_lmdb.exists(fingerprint);
+    let file_lmdb = self.inner.file_lmdb.clone()?;
+    let is_lmdb_file = file_lmdb.exists(fingerprint);
+    let is_fsdb_file = self.inner.file_fsdb.exists(fingerprint);
+
+    // TODO: Could technically use select to return slightly more quickly with the first
+    // affirmative answer, but this is simpler.
+    match future::try_join3(is_lmdb_dir, is_lmdb_file, is_fsdb_file).await? {
+      (true, _, _) => Ok(Some(EntryType::Directory)),
+      (_, true, _) => Ok(Some(EntryType::File)),
+      (_, _, true) => Ok(Some(EntryType::File)),
+      (false, false, false) => Ok(None),
+    }
+  }
+
+  pub async fn lease_all(
+    &self,
+    digests: impl Iterator<Item = (Digest, EntryType)>,
+  ) -> Result<(), String> {
+    // NB: Entries are never evicted from a read-only store, so they need not be leased.
+    if self.inner.read_only {
+      return Ok(());
+    }
+    let mut digests = digests.collect::<Vec<_>>();
+    if !self.base_layers.is_empty() {
+      // NB: Entries which are only present in a base layer are never evicted by this store, so
+      // they need not (and in the case of large files, cannot) be leased.
+      for entry_type in [EntryType::File, EntryType::Directory] {
+        let missing = self
+          .get_missing_digests_inner(
+            entry_type,
+            digests
+              .iter()
+              .filter(|(_, t)| *t == entry_type)
+              .map(|(digest, _)| *digest)
+              .collect(),
+          )
+          .await?;
+        digests.retain(|(digest, t)| *t != entry_type || !missing.contains(digest));
+      }
+    }
+
+    // Entries in LMDB are leased in batches, with one write transaction per shard, while large
+    // files are leased in chunks on blocking threads. See `ShardedFSDB::lease_batch`.
+    let mut fsdb_digests = vec![];
+    let mut file_lmdb_digests = vec![];
+    let mut directory_lmdb_digests = vec![];
+    let mut leased = 0;
+    for (digest, entry_type) in digests {
+      match entry_type {
+        EntryType::File if self.file_uses_fsdb(digest).await? => fsdb_digests.push(digest),
+        EntryType::File => file_lmdb_digests.push(digest),
+        EntryType::Directory => directory_lmdb_digests.push(digest),
+      }
+    }
+    if !fsdb_digests.is_empty() {
+      let start = Instant::now();
+      self
+        .inner
+        .file_fsdb
+        .lease_batch(fsdb_digests.iter().map(|digest| digest.hash).collect())
+        .await?;
+      leased += fsdb_digests.len();
+      for digest in fsdb_digests {
+        self.check_slow_op("lease", Self::backend_name(true), digest, start);
+      }
+    }
+    for (dbs, digests) in [
+      (&self.inner.file_lmdb, file_lmdb_digests),
+      (&self.inner.directory_lmdb, directory_lmdb_digests),
+    ] {
+      if digests.is_empty() {
+        continue;
+      }
+      let start = Instant::now();
+      dbs
+        .clone()?
+        .lease_batch(digests.iter().map(|digest| digest.hash).collect())
+        .await?;
+      leased += digests.len();
+      for digest in digests {
+        self.check_slow_op("lease", Self::backend_name(false), digest, start);
+      }
+    }
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .increment_counter(Metric::LocalStoreLeaseExtensions, leased as u64);
+    }
+    Ok(())
+  }
+
+  ///
+  /// Attempts to shrink the stored files to be no bigger than target_bytes
+  /// (excluding lmdb overhead).
+  ///
+  /// Returns the size it was shrunk to, which may be larger than target_bytes.
+  ///
+  /// TODO: Use LMDB database statistics when lmdb-rs exposes them.
+  ///
+  pub async fn shrink(
+    &self,
+    target_bytes: usize,
+    shrink_behavior: ShrinkBehavior,
+  ) -> Result<usize, String> {
+    self.check_writable()?;
+    let used_bytes = self.evict(target_bytes, usize::MAX, || false).await?;
+
+    if shrink_behavior != ShrinkBehavior::Fast {
+      self.inner.file_lmdb.clone()?.compact()?;
+    }
+
+    if shrink_behavior == ShrinkBehavior::CompactAndRebalance {
+      if self.inner.fallback_lookups.load(atomic::Ordering::SeqCst) {
+        self.rebalance_and_record().await?;
+      }
+      let removed = self.inner.file_fsdb.tidy_shard_dirs().await?;
+      log::debug!(
+        "Removed {removed} empty shard directories in {}.",
+        self.inner.file_fsdb.root.display()
+      );
+    } else {
+      self.record_limit_if_placed().await?;
+    }
+
+    Ok(used_bytes)
+  }
+
+  ///
+  /// Evicts expired entries, oldest first, until the stored entries are no bigger than
+  /// target_bytes, or until `should_stop` returns true. Entries are removed in batches of at most
+  /// `batch_size`, and `should_stop` is checked (after yielding) after each batch.
+  ///
+  /// Only one eviction runs at a time: others (whether by `Self::shrink`, background garbage
+  /// collection, or the disk space watchdog) wait for it to complete.
+  ///
+  /// Returns the size of the remaining entries.
+  ///
+  async fn evict(
+    &self,
+    target_bytes: usize,
+    batch_size: usize,
+    should_stop: impl Fn() -> bool,
+  ) -> Result<usize, String> {
+    let _eviction_guard = self.inner.eviction_lock.lock().await;
+
+    // NB: Orphaned temporary files are not entries, and so are not counted toward the size of the
+    // store: they are removed regardless of the target.
+    if let Err(e) = self.inner.file_fsdb.remove_stale_tempfiles().await {
+      log::warn!("Failed to remove orphaned temporary files: {e}");
+    }
+
+    if let Some(recently_stored) = &self.inner.recently_stored {
+      recently_stored.lock().prune();
+    }
+    let pinned = self.pinned_fingerprints().await?;
+
+    // Quarantined entries (see `Self::quarantine`) are kept for the lease time, and count toward
+    // the size of the store until then.
+    let mut quarantined = self.expire_quarantined_entries().await?;
+    let mut used_bytes: usize = quarantined.iter().map(|(_, size_bytes)| size_bytes).sum();
+    // Entries are evicted in descending order of their score under the eviction policy. Entries
+    // are consumed a shard at a time, and only those which may be evicted are retained: the rest
+    // are only counted toward the size of the store.
+    let mut fingerprints_by_score = BinaryHeap::new();
+    let eviction_policy = self.inner.eviction_policy;
+    let backends = [
+      (
+        self.inner.file_lmdb.clone()?.aged_fingerprint_batches(),
+        EntryType::File,
+      ),
+      (
+        self
+          .inner
+          .directory_lmdb
+          .clone()?
+          .aged_fingerprint_batches(),
+        EntryType::Directory,
+      ),
+      (
+        self.inner.file_fsdb.aged_fingerprint_batches(),
+        EntryType::File,
+      ),
+    ];
+    for (mut batches, entry_type) in backends {
+      while let Some(batch) = batches.try_next().await? {
+        for aged_fingerprint in batch {
+          used_bytes += aged_fingerprint.size_bytes;
+          // Leased, pinned and recently stored entries cannot be collected.
+          if aged_fingerprint.expired_seconds_ago == 0
+            || pinned.contains(&aged_fingerprint.fingerprint)
+            || self.is_recently_stored(&aged_fingerprint.fingerprint)
+          {
+            continue;
+          }
+          fingerprints_by_score.push((
+            eviction_policy.score(&aged_fingerprint),
+            aged_fingerprint,
+            entry_type,
+          ));
+        }
+      }
+    }
+
+    // Quarantined entries are unusable, so they are evicted (oldest first) before usable entries.
+    while used_bytes > target_bytes {
+      let Some((path, size_bytes)) = quarantined.pop() else {
+        break;
+      };
+      Self::remove_quarantined_entry(&path, size_bytes).await?;
+      used_bytes -= size_bytes;
+    }
+
+    let variants_lmdb = self.variants_lmdb()?;
+    // Variants which are removed along with the entry that they are linked to.
+    let mut removed_variants = HashSet::new();
+    // Entries (and links between variants) which have been chosen for eviction, but not yet removed.
+    let mut batch = Vec::new();
+    let mut batch_links = Vec::new();
+    while used_bytes > target_bytes {
+      if batch.len() >= batch_size.min(MAX_EVICTION_BATCH_SIZE) {
+        used_bytes = self
+          .evict_batch(
+            used_bytes,
+            std::mem::take(&mut batch),
+            std::mem::take(&mut batch_links),
+            &removed_variants,
+          )
+          .await?;
+        tokio::task::yield_now().await;
+        if should_stop() {
+          break;
+        }
+        continue;
+      }
+      let (_, aged_fingerprint, entry_type) = match fingerprints_by_score.pop() {
+        Some(entry) => entry,
+        // Everything remaining is leased, pinned, stored too recently to be collected, or linked to
+        // a variant which cannot be collected.
+        None => break,
+      };
+      if removed_variants.contains(&aged_fingerprint.fingerprint) {
+        continue;
+      }
+      let digest = Digest {
+        hash: aged_fingerprint.fingerprint,
+        size_bytes: aged_fingerprint.size_bytes,
+      };
+      // Linked variants are evicted together, or not at all.
+      let variant = match &variants_lmdb {
+        Some(variants_lmdb) => Self::load_variant_link(variants_lmdb, digest.hash).await?,
+        None => None,
+      };
+      if let Some(variant) = variant {
+        if pinned.contains(&variant.hash)
+          || self.is_recently_stored(&variant.hash)
+          || self.is_leased(entry_type, variant).await?
+        {
+          continue;
+        }
+      }
+      batch.push((entry_type, digest));
+      used_bytes -= aged_fingerprint.size_bytes;
+      if let Some(variant) = variant {
+        // NB: The variant might not be present, in which case its size is restored by `evict_batch`.
+        batch.push((entry_type, variant));
+        used_bytes = used_bytes.saturating_sub(variant.size_bytes);
+        batch_links.extend([digest.hash, variant.hash]);
+        removed_variants.insert(variant.hash);
+      }
+    }
+
+    if !batch.is_empty() {
+      used_bytes = self
+        .evict_batch(used_bytes, batch, batch_links, &removed_variants)
+        .await?;
+    }
+    Ok(used_bytes)
+  }
+
+  ///
+  /// Pins the given digests, which exempts them from garbage collection by `Self::shrink`
+  /// regardless of how long ago they were leased, until they are unpinned. Digests need not be
+  /// present in the store to be pinned.
+  ///
+  /// Pins are persisted in the store, so they apply to every process which uses it.
+  ///
+  pub async fn pin(&self, digests: impl IntoIterator<Item = Digest>) -> Result<(), String> {
+    self.check_writable()?;
+    let items = digests
+      .into_iter()
+      .map(|digest| (digest.hash, Bytes::new()))
+      .collect::<Vec<_>>();
+    if items.is_empty() {
+      return Ok(());
+    }
+    let pins_lmdb = self
+      .inner
+      .pins_lmdb
+      .lock()
+      .get_or_insert_with(|| {
+        ShardedLmdb::new(
+          self.inner.root.join("pins"),
+          PINS_MAX_SIZE_BYTES,
+          self.inner.executor.clone(),
+          self.inner.file_fsdb.lease_time,
+          1,
+        )
+        .map(Arc::new)
+      })
+      .clone()?;
+    pins_lmdb.store_bytes_batch(items, false).await
+  }
+
+  ///
+  /// Unpins the given digests, which were pinned by `Self::pin`. Digests which were not pinned are
+  /// ignored.
+  ///
+  pub async fn unpin(&self, digests: impl IntoIterator<Item = Digest>) -> Result<(), String> {
+    self.check_writable()?;
+    let fingerprints = digests
+      .into_iter()
+      .map(|digest| digest.hash)
+      .collect::<Vec<_>>();
+    if fingerprints.is_empty() {
+      return Ok(());
+    }
+    if let Some(pins_lmdb) = self.pins_lmdb()? {
+      pins_lmdb.remove_batch(fingerprints).await?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Returns true if the given digest is pinned. See `Self::pin`.
+  ///
+  pub async fn is_pinned(&self, digest: Digest) -> Result<bool, String> {
+    match self.pins_lmdb()? {
+      Some(pins_lmdb) => pins_lmdb.exists(digest.hash).await,
+      None => Ok(false),
+    }
+  }
+
+  async fn pinned_fingerprints(&self) -> Result<HashSet<Fingerprint>, String> {
+    let Some(pins_lmdb) = self.pins_lmdb()? else {
+      return Ok(HashSet::new());
+    };
+    Ok(
+      pins_lmdb
+        .all_fingerprints()
+        .await?
+        .into_iter()
+        .map(|aged_fingerprint| aged_fingerprint.fingerprint)
+        .collect(),
+    )
+  }
+
+  ///
+  /// The database of pinned entries, if any entries have been pinned.
+  ///
+  fn pins_lmdb(&self) -> Result<Option<Arc<ShardedLmdb>>, String> {
+    self.inner.pins_lmdb.lock().clone().transpose()
+  }
+
+  ///
+  /// Removes a batch of entries chosen by `Self::evict`, and returns the updated `used_bytes`:
+  /// the sizes of variants which turned out not to be present are restored.
+  ///
+  async fn evict_batch(
+    &self,
+    mut used_bytes: usize,
+    batch: Vec<(EntryType, Digest)>,
+    links: Vec<Fingerprint>,
+    variants: &HashSet<Fingerprint>,
+  ) -> Result<usize, String> {
+    let removed = self.remove_batch(batch.clone()).await?;
+    let mut evicted_bytes = 0;
+    for (_, digest) in batch {
+      if removed.contains(&digest) {
+        evicted_bytes += digest.size_bytes;
+      } else if variants.contains(&digest.hash) {
+        used_bytes += digest.size_bytes;
+      }
+    }
+    if !links.is_empty() {
+      if let Some(variants_lmdb) = self.variants_lmdb()? {
+        variants_lmdb.remove_batch(links).await?;
+      }
+    }
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .increment_counter(Metric::LocalStoreEvictedBytes, evicted_bytes as u64);
+    }
+    Ok(used_bytes)
+  }
+
+  ///
+  /// Starts garbage collecting this store in the background, rather than in a single blocking
+  /// `Self::shrink`. Each pass evicts expired entries until the store is no bigger than the
+  /// target size, yielding to other work between batches of evictions, and then waits for the
+  /// configured interval before starting again.
+  ///
+  /// Any background collection which was already running is stopped first. Each pass is reported
+  /// as a `local_store_gc` workunit (if the caller has a workunit store).
+  ///
+  pub async fn start_background_gc(&self, options: BackgroundGcOptions) {
+    self.stop_background_gc().await;
+    if self.inner.read_only {
+      log::debug!(
+        "Not garbage collecting the read-only local store at {}.",
+        self.inner.root.display()
+      );
+      return;
+    }
+
+    let (stop_sender, mut stop_receiver) = watch::channel(false);
+    let store = self.clone();
+    let has_workunit_store = workunit_store::get_workunit_store_handle().is_some();
+    let task = self.inner.executor.native_spawn(async move {
+      loop {
+        let pass = store.background_gc_pass(options, &stop_receiver);
+        let result = if has_workunit_store {
+          in_workunit!("local_store_gc", Level::Debug, |_workunit| pass).await
+        } else {
+          pass.await
+        };
+        if let Err(e) = result {
+          log::warn!("Failed to garbage collect the local store: {e}");
+        }
+
+        if *stop_receiver.borrow() || store.is_shutting_down() {
+          break;
+        }
+        let sleep = Box::pin(tokio::time::sleep(options.interval));
+        let stopped = Box::pin(stop_receiver.changed());
+        if let future::Either::Right(_) = future::select(sleep, stopped).await {
+          break;
+        }
+      }
+    });
+    *self.inner.background_gc.lock() = Some((stop_sender, task));
+  }
+
+  ///
+  /// Stops background garbage collection started by `Self::start_background_gc` (if any), and
+  /// waits for a pass which is in progress to finish its current batch.
+  ///
+  pub async fn stop_background_gc(&self) {
+    let background_gc = self.inner.background_gc.lock().take();
+    if let Some((stop_sender, task)) = background_gc {
+      let _ = stop_sender.send(true);
+      if let Err(e) = task.await {
+        log::warn!("Background garbage collection of the local store failed: {e}");
+      }
+    }
+  }
+
+  async fn background_gc_pass(
+    &self,
+    options: BackgroundGcOptions,
+    stop_receiver: &watch::Receiver<bool>,
+  ) -> Result<(), String> {
+    let used_bytes = self
+      .evict(options.target_bytes, options.batch_size, || {
+        *stop_receiver.borrow() || self.is_shutting_down()
+      })
+      .await?;
+    log::debug!(
+      "Garbage collected the local store: {used_bytes} bytes remain (target: {} bytes).",
+      options.target_bytes
+    );
+    self.record_limit_if_placed().await?;
+    if let Some(level) = self.inner.cold_compression_level {
+      let saved_bytes = self.compress_cold_files(level).await?;
+      log::debug!("Compressed large files whose leases lapsed, saving {saved_bytes} bytes.");
+    }
+    Ok(())
+  }
+
+  ///
+  /// Compresses the large files whose leases have lapsed (and which are neither pinned nor recently
+  /// stored) with zstd at the given level, so that they take less space until they are either used
+  /// again or evicted. Returns the number of bytes saved.
+  ///
+  /// Compressed files remain present, and are decompressed transparently: in memory when they are
+  /// loaded as bytes, and on disk when a path to them is needed (e.g. by `Self::load_from_fs`).
+  ///
+  pub async fn compress_cold_files(&self, level: i32) -> Result<u64, String> {
+    self.check_writable()?;
+    let pinned = self.pinned_fingerprints().await?;
+    let fsdb = &self.inner.file_fsdb;
+    let expiration_time = SystemTime::now() - fsdb.lease_time;
+    let entries = fsdb.leased_entry_batches().try_concat().await?;
+    // NB: Files which are already compressed, or which did not shrink when they were last
+    // compressed, are skipped.
+    let cold = entries
+      .into_iter()
+      .filter(|entry| {
+        entry.mtime < expiration_time
+          && !entry.compressed
+          && !fsdb.is_incompressible(entry.fingerprint)
+          && !pinned.contains(&entry.fingerprint)
+          && !self.is_recently_stored(&entry.fingerprint)
+      })
+      .map(|entry| entry.fingerprint)
+      .collect::<Vec<_>>();
+    stream::iter(cold)
+      .map(|fingerprint| fsdb.compress(fingerprint, level))
+      .buffer_unordered(FSDB_SCAN_CONCURRENCY)
+      .try_fold(0, |saved_bytes, saved| future::ok(saved_bytes + saved))
+      .await
+  }
+
+  ///
+  /// Starts monitoring the space which is available on the filesystem of this store. Whenever less
+  /// than `DiskSpaceWatchdogOptions::low_water_free_bytes` is available, expired entries are
+  /// evicted until enough space has been freed, and while less than
+  /// `DiskSpaceWatchdogOptions::min_free_bytes` is available, writes fail with `LocalStoreFull`
+  /// rather than with whichever IO error the filesystem produces. Writes which would fail while
+  /// entries are being evicted instead wait for eviction to finish.
+  ///
+  /// NB: Evicting entries from LMDB does not shrink its files, but does free their pages to be
+  /// reused by later writes.
+  ///
+  /// Any watchdog which was already running is stopped first.
+  ///
+  pub async fn start_disk_space_watchdog(&self, options: DiskSpaceWatchdogOptions) {
+    self.stop_disk_space_watchdog().await;
+    if self.inner.read_only {
+      log::debug!(
+        "Not monitoring the free space of the read-only local store at {}.",
+        self.inner.root.display()
+      );
+      return;
+    }
+
+    let (stop_sender, mut stop_receiver) = watch::channel(false);
+    let (free_space_sender, free_space) = watch::channel(FreeSpace {
+      available_bytes: u64::MAX,
+      evicting: false,
+    });
+    let store = self.clone();
+    let task = self.inner.executor.native_spawn(async move {
+      loop {
+        if let Err(e) = store
+          .disk_space_watchdog_pass(options, &free_space_sender, &stop_receiver)
+          .await
+        {
+          log::warn!("Failed to check the free space of the local store: {e}");
+        }
+
+        if *stop_receiver.borrow() || store.is_shutting_down() {
+          break;
+        }
+        let sleep = Box::pin(tokio::time::sleep(options.interval));
+        let stopped = Box::pin(stop_receiver.changed());
+        if let future::Either::Right(_) = future::select(sleep, stopped).await {
+          break;
+        }
+      }
+    });
+    *self.inner.disk_space_watchdog.lock() = Some(DiskSpaceWatchdog {
+      min_free_bytes: options.min_free_bytes,
+      stop_sender,
+      task,
+      free_space,
+    });
+  }
+
+  ///
+  /// Stops the disk space watchdog started by `Self::start_disk_space_watchdog` (if any), and
+  /// waits for it to finish the batch of evictions which it is running (if any).
+  ///
+  pub async fn stop_disk_space_watchdog(&self) {
+    let disk_space_watchdog = self.inner.disk_space_watchdog.lock().take();
+    if let Some(disk_space_watchdog) = disk_space_watchdog {
+      let _ = disk_space_watchdog.stop_sender.send(true);
+      if let Err(e) = disk_space_watchdog.task.await {
+        log::warn!("The disk space watchdog of the local store failed: {e}");
+      }
+    }
+  }
+
+  ///
+  /// Fails with `LocalStoreFull` if the disk space watchdog is running, and found that less than
+  /// its minimum free space was available on its last check. If the watchdog is evicting entries,
+  /// waits for it to finish before deciding.
+  ///
+  pub async fn check_free_space(&self) -> Result<(), LocalStoreFull> {
+    let (mut free_space, min_free_bytes) = match &*self.inner.disk_space_watchdog.lock() {
+      Some(disk_space_watchdog) => (
+        disk_space_watchdog.free_space.clone(),
+        disk_space_watchdog.min_free_bytes,
+      ),
+      None => return Ok(()),
+    };
+    loop {
+      let FreeSpace {
+        available_bytes,
+        evicting,
+      } = *free_space.borrow();
+      if available_bytes >= min_free_bytes {
+        return Ok(());
+      }
+      // NB: If the watchdog has stopped, its last observation stands.
+      if !evicting || free_space.changed().await.is_err() {
+        return Err(LocalStoreFull {
+          root: self.inner.root.clone(),
+          available_bytes,
+          min_free_bytes,
+        });
+      }
+    }
+  }
+
+  async fn disk_space_watchdog_pass(
+    &self,
+    options: DiskSpaceWatchdogOptions,
+    free_space_sender: &watch::Sender<FreeSpace>,
+    stop_receiver: &watch::Receiver<bool>,
+  ) -> Result<(), String> {
+    let root = self.inner.root.clone();
+    let available = || {
+      let root = root.clone();
+      self.inner.executor.spawn_blocking(
+        move || platform::available_bytes(&root),
+        |e| Err(format!("Free space check task failed: {e}")),
+      )
+    };
+    let available_bytes = available().await?;
+    if available_bytes >= options.low_water_free_bytes {
+      free_space_sender.send_replace(FreeSpace {
+        available_bytes,
+        evicting: false,
+      });
+      return Ok(());
+    }
+
+    free_space_sender.send_replace(FreeSpace {
+      available_bytes,
+      evicting: true,
+    });
+    let evicted = async {
+      let used_bytes = self.stats().await?.total_bytes();
+      let needed_bytes = (options.low_water_free_bytes - available_bytes) as usize;
+      let target_bytes = used_bytes.saturating_sub(needed_bytes);
+      log::info!(
+        "Only {available_bytes} bytes are available for the local store at {}: evicting expired \
+         entries to shrink it from {used_bytes} to {target_bytes} bytes.",
+        self.inner.root.display()
+      );
+      self
+        .evict(target_bytes, DISK_SPACE_WATCHDOG_BATCH_SIZE, || {
+          *stop_receiver.borrow() || self.is_shutting_down()
+        })
+        .await?;
+      available().await
+    }
+    .await;
+    free_space_sender.send_replace(FreeSpace {
+      available_bytes: *evicted.as_ref().unwrap_or(&available_bytes),
+      evicting: false,
+    });
+    evicted.map(|_| ())
+  }
+
+  pub async fn remove(&self, entry_type: EntryType, digest: Digest) -> Result<bool, String> {
+    self.check_writable()?;
+    let start = Instant::now();
+    if let Some(memory_cache) = &self.inner.memory_cache {
+      memory_cache.lock().remove((entry_type, digest));
+    }
+    let uses_fsdb = entry_type == EntryType::File && self.file_uses_fsdb(digest).await?;
+    let removed = match entry_type {
+      EntryType::Directory => {
+        if let Some(directory_stats_lmdb) = &self.inner.directory_stats_lmdb {
+          directory_stats_lmdb.clone()?.remove(digest.hash).await?;
+        }
+        self
+          .inner
+          .directory_lmdb
+          .clone()?
+          .remove(digest.hash)
+          .await?
+      }
+      EntryType::File if uses_fsdb => self.inner.file_fsdb.remove(digest.hash).await?,
+      EntryType::File => self.inner.file_lmdb.clone()?.remove(digest.hash).await?,
+    };
+    if let (true, Some(provenance_lmdb)) = (removed, &self.inner.provenance_lmdb) {
+      provenance_lmdb.clone()?.remove(digest.hash).await?;
+    }
+    self.check_slow_op("remove", Self::backend_name(uses_fsdb), digest, start);
+    Ok(removed)
+  }
+
+  ///
+  /// Removes the given entries, returning those which were present. Files stored on disk are
+  /// removed concurrently, and entries stored in LMDB are removed in a single write transaction
+  /// per shard.
+  ///
+  pub async fn remove_batch(
+    &self,
+    entries: Vec<(EntryType, Digest)>,
+  ) -> Result<HashSet<Digest>, String> {
+    self.check_writable()?;
+    let mut fsdb_digests = vec![];
+    let mut file_lmdb_digests = vec![];
+    let mut directory_lmdb_digests = vec![];
+    for (entry_type, digest) in entries {
+      if let Some(memory_cache) = &self.inner.memory_cache {
+        memory_cache.lock().remove((entry_type, digest));
+      }
+      match entry_type {
+        EntryType::Directory => directory_lmdb_digests.push(digest),
+        EntryType::File if self.file_uses_fsdb(digest).await? => fsdb_digests.push(digest),
+        EntryType::File => file_lmdb_digests.push(digest),
+      }
+    }
+
+    let fingerprints =
+      |digests: &[Digest]| digests.iter().map(|digest| digest.hash).collect::<Vec<_>>();
+    if let Some(directory_stats_lmdb) = &self.inner.directory_stats_lmdb {
+      directory_stats_lmdb
+        .clone()?
+        .remove_batch(fingerprints(&directory_lmdb_digests))
+        .await?;
+    }
+    let (removed_from_fsdb, removed_from_file_lmdb, removed_from_directory_lmdb) = try_join3(
+      self
+        .inner
+        .file_fsdb
+        .remove_batch(fingerprints(&fsdb_digests)),
+      self
+        .inner
+        .file_lmdb
+        .clone()?
+        .remove_batch(fingerprints(&file_lmdb_digests)),
+      self
+        .inner
+        .directory_lmdb
+        .clone()?
+        .remove_batch(fingerprints(&directory_lmdb_digests)),
+    )
+    .await?;
+    if let Some(provenance_lmdb) = &self.inner.provenance_lmdb {
+      provenance_lmdb
+        .clone()?
+        .remove_batch(
+          removed_from_fsdb
+            .iter()
+            .chain(removed_from_file_lmdb.iter())
+            .chain(removed_from_directory_lmdb.iter())
+            .copied()
+            .collect(),
+        )
+        .await?;
+    }
+
+    Ok(
+      [
+        (fsdb_digests, removed_from_fsdb),
+        (file_lmdb_digests, removed_from_file_lmdb),
+        (directory_lmdb_digests, removed_from_directory_lmdb),
+      ]
+      .into_iter()
+      .flat_map(|(digests, removed)| {
+        digests
+          .into_iter()
+          .filter(move |digest| removed.contains(&digest.hash))
+      })
+      .collect(),
+    )
+  }
+
+  ///
+  /// Attempts to claim the given digest for `claim_duration`, returning true if this caller won
+  /// the claim. At most one concurrent caller (in this or any other process using the same store)
+  /// will win a claim until it expires, which allows callers to coordinate so that only one of
+  /// them regenerates a missing or expired blob.
+  ///
+  /// Claims are recorded as leases in LMDB regardless of the size of the digest, so for entries
+  /// which are stored in LMDB, an existing unexpired lease on the entry also counts as a claim.
+  /// Storing the blob with an initial lease replaces the claim with an ordinary lease.
+  ///
+  pub async fn try_claim(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    claim_duration: Duration,
+  ) -> Result<bool, String> {
+    self.check_writable()?;
+    let dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    }?;
+    dbs.try_lease(digest.hash, claim_duration).await
+  }
+
+  ///
+  /// As `Self::remove`, but refuses to remove an entry whose lease is still active.
+  ///
+  /// NB: The lease is checked before removal, so a lease which is taken concurrently with this call
+  /// may not be respected.
+  ///
+  pub async fn remove_unless_leased(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<RemoveOutcome, String> {
+    if self.is_leased(entry_type, digest).await? {
+      return Ok(RemoveOutcome::WasLeased);
+    }
+
+    if self.remove(entry_type, digest).await? {
+      Ok(RemoveOutcome::Removed)
+    } else {
+      Ok(RemoveOutcome::NotFound)
+    }
+  }
+
+  async fn is_leased(&self, entry_type: EntryType, digest: Digest) -> Result<bool, String> {
+    match entry_type {
+      EntryType::Directory => {
+        self
+          .inner
+          .directory_lmdb
+          .clone()?
+          .is_leased(digest.hash)
+          .await
+      }
+      EntryType::File if self.file_uses_fsdb(digest).await? => {
+        self.inner.file_fsdb.is_leased(digest.hash).await
+      }
+      EntryType::File => self.inner.file_lmdb.clone()?.is_leased(digest.hash).await,
+    }
+  }
+
+  ///
+  /// Store the given data in a single pass, using the given Fingerprint. Prefer `Self::store`
+  /// for values which should not be pulled into memory, and `Self::store_bytes_batch` when storing
+  /// multiple values at a time.
+  ///
+  pub async fn store_bytes(
+    &self,
+    entry_type: EntryType,
+    fingerprint: Fingerprint,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    let start = Instant::now();
+    let digest = Digest::new(fingerprint, bytes.len());
+    self
+      .store_bytes_batch(entry_type, vec![(fingerprint, bytes)], initial_lease)
+      .await?;
+    self.check_slow_op(
+      "store",
+      Self::backend_name(self.should_use_fsdb(entry_type, digest.size_bytes)),
+      digest,
+      start,
+    );
+    Ok(())
+  }
+
+  ///
+  /// Store the given items in a single pass, optionally using the given Digests. Prefer `Self::store`
+  /// for values which should not be pulled into memory.
+  ///
+  /// See also: `Self::store_bytes`.
+  ///
+  pub async fn store_bytes_batch(
+    &self,
+    entry_type: EntryType,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    self.check_writable()?;
+    self.check_free_space().await.map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let mut fsdb_items = vec![];
+    let mut lmdb_items = vec![];
+    for (fingerprint, bytes) in items {
+      if self.should_use_fsdb(entry_type, bytes.len()) {
+        fsdb_items.push((fingerprint, bytes));
+      } else {
+        lmdb_items.push((fingerprint, bytes));
+      }
+    }
+
+    let stored_fingerprints = fsdb_items
+      .iter()
+      .chain(lmdb_items.iter())
+      .map(|(fingerprint, _)| *fingerprint)
+      .collect::<Vec<_>>();
+    let fsdb_sizes = fsdb_items
+      .iter()
+      .map(|(_, bytes)| bytes.len())
+      .collect::<Vec<_>>();
+    let lmdb_sizes = lmdb_items
+      .iter()
+      .map(|(_, bytes)| bytes.len())
+      .collect::<Vec<_>>();
+    let lmdb_dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    };
+    try_join(
+      try_join_all(fsdb_items.into_iter().map(|(fingerprint, bytes)| {
+        self.single_flight_store(
+          fingerprint,
+          self
+            .inner
+            .file_fsdb
+            .store_bytes_batch(vec![(fingerprint, bytes)], initial_lease),
+        )
+      })),
+      lmdb_dbs?.store_bytes_batch(lmdb_items, initial_lease),
+    )
+    .await?;
+    self.record_stored(stored_fingerprints.clone());
+    self.record_provenance(stored_fingerprints).await;
+    self.record_writes(true, &fsdb_sizes, start);
+    self.record_writes(false, &lmdb_sizes, start);
+
+    Ok(())
+  }
+
+  ///
+  /// As `Self::store_bytes_batch`, but either stores all of the given items or none of them: if any
+  /// item fails to be stored, the entries which were added by this call are removed again before
+  /// it returns an error. Entries which were already present are left in place.
+  ///
+  /// Large items are written to temporary files before anything is committed, and those files are
+  /// only moved into place after the small items have been committed to LMDB, so that failures to
+  /// write content (such as running out of space) cause nothing to be stored. The batch is not
+  /// isolated though: concurrent readers may observe entries which are then rolled back.
+  ///
+  pub async fn store_bytes_batch_atomic(
+    &self,
+    entry_type: EntryType,
+    items: Vec<(Fingerprint, Bytes)>,
+    initial_lease: bool,
+  ) -> Result<(), String> {
+    self.check_writable()?;
+    self.check_free_space().await.map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let (fsdb_items, lmdb_items): (Vec<_>, Vec<_>) = items
+      .into_iter()
+      .partition(|(_, bytes)| self.should_use_fsdb(entry_type, bytes.len()));
+    let digests = |items: &[(Fingerprint, Bytes)]| {
+      items
+        .iter()
+        .map(|(fingerprint, bytes)| Digest::new(*fingerprint, bytes.len()))
+        .collect::<Vec<_>>()
+    };
+    let (fsdb_digests, lmdb_digests) = (digests(&fsdb_items), digests(&lmdb_items));
+    let added = self
+      .get_missing_digests_inner(
+        entry_type,
+        fsdb_digests
+          .iter()
+          .chain(lmdb_digests.iter())
+          .copied()
+          .collect(),
+      )
+      .await?;
+
+    let staged = self.inner.file_fsdb.stage_bytes_batch(&fsdb_items).await?;
+    let lmdb_dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    };
+    let result = async {
+      lmdb_dbs?
+        .store_bytes_batch(lmdb_items, initial_lease)
+        .await?;
+      self
+        .inner
+        .file_fsdb
+        .commit_staged(&staged, initial_lease)
+        .await
+    }
+    .await;
+    if let Err(e) = result {
+      join_all(staged.iter().map(|(_, tempfile)| tempfile.discard())).await;
+      let added = added
+        .into_iter()
+        .map(|digest| (entry_type, digest))
+        .collect();
+      if let Err(rollback_err) = self.remove_batch(added).await {
+        return Err(format!(
+          "{e} (and failed to remove the entries which were stored: {rollback_err})"
+        ));
+      }
+      return Err(e);
+    }
+
+    let stored_fingerprints = fsdb_digests
+      .iter()
+      .chain(lmdb_digests.iter())
+      .map(|digest| digest.hash)
+      .collect::<Vec<_>>();
+    self.record_stored(stored_fingerprints.clone());
+    self.record_provenance(stored_fingerprints).await;
+    let sizes = |digests: &[Digest]| {
+      digests
+        .iter()
+        .map(|digest| digest.size_bytes)
+        .collect::<Vec<_>>()
+    };
+    self.record_writes(true, &sizes(&fsdb_digests), start);
+    self.record_writes(false, &sizes(&lmdb_digests), start);
+    Ok(())
+  }
+
+  ///
+  /// Guards a store of the given fingerprint into the FSDB, so that only one store of a
+  /// fingerprint runs at a time: concurrent callers await its result rather than copying and
+  /// hashing the same content again. If the running store fails, one of the waiting callers runs
+  /// its own store instead.
+  ///
+  /// NB: Every store into the FSDB refreshes the mtime of the file, which leases it regardless of
+  /// `initial_lease`, so callers need not agree on it.
+  ///
+  async fn single_flight_store(
+    &self,
+    fingerprint: Fingerprint,
+    store: impl Future<Output = Result<(), String>>,
+  ) -> Result<(), String> {
+    let cell = {
+      let mut in_flight = self.inner.in_flight_fsdb_stores.lock();
+      in_flight.retain(|_, cell| cell.strong_count() > 0);
+      if let Some(cell) = in_flight.get(&fingerprint).and_then(Weak::upgrade) {
+        cell
+      } else {
+        let cell = Arc::new(OnceCell::new());
+        in_flight.insert(fingerprint, Arc::downgrade(&cell));
+        cell
+      }
+    };
+    cell.get_or_try_init(store).await.map(|&()| ())
+  }
+
+  ///
+  /// Store data in two passes, without buffering it entirely into memory. Prefer
+  /// `Self::store_bytes` for small values which fit comfortably in memory.
+  ///
+  pub async fn store(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    src: PathBuf,
+  ) -> Result<Digest, String> {
+    self
+      .store_inner(entry_type, initial_lease, src_is_immutable, src, None)
+      .await
+  }
+
+  ///
+  /// Store the content of the given reader in a single pass, returning its digest. Unlike
+  /// `Self::store`, the content need not already be in a file: content which is smaller than the
+  /// large file size limit is buffered in memory and stored in LMDB, while larger content is
+  /// hashed as it is written into a temporary file in the FSDB.
+  ///
+  pub async fn store_from_reader<R: AsyncRead + Send + Unpin>(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    mut reader: R,
+  ) -> Result<Digest, String> {
+    self.check_writable()?;
+    self.check_free_space().await.map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let limit = match entry_type {
+      EntryType::File => self.inner.large_file_size_limit,
+      EntryType::Directory => usize::MAX,
+    };
+
+    let mut buffer = Vec::new();
+    (&mut reader)
+      .take(limit as u64)
+      .read_to_end(&mut buffer)
+      .await
+      .map_err(|e| format!("Failed to read content to store: {e}"))?;
+    if buffer.len() < limit {
+      let bytes = Bytes::from(buffer);
+      let digest = Digest::of_bytes_with(self.inner.digest_function, &bytes);
+      self
+        .store_bytes(entry_type, digest.hash, bytes, initial_lease)
+        .await?;
+      return Ok(digest);
+    }
+
+    let mut reader = io::Cursor::new(buffer).chain(reader);
+    let digest = self.inner.file_fsdb.store_from_reader(&mut reader).await?;
+    self.record_stored([digest.hash]);
+    self.record_provenance(vec![digest.hash]).await;
+    self.record_writes(true, &[digest.size_bytes], start);
+    self.check_slow_op("store", Self::backend_name(true), digest, start);
+    Ok(digest)
+  }
+
+  ///
+  /// As `Self::store`, but additionally links the stored entry to `variant_digest`: another form of
+  /// the same content (for example, the decompressed form of a compressed archive), which the
+  /// caller may store separately once it has computed it. Linked entries are kept together by
+  /// `Self::shrink`, which evicts both or neither of them.
+  ///
+  /// An entry may only be linked to a single variant: if either entry is already linked, the
+  /// existing link is preserved.
+  ///
+  pub async fn store_with_variant(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    src: PathBuf,
+    variant_digest: Digest,
+  ) -> Result<Digest, String> {
+    let digest = self
+      .store(entry_type, initial_lease, src_is_immutable, src)
+      .await?;
+    let link = |digest: Digest| {
+      let mut bytes = BytesMut::with_capacity(hashing::FINGERPRINT_SIZE + 8);
+      bytes.extend_from_slice(digest.hash.as_bytes());
+      bytes.extend_from_slice(&(digest.size_bytes as u64).to_le_bytes());
+      bytes.freeze()
+    };
+    let variants_lmdb = self
+      .inner
+      .variants_lmdb
+      .lock()
+      .get_or_insert_with(|| {
+        ShardedLmdb::new(
+          self.inner.root.join("variants"),
+          VARIANTS_MAX_SIZE_BYTES,
+          self.inner.executor.clone(),
+          self.inner.file_fsdb.lease_time,
+          1,
+        )
+        .map(Arc::new)
+      })
+      .clone()?;
+    variants_lmdb
+      .store_bytes_batch(
+        vec![
+          (digest.hash, link(variant_digest)),
+          (variant_digest.hash, link(digest)),
+        ],
+        false,
+      )
+      .await?;
+    Ok(digest)
+  }
+
+  ///
+  /// Returns the variant which the given digest was linked to by `Self::store_with_variant`, if
+  /// that variant has been stored.
+  ///
+  pub async fn load_variant(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<Option<Digest>, String> {
+    let variant = match self.variants_lmdb()? {
+      Some(variants_lmdb) => Self::load_variant_link(&variants_lmdb, digest.hash).await?,
+      None => None,
+    };
+    let variant = match variant {
+      Some(variant) => variant,
+      None => return Ok(None),
+    };
+    let missing = self
+      .get_missing_digests(entry_type, HashSet::from([variant]))
+      .await?;
+    Ok(if missing.is_empty() {
+      Some(variant)
+    } else {
+      None
+    })
+  }
+
+  ///
+  /// The database of links between variants, if any links have been stored.
+  ///
+  fn variants_lmdb(&self) -> Result<Option<Arc<ShardedLmdb>>, String> {
+    self.inner.variants_lmdb.lock().clone().transpose()
+  }
+
+  async fn load_variant_link(
+    variants_lmdb: &ShardedLmdb,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<Digest>, String> {
+    variants_lmdb
+      .load_bytes_with(fingerprint, move |bytes| {
+        if bytes.len() != hashing::FINGERPRINT_SIZE + 8 {
+          return Err(format!(
+            "Variant link for {fingerprint:?} had an unexpected length: {}",
+            bytes.len()
+          ));
+        }
+        let (hash, size_bytes) = bytes.split_at(hashing::FINGERPRINT_SIZE);
+        let mut array = [0_u8; 8];
+        array.copy_from_slice(size_bytes);
+        Ok(Digest::new(
+          Fingerprint::from_bytes_unsafe(hash),
+          u64::from_le_bytes(array) as usize,
+        ))
+      })
+      .await
+  }
+
+  ///
+  /// As `Self::store`, but gives up if hashing and copying the data has not completed by the given
+  /// deadline, in which case `StoreError::DeadlineExceeded` is returned and no partial data is left
+  /// in the store. Other failures are returned as such, even if they happen after the deadline.
+  ///
+  /// The deadline is checked between chunks, so a single read which blocks for a long time may
+  /// cause it to be exceeded by up to that amount.
+  ///
+  pub async fn store_with_deadline(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    src: PathBuf,
+    deadline: Instant,
+  ) -> Result<Digest, StoreError> {
+    let deadline = Deadline::new(deadline);
+    match self
+      .store_inner(
+        entry_type,
+        initial_lease,
+        src_is_immutable,
+        src.clone(),
+        Some(deadline.clone()),
+      )
+      .await
+    {
+      Ok(digest) => Ok(digest),
+      Err(_) if deadline.was_exceeded() => Err(StoreError::DeadlineExceeded(format!(
+        "Deadline exceeded while storing {}.",
+        src.display()
+      ))),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn store_inner(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    src: PathBuf,
+    deadline: Option<Deadline>,
+  ) -> Result<Digest, String> {
+    self.check_writable()?;
+    self.check_free_space().await.map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let file = tokio::fs::File::open(src.clone())
+      .await
+      .map_err(|e| format!("Failed to open {src:?}: {e}"))?;
+
+    // Large mutable files would otherwise be read twice: once to hash them, and again to copy them
+    // into the FSDB. Immutable files are hashed up front so that they may be linked or cloned.
+    if entry_type == EntryType::File && !src_is_immutable {
+      let len = file
+        .metadata()
+        .await
+        .map_err(|e| format!("Failed to stat {src:?}: {e}"))?
+        .len() as usize;
+      if len >= self.inner.pipelined_store_min_bytes && self.should_use_fsdb(entry_type, len) {
+        let digest = self
+          .inner
+          .file_fsdb
+          .store_pipelined(src.clone(), deadline.clone())
+          .await?;
+        // The file may have shrunk while it was being read: if so, move it to LMDB.
+        if self.should_use_fsdb(entry_type, digest.size_bytes) {
+          self.record_stored([digest.hash]);
+          self.record_provenance(vec![digest.hash]).await;
+          self.record_writes(true, &[digest.size_bytes], start);
+          self.check_slow_op("store", Self::backend_name(true), digest, start);
+          return Ok(digest);
+        }
+        self.inner.file_fsdb.remove(digest.hash).await?;
+      }
+    }
+
+    let mut file = DeadlineReader::new(file, deadline.clone());
+    let digest = async_copy_and_hash_with(
+      self.inner.digest_function,
+      &mut file,
+      &mut tokio::io::sink(),
+    )
+    .await
+    .map_err(|e| format!("Failed to hash {src:?}: {e}"))?;
+
+    let uses_fsdb = self.should_use_fsdb(entry_type, digest.size_bytes);
+    if uses_fsdb {
+      self
+        .single_flight_store(
+          digest.hash,
+          self.inner.file_fsdb.store(
+            initial_lease,
+            src_is_immutable,
+            digest,
+            src,
+            deadline.clone(),
+          ),
+        )
+        .await?;
+    } else {
+      let dbs = match entry_type {
+        EntryType::Directory => self.inner.directory_lmdb.clone()?,
+        EntryType::File => self.inner.file_lmdb.clone()?,
+      };
+      let result = UnderlyingByteStore::store(
+        &*dbs,
+        initial_lease,
+        src_is_immutable,
+        digest,
+        src,
+        deadline.clone(),
+      )
+      .await;
+      // NB: Failures to store into LMDB are ignored, except for those caused by the deadline.
+      if let (Err(e), Some(deadline)) = (result, deadline) {
+        if deadline.was_exceeded() {
+          return Err(e);
+        }
+      }
+    }
+
+    self.record_stored([digest.hash]);
+    self.record_provenance(vec![digest.hash]).await;
+    self.record_writes(uses_fsdb, &[digest.size_bytes], start);
+    self.check_slow_op("store", Self::backend_name(uses_fsdb), digest, start);
+    Ok(digest)
+  }
+
+  ///
+  /// As `Self::store`, but before returning, reads the stored blob back and re-hashes it to confirm
+  /// that it matches, and then flushes it to disk.
+  ///
+  /// This is deliberately expensive, and should only be used for critical blobs for which a storage
+  /// failure must be detected immediately rather than at next access.
+  ///
+  pub async fn store_verified(
+    &self,
+    entry_type: EntryType,
+    initial_lease: bool,
+    src_is_immutable: bool,
+    src: PathBuf,
+  ) -> Result<Digest, String> {
+    let digest = self
+      .store(entry_type, initial_lease, src_is_immutable, src.clone())
+      .await?;
+
+    let actual_digest = self
+      .load_bytes_with(entry_type, digest, {
+        let digest_function = self.inner.digest_function;
+        move |bytes| Digest::of_bytes_with(digest_function, bytes)
+      })
+      .await?
+      .ok_or_else(|| format!("{src:?} was not present in the store after storing {digest:?}."))?;
+    if actual_digest != digest {
+      return Err(format!(
+        "Failed to verify {src:?}: stored {digest:?}, but read back {actual_digest:?}."
+      ));
+    }
+
+    if self.should_use_fsdb(entry_type, digest.size_bytes) {
+      self.inner.file_fsdb.sync(digest.hash).await?;
+    } else {
+      let dbs = match entry_type {
+        EntryType::Directory => self.inner.directory_lmdb.clone(),
+        EntryType::File => self.inner.file_lmdb.clone(),
+      }?;
+      dbs.sync(digest.hash).await?;
+    }
+
+    Ok(digest)
+  }
+
+  ///
+  /// Given a collection of Digests (digests),
+  /// returns the set of digests from that collection not present in the
+  /// underlying LMDB store.
+  ///
+  pub async fn get_missing_digests(
+    &self,
+    entry_type: EntryType,
+    digests: HashSet<Digest>,
+  ) -> Result<HashSet<Digest>, String> {
+    let mut missing = self.get_missing_digests_inner(entry_type, digests).await?;
+    for layer in self.base_layers.iter() {
+      if missing.is_empty() {
+        break;
+      }
+      missing = layer.get_missing_digests_inner(entry_type, missing).await?;
+    }
+    Ok(missing)
+  }
+
+  async fn get_missing_digests_inner(
+    &self,
+    entry_type: EntryType,
+    digests: HashSet<Digest>,
+  ) -> Result<HashSet<Digest>, String> {
+    let mut fsdb_digests = vec![];
+    let mut lmdb_digests = vec![];
+    for digest in digests.iter() {
+      if self.should_use_fsdb(entry_type, digest.size_bytes) {
+        fsdb_digests.push(digest);
+      }
+      // Avoid I/O for this case. This allows some client-provided operations (like
+      // merging snapshots) to work without needing to first store the empty snapshot.
+      else if *digest != EMPTY_DIGEST {
+        lmdb_digests.push(digest);
+      }
+    }
+
+    let lmdb = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    }?;
+    let (mut existing, existing_lmdb_digests) = try_join(
+      self
+        .inner
+        .file_fsdb
+        .exists_batch(fsdb_digests.iter().map(|digest| digest.hash).collect()),
+      lmdb.exists_batch(lmdb_digests.iter().map(|digest| digest.hash).collect()),
+    )
+    .await?;
+
+    existing.extend(existing_lmdb_digests);
+
+    if entry_type == EntryType::File && self.inner.fallback_lookups.load(atomic::Ordering::SeqCst) {
+      // Look for digests which were missing from their routed backend in the other backend.
+      let (in_fsdb, in_lmdb) = try_join(
+        self
+          .inner
+          .file_fsdb
+          .exists_batch(lmdb_digests.iter().map(|digest| digest.hash).collect()),
+        lmdb.exists_batch(fsdb_digests.iter().map(|digest| digest.hash).collect()),
+      )
+      .await?;
+      existing.extend(in_fsdb);
+      existing.extend(in_lmdb);
+    }
+
+    Ok(
+      digests
+        .into_iter()
+        .filter(|digest| *digest != EMPTY_DIGEST && !existing.contains(&digest.hash))
+        .collect(),
+    )
+  }
+
+  ///
+  /// Return the path this digest is persistent on the filesystem at, or None. A file which was
+  /// compressed because its lease lapsed is decompressed first: in place, or into this store if it
+  /// is held by a read-only base layer.
+  ///
+  pub async fn load_from_fs(&self, digest: Digest) -> Result<Option<PathBuf>, String> {
+    for layer in self.layers() {
+      let fsdb = &layer.inner.file_fsdb;
+      if fsdb.exists(digest.hash).await? {
+        let dest = if layer.inner.read_only {
+          &self.inner.file_fsdb
+        } else {
+          fsdb
+        };
+        return Ok(Some(fsdb.readable_path_in(dest, digest.hash).await?));
+      }
+    }
+    Ok(None)
+  }
+
+  ///
+  /// Copies the large file with the given digest to `destination` (which is created with the given
+  /// mode), returning false if it is not held as a file on disk (see `Self::load_from_fs`).
+  ///
+  /// The copy is made within the kernel where possible (see `platform::offloaded_copy`), so that
+  /// the content does not pass through user space. Otherwise, if `can_hardlink` is set (because the
+  /// destination will never be modified), the file is hardlinked, and if not (or if linking fails)
+  /// the content is copied through user space.
+  ///
+  pub async fn materialize_from_fs(
+    &self,
+    digest: Digest,
+    destination: PathBuf,
+    mode: u32,
+    can_hardlink: bool,
+  ) -> Result<bool, String> {
+    let src = match self.load_from_fs(digest).await? {
+      Some(src) => src,
+      None => return Ok(false),
+    };
+    let create_destination = move |destination: &Path| {
+      platform::create_with_mode(
+        std::fs::OpenOptions::new()
+          .write(true)
+          .create(true)
+          .truncate(true),
+        mode,
+      )
+      .open(destination)
+      .map_err(|e| format!("Error opening file {destination:?} for writing: {e}"))
+    };
+    self
+      .inner
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut src_file =
+            std::fs::File::open(&src).map_err(|e| format!("Failed to open {src:?}: {e}"))?;
+          let mut dest_file = create_destination(&destination)?;
+          match platform::offloaded_copy(&src_file, &dest_file) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+              log::trace!("Could not copy {src:?} within the kernel: {e}");
+              if can_hardlink {
+                drop(dest_file);
+                let linked = std::fs::remove_file(&destination)
+                  .and_then(|()| std::fs::hard_link(&src, &destination));
+                match linked {
+                  Ok(()) => return Ok(true),
+                  Err(e) => log::debug!("Could not link {src:?} to {destination:?}: {e}"),
+                }
+                dest_file = create_destination(&destination)?;
+              }
+              io::copy(&mut src_file, &mut dest_file)
+                .map_err(|e| format!("Error copying bytes from {src:?} to {destination:?}: {e}"))?;
+            }
+            Err(e) => {
+              return Err(format!(
+                "Error copying bytes from {src:?} to {destination:?}: {e}"
+              ))
+            }
+          }
+          // NB: The mode with which a file is created is subject to the umask.
+          platform::set_mode(&dest_file, mode)
+            .map_err(|e| format!("Error setting permissions on {destination:?}: {e}"))?;
+          Ok(true)
+        },
+        |e| Err(format!("`materialize_from_fs` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Loads bytes from the underlying store using the given function.
+  /// In the case of the LMDB store, because the database is blocking, this accepts a function that
+  /// views a slice rather than returning a clone of the data.
+  /// The upshot is that the database is able to provide slices directly into shared memory.
+  ///
+  pub async fn load_bytes_with<T: Send + 'static, F: FnMut(&[u8]) -> T + Send + Sync + 'static>(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    f: F,
+  ) -> Result<Option<T>, String> {
+    let faster = if let Some(faster) = &self.read_warm_target {
+      faster
+    } else {
+      return self.load_bytes_with_layers(entry_type, digest, f).await;
+    };
+
+    let missing_from_faster = faster
+      .get_missing_digests_inner(entry_type, HashSet::from([digest]))
+      .await?;
+    if missing_from_faster.is_empty() {
+      return faster.load_bytes_with_inner(entry_type, digest, f).await;
+    }
+
+    let result = self.load_bytes_with_layers(entry_type, digest, f).await?;
+    if result.is_some() {
+      let faster = faster.clone();
+      let source = self.clone();
+      self.spawn_background(async move {
+        if let Err(e) = faster.copy_from(&source, entry_type, digest).await {
+          log::warn!("Failed to warm {entry_type:?} {digest:?} into a faster store: {e}");
+        }
+      });
+    }
+    Ok(result)
+  }
 
-    // TODO: Could technically use select to return slightly more quickly with the first
-    // affirmative answer, but this is simpler.
-    match future::try_join3(is_lmdb_dir, is_lmdb_file, is_fsdb_file).await? {
-      (true, _, _) => Ok(Some(EntryType::Directory)),
-      (_, true, _) => Ok(Some(EntryType::File)),
-      (_, _, true) => Ok(Some(EntryType::File)),
-      (false, false, false) => Ok(None),
+  ///
+  /// Loads bytes from the first layer of this store which contains the digest. See
+  /// `Self::with_base_layers`.
+  ///
+  async fn load_bytes_with_layers<
+    T: Send + 'static,
+    F: FnMut(&[u8]) -> T + Send + Sync + 'static,
+  >(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    f: F,
+  ) -> Result<Option<T>, String> {
+    if self.base_layers.is_empty() {
+      return self.load_bytes_with_inner(entry_type, digest, f).await;
+    }
+
+    for layer in self.layers() {
+      let missing = layer
+        .get_missing_digests_inner(entry_type, HashSet::from([digest]))
+        .await?;
+      if missing.is_empty() {
+        return layer.load_bytes_with_inner(entry_type, digest, f).await;
+      }
     }
+    Ok(None)
   }
 
-  pub async fn lease_all(
+  async fn load_bytes_with_inner<
+    T: Send + 'static,
+    F: FnMut(&[u8]) -> T + Send + Sync + 'static,
+  >(
     &self,
-    digests: impl Iterator<Item = (Digest, EntryType)>,
-  ) -> Result<(), String> {
-    // NB: Lease extension happens periodically in the background, so this code needn't be parallel.
-    for (digest, entry_type) in digests {
-      if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
-        self.inner.file_fsdb.lease(digest.hash).await?;
-      } else {
-        let dbs = match entry_type {
-          EntryType::File => self.inner.file_lmdb.clone(),
-          EntryType::Directory => self.inner.directory_lmdb.clone(),
+    entry_type: EntryType,
+    digest: Digest,
+    mut f: F,
+  ) -> Result<Option<T>, String> {
+    let start = Instant::now();
+    if digest == EMPTY_DIGEST {
+      // Avoid I/O for this case. This allows some client-provided operations (like merging
+      // snapshots) to work without needing to first store the empty snapshot.
+      return Ok(Some(f(&[])));
+    }
+
+    if let Some(memory_cache) = &self.inner.memory_cache {
+      let cached = memory_cache.lock().get((entry_type, digest));
+      if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+        let counter = if cached.is_some() {
+          Metric::LocalStoreMemoryCacheHits
+        } else {
+          Metric::LocalStoreMemoryCacheMisses
         };
-        dbs?
-          .lease(digest.hash)
+        workunit_store_handle.store.increment_counter(counter, 1);
+      }
+      if let Some(bytes) = cached {
+        return Ok(Some(f(&bytes)));
+      }
+    }
+
+    // NB: A stored entry with the wrong length is corrupt (e.g. because a write was interrupted):
+    // a description of the corruption is passed out so that the entry can be quarantined. If reads
+    // are verified, the content is hashed too, which also catches corruption which preserves the
+    // length of an entry (e.g. bit rot).
+    let verify_digest_function = self
+      .inner
+      .verify_reads
+      .then_some(self.inner.digest_function);
+    let mut len_checked_f = move |bytes: &[u8]| {
+      if bytes.len() != digest.size_bytes {
+        return Ok::<_, String>(Err(format!(
+          "an unexpected length of {} bytes",
+          bytes.len()
+        )));
+      }
+      if let Some(digest_function) = verify_digest_function {
+        let actual_digest = Digest::of_bytes_with(digest_function, bytes);
+        if actual_digest != digest {
+          if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+            workunit_store_handle
+              .store
+              .increment_counter(Metric::LocalStoreReadVerificationFailures, 1);
+          }
+          return Ok(Err(format!(
+            "unexpected content, with fingerprint {}",
+            actual_digest.hash
+          )));
+        }
+      }
+      Ok(Ok(f(bytes)))
+    };
+
+    let use_fsdb = match entry_type {
+      EntryType::File => self.file_uses_fsdb(digest).await?,
+      EntryType::Directory => false,
+    };
+    let result = if use_fsdb {
+      self
+        .inner
+        .file_fsdb
+        .load_bytes_with(digest.hash, len_checked_f)
+        .await?
+    } else {
+      let dbs = match entry_type {
+        EntryType::Directory => self.inner.directory_lmdb.clone(),
+        EntryType::File => self.inner.file_lmdb.clone(),
+      }?;
+      match &self.inner.memory_cache {
+        Some(memory_cache) => {
+          let bytes = dbs
+            .load_bytes_with(digest.hash, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+            .await?;
+          match bytes {
+            Some(bytes) => {
+              let result = len_checked_f(&bytes)?;
+              if result.is_ok() {
+                memory_cache.lock().insert((entry_type, digest), bytes);
+              }
+              Some(result)
+            }
+            None => None,
+          }
+        }
+        None => dbs.load_bytes_with(digest.hash, len_checked_f).await?,
+      }
+    };
+    let result = match result {
+      Some(Ok(t)) => Some(t),
+      Some(Err(corruption)) => {
+        // Treat the corrupt entry as a miss, so that callers can repopulate it.
+        log::warn!("Stored {entry_type:?} {digest:?} had {corruption}: moving it into quarantine.");
+        if let Err(e) = self.quarantine(entry_type, digest).await {
+          log::warn!("Failed to quarantine {entry_type:?} {digest:?}: {e}");
+        }
+        if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+          workunit_store_handle
+            .store
+            .increment_counter(Metric::LocalStoreQuarantinedEntries, 1);
+        }
+        None
+      }
+      None => None,
+    };
+
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle.store.record_observation(
+        ObservationMetric::LocalStoreReadBlobSize,
+        digest.size_bytes as u64,
+      );
+      workunit_store_handle.store.record_observation(
+        ObservationMetric::LocalStoreReadBlobTimeMicros,
+        start.elapsed().as_micros() as u64,
+      );
+    }
+    self.check_slow_op("load", Self::backend_name(use_fsdb), digest, start);
+
+    Ok(result)
+  }
+
+  ///
+  /// Returns true if the given digest is present in this store, and its content has the expected
+  /// length and hash. Unlike `Self::load_bytes_with`, the content is only hashed in place (or
+  /// streamed from disk) rather than being made available to a caller.
+  ///
+  /// NB: Unlike `Self::load_bytes_with`, this does not consult a read warm target.
+  ///
+  pub async fn verify_present(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<bool, String> {
+    if digest == EMPTY_DIGEST {
+      return Ok(true);
+    }
+
+    let use_fsdb = match entry_type {
+      EntryType::File => self.file_uses_fsdb(digest).await?,
+      EntryType::Directory => false,
+    };
+    if use_fsdb {
+      return self.inner.file_fsdb.verify(digest).await;
+    }
+
+    let dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    }?;
+    let digest_function = self.inner.digest_function;
+    let verified = dbs
+      .load_bytes_with(digest.hash, move |bytes| {
+        Ok(
+          bytes.len() == digest.size_bytes
+            && Digest::of_bytes_with(digest_function, bytes) == digest,
+        )
+      })
+      .await?;
+    Ok(verified.unwrap_or(false))
+  }
+
+  ///
+  /// Re-hashes every entry in the store, and reports those whose content does not match their
+  /// fingerprint: for example, because of a write which was interrupted by power loss. Corrupt
+  /// entries are then handled according to `action`.
+  ///
+  pub async fn verify_integrity(
+    &self,
+    action: CorruptEntryAction,
+  ) -> Result<IntegrityReport, String> {
+    if action != CorruptEntryAction::Report {
+      self.check_writable()?;
+    }
+    let mut report = IntegrityReport::default();
+
+    let lmdbs = [
+      (EntryType::File, self.inner.file_lmdb.clone()?),
+      (EntryType::Directory, self.inner.directory_lmdb.clone()?),
+    ];
+    for (entry_type, lmdb) in lmdbs {
+      for aged_fingerprint in lmdb.aged_fingerprints().await? {
+        let digest = Digest::new(aged_fingerprint.fingerprint, aged_fingerprint.size_bytes);
+        let digest_function = self.inner.digest_function;
+        let verified = lmdb
+          .load_bytes_with(digest.hash, move |bytes| {
+            Ok(Digest::of_bytes_with(digest_function, bytes) == digest)
+          })
           .await
-          .map_err(|err| format!("Error leasing digest {digest:?}: {err}"))?;
+          .unwrap_or_else(|e| {
+            log::warn!("Failed to load {entry_type:?} {digest:?}: {e}");
+            Some(false)
+          });
+        report.checked += 1;
+        // NB: An entry which was removed concurrently is not corrupt.
+        if verified == Some(false) {
+          report.corrupt.push((entry_type, digest));
+        }
+      }
+    }
+
+    for digest in self.inner.file_fsdb.all_digests().await? {
+      report.checked += 1;
+      if !self.inner.file_fsdb.verify(digest).await?
+        && self.inner.file_fsdb.exists(digest.hash).await?
+      {
+        report.corrupt.push((EntryType::File, digest));
+      }
+    }
+
+    for (entry_type, digest) in &report.corrupt {
+      log::warn!("{entry_type:?} {digest:?} in the local store is corrupt.");
+      match action {
+        CorruptEntryAction::Report => (),
+        CorruptEntryAction::Remove => {
+          self.remove(*entry_type, *digest).await?;
+        }
+        CorruptEntryAction::Quarantine => {
+          self.quarantine(*entry_type, *digest).await?;
+        }
+      }
+    }
+    Ok(report)
+  }
+
+  ///
+  /// Moves the given entry out of the store into its quarantine directory, named by its
+  /// fingerprint. Entries stored on disk are moved as-is, while entries stored in LMDB are copied
+  /// out (if they are readable at all) and then removed.
+  ///
+  async fn quarantine(&self, entry_type: EntryType, digest: Digest) -> Result<PathBuf, String> {
+    let quarantine_dir = self.inner.root.join(QUARANTINE_DIR);
+    tokio::fs::create_dir_all(&quarantine_dir)
+      .await
+      .map_err(|e| format!("Failed to create {quarantine_dir:?}: {e}"))?;
+    let dest = quarantine_dir.join(digest.hash.to_hex());
+
+    let uses_fsdb = entry_type == EntryType::File && self.file_uses_fsdb(digest).await?;
+    if uses_fsdb {
+      self.inner.file_fsdb.quarantine(digest.hash, &dest).await?;
+      return Ok(dest);
+    }
+
+    let dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    }?;
+    match dbs
+      .load_bytes_with(digest.hash, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+      .await
+    {
+      Ok(Some(bytes)) => tokio::fs::write(&dest, bytes)
+        .await
+        .map_err(|e| format!("Failed to write {dest:?}: {e}"))?,
+      Ok(None) => (),
+      Err(e) => log::warn!("Could not copy {entry_type:?} {digest:?} into quarantine: {e}"),
+    }
+    self.remove(entry_type, digest).await?;
+    Ok(dest)
+  }
+
+  ///
+  /// Removes quarantined entries which were quarantined at least the lease time ago, and returns
+  /// the paths and sizes of the remaining entries, most recently quarantined first.
+  ///
+  async fn expire_quarantined_entries(&self) -> Result<Vec<(PathBuf, usize)>, String> {
+    let quarantine_dir = self.inner.root.join(QUARANTINE_DIR);
+    let read_error = |e: io::Error| format!("Failed to read {quarantine_dir:?}: {e}");
+    let mut read_dir = match tokio::fs::read_dir(&quarantine_dir).await {
+      Ok(read_dir) => read_dir,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(read_error(e)),
+    };
+    let mut remaining = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await.map_err(read_error)? {
+      let path = entry.path();
+      let metadata = match entry.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(format!("Failed to get metadata for {path:?}: {e}")),
+      };
+      let size_bytes = metadata.len() as usize;
+      let quarantined_at = metadata
+        .modified()
+        .map_err(|e| format!("Failed to get the mtime of {path:?}: {e}"))?;
+      let expired = quarantined_at
+        .elapsed()
+        .map(|age| age >= self.inner.file_fsdb.lease_time)
+        .unwrap_or(false);
+      if expired {
+        Self::remove_quarantined_entry(&path, size_bytes).await?;
+      } else {
+        remaining.push((quarantined_at, path, size_bytes));
+      }
+    }
+    remaining.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+    Ok(
+      remaining
+        .into_iter()
+        .map(|(_, path, size_bytes)| (path, size_bytes))
+        .collect(),
+    )
+  }
+
+  async fn remove_quarantined_entry(path: &Path, size_bytes: usize) -> Result<(), String> {
+    match tokio::fs::remove_file(path).await {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => {
+        return Err(format!("Failed to remove {path:?}: {e}"))
       }
+      _ => (),
+    }
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .increment_counter(Metric::LocalStoreEvictedBytes, size_bytes as u64);
     }
     Ok(())
   }
 
   ///
-  /// Attempts to shrink the stored files to be no bigger than target_bytes
-  /// (excluding lmdb overhead).
+  /// Loads the given digest into a file at `destination` with the given mode, for callers which will
+  /// memory map the destination. Returns false (without creating the destination) if the digest is
+  /// not present.
   ///
-  /// Returns the size it was shrunk to, which may be larger than target_bytes.
+  /// If the digest is stored on disk, and the destination is on the same (local) filesystem as the
+  /// store, the filesystem is asked to copy it (which may use `copy_file_range` or a clone). Otherwise,
+  /// the destination is created at its final size, memory mapped, and the content is copied directly
+  /// into the mapping.
+  ///
+  pub async fn load_into_mmap(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    destination: PathBuf,
+    mode: u32,
+  ) -> Result<bool, String> {
+    let uses_fsdb = match entry_type {
+      EntryType::File => self.file_uses_fsdb(digest).await?,
+      EntryType::Directory => false,
+    };
+    if !uses_fsdb {
+      let loaded = self
+        .load_bytes_with_inner(entry_type, digest, move |bytes| {
+          Self::write_into_mmap(&destination, mode, bytes.len(), |mapping| {
+            mapping.copy_from_slice(bytes);
+            Ok(())
+          })
+        })
+        .await?;
+      return loaded.transpose().map(|loaded| loaded.is_some());
+    }
+
+    let src = self.inner.file_fsdb.readable_path(digest.hash).await?;
+    let store_is_local = !self.inner.network_filesystem;
+    let store_filesystem_device = self.inner.filesystem_device;
+    let copied_by_filesystem = self
+      .inner
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut src_file = match std::fs::File::open(&src) {
+            Ok(src_file) => src_file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to open {src:?}: {e}")),
+          };
+          let len = src_file
+            .metadata()
+            .map_err(|e| format!("Could not access metadata for {src:?}: {e}"))?
+            .len() as usize;
+          if len != digest.size_bytes {
+            return Err(format!(
+              "Stored file for {digest:?} had an unexpected length: {len}"
+            ));
+          }
+
+          let destination_parent = destination.parent().unwrap_or_else(|| Path::new("."));
+          let same_device = store_is_local
+            && store_filesystem_device.is_some()
+            && platform::device_id(
+              &destination_parent.metadata().map_err(|e| {
+                format!("Could not access metadata for {destination_parent:?}: {e}")
+              })?,
+            ) == store_filesystem_device;
+          if same_device {
+            // NB: `std::fs::copy` uses `copy_file_range` on Linux, and clones on macOS.
+            std::fs::copy(&src, &destination)
+              .map_err(|e| format!("Failed to copy {src:?} to {destination:?}: {e}"))?;
+            platform::set_path_mode(&destination, mode)
+              .map_err(|e| format!("Failed to set permissions of {destination:?}: {e}"))?;
+            return Ok(Some(true));
+          }
+
+          Self::write_into_mmap(&destination, mode, len, |mapping| {
+            io::Read::read_exact(&mut src_file, mapping)
+              .map_err(|e| format!("Failed to read {src:?}: {e}"))
+          })?;
+          Ok(Some(false))
+        },
+        |e| Err(format!("`load_into_mmap` task failed: {e}")),
+      )
+      .await?;
+
+    if copied_by_filesystem == Some(true) {
+      if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+        workunit_store_handle
+          .store
+          .increment_counter(Metric::LocalStoreFilesystemCopies, 1);
+      }
+    }
+    Ok(copied_by_filesystem.is_some())
+  }
+
+  ///
+  /// Creates (or truncates) the given destination with the given length and mode, memory maps it,
+  /// and calls `f` to fill the mapping.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  fn write_into_mmap(
+    destination: &Path,
+    mode: u32,
+    len: usize,
+    f: impl FnOnce(&mut [u8]) -> Result<(), String>,
+  ) -> Result<(), String> {
+    let file = platform::create_with_mode(
+      std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true),
+      mode,
+    )
+    .open(destination)
+    .map_err(|e| format!("Failed to create {destination:?}: {e}"))?;
+    platform::set_mode(&file, mode)
+      .map_err(|e| format!("Failed to set permissions of {destination:?}: {e}"))?;
+    if len == 0 {
+      // Empty files cannot be mapped.
+      return Ok(());
+    }
+    file
+      .set_len(len as u64)
+      .map_err(|e| format!("Failed to resize {destination:?}: {e}"))?;
+    // Unsafety: the destination could be mutated by another process while it is mapped, but we
+    // only write to the mapping, and the content is verified by its length and digest before this.
+    let mut mapping = unsafe { memmap::MmapMut::map_mut(&file) }
+      .map_err(|e| format!("Failed to memory map {destination:?}: {e}"))?;
+    f(&mut mapping[..])?;
+    mapping
+      .flush()
+      .map_err(|e| format!("Failed to flush {destination:?}: {e}"))
+  }
+
+  ///
+  /// Streams the content of the given digest in chunks of at most `chunk_size` bytes, so that a
+  /// consumer can apply backpressure. Large files are read incrementally from disk, while values
+  /// stored in LMDB are copied out once and then sliced.
+  ///
+  /// Fails (as the final item of the stream) if the total length does not match the digest.
+  ///
+  pub fn stream_chunks(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    chunk_size: usize,
+  ) -> impl Stream<Item = Result<Bytes, String>> + Send + 'static {
+    let store = self.clone();
+    try_stream! {
+      if chunk_size == 0 {
+        Err("Chunk size must be greater than zero.".to_owned())?;
+      }
+      let missing = || format!("{entry_type:?} {digest:?} was not present in the local store.");
+
+      let mut total_len = 0;
+      if digest == EMPTY_DIGEST {
+        // Avoid I/O for this case, as in `Self::load_bytes_with`.
+      } else if entry_type == EntryType::File && store.file_uses_fsdb(digest).await? {
+        let path = store.inner.file_fsdb.readable_path(digest.hash).await?;
+        let mut file = match tokio::fs::File::open(&path).await {
+          Ok(file) => file,
+          Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(missing())?,
+          Err(e) => Err(format!("Failed to open {path:?}: {e}"))?,
+        };
+        loop {
+          let mut chunk = BytesMut::with_capacity(chunk_size);
+          let mut limited = (&mut file).take(chunk_size as u64);
+          while limited
+            .read_buf(&mut chunk)
+            .await
+            .map_err(|e| format!("Failed to read {path:?}: {e}"))?
+            > 0
+          {}
+          if chunk.is_empty() {
+            break;
+          }
+          total_len += chunk.len();
+          yield chunk.freeze();
+        }
+      } else {
+        let dbs = match entry_type {
+          EntryType::Directory => store.inner.directory_lmdb.clone(),
+          EntryType::File => store.inner.file_lmdb.clone(),
+        }?;
+        let bytes = dbs
+          .load_bytes_with(digest.hash, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+          .await?
+          .ok_or_else(missing)?;
+        for offset in (0..bytes.len()).step_by(chunk_size) {
+          let chunk = bytes.slice(offset..std::cmp::min(offset + chunk_size, bytes.len()));
+          total_len += chunk.len();
+          yield chunk;
+        }
+      }
+
+      if total_len != digest.size_bytes {
+        Err(format!(
+          "Streamed {total_len} bytes for {digest:?}, which does not match its expected length."
+        ))?;
+      }
+    }
+  }
+
+  ///
+  /// Returns a reader for the content of the given digest, or None if it is not present. Large files
+  /// are read incrementally from disk (and so are never held in memory in their entirety), while
+  /// values stored in LMDB are copied out once and then read in whatever chunks the consumer asks
+  /// for.
+  ///
+  /// NB: Like `Self::stream_chunks`, this does not consult a read warm target.
+  ///
+  pub async fn load_stream(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<Option<Pin<Box<dyn AsyncRead + Send>>>, String> {
+    if digest == EMPTY_DIGEST {
+      // Avoid I/O for this case, as in `Self::load_bytes_with`.
+      return Ok(Some(Box::pin(io::Cursor::new(Bytes::new()))));
+    }
+
+    let use_fsdb = match entry_type {
+      EntryType::File => self.file_uses_fsdb(digest).await?,
+      EntryType::Directory => false,
+    };
+    if use_fsdb {
+      let path = self.inner.file_fsdb.readable_path(digest.hash).await?;
+      let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to open {path:?}: {e}")),
+      };
+      let len = file
+        .metadata()
+        .await
+        .map_err(|e| format!("Could not access metadata for {path:?}: {e}"))?
+        .len() as usize;
+      if len != digest.size_bytes {
+        return Err(format!(
+          "Stored file for {digest:?} had an unexpected length: {len}"
+        ));
+      }
+      return Ok(Some(Box::pin(file)));
+    }
+
+    let dbs = match entry_type {
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+      EntryType::File => self.inner.file_lmdb.clone(),
+    }?;
+    let bytes = dbs
+      .load_bytes_with(digest.hash, move |bytes| {
+        if bytes.len() == digest.size_bytes {
+          Ok(Bytes::copy_from_slice(bytes))
+        } else {
+          Err(format!(
+            "Stored value for {digest:?} had an unexpected length: {}",
+            bytes.len()
+          ))
+        }
+      })
+      .await?;
+    Ok(bytes.map(|bytes| Box::pin(io::Cursor::new(bytes)) as Pin<Box<dyn AsyncRead + Send>>))
+  }
+
+  ///
+  /// Writes the given entries to `writer` as an archive which `Self::import` can store into another
+  /// store: for example, to seed a fresh machine, or to capture exactly the entries which a failing
+  /// build needs. All of the entries must be present in this store itself (rather than in its base
+  /// layers or read warm target), and are written in order of their entry type and fingerprint.
+  ///
+  /// An archive consists of:
+  ///   * `ARCHIVE_MAGIC`, followed by `ARCHIVE_VERSION` as a big-endian u32.
+  ///   * The name of the digest function of the store, preceded by its length as a u8.
+  ///   * The number of entries, as a big-endian u64.
+  ///   * An index of the entries, each as its entry type (0 for a file, or 1 for a directory) as a
+  ///     u8, its fingerprint, and its length as a big-endian u64.
+  ///   * The content of each entry, in the order of the index.
   ///
-  /// TODO: Use LMDB database statistics when lmdb-rs exposes them.
+  /// Because the index precedes the content, an archive can be written and read as a stream.
   ///
-  pub async fn shrink(
+  pub async fn export<W: AsyncWrite + Unpin>(
     &self,
-    target_bytes: usize,
-    shrink_behavior: ShrinkBehavior,
-  ) -> Result<usize, String> {
-    let mut used_bytes: usize = 0;
-    let mut fingerprints_by_expired_ago = BinaryHeap::new();
-
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .file_lmdb
-        .clone()?
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::File)
-        }),
-    );
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .directory_lmdb
-        .clone()?
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::Directory)
-        }),
-    );
-    fingerprints_by_expired_ago.extend(
-      self
-        .inner
-        .file_fsdb
-        .aged_fingerprints()
-        .await?
-        .into_iter()
-        .map(|fingerprint| {
-          used_bytes += fingerprint.size_bytes;
-          (fingerprint, EntryType::File)
-        }),
-    );
+    entries: impl IntoIterator<Item = (Digest, EntryType)>,
+    writer: &mut W,
+  ) -> Result<(), String> {
+    let mut entries = entries
+      .into_iter()
+      .map(|(digest, entry_type)| (entry_type, digest.hash, digest.size_bytes))
+      .collect::<Vec<_>>();
+    entries.sort();
+    entries.dedup();
 
-    while used_bytes > target_bytes {
-      let (aged_fingerprint, entry_type) = fingerprints_by_expired_ago
-        .pop()
-        .expect("lmdb corruption detected, sum of size of blobs exceeded stored blobs");
-      if aged_fingerprint.expired_seconds_ago == 0 {
-        // Ran out of expired blobs - everything remaining is leased and cannot be collected.
-        return Ok(used_bytes);
+    for entry_type in [EntryType::Directory, EntryType::File] {
+      let digests = entries
+        .iter()
+        .filter(|(t, _, _)| *t == entry_type)
+        .map(|(_, fingerprint, size_bytes)| Digest::new(*fingerprint, *size_bytes))
+        .collect();
+      let missing = self.get_missing_digests_inner(entry_type, digests).await?;
+      if let Some(digest) = missing.iter().next() {
+        return Err(format!(
+          "Cannot export {} {entry_type:?} entries which are not present in the local store, \
+           including {digest:?}.",
+          missing.len()
+        ));
       }
-      self
-        .remove(
-          entry_type,
-          Digest {
-            hash: aged_fingerprint.fingerprint,
-            size_bytes: aged_fingerprint.size_bytes,
-          },
-        )
-        .await?;
-      used_bytes -= aged_fingerprint.size_bytes;
     }
 
-    if shrink_behavior == ShrinkBehavior::Compact {
-      self.inner.file_lmdb.clone()?.compact()?;
+    let write_err = |e: io::Error| format!("Failed to write store archive: {e}");
+    let digest_function = self.inner.digest_function.to_string();
+    let mut header = BytesMut::new();
+    header.extend_from_slice(ARCHIVE_MAGIC);
+    header.extend_from_slice(&ARCHIVE_VERSION.to_be_bytes());
+    header.extend_from_slice(&[digest_function.len() as u8]);
+    header.extend_from_slice(digest_function.as_bytes());
+    header.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+    for (entry_type, fingerprint, size_bytes) in &entries {
+      header.extend_from_slice(&[match entry_type {
+        EntryType::File => 0,
+        EntryType::Directory => 1,
+      }]);
+      header.extend_from_slice(fingerprint.as_bytes());
+      header.extend_from_slice(&(*size_bytes as u64).to_be_bytes());
     }
+    writer.write_all(&header).await.map_err(write_err)?;
 
-    Ok(used_bytes)
+    for (entry_type, fingerprint, size_bytes) in entries {
+      let digest = Digest::new(fingerprint, size_bytes);
+      let mut reader = self
+        .load_stream(entry_type, digest)
+        .await?
+        .ok_or_else(|| format!("{entry_type:?} {digest:?} was removed while it was exported."))?;
+      let copied = tokio::io::copy(&mut reader, writer)
+        .await
+        .map_err(write_err)?;
+      if copied != size_bytes as u64 {
+        return Err(format!(
+          "Exported {copied} bytes for {digest:?}, which does not match its expected length."
+        ));
+      }
+    }
+    writer.flush().await.map_err(write_err)
   }
 
-  pub async fn remove(&self, entry_type: EntryType, digest: Digest) -> Result<bool, String> {
-    match entry_type {
-      EntryType::Directory => self.inner.directory_lmdb.clone()?.remove(digest.hash).await,
-      EntryType::File if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) => {
-        self.inner.file_fsdb.remove(digest.hash).await
+  ///
+  /// Stores the entries of an archive written by `Self::export` (by a store with the same digest
+  /// function), and returns them. The content of each entry is hashed as it is stored, and must
+  /// match its digest in the index of the archive.
+  ///
+  pub async fn import<R: AsyncRead + Send + Unpin>(
+    &self,
+    reader: &mut R,
+    initial_lease: bool,
+  ) -> Result<Vec<(Digest, EntryType)>, String> {
+    self.check_writable()?;
+    let read_err = |e: io::Error| format!("Failed to read store archive: {e}");
+    let mut magic = [0; ARCHIVE_MAGIC.len()];
+    reader.read_exact(&mut magic).await.map_err(read_err)?;
+    let version = reader.read_u32().await.map_err(read_err)?;
+    if &magic != ARCHIVE_MAGIC || version != ARCHIVE_VERSION {
+      return Err(format!(
+        "Not a store archive, or a store archive with an unsupported version: expected version \
+         {ARCHIVE_VERSION}."
+      ));
+    }
+    let mut digest_function = vec![0; reader.read_u8().await.map_err(read_err)? as usize];
+    reader
+      .read_exact(&mut digest_function)
+      .await
+      .map_err(read_err)?;
+    let digest_function = String::from_utf8_lossy(&digest_function);
+    if digest_function != self.inner.digest_function.to_string() {
+      return Err(format!(
+        "The store archive was written by a store with the {digest_function} digest function, \
+         but this store uses {}.",
+        self.inner.digest_function
+      ));
+    }
+
+    // NB: The entry count is not trusted to preallocate the index.
+    let entry_count = reader.read_u64().await.map_err(read_err)?;
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+      let entry_type = match reader.read_u8().await.map_err(read_err)? {
+        0 => EntryType::File,
+        1 => EntryType::Directory,
+        tag => return Err(format!("Unrecognized entry type in store archive: {tag}")),
+      };
+      let mut fingerprint = [0; hashing::FINGERPRINT_SIZE];
+      reader
+        .read_exact(&mut fingerprint)
+        .await
+        .map_err(read_err)?;
+      let size_bytes = reader.read_u64().await.map_err(read_err)? as usize;
+      entries.push((
+        Digest::new(Fingerprint(fingerprint), size_bytes),
+        entry_type,
+      ));
+    }
+
+    for (digest, entry_type) in &entries {
+      let content = (&mut *reader).take(digest.size_bytes as u64);
+      let actual_digest = self
+        .store_from_reader(*entry_type, initial_lease, content)
+        .await?;
+      if actual_digest != *digest {
+        return Err(format!(
+          "The content of {entry_type:?} {digest:?} in the store archive had digest \
+           {actual_digest:?}."
+        ));
       }
-      EntryType::File => self.inner.file_lmdb.clone()?.remove(digest.hash).await,
     }
+    Ok(entries)
   }
 
   ///
-  /// Store the given data in a single pass, using the given Fingerprint. Prefer `Self::store`
-  /// for values which should not be pulled into memory, and `Self::store_bytes_batch` when storing
-  /// multiple values at a time.
+  /// Loads cached `DirectoryStats` for the given directory fingerprint, if the cache is enabled and
+  /// contains them.
   ///
-  pub async fn store_bytes(
+  pub async fn load_directory_stats(
     &self,
-    entry_type: EntryType,
     fingerprint: Fingerprint,
-    bytes: Bytes,
-    initial_lease: bool,
-  ) -> Result<(), String> {
-    self
-      .store_bytes_batch(entry_type, vec![(fingerprint, bytes)], initial_lease)
+  ) -> Result<Option<DirectoryStats>, String> {
+    let directory_stats_lmdb = match &self.inner.directory_stats_lmdb {
+      Some(directory_stats_lmdb) => directory_stats_lmdb.clone()?,
+      None => return Ok(None),
+    };
+    directory_stats_lmdb
+      .load_bytes_with(fingerprint, move |bytes| {
+        if bytes.len() != 24 {
+          return Err(format!(
+            "Cached stats for {fingerprint:?} had an unexpected length: {}",
+            bytes.len()
+          ));
+        }
+        let field = |i: usize| {
+          let mut array = [0_u8; 8];
+          array.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+          u64::from_le_bytes(array) as usize
+        };
+        Ok(DirectoryStats {
+          total_bytes: field(0),
+          file_count: field(1),
+          max_depth: field(2),
+        })
+      })
       .await
   }
 
   ///
-  /// Store the given items in a single pass, optionally using the given Digests. Prefer `Self::store`
-  /// for values which should not be pulled into memory.
-  ///
-  /// See also: `Self::store_bytes`.
+  /// Caches `DirectoryStats` for the given directory fingerprint, if the cache is enabled and the
+  /// store is not read-only.
   ///
-  pub async fn store_bytes_batch(
+  pub async fn store_directory_stats(
     &self,
-    entry_type: EntryType,
-    items: Vec<(Fingerprint, Bytes)>,
-    initial_lease: bool,
+    fingerprint: Fingerprint,
+    stats: DirectoryStats,
   ) -> Result<(), String> {
-    let mut fsdb_items = vec![];
-    let mut lmdb_items = vec![];
-    for (fingerprint, bytes) in items {
-      if ByteStore::should_use_fsdb(entry_type, bytes.len()) {
-        fsdb_items.push((fingerprint, bytes));
-      } else {
-        lmdb_items.push((fingerprint, bytes));
-      }
-    }
-
-    let lmdb_dbs = match entry_type {
-      EntryType::Directory => self.inner.directory_lmdb.clone(),
-      EntryType::File => self.inner.file_lmdb.clone(),
+    let directory_stats_lmdb = match &self.inner.directory_stats_lmdb {
+      Some(directory_stats_lmdb) if !self.inner.read_only => directory_stats_lmdb.clone()?,
+      _ => return Ok(()),
     };
-    try_join(
-      self
-        .inner
-        .file_fsdb
-        .store_bytes_batch(fsdb_items, initial_lease),
-      lmdb_dbs?.store_bytes_batch(lmdb_items, initial_lease),
-    )
-    .await?;
-
+    let mut bytes = BytesMut::with_capacity(24);
+    for field in [stats.total_bytes, stats.file_count, stats.max_depth] {
+      bytes.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+    directory_stats_lmdb
+      .store_bytes(fingerprint, bytes.freeze(), false)
+      .await?;
     Ok(())
   }
 
   ///
-  /// Store data in two passes, without buffering it entirely into memory. Prefer
-  /// `Self::store_bytes` for small values which fit comfortably in memory.
+  /// Copies the given digest from the source store into this store, with an initial lease.
   ///
-  pub async fn store(
+  pub(crate) async fn copy_from(
     &self,
+    source: &ByteStore,
     entry_type: EntryType,
-    initial_lease: bool,
-    src_is_immutable: bool,
-    src: PathBuf,
-  ) -> Result<Digest, String> {
-    let mut file = tokio::fs::File::open(src.clone())
-      .await
-      .map_err(|e| format!("Failed to open {src:?}: {e}"))?;
-    let digest = async_copy_and_hash(&mut file, &mut tokio::io::sink())
-      .await
-      .map_err(|e| format!("Failed to hash {src:?}: {e}"))?;
-
-    if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
+    digest: Digest,
+  ) -> Result<(), String> {
+    self.check_writable()?;
+    self.check_free_space().await.map_err(|e| e.to_string())?;
+    let missing = || format!("{entry_type:?} {digest:?} was not present in the source store.");
+    if self.should_use_fsdb(entry_type, digest.size_bytes) {
+      let src = source.load_from_fs(digest).await?.ok_or_else(missing)?;
       self
         .inner
         .file_fsdb
-        .store(initial_lease, src_is_immutable, digest, src)
-        .await?;
+        .store(true, true, digest, src, None)
+        .await
     } else {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_lmdb.clone()?,
-        EntryType::File => self.inner.file_lmdb.clone()?,
-      };
-      let _ = dbs
-        .store(initial_lease, src_is_immutable, digest, move || {
-          std::fs::File::open(&src)
-        })
-        .await;
+      let bytes = source
+        .load_bytes_with_layers(entry_type, digest, Bytes::copy_from_slice)
+        .await?
+        .ok_or_else(missing)?;
+      self.store_bytes(entry_type, digest.hash, bytes, true).await
     }
+  }
 
-    Ok(digest)
+  pub async fn all_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
+    let lmdb = match entry_type {
+      EntryType::File => self.inner.file_lmdb.clone(),
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
+    }?;
+    let mut digests = vec![];
+    digests.extend(lmdb.all_digests().await?);
+    digests.extend(self.inner.file_fsdb.all_digests().await?);
+    Ok(digests)
   }
 
   ///
-  /// Given a collection of Digests (digests),
-  /// returns the set of digests from that collection not present in the
-  /// underlying LMDB store.
+  /// Returns the Digests of all entries of the given type which were modified after `since`.
   ///
-  pub async fn get_missing_digests(
+  /// For files stored on disk this is their mtime, and for entries stored in LMDB it is derived from
+  /// their stored lease. Because extending a lease also updates these times, this captures entries
+  /// which were "recently touched" rather than strictly "recently created".
+  ///
+  pub async fn entries_modified_since(
     &self,
     entry_type: EntryType,
-    digests: HashSet<Digest>,
-  ) -> Result<HashSet<Digest>, String> {
-    let mut fsdb_digests = vec![];
-    let mut lmdb_digests = vec![];
-    for digest in digests.iter() {
-      if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
-        fsdb_digests.push(digest);
-      }
-      // Avoid I/O for this case. This allows some client-provided operations (like
-      // merging snapshots) to work without needing to first store the empty snapshot.
-      else if *digest != EMPTY_DIGEST {
-        lmdb_digests.push(digest);
-      }
-    }
-
+    since: SystemTime,
+  ) -> Result<Vec<Digest>, String> {
     let lmdb = match entry_type {
-      EntryType::Directory => self.inner.directory_lmdb.clone(),
       EntryType::File => self.inner.file_lmdb.clone(),
+      EntryType::Directory => self.inner.directory_lmdb.clone(),
     }?;
-    let (mut existing, existing_lmdb_digests) = try_join(
-      self
-        .inner
-        .file_fsdb
-        .exists_batch(fsdb_digests.iter().map(|digest| digest.hash).collect()),
-      lmdb.exists_batch(lmdb_digests.iter().map(|digest| digest.hash).collect()),
-    )
-    .await?;
-
-    existing.extend(existing_lmdb_digests);
-
-    Ok(
-      digests
-        .into_iter()
-        .filter(|digest| *digest != EMPTY_DIGEST && !existing.contains(&digest.hash))
-        .collect(),
-    )
+    let mut digests = lmdb.modified_since(since).await?;
+    if entry_type == EntryType::File {
+      digests.extend(self.inner.file_fsdb.modified_since(since).await?);
+    }
+    Ok(digests)
   }
 
   ///
-  /// Return the path this digest is persistent on the filesystem at, or None.
+  /// Lists all shards of the store, in a stable order. Every stored entry belongs to exactly one
+  /// shard, so tools which process the store in parallel can divide the shards between workers
+  /// without coordinating, with each worker calling `Self::digests_in_shard` for its shards.
   ///
-  pub async fn load_from_fs(&self, digest: Digest) -> Result<Option<PathBuf>, String> {
-    if self.inner.file_fsdb.exists(digest.hash).await? {
-      return Ok(Some(self.inner.file_fsdb.get_path(digest.hash)));
-    }
-    Ok(None)
+  pub fn shards(&self) -> Vec<ShardId> {
+    let mut lmdb_shard_ids = [&self.inner.file_lmdb, &self.inner.directory_lmdb]
+      .into_iter()
+      .flat_map(|lmdb| {
+        lmdb
+          .as_ref()
+          .map(|lmdb| lmdb.shard_ids())
+          .unwrap_or_default()
+      })
+      .collect::<Vec<_>>();
+    lmdb_shard_ids.sort_unstable();
+    lmdb_shard_ids.dedup();
+    lmdb_shard_ids
+      .into_iter()
+      .map(ShardId::Lmdb)
+      .chain(ShardedFSDB::shard_ids().map(ShardId::Fsdb))
+      .collect()
   }
 
   ///
-  /// Loads bytes from the underlying store using the given function.
-  /// In the case of the LMDB store, because the database is blocking, this accepts a function that
-  /// views a slice rather than returning a clone of the data.
-  /// The upshot is that the database is able to provide slices directly into shared memory.
+  /// Returns the Digests of all entries of the given type which are stored in the given shard.
   ///
-  pub async fn load_bytes_with<T: Send + 'static, F: FnMut(&[u8]) -> T + Send + Sync + 'static>(
+  pub async fn digests_in_shard(
     &self,
     entry_type: EntryType,
-    digest: Digest,
-    mut f: F,
-  ) -> Result<Option<T>, String> {
-    let start = Instant::now();
-    if digest == EMPTY_DIGEST {
-      // Avoid I/O for this case. This allows some client-provided operations (like merging
-      // snapshots) to work without needing to first store the empty snapshot.
-      return Ok(Some(f(&[])));
+    shard: ShardId,
+  ) -> Result<Vec<Digest>, String> {
+    match (entry_type, shard) {
+      (EntryType::File, ShardId::Lmdb(shard_id)) => {
+        Self::lmdb_digests_in_shard(self.inner.file_lmdb.clone()?, shard_id).await
+      }
+      (EntryType::Directory, ShardId::Lmdb(shard_id)) => {
+        Self::lmdb_digests_in_shard(self.inner.directory_lmdb.clone()?, shard_id).await
+      }
+      (EntryType::File, ShardId::Fsdb(shard_id)) => {
+        self.inner.file_fsdb.digests_in_shard(shard_id).await
+      }
+      // Directories are never stored on disk.
+      (EntryType::Directory, ShardId::Fsdb(_)) => Ok(vec![]),
     }
+  }
 
-    let len_checked_f = move |bytes: &[u8]| {
-      if bytes.len() == digest.size_bytes {
-        Ok(f(bytes))
-      } else {
-        Err(format!(
-          "Got hash collision reading from store - digest {:?} was requested, but retrieved \
-                bytes with that fingerprint had length {}. Congratulations, you may have broken \
-                sha256! Underlying bytes: {:?}",
-          digest,
-          bytes.len(),
-          bytes
-        ))
+  ///
+  /// Returns the number of entries in each shard directory of large files. If
+  /// `LocalOptions::max_entries_per_shard` is set, shards with more entries than that are logged,
+  /// since very large directories are slow to scan, and may hit filesystem limits on the number of
+  /// entries per directory.
+  ///
+  pub async fn shard_entry_counts(&self) -> Result<Vec<(ShardId, usize)>, String> {
+    let counts = self.inner.file_fsdb.shard_entry_counts().await?;
+    if let Some(max_entries_per_shard) = self.inner.max_entries_per_shard {
+      let overfull = counts
+        .iter()
+        .filter(|(_, count)| *count > max_entries_per_shard)
+        .map(|(shard_id, count)| format!("{shard_id:02x} ({count} entries)"))
+        .collect::<Vec<_>>();
+      if !overfull.is_empty() {
+        log::warn!(
+          "{} shard(s) of {} contain more than the maximum of {max_entries_per_shard} entries: {}. \
+           Consider garbage collecting the store, or relocating it to a filesystem which handles \
+           large directories well.",
+          overfull.len(),
+          self.inner.file_fsdb.root.display(),
+          overfull.join(", "),
+        );
       }
-    };
+    }
+    Ok(
+      counts
+        .into_iter()
+        .map(|(shard_id, count)| (ShardId::Fsdb(shard_id), count))
+        .collect(),
+    )
+  }
 
-    let result = if ByteStore::should_use_fsdb(entry_type, digest.size_bytes) {
-      self
-        .inner
-        .file_fsdb
-        .load_bytes_with(digest.hash, len_checked_f)
-        .await?
-    } else {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_lmdb.clone(),
-        EntryType::File => self.inner.file_lmdb.clone(),
-      }?;
-      dbs.load_bytes_with(digest.hash, len_checked_f).await?
+  ///
+  /// Reports the number and size of the entries in each backend of the store, including how many
+  /// bytes have expired leases (and so may be garbage collected), broken down by shard.
+  ///
+  pub async fn stats(&self) -> Result<StoreStats, String> {
+    let lmdb_stats = |lmdb: Result<Arc<ShardedLmdb>, String>| async move {
+      let lmdb = lmdb?;
+      let mut stats = BackendStats::default();
+      for shard_id in lmdb.shard_ids() {
+        for aged_fingerprint in lmdb.all_fingerprints_in_shard(shard_id).await? {
+          stats.add(ShardId::Lmdb(shard_id), &aged_fingerprint);
+        }
+      }
+      Ok::<_, String>(stats)
     };
+    let (file_lmdb, directory_lmdb, fsdb_fingerprints) = try_join3(
+      lmdb_stats(self.inner.file_lmdb.clone()),
+      lmdb_stats(self.inner.directory_lmdb.clone()),
+      self.inner.file_fsdb.aged_fingerprints(),
+    )
+    .await?;
 
-    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
-      workunit_store_handle.store.record_observation(
-        ObservationMetric::LocalStoreReadBlobSize,
-        digest.size_bytes as u64,
-      );
-      workunit_store_handle.store.record_observation(
-        ObservationMetric::LocalStoreReadBlobTimeMicros,
-        start.elapsed().as_micros() as u64,
+    let mut file_fsdb = BackendStats::default();
+    for aged_fingerprint in fsdb_fingerprints {
+      // NB: Each FSDB shard is named by the first byte of the fingerprints that it contains.
+      file_fsdb.add(
+        ShardId::Fsdb(aged_fingerprint.fingerprint.as_bytes()[0]),
+        &aged_fingerprint,
       );
     }
 
-    Ok(result)
+    Ok(StoreStats {
+      file_lmdb,
+      directory_lmdb,
+      file_fsdb,
+    })
   }
 
-  pub async fn all_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
-    let lmdb = match entry_type {
-      EntryType::File => self.inner.file_lmdb.clone(),
-      EntryType::Directory => self.inner.directory_lmdb.clone(),
-    }?;
-    let mut digests = vec![];
-    digests.extend(lmdb.all_digests().await?);
-    digests.extend(self.inner.file_fsdb.all_digests().await?);
-    Ok(digests)
+  async fn lmdb_digests_in_shard(
+    lmdb: Arc<ShardedLmdb>,
+    shard_id: u8,
+  ) -> Result<Vec<Digest>, String> {
+    Ok(
+      lmdb
+        .all_fingerprints_in_shard(shard_id)
+        .await?
+        .into_iter()
+        .map(|fingerprint| Digest::new(fingerprint.fingerprint, fingerprint.size_bytes))
+        .collect(),
+    )
+  }
+
+  pub(crate) fn should_use_fsdb(&self, entry_type: EntryType, len: usize) -> bool {
+    entry_type == EntryType::File && len >= self.inner.large_file_size_limit
   }
 
-  pub(crate) fn should_use_fsdb(entry_type: EntryType, len: usize) -> bool {
-    entry_type == EntryType::File && len >= LARGE_FILE_SIZE_LIMIT
+  ///
+  /// Creates all of the shard directories for large files up front, so that storing a large file
+  /// does not need to create its shard directory. This trades a one-time cost for lower latency on
+  /// the first store into each shard.
+  ///
+  pub async fn precreate_shards(&self) -> Result<(), String> {
+    self.check_writable()?;
+    self.inner.file_fsdb.precreate_shards().await
   }
 
   pub(crate) fn get_file_fsdb(&self) -> ShardedFSDB {
     self.inner.file_fsdb.clone()
   }
+
+  pub(crate) fn falls_back_on_lookups(&self) -> bool {
+    self.inner.fallback_lookups.load(atomic::Ordering::SeqCst)
+  }
 }
@@ -1,19 +1,28 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 use crate::local::ByteStore;
-use crate::{EntryType, LocalOptions, ShrinkBehavior};
+use crate::{
+  scope_task_origin, BackgroundGcOptions, ContentSummary, CorruptEntryAction,
+  DiskSpaceWatchdogOptions, EntryType, EvictionPolicy, FsdbShardLayout, FullShardPolicy,
+  IntegrityReport, LocalOptions, Origin, PlacementReport, RemoveOutcome, ShardId, ShrinkBehavior,
+  StoreError, ThresholdChangeBehavior,
+};
 
 use std::collections::HashSet;
 use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use hashing::{Digest, Fingerprint};
+use futures::{future, TryStreamExt};
+use hashing::{Digest, DigestFunction, Fingerprint};
 use tempfile::{NamedTempFile, TempDir};
 use testutil::data::{TestData, TestDirectory};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::sleep;
 use walkdir::WalkDir;
+use workunit_store::WorkunitStore;
 
 async fn assert_store_bytes(
   store: ByteStore,
@@ -90,6 +99,196 @@ async fn missing_file() {
   );
 }
 
+///
+/// True if the filesystem containing the given directory can clone files.
+///
+#[cfg(target_os = "linux")]
+fn filesystem_clones_files(dir: &Path) -> bool {
+  use std::os::unix::io::AsRawFd;
+
+  let mut src = NamedTempFile::new_in(dir).unwrap();
+  src.write_all(b"content").unwrap();
+  let dest = NamedTempFile::new_in(dir).unwrap();
+  unsafe {
+    libc::ioctl(
+      dest.as_file().as_raw_fd(),
+      libc::FICLONE as _,
+      src.as_file().as_raw_fd(),
+    ) == 0
+  }
+}
+
+#[cfg(target_os = "macos")]
+fn filesystem_clones_files(dir: &Path) -> bool {
+  use std::ffi::CString;
+  use std::os::unix::ffi::OsStrExt;
+
+  let src = dir.join("clone-probe-src");
+  let dest = dir.join("clone-probe-dest");
+  std::fs::write(&src, b"content").unwrap();
+  let c_src = CString::new(src.as_os_str().as_bytes()).unwrap();
+  let c_dest = CString::new(dest.as_os_str().as_bytes()).unwrap();
+  let cloned = unsafe { libc::clonefile(c_src.as_ptr(), c_dest.as_ptr(), 0) == 0 };
+  let _ = std::fs::remove_file(&src);
+  let _ = std::fs::remove_file(&dest);
+  cloned
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn filesystem_clones_files(_dir: &Path) -> bool {
+  false
+}
+
+#[tokio::test]
+async fn save_immutable_large_file_is_independent_of_source() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let clones = || {
+    workunit_store
+      .get_metrics()
+      .get("local_store_filesystem_clones")
+      .copied()
+      .unwrap_or(0)
+  };
+
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  let mut src = NamedTempFile::new_in(dir.path()).unwrap();
+  src.write_all(&large.bytes()).unwrap();
+  src.flush().unwrap();
+  let digest = store
+    .store(EntryType::File, false, true, src.path().to_owned())
+    .await
+    .unwrap();
+  assert_eq!(digest, large.digest());
+  // The file should have been cloned if (and only if) the filesystem supports cloning.
+  let expected_clones = u64::from(filesystem_clones_files(dir.path()));
+  assert_eq!(clones(), expected_clones);
+
+  // However the file was stored (by cloning or by copying), changing the source afterward should
+  // not affect the stored file.
+  std::fs::write(src.path(), "987654321".repeat(1000 * 512)).unwrap();
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn save_read_only_immutable_large_file_links_source() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  let src = dir.path().join("generated");
+  std::fs::write(&src, large.bytes()).unwrap();
+  std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o444)).unwrap();
+  let a_day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+  fs_set_times::set_mtime(&src, fs_set_times::SystemTimeSpec::Absolute(a_day_ago)).unwrap();
+  let digest = store
+    .store(EntryType::File, false, true, src.clone())
+    .await
+    .unwrap();
+  assert_eq!(digest, large.digest());
+
+  // The stored file should share an inode with the source, whose permissions are unchanged.
+  let stored_metadata =
+    std::fs::metadata(store.get_file_fsdb().get_path(large.fingerprint())).unwrap();
+  assert_eq!(
+    stored_metadata.ino(),
+    std::fs::metadata(&src).unwrap().ino()
+  );
+
+  // Leasing the stored file records its lease without touching the mtime of the source, and the
+  // entry is leased despite the old mtime that it shares with the source.
+  store
+    .lease_all(std::iter::once((large.digest(), EntryType::File)))
+    .await
+    .unwrap();
+  let src_metadata = std::fs::metadata(&src).unwrap();
+  assert_eq!(src_metadata.modified().unwrap(), a_day_ago);
+  assert_eq!(src_metadata.permissions().mode() & 0o777, 0o444);
+  assert_eq!(
+    large.len(),
+    store.shrink(0, ShrinkBehavior::Fast).await.unwrap()
+  );
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn linked_large_file_is_read_without_mapping() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store_with_options(
+    dir.path(),
+    LocalOptions {
+      fsdb_mmap_reads: true,
+      ..LocalOptions::default()
+    },
+  );
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  let src = dir.path().join("generated");
+  std::fs::write(&src, large.bytes()).unwrap();
+  std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o444)).unwrap();
+  store
+    .store(EntryType::File, false, true, src.clone())
+    .await
+    .unwrap();
+
+  let fsdb = store.get_file_fsdb();
+  assert!(fsdb.is_linked(large.fingerprint()).await.unwrap());
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+
+  // Removing the entry forgets that it was linked, and leaves the source in place.
+  assert_eq!(
+    store.remove(EntryType::File, large.digest()).await,
+    Ok(true)
+  );
+  assert!(!fsdb.is_linked(large.fingerprint()).await.unwrap());
+  assert_eq!(std::fs::read(&src).unwrap(), large.bytes());
+}
+
+#[tokio::test]
+async fn concurrent_stores_of_large_file() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  let mut src = NamedTempFile::new_in(dir.path()).unwrap();
+  src.write_all(&large.bytes()).unwrap();
+  src.flush().unwrap();
+
+  // Concurrent stores of the same content (whether from files or from bytes) all succeed, and
+  // leave exactly one file behind in the shard.
+  let from_files = future::try_join_all(
+    (0..8).map(|_| store.store(EntryType::File, false, false, src.path().to_owned())),
+  );
+  let from_bytes = future::try_join_all(
+    (0..8).map(|_| store.store_bytes(EntryType::File, large.fingerprint(), large.bytes(), false)),
+  );
+  let (digests, _) = future::try_join(from_files, from_bytes).await.unwrap();
+  assert!(digests.into_iter().all(|digest| digest == large.digest()));
+
+  let path = store.get_file_fsdb().get_path(large.fingerprint());
+  let shard_entries = std::fs::read_dir(path.parent().unwrap())
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .collect::<Vec<_>>();
+  assert_eq!(shard_entries, vec![path]);
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+}
+
 #[tokio::test]
 async fn record_and_load_directory_proto() {
   let dir = TempDir::new().unwrap();
@@ -192,6 +391,243 @@ async fn garbage_collect_nothing_to_do_with_lease() {
   );
 }
 
+#[tokio::test]
+async fn lease_all_batches() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let directory = TestDirectory::containing_roland();
+  let files = [TestData::roland(), TestData::catnip(), large];
+  for testdata in &files {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  store
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+
+  let entries = files
+    .iter()
+    .map(|testdata| (testdata.digest(), EntryType::File))
+    .chain(std::iter::once((directory.digest(), EntryType::Directory)))
+    .collect::<Vec<_>>();
+  store.lease_all(entries.clone().into_iter()).await.unwrap();
+  for (digest, entry_type) in entries {
+    assert_eq!(
+      store.remove_unless_leased(entry_type, digest).await,
+      Ok(RemoveOutcome::WasLeased)
+    );
+  }
+}
+
+#[tokio::test]
+async fn lease_all_large_files() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  let files = (0..3)
+    .map(|i| TestData::new(format!("{i}").repeat(1000 * 512).as_str()))
+    .collect::<Vec<_>>();
+  for testdata in &files {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+    let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+    fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+  }
+
+  store
+    .lease_all(
+      files
+        .iter()
+        .map(|testdata| (testdata.digest(), EntryType::File)),
+    )
+    .await
+    .unwrap();
+  for testdata in &files {
+    let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+    assert!(std::fs::metadata(path).unwrap().modified().unwrap() > an_hour_ago);
+  }
+
+  // A large file which is not present fails the batch.
+  let missing = TestData::new("9".repeat(1000 * 512).as_str());
+  assert!(store
+    .lease_all(std::iter::once((missing.digest(), EntryType::File)))
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn fsdb_lease_database() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      fsdb_lease_database: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  let files = (0..2)
+    .map(|i| TestData::new(format!("{i}").repeat(1000 * 512).as_str()))
+    .collect::<Vec<_>>();
+  for testdata in &files {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+    // Simulate a filesystem which does not reliably update mtimes.
+    let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+    fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+  }
+
+  // Leasing records the lease without touching the file, and the lease is respected regardless of
+  // its mtime.
+  store
+    .lease_all(std::iter::once((files[0].digest(), EntryType::File)))
+    .await
+    .unwrap();
+  let path = store.get_file_fsdb().get_path(files[0].fingerprint());
+  assert!(
+    std::fs::metadata(path).unwrap().modified().unwrap()
+      < SystemTime::now() - Duration::from_secs(30 * 60)
+  );
+  assert_eq!(
+    store
+      .remove_unless_leased(EntryType::File, files[0].digest())
+      .await,
+    Ok(RemoveOutcome::WasLeased)
+  );
+
+  // A file without a recorded lease falls back to its mtime, which has expired.
+  assert_eq!(
+    store
+      .remove_unless_leased(EntryType::File, files[1].digest())
+      .await,
+    Ok(RemoveOutcome::Removed)
+  );
+
+  // Removing a file removes its recorded lease, so it is not leased if it is stored again with an
+  // expired mtime.
+  assert_eq!(
+    store.remove(EntryType::File, files[0].digest()).await,
+    Ok(true)
+  );
+  store
+    .store_bytes(
+      EntryType::File,
+      files[0].fingerprint(),
+      files[0].bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  let path = store.get_file_fsdb().get_path(files[0].fingerprint());
+  fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+  assert_eq!(
+    store
+      .remove_unless_leased(EntryType::File, files[0].digest())
+      .await,
+    Ok(RemoveOutcome::Removed)
+  );
+}
+
+#[tokio::test]
+async fn fsdb_existence_cache() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      fsdb_existence_cache_ttl: Some(Duration::from_secs(60 * 60)),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let files = (0..2)
+    .map(|i| TestData::new(format!("{i}").repeat(1000 * 512).as_str()))
+    .collect::<Vec<_>>();
+  let missing = |testdata: &TestData| {
+    store.get_missing_digests(EntryType::File, HashSet::from([testdata.digest()]))
+  };
+
+  // Files stored by this process are seen immediately, and removed files are seen to be absent.
+  assert_eq!(
+    missing(&files[0]).await,
+    Ok(HashSet::from([files[0].digest()]))
+  );
+  store
+    .store_bytes(
+      EntryType::File,
+      files[0].fingerprint(),
+      files[0].bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(missing(&files[0]).await, Ok(HashSet::new()));
+  assert_eq!(
+    store.remove(EntryType::File, files[0].digest()).await,
+    Ok(true)
+  );
+  assert_eq!(
+    missing(&files[0]).await,
+    Ok(HashSet::from([files[0].digest()]))
+  );
+
+  // But a file which was found to be absent is not seen if another process stores it, until the
+  // TTL has elapsed.
+  assert_eq!(
+    missing(&files[1]).await,
+    Ok(HashSet::from([files[1].digest()]))
+  );
+  new_store(dir.path())
+    .store_bytes(
+      EntryType::File,
+      files[1].fingerprint(),
+      files[1].bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    missing(&files[1]).await,
+    Ok(HashSet::from([files[1].digest()]))
+  );
+  assert_eq!(
+    new_store(dir.path())
+      .get_missing_digests(EntryType::File, HashSet::from([files[1].digest()]))
+      .await,
+    Ok(HashSet::new())
+  );
+}
+
 #[tokio::test]
 async fn garbage_collect_expired() {
   let lease_time = Duration::from_secs(1);
@@ -235,129 +671,512 @@ async fn garbage_collect_expired() {
 }
 
 #[tokio::test]
-async fn garbage_collect_remove_one_of_two_files_no_leases() {
+async fn disk_space_watchdog() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-  let bytes_1 = Bytes::from("0123456789");
-  let fingerprint_1 = Fingerprint::from_hex_string(
-    "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
-  )
-  .unwrap();
-  let digest_1 = Digest::new(fingerprint_1, 10);
-  let bytes_2 = Bytes::from("9876543210");
-  let fingerprint_2 = Fingerprint::from_hex_string(
-    "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
-  )
-  .unwrap();
-  let digest_2 = Digest::new(fingerprint_2, 10);
-  store
-    .store_bytes(EntryType::File, fingerprint_1, bytes_1.clone(), false)
-    .await
-    .expect("Error storing");
+  let expired = TestData::roland();
+  let leased = TestData::catnip();
+  for (testdata, initial_lease) in [(&expired, false), (&leased, true)] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        initial_lease,
+      )
+      .await
+      .unwrap();
+  }
+
+  // No filesystem has this much space, so the watchdog evicts whatever it can, and then rejects
+  // writes.
   store
-    .store_bytes(EntryType::File, fingerprint_2, bytes_2.clone(), false)
+    .start_disk_space_watchdog(DiskSpaceWatchdogOptions {
+      low_water_free_bytes: u64::MAX,
+      min_free_bytes: u64::MAX,
+      interval: Duration::from_millis(10),
+    })
+    .await;
+  let deadline = Instant::now() + Duration::from_secs(10);
+  let full = loop {
+    if let Err(full) = store.check_free_space().await {
+      break full;
+    }
+    assert!(Instant::now() < deadline, "The watchdog never checked.");
+    sleep(Duration::from_millis(10)).await;
+  };
+  assert_eq!(full.min_free_bytes, u64::MAX);
+  assert_eq!(load_file_bytes(&store, expired.digest()).await, Ok(None));
+  assert_eq!(
+    load_file_bytes(&store, leased.digest()).await,
+    Ok(Some(leased.bytes()))
+  );
+  let err = store
+    .store_bytes(
+      EntryType::File,
+      expired.fingerprint(),
+      expired.bytes(),
+      false,
+    )
     .await
-    .expect("Error storing");
+    .unwrap_err();
+  assert!(err.contains("is full"), "{err}");
+
+  // Once the watchdog is stopped, writes are attempted again.
+  store.stop_disk_space_watchdog().await;
   store
-    .shrink(10, ShrinkBehavior::Fast)
+    .store_bytes(
+      EntryType::File,
+      expired.fingerprint(),
+      expired.bytes(),
+      false,
+    )
     .await
-    .expect("Error shrinking");
-  let mut entries = Vec::new();
-  entries.push(
-    load_bytes(&store, EntryType::File, digest_1)
+    .unwrap();
+}
+
+#[tokio::test]
+async fn compress_cold_files() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let cold = TestData::new("0".repeat(1000 * 512).as_str());
+  let leased = TestData::new("1".repeat(1000 * 512).as_str());
+  for (testdata, initial_lease) in [(&cold, false), (&leased, true)] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        initial_lease,
+      )
       .await
-      .expect("Error loading bytes"),
+      .unwrap();
+  }
+  let path = store.get_file_fsdb().get_path(cold.fingerprint());
+  let compressed_path = path.with_file_name(format!("{}.zst", cold.fingerprint().to_hex()));
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+
+  // Only the file whose lease lapsed is compressed, and it keeps its mtime.
+  assert!(store.compress_cold_files(3).await.unwrap() > 0);
+  assert!(!path.exists());
+  assert_eq!(
+    std::fs::metadata(&compressed_path)
+      .unwrap()
+      .modified()
+      .unwrap(),
+    an_hour_ago
   );
-  entries.push(
-    load_bytes(&store, EntryType::File, digest_2)
+  assert!(store
+    .get_file_fsdb()
+    .get_path(leased.fingerprint())
+    .exists());
+  assert_eq!(store.compress_cold_files(3).await, Ok(0));
+
+  // For the purposes of garbage collection, the compressed file has its size on disk.
+  let compressed_len = std::fs::metadata(&compressed_path).unwrap().len() as usize;
+  assert!(compressed_len < cold.len());
+  let stats = store.stats().await.unwrap().file_fsdb;
+  assert_eq!(stats.total_bytes, compressed_len + leased.len());
+  assert_eq!(stats.expired_bytes, compressed_len);
+
+  // But its digest is listed with the size of its content.
+  assert_eq!(
+    store
+      .get_missing_digests(EntryType::File, HashSet::from([cold.digest()]))
+      .await,
+    Ok(HashSet::new())
+  );
+  assert_eq!(
+    store
+      .all_digests(EntryType::File)
       .await
-      .expect("Error loading bytes"),
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+    HashSet::from([cold.digest(), leased.digest()])
   );
   assert_eq!(
-    1,
-    entries.iter().filter(|maybe| maybe.is_some()).count(),
-    "Want one Some but got: {entries:?}"
+    load_file_bytes(&store, cold.digest()).await,
+    Ok(Some(cold.bytes()))
   );
-}
+  assert!(!path.exists());
 
-#[tokio::test]
-async fn garbage_collect_remove_both_files_no_leases() {
+  // Using the file via a path decompresses it.
+  assert_eq!(
+    store.load_from_fs(cold.digest()).await,
+    Ok(Some(path.clone()))
+  );
+  assert_eq!(std::fs::read(&path).unwrap(), cold.bytes());
+  assert!(!compressed_path.exists());
+
+  // Removing a compressed file removes it entirely.
+  assert!(store.compress_cold_files(3).await.unwrap() > 0);
+  assert_eq!(store.remove(EntryType::File, cold.digest()).await, Ok(true));
+  assert!(!compressed_path.exists());
+  assert_eq!(load_file_bytes(&store, cold.digest()).await, Ok(None));
+}
+
+#[tokio::test]
+async fn compress_cold_files_skips_incompressible_files() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-  let bytes_1 = Bytes::from("0123456789");
-  let fingerprint_1 = Fingerprint::from_hex_string(
-    "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+  // Content which zstd cannot shrink.
+  let mut state = 0x2545_f491_4f6c_dd1d_u64;
+  let random = (0..1000 * 512)
+    .map(|_| {
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      state as u8
+    })
+    .collect::<Vec<_>>();
+  let digest = Digest::of_bytes(&random);
+  store
+    .store_bytes(EntryType::File, digest.hash, Bytes::from(random), false)
+    .await
+    .unwrap();
+  let path = store.get_file_fsdb().get_path(digest.hash);
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+
+  assert_eq!(store.compress_cold_files(3).await, Ok(0));
+  assert!(store.get_file_fsdb().is_incompressible(digest.hash));
+  assert!(path.exists());
+}
+
+#[tokio::test]
+async fn compressed_files_in_read_only_base_layers() {
+  let base_dir = TempDir::new().unwrap();
+  let top_dir = TempDir::new().unwrap();
+  let testdata = TestData::new("0".repeat(1000 * 512).as_str());
+  let base_store = new_store(base_dir.path());
+  base_store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  let base_path = base_store.get_file_fsdb().get_path(testdata.fingerprint());
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  fs_set_times::set_mtime(
+    &base_path,
+    fs_set_times::SystemTimeSpec::Absolute(an_hour_ago),
   )
   .unwrap();
-  let digest_1 = Digest::new(fingerprint_1, 10);
-  let bytes_2 = Bytes::from("9876543210");
-  let fingerprint_2 = Fingerprint::from_hex_string(
-    "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
+  assert!(base_store.compress_cold_files(3).await.unwrap() > 0);
+  base_store.shutdown().await;
+
+  let base_store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    base_dir.path(),
+    LocalOptions {
+      read_only: true,
+      ..LocalOptions::default()
+    },
   )
   .unwrap();
-  let digest_2 = Digest::new(fingerprint_2, 10);
-  store
-    .store_bytes(EntryType::File, fingerprint_1, bytes_1.clone(), false)
-    .await
-    .expect("Error storing");
-  store
-    .store_bytes(EntryType::File, fingerprint_2, bytes_2.clone(), false)
-    .await
-    .expect("Error storing");
+  let top_store = new_store(top_dir.path());
+  let overlay_store = top_store.with_base_layers(vec![base_store]);
+
+  // The file cannot be decompressed in the read-only base layer, so it is decompressed into the
+  // top layer.
+  let top_path = top_store.get_file_fsdb().get_path(testdata.fingerprint());
+  assert_eq!(
+    overlay_store.load_from_fs(testdata.digest()).await,
+    Ok(Some(top_path.clone()))
+  );
+  assert_eq!(std::fs::read(&top_path).unwrap(), testdata.bytes());
+  assert!(!base_path.exists());
+}
+
+///
+/// Simulates a process which died after creating the temporary file for a large file, but before
+/// persisting it, by writing a temporary file into the shard directory and leaking it.
+///
+fn leak_tempfile(store: &ByteStore, fingerprint: Fingerprint, age: Duration) -> std::path::PathBuf {
+  let shard = store
+    .get_file_fsdb()
+    .get_path(fingerprint)
+    .parent()
+    .unwrap()
+    .to_owned();
+  std::fs::create_dir_all(&shard).unwrap();
+  let mut tempfile = NamedTempFile::new_in(&shard).unwrap();
+  tempfile.write_all(b"partially written").unwrap();
+  let (_, path) = tempfile.keep().unwrap();
+  let mtime = SystemTime::now() - age;
+  fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(mtime)).unwrap();
+  path
+}
+
+#[tokio::test]
+async fn garbage_collect_removes_stale_tempfiles() {
+  let lease_time = Duration::from_secs(60);
+  let dir = TempDir::new().unwrap();
+  let store = new_store_with_lease_time(dir.path(), lease_time);
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
   store
-    .shrink(1, ShrinkBehavior::Fast)
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
     .await
-    .expect("Error shrinking");
+    .unwrap();
+
+  let stale = leak_tempfile(&store, testdata.fingerprint(), lease_time * 2);
+  let fresh = leak_tempfile(&store, testdata.fingerprint(), Duration::ZERO);
+
+  // Temporary files are not entries: they are neither listed nor counted.
   assert_eq!(
-    load_bytes(&store, EntryType::File, digest_1).await,
-    Ok(None),
-    "Should have garbage collected {fingerprint_1:?}"
+    store.all_digests(EntryType::File).await,
+    Ok(vec![testdata.digest()])
   );
   assert_eq!(
-    load_bytes(&store, EntryType::File, digest_2).await,
-    Ok(None),
-    "Should have garbage collected {fingerprint_2:?}"
+    store
+      .shrink(usize::MAX, ShrinkBehavior::Fast)
+      .await
+      .expect("Error shrinking"),
+    testdata.len()
+  );
+
+  // But stale temporary files are removed by shrinking, while those which might still be in the
+  // process of being written are not.
+  assert!(!stale.exists());
+  assert!(fresh.exists());
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
   );
 }
 
 #[tokio::test]
-async fn garbage_collect_remove_one_of_two_directories_no_leases() {
+async fn startup_removes_stale_tempfiles() {
+  let lease_time = Duration::from_secs(60);
   let dir = TempDir::new().unwrap();
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let stale = {
+    let store = new_store_with_lease_time(dir.path(), lease_time);
+    // Wait for the startup sweep of this store to complete before "crashing".
+    store.clone().shutdown().await;
+    leak_tempfile(&store, testdata.fingerprint(), lease_time * 2)
+  };
+  assert!(stale.exists());
 
-  let testdir = TestDirectory::containing_roland();
-  let other_testdir = TestDirectory::containing_dnalor();
+  // Re-opening the store removes the temporary file in the background.
+  let store = new_store_with_lease_time(dir.path(), lease_time);
+  store.clone().shutdown().await;
+  assert!(!stale.exists());
+}
 
+#[tokio::test]
+async fn background_garbage_collection() {
+  let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
+  let testdata = [TestData::roland(), TestData::catnip()];
+  for testdata in &testdata {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+
+  store
+    .start_background_gc(BackgroundGcOptions {
+      target_bytes: 0,
+      interval: Duration::from_millis(10),
+      batch_size: 1,
+    })
+    .await;
+  for testdata in &testdata {
+    let mut attempts = 0;
+    while load_file_bytes(&store, testdata.digest()).await != Ok(None) {
+      attempts += 1;
+      assert!(
+        attempts < 100,
+        "{:?} was never collected.",
+        testdata.digest()
+      );
+      sleep(Duration::from_millis(50)).await;
+    }
+  }
+  store.stop_background_gc().await;
+
+  // Once stopped, nothing more is collected.
+  let testdata = TestData::robin();
   store
     .store_bytes(
-      EntryType::Directory,
-      testdir.fingerprint(),
-      testdir.bytes(),
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
       false,
     )
     .await
+    .unwrap();
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_skips_pinned() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let pinned = TestData::roland();
+  let unpinned = TestData::catnip();
+  for testdata in [&pinned, &unpinned] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  // The database of pins is not created until something is pinned.
+  assert_eq!(store.is_pinned(pinned.digest()).await, Ok(false));
+  assert!(!dir.path().join("pins").exists());
+  store.pin([pinned.digest()]).await.unwrap();
+  assert_eq!(store.is_pinned(pinned.digest()).await, Ok(true));
+  drop(store);
+
+  // Pins which were stored by an earlier store apply when it is reopened: only the pinned entry
+  // survives, although it is not leased.
+  let store = new_store(dir.path());
+  let size = store.shrink(0, ShrinkBehavior::Fast).await.unwrap();
+  assert_eq!(size, pinned.len());
+  assert_eq!(
+    load_file_bytes(&store, pinned.digest()).await,
+    Ok(Some(pinned.bytes()))
+  );
+  assert_eq!(load_file_bytes(&store, unpinned.digest()).await, Ok(None));
+
+  // Once unpinned, the entry can be collected.
+  store.unpin([pinned.digest()]).await.unwrap();
+  assert_eq!(store.is_pinned(pinned.digest()).await, Ok(false));
+  assert_eq!(store.shrink(0, ShrinkBehavior::Fast).await, Ok(0));
+  assert_eq!(load_file_bytes(&store, pinned.digest()).await, Ok(None));
+}
+
+async fn evict_one_of_two_large_files(policy: EvictionPolicy) -> Vec<Digest> {
+  let lease_time = Duration::from_secs(60);
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      lease_time,
+      eviction_policy: policy,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  // The smaller file expired longer ago, but the larger file is much larger.
+  let smaller = TestData::new("123456789".repeat(1000 * 64).as_str());
+  let larger = TestData::new("987654321".repeat(1000 * 256).as_str());
+  for (testdata, expired_ago) in [
+    (&smaller, Duration::from_secs(90 * 60)),
+    (&larger, Duration::from_secs(60 * 60)),
+  ] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+    let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+    let mtime = SystemTime::now() - lease_time - expired_ago;
+    fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(mtime)).unwrap();
+  }
+
+  store
+    .shrink(smaller.len() + larger.len() - 1, ShrinkBehavior::Fast)
+    .await
+    .unwrap();
+  let mut remaining = vec![];
+  for testdata in [&smaller, &larger] {
+    if load_file_bytes(&store, testdata.digest())
+      .await
+      .unwrap()
+      .is_some()
+    {
+      remaining.push(testdata.digest());
+    }
+  }
+  remaining
+}
+
+#[tokio::test]
+async fn eviction_policy_oldest() {
+  let larger = TestData::new("987654321".repeat(1000 * 256).as_str());
+  assert_eq!(
+    evict_one_of_two_large_files(EvictionPolicy::Oldest).await,
+    vec![larger.digest()]
+  );
+}
+
+#[tokio::test]
+async fn eviction_policy_largest_and_oldest() {
+  let smaller = TestData::new("123456789".repeat(1000 * 64).as_str());
+  assert_eq!(
+    evict_one_of_two_large_files(EvictionPolicy::LargestAndOldest).await,
+    vec![smaller.digest()]
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_remove_one_of_two_files_no_leases() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let bytes_1 = Bytes::from("0123456789");
+  let fingerprint_1 = Fingerprint::from_hex_string(
+    "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+  )
+  .unwrap();
+  let digest_1 = Digest::new(fingerprint_1, 10);
+  let bytes_2 = Bytes::from("9876543210");
+  let fingerprint_2 = Fingerprint::from_hex_string(
+    "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
+  )
+  .unwrap();
+  let digest_2 = Digest::new(fingerprint_2, 10);
+  store
+    .store_bytes(EntryType::File, fingerprint_1, bytes_1.clone(), false)
+    .await
     .expect("Error storing");
   store
-    .store_bytes(
-      EntryType::Directory,
-      other_testdir.fingerprint(),
-      other_testdir.bytes(),
-      false,
-    )
+    .store_bytes(EntryType::File, fingerprint_2, bytes_2.clone(), false)
     .await
     .expect("Error storing");
   store
-    .shrink(84, ShrinkBehavior::Fast)
+    .shrink(10, ShrinkBehavior::Fast)
     .await
     .expect("Error shrinking");
   let mut entries = Vec::new();
   entries.push(
-    load_bytes(&store, EntryType::Directory, testdir.digest())
+    load_bytes(&store, EntryType::File, digest_1)
       .await
       .expect("Error loading bytes"),
   );
   entries.push(
-    load_bytes(&store, EntryType::Directory, other_testdir.digest())
+    load_bytes(&store, EntryType::File, digest_2)
       .await
       .expect("Error loading bytes"),
   );
@@ -369,71 +1188,340 @@ async fn garbage_collect_remove_one_of_two_directories_no_leases() {
 }
 
 #[tokio::test]
-async fn garbage_collect_remove_file_with_leased_directory() {
+async fn garbage_collect_remove_both_files_no_leases() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-
-  let testdir = TestDirectory::containing_roland();
-  let testdata = TestData::forty_chars();
-
+  let bytes_1 = Bytes::from("0123456789");
+  let fingerprint_1 = Fingerprint::from_hex_string(
+    "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+  )
+  .unwrap();
+  let digest_1 = Digest::new(fingerprint_1, 10);
+  let bytes_2 = Bytes::from("9876543210");
+  let fingerprint_2 = Fingerprint::from_hex_string(
+    "7619ee8cea49187f309616e30ecf54be072259b43760f1f550a644945d5572f2",
+  )
+  .unwrap();
+  let digest_2 = Digest::new(fingerprint_2, 10);
   store
-    .store_bytes(
-      EntryType::Directory,
-      testdir.fingerprint(),
-      testdir.bytes(),
-      true,
-    )
+    .store_bytes(EntryType::File, fingerprint_1, bytes_1.clone(), false)
     .await
     .expect("Error storing");
-
   store
-    .store_bytes(
-      EntryType::File,
-      testdata.fingerprint(),
-      testdata.bytes(),
-      false,
-    )
+    .store_bytes(EntryType::File, fingerprint_2, bytes_2.clone(), false)
     .await
     .expect("Error storing");
-
   store
-    .shrink(80, ShrinkBehavior::Fast)
+    .shrink(1, ShrinkBehavior::Fast)
     .await
     .expect("Error shrinking");
-
   assert_eq!(
-    load_bytes(&store, EntryType::File, testdata.digest()).await,
+    load_bytes(&store, EntryType::File, digest_1).await,
     Ok(None),
-    "File was present when it should've been garbage collected"
+    "Should have garbage collected {fingerprint_1:?}"
   );
   assert_eq!(
-    load_bytes(&store, EntryType::Directory, testdir.digest()).await,
-    Ok(Some(testdir.bytes())),
-    "Directory was missing despite lease"
+    load_bytes(&store, EntryType::File, digest_2).await,
+    Ok(None),
+    "Should have garbage collected {fingerprint_2:?}"
   );
 }
 
 #[tokio::test]
-async fn garbage_collect_remove_file_while_leased_file() {
+async fn garbage_collect_across_many_shards() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
+  let an_hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+  // Entries in many shards of each backend, which are consumed a shard at a time.
+  let mut digests = vec![];
+  for i in 0..40 {
+    digests.push(prime_store_with_file_bytes(&store, Bytes::from(format!("{i:09}"))).await);
+    let large =
+      prime_store_with_file_bytes(&store, Bytes::from(format!("{i:09}").repeat(1000 * 64))).await;
+    // Large files are leased by their mtime when they are stored.
+    let path = store.get_file_fsdb().get_path(large.hash);
+    fs_set_times::set_mtime(&path, fs_set_times::SystemTimeSpec::Absolute(an_hour_ago)).unwrap();
+    digests.push(large);
+  }
+  let leased = digests[1];
+  store
+    .lease_all(std::iter::once((leased, EntryType::File)))
+    .await
+    .unwrap();
 
-  let testdir = TestDirectory::containing_roland();
+  let remaining = store
+    .shrink(1, ShrinkBehavior::Fast)
+    .await
+    .expect("Error shrinking");
+  assert_eq!(remaining, leased.size_bytes);
+  for digest in digests {
+    let expected = (digest == leased).then_some(digest);
+    assert_eq!(
+      load_file_bytes(&store, digest)
+        .await
+        .unwrap()
+        .map(|_| digest),
+      expected
+    );
+  }
+}
 
+#[tokio::test]
+async fn garbage_collect_skips_recently_stored() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      min_entry_age_before_eviction: Some(Duration::from_millis(500)),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let testdata = TestData::roland();
   store
     .store_bytes(
-      EntryType::Directory,
-      testdir.fingerprint(),
-      testdir.bytes(),
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
       false,
     )
     .await
     .expect("Error storing");
-  let forty_chars = TestData::forty_chars();
-  store
-    .store_bytes(
-      EntryType::File,
-      forty_chars.fingerprint(),
+
+  // Although unleased, the file was stored too recently to be evicted.
+  assert_eq!(
+    store.shrink(0, ShrinkBehavior::Fast).await,
+    Ok(testdata.len())
+  );
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+
+  sleep(Duration::from_millis(600)).await;
+  assert_eq!(store.shrink(0, ShrinkBehavior::Fast).await, Ok(0));
+  assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+}
+
+///
+/// Stores catnip linked to its "variant" roland, and returns the store.
+///
+async fn store_linked_variants(dir: &Path, lease_variant: bool) -> ByteStore {
+  let store = new_store(dir);
+  let mut src = NamedTempFile::new().unwrap();
+  src.write_all(&TestData::catnip().bytes()).unwrap();
+  assert_eq!(
+    store
+      .store_with_variant(
+        EntryType::File,
+        false,
+        true,
+        src.path().to_owned(),
+        TestData::roland().digest()
+      )
+      .await,
+    Ok(TestData::catnip().digest())
+  );
+  // The variant is not available until it has been stored.
+  assert_eq!(
+    store
+      .load_variant(EntryType::File, TestData::catnip().digest())
+      .await,
+    Ok(None)
+  );
+  store
+    .store_bytes(
+      EntryType::File,
+      TestData::roland().fingerprint(),
+      TestData::roland().bytes(),
+      lease_variant,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .load_variant(EntryType::File, TestData::catnip().digest())
+      .await,
+    Ok(Some(TestData::roland().digest()))
+  );
+  store
+}
+
+#[tokio::test]
+async fn garbage_collect_evicts_variants_together() {
+  let compressed = TestData::catnip();
+  let decompressed = TestData::roland();
+
+  // Evicting either entry evicts both.
+  let dir = TempDir::new().unwrap();
+  let store = store_linked_variants(dir.path(), false).await;
+  let target = compressed.len() + decompressed.len() - 1;
+  assert_eq!(store.shrink(target, ShrinkBehavior::Fast).await, Ok(0));
+  assert_eq!(load_file_bytes(&store, compressed.digest()).await, Ok(None));
+  assert_eq!(
+    load_file_bytes(&store, decompressed.digest()).await,
+    Ok(None)
+  );
+
+  // If either entry is leased, neither is evicted.
+  let dir = TempDir::new().unwrap();
+  let store = store_linked_variants(dir.path(), true).await;
+  assert_eq!(
+    store.shrink(0, ShrinkBehavior::Fast).await,
+    Ok(compressed.len() + decompressed.len())
+  );
+  assert_eq!(
+    load_file_bytes(&store, compressed.digest()).await,
+    Ok(Some(compressed.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn variants_database_is_opened_once_used() {
+  // A store which has never linked variants does not create their database.
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(store.shrink(0, ShrinkBehavior::Fast).await, Ok(0));
+  assert!(!dir.path().join("variants").exists());
+  drop(store);
+
+  // Links which were stored by an earlier store are used when it is reopened.
+  let store = store_linked_variants(dir.path(), false).await;
+  drop(store);
+  let store = new_store(dir.path());
+  assert_eq!(
+    store
+      .load_variant(EntryType::File, TestData::catnip().digest())
+      .await,
+    Ok(Some(TestData::roland().digest()))
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_remove_one_of_two_directories_no_leases() {
+  let dir = TempDir::new().unwrap();
+
+  let testdir = TestDirectory::containing_roland();
+  let other_testdir = TestDirectory::containing_dnalor();
+
+  let store = new_store(dir.path());
+  store
+    .store_bytes(
+      EntryType::Directory,
+      testdir.fingerprint(),
+      testdir.bytes(),
+      false,
+    )
+    .await
+    .expect("Error storing");
+  store
+    .store_bytes(
+      EntryType::Directory,
+      other_testdir.fingerprint(),
+      other_testdir.bytes(),
+      false,
+    )
+    .await
+    .expect("Error storing");
+  store
+    .shrink(84, ShrinkBehavior::Fast)
+    .await
+    .expect("Error shrinking");
+  let mut entries = Vec::new();
+  entries.push(
+    load_bytes(&store, EntryType::Directory, testdir.digest())
+      .await
+      .expect("Error loading bytes"),
+  );
+  entries.push(
+    load_bytes(&store, EntryType::Directory, other_testdir.digest())
+      .await
+      .expect("Error loading bytes"),
+  );
+  assert_eq!(
+    1,
+    entries.iter().filter(|maybe| maybe.is_some()).count(),
+    "Want one Some but got: {entries:?}"
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_remove_file_with_leased_directory() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let testdir = TestDirectory::containing_roland();
+  let testdata = TestData::forty_chars();
+
+  store
+    .store_bytes(
+      EntryType::Directory,
+      testdir.fingerprint(),
+      testdir.bytes(),
+      true,
+    )
+    .await
+    .expect("Error storing");
+
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .expect("Error storing");
+
+  store
+    .shrink(80, ShrinkBehavior::Fast)
+    .await
+    .expect("Error shrinking");
+
+  assert_eq!(
+    load_bytes(&store, EntryType::File, testdata.digest()).await,
+    Ok(None),
+    "File was present when it should've been garbage collected"
+  );
+  assert_eq!(
+    load_bytes(&store, EntryType::Directory, testdir.digest()).await,
+    Ok(Some(testdir.bytes())),
+    "Directory was missing despite lease"
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_remove_file_while_leased_file() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let testdir = TestDirectory::containing_roland();
+
+  store
+    .store_bytes(
+      EntryType::Directory,
+      testdir.fingerprint(),
+      testdir.bytes(),
+      false,
+    )
+    .await
+    .expect("Error storing");
+  let forty_chars = TestData::forty_chars();
+  store
+    .store_bytes(
+      EntryType::File,
+      forty_chars.fingerprint(),
       forty_chars.bytes(),
       true,
     )
@@ -548,6 +1636,143 @@ async fn garbage_collect_and_compact() {
   );
 }
 
+#[tokio::test]
+async fn remove_batch() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let directory = TestDirectory::containing_roland();
+  prime_store_with_file_bytes(&store, small_testdata.bytes()).await;
+  prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
+  store
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+
+  let missing = TestData::catnip().digest();
+  let removed = store
+    .remove_batch(vec![
+      (EntryType::File, small_testdata.digest()),
+      (EntryType::File, large_testdata.digest()),
+      (EntryType::Directory, directory.digest()),
+      (EntryType::File, missing),
+    ])
+    .await
+    .unwrap();
+  assert_eq!(
+    removed,
+    HashSet::from([
+      small_testdata.digest(),
+      large_testdata.digest(),
+      directory.digest()
+    ])
+  );
+  for testdata in [&small_testdata, &large_testdata] {
+    assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+  }
+  assert_eq!(
+    load_bytes(&store, EntryType::Directory, directory.digest()).await,
+    Ok(None)
+  );
+}
+
+#[tokio::test]
+async fn remove_unless_leased_leased() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  for testdata in [&small_testdata, &large_testdata] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        true,
+      )
+      .await
+      .expect("Error storing");
+    assert_eq!(
+      store
+        .remove_unless_leased(EntryType::File, testdata.digest())
+        .await,
+      Ok(RemoveOutcome::WasLeased)
+    );
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+}
+
+#[tokio::test]
+async fn remove_unless_leased_unleased() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store_with_lease_time(dir.path(), Duration::from_secs(1));
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  for testdata in [&small_testdata, &large_testdata] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .expect("Error storing");
+  }
+  // Files in the FSDB are leased by their mtime: wait for it to fall outside of the lease window.
+  sleep(Duration::from_secs(2)).await;
+
+  for testdata in [&small_testdata, &large_testdata] {
+    assert_eq!(
+      store
+        .remove_unless_leased(EntryType::File, testdata.digest())
+        .await,
+      Ok(RemoveOutcome::Removed)
+    );
+    assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+    assert_eq!(
+      store
+        .remove_unless_leased(EntryType::File, testdata.digest())
+        .await,
+      Ok(RemoveOutcome::NotFound)
+    );
+  }
+}
+
+#[tokio::test]
+async fn try_claim_one_of_many() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let digest = TestData::roland().digest();
+  let claim_duration = Duration::from_secs(1);
+
+  let claims =
+    future::try_join_all((0..16).map(|_| store.try_claim(EntryType::File, digest, claim_duration)))
+      .await
+      .unwrap();
+  assert_eq!(1, claims.into_iter().filter(|claimed| *claimed).count());
+
+  // Once the claim has expired, it may be claimed again.
+  sleep(claim_duration * 2).await;
+  assert_eq!(
+    Ok(true),
+    store
+      .try_claim(EntryType::File, digest, claim_duration)
+      .await
+  );
+}
+
 #[tokio::test]
 async fn entry_type_for_file() {
   let testdata = TestData::roland();
@@ -606,89 +1831,2069 @@ async fn entry_type_for_missing() {
       false,
     )
     .await
-    .expect("Error storing");
-  prime_store_with_file_bytes(&store, testdata.bytes()).await;
-  assert_eq!(
-    store
-      .entry_type(TestDirectory::recursive().fingerprint())
-      .await,
-    Ok(None)
-  )
+    .expect("Error storing");
+  prime_store_with_file_bytes(&store, testdata.bytes()).await;
+  assert_eq!(
+    store
+      .entry_type(TestDirectory::recursive().fingerprint())
+      .await,
+    Ok(None)
+  )
+}
+
+#[tokio::test]
+async fn empty_file_is_known() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let empty_file = TestData::empty();
+  assert_eq!(
+    store
+      .load_bytes_with(EntryType::File, empty_file.digest(), |b| {
+        Bytes::copy_from_slice(b)
+      })
+      .await,
+    Ok(Some(empty_file.bytes())),
+  )
+}
+
+#[tokio::test]
+async fn empty_directory_is_known() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let empty_dir = TestDirectory::empty();
+  assert_eq!(
+    store
+      .load_bytes_with(EntryType::Directory, empty_dir.digest(), |b| {
+        Bytes::copy_from_slice(b)
+      })
+      .await,
+    Ok(Some(empty_dir.bytes())),
+  )
+}
+
+#[tokio::test]
+async fn all_digests() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let digest1 = prime_store_with_file_bytes(&store, TestData::roland().bytes()).await;
+  assert_eq!(Ok(vec![digest1]), store.all_digests(EntryType::File).await);
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let digest2 = prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
+  assert_eq!(
+    vec![digest1, digest2].into_iter().collect::<HashSet<_>>(),
+    store
+      .all_digests(EntryType::File)
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+  );
+}
+
+#[tokio::test]
+async fn all_digests_across_many_shards() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  // More shards than are scanned concurrently.
+  let mut expected = HashSet::new();
+  for i in 0..40 {
+    let bytes = Bytes::from(format!("{i:09}").repeat(1000 * 64));
+    expected.insert(prime_store_with_file_bytes(&store, bytes).await);
+  }
+  assert_eq!(
+    expected,
+    store
+      .all_digests(EntryType::File)
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+  );
+}
+
+#[tokio::test]
+async fn entries_modified_since() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let testdir = TestDirectory::containing_roland();
+
+  let before = SystemTime::now() - Duration::from_secs(60);
+  store
+    .store_bytes(
+      EntryType::File,
+      small_testdata.fingerprint(),
+      small_testdata.bytes(),
+      true,
+    )
+    .await
+    .unwrap();
+  store
+    .store_bytes(
+      EntryType::File,
+      large_testdata.fingerprint(),
+      large_testdata.bytes(),
+      true,
+    )
+    .await
+    .unwrap();
+  store
+    .store_bytes(
+      EntryType::Directory,
+      testdir.fingerprint(),
+      testdir.bytes(),
+      true,
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(
+    HashSet::from([small_testdata.digest(), large_testdata.digest()]),
+    store
+      .entries_modified_since(EntryType::File, before)
+      .await
+      .unwrap()
+      .into_iter()
+      .collect::<HashSet<_>>(),
+  );
+  assert_eq!(
+    Ok(vec![testdir.digest()]),
+    store
+      .entries_modified_since(EntryType::Directory, before)
+      .await
+  );
+
+  let after = SystemTime::now() + Duration::from_secs(60);
+  assert_eq!(
+    Ok(vec![]),
+    store.entries_modified_since(EntryType::File, after).await
+  );
+}
+
+#[tokio::test]
+async fn stream_chunks() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  prime_store_with_file_bytes(&store, small_testdata.bytes()).await;
+  prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
+
+  for (testdata, chunk_size) in [(&small_testdata, 3), (&large_testdata, 64 * 1024)] {
+    let chunks = store
+      .stream_chunks(EntryType::File, testdata.digest(), chunk_size)
+      .try_collect::<Vec<_>>()
+      .await
+      .unwrap();
+    assert!(chunks.iter().all(|chunk| chunk.len() <= chunk_size));
+    assert_eq!(chunks.len(), (testdata.len() + chunk_size - 1) / chunk_size);
+    assert_eq!(chunks.concat(), testdata.bytes());
+  }
+}
+
+#[tokio::test]
+async fn stream_chunks_missing() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let result = store
+    .stream_chunks(EntryType::File, TestData::roland().digest(), 1024)
+    .try_collect::<Vec<_>>()
+    .await;
+  assert!(result.is_err(), "Expected an error, got: {result:?}");
+}
+
+#[tokio::test]
+async fn load_stream() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  prime_store_with_file_bytes(&store, small_testdata.bytes()).await;
+  prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
+
+  for testdata in [&small_testdata, &large_testdata] {
+    let mut reader = store
+      .load_stream(EntryType::File, testdata.digest())
+      .await
+      .unwrap()
+      .unwrap();
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, testdata.bytes());
+  }
+
+  assert!(store
+    .load_stream(EntryType::File, TestData::catnip().digest())
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn read_warm_target() {
+  let slow_dir = TempDir::new().unwrap();
+  let fast_dir = TempDir::new().unwrap();
+  let slow_store = new_store(slow_dir.path());
+  let fast_store = new_store(fast_dir.path());
+  let warming_store = slow_store.with_read_warm_target(fast_store.clone());
+
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [&small_testdata, &large_testdata] {
+    prime_store_with_file_bytes(&slow_store, testdata.bytes()).await;
+    assert_eq!(
+      load_file_bytes(&fast_store, testdata.digest()).await,
+      Ok(None)
+    );
+
+    assert_eq!(
+      load_file_bytes(&warming_store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+
+    // Warming happens in the background.
+    let mut attempts = 0;
+    while load_file_bytes(&fast_store, testdata.digest()).await != Ok(Some(testdata.bytes())) {
+      attempts += 1;
+      assert!(attempts < 100, "{:?} was never warmed.", testdata.digest());
+      sleep(Duration::from_millis(50)).await;
+    }
+  }
+}
+
+#[tokio::test]
+async fn base_layers() {
+  let base_dir = TempDir::new().unwrap();
+  let top_dir = TempDir::new().unwrap();
+  let base_store = new_store(base_dir.path());
+  let top_store = new_store(top_dir.path());
+  let overlay_store = top_store.with_base_layers(vec![base_store.clone()]);
+
+  let small = TestData::roland();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [&small, &large] {
+    prime_store_with_file_bytes(&base_store, testdata.bytes()).await;
+  }
+
+  // Reads fall through to the base layer.
+  for testdata in [&small, &large] {
+    assert_eq!(
+      load_file_bytes(&overlay_store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(
+      load_file_bytes(&top_store, testdata.digest()).await,
+      Ok(None)
+    );
+  }
+  assert_eq!(
+    overlay_store.entry_type(small.fingerprint()).await,
+    Ok(Some(EntryType::File))
+  );
+  assert!(overlay_store
+    .load_from_fs(large.digest())
+    .await
+    .unwrap()
+    .unwrap()
+    .starts_with(base_dir.path()));
+
+  // Only digests which are missing from every layer are missing.
+  let catnip = TestData::catnip();
+  assert_eq!(
+    overlay_store
+      .get_missing_digests(
+        EntryType::File,
+        HashSet::from([small.digest(), large.digest(), catnip.digest()])
+      )
+      .await,
+    Ok(HashSet::from([catnip.digest()]))
+  );
+
+  // Writes go to the top layer, and entries in the base layer need not be leased.
+  prime_store_with_file_bytes(&overlay_store, catnip.bytes()).await;
+  assert_eq!(
+    load_file_bytes(&top_store, catnip.digest()).await,
+    Ok(Some(catnip.bytes()))
+  );
+  assert_eq!(
+    load_file_bytes(&base_store, catnip.digest()).await,
+    Ok(None)
+  );
+  overlay_store
+    .lease_all(
+      [&small, &large, &catnip]
+        .into_iter()
+        .map(|testdata| (testdata.digest(), EntryType::File)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_awaits_background_tasks() {
+  let slow_dir = TempDir::new().unwrap();
+  let fast_dir = TempDir::new().unwrap();
+  let slow_store = new_store(slow_dir.path());
+  let fast_store = new_store(fast_dir.path());
+
+  let warmed = TestData::roland();
+  let unwarmed = TestData::catnip();
+  prime_store_with_file_bytes(&slow_store, warmed.bytes()).await;
+  prime_store_with_file_bytes(&slow_store, unwarmed.bytes()).await;
+
+  let warming_store = slow_store.with_read_warm_target(fast_store.clone());
+  assert_eq!(
+    load_file_bytes(&warming_store, warmed.digest()).await,
+    Ok(Some(warmed.bytes()))
+  );
+  // Shutting down waits for the warming task, so the blob is immediately present.
+  warming_store.clone().shutdown().await;
+  assert_eq!(
+    load_file_bytes(&fast_store, warmed.digest()).await,
+    Ok(Some(warmed.bytes()))
+  );
+
+  // And no new background tasks are spawned after shutdown.
+  assert_eq!(
+    load_file_bytes(&warming_store, unwarmed.digest()).await,
+    Ok(Some(unwarmed.bytes()))
+  );
+  sleep(Duration::from_millis(100)).await;
+  assert_eq!(
+    load_file_bytes(&fast_store, unwarmed.digest()).await,
+    Ok(None)
+  );
+}
+
+#[tokio::test]
+async fn get_missing_digests() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  prime_store_with_file_bytes(&store, small_testdata.bytes()).await;
+  prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
+  let missing = store
+    .get_missing_digests(
+      EntryType::File,
+      HashSet::from([
+        small_testdata.digest(),
+        large_testdata.digest(),
+        hashing::Digest::of_bytes("1".as_bytes()),
+      ]),
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    missing,
+    HashSet::from([hashing::Digest::of_bytes("1".as_bytes())])
+  )
+}
+
+#[tokio::test]
+async fn store_from_reader() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small_testdata = TestData::roland();
+  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+
+  for (testdata, in_fsdb) in [(&small_testdata, false), (&large_testdata, true)] {
+    assert_eq!(
+      store
+        .store_from_reader(EntryType::File, false, &testdata.bytes()[..])
+        .await,
+      Ok(testdata.digest())
+    );
+    assert_eq!(
+      store
+        .get_file_fsdb()
+        .get_path(testdata.fingerprint())
+        .exists(),
+      in_fsdb
+    );
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+}
+
+#[tokio::test]
+async fn store_verified() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+
+  let mut file = NamedTempFile::new().unwrap();
+  file.write_all(&testdata.bytes()).unwrap();
+  file.flush().unwrap();
+
+  assert_eq!(
+    store
+      .store_verified(EntryType::File, false, true, file.path().to_owned())
+      .await,
+    Ok(testdata.digest())
+  );
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn store_verified_detects_corrupt_write() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let testdata = TestData::roland();
+
+  // Pre-populate the store with bytes of the correct length but the wrong content under the
+  // fingerprint that is about to be stored, so that the store "succeeds" without writing.
+  let corrupt_bytes = Bytes::from("X".repeat(testdata.len()));
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      corrupt_bytes,
+      false,
+    )
+    .await
+    .expect("Error storing");
+
+  let mut file = NamedTempFile::new().unwrap();
+  file.write_all(&testdata.bytes()).unwrap();
+  file.flush().unwrap();
+
+  let result = store
+    .store_verified(EntryType::File, false, true, file.path().to_owned())
+    .await;
+  assert!(
+    matches!(&result, Err(e) if e.contains("Failed to verify")),
+    "Expected a verification failure, got: {result:?}"
+  );
+}
+
+/// Simulates a store which was written with a large file size limit of 1 byte, by placing a small
+/// file in the FSDB and recording that limit.
+async fn prime_store_with_lowered_threshold(dir: &Path, testdata: &TestData) {
+  let store = new_store(dir);
+  let file_fsdb = store.get_file_fsdb();
+  let tempfile = file_fsdb
+    .get_tempfile(testdata.fingerprint())
+    .await
+    .unwrap();
+  tempfile
+    .open()
+    .await
+    .unwrap()
+    .write_all(&testdata.bytes())
+    .await
+    .unwrap();
+  tempfile.persist().await.unwrap();
+  std::fs::write(dir.join("large_file_size_limit"), "1").unwrap();
+}
+
+fn new_store_with_threshold_change<P: AsRef<Path>>(
+  dir: P,
+  on_threshold_change: ThresholdChangeBehavior,
+) -> Result<ByteStore, String> {
+  ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir,
+    LocalOptions {
+      on_threshold_change,
+      ..LocalOptions::default()
+    },
+  )
+}
+
+#[tokio::test]
+async fn threshold_change_error() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  prime_store_with_lowered_threshold(dir.path(), &testdata).await;
+
+  let err = new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Error)
+    .expect_err("Opening the store should have failed.");
+  assert!(err.contains("large file size limit of 1 bytes"), "{err}");
+}
+
+#[tokio::test]
+async fn threshold_change_fallback() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  prime_store_with_lowered_threshold(dir.path(), &testdata).await;
+
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Fallback).unwrap();
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(
+    store
+      .get_missing_digests(EntryType::File, HashSet::from([testdata.digest()]))
+      .await,
+    Ok(HashSet::new())
+  );
+  // The file is left where it is, and the recorded limit is unchanged.
+  assert!(store
+    .get_file_fsdb()
+    .get_path(testdata.fingerprint())
+    .exists());
+  assert_eq!(
+    std::fs::read_to_string(dir.path().join("large_file_size_limit")).unwrap(),
+    "1"
+  );
+
+  assert_eq!(
+    store.remove(EntryType::File, testdata.digest()).await,
+    Ok(true)
+  );
+  assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+}
+
+#[tokio::test]
+async fn threshold_change_fallback_records_limit_once_placed() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  prime_store_with_lowered_threshold(dir.path(), &testdata).await;
+
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Fallback).unwrap();
+  assert!(store.falls_back_on_lookups());
+  // While a file is misplaced, garbage collection does not record the new limit.
+  store
+    .shrink(usize::MAX, ShrinkBehavior::Fast)
+    .await
+    .unwrap();
+  assert!(store.falls_back_on_lookups());
+
+  // But once it is gone, it does.
+  assert_eq!(
+    store.remove(EntryType::File, testdata.digest()).await,
+    Ok(true)
+  );
+  store
+    .shrink(usize::MAX, ShrinkBehavior::Fast)
+    .await
+    .unwrap();
+  assert!(!store.falls_back_on_lookups());
+  assert_eq!(
+    std::fs::read_to_string(dir.path().join("large_file_size_limit")).unwrap(),
+    "524288"
+  );
+  store.shutdown().await;
+
+  // And a reopened store no longer falls back.
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Fallback).unwrap();
+  assert!(!store.falls_back_on_lookups());
+}
+
+#[tokio::test]
+async fn shrink_compact_and_rebalance() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  prime_store_with_lowered_threshold(dir.path(), &testdata).await;
+
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Fallback).unwrap();
+  store.precreate_shards().await.unwrap();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  store
+    .store_bytes(EntryType::File, large.fingerprint(), large.bytes(), true)
+    .await
+    .unwrap();
+  store
+    .shrink(usize::MAX, ShrinkBehavior::CompactAndRebalance)
+    .await
+    .unwrap();
+
+  // The small file was moved into LMDB, and the new limit was recorded.
+  assert!(!store
+    .get_file_fsdb()
+    .get_path(testdata.fingerprint())
+    .exists());
+  assert_eq!(
+    std::fs::read_to_string(dir.path().join("large_file_size_limit")).unwrap(),
+    "524288"
+  );
+  // Only the shard directory holding the large file remains.
+  let shards = std::fs::read_dir(dir.path().join("immutable").join("files"))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    shards,
+    vec![store
+      .get_file_fsdb()
+      .get_path(large.fingerprint())
+      .parent()
+      .unwrap()
+      .to_owned()]
+  );
+  for testdata in [testdata, large] {
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+
+  // Although the shards were precreated, files can still be stored in the removed shards.
+  let other_large = TestData::new("987654321".repeat(1000 * 512).as_str());
+  store
+    .store_bytes(
+      EntryType::File,
+      other_large.fingerprint(),
+      other_large.bytes(),
+      true,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    load_file_bytes(&store, other_large.digest()).await,
+    Ok(Some(other_large.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn configured_large_file_size_limit() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  let new_store_with_limit = |large_file_size_limit: usize| {
+    ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        large_file_size_limit,
+        on_threshold_change: ThresholdChangeBehavior::Rebalance,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap()
+  };
+  let marker_path = dir.path().join("large_file_size_limit");
+
+  // A lowered limit routes even small files to disk, and is recorded for a new store.
+  let store = new_store_with_limit(1);
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert!(store
+    .get_file_fsdb()
+    .get_path(testdata.fingerprint())
+    .exists());
+  assert_eq!(std::fs::read_to_string(&marker_path).unwrap(), "1");
+  store.shutdown().await;
+
+  // Reopening the store with a different limit migrates the file into the other backend.
+  let store = new_store_with_limit(1024);
+  let mut attempts = 0;
+  while std::fs::read_to_string(&marker_path).unwrap() != "1024" {
+    attempts += 1;
+    assert!(attempts < 100, "Rebalance did not complete.");
+    sleep(Duration::from_millis(50)).await;
+  }
+  assert!(!store
+    .get_file_fsdb()
+    .get_path(testdata.fingerprint())
+    .exists());
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn exists_batch_listing_and_metadata_agree() {
+  let dir = TempDir::new().unwrap();
+  let stored = (0..4)
+    .map(|i| TestData::new(&format!("{i}").repeat(1000 * 1024)))
+    .collect::<Vec<_>>();
+  let missing = TestData::new("9".repeat(1000 * 1024).as_str());
+  {
+    let store = new_store(dir.path());
+    for testdata in &stored {
+      store
+        .store_bytes(
+          EntryType::File,
+          testdata.fingerprint(),
+          testdata.bytes(),
+          false,
+        )
+        .await
+        .unwrap();
+    }
+  }
+
+  let digests = stored
+    .iter()
+    .chain([&missing])
+    .map(|testdata| testdata.digest())
+    .collect::<HashSet<_>>();
+  for fsdb_exists_listing_density in [0, usize::MAX] {
+    let store = ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        fsdb_exists_listing_density,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(
+      store
+        .get_missing_digests(EntryType::File, digests.clone())
+        .await,
+      Ok(HashSet::from([missing.digest()])),
+    );
+  }
+}
+
+#[tokio::test]
+async fn precreate_shards() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  store.precreate_shards().await.unwrap();
+
+  let shards_root = dir.path().join("immutable").join("files");
+  let shards = std::fs::read_dir(&shards_root)
+    .unwrap()
+    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+    .collect::<HashSet<_>>();
+  assert_eq!(shards.len(), 256);
+  assert!(shards.contains("00") && shards.contains("ff"));
+
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+fn new_store_with_shard_layout<P: AsRef<Path>>(dir: P, layout: FsdbShardLayout) -> ByteStore {
+  ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir,
+    LocalOptions {
+      fsdb_shard_layout: layout,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap()
+}
+
+#[tokio::test]
+async fn shard_layout_migration() {
+  let dir = TempDir::new().unwrap();
+  let shards_root = dir.path().join("immutable").join("files");
+  let large = [
+    TestData::new("123456789".repeat(1000 * 512).as_str()),
+    TestData::new("987654321".repeat(1000 * 512).as_str()),
+  ];
+  let store = new_store(dir.path());
+  for testdata in &large {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  store.shutdown().await;
+
+  // A non-entry in the store is left where it is, rather than failing the migration.
+  let stray_path = shards_root.join("stray");
+  std::fs::write(&stray_path, "not an entry").unwrap();
+
+  // Reopening the store with a deeper layout moves its files in the background (while they remain
+  // loadable), and removes the old directories.
+  let layout = FsdbShardLayout::new(2, 2).unwrap();
+  let store = new_store_with_shard_layout(dir.path(), layout);
+  for testdata in &large {
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+  wait_for_shard_layout(dir.path(), "2x2").await;
+  assert!(stray_path.exists());
+  std::fs::remove_file(&stray_path).unwrap();
+  for testdata in &large {
+    let hex = testdata.fingerprint().to_hex();
+    let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+    assert_eq!(
+      path,
+      shards_root.join(&hex[0..2]).join(&hex[2..4]).join(&hex)
+    );
+    assert!(path.exists());
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+    let shard = ShardId::Fsdb(testdata.fingerprint().as_bytes()[0]);
+    assert_eq!(
+      store.digests_in_shard(EntryType::File, shard).await,
+      Ok(vec![testdata.digest()])
+    );
+  }
+  let files = WalkDir::new(&shards_root)
+    .min_depth(1)
+    .into_iter()
+    .map(|entry| entry.unwrap())
+    .collect::<Vec<_>>();
+  assert!(files
+    .iter()
+    .all(|entry| entry.depth() > 1 || entry.file_type().is_dir()));
+  assert_eq!(files.iter().filter(|entry| entry.depth() == 3).count(), 2);
+  store.shutdown().await;
+
+  // And moving back to a shallower layout works too.
+  let store = new_store_with_shard_layout(dir.path(), FsdbShardLayout::new(1, 1).unwrap());
+  wait_for_shard_layout(dir.path(), "1x1").await;
+  for testdata in &large {
+    let hex = testdata.fingerprint().to_hex();
+    assert_eq!(
+      store.get_file_fsdb().get_path(testdata.fingerprint()),
+      shards_root.join(&hex[0..1]).join(&hex)
+    );
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+  assert_eq!(
+    WalkDir::new(&shards_root)
+      .max_depth(3)
+      .into_iter()
+      .filter(|entry| entry.as_ref().unwrap().depth() == 3)
+      .count(),
+    0
+  );
+}
+
+async fn wait_for_shard_layout(dir: &Path, expected: &str) {
+  let marker_path = dir.join("fsdb_shard_layout");
+  for _ in 0..100 {
+    if std::fs::read_to_string(&marker_path).unwrap() == expected {
+      return;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  panic!("The shard layout was not migrated to {expected}.");
+}
+
+#[tokio::test]
+async fn shard_layout_not_migrated_while_in_use() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let store = new_store(dir.path());
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+
+  // While the store is open, another store with a different layout uses the recorded layout.
+  let other = new_store_with_shard_layout(dir.path(), FsdbShardLayout::new(1, 2).unwrap());
+  assert_eq!(
+    other.get_file_fsdb().get_path(testdata.fingerprint()),
+    store.get_file_fsdb().get_path(testdata.fingerprint())
+  );
+  assert_eq!(
+    load_file_bytes(&other, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(
+    std::fs::read_to_string(dir.path().join("fsdb_shard_layout")).unwrap(),
+    "2x1"
+  );
+  other.shutdown().await;
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn full_shard_policy_subshard() {
+  let dir = TempDir::new().unwrap();
+  let new_store_with_policy = |fsdb_full_shard_policy| {
+    ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        fsdb_full_shard_policy,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap()
+  };
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let store = new_store_with_policy(FullShardPolicy::Subshard);
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  let path = store.get_file_fsdb().get_path(testdata.fingerprint());
+
+  // Simulate the file having been stored while its shard directory was full, in which case it is
+  // held where a layout one level deeper would hold it.
+  let hex = testdata.fingerprint().to_hex();
+  let subshard_path = dir
+    .path()
+    .join("immutable")
+    .join("files")
+    .join(&hex[0..2])
+    .join(&hex[2..4])
+    .join(&hex);
+  std::fs::create_dir_all(subshard_path.parent().unwrap()).unwrap();
+  std::fs::rename(&path, &subshard_path).unwrap();
+
+  assert_eq!(
+    store.get_file_fsdb().get_path(testdata.fingerprint()),
+    subshard_path
+  );
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(
+    store
+      .get_missing_digests(EntryType::File, HashSet::from([testdata.digest()]))
+      .await,
+    Ok(HashSet::new())
+  );
+  assert_eq!(
+    store.all_digests(EntryType::File).await,
+    Ok(vec![testdata.digest()])
+  );
+  store.shutdown().await;
+
+  // Without the policy, the file is not found in the sub-shard.
+  let store = new_store_with_policy(FullShardPolicy::Fail);
+  assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+}
+
+#[test]
+fn shard_layout_parsing() {
+  assert_eq!("2x2".parse::<FsdbShardLayout>(), FsdbShardLayout::new(2, 2));
+  assert!("0x2".parse::<FsdbShardLayout>().is_err());
+  assert!("3x2".parse::<FsdbShardLayout>().is_err());
+  assert!("2".parse::<FsdbShardLayout>().is_err());
+}
+
+fn new_store_maintaining_content_summary<P: AsRef<Path>>(dir: P) -> ByteStore {
+  ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir,
+    LocalOptions {
+      maintain_content_summary: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap()
+}
+
+#[tokio::test]
+async fn content_summary_fast() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store_maintaining_content_summary(dir.path());
+  assert_eq!(store.content_summary_fast(), Some(ContentSummary::empty()));
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let files = [TestData::roland(), TestData::catnip(), large.clone()];
+  let directory = TestDirectory::containing_roland();
+  for testdata in &files {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  store
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  let mut expected = ContentSummary::empty();
+  for testdata in &files {
+    expected.toggle(EntryType::File, testdata.fingerprint());
+  }
+  expected.toggle(EntryType::Directory, directory.fingerprint());
+  assert_eq!(store.content_summary_fast(), Some(expected));
+  assert_eq!(store.content_summary().await, Ok(expected));
+
+  // Storing entries which are already present does not change the summary.
+  for testdata in &files {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  assert_eq!(store.content_summary_fast(), Some(expected));
+
+  // Nor does removing entries which are absent.
+  for testdata in [TestData::catnip(), large.clone()] {
+    assert_eq!(
+      store.remove(EntryType::File, testdata.digest()).await,
+      Ok(true)
+    );
+    assert_eq!(
+      store.remove(EntryType::File, testdata.digest()).await,
+      Ok(false)
+    );
+    expected.toggle(EntryType::File, testdata.fingerprint());
+  }
+  assert_eq!(store.content_summary_fast(), Some(expected));
+  assert_eq!(store.content_summary().await, Ok(expected));
+
+  // The summary is persisted on shutdown, and consumed when the store is reopened.
+  store.shutdown().await;
+  let store = new_store_maintaining_content_summary(dir.path());
+  assert_eq!(store.content_summary_fast(), Some(expected));
+  assert!(!dir.path().join("content_summary").exists());
+
+  // Without a persisted summary, it is recomputed.
+  drop(store);
+  let store = new_store_maintaining_content_summary(dir.path());
+  assert_eq!(store.content_summary_fast(), Some(expected));
+
+  // The summary does not depend on the order in which entries were stored.
+  let other_dir = TempDir::new().unwrap();
+  let other_store = new_store_maintaining_content_summary(other_dir.path());
+  for (entry_type, fingerprint, bytes) in [
+    (
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+    ),
+    (
+      EntryType::File,
+      TestData::roland().fingerprint(),
+      TestData::roland().bytes(),
+    ),
+  ] {
+    other_store
+      .store_bytes(entry_type, fingerprint, bytes, false)
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    other_store.content_summary_fast(),
+    store.content_summary_fast()
+  );
+}
+
+#[tokio::test]
+async fn network_filesystem_detection() {
+  let new_store_on = |network_filesystem: bool, assume_local_fs: bool| {
+    let dir = TempDir::new().unwrap();
+    let store = ByteStore::new_with_filesystem_detection(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        assume_local_fs,
+        ..LocalOptions::default()
+      },
+      |_| Ok(network_filesystem),
+    )
+    .unwrap();
+    (store, dir)
+  };
+
+  assert!(!new_store_on(false, false).0.is_on_network_filesystem());
+  assert!(new_store_on(true, false).0.is_on_network_filesystem());
+  assert!(!new_store_on(true, true).0.is_on_network_filesystem());
+
+  // The leases of large files are recorded in a database on network filesystems.
+  let (_store, dir) = new_store_on(false, false);
+  assert!(!dir.path().join("fsdb_leases").exists());
+  let (_store, dir) = new_store_on(true, false);
+  assert!(dir.path().join("fsdb_leases").exists());
+
+  // A store on a network filesystem otherwise behaves normally.
+  let (store, _dir) = new_store_on(true, false);
+  let testdata = TestData::roland();
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+
+  // If detection fails, the filesystem is assumed to be local.
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_filesystem_detection(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions::default(),
+    |_| Err("statfs failed".to_owned()),
+  )
+  .unwrap();
+  assert!(!store.is_on_network_filesystem());
+}
+
+#[tokio::test]
+async fn verify_present() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [TestData::roland(), large.clone()] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    store
+      .verify_present(EntryType::File, TestData::roland().digest())
+      .await,
+    Ok(true)
+  );
+  assert_eq!(
+    store.verify_present(EntryType::File, large.digest()).await,
+    Ok(true)
+  );
+  assert_eq!(
+    store
+      .verify_present(EntryType::File, TestData::catnip().digest())
+      .await,
+    Ok(false)
+  );
+
+  // Corrupt the large file without changing its length.
+  let path = store.get_file_fsdb().get_path(large.fingerprint());
+  std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&path, "987654321".repeat(1000 * 512)).unwrap();
+  assert_eq!(
+    store.verify_present(EntryType::File, large.digest()).await,
+    Ok(false)
+  );
+}
+
+#[tokio::test]
+async fn verify_integrity() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [TestData::roland(), large.clone()] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    store.verify_integrity(CorruptEntryAction::Report).await,
+    Ok(IntegrityReport {
+      checked: 2,
+      corrupt: vec![],
+    })
+  );
+
+  // Corrupt the large file without changing its length, and then quarantine it.
+  let path = store.get_file_fsdb().get_path(large.fingerprint());
+  std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&path, "987654321".repeat(1000 * 512)).unwrap();
+  assert_eq!(
+    store.verify_integrity(CorruptEntryAction::Quarantine).await,
+    Ok(IntegrityReport {
+      checked: 2,
+      corrupt: vec![(EntryType::File, large.digest())],
+    })
+  );
+  assert!(!path.exists());
+  assert!(dir
+    .path()
+    .join("quarantine")
+    .join(large.fingerprint().to_hex())
+    .exists());
+  assert_eq!(
+    load_file_bytes(&store, TestData::roland().digest()).await,
+    Ok(Some(TestData::roland().bytes()))
+  );
+}
+
+#[tokio::test]
+async fn load_quarantines_entry_with_wrong_length() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  prime_store_with_file_bytes(&store, large.bytes()).await;
+
+  // Truncate the stored file, as an interrupted write might.
+  let path = store.get_file_fsdb().get_path(large.fingerprint());
+  std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&path, "123456789").unwrap();
+
+  // The load is treated as a miss, and the entry is moved aside so that it can be repopulated.
+  assert_eq!(load_file_bytes(&store, large.digest()).await, Ok(None));
+  assert!(!path.exists());
+  assert!(dir
+    .path()
+    .join("quarantine")
+    .join(large.fingerprint().to_hex())
+    .exists());
+  prime_store_with_file_bytes(&store, large.bytes()).await;
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_quarantined_entries() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store_with_lease_time(dir.path(), Duration::from_millis(500));
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let quarantined = dir
+    .path()
+    .join("quarantine")
+    .join(large.fingerprint().to_hex());
+  // Truncates the stored file, so that loading it quarantines it.
+  async fn quarantine(store: &ByteStore, large: &TestData, quarantined: &Path) {
+    prime_store_with_file_bytes(store, large.bytes()).await;
+    let path = store.get_file_fsdb().get_path(large.fingerprint());
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(&path, "123456789").unwrap();
+    assert_eq!(load_file_bytes(store, large.digest()).await, Ok(None));
+    assert!(quarantined.exists());
+  }
+
+  // Quarantined entries count toward the size of the store, and are evicted to meet the target.
+  quarantine(&store, &large, &quarantined).await;
+  assert_eq!(store.shrink(usize::MAX, ShrinkBehavior::Fast).await, Ok(9));
+  assert!(quarantined.exists());
+  assert_eq!(store.shrink(0, ShrinkBehavior::Fast).await, Ok(0));
+  assert!(!quarantined.exists());
+
+  // And are removed regardless of the target once they have been kept for the lease time.
+  quarantine(&store, &large, &quarantined).await;
+  sleep(Duration::from_millis(600)).await;
+  assert_eq!(store.shrink(usize::MAX, ShrinkBehavior::Fast).await, Ok(0));
+  assert!(!quarantined.exists());
+}
+
+#[tokio::test]
+async fn verify_reads_quarantines_corrupt_entry() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let counter = |name: &str| workunit_store.get_metrics().get(name).copied().unwrap_or(0);
+
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      verify_reads: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  prime_store_with_file_bytes(&store, large.bytes()).await;
+  assert_eq!(
+    load_file_bytes(&store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+
+  // Corrupt the stored file without changing its length, as bit rot might.
+  let path = store.get_file_fsdb().get_path(large.fingerprint());
+  std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&path, "987654321".repeat(1000 * 512)).unwrap();
+
+  // The load is treated as a miss, and the entry is moved aside so that it can be repopulated.
+  assert_eq!(load_file_bytes(&store, large.digest()).await, Ok(None));
+  assert_eq!(counter("local_store_read_verification_failures"), 1);
+  assert!(!path.exists());
+  assert!(dir
+    .path()
+    .join("quarantine")
+    .join(large.fingerprint().to_hex())
+    .exists());
+}
+
+#[tokio::test]
+async fn write_metrics() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let counter = |name: &str| workunit_store.get_metrics().get(name).copied().unwrap_or(0);
+
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [TestData::roland(), TestData::catnip(), large.clone()] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  assert_eq!(counter("local_store_lmdb_writes"), 2);
+  assert_eq!(counter("local_store_fsdb_writes"), 1);
+
+  store
+    .lease_all(
+      [TestData::roland().digest(), large.digest()]
+        .into_iter()
+        .map(|digest| (digest, EntryType::File)),
+    )
+    .await
+    .unwrap();
+  assert_eq!(counter("local_store_lease_extensions"), 2);
+}
+
+#[tokio::test]
+async fn memory_cache() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let counter = |name: &str| workunit_store.get_metrics().get(name).copied().unwrap_or(0);
+
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      memory_cache_bytes: Some(8),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let (a, b, c) = (
+    TestData::new("aaaa"),
+    TestData::new("bbbb"),
+    TestData::new("cccc"),
+  );
+  for testdata in [&a, &b, &c] {
+    prime_store_with_file_bytes(&store, testdata.bytes()).await;
+  }
+
+  // The cache holds two entries, so loading `c` evicts `b`, which was used least recently.
+  for (testdata, hits, misses) in [
+    (&a, 0, 1),
+    (&b, 0, 2),
+    (&a, 1, 2),
+    (&c, 1, 3),
+    (&a, 2, 3),
+    (&b, 2, 4),
+  ] {
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(counter("local_store_memory_cache_hits"), hits);
+    assert_eq!(counter("local_store_memory_cache_misses"), misses);
+  }
+
+  // Removed entries are no longer served from the cache.
+  assert_eq!(store.remove(EntryType::File, a.digest()).await, Ok(true));
+  assert_eq!(load_file_bytes(&store, a.digest()).await, Ok(None));
+}
+
+#[tokio::test]
+async fn read_only() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small = TestData::roland();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [&small, &large] {
+    prime_store_with_file_bytes(&store, testdata.bytes()).await;
+  }
+  store.shutdown().await;
+
+  let snapshot = || {
+    WalkDir::new(dir.path())
+      .into_iter()
+      .map(|entry| {
+        let entry = entry.unwrap();
+        let metadata = entry.metadata().unwrap();
+        (
+          entry.into_path(),
+          metadata.len(),
+          metadata.modified().unwrap(),
+        )
+      })
+      .collect::<Vec<_>>()
+  };
+  let before = snapshot();
+
+  let read_only_options = || LocalOptions {
+    read_only: true,
+    ..LocalOptions::default()
+  };
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    read_only_options(),
+  )
+  .unwrap();
+  assert!(store.is_read_only());
+
+  // Loads succeed, while leasing does nothing.
+  for testdata in [&small, &large] {
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+  store
+    .lease_all(
+      [
+        (small.digest(), EntryType::File),
+        (large.digest(), EntryType::File),
+      ]
+      .into_iter(),
+    )
+    .await
+    .unwrap();
+
+  // Writes fail.
+  let catnip = TestData::catnip();
+  assert!(store
+    .store_bytes(EntryType::File, catnip.fingerprint(), catnip.bytes(), false)
+    .await
+    .is_err());
+  assert!(store
+    .store_from_reader(EntryType::File, false, large.bytes().as_ref())
+    .await
+    .is_err());
+  assert!(store.remove(EntryType::File, small.digest()).await.is_err());
+  assert!(store.shrink(0, ShrinkBehavior::Fast).await.is_err());
+  assert!(store
+    .get_file_fsdb()
+    .get_tempfile(catnip.fingerprint())
+    .await
+    .is_err());
+
+  // And nothing in the store was modified.
+  store.shutdown().await;
+  assert_eq!(snapshot(), before);
+
+  // A store which does not exist cannot be opened read-only.
+  let empty_dir = TempDir::new().unwrap();
+  assert!(ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    empty_dir.path(),
+    read_only_options()
+  )
+  .is_err());
+}
+
+#[tokio::test]
+async fn blake3_digest_function() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      digest_function: DigestFunction::Blake3,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  assert_eq!(store.digest_function(), DigestFunction::Blake3);
+
+  let small = Bytes::from("European Burmese");
+  let large = Bytes::from("123456789".repeat(1000 * 512));
+  for bytes in [small, large] {
+    let expected_digest = Digest::of_bytes_with(DigestFunction::Blake3, &bytes);
+    assert_ne!(expected_digest, Digest::of_bytes(&bytes));
+
+    // Content is hashed with BLAKE3 when it is stored from a reader or a file...
+    assert_eq!(
+      store
+        .store_from_reader(EntryType::File, false, bytes.as_ref())
+        .await,
+      Ok(expected_digest)
+    );
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&bytes).unwrap();
+    assert_eq!(
+      store
+        .store(EntryType::File, false, false, file.path().to_owned())
+        .await,
+      Ok(expected_digest)
+    );
+    assert_eq!(
+      load_file_bytes(&store, expected_digest).await,
+      Ok(Some(bytes.clone()))
+    );
+    assert_eq!(
+      store.verify_present(EntryType::File, expected_digest).await,
+      Ok(true)
+    );
+
+    // ...and content which is stored under its SHA-256 digest does not verify.
+    let sha256_digest = Digest::of_bytes(&bytes);
+    store
+      .store_bytes(EntryType::File, sha256_digest.hash, bytes, false)
+      .await
+      .unwrap();
+    assert_eq!(
+      store.verify_present(EntryType::File, sha256_digest).await,
+      Ok(false)
+    );
+  }
+
+  // The store is namespaced by its digest function, so a SHA-256 store in the same directory does
+  // not contain its entries.
+  assert!(dir.path().join("blake3").join("files").exists());
+  assert!(!dir.path().join("files").exists());
+}
+
+#[tokio::test]
+async fn load_into_mmap() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let filesystem_copies = || {
+    workunit_store
+      .get_metrics()
+      .get("local_store_filesystem_copies")
+      .copied()
+      .unwrap_or(0)
+  };
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for network_filesystem in [false, true] {
+    let dir = TempDir::new().unwrap();
+    let store = ByteStore::new_with_filesystem_detection(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions::default(),
+      |_| Ok(network_filesystem),
+    )
+    .unwrap();
+    for testdata in [TestData::roland(), large.clone()] {
+      store
+        .store_bytes(
+          EntryType::File,
+          testdata.fingerprint(),
+          testdata.bytes(),
+          false,
+        )
+        .await
+        .unwrap();
+    }
+
+    let dest_dir = TempDir::new().unwrap();
+    let copies_before = filesystem_copies();
+    for (name, testdata) in [("roland", TestData::roland()), ("large", large.clone())] {
+      let dest = dest_dir.path().join(name);
+      assert_eq!(
+        store
+          .load_into_mmap(EntryType::File, testdata.digest(), dest.clone(), 0o555)
+          .await,
+        Ok(true)
+      );
+      assert_eq!(std::fs::read(&dest).unwrap(), testdata.bytes());
+      assert_eq!(
+        std::fs::metadata(&dest).unwrap().permissions().mode() & 0o777,
+        0o555
+      );
+    }
+    // Only the large file is stored on disk, and so can be copied by the filesystem, and then only
+    // when the store is on a local filesystem.
+    let expected_copies = if network_filesystem { 0 } else { 1 };
+    assert_eq!(filesystem_copies() - copies_before, expected_copies);
+
+    let missing = dest_dir.path().join("catnip");
+    assert_eq!(
+      store
+        .load_into_mmap(
+          EntryType::File,
+          TestData::catnip().digest(),
+          missing.clone(),
+          0o644
+        )
+        .await,
+      Ok(false)
+    );
+    assert!(!missing.exists());
+  }
+}
+
+#[tokio::test]
+async fn load_large_file_with_and_without_mmap() {
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for fsdb_mmap_reads in [true, false] {
+    let dir = TempDir::new().unwrap();
+    let store = ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        fsdb_mmap_reads,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap();
+    store
+      .store_bytes(EntryType::File, large.fingerprint(), large.bytes(), false)
+      .await
+      .unwrap();
+
+    assert_eq!(
+      load_file_bytes(&store, large.digest()).await,
+      Ok(Some(large.bytes()))
+    );
+    assert_eq!(
+      load_file_bytes(
+        &store,
+        TestData::new("987654321".repeat(1000 * 512).as_str()).digest()
+      )
+      .await,
+      Ok(None)
+    );
+  }
+}
+
+#[tokio::test]
+async fn stats() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  store
+    .store_bytes(
+      EntryType::File,
+      TestData::roland().fingerprint(),
+      TestData::roland().bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  store
+    .store_bytes(EntryType::File, large.fingerprint(), large.bytes(), false)
+    .await
+    .unwrap();
+  let directory = TestDirectory::containing_roland();
+  store
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      true,
+    )
+    .await
+    .unwrap();
+
+  let stats = store.stats().await.unwrap();
+  assert_eq!(stats.file_lmdb.entries, 1);
+  assert_eq!(stats.file_lmdb.total_bytes, TestData::roland().len());
+  assert_eq!(stats.file_lmdb.expired_bytes, TestData::roland().len());
+  assert_eq!(stats.file_fsdb.entries, 1);
+  assert_eq!(stats.file_fsdb.total_bytes, large.len());
+  assert_eq!(
+    stats.file_fsdb.shards.keys().collect::<Vec<_>>(),
+    vec![&ShardId::Fsdb(large.fingerprint().as_bytes()[0])]
+  );
+  // The directory was leased when it was stored.
+  assert_eq!(stats.directory_lmdb.entries, 1);
+  assert_eq!(stats.directory_lmdb.expired_bytes, 0);
+  assert_eq!(
+    stats.total_bytes(),
+    TestData::roland().len() + large.len() + directory.bytes().len()
+  );
+}
+
+#[tokio::test]
+async fn shard_entry_counts() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      max_entries_per_shard: Some(1),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let other_large = TestData::new("987654321".repeat(1000 * 512).as_str());
+  for testdata in [TestData::roland(), large.clone(), other_large.clone()] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+
+  let counts = store.shard_entry_counts().await.unwrap();
+  // Only large files are stored in shard directories, and every shard is reported.
+  assert_eq!(counts.len(), 256);
+  assert_eq!(counts.iter().map(|(_, count)| count).sum::<usize>(), 2);
+  for testdata in [large, other_large] {
+    let shard = ShardId::Fsdb(testdata.fingerprint().as_bytes()[0]);
+    assert!(counts
+      .iter()
+      .any(|(shard_id, count)| *shard_id == shard && *count >= 1));
+  }
+}
+
+#[tokio::test]
+async fn digests_in_shard() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for testdata in [TestData::roland(), TestData::catnip(), large.clone()] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  let directory = TestDirectory::containing_roland();
+  store
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+
+  let shards = store.shards();
+  assert!(shards.contains(&ShardId::Lmdb(0)));
+  assert!(shards.contains(&ShardId::Fsdb(0xff)));
+
+  // Every entry should be listed in exactly one shard.
+  let digests_in_shards = |entry_type| {
+    let store = store.clone();
+    let shards = shards.clone();
+    async move {
+      let mut digests = vec![];
+      for shard in shards {
+        digests.extend(store.digests_in_shard(entry_type, shard).await.unwrap());
+      }
+      digests.sort_by_key(|digest| digest.hash);
+      digests
+    }
+  };
+  let mut file_digests = vec![
+    TestData::roland().digest(),
+    TestData::catnip().digest(),
+    large.digest(),
+  ];
+  file_digests.sort_by_key(|digest| digest.hash);
+  assert_eq!(digests_in_shards(EntryType::File).await, file_digests);
+  assert_eq!(
+    digests_in_shards(EntryType::Directory).await,
+    vec![directory.digest()]
+  );
+}
+
+#[tokio::test]
+async fn verify_placement() {
+  let dir = TempDir::new().unwrap();
+  let small = TestData::roland();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  {
+    let store = new_store(dir.path());
+    for testdata in [&small, &large] {
+      store
+        .store_bytes(
+          EntryType::File,
+          testdata.fingerprint(),
+          testdata.bytes(),
+          false,
+        )
+        .await
+        .unwrap();
+    }
+    assert_eq!(
+      store.verify_placement().await,
+      Ok(PlacementReport::default())
+    );
+  }
+
+  let dir = TempDir::new().unwrap();
+  prime_store_with_lowered_threshold(dir.path(), &small).await;
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Fallback).unwrap();
+  assert_eq!(
+    store.verify_placement().await,
+    Ok(PlacementReport {
+      small_files_in_fsdb: vec![small.digest()],
+      large_files_in_lmdb: vec![],
+    })
+  );
+}
+
+#[tokio::test]
+async fn threshold_change_rebalance() {
+  let dir = TempDir::new().unwrap();
+  let testdata = TestData::roland();
+  prime_store_with_lowered_threshold(dir.path(), &testdata).await;
+
+  let store =
+    new_store_with_threshold_change(dir.path(), ThresholdChangeBehavior::Rebalance).unwrap();
+  // The file is readable while the rebalance is in progress.
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+
+  // Once the rebalance completes, the current limit is recorded.
+  let marker_path = dir.path().join("large_file_size_limit");
+  let mut attempts = 0;
+  while std::fs::read_to_string(&marker_path).unwrap() == "1" {
+    attempts += 1;
+    assert!(attempts < 100, "Rebalance did not complete.");
+    sleep(Duration::from_millis(50)).await;
+  }
+
+  assert!(!store
+    .get_file_fsdb()
+    .get_path(testdata.fingerprint())
+    .exists());
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn slow_op_threshold() {
+  let workunit_store = WorkunitStore::new(false, log::Level::Debug);
+  workunit_store.init_thread_state(None);
+  let slow_operations = || {
+    workunit_store
+      .get_metrics()
+      .get("local_store_slow_operations")
+      .copied()
+      .unwrap_or(0)
+  };
+
+  let testdata = TestData::roland();
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  load_file_bytes(&store, testdata.digest()).await.unwrap();
+  assert_eq!(slow_operations(), 0);
+
+  // With a zero threshold, every operation is slow.
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      slow_op_threshold: Some(Duration::ZERO),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  store
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  load_file_bytes(&store, testdata.digest()).await.unwrap();
+  store
+    .lease_all(vec![(testdata.digest(), EntryType::File)].into_iter())
+    .await
+    .unwrap();
+  store
+    .remove(EntryType::File, testdata.digest())
+    .await
+    .unwrap();
+  assert_eq!(slow_operations(), 4);
 }
 
 #[tokio::test]
-async fn empty_file_is_known() {
+async fn store_with_deadline() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-  let empty_file = TestData::empty();
+  let testdata = TestData::roland();
+  let mut file = NamedTempFile::new().unwrap();
+  file.write_all(&testdata.bytes()).unwrap();
+  file.flush().unwrap();
+
   assert_eq!(
     store
-      .load_bytes_with(EntryType::File, empty_file.digest(), |b| {
-        Bytes::copy_from_slice(b)
-      })
+      .store_with_deadline(
+        EntryType::File,
+        false,
+        true,
+        file.path().to_owned(),
+        Instant::now() + Duration::from_secs(60),
+      )
       .await,
-    Ok(Some(empty_file.bytes())),
-  )
-}
+    Ok(testdata.digest())
+  );
 
-#[tokio::test]
-async fn empty_directory_is_known() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-  let empty_dir = TestDirectory::empty();
-  assert_eq!(
+  assert!(matches!(
     store
-      .load_bytes_with(EntryType::Directory, empty_dir.digest(), |b| {
-        Bytes::copy_from_slice(b)
-      })
+      .store_with_deadline(
+        EntryType::File,
+        false,
+        true,
+        file.path().to_owned(),
+        Instant::now(),
+      )
       .await,
-    Ok(Some(empty_dir.bytes())),
-  )
-}
+    Err(StoreError::DeadlineExceeded(_))
+  ));
+  assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
 
-#[tokio::test]
-async fn all_digests() {
-  let dir = TempDir::new().unwrap();
-  let store = new_store(dir.path());
-  let digest1 = prime_store_with_file_bytes(&store, TestData::roland().bytes()).await;
-  assert_eq!(Ok(vec![digest1]), store.all_digests(EntryType::File).await);
-  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
-  let digest2 = prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
-  assert_eq!(
-    vec![digest1, digest2].into_iter().collect::<HashSet<_>>(),
-    store
-      .all_digests(EntryType::File)
-      .await
-      .unwrap()
-      .into_iter()
-      .collect::<HashSet<_>>(),
+  // Failures which happen after the deadline, but which were not caused by it, are not reported as
+  // the deadline being exceeded.
+  let missing = dir.path().join("missing");
+  let result = store
+    .store_with_deadline(
+      EntryType::File,
+      false,
+      true,
+      missing,
+      Instant::now() - Duration::from_secs(1),
+    )
+    .await;
+  assert!(
+    matches!(result, Err(StoreError::Unclassified(_))),
+    "Unexpected result: {result:?}"
   );
 }
 
 #[tokio::test]
-async fn get_missing_digests() {
+async fn store_with_deadline_slow_reader() {
   let dir = TempDir::new().unwrap();
   let store = new_store(dir.path());
-  let small_testdata = TestData::roland();
-  let large_testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let testdata = TestData::new("123456789".repeat(1000 * 512).as_str());
 
-  prime_store_with_file_bytes(&store, small_testdata.bytes()).await;
-  prime_store_with_file_bytes(&store, large_testdata.bytes()).await;
-  let missing = store
-    .get_missing_digests(
+  // Use a named pipe as the source, which is written promptly for the hashing pass, but slowly for
+  // the copy.
+  let src_dir = TempDir::new().unwrap();
+  let src = src_dir.path().join("src");
+  assert!(std::process::Command::new("mkfifo")
+    .arg(&src)
+    .status()
+    .unwrap()
+    .success());
+  let writer = std::thread::spawn({
+    let src = src.clone();
+    let bytes = testdata.bytes();
+    move || {
+      std::fs::write(&src, &bytes).unwrap();
+      // Give the hashing pass time to observe EOF before re-opening the pipe.
+      std::thread::sleep(Duration::from_millis(200));
+      let mut file = std::fs::OpenOptions::new().write(true).open(&src).unwrap();
+      let _ = file.write_all(&bytes[..1024]);
+      std::thread::sleep(Duration::from_millis(1500));
+      // Fails once the reader has given up.
+      let _ = file.write_all(&bytes[1024..]);
+    }
+  });
+
+  let result = store
+    .store_with_deadline(
       EntryType::File,
-      HashSet::from([
-        small_testdata.digest(),
-        large_testdata.digest(),
-        hashing::Digest::of_bytes("1".as_bytes()),
-      ]),
+      false,
+      false,
+      src,
+      Instant::now() + Duration::from_millis(800),
     )
-    .await
-    .unwrap();
-  assert_eq!(
-    missing,
-    HashSet::from([hashing::Digest::of_bytes("1".as_bytes())])
-  )
+    .await;
+  writer.join().unwrap();
+
+  assert!(
+    matches!(result, Err(StoreError::DeadlineExceeded(_))),
+    "Unexpected result: {result:?}"
+  );
+  assert_eq!(load_file_bytes(&store, testdata.digest()).await, Ok(None));
+  // The partially written temporary file should have been removed.
+  let files = WalkDir::new(dir.path().join("immutable"))
+    .into_iter()
+    .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+    .count();
+  assert_eq!(files, 0);
 }
 
 pub fn new_store<P: AsRef<Path>>(dir: P) -> ByteStore {
@@ -748,3 +3953,422 @@ fn get_directory_size(path: &Path) -> usize {
   }
   len
 }
+
+#[tokio::test]
+async fn pipelined_store() {
+  // Spans several of the chunks in which files are read.
+  let bytes = Bytes::from("123456789".repeat(1024 * 1024));
+  for digest_function in [DigestFunction::Sha256, DigestFunction::Blake3] {
+    let dir = TempDir::new().unwrap();
+    let store = ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        digest_function,
+        pipelined_store_min_bytes: 0,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap();
+
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&bytes).unwrap();
+    let expected_digest = Digest::of_bytes_with(digest_function, &bytes);
+    assert_eq!(
+      store
+        .store(EntryType::File, false, false, file.path().to_owned())
+        .await,
+      Ok(expected_digest)
+    );
+    assert_eq!(
+      load_file_bytes(&store, expected_digest).await,
+      Ok(Some(bytes.clone()))
+    );
+    assert!(store
+      .get_file_fsdb()
+      .get_path(expected_digest.hash)
+      .exists());
+
+    // The temporary file which the content was written to was moved into place.
+    let tempfiles = WalkDir::new(dir.path())
+      .into_iter()
+      .map(|entry| entry.unwrap())
+      .filter(|entry| entry.file_name().to_string_lossy().starts_with(".tmp"))
+      .count();
+    assert_eq!(tempfiles, 0);
+  }
+}
+
+#[tokio::test]
+async fn materialize_from_fs() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let small = TestData::roland();
+  for testdata in [&large, &small] {
+    prime_store_with_file_bytes(&store, testdata.bytes()).await;
+  }
+
+  let materialize_dir = TempDir::new().unwrap();
+  let destination = materialize_dir.path().join("large");
+  assert_eq!(
+    store
+      .materialize_from_fs(large.digest(), destination.clone(), 0o555, false)
+      .await,
+    Ok(true)
+  );
+  assert_eq!(std::fs::read(&destination).unwrap(), large.bytes());
+  let metadata = std::fs::metadata(&destination).unwrap();
+  assert_eq!(metadata.permissions().mode() & 0o777, 0o555);
+  assert_ne!(
+    metadata.ino(),
+    std::fs::metadata(store.get_file_fsdb().get_path(large.fingerprint()))
+      .unwrap()
+      .ino()
+  );
+
+  // Small files are not held on disk, and so are not materialized.
+  assert_eq!(
+    store
+      .materialize_from_fs(
+        small.digest(),
+        materialize_dir.path().join("small"),
+        0o644,
+        false
+      )
+      .await,
+    Ok(false)
+  );
+}
+
+#[tokio::test]
+async fn namespaces() {
+  let dir = TempDir::new().unwrap();
+  let new_namespaced_store = |namespace: &str| {
+    ByteStore::new_with_options(
+      task_executor::Executor::new(),
+      dir.path(),
+      LocalOptions {
+        namespace: Some(namespace.to_owned()),
+        ..LocalOptions::default()
+      },
+    )
+  };
+  let first = new_namespaced_store("first").unwrap();
+  let second = new_namespaced_store("second").unwrap();
+  assert!(new_namespaced_store("../first").is_err());
+
+  // Directories are not shared between namespaces.
+  let directory = TestDirectory::containing_roland();
+  first
+    .store_bytes(
+      EntryType::Directory,
+      directory.fingerprint(),
+      directory.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    second
+      .get_missing_digests(EntryType::Directory, HashSet::from([directory.digest()]))
+      .await,
+    Ok(HashSet::from([directory.digest()]))
+  );
+
+  // But identical large files are linked between them.
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  for store in [&first, &second] {
+    store
+      .store_bytes(EntryType::File, large.fingerprint(), large.bytes(), false)
+      .await
+      .unwrap();
+  }
+  let first_path = first.get_file_fsdb().get_path(large.fingerprint());
+  let second_path = second.get_file_fsdb().get_path(large.fingerprint());
+  assert_ne!(first_path, second_path);
+  assert_eq!(
+    std::fs::metadata(&first_path).unwrap().ino(),
+    std::fs::metadata(&second_path).unwrap().ino()
+  );
+
+  // Removing a file from one namespace leaves it present in the other.
+  assert_eq!(
+    first.remove(EntryType::File, large.digest()).await,
+    Ok(true)
+  );
+  assert_eq!(
+    load_file_bytes(&second, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn store_bytes_batch_atomic() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let small = [TestData::roland(), TestData::catnip()];
+  let large = (0..3)
+    .map(|i| TestData::new(format!("{i}").repeat(1000 * 512).as_str()))
+    .collect::<Vec<_>>();
+  let items = |testdata: &[&TestData]| {
+    testdata
+      .iter()
+      .map(|testdata| (testdata.fingerprint(), testdata.bytes()))
+      .collect::<Vec<_>>()
+  };
+
+  // One small file and one large file are already present.
+  store
+    .store_bytes_batch(EntryType::File, items(&[&small[0], &large[0]]), false)
+    .await
+    .unwrap();
+
+  // A directory in place of one of the large files causes the batch to fail after the small files
+  // have been committed, so they must be removed again.
+  std::fs::create_dir_all(store.get_file_fsdb().get_path(large[2].fingerprint())).unwrap();
+  let all = small.iter().chain(large.iter()).collect::<Vec<_>>();
+  assert!(store
+    .store_bytes_batch_atomic(EntryType::File, items(&all), false)
+    .await
+    .is_err());
+  assert_eq!(
+    store
+      .get_missing_digests(
+        EntryType::File,
+        all.iter().map(|testdata| testdata.digest()).collect(),
+      )
+      .await,
+    Ok(HashSet::from([small[1].digest(), large[1].digest()]))
+  );
+  let tempfiles = WalkDir::new(dir.path())
+    .into_iter()
+    .map(|entry| entry.unwrap())
+    .filter(|entry| entry.file_name().to_string_lossy().starts_with(".tmp"))
+    .count();
+  assert_eq!(tempfiles, 0);
+
+  // Once the obstruction is removed, the whole batch is stored.
+  std::fs::remove_dir(store.get_file_fsdb().get_path(large[2].fingerprint())).unwrap();
+  store
+    .store_bytes_batch_atomic(EntryType::File, items(&all), false)
+    .await
+    .unwrap();
+  for testdata in all {
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()).await,
+      Ok(Some(testdata.bytes()))
+    );
+  }
+}
+
+#[tokio::test]
+async fn known_remotely() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      remote_presence_ttl: Some(Duration::from_millis(500)),
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let roland = TestData::roland().digest();
+  let catnip = TestData::catnip().digest();
+  let both = || vec![roland, catnip];
+
+  assert_eq!(store.known_remotely("a", both()).await, HashSet::new());
+  store.record_known_remotely("a", vec![roland]).await;
+  assert_eq!(
+    store.known_remotely("a", both()).await,
+    HashSet::from([roland])
+  );
+  // Presence is recorded per remote store.
+  assert_eq!(store.known_remotely("b", both()).await, HashSet::new());
+
+  store.forget_known_remotely("a", vec![roland]).await;
+  assert_eq!(store.known_remotely("a", both()).await, HashSet::new());
+
+  // Records expire, unless they are refreshed.
+  store.record_known_remotely("a", both()).await;
+  sleep(Duration::from_millis(300)).await;
+  store.record_known_remotely("a", vec![catnip]).await;
+  sleep(Duration::from_millis(300)).await;
+  assert_eq!(
+    store.known_remotely("a", both()).await,
+    HashSet::from([catnip])
+  );
+}
+
+#[tokio::test]
+async fn known_remotely_disabled() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let roland = TestData::roland().digest();
+
+  store.record_known_remotely("a", vec![roland]).await;
+  assert_eq!(
+    store.known_remotely("a", vec![roland]).await,
+    HashSet::new()
+  );
+  assert!(!dir.path().join("remote_presence").exists());
+}
+
+#[tokio::test]
+async fn provenance() {
+  let dir = TempDir::new().unwrap();
+  let store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    dir.path(),
+    LocalOptions {
+      record_provenance: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let small = TestData::roland();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let unscoped = TestData::catnip();
+  let store_bytes = |testdata: &TestData| {
+    store.store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+  };
+
+  let before = SystemTime::now() - Duration::from_secs(1);
+  scope_task_origin(Origin::Rule("a_rule".to_owned()), async {
+    store_bytes(&small).await.unwrap();
+    store_bytes(&large).await.unwrap();
+  })
+  .await;
+  store_bytes(&unscoped).await.unwrap();
+  for testdata in [&small, &large] {
+    let provenance = store.provenance(testdata.digest()).await.unwrap().unwrap();
+    assert_eq!(provenance.origin, Origin::Rule("a_rule".to_owned()));
+    assert!(provenance.recorded_at >= before);
+  }
+  assert_eq!(store.provenance(unscoped.digest()).await, Ok(None));
+
+  // Storing an entry again keeps its first provenance.
+  scope_task_origin(Origin::Process("a process".to_owned()), store_bytes(&small))
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .provenance(small.digest())
+      .await
+      .unwrap()
+      .map(|provenance| provenance.origin),
+    Some(Origin::Rule("a_rule".to_owned()))
+  );
+
+  // But not once it has been removed.
+  assert_eq!(
+    store.remove(EntryType::File, small.digest()).await,
+    Ok(true)
+  );
+  assert_eq!(store.provenance(small.digest()).await, Ok(None));
+}
+
+#[tokio::test]
+async fn export_and_import() {
+  let dir = TempDir::new().unwrap();
+  let store = new_store(dir.path());
+  let roland = TestData::roland();
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let testdir = TestDirectory::containing_roland();
+  for testdata in [&roland, &large] {
+    store
+      .store_bytes(
+        EntryType::File,
+        testdata.fingerprint(),
+        testdata.bytes(),
+        false,
+      )
+      .await
+      .unwrap();
+  }
+  store
+    .store_bytes(
+      EntryType::Directory,
+      testdir.fingerprint(),
+      testdir.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+
+  let entries = vec![
+    (roland.digest(), EntryType::File),
+    (large.digest(), EntryType::File),
+    (testdir.digest(), EntryType::Directory),
+  ];
+  let mut archive = Vec::new();
+  store.export(entries.clone(), &mut archive).await.unwrap();
+
+  // The archive round trips into another store, including large files.
+  let imported_dir = TempDir::new().unwrap();
+  let imported_store = new_store(imported_dir.path());
+  let mut imported = imported_store
+    .import(&mut archive.as_slice(), false)
+    .await
+    .unwrap();
+  imported.sort();
+  let mut expected = entries.clone();
+  expected.sort();
+  assert_eq!(imported, expected);
+  assert_eq!(
+    load_file_bytes(&imported_store, large.digest()).await,
+    Ok(Some(large.bytes()))
+  );
+  assert_eq!(
+    load_directory_proto_bytes(&imported_store, testdir.digest()).await,
+    Ok(Some(testdir.bytes()))
+  );
+
+  // Truncated and corrupted archives are rejected.
+  let other_dir = TempDir::new().unwrap();
+  let truncated = &archive[..archive.len() - 1];
+  assert!(new_store(other_dir.path())
+    .import(&mut &truncated[..], false)
+    .await
+    .is_err());
+  let mut corrupted = archive.clone();
+  let last = corrupted.len() - 1;
+  corrupted[last] ^= 0xff;
+  let err = new_store(other_dir.path())
+    .import(&mut corrupted.as_slice(), false)
+    .await
+    .unwrap_err();
+  assert!(err.contains("had digest"), "{err}");
+
+  // As are archives from stores with a different digest function.
+  let blake3_store = ByteStore::new_with_options(
+    task_executor::Executor::new(),
+    other_dir.path().join("blake3"),
+    LocalOptions {
+      digest_function: DigestFunction::Blake3,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+  let err = blake3_store
+    .import(&mut archive.as_slice(), false)
+    .await
+    .unwrap_err();
+  assert!(err.contains("sha256 digest function"), "{err}");
+
+  // And entries which are not present cannot be exported.
+  let err = imported_store
+    .export(
+      vec![(TestData::catnip().digest(), EntryType::File)],
+      &mut Vec::new(),
+    )
+    .await
+    .unwrap_err();
+  assert!(err.contains("not present"), "{err}");
+}
@@ -0,0 +1,322 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::net::Ipv4Addr;
+
+///
+/// The multicast group and port of multicast DNS (RFC 6762).
+///
+pub(crate) const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub(crate) const MDNS_PORT: u16 = 5353;
+
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// The top bit of the class of an mDNS record is the "cache flush" (or "unicast response") bit.
+const CLASS_MASK: u16 = 0x7fff;
+const FLAGS_RESPONSE: u16 = 0x8400;
+// Bounds the number of compression pointers followed while reading a name, to reject loops.
+const MAX_NAME_POINTERS: usize = 16;
+
+///
+/// The records of a DNS message which are relevant to DNS-based service discovery (RFC 6763):
+/// the subset of mDNS which is needed for engines to find one another.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Record {
+  /// A question which asks for the instances of the named service type.
+  Query { service: String },
+  /// An answer which names an instance of a service type. A TTL of zero withdraws the instance.
+  Pointer {
+    service: String,
+    instance: String,
+    ttl: u32,
+  },
+  /// An answer which gives the port of an instance.
+  Service {
+    instance: String,
+    port: u16,
+    ttl: u32,
+  },
+}
+
+///
+/// Encodes a query for the instances of the given service type.
+///
+pub(crate) fn encode_query(service: &str) -> Vec<u8> {
+  let mut message = header(0, 1, 0);
+  write_name(&mut message, service);
+  write_u16(&mut message, TYPE_PTR);
+  write_u16(&mut message, CLASS_IN);
+  message
+}
+
+///
+/// Encodes an unsolicited response which announces (or, with a TTL of zero, withdraws) the given
+/// instance of a service type, served on the given port of the sending host.
+///
+/// NB: Peers use the source address of the message rather than resolving the target of the SRV
+/// record, so no address records are sent.
+///
+pub(crate) fn encode_announcement(service: &str, instance: &str, port: u16, ttl: u32) -> Vec<u8> {
+  let instance_name = format!("{instance}.{service}");
+  let mut message = header(FLAGS_RESPONSE, 0, 2);
+
+  write_name(&mut message, service);
+  write_u16(&mut message, TYPE_PTR);
+  write_u16(&mut message, CLASS_IN);
+  write_u32(&mut message, ttl);
+  let mut rdata = Vec::new();
+  write_name(&mut rdata, &instance_name);
+  write_u16(&mut message, rdata.len() as u16);
+  message.extend_from_slice(&rdata);
+
+  write_name(&mut message, &instance_name);
+  write_u16(&mut message, TYPE_SRV);
+  write_u16(&mut message, CLASS_IN);
+  write_u32(&mut message, ttl);
+  let mut rdata = Vec::new();
+  // Priority and weight.
+  write_u16(&mut rdata, 0);
+  write_u16(&mut rdata, 0);
+  write_u16(&mut rdata, port);
+  write_name(&mut rdata, &format!("{instance}.local"));
+  write_u16(&mut message, rdata.len() as u16);
+  message.extend_from_slice(&rdata);
+
+  message
+}
+
+///
+/// Decodes the PTR questions, and the PTR and SRV answers, of the given message. Other records
+/// are skipped.
+///
+pub(crate) fn decode(message: &[u8]) -> Result<Vec<Record>, String> {
+  let mut reader = Reader { message, offset: 0 };
+  let _id = reader.u16()?;
+  let _flags = reader.u16()?;
+  let questions = reader.u16()?;
+  let answers = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+
+  let mut records = Vec::new();
+  for _ in 0..questions {
+    let name = reader.name()?;
+    let record_type = reader.u16()?;
+    let class = reader.u16()? & CLASS_MASK;
+    if record_type == TYPE_PTR && class == CLASS_IN {
+      records.push(Record::Query { service: name });
+    }
+  }
+  for _ in 0..answers {
+    let name = reader.name()?;
+    let record_type = reader.u16()?;
+    let class = reader.u16()? & CLASS_MASK;
+    let ttl = reader.u32()?;
+    let len = reader.u16()? as usize;
+    let end = reader.offset + len;
+    if end > message.len() {
+      return Err("Truncated record.".to_owned());
+    }
+    match (record_type, class) {
+      (TYPE_PTR, CLASS_IN) => records.push(Record::Pointer {
+        service: name,
+        instance: reader.name()?,
+        ttl,
+      }),
+      (TYPE_SRV, CLASS_IN) => {
+        let _priority = reader.u16()?;
+        let _weight = reader.u16()?;
+        records.push(Record::Service {
+          instance: name,
+          port: reader.u16()?,
+          ttl,
+        });
+      }
+      _ => (),
+    }
+    reader.offset = end;
+  }
+  Ok(records)
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+  let mut message = Vec::with_capacity(512);
+  // NB: mDNS messages which are sent to the multicast group have an ID of zero.
+  for field in [0, flags, questions, answers, 0, 0] {
+    write_u16(&mut message, field);
+  }
+  message
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+  for label in name.split('.').filter(|label| !label.is_empty()) {
+    // Labels are limited to 63 bytes: longer ones are truncated rather than corrupting the name.
+    let label = &label.as_bytes()[..label.len().min(63)];
+    buf.push(label.len() as u8);
+    buf.extend_from_slice(label);
+  }
+  buf.push(0);
+}
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32(buf: &mut Vec<u8>, value: u32) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+struct Reader<'a> {
+  message: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+    let bytes = self
+      .message
+      .get(self.offset..self.offset + len)
+      .ok_or_else(|| "Truncated message.".to_owned())?;
+    self.offset += len;
+    Ok(bytes)
+  }
+
+  fn u16(&mut self) -> Result<u16, String> {
+    Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  ///
+  /// Reads a (possibly compressed) name, leaving the offset after its first occurrence.
+  ///
+  fn name(&mut self) -> Result<String, String> {
+    let mut labels = Vec::new();
+    let mut offset = self.offset;
+    let mut end_of_name = None;
+    let mut pointers = 0;
+    loop {
+      let len = *self
+        .message
+        .get(offset)
+        .ok_or_else(|| "Truncated name.".to_owned())? as usize;
+      match len {
+        0 => {
+          offset += 1;
+          break;
+        }
+        len if len & 0xc0 == 0xc0 => {
+          let low = *self
+            .message
+            .get(offset + 1)
+            .ok_or_else(|| "Truncated name.".to_owned())? as usize;
+          end_of_name.get_or_insert(offset + 2);
+          pointers += 1;
+          if pointers > MAX_NAME_POINTERS {
+            return Err("Too many compression pointers in name.".to_owned());
+          }
+          offset = ((len & 0x3f) << 8) | low;
+        }
+        len => {
+          let label = self
+            .message
+            .get(offset + 1..offset + 1 + len)
+            .ok_or_else(|| "Truncated name.".to_owned())?;
+          labels.push(String::from_utf8_lossy(label).into_owned());
+          offset += 1 + len;
+        }
+      }
+    }
+    self.offset = end_of_name.unwrap_or(offset);
+    Ok(labels.join("."))
+  }
+}
+
+///
+/// Binds a socket to the mDNS port which has joined the mDNS multicast group on the interface
+/// with the given address, and which sends its messages from that interface.
+///
+/// The socket allows its address to be reused, so that it may share the port with other mDNS
+/// responders on the host (including other engines).
+///
+#[cfg(unix)]
+pub(crate) fn bind(interface: Ipv4Addr) -> std::io::Result<tokio::net::UdpSocket> {
+  use std::os::unix::io::{AsRawFd, FromRawFd};
+
+  let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+  if fd < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  // NB: Take ownership of the socket immediately, so that it is closed on error.
+  let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+  let enable: libc::c_int = 1;
+  for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+    let result = unsafe {
+      libc::setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_SOCKET,
+        option,
+        &enable as *const libc::c_int as *const libc::c_void,
+        std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+      )
+    };
+    if result != 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+  }
+
+  let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+  address.sin_family = libc::AF_INET as libc::sa_family_t;
+  address.sin_port = MDNS_PORT.to_be();
+  address.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+  #[cfg(target_os = "macos")]
+  {
+    address.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+  }
+  let result = unsafe {
+    libc::bind(
+      socket.as_raw_fd(),
+      &address as *const libc::sockaddr_in as *const libc::sockaddr,
+      std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+    )
+  };
+  if result != 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  let interface_address = libc::in_addr {
+    s_addr: u32::from(interface).to_be(),
+  };
+  let result = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      libc::IPPROTO_IP,
+      libc::IP_MULTICAST_IF,
+      &interface_address as *const libc::in_addr as *const libc::c_void,
+      std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+    )
+  };
+  if result != 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  socket.join_multicast_v4(&MDNS_ADDR, &interface)?;
+  // Engines on the same host discover one another via the loopback of their messages.
+  socket.set_multicast_loop_v4(true)?;
+  socket.set_nonblocking(true)?;
+  tokio::net::UdpSocket::from_std(socket)
+}
+
+///
+/// NB: Sharing the mDNS port with other responders relies on `SO_REUSEPORT`, which Windows does
+/// not have, so LAN cache peers are not discovered there.
+///
+#[cfg(windows)]
+pub(crate) fn bind(_interface: Ipv4Addr) -> std::io::Result<tokio::net::UdpSocket> {
+  Err(std::io::Error::new(
+    std::io::ErrorKind::Unsupported,
+    "Discovering LAN cache peers via mDNS is not supported on Windows.",
+  ))
+}
@@ -0,0 +1,62 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use crate::mdns::{decode, encode_announcement, encode_query, Record};
+
+const SERVICE: &str = "_test._tcp.local";
+
+#[test]
+fn roundtrip() {
+  assert_eq!(
+    decode(&encode_query(SERVICE)).unwrap(),
+    vec![Record::Query {
+      service: SERVICE.to_owned()
+    }]
+  );
+  assert_eq!(
+    decode(&encode_announcement(SERVICE, "abc", 1234, 30)).unwrap(),
+    vec![
+      Record::Pointer {
+        service: SERVICE.to_owned(),
+        instance: format!("abc.{SERVICE}"),
+        ttl: 30,
+      },
+      Record::Service {
+        instance: format!("abc.{SERVICE}"),
+        port: 1234,
+        ttl: 30,
+      },
+    ]
+  );
+}
+
+#[test]
+fn compressed_names() {
+  // A response whose PTR record points back at its (already written) name.
+  let mut message = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+  // The name, at offset 12: `_test._tcp.local`.
+  message.extend_from_slice(b"\x05_test\x04_tcp\x05local\x00");
+  // Type PTR, class IN (with the cache flush bit), TTL 60.
+  message.extend_from_slice(&[0, 12, 0x80, 1, 0, 0, 0, 60]);
+  // The instance `x`, followed by a pointer to offset 12.
+  message.extend_from_slice(&[0, 4, 1, b'x', 0xc0, 12]);
+
+  assert_eq!(
+    decode(&message).unwrap(),
+    vec![Record::Pointer {
+      service: SERVICE.to_owned(),
+      instance: format!("x.{SERVICE}"),
+      ttl: 60,
+    }]
+  );
+}
+
+#[test]
+fn malformed() {
+  let message = encode_announcement(SERVICE, "abc", 1234, 30);
+  assert!(decode(&message[..message.len() - 3]).is_err());
+
+  // A name which points at itself.
+  let mut message = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+  message.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+  assert!(decode(&message).is_err());
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use fs::{DirectoryDigest, Permissions, PreparedPathGlobs};
+
+use crate::{Snapshot, Store, StoreError};
+
+///
+/// Properties of the files of a Snapshot which are not included in its digest, and which are
+/// only captured and restored when explicitly requested.
+///
+/// Mtimes are normalized to offsets from the mtime of the oldest captured file: their absolute
+/// values vary between machines and checkouts, but their relative order is what make-style tools
+/// inspect.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NodeProperties {
+  mtimes: BTreeMap<PathBuf, Duration>,
+}
+
+impl NodeProperties {
+  ///
+  /// Normalizes the given absolute mtimes, which should be keyed by paths relative to the root of
+  /// a Snapshot.
+  ///
+  pub fn from_mtimes(mtimes: impl IntoIterator<Item = (PathBuf, SystemTime)>) -> Self {
+    let mtimes = mtimes.into_iter().collect::<Vec<_>>();
+    let oldest = match mtimes.iter().map(|(_, mtime)| *mtime).min() {
+      Some(oldest) => oldest,
+      None => return Self::default(),
+    };
+    Self {
+      mtimes: mtimes
+        .into_iter()
+        .map(|(path, mtime)| {
+          let offset = mtime.duration_since(oldest).unwrap_or_default();
+          (path, offset)
+        })
+        .collect(),
+    }
+  }
+
+  ///
+  /// The normalized mtime of the given file, if one was captured.
+  ///
+  pub fn mtime(&self, path: &Path) -> Option<Duration> {
+    self.mtimes.get(path).copied()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.mtimes.is_empty()
+  }
+
+  ///
+  /// Reads the mtimes of the files of the given Snapshot below the given root.
+  ///
+  fn capture(root: &Path, snapshot: &Snapshot) -> Result<Self, String> {
+    let mtimes = snapshot
+      .files()
+      .into_iter()
+      .map(|path| {
+        let mtime = root
+          .join(&path)
+          .metadata()
+          .and_then(|metadata| metadata.modified())
+          .map_err(|e| format!("Failed to read mtime of {}: {e}", path.display()))?;
+        Ok((path, mtime))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    Ok(Self::from_mtimes(mtimes))
+  }
+
+  ///
+  /// Sets the mtimes of the files below the given destination. The newest file receives the
+  /// current time, and the rest receive times which preserve their offsets from it, so that none
+  /// of them are in the future.
+  ///
+  fn restore(&self, destination: &Path) -> Result<(), String> {
+    let newest_offset = self.mtimes.values().max().copied().unwrap_or_default();
+    let base = SystemTime::now()
+      .checked_sub(newest_offset)
+      .unwrap_or(SystemTime::UNIX_EPOCH);
+    for (path, offset) in &self.mtimes {
+      let path = destination.join(path);
+      fs_set_times::set_mtime(
+        &path,
+        fs_set_times::SystemTimeSpec::Absolute(base + *offset),
+      )
+      .map_err(|e| format!("Failed to set mtime of {}: {e}", path.display()))?;
+    }
+    Ok(())
+  }
+}
+
+impl Snapshot {
+  ///
+  /// Like `Snapshot::capture_snapshot_from_arbitrary_root`, but additionally captures the
+  /// NodeProperties of the matched files. The digest of the Snapshot is unaffected.
+  ///
+  pub async fn capture_snapshot_with_node_properties(
+    store: Store,
+    executor: task_executor::Executor,
+    root_path: PathBuf,
+    path_globs: PreparedPathGlobs,
+  ) -> Result<(Snapshot, NodeProperties), String> {
+    let snapshot = Snapshot::capture_snapshot_from_arbitrary_root(
+      store,
+      executor.clone(),
+      root_path.clone(),
+      path_globs,
+      None,
+    )
+    .await?;
+    let node_properties = executor
+      .spawn_blocking(
+        {
+          let snapshot = snapshot.clone();
+          move || NodeProperties::capture(&root_path, &snapshot)
+        },
+        |e| Err(format!("Mtime capture task failed: {e}")),
+      )
+      .await?;
+    Ok((snapshot, node_properties))
+  }
+}
+
+impl Store {
+  ///
+  /// Materializes the given Directory as mutable files (because hardlinks out of the store would
+  /// share their mtimes with it), and then restores the given NodeProperties.
+  ///
+  pub async fn materialize_directory_with_node_properties(
+    &self,
+    destination: PathBuf,
+    digest: DirectoryDigest,
+    node_properties: &NodeProperties,
+    perms: Permissions,
+  ) -> Result<(), StoreError> {
+    self
+      .materialize_directory(destination.clone(), digest, true, &BTreeSet::new(), perms)
+      .await?;
+    if node_properties.is_empty() {
+      return Ok(());
+    }
+    let node_properties = node_properties.clone();
+    self
+      .local
+      .executor()
+      .spawn_blocking(
+        move || node_properties.restore(&destination),
+        |e| Err(format!("Mtime restoration task failed: {e}")),
+      )
+      .await?;
+    Ok(())
+  }
+}
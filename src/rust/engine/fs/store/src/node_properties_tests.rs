@@ -0,0 +1,108 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use fs::{GlobExpansionConjunction, PathGlobs, Permissions, PreparedPathGlobs, StrictGlobMatching};
+use tempfile::TempDir;
+use testutil::make_file;
+
+use crate::{NodeProperties, Snapshot, Store};
+
+fn all_globs() -> PreparedPathGlobs {
+  PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap()
+}
+
+fn set_mtime(path: &Path, mtime: SystemTime) {
+  fs_set_times::set_mtime(path, fs_set_times::SystemTimeSpec::Absolute(mtime)).unwrap();
+}
+
+fn mtime(path: &Path) -> SystemTime {
+  path.metadata().unwrap().modified().unwrap()
+}
+
+#[test]
+fn normalizes_mtimes() {
+  let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+  let properties = NodeProperties::from_mtimes(vec![
+    (PathBuf::from("b"), epoch + Duration::from_secs(5)),
+    (PathBuf::from("a"), epoch),
+  ]);
+  assert_eq!(properties.mtime(Path::new("a")), Some(Duration::ZERO));
+  assert_eq!(
+    properties.mtime(Path::new("b")),
+    Some(Duration::from_secs(5))
+  );
+  assert_eq!(properties.mtime(Path::new("c")), None);
+
+  // Shifting all mtimes by the same amount does not affect the normalized mtimes.
+  let shifted = NodeProperties::from_mtimes(vec![
+    (PathBuf::from("a"), epoch + Duration::from_secs(60)),
+    (PathBuf::from("b"), epoch + Duration::from_secs(65)),
+  ]);
+  assert_eq!(properties, shifted);
+}
+
+#[tokio::test]
+async fn capture_and_restore_mtimes() {
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+
+  let root = TempDir::new().unwrap();
+  std::fs::create_dir(root.path().join("src")).unwrap();
+  make_file(&root.path().join("src/main.c"), b"int main;", 0o644);
+  make_file(&root.path().join("main.o"), b"object", 0o644);
+  let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+  set_mtime(&root.path().join("src/main.c"), epoch);
+  set_mtime(&root.path().join("main.o"), epoch + Duration::from_secs(30));
+
+  let (snapshot, properties) = Snapshot::capture_snapshot_with_node_properties(
+    store.clone(),
+    executor.clone(),
+    root.path().to_owned(),
+    all_globs(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    properties.mtime(Path::new("main.o")),
+    Some(Duration::from_secs(30))
+  );
+
+  // The digest is the same as without capturing properties.
+  let plain = Snapshot::capture_snapshot_from_arbitrary_root(
+    store.clone(),
+    executor.clone(),
+    root.path().to_owned(),
+    all_globs(),
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(snapshot.digest, plain.digest);
+
+  let dest = TempDir::new().unwrap();
+  store
+    .materialize_directory_with_node_properties(
+      dest.path().to_owned(),
+      snapshot.clone().into(),
+      &properties,
+      Permissions::Writable,
+    )
+    .await
+    .unwrap();
+  let source_mtime = mtime(&dest.path().join("src/main.c"));
+  let object_mtime = mtime(&dest.path().join("main.o"));
+  assert_eq!(
+    object_mtime.duration_since(source_mtime).unwrap(),
+    Duration::from_secs(30)
+  );
+  assert!(object_mtime <= SystemTime::now());
+}
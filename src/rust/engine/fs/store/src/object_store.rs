@@ -0,0 +1,602 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, AUTHORIZATION, ETAG};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest as Sha2Digest, Sha256};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// The smallest part (other than the last) which S3 accepts in a multipart upload.
+const MIN_PART_SIZE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Stands in for the hash of a request body, which would otherwise need to be computed up front.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+///
+/// An error from an object storage service, which is retryable if it might be transient.
+///
+#[derive(Debug)]
+pub(crate) struct ObjectStoreError {
+  pub message: String,
+  pub retryable: bool,
+}
+
+impl ObjectStoreError {
+  fn permanent(message: String) -> Self {
+    Self {
+      message,
+      retryable: false,
+    }
+  }
+
+  pub(crate) fn transient(message: String) -> Self {
+    Self {
+      message,
+      retryable: true,
+    }
+  }
+}
+
+impl From<reqwest::Error> for ObjectStoreError {
+  fn from(err: reqwest::Error) -> Self {
+    Self {
+      retryable: err.is_timeout() || err.is_connect() || err.is_body(),
+      message: err.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for ObjectStoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&self.message, f)
+  }
+}
+
+///
+/// Credentials for signing requests, read from the standard AWS environment variables. GCS accepts
+/// HMAC keys in the same form.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Credentials {
+  pub access_key_id: String,
+  pub secret_access_key: String,
+  pub session_token: Option<String>,
+}
+
+impl Credentials {
+  fn from_env(env: &impl Fn(&str) -> Option<String>) -> Option<Self> {
+    Some(Self {
+      access_key_id: env("AWS_ACCESS_KEY_ID")?,
+      secret_access_key: env("AWS_SECRET_ACCESS_KEY")?,
+      session_token: env("AWS_SESSION_TOKEN"),
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ObjectStoreKind {
+  /// An S3-compatible bucket, whose requests are signed (if credentials are available) and which
+  /// supports multipart uploads.
+  Bucket,
+  /// A plain HTTP server (such as nginx, or a CDN), whose objects are read with GET and HEAD, and
+  /// written with PUT. Servers which should be read-only can reject writes, but writes should also
+  /// be disabled in Pants to avoid failing them.
+  StaticHttp,
+}
+
+///
+/// The location of the objects of a store, parsed from an address of the form
+/// `s3://bucket/optional/prefix`, `gs://bucket/optional/prefix`, or
+/// `static+https://host:port/optional/prefix` (or `static+http://`).
+///
+/// S3 buckets are addressed in the virtual-hosted style in the region named by `AWS_REGION` (or
+/// `AWS_DEFAULT_REGION`), unless `AWS_ENDPOINT_URL` names another S3-compatible service, in which
+/// case they are addressed in the path style. GCS buckets are addressed via its XML API.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ObjectStoreAddress {
+  pub kind: ObjectStoreKind,
+  pub base_url: Url,
+  pub prefix: String,
+  pub region: String,
+}
+
+impl ObjectStoreAddress {
+  pub fn parse(address: &str, env: &impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+    let (scheme, rest) = address
+      .split_once("://")
+      .ok_or_else(|| format!("Object store address `{address}` does not have a scheme."))?;
+    // NB: For static HTTP servers, the "bucket" is the host (and port).
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+      return Err(format!(
+        "Object store address `{address}` does not name a bucket or host."
+      ));
+    }
+    let prefix = prefix
+      .split('/')
+      .filter(|segment| !segment.is_empty())
+      .map(|segment| format!("{}/", uri_encode(segment)))
+      .collect::<String>();
+
+    let (kind, region, base_url) = match scheme {
+      "static+http" | "static+https" => (
+        ObjectStoreKind::StaticHttp,
+        String::new(),
+        format!("{}://{bucket}/", &scheme["static+".len()..]),
+      ),
+      "s3" => {
+        let region = env("AWS_REGION")
+          .or_else(|| env("AWS_DEFAULT_REGION"))
+          .unwrap_or_else(|| "us-east-1".to_owned());
+        let base_url = match env("AWS_ENDPOINT_URL") {
+          Some(endpoint) => format!("{}/{bucket}/", endpoint.trim_end_matches('/')),
+          None => format!("https://{bucket}.s3.{region}.amazonaws.com/"),
+        };
+        (ObjectStoreKind::Bucket, region, base_url)
+      }
+      "gs" => (
+        ObjectStoreKind::Bucket,
+        "auto".to_owned(),
+        format!("https://storage.googleapis.com/{bucket}/"),
+      ),
+      scheme => {
+        return Err(format!(
+          "Unsupported object store scheme `{scheme}` in `{address}`: expected one of `s3`, \
+           `gs`, `static+http` or `static+https`."
+        ))
+      }
+    };
+    let base_url = Url::parse(&base_url)
+      .map_err(|e| format!("Invalid object store address `{address}`: {e}"))?;
+    Ok(Self {
+      kind,
+      base_url,
+      prefix,
+      region,
+    })
+  }
+
+  ///
+  /// The URL of the given key with the given query parameters, and the canonical form of the
+  /// query string which is signed.
+  ///
+  pub(crate) fn url(&self, key: &str, query: &[(&str, &str)]) -> Result<(Url, String), String> {
+    let mut query = query
+      .iter()
+      .map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value)))
+      .collect::<Vec<_>>();
+    query.sort();
+    let canonical_query = query.join("&");
+
+    let mut url = self
+      .base_url
+      .join(&format!("{}{key}", self.prefix))
+      .map_err(|e| format!("Invalid object store key `{key}`: {e}"))?;
+    if !canonical_query.is_empty() {
+      url.set_query(Some(&canonical_query));
+    }
+    Ok((url, canonical_query))
+  }
+
+  fn host(&self) -> String {
+    let host = self.base_url.host_str().unwrap_or_default();
+    match self.base_url.port() {
+      Some(port) => format!("{host}:{port}"),
+      None => host.to_owned(),
+    }
+  }
+}
+
+///
+/// An object storage service holding blobs as objects: see `ObjectStoreKind`.
+///
+/// Blobs smaller than the part size are stored with a single request, while larger blobs are
+/// stored in buckets with a multipart upload whose parts are uploaded one at a time. The number of requests
+/// in flight at once is bounded, and uploads (but not downloads, which may legitimately take
+/// longer) are subject to a timeout.
+///
+pub(crate) struct ObjectStore {
+  client: reqwest::Client,
+  address: ObjectStoreAddress,
+  credentials: Option<Credentials>,
+  headers: HeaderMap,
+  part_size_bytes: usize,
+  upload_timeout: Duration,
+  request_semaphore: Semaphore,
+}
+
+///
+/// The body of an object which is being downloaded, which holds its request's concurrency permit
+/// until it has been consumed.
+///
+pub(crate) struct ObjectBody<'a> {
+  response: reqwest::Response,
+  _permit: SemaphorePermit<'a>,
+}
+
+impl ObjectBody<'_> {
+  pub async fn chunk(&mut self) -> Result<Option<Bytes>, ObjectStoreError> {
+    Ok(self.response.chunk().await?)
+  }
+}
+
+impl ObjectStore {
+  pub fn supports_address(address: &str) -> bool {
+    ["s3://", "gs://", "static+http://", "static+https://"]
+      .iter()
+      .any(|scheme| address.starts_with(scheme))
+  }
+
+  pub fn new(
+    address: &str,
+    headers: &BTreeMap<String, String>,
+    part_size_bytes: usize,
+    upload_timeout: Duration,
+    concurrency_limit: usize,
+  ) -> Result<Self, String> {
+    let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let client = reqwest::Client::builder()
+      .build()
+      .map_err(|e| format!("Failed to create a client for {address}: {e}"))?;
+    let address = ObjectStoreAddress::parse(address, &env)?;
+    let credentials = match address.kind {
+      ObjectStoreKind::Bucket => Credentials::from_env(&env),
+      // NB: Static servers may instead be authenticated via the configured headers.
+      ObjectStoreKind::StaticHttp => None,
+    };
+    Ok(Self {
+      client,
+      address,
+      credentials,
+      headers: grpc_util::headers_to_http_header_map(headers)?,
+      part_size_bytes: part_size_bytes.max(MIN_PART_SIZE_BYTES),
+      upload_timeout,
+      request_semaphore: Semaphore::new(concurrency_limit.max(1)),
+    })
+  }
+
+  async fn permit(&self) -> Result<SemaphorePermit<'_>, ObjectStoreError> {
+    self
+      .request_semaphore
+      .acquire()
+      .await
+      .map_err(|e| ObjectStoreError::permanent(format!("Failed to acquire request permit: {e}")))
+  }
+
+  ///
+  /// Signs and sends a request for the given key. The caller should hold a permit.
+  ///
+  async fn send(
+    &self,
+    method: Method,
+    key: &str,
+    query: &[(&str, &str)],
+    body: Option<Bytes>,
+  ) -> Result<reqwest::Response, ObjectStoreError> {
+    let (url, canonical_query) = self
+      .address
+      .url(key, query)
+      .map_err(ObjectStoreError::permanent)?;
+
+    let mut request = self
+      .client
+      .request(method.clone(), url.clone())
+      .headers(self.headers.clone());
+    if let Some(credentials) = &self.credentials {
+      let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+      let mut signed_headers = BTreeMap::new();
+      signed_headers.insert("host".to_owned(), self.address.host());
+      signed_headers.insert(
+        "x-amz-content-sha256".to_owned(),
+        UNSIGNED_PAYLOAD.to_owned(),
+      );
+      signed_headers.insert("x-amz-date".to_owned(), amz_date.clone());
+      if let Some(session_token) = &credentials.session_token {
+        signed_headers.insert("x-amz-security-token".to_owned(), session_token.clone());
+      }
+
+      for (name, value) in &signed_headers {
+        // NB: The client sets the host header from the URL.
+        if name != "host" {
+          request = request.header(name, value);
+        }
+      }
+      request = request.header(
+        AUTHORIZATION,
+        authorization(
+          credentials,
+          &self.address.region,
+          method.as_str(),
+          url.path(),
+          &canonical_query,
+          &signed_headers,
+          UNSIGNED_PAYLOAD,
+          &amz_date,
+        ),
+      );
+    }
+    if let Some(body) = body {
+      request = request.body(body).timeout(self.upload_timeout);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() || status == StatusCode::NOT_FOUND {
+      return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(ObjectStoreError {
+      message: format!("{method} of {url} failed with {status}: {body}"),
+      retryable: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+    })
+  }
+
+  ///
+  /// Returns true if an object exists for the given key.
+  ///
+  pub async fn exists(&self, key: &str) -> Result<bool, ObjectStoreError> {
+    let _permit = self.permit().await?;
+    let response = self.send(Method::HEAD, key, &[], None).await?;
+    Ok(response.status() != StatusCode::NOT_FOUND)
+  }
+
+  ///
+  /// Begins downloading the object for the given key, if it exists.
+  ///
+  pub async fn get(&self, key: &str) -> Result<Option<ObjectBody<'_>>, ObjectStoreError> {
+    let permit = self.permit().await?;
+    let response = self.send(Method::GET, key, &[], None).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+    Ok(Some(ObjectBody {
+      response,
+      _permit: permit,
+    }))
+  }
+
+  ///
+  /// Stores the given bytes (of the given length) as the object for the given key.
+  ///
+  pub async fn put<ByteSource>(
+    &self,
+    key: &str,
+    len: usize,
+    bytes: &ByteSource,
+  ) -> Result<(), ObjectStoreError>
+  where
+    ByteSource: Fn(Range<usize>) -> Bytes,
+  {
+    if len <= self.part_size_bytes || self.address.kind == ObjectStoreKind::StaticHttp {
+      let _permit = self.permit().await?;
+      self
+        .send(Method::PUT, key, &[], Some(bytes(0..len)))
+        .await
+        .and_then(|response| not_found_to_error(response, key))?;
+      return Ok(());
+    }
+
+    let upload_id = self.create_multipart_upload(key).await?;
+    let result = match self.upload_parts(key, &upload_id, len, bytes).await {
+      Ok(etags) => self.complete_multipart_upload(key, &upload_id, etags).await,
+      Err(e) => Err(e),
+    };
+    if result.is_err() {
+      // Abort the upload so that its parts are not retained (and billed for). This is best effort:
+      // buckets may also be configured to clean up incomplete uploads.
+      let abort = async {
+        let _permit = self.permit().await?;
+        self
+          .send(Method::DELETE, key, &[("uploadId", &upload_id)], None)
+          .await
+      };
+      if let Err(e) = abort.await {
+        log::debug!("Failed to abort multipart upload of {key}: {e}");
+      }
+    }
+    result
+  }
+
+  async fn create_multipart_upload(&self, key: &str) -> Result<String, ObjectStoreError> {
+    let _permit = self.permit().await?;
+    let response = self
+      .send(Method::POST, key, &[("uploads", "")], Some(Bytes::new()))
+      .await
+      .and_then(|response| not_found_to_error(response, key))?;
+    let body = response.text().await?;
+    xml_element(&body, "UploadId").ok_or_else(|| {
+      ObjectStoreError::transient(format!(
+        "Creating a multipart upload of {key} did not return an upload id: {body}"
+      ))
+    })
+  }
+
+  ///
+  /// Uploads the parts of a multipart upload, and returns their ETags.
+  ///
+  async fn upload_parts<ByteSource>(
+    &self,
+    key: &str,
+    upload_id: &str,
+    len: usize,
+    bytes: &ByteSource,
+  ) -> Result<Vec<String>, ObjectStoreError>
+  where
+    ByteSource: Fn(Range<usize>) -> Bytes,
+  {
+    let mut etags = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+      let next_offset = std::cmp::min(offset + self.part_size_bytes, len);
+      let part_number = (etags.len() + 1).to_string();
+      let _permit = self.permit().await?;
+      let response = self
+        .send(
+          Method::PUT,
+          key,
+          &[("partNumber", &part_number), ("uploadId", upload_id)],
+          Some(bytes(offset..next_offset)),
+        )
+        .await
+        .and_then(|response| not_found_to_error(response, key))?;
+      let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .ok_or_else(|| {
+          ObjectStoreError::transient(format!(
+            "Uploading part {part_number} of {key} did not return an ETag."
+          ))
+        })?;
+      etags.push(etag.to_owned());
+      offset = next_offset;
+    }
+    Ok(etags)
+  }
+
+  async fn complete_multipart_upload(
+    &self,
+    key: &str,
+    upload_id: &str,
+    etags: Vec<String>,
+  ) -> Result<(), ObjectStoreError> {
+    let mut body = "<CompleteMultipartUpload>".to_owned();
+    for (index, etag) in etags.iter().enumerate() {
+      body.push_str(&format!(
+        "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+        index + 1
+      ));
+    }
+    body.push_str("</CompleteMultipartUpload>");
+
+    let _permit = self.permit().await?;
+    let response = self
+      .send(
+        Method::POST,
+        key,
+        &[("uploadId", upload_id)],
+        Some(Bytes::from(body)),
+      )
+      .await
+      .and_then(|response| not_found_to_error(response, key))?;
+    // NB: Completing an upload may fail after the response has begun, in which case the status is
+    // successful but the body holds an error.
+    let body = response.text().await?;
+    if body.contains("<Error>") {
+      return Err(ObjectStoreError::transient(format!(
+        "Completing the multipart upload of {key} failed: {body}"
+      )));
+    }
+    Ok(())
+  }
+}
+
+fn not_found_to_error(
+  response: reqwest::Response,
+  key: &str,
+) -> Result<reqwest::Response, ObjectStoreError> {
+  if response.status() == StatusCode::NOT_FOUND {
+    Err(ObjectStoreError::permanent(format!(
+      "Failed to store {key}: the bucket or path does not exist."
+    )))
+  } else {
+    Ok(response)
+  }
+}
+
+///
+/// The text of the first element with the given name in the given XML document. Object stores'
+/// responses are simple enough that they do not warrant an XML parser.
+///
+pub(crate) fn xml_element(document: &str, name: &str) -> Option<String> {
+  let start = document.find(&format!("<{name}>"))? + name.len() + 2;
+  let end = start + document[start..].find(&format!("</{name}>"))?;
+  Some(document[start..end].to_owned())
+}
+
+///
+/// Percent-encodes everything except the characters which are unreserved in URIs, as AWS
+/// Signature Version 4 requires.
+///
+pub(crate) fn uri_encode(s: &str) -> String {
+  let mut encoded = String::with_capacity(s.len());
+  for byte in s.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        encoded.push(byte as char)
+      }
+      byte => encoded.push_str(&format!("%{byte:02X}")),
+    }
+  }
+  encoded
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+  const BLOCK_SIZE_BYTES: usize = 64;
+  let mut block = [0_u8; BLOCK_SIZE_BYTES];
+  if key.len() > BLOCK_SIZE_BYTES {
+    block[..32].copy_from_slice(&Sha256::digest(key));
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+
+  let mut inner = Sha256::new();
+  inner.update(block.map(|b| b ^ 0x36));
+  inner.update(data);
+  let mut outer = Sha256::new();
+  outer.update(block.map(|b| b ^ 0x5c));
+  outer.update(inner.finalize());
+  outer.finalize().into()
+}
+
+///
+/// Computes the value of the authorization header for a request, as described in
+/// https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html. The given headers
+/// must have lowercase names, and must include `host` and `x-amz-date`.
+///
+pub(crate) fn authorization(
+  credentials: &Credentials,
+  region: &str,
+  method: &str,
+  canonical_uri: &str,
+  canonical_query: &str,
+  headers: &BTreeMap<String, String>,
+  payload_hash: &str,
+  amz_date: &str,
+) -> String {
+  let canonical_headers = headers
+    .iter()
+    .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+    .collect::<String>();
+  let signed_headers = headers.keys().cloned().collect::<Vec<_>>().join(";");
+  let canonical_request = format!(
+    "{method}\n{canonical_uri}\n{canonical_query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+  );
+
+  let date = &amz_date[..8];
+  let scope = format!("{date}/{region}/s3/aws4_request");
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+    hex::encode(Sha256::digest(canonical_request.as_bytes()))
+  );
+
+  let signing_key = [region, "s3", "aws4_request"].iter().fold(
+    hmac_sha256(
+      format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+      date.as_bytes(),
+    ),
+    |key, part| hmac_sha256(&key, part.as_bytes()),
+  );
+  let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+  format!(
+    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+    credentials.access_key_id
+  )
+}
@@ -0,0 +1,544 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Stream, StreamExt};
+use hashing::{Digest, DigestFunction, Fingerprint, Hasher};
+use parking_lot::Mutex;
+use protos::gen::google::bytestream::byte_stream_client::ByteStreamClient;
+use protos::gen::google::bytestream::byte_stream_server::{ByteStream, ByteStreamServer};
+use protos::gen::google::bytestream::{
+  QueryWriteStatusRequest, QueryWriteStatusResponse, ReadRequest, ReadResponse, WriteRequest,
+  WriteResponse,
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+use workunit_store::Metric;
+
+use crate::local;
+use crate::mdns::{self, Record};
+use crate::object_store::hmac_sha256;
+
+/// The DNS-SD service type under which engines advertise their local stores.
+const SERVICE: &str = "_pants-cas._tcp.local";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+// Peers which miss a few announcements in a row are forgotten.
+const ANNOUNCEMENT_TTL: Duration = Duration::from_secs(30);
+const CHUNK_SIZE_BYTES: usize = 1024 * 1024;
+// Announcements from further peers are ignored until some of the known peers expire.
+const MAX_PEERS: usize = 64;
+const AUTHORIZATION_HEADER: &str = "x-pants-lan-cache-authorization";
+// Requests which were signed further than this from the current time are rejected, which bounds
+// how long an observed request may be replayed.
+const AUTHORIZATION_WINDOW: Duration = Duration::from_secs(60);
+
+///
+/// Shares the local stores of engines on the same LAN, as a read-through layer in front of a
+/// remote store.
+///
+/// Each engine serves (read-only) ByteStream reads of its local store on a TCP port of a
+/// configured interface, and advertises that port via multicast DNS on the same interface. Blobs
+/// are loaded from whichever peer responds first, and are verified against their digests, so
+/// peers need not be trusted for correctness. Errors and timeouts from peers are logged rather
+/// than failing requests, which then fall through to the remote store.
+///
+/// Engines only serve reads which are signed with a secret shared by all of the peers: see
+/// `authorization`. The secret itself is never sent, so advertising a peer does not reveal it.
+///
+pub(crate) struct PeerCache {
+  peers: Arc<Mutex<HashMap<String, Peer>>>,
+  secret: Arc<String>,
+  timeout: Duration,
+  task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct Peer {
+  address: SocketAddr,
+  expires: Instant,
+  client: ByteStreamClient<Channel>,
+}
+
+impl PeerCache {
+  ///
+  /// Starts serving the given local store to peers on the given address and port (or an
+  /// ephemeral port, if zero), and discovering the peers which serve theirs on the interface with
+  /// that address. Only peers which share the given secret may read the local store, and only
+  /// their stores can be read. Requests to peers which take longer than the given timeout are
+  /// abandoned.
+  ///
+  pub fn new(
+    local: local::ByteStore,
+    address: Ipv4Addr,
+    port: u16,
+    secret: String,
+    timeout: Duration,
+  ) -> Self {
+    let peers = Arc::default();
+    let secret = Arc::new(secret);
+    let task = local.executor().native_spawn(Self::run(
+      local.clone(),
+      address,
+      port,
+      secret.clone(),
+      peers.clone(),
+      timeout,
+    ));
+    Self {
+      peers,
+      secret,
+      timeout,
+      task,
+    }
+  }
+
+  async fn run(
+    local: local::ByteStore,
+    address: Ipv4Addr,
+    port: u16,
+    secret: Arc<String>,
+    peers: Arc<Mutex<HashMap<String, Peer>>>,
+    timeout: Duration,
+  ) {
+    let listener = match TcpListener::bind((address, port)).await {
+      Ok(listener) => listener,
+      Err(e) => {
+        log::warn!("Failed to listen for LAN cache peers on {address}:{port}: {e}");
+        return;
+      }
+    };
+    let port = match listener.local_addr() {
+      Ok(address) => address.port(),
+      Err(e) => {
+        log::warn!("Failed to get the port of the LAN cache: {e}");
+        return;
+      }
+    };
+    let socket = match mdns::bind(address) {
+      Ok(socket) => socket,
+      Err(e) => {
+        log::warn!("Failed to bind the mDNS socket for discovering LAN cache peers: {e}");
+        return;
+      }
+    };
+    log::debug!("Serving the local store to LAN cache peers on {address}:{port}.");
+
+    future::join(
+      Self::serve(local, listener, secret),
+      Self::discover(&socket, port, &peers, timeout),
+    )
+    .await;
+  }
+
+  pub(crate) async fn serve(local: local::ByteStore, listener: TcpListener, secret: Arc<String>) {
+    let incoming = async_stream::stream! {
+      loop {
+        yield listener.accept().await.map(|(stream, _)| stream);
+      }
+    };
+    let mut server = Server::builder();
+    let router = server.add_service(ByteStreamServer::new(PeerServer { local, secret }));
+    if let Err(e) = router.serve_with_incoming(incoming).await {
+      log::warn!("The LAN cache stopped serving peers: {e}");
+    }
+  }
+
+  ///
+  /// Periodically announces this engine (and asks for others), and tracks (up to `MAX_PEERS` of)
+  /// the peers which announce themselves.
+  ///
+  async fn discover(
+    socket: &UdpSocket,
+    port: u16,
+    peers: &Mutex<HashMap<String, Peer>>,
+    timeout: Duration,
+  ) {
+    let instance = Uuid::new_v4().simple().to_string();
+    let query = mdns::encode_query(SERVICE);
+    let announcement =
+      mdns::encode_announcement(SERVICE, &instance, port, ANNOUNCEMENT_TTL.as_secs() as u32);
+    let announce = async {
+      let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+      loop {
+        interval.tick().await;
+        send(socket, &query).await;
+        send(socket, &announcement).await;
+        let now = Instant::now();
+        peers.lock().retain(|_, peer| peer.expires > now);
+      }
+    };
+
+    let listen = async {
+      let mut buf = vec![0; 9000];
+      loop {
+        let (len, source) = match socket.recv_from(&mut buf).await {
+          Ok(received) => received,
+          Err(e) => {
+            log::debug!("Failed to receive an mDNS message: {e}");
+            continue;
+          }
+        };
+        let records = match mdns::decode(&buf[..len]) {
+          Ok(records) => records,
+          Err(e) => {
+            log::trace!("Ignoring a malformed mDNS message from {source}: {e}");
+            continue;
+          }
+        };
+        for record in records {
+          match record {
+            Record::Query { service } if service == SERVICE => send(socket, &announcement).await,
+            Record::Pointer {
+              service,
+              instance: peer,
+              ttl: 0,
+            } if service == SERVICE => {
+              // The peer has withdrawn.
+              if let Some(name) = instance_name(&peer) {
+                peers.lock().remove(name);
+              }
+            }
+            Record::Service {
+              instance: peer,
+              port,
+              ttl,
+            } => {
+              let Some(name) = instance_name(&peer).filter(|name| *name != instance) else {
+                continue;
+              };
+              let address = SocketAddr::new(source.ip(), port);
+              let expires = Instant::now() + Duration::from_secs(ttl.into());
+              let mut peers = peers.lock();
+              if !peers.contains_key(name) && peers.len() >= MAX_PEERS {
+                let now = Instant::now();
+                peers.retain(|_, peer| peer.expires > now);
+                if peers.len() >= MAX_PEERS {
+                  log::debug!(
+                    "Ignoring the LAN cache peer at {address}: {MAX_PEERS} peers are already known."
+                  );
+                  continue;
+                }
+              }
+              match peers.get_mut(name) {
+                Some(peer) if peer.address == address => peer.expires = expires,
+                _ => match Self::client(address, timeout) {
+                  Ok(client) => {
+                    log::debug!("Discovered a LAN cache peer at {address}.");
+                    peers.insert(
+                      name.to_owned(),
+                      Peer {
+                        address,
+                        expires,
+                        client,
+                      },
+                    );
+                  }
+                  Err(e) => {
+                    log::debug!("Failed to connect to the LAN cache peer at {address}: {e}")
+                  }
+                },
+              }
+            }
+            _ => (),
+          }
+        }
+      }
+    };
+
+    future::join(announce, listen).await;
+  }
+
+  fn client(address: SocketAddr, timeout: Duration) -> Result<ByteStreamClient<Channel>, String> {
+    let endpoint = Endpoint::from_shared(format!("http://{address}"))
+      .map_err(|e| e.to_string())?
+      .timeout(timeout);
+    Ok(ByteStreamClient::new(Channel::balance_list(
+      vec![endpoint].into_iter(),
+    )))
+  }
+
+  ///
+  /// Adds a peer directly, rather than via discovery.
+  ///
+  #[cfg(test)]
+  pub(crate) fn add_peer(&self, address: SocketAddr) {
+    let peer = Peer {
+      address,
+      expires: Instant::now() + ANNOUNCEMENT_TTL,
+      client: Self::client(address, self.timeout).unwrap(),
+    };
+    self.peers.lock().insert(address.to_string(), peer);
+  }
+
+  ///
+  /// True if any peers are known.
+  ///
+  pub fn has_peers(&self) -> bool {
+    let now = Instant::now();
+    self.peers.lock().values().any(|peer| peer.expires > now)
+  }
+
+  ///
+  /// Writes the given blob to the destination from the first peer which has it, and returns true.
+  /// Otherwise returns false, in which case partial content may have been written.
+  ///
+  pub async fn load(
+    &self,
+    digest: Digest,
+    digest_function: DigestFunction,
+    destination: &mut (dyn AsyncWrite + Send + Unpin),
+  ) -> bool {
+    let result = self
+      .load_from_peers(digest, digest_function, destination)
+      .await;
+    let metric = match result {
+      Ok(address) => {
+        log::trace!("Loaded {digest:?} from the LAN cache peer at {address}.");
+        Metric::RemoteStorePeerCacheHits
+      }
+      Err(e) => {
+        log::trace!("Failed to load {digest:?} from LAN cache peers: {e}");
+        Metric::RemoteStorePeerCacheMisses
+      }
+    };
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle.store.increment_counter(metric, 1);
+    }
+    result.is_ok()
+  }
+
+  async fn load_from_peers(
+    &self,
+    digest: Digest,
+    digest_function: DigestFunction,
+    destination: &mut (dyn AsyncWrite + Send + Unpin),
+  ) -> Result<SocketAddr, String> {
+    let now = Instant::now();
+    let peers = self
+      .peers
+      .lock()
+      .values()
+      .filter(|peer| peer.expires > now)
+      .cloned()
+      .collect::<Vec<_>>();
+    if peers.is_empty() {
+      return Err("No peers are known.".to_owned());
+    }
+
+    // Ask every peer for the blob, and continue reading from the first which responds with it.
+    let resource_name = format!("blobs/{}/{}", digest.hash, digest.size_bytes);
+    let signature = MetadataValue::from_str(&authorization(
+      self.secret.as_bytes(),
+      &resource_name,
+      unix_time_secs(),
+    ))
+    .map_err(|e| format!("Failed to sign a request: {e}"))?;
+    let reads = peers.into_iter().map(|peer| {
+      let mut request = Request::new(ReadRequest {
+        resource_name: resource_name.clone(),
+        read_offset: 0,
+        read_limit: 0,
+      });
+      request
+        .metadata_mut()
+        .insert(AUTHORIZATION_HEADER, signature.clone());
+      Box::pin(async move {
+        let mut client = peer.client;
+        let mut stream = client
+          .read(request)
+          .await
+          .map_err(|status| status.to_string())?
+          .into_inner();
+        let first = stream
+          .message()
+          .await
+          .map_err(|status| status.to_string())?;
+        Ok::<_, String>((peer.address, stream, first))
+      })
+    });
+    let ((address, mut stream, mut response), _) =
+      tokio::time::timeout(self.timeout, future::select_ok(reads))
+        .await
+        .map_err(|_| format!("Timed out after {:?}.", self.timeout))??;
+
+    let mut hasher = Hasher::new_with(digest_function);
+    while let Some(ReadResponse { data }) = response {
+      hasher.update(&data);
+      destination
+        .write_all(&data)
+        .await
+        .map_err(|e| format!("Failed to write {digest:?}: {e}"))?;
+      response = tokio::time::timeout(self.timeout, stream.message())
+        .await
+        .map_err(|_| format!("Reading from {address} timed out after {:?}.", self.timeout))?
+        .map_err(|status| format!("Failed to read from {address}: {status}"))?;
+    }
+    if hasher.finish() != digest {
+      return Err(format!("The peer at {address} returned the wrong content."));
+    }
+    Ok(address)
+  }
+}
+
+impl Drop for PeerCache {
+  fn drop(&mut self) {
+    self.task.abort();
+  }
+}
+
+///
+/// Sends the given message to the mDNS multicast group.
+///
+async fn send(socket: &UdpSocket, message: &[u8]) {
+  let group = SocketAddr::from((mdns::MDNS_ADDR, mdns::MDNS_PORT));
+  if let Err(e) = socket.send_to(message, group).await {
+    log::debug!("Failed to send an mDNS message: {e}");
+  }
+}
+
+///
+/// The name of an instance of the service, given its full name.
+///
+fn instance_name(full_name: &str) -> Option<&str> {
+  full_name
+    .strip_suffix(SERVICE)
+    .and_then(|name| name.strip_suffix('.'))
+    .filter(|name| !name.is_empty())
+}
+
+fn unix_time_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+///
+/// The value of the authorization header of a read of the given resource at the given time: the
+/// time, and an HMAC-SHA256 of the time and the resource name keyed by the shared secret.
+///
+fn authorization(secret: &[u8], resource_name: &str, timestamp_secs: u64) -> String {
+  let signature = hmac_sha256(
+    secret,
+    format!("{timestamp_secs}:{resource_name}").as_bytes(),
+  );
+  format!("{timestamp_secs}:{}", hex::encode(signature))
+}
+
+///
+/// Checks that the given authorization header of a read of the given resource was signed with the
+/// shared secret within `AUTHORIZATION_WINDOW` of the current time.
+///
+fn verify_authorization(
+  secret: &[u8],
+  resource_name: &str,
+  header: Option<&str>,
+) -> Result<(), String> {
+  let header = header.ok_or_else(|| "The request is not signed.".to_owned())?;
+  let timestamp_secs = header
+    .split_once(':')
+    .and_then(|(timestamp, _)| timestamp.parse::<u64>().ok())
+    .ok_or_else(|| "The request has a malformed signature.".to_owned())?;
+  if unix_time_secs().abs_diff(timestamp_secs) > AUTHORIZATION_WINDOW.as_secs() {
+    return Err("The request was not signed recently.".to_owned());
+  }
+  let expected = authorization(secret, resource_name, timestamp_secs);
+  // NB: Compare in constant time, so that the signature cannot be guessed byte by byte.
+  let matches = expected.len() == header.len()
+    && expected
+      .bytes()
+      .zip(header.bytes())
+      .fold(0, |difference, (a, b)| difference | (a ^ b))
+      == 0;
+  if matches {
+    Ok(())
+  } else {
+    Err("The request has an invalid signature.".to_owned())
+  }
+}
+
+///
+/// Parses a resource name of the form `blobs/{hash}/{size}`.
+///
+fn parse_resource_name(resource_name: &str) -> Result<Digest, String> {
+  let parts = resource_name.split('/').collect::<Vec<_>>();
+  let ["blobs", hash, size] = parts[..] else {
+    return Err(format!("Malformed resource name: {resource_name}"));
+  };
+  let hash = Fingerprint::from_hex_string(hash)?;
+  let size = size
+    .parse()
+    .map_err(|e| format!("Malformed size in resource name {resource_name}: {e}"))?;
+  Ok(Digest::new(hash, size))
+}
+
+///
+/// Serves reads of a local store to peers.
+///
+struct PeerServer {
+  local: local::ByteStore,
+  secret: Arc<String>,
+}
+
+#[tonic::async_trait]
+impl ByteStream for PeerServer {
+  type ReadStream = Pin<Box<dyn Stream<Item = Result<ReadResponse, Status>> + Send>>;
+
+  async fn read(
+    &self,
+    request: Request<ReadRequest>,
+  ) -> Result<Response<Self::ReadStream>, Status> {
+    verify_authorization(
+      self.secret.as_bytes(),
+      &request.get_ref().resource_name,
+      request
+        .metadata()
+        .get(AUTHORIZATION_HEADER)
+        .and_then(|value| value.to_str().ok()),
+    )
+    .map_err(Status::unauthenticated)?;
+    let request = request.into_inner();
+    if request.read_offset != 0 || request.read_limit != 0 {
+      return Err(Status::unimplemented("Partial reads are not supported."));
+    }
+    let digest = parse_resource_name(&request.resource_name).map_err(Status::invalid_argument)?;
+    let entry_type = self
+      .local
+      .entry_type(digest.hash)
+      .await
+      .map_err(Status::internal)?
+      .ok_or_else(|| Status::not_found(format!("{digest:?} is not present.")))?;
+    let chunks = self
+      .local
+      .stream_chunks(entry_type, digest, CHUNK_SIZE_BYTES)
+      .map(|chunk| {
+        chunk
+          .map(|data| ReadResponse { data })
+          .map_err(Status::internal)
+      });
+    Ok(Response::new(Box::pin(chunks)))
+  }
+
+  async fn write(
+    &self,
+    _: Request<Streaming<WriteRequest>>,
+  ) -> Result<Response<WriteResponse>, Status> {
+    Err(Status::unimplemented("The LAN cache is read-only."))
+  }
+
+  async fn query_write_status(
+    &self,
+    _: Request<QueryWriteStatusRequest>,
+  ) -> Result<Response<QueryWriteStatusResponse>, Status> {
+    Err(Status::unimplemented("The LAN cache is read-only."))
+  }
+}
@@ -0,0 +1,112 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hashing::DigestFunction;
+use tempfile::TempDir;
+use testutil::data::TestData;
+use tokio::net::TcpListener;
+use workunit_store::WorkunitStore;
+
+use crate::local_tests::new_store;
+use crate::peer_cache::PeerCache;
+use crate::EntryType;
+
+const SECRET: &str = "hunter2";
+
+///
+/// Serves a store containing the given data to peers which share `SECRET`.
+///
+async fn serve(dir: &TempDir, testdata: &TestData) -> SocketAddr {
+  let served = new_store(dir.path());
+  served
+    .store_bytes(
+      EntryType::File,
+      testdata.fingerprint(),
+      testdata.bytes(),
+      false,
+    )
+    .await
+    .unwrap();
+  let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+  let address = listener.local_addr().unwrap();
+  tokio::spawn(PeerCache::serve(
+    served,
+    listener,
+    Arc::new(SECRET.to_owned()),
+  ));
+  address
+}
+
+fn new_cache(dir: &TempDir, secret: &str) -> PeerCache {
+  PeerCache::new(
+    new_store(dir.path()),
+    Ipv4Addr::LOCALHOST,
+    0,
+    secret.to_owned(),
+    Duration::from_secs(5),
+  )
+}
+
+#[tokio::test]
+async fn loads_from_peers() {
+  let _ = WorkunitStore::setup_for_tests();
+  let testdata = TestData::roland();
+
+  let served_dir = TempDir::new().unwrap();
+  let address = serve(&served_dir, &testdata).await;
+
+  let dir = TempDir::new().unwrap();
+  let cache = new_cache(&dir, SECRET);
+  assert!(!cache.has_peers());
+  let mut destination = Vec::new();
+  assert!(
+    !cache
+      .load(testdata.digest(), DigestFunction::Sha256, &mut destination)
+      .await
+  );
+
+  cache.add_peer(address);
+  assert!(cache.has_peers());
+  assert!(
+    cache
+      .load(testdata.digest(), DigestFunction::Sha256, &mut destination)
+      .await
+  );
+  assert_eq!(destination, testdata.bytes());
+
+  // Blobs which no peer has are not loaded.
+  let mut destination = Vec::new();
+  assert!(
+    !cache
+      .load(
+        TestData::catnip().digest(),
+        DigestFunction::Sha256,
+        &mut destination
+      )
+      .await
+  );
+  assert!(destination.is_empty());
+}
+
+#[tokio::test]
+async fn does_not_load_from_peers_with_another_secret() {
+  let _ = WorkunitStore::setup_for_tests();
+  let testdata = TestData::roland();
+
+  let served_dir = TempDir::new().unwrap();
+  let address = serve(&served_dir, &testdata).await;
+
+  let dir = TempDir::new().unwrap();
+  let cache = new_cache(&dir, "not the secret");
+  cache.add_peer(address);
+  let mut destination = Vec::new();
+  assert!(
+    !cache
+      .load(testdata.digest(), DigestFunction::Sha256, &mut destination)
+      .await
+  );
+  assert!(destination.is_empty());
+}
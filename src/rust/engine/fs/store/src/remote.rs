@@ -1,10 +1,12 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 use std::cmp::min;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fmt;
+use std::io::Write;
 use std::ops::Range;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -12,38 +14,118 @@ use async_oncecell::OnceCell;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::StreamExt;
-use futures::{Future, FutureExt};
-use grpc_util::retry::{retry_call, status_is_retryable};
+use futures::{future, Future, FutureExt};
+use grpc_util::retry::RetryPolicy;
 use grpc_util::{
   headers_to_http_header_map, layered_service, status_ref_to_str, status_to_str, LayeredService,
 };
-use hashing::{Digest, Hasher};
+use hashing::{Digest, DigestFunction, Hasher};
 use log::Level;
 use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use protos::gen::google::bytestream::byte_stream_client::ByteStreamClient;
+use protos::gen::google::bytestream::QueryWriteStatusRequest;
+use protos::require_digest;
 use remexec::{
   capabilities_client::CapabilitiesClient,
   content_addressable_storage_client::ContentAddressableStorageClient, BatchUpdateBlobsRequest,
   ServerCapabilities,
 };
-use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio::sync::Mutex;
 use tonic::{Code, Request, Status};
-use workunit_store::{in_workunit, ObservationMetric};
+use uuid::Uuid;
+use workunit_store::{in_workunit, Metric, ObservationMetric, RunningWorkunit};
 
+use crate::directory_store::DirectoryStore;
+use crate::object_store::{ObjectStore, ObjectStoreError};
+use crate::peer_cache::PeerCache;
+use crate::small_blob_cache::SmallBlobCache;
+use crate::transfer_limits::{throttle_writes, TransferLimits};
 use crate::StoreError;
 
+/// The zstd level at which blobs are compressed for transfer: zstd's default, which favors speed.
+const ZSTD_COMPRESSION_LEVEL: i32 = 0;
+
+/// The size of the buffer through which blobs are loaded into files, so that the (typically small)
+/// chunks of a stream are not each written by a separate blocking task.
+const FILE_WRITE_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// An estimate of the protobuf framing of each blob in a batch request or response, beyond its
+/// content and the hex of its hash: the tags and lengths of the entry, its digest and its fields,
+/// the varint of its size, and its compressor.
+const BATCH_ENTRY_FRAMING_BYTES: usize = 32;
+
+/// An estimate of the protobuf framing of a batch request, beyond its instance name and entries.
+const BATCH_REQUEST_FRAMING_BYTES: usize = 16;
+
+/// The maximum number of blobs in a single batch request, so that batches of many tiny (or empty)
+/// blobs are bounded even though their total size is not.
+pub(crate) const MAX_BATCH_ENTRIES: usize = 1000;
+
 #[derive(Clone)]
 pub struct ByteStore {
   instance_name: Option<String>,
   chunk_size_bytes: usize,
   _upload_timeout: Duration,
-  _rpc_attempts: usize,
-  byte_stream_client: Arc<ByteStreamClient<LayeredService>>,
-  cas_client: Arc<ContentAddressableStorageClient<LayeredService>>,
+  retry_policy: RetryPolicy,
+  write_retry_policy: RetryPolicy,
+  backend: Backend,
+  // See `ByteStore::with_small_blob_cache`.
+  small_blob_cache: Option<Arc<SmallBlobCache>>,
+  // See `ByteStore::with_peer_cache`.
+  peer_cache: Option<Arc<PeerCache>>,
+  // See `ByteStore::with_transfer_limits`.
+  transfer_limits: Arc<TransferLimits>,
   capabilities_cell: Arc<OnceCell<ServerCapabilities>>,
-  capabilities_client: Arc<CapabilitiesClient<LayeredService>>,
   batch_api_size_limit: usize,
+  // See `ByteStore::with_digest_function`.
+  digest_function: DigestFunction,
+}
+
+/// The service which holds the blobs of a remote bytestore.
+#[derive(Clone)]
+enum Backend {
+  /// A REAPI CAS server, accessed via gRPC.
+  Reapi {
+    byte_stream_client: Arc<ByteStreamClient<LayeredService>>,
+    cas_client: Arc<ContentAddressableStorageClient<LayeredService>>,
+    capabilities_client: Arc<CapabilitiesClient<LayeredService>>,
+  },
+  /// An object storage service (or static HTTP server), which holds each blob as an object whose
+  /// key is the blob's resource name.
+  ObjectStore(Arc<ObjectStore>),
+  /// A (typically shared) directory, which holds each blob as a file.
+  Directory(Arc<DirectoryStore>),
+}
+
+///
+/// The identity of an upload across its attempts, so that a failed ByteStream write can be resumed
+/// by a later attempt, which must use the same resource name.
+///
+#[derive(Default)]
+struct Upload {
+  id: parking_lot::Mutex<Option<Uuid>>,
+}
+
+impl Upload {
+  ///
+  /// Returns the id of this upload, and whether an earlier attempt may already have written some
+  /// of it.
+  ///
+  fn resume(&self) -> (Uuid, bool) {
+    let mut id = self.id.lock();
+    match *id {
+      Some(id) => (id, true),
+      None => (*id.insert(Uuid::new_v4()), false),
+    }
+  }
+
+  ///
+  /// Gives this upload a new id, which no earlier attempt has written to.
+  ///
+  fn restart(&self) -> Uuid {
+    *self.id.lock().insert(Uuid::new_v4())
+  }
 }
 
 impl fmt::Debug for ByteStore {
@@ -58,14 +140,18 @@ enum ByteStoreError {
   /// gRPC error
   Grpc(Status),
 
+  /// Object storage error
+  ObjectStore(ObjectStoreError),
+
   /// Other errors
   Other(String),
 }
 
 impl ByteStoreError {
-  fn retryable(&self) -> bool {
+  fn retryable(&self, retry_policy: &RetryPolicy) -> bool {
     match self {
-      ByteStoreError::Grpc(status) => status_is_retryable(status),
+      ByteStoreError::Grpc(status) => retry_policy.status_is_retryable(status),
+      ByteStoreError::ObjectStore(err) => err.retryable,
       ByteStoreError::Other(_) => false,
     }
   }
@@ -77,6 +163,12 @@ impl From<Status> for ByteStoreError {
   }
 }
 
+impl From<ObjectStoreError> for ByteStoreError {
+  fn from(err: ObjectStoreError) -> ByteStoreError {
+    ByteStoreError::ObjectStore(err)
+  }
+}
+
 impl From<String> for ByteStoreError {
   fn from(string: String) -> ByteStoreError {
     ByteStoreError::Other(string)
@@ -92,6 +184,7 @@ impl fmt::Display for ByteStoreError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       ByteStoreError::Grpc(status) => fmt::Display::fmt(&status_ref_to_str(status), f),
+      ByteStoreError::ObjectStore(err) => fmt::Display::fmt(err, f),
       ByteStoreError::Other(msg) => fmt::Display::fmt(msg, f),
     }
   }
@@ -114,6 +207,15 @@ impl LoadDestination for tokio::fs::File {
   }
 }
 
+#[async_trait]
+impl LoadDestination for BufWriter<tokio::fs::File> {
+  async fn reset(&mut self) -> std::io::Result<()> {
+    // NB: A `BufWriter` cannot discard its buffer, so it is flushed before the file is truncated.
+    self.flush().await?;
+    self.get_mut().reset().await
+  }
+}
+
 #[async_trait]
 impl LoadDestination for Vec<u8> {
   async fn reset(&mut self) -> std::io::Result<()> {
@@ -125,18 +227,58 @@ impl LoadDestination for Vec<u8> {
 impl ByteStore {
   // TODO: Consider extracting these options to a struct with `impl Default`, similar to
   // `super::LocalOptions`.
+  ///
+  /// Creates a store backed by the REAPI CAS at the given address or, if the address has an `s3://`,
+  /// `gs://`, `static+http://` or `static+https://` scheme, by the given bucket or HTTP server. See
+  /// `ObjectStoreAddress` for how these are located and accessed: the instance name, TLS config
+  /// and token provider are not used for them. An address with a `file://` scheme names a
+  /// directory: see `DirectoryStore`.
+  ///
   pub fn new(
     cas_address: &str,
     instance_name: Option<String>,
     tls_config: grpc_util::tls::Config,
     mut headers: BTreeMap<String, String>,
+    token_provider: Option<Arc<grpc_util::auth::TokenProvider>>,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
-    rpc_retries: usize,
+    retry_policy: RetryPolicy,
     rpc_concurrency_limit: usize,
     capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
     batch_api_size_limit: usize,
   ) -> Result<ByteStore, String> {
+    let backend = if DirectoryStore::supports_address(cas_address) {
+      Some(Backend::Directory(Arc::new(DirectoryStore::new(
+        cas_address,
+      )?)))
+    } else if ObjectStore::supports_address(cas_address) {
+      Some(Backend::ObjectStore(Arc::new(ObjectStore::new(
+        cas_address,
+        &headers,
+        chunk_size_bytes,
+        upload_timeout,
+        rpc_concurrency_limit,
+      )?)))
+    } else {
+      None
+    };
+    if let Some(backend) = backend {
+      return Ok(ByteStore {
+        instance_name,
+        chunk_size_bytes,
+        _upload_timeout: upload_timeout,
+        retry_policy: retry_policy.clone(),
+        write_retry_policy: retry_policy,
+        backend,
+        small_blob_cache: None,
+        peer_cache: None,
+        transfer_limits: Arc::default(),
+        capabilities_cell: capabilities_cell_opt.unwrap_or_else(|| Arc::new(OnceCell::new())),
+        batch_api_size_limit,
+        digest_function: DigestFunction::Sha256,
+      });
+    }
+
     let tls_client_config = if cas_address.starts_with("https://") {
       Some(tls_config.try_into()?)
     } else {
@@ -150,6 +292,7 @@ impl ByteStore {
       tonic::transport::Channel::balance_list(vec![endpoint].into_iter()),
       rpc_concurrency_limit,
       http_headers,
+      token_provider,
       None,
     );
 
@@ -163,19 +306,237 @@ impl ByteStore {
       instance_name,
       chunk_size_bytes,
       _upload_timeout: upload_timeout,
-      _rpc_attempts: rpc_retries + 1,
-      byte_stream_client,
-      cas_client,
+      retry_policy: retry_policy.clone(),
+      write_retry_policy: retry_policy,
+      backend: Backend::Reapi {
+        byte_stream_client,
+        cas_client,
+        capabilities_client,
+      },
+      small_blob_cache: None,
+      peer_cache: None,
+      transfer_limits: Arc::default(),
       capabilities_cell: capabilities_cell_opt.unwrap_or_else(|| Arc::new(OnceCell::new())),
-      capabilities_client,
       batch_api_size_limit,
+      digest_function: DigestFunction::Sha256,
     })
   }
 
+  ///
+  /// Sets the function which computes the digests of blobs in this store. Functions other than
+  /// SHA-256 must be advertised in the capabilities of the server, which are checked before the
+  /// first request is made.
+  ///
+  pub fn with_digest_function(mut self, digest_function: DigestFunction) -> Self {
+    self.digest_function = digest_function;
+    self
+  }
+
+  ///
+  /// Sets the policy with which failed uploads (ByteStream writes and BatchUpdateBlobs requests)
+  /// are retried. By default, uploads are retried with the same policy as all other requests.
+  ///
+  pub fn with_write_retry_policy(mut self, write_retry_policy: RetryPolicy) -> Self {
+    self.write_retry_policy = write_retry_policy;
+    self
+  }
+
+  ///
+  /// Limits the number of concurrent ByteStream reads and writes, and the aggregate rate (in bytes
+  /// per second) at which blob content is transferred, across all clones of this store. The
+  /// limits apply only to REAPI servers: object stores have their own concurrency limit.
+  ///
+  pub fn with_transfer_limits(
+    mut self,
+    read_concurrency_limit: Option<usize>,
+    write_concurrency_limit: Option<usize>,
+    bytes_per_second: Option<usize>,
+  ) -> Self {
+    self.transfer_limits = Arc::new(TransferLimits::new(
+      read_concurrency_limit,
+      write_concurrency_limit,
+      bytes_per_second,
+    ));
+    self
+  }
+
+  ///
+  /// Calls the given function until it succeeds, fails with an error which the given policy does
+  /// not retry, or runs out of attempts.
+  ///
+  async fn retry<T, C, F, Fut>(
+    retry_policy: &RetryPolicy,
+    client: C,
+    f: F,
+  ) -> Result<T, ByteStoreError>
+  where
+    C: Clone,
+    F: FnMut(C) -> Fut,
+    Fut: Future<Output = Result<T, ByteStoreError>>,
+  {
+    retry_policy
+      .retry_call(client, f, |err: &ByteStoreError| {
+        err.retryable(retry_policy)
+      })
+      .await
+  }
+
+  ///
+  /// Places the given cache in front of this store for blobs which it accepts: they are read from
+  /// it first, and written through to it.
+  ///
+  pub(crate) fn with_small_blob_cache(mut self, small_blob_cache: SmallBlobCache) -> Self {
+    self.small_blob_cache = Some(Arc::new(small_blob_cache));
+    self
+  }
+
+  ///
+  /// Places the given cache of LAN peers in front of this store: blobs are read from peers first
+  /// (other than those which are batched), but are not written to them.
+  ///
+  pub(crate) fn with_peer_cache(mut self, peer_cache: PeerCache) -> Self {
+    self.peer_cache = Some(Arc::new(peer_cache));
+    self
+  }
+
+  fn small_blob_cache_for(&self, digest: Digest) -> Option<&SmallBlobCache> {
+    self
+      .small_blob_cache
+      .as_deref()
+      .filter(|cache| cache.accepts(digest))
+  }
+
+  ///
+  /// Writes the given blob to the small blob cache, if it accepts it.
+  ///
+  async fn populate_small_blob_cache(&self, digest: Digest, bytes: &[u8]) {
+    if let Some(cache) = self.small_blob_cache_for(digest) {
+      if let Err(e) = cache.set(&self.blob_resource_name(digest), bytes).await {
+        log::debug!("Failed to write {digest:?} to the small blob cache: {e}");
+      }
+    }
+  }
+
+  ///
+  /// The value of the given function in the REAPI `DigestFunction` enum.
+  ///
+  fn digest_function_value(digest_function: DigestFunction) -> i32 {
+    match digest_function {
+      DigestFunction::Sha256 => remexec::digest_function::Value::Sha256 as i32,
+      // NB: The vendored protos predate BLAKE3, which the REAPI assigns this value.
+      DigestFunction::Blake3 => 9,
+    }
+  }
+
+  ///
+  /// The name of the given value of the REAPI `DigestFunction` enum, for error messages.
+  ///
+  fn digest_function_name(value: i32) -> String {
+    if value == Self::digest_function_value(DigestFunction::Blake3) {
+      return DigestFunction::Blake3.to_string();
+    }
+    match remexec::digest_function::Value::from_i32(value) {
+      Some(value) => format!("{value:?}").to_lowercase(),
+      None => format!("unknown ({value})"),
+    }
+  }
+
+  ///
+  /// Fails unless the server supports the configured digest function, with an error which names
+  /// the functions that it does support. Object and directory stores support any function,
+  /// because they do not interpret blobs.
+  ///
+  async fn check_digest_function(&self) -> Result<(), ByteStoreError> {
+    if matches!(
+      self.backend,
+      Backend::ObjectStore(_) | Backend::Directory(_)
+    ) {
+      return Ok(());
+    }
+    let capabilities = match self.get_capabilities().await {
+      Ok(capabilities) => capabilities,
+      // NB: SHA-256 is the default, so rather than failing here, let the request itself surface
+      // (and retry) any error from the server.
+      Err(e) if self.digest_function == DigestFunction::Sha256 => {
+        log::debug!("Failed to fetch the capabilities of the remote store: {e}");
+        return Ok(());
+      }
+      Err(e) => return Err(e),
+    };
+    let mut supported = capabilities
+      .cache_capabilities
+      .as_ref()
+      .map(|c| c.digest_functions.clone())
+      .unwrap_or_default();
+    // Servers which predate digest function negotiation do not list any, and use SHA-256.
+    if supported.is_empty() {
+      supported.push(Self::digest_function_value(DigestFunction::Sha256));
+    }
+    if supported.contains(&Self::digest_function_value(self.digest_function)) {
+      return Ok(());
+    }
+    Err(ByteStoreError::Other(format!(
+      "The remote store does not support the {} digest function: it only supports {}. Use a \
+      remote store which supports {}.",
+      self.digest_function,
+      supported
+        .into_iter()
+        .map(Self::digest_function_name)
+        .collect::<Vec<_>>()
+        .join(", "),
+      self.digest_function,
+    )))
+  }
+
+  ///
+  /// The part of a ByteStream resource name which identifies the given blob. Blobs whose digests
+  /// were computed with a function other than SHA-256 are namespaced by the name of the function.
+  ///
+  fn blob_resource_name(&self, digest: Digest) -> String {
+    self.blob_resource_name_with(digest, false)
+  }
+
+  ///
+  /// Like `blob_resource_name`, but optionally names the zstd-compressed form of the blob, as
+  /// described by REAPI v2.1.
+  ///
+  fn blob_resource_name_with(&self, digest: Digest, compressed: bool) -> String {
+    let prefix = if compressed {
+      "compressed-blobs/zstd"
+    } else {
+      "blobs"
+    };
+    match self.digest_function {
+      DigestFunction::Sha256 => format!("{prefix}/{}/{}", digest.hash, digest.size_bytes),
+      digest_function => format!(
+        "{prefix}/{digest_function}/{}/{}",
+        digest.hash, digest.size_bytes
+      ),
+    }
+  }
+
+  ///
+  /// True if the server supports zstd compression of ByteStream reads and writes. Servers which
+  /// fail to report their capabilities are assumed not to.
+  ///
+  async fn byte_stream_supports_zstd(&self) -> bool {
+    match self.get_capabilities().await {
+      Ok(capabilities) => supports_zstd(capabilities, |c| &c.supported_compressors),
+      Err(e) => {
+        log::debug!("Failed to get the capabilities of the remote store, so not compressing: {e}");
+        false
+      }
+    }
+  }
+
   pub(crate) fn chunk_size_bytes(&self) -> usize {
     self.chunk_size_bytes
   }
 
+  pub(crate) fn instance_name(&self) -> Option<&str> {
+    self.instance_name.as_deref()
+  }
+
   pub async fn store_buffered<WriteToBuffer, WriteResult>(
     &self,
     digest: Digest,
@@ -201,6 +562,8 @@ impl ByteStore {
       let mapping = memmap::Mmap::map(&read_buffer).map_err(|e| {
         format!("Failed to memory map the temporary file buffer for {digest:?}: {e}")
       })?;
+      // NB: `madvise` is not available on Windows, where the hint is skipped.
+      #[cfg(unix)]
       if let Err(err) = madvise::madvise(
         mapping.as_ptr(),
         mapping.len(),
@@ -216,29 +579,111 @@ impl ByteStore {
       Ok(mapping) as Result<memmap::Mmap, String>
     }?);
 
-    retry_call(
-      mmap,
-      |mmap| self.store_bytes_source(digest, move |range| Bytes::copy_from_slice(&mmap[range])),
-      ByteStoreError::retryable,
-    )
+    let upload = Upload::default();
+    Self::retry(&self.write_retry_policy, mmap.clone(), |mmap| {
+      self.store_bytes_source(digest, &upload, move |range| {
+        Bytes::copy_from_slice(&mmap[range])
+      })
+    })
     .await
-    .map_err(|e| e.to_string().into())
+    .map_err(|e| e.to_string())?;
+    self.populate_small_blob_cache(digest, &mmap).await;
+    Ok(())
   }
 
   pub async fn store_bytes(&self, bytes: Bytes) -> Result<(), String> {
-    let digest = Digest::of_bytes(&bytes);
-    retry_call(
-      bytes,
-      |bytes| self.store_bytes_source(digest, move |range| bytes.slice(range)),
-      ByteStoreError::retryable,
-    )
+    let digest = Digest::of_bytes_with(self.digest_function, &bytes);
+    let upload = Upload::default();
+    Self::retry(&self.write_retry_policy, bytes.clone(), |bytes| {
+      self.store_bytes_source(digest, &upload, move |range| bytes.slice(range))
+    })
     .await
-    .map_err(|e| e.to_string())
+    .map_err(|e| e.to_string())?;
+    self.populate_small_blob_cache(digest, &bytes).await;
+    Ok(())
   }
 
+  ///
+  /// Stores the given blobs, grouping those which are small enough into as few BatchUpdateBlobs
+  /// requests as the server's maximum batch size allows. Larger blobs (and all blobs, for backends
+  /// which do not support batch requests) are stored individually.
+  ///
+  pub async fn store_bytes_batch(&self, items: Vec<Bytes>) -> Result<(), String> {
+    let max_batch_size_bytes =
+      Self::retry(&self.retry_policy, (), |()| self.max_batch_size_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(max_batch_size_bytes) = max_batch_size_bytes else {
+      future::try_join_all(items.into_iter().map(|bytes| self.store_bytes(bytes))).await?;
+      return Ok(());
+    };
+
+    let blobs = items
+      .into_iter()
+      .map(|bytes| (Digest::of_bytes_with(self.digest_function, &bytes), bytes))
+      .collect();
+    let (batches, oversized) =
+      partition_into_batches(blobs, |(digest, _)| digest.size_bytes, max_batch_size_bytes);
+    let batches = future::try_join_all(batches.into_iter().map(|batch| async move {
+      Self::retry(&self.write_retry_policy, batch.clone(), |batch| {
+        self.store_blobs_batch(batch)
+      })
+      .await
+      .map_err(|e| e.to_string())?;
+      for (digest, bytes) in &batch {
+        self.populate_small_blob_cache(*digest, bytes).await;
+      }
+      Ok::<_, String>(())
+    }));
+    let oversized = future::try_join_all(
+      oversized
+        .into_iter()
+        .map(|(_, bytes)| self.store_bytes(bytes)),
+    );
+    future::try_join(batches, oversized).await?;
+    Ok(())
+  }
+
+  ///
+  /// The largest total size of the entries in a single batch request (see
+  /// `batch_entry_size_bytes`), or None if this store does not support batch requests.
+  ///
+  async fn max_batch_size_bytes(&self) -> Result<Option<usize>, ByteStoreError> {
+    if !matches!(self.backend, Backend::Reapi { .. }) {
+      return Ok(None);
+    }
+    let max_batch_total_size_bytes = self
+      .get_capabilities()
+      .await?
+      .cache_capabilities
+      .as_ref()
+      .map(|c| c.max_batch_total_size_bytes as usize)
+      .unwrap_or_default();
+    // NB: The server's limit applies to the whole request, so the framing of the request (and its
+    // instance name) is deducted from it.
+    Ok(Some(if max_batch_total_size_bytes == 0 {
+      self.batch_api_size_limit
+    } else {
+      let request_overhead_bytes = BATCH_REQUEST_FRAMING_BYTES
+        + self
+          .instance_name
+          .as_ref()
+          .map_or(0, |instance_name| instance_name.len());
+      min(
+        self.batch_api_size_limit,
+        max_batch_total_size_bytes.saturating_sub(request_overhead_bytes),
+      )
+    }))
+  }
+
+  ///
+  /// Makes one attempt to store the given blob. Attempts to store a blob which share an `Upload`
+  /// may resume one another's ByteStream writes.
+  ///
   async fn store_bytes_source<ByteSource>(
     &self,
     digest: Digest,
+    upload: &Upload,
     bytes: ByteSource,
   ) -> Result<(), ByteStoreError>
   where
@@ -246,33 +691,52 @@ impl ByteStore {
   {
     let len = digest.size_bytes;
 
-    let max_batch_total_size_bytes = {
-      let capabilities = self.get_capabilities().await?;
-
-      capabilities
-        .cache_capabilities
-        .as_ref()
-        .map(|c| c.max_batch_total_size_bytes as usize)
-        .unwrap_or_default()
+    self.check_digest_function().await?;
+    let (use_batch_api, compress) = match self.max_batch_size_bytes().await? {
+      Some(max_batch_size_bytes) => {
+        let capabilities = self.get_capabilities().await?;
+        let use_batch_api = batch_entry_size_bytes(len) <= max_batch_size_bytes;
+        let compress = if use_batch_api {
+          supports_zstd(capabilities, |c| &c.supported_batch_update_compressors)
+        } else {
+          supports_zstd(capabilities, |c| &c.supported_compressors)
+        };
+        (use_batch_api, compress)
+      }
+      None => (false, false),
     };
 
-    let batch_api_allowed_by_local_config = len <= self.batch_api_size_limit;
-    let batch_api_allowed_by_server_config =
-      max_batch_total_size_bytes == 0 || len < max_batch_total_size_bytes;
-
     in_workunit!(
       "store_bytes",
       Level::Trace,
       desc = Some(format!("Storing {digest:?}")),
       |workunit| async move {
-        let result = if batch_api_allowed_by_local_config && batch_api_allowed_by_server_config {
-          self.store_bytes_source_batch(digest, bytes).await
-        } else {
-          self.store_bytes_source_stream(digest, bytes).await
+        let start = Instant::now();
+        let result = match &self.backend {
+          Backend::Reapi { cas_client, .. } if use_batch_api => {
+            self
+              .store_bytes_source_batch(cas_client, digest, bytes, compress)
+              .await
+          }
+          Backend::Reapi {
+            byte_stream_client, ..
+          } => {
+            self
+              .store_bytes_source_stream(byte_stream_client, digest, upload, bytes, compress)
+              .await
+          }
+          Backend::ObjectStore(object_store) => object_store
+            .put(&self.blob_resource_name(digest), len, &bytes)
+            .await
+            .map_err(ByteStoreError::from),
+          Backend::Directory(directory) => directory
+            .store(self.digest_function, digest, &bytes)
+            .await
+            .map_err(ByteStoreError::Other),
         };
 
         if result.is_ok() {
-          workunit.record_observation(ObservationMetric::RemoteStoreBlobBytesUploaded, len as u64);
+          Self::record_write(workunit, start, len);
         }
 
         result
@@ -281,56 +745,206 @@ impl ByteStore {
     .await
   }
 
+  ///
+  /// Records the observations for a successful write of the given number of bytes.
+  ///
+  fn record_write(workunit: &mut RunningWorkunit, start: Instant, len: usize) {
+    workunit.record_observation(
+      ObservationMetric::RemoteStoreWriteBlobTimeMicros,
+      start.elapsed().as_micros() as u64,
+    );
+    workunit.record_observation(ObservationMetric::RemoteStoreBlobBytesUploaded, len as u64);
+    workunit.increment_counter(Metric::RemoteStoreBytesWritten, len as u64);
+  }
+
   async fn store_bytes_source_batch<ByteSource>(
     &self,
+    cas_client: &ContentAddressableStorageClient<LayeredService>,
     digest: Digest,
     bytes: ByteSource,
+    compress: bool,
   ) -> Result<(), ByteStoreError>
   where
     ByteSource: Fn(Range<usize>) -> Bytes + Send + Sync + 'static,
   {
+    self
+      .batch_update_blobs(
+        cas_client,
+        vec![(digest, bytes(0..digest.size_bytes))],
+        compress,
+      )
+      .await
+  }
+
+  ///
+  /// Stores the given blobs with a single BatchUpdateBlobs request.
+  ///
+  async fn store_blobs_batch(&self, blobs: Vec<(Digest, Bytes)>) -> Result<(), ByteStoreError> {
+    self.check_digest_function().await?;
+    let cas_client = match &self.backend {
+      Backend::Reapi { cas_client, .. } => cas_client,
+      Backend::ObjectStore(_) | Backend::Directory(_) => {
+        return Err(ByteStoreError::Other(
+          "Only REAPI stores support batch requests.".to_owned(),
+        ))
+      }
+    };
+    let compress = supports_zstd(self.get_capabilities().await?, |c| {
+      &c.supported_batch_update_compressors
+    });
+    let len: usize = blobs.iter().map(|(digest, _)| digest.size_bytes).sum();
+
+    in_workunit!(
+      "store_bytes_batch",
+      Level::Trace,
+      desc = Some(format!("Storing a batch of {} blobs", blobs.len())),
+      |workunit| async move {
+        let start = Instant::now();
+        let result = self.batch_update_blobs(cas_client, blobs, compress).await;
+        if result.is_ok() {
+          Self::record_write(workunit, start, len);
+        }
+        result
+      }
+    )
+    .await
+  }
+
+  ///
+  /// Sends a BatchUpdateBlobs request for the given blobs (optionally compressed), and fails if
+  /// storing any of them failed.
+  ///
+  async fn batch_update_blobs(
+    &self,
+    cas_client: &ContentAddressableStorageClient<LayeredService>,
+    blobs: Vec<(Digest, Bytes)>,
+    compress: bool,
+  ) -> Result<(), ByteStoreError> {
+    let requests = blobs
+      .into_iter()
+      .map(|(digest, bytes)| {
+        let (data, compressor) = if compress {
+          let data = zstd::bulk::compress(&bytes, ZSTD_COMPRESSION_LEVEL)?;
+          (Bytes::from(data), remexec::compressor::Value::Zstd)
+        } else {
+          (bytes, remexec::compressor::Value::Identity)
+        };
+        Ok(remexec::batch_update_blobs_request::Request {
+          digest: Some(digest.into()),
+          data,
+          compressor: compressor as i32,
+        })
+      })
+      .collect::<Result<Vec<_>, ByteStoreError>>()?;
+    self
+      .transfer_limits
+      .consume(requests.iter().map(|request| request.data.len()).sum())
+      .await;
     let request = BatchUpdateBlobsRequest {
       instance_name: self.instance_name.clone().unwrap_or_default(),
-      requests: vec![remexec::batch_update_blobs_request::Request {
-        digest: Some(digest.into()),
-        data: bytes(0..digest.size_bytes),
-        compressor: remexec::compressor::Value::Identity as i32,
-      }],
+      requests,
     };
 
-    let mut client = self.cas_client.as_ref().clone();
-    client
+    let mut client = cas_client.clone();
+    let response = client
       .batch_update_blobs(request)
       .await
-      .map_err(ByteStoreError::Grpc)?;
+      .map_err(ByteStoreError::Grpc)?
+      .into_inner();
+    for response in response.responses {
+      let status = response.status.unwrap_or_default();
+      if status.code != Code::Ok as i32 {
+        return Err(ByteStoreError::Grpc(Status::new(
+          Code::from_i32(status.code),
+          format!(
+            "Failed to store {:?}: {}",
+            response.digest.map(|d| d.hash),
+            status.message
+          ),
+        )));
+      }
+    }
     Ok(())
   }
 
+  fn upload_resource_name(&self, upload_id: Uuid, digest: Digest, compress: bool) -> String {
+    let instance_name = self.instance_name.clone().unwrap_or_default();
+    format!(
+      "{}{}uploads/{}/{}",
+      &instance_name,
+      if instance_name.is_empty() { "" } else { "/" },
+      upload_id,
+      self.blob_resource_name_with(digest, compress),
+    )
+  }
+
+  ///
+  /// Uploads the given blob with a ByteStream write. If an earlier attempt of the same upload
+  /// failed, the write resumes from the offset which the server reports (via QueryWriteStatus)
+  /// that it committed, or restarts under a new resource name if the server cannot report it.
+  ///
+  /// Compressed writes are always restarted, since their offsets are in the compressed stream.
+  ///
   async fn store_bytes_source_stream<ByteSource>(
     &self,
+    byte_stream_client: &ByteStreamClient<LayeredService>,
     digest: Digest,
+    upload: &Upload,
     bytes: ByteSource,
+    compress: bool,
   ) -> Result<(), ByteStoreError>
   where
     ByteSource: Fn(Range<usize>) -> Bytes + Send + Sync + 'static,
   {
     let len = digest.size_bytes;
-    let instance_name = self.instance_name.clone().unwrap_or_default();
-    let resource_name = format!(
-      "{}{}uploads/{}/blobs/{}/{}",
-      &instance_name,
-      if instance_name.is_empty() { "" } else { "/" },
-      uuid::Uuid::new_v4(),
-      digest.hash,
-      digest.size_bytes,
-    );
-    let store = self.clone();
+    let mut client = byte_stream_client.clone();
 
-    let mut client = self.byte_stream_client.as_ref().clone();
+    let (upload_id, resuming) = if compress {
+      (upload.restart(), false)
+    } else {
+      upload.resume()
+    };
+    let mut resource_name = self.upload_resource_name(upload_id, digest, compress);
+    let mut start_offset = 0;
+    if resuming {
+      let status = client
+        .query_write_status(QueryWriteStatusRequest {
+          resource_name: resource_name.clone(),
+        })
+        .await
+        .map(|response| response.into_inner());
+      match status {
+        Ok(status) if status.complete => return Ok(()),
+        Ok(status) if status.committed_size >= 0 && status.committed_size as usize <= len => {
+          log::debug!(
+            "Resuming upload of {digest:?} at offset {}",
+            status.committed_size
+          );
+          start_offset = status.committed_size as usize;
+        }
+        status => {
+          log::debug!("Restarting upload of {digest:?}, which cannot be resumed: {status:?}");
+          resource_name = self.upload_resource_name(upload.restart(), digest, compress);
+        }
+      }
+    }
+
+    let chunk_size_bytes = self.chunk_size_bytes;
 
-    let chunk_size_bytes = store.chunk_size_bytes;
+    let _permit = self.transfer_limits.write_permit().await;
+    if compress {
+      return Self::store_compressed_stream(
+        client,
+        resource_name,
+        digest,
+        bytes,
+        chunk_size_bytes,
+        self.transfer_limits.clone(),
+      )
+      .await;
+    }
 
-    let stream = futures::stream::unfold((0, false), move |(offset, has_sent_any)| {
+    let stream = futures::stream::unfold((start_offset, false), move |(offset, has_sent_any)| {
       if offset >= len && has_sent_any {
         futures::future::ready(None)
       } else {
@@ -346,6 +960,7 @@ impl ByteStore {
         futures::future::ready(Some((req, (next_offset, true))))
       }
     });
+    let stream = throttle_writes(self.transfer_limits.clone(), stream);
 
     // NB: We must box the future to avoid a stack overflow.
     Box::pin(async move {
@@ -367,20 +982,107 @@ impl ByteStore {
     .await
   }
 
+  ///
+  /// Uploads the given blob in zstd-compressed chunks. The compressed size is not known until the
+  /// upload completes, and a server which already has the blob may report a committed size of -1.
+  ///
+  async fn store_compressed_stream<ByteSource>(
+    mut client: ByteStreamClient<LayeredService>,
+    resource_name: String,
+    digest: Digest,
+    bytes: ByteSource,
+    chunk_size_bytes: usize,
+    transfer_limits: Arc<TransferLimits>,
+  ) -> Result<(), ByteStoreError>
+  where
+    ByteSource: Fn(Range<usize>) -> Bytes + Send + Sync + 'static,
+  {
+    let len = digest.size_bytes;
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), ZSTD_COMPRESSION_LEVEL)?;
+    let compressed_len = Arc::new(AtomicI64::new(0));
+    let stream = {
+      let compressed_len = compressed_len.clone();
+      async_stream::stream! {
+        let mut write_offset = 0;
+        let mut offset = 0;
+        while offset < len {
+          let next_offset = min(offset + chunk_size_bytes, len);
+          if let Err(e) = encoder.write_all(&bytes(offset..next_offset)) {
+            // NB: Ending the stream without finishing the write fails the upload.
+            log::debug!("Failed to compress {digest:?}: {e}");
+            return;
+          }
+          offset = next_offset;
+          let data = std::mem::take(encoder.get_mut());
+          if data.is_empty() {
+            continue;
+          }
+          let data_len = data.len() as i64;
+          yield protos::gen::google::bytestream::WriteRequest {
+            resource_name: resource_name.clone(),
+            write_offset,
+            finish_write: false,
+            data: Bytes::from(data),
+          };
+          write_offset += data_len;
+        }
+        let data = match encoder.finish() {
+          Ok(data) => data,
+          Err(e) => {
+            log::debug!("Failed to compress {digest:?}: {e}");
+            return;
+          }
+        };
+        compressed_len.store(write_offset + data.len() as i64, Ordering::SeqCst);
+        yield protos::gen::google::bytestream::WriteRequest {
+          resource_name: resource_name.clone(),
+          write_offset,
+          finish_write: true,
+          data: Bytes::from(data),
+        };
+      }
+    };
+    let stream = throttle_writes(transfer_limits, stream);
+
+    // NB: We must box the future to avoid a stack overflow.
+    Box::pin(async move {
+      let response = client
+        .write(Request::new(stream))
+        .await
+        .map_err(ByteStoreError::Grpc)?
+        .into_inner();
+      let compressed_len = compressed_len.load(Ordering::SeqCst);
+      if response.committed_size == compressed_len || response.committed_size == -1 {
+        Ok(())
+      } else {
+        Err(ByteStoreError::Other(format!(
+          "Uploading compressed file with digest {:?}: want committed size {} but got {}",
+          digest, compressed_len, response.committed_size
+        )))
+      }
+    })
+    .await
+  }
+
   async fn load_monomorphic(
     &self,
     digest: Digest,
     destination: &mut dyn LoadDestination,
   ) -> Result<bool, String> {
     let start = Instant::now();
+    self
+      .check_digest_function()
+      .await
+      .map_err(|e| e.to_string())?;
+    let compressed =
+      matches!(self.backend, Backend::Reapi { .. }) && self.byte_stream_supports_zstd().await;
     let store = self.clone();
     let instance_name = store.instance_name.clone().unwrap_or_default();
     let resource_name = format!(
-      "{}{}blobs/{}/{}",
+      "{}{}{}",
       &instance_name,
       if instance_name.is_empty() { "" } else { "/" },
-      digest.hash,
-      digest.size_bytes
+      self.blob_resource_name_with(digest, compressed),
     );
     let workunit_desc = format!("Loading bytes at: {resource_name}");
 
@@ -390,14 +1092,71 @@ impl ByteStore {
       // 0 means no limit.
       read_limit: 0,
     };
-    let client = self.byte_stream_client.as_ref().clone();
+    let digest_function = self.digest_function;
 
     let destination = Arc::new(Mutex::new(destination));
 
-    let result_future = retry_call(
+    let client = match &self.backend {
+      Backend::Reapi {
+        byte_stream_client, ..
+      } => byte_stream_client.as_ref().clone(),
+      Backend::ObjectStore(object_store) => {
+        let key = self.blob_resource_name(digest);
+        let result_future = Self::retry(
+          &self.retry_policy,
+          (object_store.clone(), destination),
+          move |(object_store, destination)| {
+            let key = key.clone();
+            async move {
+              let mut body = match object_store.get(&key).await? {
+                Some(body) => body,
+                None => return Ok(false),
+              };
+
+              let mut writer = destination.lock().await;
+              let mut hasher = Hasher::new_with(digest_function);
+              writer.reset().await?;
+              while let Some(chunk) = body.chunk().await? {
+                writer.write_all(&chunk).await?;
+                hasher.update(&chunk);
+              }
+              writer.shutdown().await?;
+
+              let actual_digest = hasher.finish();
+              if actual_digest != digest {
+                return Err(ByteStoreError::ObjectStore(ObjectStoreError::transient(
+                  format!(
+                    "Remote store gave wrong digest: expected {digest:?}, got {actual_digest:?}"
+                  ),
+                )));
+              }
+              Ok(true)
+            }
+          },
+        );
+        return Self::record_load(digest, start, workunit_desc, result_future).await;
+      }
+      Backend::Directory(directory) => {
+        let directory = directory.clone();
+        let result_future = async move {
+          let mut destination = destination.lock().await;
+          directory
+            .load(digest_function, digest, &mut **destination)
+            .await
+            .map_err(ByteStoreError::Other)
+        };
+        return Self::record_load(digest, start, workunit_desc, result_future).await;
+      }
+    };
+
+    let transfer_limits = self.transfer_limits.clone();
+    let result_future = Self::retry(
+      &self.retry_policy,
       (client, request, destination),
       move |(mut client, request, destination)| {
+        let transfer_limits = transfer_limits.clone();
         async move {
+          let _permit = transfer_limits.read_permit().await;
           let mut start_opt = Some(Instant::now());
           let response = client.read(request).await?;
 
@@ -417,12 +1176,31 @@ impl ByteStore {
           });
 
           let mut writer = destination.lock().await;
-          let mut hasher = Hasher::new();
+          let mut hasher = Hasher::new_with(digest_function);
+          let mut decoder = if compressed {
+            Some(zstd::stream::write::Decoder::new(Vec::new())?)
+          } else {
+            None
+          };
           writer.reset().await?;
           while let Some(response) = stream.next().await {
             let response = response?;
-            writer.write_all(&response.data).await?;
-            hasher.update(&response.data);
+            transfer_limits.consume(response.data.len()).await;
+            let data = match decoder.as_mut() {
+              Some(decoder) => {
+                decoder.write_all(&response.data)?;
+                Bytes::from(std::mem::take(decoder.get_mut()))
+              }
+              None => response.data,
+            };
+            writer.write_all(&data).await?;
+            hasher.update(&data);
+          }
+          if let Some(mut decoder) = decoder {
+            decoder.flush()?;
+            let data = decoder.into_inner();
+            writer.write_all(&data).await?;
+            hasher.update(&data);
           }
           writer.shutdown().await?;
 
@@ -442,9 +1220,19 @@ impl ByteStore {
           Err(err) => Err(err),
         })
       },
-      ByteStoreError::retryable,
     );
+    Self::record_load(digest, start, workunit_desc, result_future).await
+  }
 
+  ///
+  /// Awaits the given load within a workunit which records its observations.
+  ///
+  async fn record_load(
+    digest: Digest,
+    start: Instant,
+    workunit_desc: String,
+    result_future: impl Future<Output = Result<bool, ByteStoreError>>,
+  ) -> Result<bool, String> {
     in_workunit!(
       "load",
       Level::Trace,
@@ -455,11 +1243,10 @@ impl ByteStore {
           ObservationMetric::RemoteStoreReadBlobTimeMicros,
           start.elapsed().as_micros() as u64,
         );
-        if result.is_ok() {
-          workunit.record_observation(
-            ObservationMetric::RemoteStoreBlobBytesDownloaded,
-            digest.size_bytes as u64,
-          );
+        match result {
+          Ok(true) => Self::record_hit(workunit, digest),
+          Ok(false) => workunit.increment_counter(Metric::RemoteStoreReadBlobMisses, 1),
+          Err(_) => (),
         }
         result
       },
@@ -467,11 +1254,54 @@ impl ByteStore {
     .await
   }
 
+  ///
+  /// Records the observations for a successful read of the given blob.
+  ///
+  fn record_hit(workunit: &mut RunningWorkunit, digest: Digest) {
+    workunit.record_observation(
+      ObservationMetric::RemoteStoreBlobBytesDownloaded,
+      digest.size_bytes as u64,
+    );
+    workunit.increment_counter(Metric::RemoteStoreReadBlobHits, 1);
+    workunit.increment_counter(Metric::RemoteStoreBytesRead, digest.size_bytes as u64);
+  }
+
   async fn load<W: LoadDestination>(
     &self,
     digest: Digest,
     mut destination: W,
   ) -> Result<Option<W>, String> {
+    if let Some(peer_cache) = self.peer_cache.as_ref().filter(|cache| cache.has_peers()) {
+      if peer_cache
+        .load(digest, self.digest_function, &mut destination)
+        .await
+      {
+        destination.shutdown().await.map_err(|e| e.to_string())?;
+        return Ok(Some(destination));
+      }
+      destination.reset().await.map_err(|e| e.to_string())?;
+    }
+
+    if let Some(cache) = self.small_blob_cache_for(digest) {
+      let bytes = match self.load_from_small_blob_cache(cache, digest).await {
+        Some(bytes) => bytes,
+        None => {
+          let mut buffer = Vec::with_capacity(digest.size_bytes);
+          if !self.load_monomorphic(digest, &mut buffer).await? {
+            return Ok(None);
+          }
+          self.populate_small_blob_cache(digest, &buffer).await;
+          buffer
+        }
+      };
+      destination
+        .write_all(&bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+      destination.shutdown().await.map_err(|e| e.to_string())?;
+      return Ok(Some(destination));
+    }
+
     if self.load_monomorphic(digest, &mut destination).await? {
       Ok(Some(destination))
     } else {
@@ -479,6 +1309,39 @@ impl ByteStore {
     }
   }
 
+  ///
+  /// Loads the given blob from the given small blob cache, if it is present and valid there.
+  ///
+  async fn load_from_small_blob_cache(
+    &self,
+    cache: &SmallBlobCache,
+    digest: Digest,
+  ) -> Option<Vec<u8>> {
+    let result = match cache.get(&self.blob_resource_name(digest)).await {
+      Ok(Some(bytes)) if Digest::of_bytes_with(self.digest_function, &bytes) == digest => {
+        Some(bytes.to_vec())
+      }
+      Ok(Some(_)) => {
+        log::debug!("The small blob cache gave the wrong content for {digest:?}.");
+        None
+      }
+      Ok(None) => None,
+      Err(e) => {
+        log::debug!("Failed to read {digest:?} from the small blob cache: {e}");
+        None
+      }
+    };
+    if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      let metric = if result.is_some() {
+        Metric::RemoteStoreSmallBlobCacheHits
+      } else {
+        Metric::RemoteStoreSmallBlobCacheMisses
+      };
+      workunit_store_handle.store.increment_counter(metric, 1);
+    }
+    result
+  }
+
   /// Load the data for `digest` (if it exists in the remote store) into memory.
   pub async fn load_bytes(&self, digest: Digest) -> Result<Option<Bytes>, String> {
     let result = self
@@ -487,17 +1350,176 @@ impl ByteStore {
     Ok(result.map(Bytes::from))
   }
 
+  ///
+  /// Loads the given blobs into memory, grouping those which are small enough into as few
+  /// BatchReadBlobs requests as the server's maximum batch size allows. Larger blobs (and all
+  /// blobs, for backends which do not support batch requests) are loaded individually.
+  ///
+  /// Blobs which do not exist in the remote store are omitted from the result.
+  ///
+  pub async fn load_bytes_batch(
+    &self,
+    digests: Vec<Digest>,
+  ) -> Result<HashMap<Digest, Bytes>, String> {
+    let load_individually = |digests: Vec<Digest>| {
+      future::try_join_all(digests.into_iter().map(|digest| async move {
+        Ok::<_, String>(self.load_bytes(digest).await?.map(|bytes| (digest, bytes)))
+      }))
+    };
+
+    let max_batch_size_bytes =
+      Self::retry(&self.retry_policy, (), |()| self.max_batch_size_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(max_batch_size_bytes) = max_batch_size_bytes else {
+      return Ok(load_individually(digests).await?.into_iter().flatten().collect());
+    };
+
+    // Serve what we can from the small blob cache.
+    let mut loaded = HashMap::new();
+    let mut uncached = Vec::with_capacity(digests.len());
+    for digest in digests {
+      let cached = match self.small_blob_cache_for(digest) {
+        Some(cache) => self.load_from_small_blob_cache(cache, digest).await,
+        None => None,
+      };
+      match cached {
+        Some(bytes) => {
+          loaded.insert(digest, Bytes::from(bytes));
+        }
+        None => uncached.push(digest),
+      }
+    }
+
+    let (batches, oversized) =
+      partition_into_batches(uncached, |digest| digest.size_bytes, max_batch_size_bytes);
+    let batches = future::try_join_all(batches.into_iter().map(|batch| async move {
+      let blobs = Self::retry(&self.retry_policy, batch, |batch| {
+        self.load_blobs_batch(batch)
+      })
+      .await
+      .map_err(|e| e.to_string())?;
+      for (digest, bytes) in &blobs {
+        self.populate_small_blob_cache(*digest, bytes).await;
+      }
+      Ok::<_, String>(blobs)
+    }));
+    let (batches, oversized) = future::try_join(batches, load_individually(oversized)).await?;
+    loaded.extend(batches.into_iter().flatten());
+    loaded.extend(oversized.into_iter().flatten());
+    Ok(loaded)
+  }
+
+  ///
+  /// Loads the given blobs with a single BatchReadBlobs request, and returns those which exist.
+  ///
+  async fn load_blobs_batch(
+    &self,
+    digests: Vec<Digest>,
+  ) -> Result<Vec<(Digest, Bytes)>, ByteStoreError> {
+    self.check_digest_function().await?;
+    let cas_client = match &self.backend {
+      Backend::Reapi { cas_client, .. } => cas_client,
+      Backend::ObjectStore(_) | Backend::Directory(_) => {
+        return Err(ByteStoreError::Other(
+          "Only REAPI stores support batch requests.".to_owned(),
+        ))
+      }
+    };
+    let compress = supports_zstd(self.get_capabilities().await?, |c| &c.supported_compressors);
+    let start = Instant::now();
+
+    in_workunit!(
+      "load_batch",
+      Level::Trace,
+      desc = Some(format!("Loading a batch of {} blobs", digests.len())),
+      |workunit| async move {
+        let request = remexec::BatchReadBlobsRequest {
+          instance_name: self.instance_name.clone().unwrap_or_default(),
+          digests: digests.iter().map(|&digest| digest.into()).collect(),
+          acceptable_compressors: if compress {
+            vec![remexec::compressor::Value::Zstd as i32]
+          } else {
+            vec![]
+          },
+        };
+        let mut client = cas_client.clone();
+        let response = client
+          .batch_read_blobs(request)
+          .await
+          .map_err(ByteStoreError::Grpc)?
+          .into_inner();
+        self
+          .transfer_limits
+          .consume(response.responses.iter().map(|r| r.data.len()).sum())
+          .await;
+        workunit.record_observation(
+          ObservationMetric::RemoteStoreReadBlobTimeMicros,
+          start.elapsed().as_micros() as u64,
+        );
+
+        let mut blobs = Vec::with_capacity(response.responses.len());
+        for response in response.responses {
+          let digest = require_digest(response.digest.as_ref())?;
+          let status = response.status.unwrap_or_default();
+          match Code::from_i32(status.code) {
+            Code::Ok => (),
+            Code::NotFound => {
+              workunit.increment_counter(Metric::RemoteStoreReadBlobMisses, 1);
+              continue;
+            }
+            code => {
+              return Err(ByteStoreError::Grpc(Status::new(
+                code,
+                format!("Failed to load {digest:?}: {}", status.message),
+              )))
+            }
+          }
+          let data = if response.compressor == remexec::compressor::Value::Zstd as i32 {
+            Bytes::from(zstd::stream::decode_all(&response.data[..])?)
+          } else if response.compressor == remexec::compressor::Value::Identity as i32 {
+            response.data
+          } else {
+            return Err(ByteStoreError::Other(format!(
+              "Remote store returned {digest:?} with unsupported compressor {}",
+              response.compressor
+            )));
+          };
+          if Digest::of_bytes_with(self.digest_function, &data) != digest {
+            return Err(ByteStoreError::Other(format!(
+              "Remote store returned the wrong content for {digest:?}"
+            )));
+          }
+          Self::record_hit(workunit, digest);
+          blobs.push((digest, data));
+        }
+        Ok(blobs)
+      }
+    )
+    .await
+  }
+
+  ///
   /// Write the data for `digest` (if it exists in the remote store) into `file`.
+  ///
+  /// The data is streamed into the file (and hashed as it is written) rather than being buffered
+  /// in memory, so blobs of any size may be loaded this way.
+  ///
   pub async fn load_file(
     &self,
     digest: Digest,
     file: tokio::fs::File,
   ) -> Result<Option<tokio::fs::File>, String> {
-    let mut result = self.load(digest, file).await;
-    if let Ok(Some(ref mut file)) = result {
-      file.rewind().await.map_err(|e| e.to_string())?;
+    let writer = BufWriter::with_capacity(FILE_WRITE_BUFFER_BYTES, file);
+    // NB: `load` shuts down the writer when it succeeds, which flushes its buffer.
+    match self.load(digest, writer).await? {
+      Some(writer) => {
+        let mut file = writer.into_inner();
+        file.rewind().await.map_err(|e| e.to_string())?;
+        Ok(Some(file))
+      }
+      None => Ok(None),
     }
-    result
   }
 
   ///
@@ -513,32 +1535,123 @@ impl ByteStore {
       in_workunit!(
         "list_missing_digests",
         Level::Trace,
-        |_workunit| async move {
-          let store2 = store.clone();
-          let client = store2.cas_client.as_ref().clone();
-          let response = retry_call(
-            client,
-            move |mut client| {
-              let request = request.clone();
-              async move { client.find_missing_blobs(request).await }
-            },
-            status_is_retryable,
-          )
-          .await
-          .map_err(status_to_str)?;
-
-          response
-            .into_inner()
-            .missing_blob_digests
-            .iter()
-            .map(|digest| digest.try_into())
-            .collect::<Result<HashSet<_>, _>>()
+        |workunit| async move {
+          let start = Instant::now();
+          let requested = request.blob_digests.len();
+          let result = store.find_missing_digests(request).await;
+          workunit.record_observation(
+            ObservationMetric::RemoteStoreFindMissingBlobsTimeMicros,
+            start.elapsed().as_micros() as u64,
+          );
+          if let Ok(missing) = &result {
+            workunit.increment_counter(
+              Metric::RemoteStoreDigestsPresent,
+              requested.saturating_sub(missing.len()) as u64,
+            );
+            workunit.increment_counter(Metric::RemoteStoreDigestsMissing, missing.len() as u64);
+          }
+          result
         }
       )
       .await
     }
   }
 
+  async fn find_missing_digests(
+    &self,
+    request: remexec::FindMissingBlobsRequest,
+  ) -> Result<HashSet<Digest>, String> {
+    self
+      .check_digest_function()
+      .await
+      .map_err(|e| e.to_string())?;
+    let client = match &self.backend {
+      Backend::Reapi { cas_client, .. } => cas_client.as_ref().clone(),
+      Backend::ObjectStore(object_store) => {
+        return self
+          .list_missing_objects(object_store.clone(), request)
+          .await
+      }
+      Backend::Directory(directory) => return self.list_missing_files(directory, request).await,
+    };
+    let response = self
+      .retry_policy
+      .retry_call(
+        client,
+        move |mut client| {
+          let request = request.clone();
+          async move { client.find_missing_blobs(request).await }
+        },
+        |status| self.retry_policy.status_is_retryable(status),
+      )
+      .await
+      .map_err(status_to_str)?;
+
+    response
+      .into_inner()
+      .missing_blob_digests
+      .iter()
+      .map(|digest| digest.try_into())
+      .collect::<Result<HashSet<_>, _>>()
+  }
+
+  ///
+  /// Checks for the existence of the object for each of the requested digests.
+  ///
+  async fn list_missing_objects(
+    &self,
+    object_store: Arc<ObjectStore>,
+    request: remexec::FindMissingBlobsRequest,
+  ) -> Result<HashSet<Digest>, String> {
+    let digests = request
+      .blob_digests
+      .iter()
+      .map(|digest| digest.try_into())
+      .collect::<Result<Vec<Digest>, _>>()?;
+    let missing = futures::future::try_join_all(digests.into_iter().map(|digest| {
+      let key = self.blob_resource_name(digest);
+      let object_store = object_store.clone();
+      async move {
+        let exists = self
+          .retry_policy
+          .retry_call(
+            object_store,
+            |object_store| {
+              let key = key.clone();
+              async move { object_store.exists(&key).await }
+            },
+            |err: &ObjectStoreError| err.retryable,
+          )
+          .await
+          .map_err(|e| e.to_string())?;
+        Ok::<_, String>(if exists { None } else { Some(digest) })
+      }
+    }))
+    .await?;
+    Ok(missing.into_iter().flatten().collect())
+  }
+
+  ///
+  /// Checks for the existence of the file for each of the requested digests.
+  ///
+  async fn list_missing_files(
+    &self,
+    directory: &DirectoryStore,
+    request: remexec::FindMissingBlobsRequest,
+  ) -> Result<HashSet<Digest>, String> {
+    let digests = request
+      .blob_digests
+      .iter()
+      .map(|digest| digest.try_into())
+      .collect::<Result<Vec<Digest>, _>>()?;
+    let missing = futures::future::try_join_all(digests.into_iter().map(|digest| async move {
+      let exists = directory.exists(self.digest_function, digest).await?;
+      Ok::<_, String>(if exists { None } else { Some(digest) })
+    }))
+    .await?;
+    Ok(missing.into_iter().flatten().collect())
+  }
+
   pub fn find_missing_blobs_request(
     &self,
     digests: impl IntoIterator<Item = Digest>,
@@ -556,7 +1669,17 @@ impl ByteStore {
         request.instance_name = s.clone();
       }
 
-      let mut client = self.capabilities_client.as_ref().clone();
+      let mut client = match &self.backend {
+        Backend::Reapi {
+          capabilities_client,
+          ..
+        } => capabilities_client.as_ref().clone(),
+        Backend::ObjectStore(_) | Backend::Directory(_) => {
+          return Err(ByteStoreError::Other(
+            "Object and directory stores do not have capabilities.".to_owned(),
+          ))
+        }
+      };
       client
         .get_capabilities(request)
         .await
@@ -570,3 +1693,58 @@ impl ByteStore {
       .await
   }
 }
+
+///
+/// True if the given (REAPI) capabilities list zstd among the compressors selected by the given
+/// function.
+///
+fn supports_zstd(
+  capabilities: &ServerCapabilities,
+  compressors: impl FnOnce(&remexec::CacheCapabilities) -> &Vec<i32>,
+) -> bool {
+  capabilities.cache_capabilities.as_ref().map_or(false, |c| {
+    compressors(c).contains(&(remexec::compressor::Value::Zstd as i32))
+  })
+}
+
+///
+/// The estimated size of the entry for a blob of the given size in a batch request or response:
+/// its content, the hex of its hash, and its framing.
+///
+pub(crate) fn batch_entry_size_bytes(size_bytes: usize) -> usize {
+  size_bytes + 2 * hashing::FINGERPRINT_SIZE + BATCH_ENTRY_FRAMING_BYTES
+}
+
+///
+/// Groups the given items (in order) into batches of at most `MAX_BATCH_ENTRIES` items, whose
+/// total entry size (see `batch_entry_size_bytes`) is at most the given limit. Items whose entries
+/// are individually larger than the limit are returned separately.
+///
+pub(crate) fn partition_into_batches<T>(
+  items: Vec<T>,
+  size: impl Fn(&T) -> usize,
+  max_batch_size_bytes: usize,
+) -> (Vec<Vec<T>>, Vec<T>) {
+  let mut batches = Vec::new();
+  let mut oversized = Vec::new();
+  let mut batch = Vec::new();
+  let mut batch_size_bytes = 0;
+  for item in items {
+    let item_size_bytes = batch_entry_size_bytes(size(&item));
+    if item_size_bytes > max_batch_size_bytes {
+      oversized.push(item);
+      continue;
+    }
+    if batch_size_bytes + item_size_bytes > max_batch_size_bytes || batch.len() == MAX_BATCH_ENTRIES
+    {
+      batches.push(std::mem::take(&mut batch));
+      batch_size_bytes = 0;
+    }
+    batch_size_bytes += item_size_bytes;
+    batch.push(item);
+  }
+  if !batch.is_empty() {
+    batches.push(batch);
+  }
+  (batches, oversized)
+}
@@ -4,14 +4,17 @@ use std::collections::{BTreeMap, HashSet};
 use std::time::Duration;
 
 use bytes::Bytes;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls;
-use hashing::Digest;
-use mock::StubCAS;
+use hashing::{Digest, DigestFunction};
+use mock::{StubCAS, StubHttpStore, StubRedis};
+use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use testutil::data::{TestData, TestDirectory};
 use tokio::io::AsyncReadExt;
 use workunit_store::WorkunitStore;
 
-use crate::remote::ByteStore;
+use crate::remote::{batch_entry_size_bytes, partition_into_batches, ByteStore, MAX_BATCH_ENTRIES};
+use crate::small_blob_cache::SmallBlobCache;
 use crate::tests::{big_file_bytes, big_file_fingerprint, new_cas};
 use crate::MEGABYTES;
 
@@ -183,6 +186,65 @@ async fn write_file_one_chunk() {
   assert_eq!(blobs.get(&testdata.fingerprint()), Some(&testdata.bytes()));
 }
 
+#[tokio::test]
+async fn unsupported_digest_function() {
+  let _ = WorkunitStore::setup_for_tests();
+  let testdata = TestData::roland();
+  let cas = StubCAS::empty();
+
+  // The StubCAS only advertises support for SHA-256.
+  let store = new_byte_store(&cas).with_digest_function(DigestFunction::Blake3);
+  let error = store.store_bytes(testdata.bytes()).await.unwrap_err();
+  assert!(
+    error.contains("does not support the blake3 digest function"),
+    "Bad error message, got: {error}"
+  );
+  let error = load_file_bytes(&store, testdata.digest())
+    .await
+    .unwrap_err();
+  assert!(
+    error.contains("does not support the blake3 digest function"),
+    "Bad error message, got: {error}"
+  );
+  assert!(cas.blobs.lock().is_empty());
+}
+
+#[tokio::test]
+async fn digest_function_negotiation() {
+  let _ = WorkunitStore::setup_for_tests();
+  let testdata = TestData::roland();
+
+  // A server which does not support SHA-256 is reported, along with what it does support.
+  let cas = StubCAS::builder()
+    .cas_digest_functions(vec![remexec::digest_function::Value::Sha1 as i32])
+    .build();
+  let error = new_byte_store(&cas)
+    .store_bytes(testdata.bytes())
+    .await
+    .unwrap_err();
+  assert!(
+    error.contains("does not support the sha256 digest function: it only supports sha1"),
+    "Bad error message, got: {error}"
+  );
+  assert!(cas.blobs.lock().is_empty());
+
+  // A server which does not list any digest functions supports (only) SHA-256.
+  let cas = StubCAS::builder().cas_digest_functions(vec![]).build();
+  assert_eq!(
+    new_byte_store(&cas).store_bytes(testdata.bytes()).await,
+    Ok(())
+  );
+  let error = new_byte_store(&cas)
+    .with_digest_function(DigestFunction::Blake3)
+    .store_bytes(testdata.bytes())
+    .await
+    .unwrap_err();
+  assert!(
+    error.contains("does not support the blake3 digest function: it only supports sha256"),
+    "Bad error message, got: {error}"
+  );
+}
+
 #[tokio::test]
 async fn write_file_multiple_chunks() {
   let _ = WorkunitStore::setup_for_tests();
@@ -193,9 +255,13 @@ async fn write_file_multiple_chunks() {
     None,
     tls::Config::default(),
     BTreeMap::new(),
+    None,
     10 * 1024,
     Duration::from_secs(5),
-    1,
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
     256,
     None,
     0, // disable batch API, force streaming API
@@ -227,6 +293,323 @@ async fn write_file_multiple_chunks() {
   }
 }
 
+#[tokio::test]
+async fn write_file_resumes_after_interruptions() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder()
+    .cas_interrupts_writes(2, 100 * 1024)
+    .build();
+
+  let store = ByteStore::new(
+    &cas.address(),
+    None,
+    tls::Config::default(),
+    BTreeMap::new(),
+    None,
+    10 * 1024,
+    Duration::from_secs(5),
+    RetryPolicy {
+      attempts: 3,
+      ..RetryPolicy::default()
+    },
+    256,
+    None,
+    0, // disable batch API, force streaming API
+  )
+  .unwrap();
+
+  let all_the_henries = big_file_bytes();
+  assert_eq!(store.store_bytes(all_the_henries.clone()).await, Ok(()));
+  assert_eq!(
+    cas.blobs.lock().get(&big_file_fingerprint()),
+    Some(&all_the_henries)
+  );
+
+  // Each retry resumed where the interrupted write left off, so no bytes were sent twice.
+  let uploaded_size: usize = cas.write_message_sizes.lock().iter().sum();
+  assert_eq!(uploaded_size, all_the_henries.len());
+}
+
+#[tokio::test]
+async fn write_compressed_file_restarts_after_interruption() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder()
+    .cas_supports_zstd()
+    .cas_interrupts_writes(1, 1)
+    .build();
+
+  let store = ByteStore::new(
+    &cas.address(),
+    None,
+    tls::Config::default(),
+    BTreeMap::new(),
+    None,
+    10 * 1024,
+    Duration::from_secs(5),
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
+    256,
+    None,
+    0, // disable batch API, force streaming API
+  )
+  .unwrap();
+
+  let all_the_henries = big_file_bytes();
+  assert_eq!(store.store_bytes(all_the_henries.clone()).await, Ok(()));
+  assert_eq!(
+    cas.blobs.lock().get(&big_file_fingerprint()),
+    Some(&all_the_henries)
+  );
+}
+
+#[tokio::test]
+async fn write_and_load_compressed_file_via_stream() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder().cas_supports_zstd().build();
+
+  let store = ByteStore::new(
+    &cas.address(),
+    None,
+    tls::Config::default(),
+    BTreeMap::new(),
+    None,
+    10 * 1024,
+    Duration::from_secs(5),
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
+    256,
+    None,
+    0, // disable batch API, force streaming API
+  )
+  .unwrap();
+
+  let all_the_henries = big_file_bytes();
+  let digest = Digest::of_bytes(&all_the_henries);
+  assert_eq!(store.store_bytes(all_the_henries.clone()).await, Ok(()));
+  assert_eq!(
+    cas.blobs.lock().get(&big_file_fingerprint()),
+    Some(&all_the_henries)
+  );
+
+  let uploaded_size: usize = cas.write_message_sizes.lock().iter().sum();
+  assert!(
+    uploaded_size < all_the_henries.len(),
+    "Uploaded {uploaded_size} bytes, which is not smaller than {}",
+    all_the_henries.len()
+  );
+
+  assert_eq!(
+    load_file_bytes(&store, digest).await,
+    Ok(Some(all_the_henries))
+  );
+}
+
+#[tokio::test]
+async fn write_and_load_compressed_file_via_batch() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder().cas_supports_zstd().build();
+  let store = new_byte_store(&cas);
+
+  let testdata = TestData::new(&"abcdefgh".repeat(1024));
+  assert_eq!(store.store_bytes(testdata.bytes()).await, Ok(()));
+  assert_eq!(
+    cas.blobs.lock().get(&testdata.fingerprint()),
+    Some(&testdata.bytes())
+  );
+
+  let write_message_sizes = cas.write_message_sizes.lock().clone();
+  assert_eq!(write_message_sizes.len(), 1);
+  assert!(write_message_sizes[0] < testdata.len());
+
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+}
+
+#[tokio::test]
+async fn write_and_load_with_transfer_limits() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder().chunk_size_bytes(10 * 1024).build();
+  let store = ByteStore::new(
+    &cas.address(),
+    None,
+    tls::Config::default(),
+    BTreeMap::new(),
+    None,
+    10 * 1024,
+    Duration::from_secs(5),
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
+    256,
+    None,
+    0, // disable batch API, force streaming API
+  )
+  .unwrap()
+  .with_transfer_limits(Some(1), Some(1), Some(10 * MEGABYTES));
+
+  let all_the_henries = big_file_bytes();
+  let digest = Digest::of_bytes(&all_the_henries);
+  // Concurrent transfers wait for one another, rather than failing.
+  let (first, second) = futures::join!(
+    store.store_bytes(all_the_henries.clone()),
+    store.store_bytes(all_the_henries.clone())
+  );
+  assert_eq!((first, second), (Ok(()), Ok(())));
+
+  let (first, second) = futures::join!(
+    load_file_bytes(&store, digest),
+    load_file_bytes(&store, digest)
+  );
+  assert_eq!(first, Ok(Some(all_the_henries.clone())));
+  assert_eq!(second, Ok(Some(all_the_henries)));
+}
+
+#[tokio::test]
+async fn store_and_load_bytes_batch() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::empty();
+  let store = ByteStore::new(
+    &cas.address(),
+    None,
+    tls::Config::default(),
+    BTreeMap::new(),
+    None,
+    10 * MEGABYTES,
+    Duration::from_secs(5),
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
+    256,
+    None,
+    1024, // only the small blobs fit in a batch
+  )
+  .unwrap();
+
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let all_the_henries = big_file_bytes();
+  assert_eq!(
+    store
+      .store_bytes_batch(vec![
+        roland.bytes(),
+        all_the_henries.clone(),
+        catnip.bytes()
+      ])
+      .await,
+    Ok(())
+  );
+  assert_eq!(
+    cas.blobs.lock().get(&roland.fingerprint()),
+    Some(&roland.bytes())
+  );
+  assert_eq!(
+    cas.blobs.lock().get(&catnip.fingerprint()),
+    Some(&catnip.bytes())
+  );
+  assert_eq!(
+    cas.blobs.lock().get(&big_file_fingerprint()),
+    Some(&all_the_henries)
+  );
+
+  // Only the large blob is read via the ByteStream API, and missing blobs are omitted.
+  let all_the_henries_digest = Digest::of_bytes(&all_the_henries);
+  let loaded = store
+    .load_bytes_batch(vec![
+      roland.digest(),
+      catnip.digest(),
+      all_the_henries_digest,
+      TestData::forty_chars().digest(),
+    ])
+    .await
+    .unwrap();
+  assert_eq!(
+    loaded,
+    vec![
+      (roland.digest(), roland.bytes()),
+      (catnip.digest(), catnip.bytes()),
+      (all_the_henries_digest, all_the_henries),
+    ]
+    .into_iter()
+    .collect()
+  );
+  assert_eq!(cas.read_request_count(), 1);
+}
+
+#[tokio::test]
+async fn load_bytes_batch_compressed() {
+  let _ = WorkunitStore::setup_for_tests();
+  let testdata = TestData::new(&"abcdefgh".repeat(64));
+  let cas = StubCAS::builder()
+    .cas_supports_zstd()
+    .file(&testdata)
+    .build();
+
+  let loaded = new_byte_store(&cas)
+    .load_bytes_batch(vec![testdata.digest()])
+    .await
+    .unwrap();
+  assert_eq!(loaded.get(&testdata.digest()), Some(&testdata.bytes()));
+  assert_eq!(cas.read_request_count(), 0);
+}
+
+#[test]
+fn batches_of_many_small_blobs_account_for_entry_overhead() {
+  // The content of a hundred one-byte blobs would fit in a limit of ten entries many times over,
+  // but their framing does not.
+  let (batches, oversized) =
+    partition_into_batches(vec![1; 100], |size| *size, 10 * batch_entry_size_bytes(1));
+  assert_eq!(batches.len(), 10);
+  assert!(batches.iter().all(|batch| batch.len() == 10));
+  assert!(oversized.is_empty());
+}
+
+#[test]
+fn batches_of_empty_blobs_are_bounded() {
+  let (batches, oversized) =
+    partition_into_batches(vec![0; 2 * MAX_BATCH_ENTRIES + 1], |size| *size, usize::MAX);
+  assert_eq!(
+    batches.iter().map(Vec::len).collect::<Vec<_>>(),
+    vec![MAX_BATCH_ENTRIES, MAX_BATCH_ENTRIES, 1]
+  );
+  assert!(oversized.is_empty());
+}
+
+#[test]
+fn batches_near_the_limit() {
+  let max_batch_size_bytes = batch_entry_size_bytes(100);
+  let (batches, oversized) =
+    partition_into_batches(vec![100, 101, 0, 0], |size| *size, max_batch_size_bytes);
+  // A blob whose entry is exactly the limit fills a batch, and one a byte larger is oversized. The
+  // entries of empty blobs are not free.
+  assert_eq!(batches, vec![vec![100], vec![0, 0]]);
+  assert_eq!(oversized, vec![101]);
+  assert!(2 * batch_entry_size_bytes(0) <= max_batch_size_bytes);
+  assert!(3 * batch_entry_size_bytes(0) > max_batch_size_bytes);
+}
+
+#[tokio::test]
+async fn store_bytes_batch_errors() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::cas_always_errors();
+
+  let error = new_byte_store(&cas)
+    .store_bytes_batch(vec![TestData::roland().bytes(), TestData::catnip().bytes()])
+    .await
+    .expect_err("Want error");
+  assert!(
+    error.contains("StubCAS is configured to always fail"),
+    "Bad error message, got: {error}"
+  );
+}
+
 #[tokio::test]
 async fn write_empty_file() {
   let _ = WorkunitStore::setup_for_tests();
@@ -266,9 +649,13 @@ async fn write_connection_error() {
     None,
     tls::Config::default(),
     BTreeMap::new(),
+    None,
     10 * 1024 * 1024,
     Duration::from_secs(1),
-    1,
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
     256,
     None,
     super::tests::STORE_BATCH_API_SIZE_LIMIT,
@@ -334,15 +721,191 @@ async fn list_missing_digests_error() {
   );
 }
 
+#[tokio::test]
+async fn static_http_store_round_trip() {
+  let _ = WorkunitStore::setup_for_tests();
+  let server = StubHttpStore::new(false);
+  let store = new_byte_store_at(&format!("static+http://{}/cache", server.host()));
+  let testdata = TestData::roland();
+
+  assert_eq!(load_bytes(&store, testdata.digest()).await, Ok(None));
+  assert_eq!(
+    store
+      .list_missing_digests(store.find_missing_blobs_request(vec![testdata.digest()]))
+      .await,
+    Ok(HashSet::from([testdata.digest()]))
+  );
+
+  store.store_bytes(testdata.bytes()).await.unwrap();
+  assert_eq!(
+    server
+      .objects
+      .lock()
+      .get(&format!(
+        "/cache/blobs/{}/{}",
+        testdata.fingerprint(),
+        testdata.len()
+      ))
+      .cloned(),
+    Some(testdata.bytes())
+  );
+  assert_eq!(
+    load_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(
+    store
+      .list_missing_digests(store.find_missing_blobs_request(vec![testdata.digest()]))
+      .await,
+    Ok(HashSet::new())
+  );
+}
+
+#[tokio::test]
+async fn static_http_store_read_only() {
+  let _ = WorkunitStore::setup_for_tests();
+  let server = StubHttpStore::new(true);
+  let store = new_byte_store_at(&format!("static+http://{}", server.host()));
+
+  let error = store
+    .store_bytes(TestData::roland().bytes())
+    .await
+    .expect_err("Want error");
+  assert!(
+    error.contains("405 Method Not Allowed"),
+    "Bad error message, got: {error}"
+  );
+}
+
+#[tokio::test]
+async fn directory_store_round_trip() {
+  let _ = WorkunitStore::setup_for_tests();
+  let dir = tempfile::TempDir::new().unwrap();
+  let store = new_byte_store_at(&format!("file://{}", dir.path().display()));
+  let testdata = TestData::roland();
+  let big_file = TestData::new(&"12345".repeat(MEGABYTES));
+
+  assert_eq!(load_bytes(&store, testdata.digest()).await, Ok(None));
+  assert_eq!(
+    store
+      .list_missing_digests(
+        store.find_missing_blobs_request(vec![testdata.digest(), big_file.digest()])
+      )
+      .await,
+    Ok(HashSet::from([testdata.digest(), big_file.digest()]))
+  );
+
+  store.store_bytes(testdata.bytes()).await.unwrap();
+  store.store_bytes(big_file.bytes()).await.unwrap();
+  assert_eq!(
+    load_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(
+    load_file_bytes(&store, big_file.digest()).await,
+    Ok(Some(big_file.bytes()))
+  );
+  assert_eq!(
+    store
+      .list_missing_digests(
+        store.find_missing_blobs_request(vec![testdata.digest(), big_file.digest()])
+      )
+      .await,
+    Ok(HashSet::new())
+  );
+  // No temporary files are left behind.
+  let leftovers = walkdir::WalkDir::new(dir.path())
+    .min_depth(1)
+    .into_iter()
+    .filter_map(Result::ok)
+    .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+    .count();
+  assert_eq!(leftovers, 0);
+}
+
+#[tokio::test]
+async fn small_blob_cache_reads_through() {
+  let cas = new_cas(1024);
+  let redis = StubRedis::new().await;
+  let store = new_byte_store_with_small_blob_cache(&cas, &redis.address(), 1024);
+  let testdata = TestData::roland();
+
+  // A miss populates the cache, from which subsequent loads are served.
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(redis.values.lock().len(), 1);
+  assert!(cas.remove(testdata.fingerprint()));
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  assert_eq!(cas.read_request_count(), 1);
+}
+
+#[tokio::test]
+async fn small_blob_cache_writes_through() {
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::empty();
+  let redis = StubRedis::new().await;
+  let testdata = TestData::roland();
+
+  let store = new_byte_store_with_small_blob_cache(&cas, &redis.address(), 1024);
+  store.store_bytes(testdata.bytes()).await.unwrap();
+  assert_eq!(
+    cas.blobs.lock().get(&testdata.fingerprint()),
+    Some(&testdata.bytes())
+  );
+  assert_eq!(redis.values.lock().len(), 1);
+
+  // Blobs which are larger than the maximum size are not cached.
+  let store = new_byte_store_with_small_blob_cache(&cas, &redis.address(), 1);
+  store.store_bytes(TestData::catnip().bytes()).await.unwrap();
+  assert_eq!(redis.values.lock().len(), 1);
+}
+
+#[tokio::test]
+async fn small_blob_cache_unavailable() {
+  let cas = new_cas(1024);
+  let testdata = TestData::roland();
+
+  // Failures to access the cache fall back to the remote store.
+  let store = new_byte_store_with_small_blob_cache(&cas, "redis://127.0.0.1:1", 1024);
+  assert_eq!(
+    load_file_bytes(&store, testdata.digest()).await,
+    Ok(Some(testdata.bytes()))
+  );
+  store.store_bytes(testdata.bytes()).await.unwrap();
+}
+
+fn new_byte_store_with_small_blob_cache(
+  cas: &StubCAS,
+  address: &str,
+  max_size_bytes: usize,
+) -> ByteStore {
+  new_byte_store(cas).with_small_blob_cache(
+    SmallBlobCache::new(address, max_size_bytes, Duration::from_secs(1)).unwrap(),
+  )
+}
+
 fn new_byte_store(cas: &StubCAS) -> ByteStore {
+  new_byte_store_at(&cas.address())
+}
+
+fn new_byte_store_at(address: &str) -> ByteStore {
   ByteStore::new(
-    &cas.address(),
+    address,
     None,
     tls::Config::default(),
     BTreeMap::new(),
+    None,
     10 * MEGABYTES,
     Duration::from_secs(1),
-    1,
+    RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
     256,
     None,
     super::tests::STORE_BATCH_API_SIZE_LIMIT,
@@ -0,0 +1,205 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::time::Duration;
+
+use bytes::Bytes;
+use hashing::Digest;
+use parking_lot::Mutex;
+use reqwest::Url;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const DEFAULT_REDIS_PORT: u16 = 6379;
+
+///
+/// A Redis server which holds small blobs in front of a remote store, to avoid the latency of
+/// fetching them (mostly Directories and action results) from the remote store itself.
+///
+/// The cache is written through to (rather than in place of) the remote store, so entries may be
+/// evicted freely: the server should be configured with an eviction policy such as
+/// `allkeys-lru`. Errors from the cache are logged rather than failing requests.
+///
+pub(crate) struct SmallBlobCache {
+  address: String,
+  username: Option<String>,
+  password: Option<String>,
+  database: Option<u32>,
+  max_size_bytes: usize,
+  timeout: Duration,
+  idle_connections: Mutex<Vec<Connection>>,
+}
+
+/// A reply to a Redis command. Error replies are converted to `Err`s.
+#[derive(Debug, Eq, PartialEq)]
+enum Reply {
+  Nil,
+  Simple(String),
+  Integer(i64),
+  Bulk(Bytes),
+}
+
+struct Connection {
+  stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+  async fn command(&mut self, args: &[&[u8]]) -> Result<Reply, String> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+      request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+      request.extend_from_slice(arg);
+      request.extend_from_slice(b"\r\n");
+    }
+    self
+      .stream
+      .get_mut()
+      .write_all(&request)
+      .await
+      .map_err(|e| format!("Failed to send command: {e}"))?;
+    self.read_reply().await
+  }
+
+  async fn read_reply(&mut self) -> Result<Reply, String> {
+    let mut line = Vec::new();
+    self
+      .stream
+      .read_until(b'\n', &mut line)
+      .await
+      .map_err(|e| format!("Failed to read reply: {e}"))?;
+    let line = std::str::from_utf8(&line)
+      .ok()
+      .and_then(|line| line.strip_suffix("\r\n"))
+      .ok_or_else(|| "Malformed reply.".to_owned())?;
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+      "+" => Ok(Reply::Simple(value.to_owned())),
+      "-" => Err(format!("Server error: {value}")),
+      ":" => value
+        .parse()
+        .map(Reply::Integer)
+        .map_err(|e| format!("Malformed integer reply `{value}`: {e}")),
+      "$" => {
+        let len: i64 = value
+          .parse()
+          .map_err(|e| format!("Malformed bulk reply length `{value}`: {e}"))?;
+        if len < 0 {
+          return Ok(Reply::Nil);
+        }
+        // NB: Bulk replies are followed by a CRLF.
+        let mut data = vec![0; len as usize + 2];
+        self
+          .stream
+          .read_exact(&mut data)
+          .await
+          .map_err(|e| format!("Failed to read reply: {e}"))?;
+        data.truncate(len as usize);
+        Ok(Reply::Bulk(Bytes::from(data)))
+      }
+      kind => Err(format!("Unsupported reply type `{kind}`.")),
+    }
+  }
+}
+
+impl SmallBlobCache {
+  ///
+  /// Creates a cache for blobs no larger than the given size, backed by the Redis server at the
+  /// given address, which has the form `redis://[[username]:password@]host[:port][/database]`.
+  ///
+  pub fn new(address: &str, max_size_bytes: usize, timeout: Duration) -> Result<Self, String> {
+    let url = Url::parse(address).map_err(|e| format!("Invalid small blob cache address: {e}"))?;
+    if url.scheme() != "redis" {
+      return Err(format!(
+        "Unsupported small blob cache address `{address}`: only `redis://` is supported."
+      ));
+    }
+    let host = url
+      .host_str()
+      .ok_or_else(|| format!("Small blob cache address `{address}` does not have a host."))?;
+    let database = match url.path().trim_matches('/') {
+      "" => None,
+      database => Some(database.parse().map_err(|e| {
+        format!("Invalid database `{database}` in small blob cache address `{address}`: {e}")
+      })?),
+    };
+    Ok(Self {
+      address: format!("{host}:{}", url.port().unwrap_or(DEFAULT_REDIS_PORT)),
+      username: Some(url.username().to_owned()).filter(|u| !u.is_empty()),
+      password: url.password().map(str::to_owned),
+      database,
+      max_size_bytes,
+      timeout,
+      idle_connections: Mutex::default(),
+    })
+  }
+
+  ///
+  /// True if the given blob is small enough to be cached.
+  ///
+  pub fn accepts(&self, digest: Digest) -> bool {
+    digest.size_bytes <= self.max_size_bytes
+  }
+
+  async fn connect(&self) -> Result<Connection, String> {
+    let stream = TcpStream::connect(&self.address)
+      .await
+      .map_err(|e| format!("Failed to connect to {}: {e}", self.address))?;
+    let mut connection = Connection {
+      stream: BufReader::new(stream),
+    };
+    if let Some(password) = &self.password {
+      match &self.username {
+        Some(username) => {
+          connection
+            .command(&[b"AUTH", username.as_bytes(), password.as_bytes()])
+            .await?
+        }
+        None => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+      };
+    }
+    if let Some(database) = self.database {
+      connection
+        .command(&[b"SELECT", database.to_string().as_bytes()])
+        .await?;
+    }
+    Ok(connection)
+  }
+
+  ///
+  /// Runs the given command on an idle connection (or a new one, if none are idle). Connections
+  /// are only reused if their command succeeds, since a failed command may have left a reply
+  /// unread.
+  ///
+  async fn command(&self, args: &[&[u8]]) -> Result<Reply, String> {
+    let command = async {
+      let idle_connection = self.idle_connections.lock().pop();
+      let mut connection = match idle_connection {
+        Some(connection) => connection,
+        None => self.connect().await?,
+      };
+      let reply = connection.command(args).await?;
+      self.idle_connections.lock().push(connection);
+      Ok(reply)
+    };
+    tokio::time::timeout(self.timeout, command)
+      .await
+      .map_err(|_| {
+        format!(
+          "Timed out after {:?} waiting for {}.",
+          self.timeout, self.address
+        )
+      })?
+  }
+
+  pub async fn get(&self, key: &str) -> Result<Option<Bytes>, String> {
+    match self.command(&[b"GET", key.as_bytes()]).await? {
+      Reply::Nil => Ok(None),
+      Reply::Bulk(bytes) => Ok(Some(bytes)),
+      reply => Err(format!("Unexpected reply to GET: {reply:?}")),
+    }
+  }
+
+  pub async fn set(&self, key: &str, bytes: &[u8]) -> Result<(), String> {
+    self.command(&[b"SET", key.as_bytes(), bytes]).await?;
+    Ok(())
+  }
+}
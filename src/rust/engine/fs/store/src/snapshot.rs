@@ -14,7 +14,7 @@ use futures::FutureExt;
 
 use fs::{
   DigestTrie, Dir, DirectoryDigest, Entry, File, GitignoreStyleExcludes, GlobMatching, PathStat,
-  PosixFS, PreparedPathGlobs, SymlinkBehavior, EMPTY_DIGEST_TREE,
+  PosixFS, PreparedPathGlobs, SymlinkBehavior, UnicodeNormalization, EMPTY_DIGEST_TREE,
 };
 use hashing::{Digest, EMPTY_DIGEST};
 
@@ -30,6 +30,22 @@ pub struct Snapshot {
   pub tree: DigestTrie,
 }
 
+///
+/// The paths which differ between two Snapshots. See `Snapshot::diff`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotDiff {
+  /// Paths which are only present in the second Snapshot, including the contents of directories
+  /// which are only present in the second Snapshot.
+  pub added: Vec<PathBuf>,
+  /// Paths which are only present in the first Snapshot, including the contents of directories
+  /// which are only present in the first Snapshot.
+  pub removed: Vec<PathBuf>,
+  /// Files whose content differs, and symlinks whose target differs. A path which is a different
+  /// type of entry in each Snapshot is instead both removed and added.
+  pub changed: Vec<PathBuf>,
+}
+
 impl Eq for Snapshot {}
 
 impl PartialEq for Snapshot {
@@ -80,6 +96,51 @@ impl Snapshot {
     directories
   }
 
+  ///
+  /// Computes the paths which differ between `a` and `b`, sorted. The DigestTries are compared
+  /// structurally, so subtrees with identical digests are never traversed.
+  ///
+  pub fn diff(a: &Snapshot, b: &Snapshot) -> SnapshotDiff {
+    if a.digest == b.digest {
+      return SnapshotDiff::default();
+    }
+    let trie_diff = a.tree.diff(&b.tree);
+
+    let mut removed = trie_diff.our_unique_files;
+    removed.extend(trie_diff.our_unique_symlinks);
+    for dir in trie_diff.our_unique_dirs {
+      Self::push_with_descendants(&a.tree, dir, &mut removed);
+    }
+    let mut added = trie_diff.their_unique_files;
+    added.extend(trie_diff.their_unique_symlinks);
+    for dir in trie_diff.their_unique_dirs {
+      Self::push_with_descendants(&b.tree, dir, &mut added);
+    }
+    let mut changed = trie_diff.changed_files;
+    changed.extend(trie_diff.changed_symlinks);
+
+    added.sort();
+    removed.sort();
+    changed.sort();
+    SnapshotDiff {
+      added,
+      removed,
+      changed,
+    }
+  }
+
+  fn push_with_descendants(tree: &DigestTrie, dir: PathBuf, paths: &mut Vec<PathBuf>) {
+    if let Ok(Some(Entry::Directory(d))) = tree.entry(&dir) {
+      d.tree().walk(SymlinkBehavior::Aware, &mut |path, _| {
+        // The walk begins with the directory itself, at the empty path.
+        if !path.as_os_str().is_empty() {
+          paths.push(dir.join(path));
+        }
+      });
+    }
+    paths.push(dir);
+  }
+
   pub async fn from_path_stats<
     S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
     Error: fmt::Debug + 'static + Send,
@@ -112,6 +173,31 @@ impl Snapshot {
       path_stats.iter().map(|p| p.into()).collect(),
       &file_digests_map,
     )?;
+    // A Snapshot captured on a case-sensitive filesystem might later be materialized on a
+    // case-insensitive one.
+    tree.check_case_collisions().map_err(|e| e.to_string())?;
+    Ok(Self {
+      digest: tree.compute_root_digest(),
+      tree,
+    })
+  }
+
+  ///
+  /// Converts the path names of this Snapshot to the given normalization form, warning about each
+  /// name that it changes (which will be captured under a name that differs from the name on disk).
+  ///
+  pub fn normalize_unicode(self, normalization: UnicodeNormalization) -> Result<Snapshot, String> {
+    let (tree, renamed) = self.tree.normalize_unicode(normalization)?;
+    if renamed.is_empty() {
+      return Ok(self);
+    }
+    for (original, normalized) in renamed {
+      log::warn!(
+        "Capturing {} as {} due to Unicode normalization.",
+        original.display(),
+        normalized.display()
+      );
+    }
     Ok(Self {
       digest: tree.compute_root_digest(),
       tree,
@@ -158,25 +244,62 @@ impl Snapshot {
     if let Ok(snapshot) = snapshot_result {
       Ok(snapshot)
     } else {
-      let posix_fs = Arc::new(PosixFS::new_with_symlink_behavior(
+      Self::capture_snapshot_with_ignorer(
+        store,
+        executor,
         root_path,
+        path_globs,
         GitignoreStyleExcludes::create(vec![])?,
-        executor,
-        SymlinkBehavior::Oblivious,
-      )?);
-
-      let path_stats = posix_fs
-        .expand_globs(path_globs, SymlinkBehavior::Oblivious, None)
-        .await
-        .map_err(|err| format!("Error expanding globs: {err}"))?;
-      Snapshot::from_path_stats(
-        OneOffStoreFileByDigest::new(store, posix_fs, true),
-        path_stats,
       )
       .await
     }
   }
 
+  ///
+  /// Like `Snapshot::capture_snapshot_from_arbitrary_root`, but skips paths which are ignored by
+  /// the `.gitignore` file in any directory at or below the root (including negated patterns in
+  /// nested files), without requiring callers to translate them into exclude globs.
+  ///
+  pub async fn capture_snapshot_respecting_gitignore<P: AsRef<Path> + Send + 'static>(
+    store: Store,
+    executor: task_executor::Executor,
+    root_path: P,
+    path_globs: PreparedPathGlobs,
+  ) -> Result<Snapshot, String> {
+    let ignorer = GitignoreStyleExcludes::create_with_nested_gitignore_files(
+      vec![],
+      root_path.as_ref().to_owned(),
+    )?;
+    Self::capture_snapshot_with_ignorer(store, executor, root_path, path_globs, ignorer).await
+  }
+
+  async fn capture_snapshot_with_ignorer<P: AsRef<Path> + Send + 'static>(
+    store: Store,
+    executor: task_executor::Executor,
+    root_path: P,
+    path_globs: PreparedPathGlobs,
+    ignorer: Arc<GitignoreStyleExcludes>,
+  ) -> Result<Snapshot, String> {
+    let posix_fs = Arc::new(PosixFS::new_with_symlink_behavior(
+      root_path,
+      ignorer,
+      executor,
+      SymlinkBehavior::Oblivious,
+    )?);
+
+    let path_stats = posix_fs
+      .expand_globs(path_globs, SymlinkBehavior::Oblivious, None)
+      .await
+      .map_err(|err| format!("Error expanding globs: {err}"))?;
+    let normalization = store.unicode_normalization();
+    Snapshot::from_path_stats(
+      OneOffStoreFileByDigest::new(store, posix_fs, true),
+      path_stats,
+    )
+    .await?
+    .normalize_unicode(normalization)
+  }
+
   /// # Safety
   ///
   /// This should only be used for testing, as this will always create an invalid Snapshot.
@@ -1,5 +1,6 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -9,10 +10,12 @@ use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
 use testutil::data::TestDirectory;
 use testutil::make_file;
 
-use crate::{OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotOps, Store, StoreError};
+use crate::{
+  OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotDiff, SnapshotOps, Store, StoreError,
+};
 use fs::{
-  Dir, DirectoryDigest, File, GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching,
-  PathGlobs, PathStat, PosixFS, StrictGlobMatching, SymlinkBehavior,
+  DigestTrie, Dir, DirectoryDigest, File, GitignoreStyleExcludes, GlobExpansionConjunction,
+  GlobMatching, PathGlobs, PathStat, PosixFS, StrictGlobMatching, SymlinkBehavior, TypedPath,
 };
 
 pub const STR: &str = "European Burmese";
@@ -524,6 +527,38 @@ async fn strip_subdir_not_in_dir() {
   );
 }
 
+#[tokio::test]
+async fn snapshot_respecting_gitignore() {
+  let (store, dir, _, _) = setup();
+
+  std::fs::create_dir(dir.path().join("build")).unwrap();
+  make_file(&dir.path().join(".gitignore"), b"/build\n", 0o600);
+  make_file(&dir.path().join("build/.gitignore"), b"!*\n", 0o600);
+  make_file(&dir.path().join("build/out"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+
+  let path_globs = PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let snapshot = Snapshot::capture_snapshot_respecting_gitignore(
+    store,
+    task_executor::Executor::new(),
+    dir.path().to_owned(),
+    path_globs,
+  )
+  .await
+  .unwrap();
+  // An ignored directory is not entered, so files below it cannot be re-included.
+  assert_eq!(
+    snapshot.files(),
+    vec![PathBuf::from(".gitignore"), PathBuf::from("roland")]
+  );
+}
+
 fn make_dir_stat(root: &Path, relpath: &Path) -> PathStat {
   std::fs::create_dir(root.join(relpath)).unwrap();
   PathStat::dir(relpath.to_owned(), Dir(relpath.to_owned()))
@@ -560,3 +595,85 @@ pub async fn expand_all_sorted(posix_fs: Arc<PosixFS>) -> Vec<PathStat> {
   v.sort_by(|a, b| a.path().cmp(b.path()));
   v
 }
+
+fn snapshot_of(files: &[(&str, &str)], links: &[(&str, &str)]) -> Snapshot {
+  let paths = files
+    .iter()
+    .map(|(path, _)| PathBuf::from(path))
+    .chain(links.iter().map(|(path, _)| PathBuf::from(path)))
+    .collect::<Vec<_>>();
+  let targets = links
+    .iter()
+    .map(|(_, target)| PathBuf::from(target))
+    .collect::<Vec<_>>();
+  let typed_paths = paths[..files.len()]
+    .iter()
+    .map(|path| TypedPath::File {
+      path,
+      is_executable: false,
+    })
+    .chain(
+      paths[files.len()..]
+        .iter()
+        .zip(targets.iter())
+        .map(|(path, target)| TypedPath::Link { path, target }),
+    )
+    .collect();
+  let file_digests = files
+    .iter()
+    .map(|(path, content)| (PathBuf::from(path), Digest::of_bytes(content.as_bytes())))
+    .chain(
+      links
+        .iter()
+        .map(|(path, _)| (PathBuf::from(path), EMPTY_DIGEST)),
+    )
+    .collect::<HashMap<_, _>>();
+  let tree = DigestTrie::from_unique_paths(typed_paths, &file_digests).unwrap();
+  Snapshot {
+    digest: tree.compute_root_digest(),
+    tree,
+  }
+}
+
+#[test]
+fn snapshot_diff() {
+  let a = snapshot_of(
+    &[
+      ("same/a.txt", "a"),
+      ("changed.txt", "before"),
+      ("removed/nested/b.txt", "b"),
+      ("file_then_dir", "c"),
+    ],
+    &[("link", "same/a.txt")],
+  );
+  let b = snapshot_of(
+    &[
+      ("same/a.txt", "a"),
+      ("changed.txt", "after"),
+      ("added.txt", "d"),
+      ("file_then_dir/e.txt", "e"),
+    ],
+    &[("link", "changed.txt")],
+  );
+
+  assert_eq!(
+    Snapshot::diff(&a, &b),
+    SnapshotDiff {
+      added: vec![
+        PathBuf::from("added.txt"),
+        PathBuf::from("file_then_dir"),
+        PathBuf::from("file_then_dir/e.txt"),
+      ],
+      removed: vec![
+        PathBuf::from("file_then_dir"),
+        PathBuf::from("removed"),
+        PathBuf::from("removed/nested"),
+        PathBuf::from("removed/nested/b.txt"),
+      ],
+      changed: vec![PathBuf::from("changed.txt"), PathBuf::from("link")],
+    }
+  );
+
+  // Identical Snapshots are not traversed at all.
+  assert_eq!(Snapshot::diff(&a, &a), SnapshotDiff::default());
+}
@@ -0,0 +1,163 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fs::{Dir, DirectoryDigest, GitignoreStyleExcludes, PosixFS, Stat, SymlinkBehavior};
+use futures::future::{self, BoxFuture, FutureExt};
+use hashing::Digest;
+use protos::gen::build::bazel::remote::execution::v2 as remexec;
+use tokio::sync::Semaphore;
+use workunit_store::{in_workunit, Level, Metric};
+
+use crate::{Snapshot, Store};
+
+struct StreamingCapture {
+  store: Store,
+  posix_fs: PosixFS,
+  files_semaphore: Semaphore,
+}
+
+impl StreamingCapture {
+  ///
+  /// Stores the Directory at the given path (relative to the root), and returns its Digest.
+  ///
+  /// The files of each directory are stored concurrently with the (depth-first) walk of its
+  /// subdirectories, but subdirectories are walked one at a time, so only the listings of the
+  /// directories between the root and the current directory are held in memory.
+  ///
+  fn capture_directory(self: Arc<Self>, dir: Dir) -> BoxFuture<'static, Result<Digest, String>> {
+    async move {
+      let listing = self
+        .posix_fs
+        .scandir(dir.clone())
+        .await
+        .map_err(|e| format!("Failed to scan {}: {e}", dir.0.display()))?;
+
+      let mut file_stats = Vec::new();
+      let mut dir_stats = Vec::new();
+      let mut symlinks = Vec::new();
+      for stat in listing.0 {
+        let name = file_name(stat.path())?;
+        match stat {
+          Stat::File(f) => file_stats.push((name, f)),
+          Stat::Dir(d) => dir_stats.push((name, d)),
+          Stat::Link(l) => symlinks.push(remexec::SymlinkNode {
+            name,
+            target: l
+              .target
+              .to_str()
+              .ok_or_else(|| format!("Symlink target {:?} is not valid UTF-8.", l.target))?
+              .to_owned(),
+            ..remexec::SymlinkNode::default()
+          }),
+        }
+      }
+
+      let files = future::try_join_all(file_stats.into_iter().map(|(name, f)| {
+        let capture = self.clone();
+        async move {
+          let _permit = capture
+            .files_semaphore
+            .acquire()
+            .await
+            .map_err(|e| format!("Failed to acquire permit to store {name}: {e}"))?;
+          let digest = capture
+            .store
+            .store_file(true, true, capture.posix_fs.file_path(&f))
+            .await?;
+          increment_counter(Metric::StreamingCaptureFiles);
+          Ok::<_, String>(remexec::FileNode {
+            name,
+            digest: Some((&digest).into()),
+            is_executable: f.is_executable,
+            ..remexec::FileNode::default()
+          })
+        }
+      }));
+      let directories = async {
+        let mut directories = Vec::with_capacity(dir_stats.len());
+        for (name, d) in dir_stats {
+          let digest = self.clone().capture_directory(d).await?;
+          directories.push(remexec::DirectoryNode {
+            name,
+            digest: Some((&digest).into()),
+          });
+        }
+        Ok::<_, String>(directories)
+      };
+      let (files, directories) = future::try_join(files, directories).await?;
+
+      // NB: The listing is sorted by path, so each kind of node is already sorted by name.
+      let directory = remexec::Directory {
+        files,
+        directories,
+        symlinks,
+        ..remexec::Directory::default()
+      };
+      let digest = self.store.record_directory(&directory, true).await?;
+      increment_counter(Metric::StreamingCaptureDirectories);
+      Ok(digest)
+    }
+    .boxed()
+  }
+}
+
+fn file_name(path: &Path) -> Result<String, String> {
+  path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .map(str::to_owned)
+    .ok_or_else(|| format!("{} does not have a valid UTF-8 file name.", path.display()))
+}
+
+fn increment_counter(metric: Metric) {
+  if let Some(mut workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+    workunit_store_handle.store.increment_counter(metric, 1);
+  }
+}
+
+impl Snapshot {
+  ///
+  /// Captures all of the (non-ignored) files below the given root, and returns the digest of the
+  /// resulting Directory.
+  ///
+  /// Unlike `Snapshot::capture_snapshot_from_arbitrary_root`, files are stored as the walk
+  /// proceeds (with at most `concurrency` files being stored at once), and each Directory is
+  /// stored as soon as its children have been, so memory usage is bounded by the depth and width
+  /// of the tree rather than by its total size. For the same reason, the returned DirectoryDigest
+  /// does not hold a DigestTrie. Symlinks are captured as symlinks.
+  ///
+  /// Progress is reported by the `streaming_capture_files` and `streaming_capture_directories`
+  /// counters.
+  ///
+  pub async fn capture_snapshot_streaming(
+    store: Store,
+    executor: task_executor::Executor,
+    root_path: PathBuf,
+    ignorer: Arc<GitignoreStyleExcludes>,
+    concurrency: usize,
+  ) -> Result<DirectoryDigest, String> {
+    let posix_fs =
+      PosixFS::new_with_symlink_behavior(&root_path, ignorer, executor, SymlinkBehavior::Aware)?;
+    let capture = Arc::new(StreamingCapture {
+      store,
+      posix_fs,
+      files_semaphore: Semaphore::new(concurrency.max(1)),
+    });
+    let root_dir = Dir(PathBuf::new());
+
+    let digest = if workunit_store::get_workunit_store_handle().is_some() {
+      in_workunit!(
+        "capture_snapshot_streaming",
+        Level::Debug,
+        desc = Some(format!("Capturing {}", root_path.display())),
+        |_workunit| capture.capture_directory(root_dir)
+      )
+      .await?
+    } else {
+      capture.capture_directory(root_dir).await?
+    };
+    Ok(DirectoryDigest::from_persisted_digest(digest))
+  }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::{Path, PathBuf};
+
+use fs::{
+  GitignoreStyleExcludes, GlobExpansionConjunction, PathGlobs, StrictGlobMatching, SymlinkBehavior,
+};
+use tempfile::TempDir;
+use testutil::make_file;
+
+use crate::{Snapshot, Store};
+
+fn setup_tree(root: &Path) {
+  std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+  std::fs::create_dir_all(root.join("empty")).unwrap();
+  make_file(&root.join("top"), b"top", 0o600);
+  make_file(&root.join("a/script"), b"#!/bin/sh", 0o700);
+  for i in 0..20 {
+    make_file(
+      &root.join(format!("a/b/c/file{i}")),
+      format!("content {i}").as_bytes(),
+      0o600,
+    );
+  }
+}
+
+#[tokio::test]
+async fn streaming_capture_matches_snapshot() {
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let root = TempDir::new().unwrap();
+  setup_tree(root.path());
+
+  let streamed = Snapshot::capture_snapshot_streaming(
+    store.clone(),
+    executor.clone(),
+    root.path().to_owned(),
+    GitignoreStyleExcludes::empty(),
+    2,
+  )
+  .await
+  .unwrap();
+
+  let path_globs = PathGlobs::new(
+    vec!["**".to_owned()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AllMatch,
+  )
+  .parse()
+  .unwrap();
+  let snapshot = Snapshot::capture_snapshot_from_arbitrary_root(
+    store.clone(),
+    executor,
+    root.path().to_owned(),
+    path_globs,
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(streamed.as_digest(), snapshot.digest);
+
+  // The captured Directories and files were persisted as the walk proceeded.
+  let loaded = Snapshot::from_digest(store.clone(), streamed)
+    .await
+    .unwrap();
+  assert_eq!(loaded.files().len(), 22);
+  assert!(loaded.directories().contains(&PathBuf::from("empty")));
+  assert_eq!(
+    store
+      .load_file_bytes_with(hashing::Digest::of_bytes(b"top"), |b| b.to_vec())
+      .await
+      .unwrap(),
+    b"top".to_vec()
+  );
+}
+
+#[tokio::test]
+async fn streaming_capture_symlinks_and_ignores() {
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let root = TempDir::new().unwrap();
+  setup_tree(root.path());
+  std::os::unix::fs::symlink("a/script", root.path().join("link")).unwrap();
+
+  let streamed = Snapshot::capture_snapshot_streaming(
+    store.clone(),
+    executor,
+    root.path().to_owned(),
+    GitignoreStyleExcludes::create(vec!["/a/b".to_owned()]).unwrap(),
+    4,
+  )
+  .await
+  .unwrap();
+
+  let tree = store.load_digest_trie(streamed).await.unwrap();
+  let mut paths = Vec::new();
+  tree.walk(SymlinkBehavior::Aware, &mut |path, _| {
+    paths.push(path.to_owned())
+  });
+  assert_eq!(
+    paths,
+    vec![
+      PathBuf::from(""),
+      PathBuf::from("a"),
+      PathBuf::from("a/script"),
+      PathBuf::from("empty"),
+      PathBuf::from("link"),
+      PathBuf::from("top"),
+    ]
+  );
+}
@@ -1,9 +1,11 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Read;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tempfile::TempDir;
@@ -12,9 +14,10 @@ use testutil::data::{TestData, TestDirectory};
 use bytes::{Bytes, BytesMut};
 use fs::{
   DigestEntry, DirectoryDigest, FileEntry, Link, PathStat, Permissions, RelativePath,
-  EMPTY_DIRECTORY_DIGEST,
+  UnicodeNormalization, EMPTY_DIRECTORY_DIGEST,
 };
 use grpc_util::prost::MessageExt;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint};
 use mock::StubCAS;
@@ -22,7 +25,9 @@ use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use workunit_store::WorkunitStore;
 
 use crate::{
-  EntryType, FileContent, Snapshot, Store, StoreError, StoreFileByDigest, UploadSummary, MEGABYTES,
+  DirectoryStats, EntryType, FileContent, ImmutableInputs, ImmutableInputsMode, LocalOptions,
+  MaterializationStrategy, RemoteUploadPolicy, Snapshot, Store, StoreError, StoreFileByDigest,
+  UploadSummary, MEGABYTES,
 };
 
 pub(crate) const STORE_BATCH_API_SIZE_LIMIT: usize = 4 * 1024 * 1024;
@@ -103,9 +108,13 @@ fn new_store<P: AsRef<Path>>(dir: P, cas_address: &str) -> Store {
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * MEGABYTES,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
       256,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -217,6 +226,15 @@ async fn load_file_falls_back_and_backfills_for_huge_file() {
       == Ok(Some(testdata.bytes())),
     "Read from local cache"
   );
+  // The file was streamed into the local store's large files, rather than its LMDB.
+  assert_eq!(immutable_file_count(dir.path()), 1);
+}
+
+fn immutable_file_count(dir: &Path) -> usize {
+  walkdir::WalkDir::new(dir.join("immutable"))
+    .into_iter()
+    .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+    .count()
 }
 
 #[tokio::test]
@@ -298,6 +316,56 @@ async fn load_recursive_directory() {
   );
 }
 
+#[tokio::test]
+async fn ensure_remote_prefetched() {
+  let dir = TempDir::new().unwrap();
+
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland();
+  let recursive_testdir = TestDirectory::recursive();
+
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder()
+    .file(&roland)
+    .file(&catnip)
+    .directory(&testdir)
+    .directory(&recursive_testdir)
+    .build();
+
+  let store = new_store(dir.path(), &cas.address());
+  // One of the two files is already present locally, and so is not downloaded.
+  store
+    .store_file_bytes(catnip.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+  assert_eq!(
+    store
+      .ensure_remote_prefetched(recursive_testdir.directory_digest(), 1)
+      .await,
+    Ok(1)
+  );
+  assert_eq!(
+    load_file_bytes(&new_local_store(dir.path()), roland.digest()).await,
+    Ok(roland.bytes())
+  );
+  assert_eq!(
+    new_local_store(dir.path())
+      .load_directory(testdir.digest())
+      .await
+      .unwrap(),
+    testdir.directory()
+  );
+
+  // Once everything is local, nothing else is downloaded.
+  assert_eq!(
+    store
+      .ensure_remote_prefetched(recursive_testdir.directory_digest(), 1)
+      .await,
+    Ok(0)
+  );
+}
+
 #[tokio::test]
 async fn load_file_missing_is_none() {
   let dir = TempDir::new().unwrap();
@@ -349,6 +417,46 @@ async fn load_file_remote_error_is_error() {
   );
 }
 
+#[tokio::test]
+async fn load_file_remote_error_makes_configured_attempts() {
+  let _ = WorkunitStore::setup_for_tests();
+  for attempts in [1, 3] {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::cas_always_errors();
+    // Configured as by the engine: reads use the retry policy, and uploads their own policy.
+    let store = Store::local_only(task_executor::Executor::new(), dir.path())
+      .unwrap()
+      .into_with_remote(
+        &cas.address(),
+        None,
+        tls::Config::default(),
+        BTreeMap::new(),
+        None,
+        10 * MEGABYTES,
+        Duration::from_secs(1),
+        RetryPolicy {
+          attempts,
+          initial_backoff: Duration::ZERO,
+          ..RetryPolicy::default()
+        },
+        256,
+        None,
+        STORE_BATCH_API_SIZE_LIMIT,
+      )
+      .unwrap()
+      .into_with_remote_write_retry_policy(RetryPolicy {
+        attempts: 5,
+        ..RetryPolicy::default()
+      })
+      .unwrap();
+
+    load_file_bytes(&store, TestData::roland().digest())
+      .await
+      .expect_err("Want error");
+    assert_eq!(attempts, cas.read_request_count());
+  }
+}
+
 #[tokio::test]
 async fn load_directory_remote_error_is_error() {
   let dir = TempDir::new().unwrap();
@@ -505,6 +613,28 @@ async fn wrong_remote_file_bytes_is_error() {
   );
 }
 
+#[tokio::test]
+async fn wrong_remote_huge_file_bytes_is_error() {
+  let dir = TempDir::new().unwrap();
+
+  let testdata = TestData::new(&"12345".repeat(MEGABYTES));
+
+  let _ = WorkunitStore::setup_for_tests();
+  let cas = StubCAS::builder()
+    .chunk_size_bytes(MEGABYTES)
+    .unverified_content(
+      testdata.fingerprint(),
+      Bytes::from("54321".repeat(MEGABYTES)),
+    )
+    .build();
+  load_file_bytes(&new_store(dir.path(), &cas.address()), testdata.digest())
+    .await
+    .expect_err("Want error");
+
+  // The temporary file which the file was streamed into should have been removed.
+  assert_eq!(immutable_file_count(dir.path()), 0);
+}
+
 #[tokio::test]
 async fn wrong_remote_directory_bytes_is_error() {
   let dir = TempDir::new().unwrap();
@@ -641,6 +771,80 @@ async fn expand_directory_missing_subdir() {
   );
 }
 
+#[tokio::test]
+async fn export_and_import_local() {
+  let dir = TempDir::new().unwrap();
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland();
+  let recursive_testdir = TestDirectory::recursive();
+
+  let store = new_local_store(dir.path());
+  store
+    .record_directory(&recursive_testdir.directory(), false)
+    .await
+    .unwrap();
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .unwrap();
+  store.store_file_bytes(roland.bytes(), false).await.unwrap();
+  store.store_file_bytes(catnip.bytes(), false).await.unwrap();
+  // Not reachable from the exported directory, and so not exported.
+  let unexported = TestData::forty_chars();
+  store
+    .store_file_bytes(unexported.bytes(), false)
+    .await
+    .unwrap();
+
+  let mut archive = Vec::new();
+  store
+    .export_local(&[recursive_testdir.digest()], &mut archive)
+    .await
+    .unwrap();
+
+  let imported_dir = TempDir::new().unwrap();
+  let imported_store = new_local_store(imported_dir.path());
+  let imported = imported_store
+    .import_local(&mut archive.as_slice(), false)
+    .await
+    .unwrap();
+  assert_eq!(
+    imported.into_iter().collect::<HashSet<_>>(),
+    HashSet::from([
+      recursive_testdir.digest(),
+      testdir.digest(),
+      roland.digest(),
+      catnip.digest(),
+    ])
+  );
+  assert_eq!(
+    imported_store
+      .load_directory(recursive_testdir.digest())
+      .await
+      .unwrap(),
+    recursive_testdir.directory()
+  );
+  assert_eq!(
+    load_file_bytes(&imported_store, catnip.digest()).await,
+    Ok(catnip.bytes())
+  );
+  assert!(matches!(
+    load_file_bytes(&imported_store, unexported.digest()).await,
+    Err(StoreError::MissingDigest { .. })
+  ));
+
+  // Exporting a digest which is not present fails.
+  let error = imported_store
+    .export_local(&[unexported.digest()], &mut Vec::new())
+    .await
+    .expect_err("Want error");
+  assert!(
+    matches!(error, StoreError::MissingDigest { .. }),
+    "Bad error: {error}"
+  );
+}
+
 #[tokio::test]
 async fn uploads_files() {
   let dir = TempDir::new().unwrap();
@@ -701,6 +905,95 @@ async fn uploads_directories_recursively() {
   );
 }
 
+#[tokio::test]
+async fn upload_policy_excludes_classes_of_blobs() {
+  let dir = TempDir::new().unwrap();
+  let cas = new_empty_cas();
+
+  let testdata = TestData::roland();
+  let testdir = TestDirectory::containing_roland();
+
+  new_local_store(dir.path())
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error storing directory locally");
+  new_local_store(dir.path())
+    .store_file_bytes(testdata.bytes(), false)
+    .await
+    .expect("Error storing file locally");
+
+  let store = new_store(dir.path(), &cas.address())
+    .into_with_remote_upload_policy(RemoteUploadPolicy {
+      files: false,
+      ..RemoteUploadPolicy::default()
+    })
+    .unwrap();
+  let summary = store
+    .ensure_remote_has_recursive(vec![testdir.digest()])
+    .await
+    .expect("Error uploading directory");
+
+  assert_eq!(summary.uploaded_file_count, 1);
+  assert_eq!(
+    cas.blobs.lock().get(&testdir.fingerprint()),
+    Some(&testdir.bytes())
+  );
+  assert_eq!(cas.blobs.lock().get(&testdata.fingerprint()), None);
+
+  // A download-only store uploads nothing.
+  let cas = new_empty_cas();
+  let store = new_store(dir.path(), &cas.address())
+    .into_with_remote_upload_policy(RemoteUploadPolicy::download_only())
+    .unwrap();
+  let summary = store
+    .ensure_remote_has_recursive(vec![testdir.digest()])
+    .await
+    .expect("Error uploading directory");
+  assert_eq!(summary.uploaded_file_count, 0);
+  assert!(cas.blobs.lock().is_empty());
+}
+
+#[tokio::test]
+async fn upload_policy_excludes_large_files() {
+  let dir = TempDir::new().unwrap();
+  let cas = new_empty_cas();
+
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let testdir = TestDirectory::containing_roland_and_treats();
+
+  new_local_store(dir.path())
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error storing directory locally");
+  for testdata in [&roland, &catnip] {
+    new_local_store(dir.path())
+      .store_file_bytes(testdata.bytes(), false)
+      .await
+      .expect("Error storing file locally");
+  }
+
+  new_store(dir.path(), &cas.address())
+    .into_with_remote_upload_policy(RemoteUploadPolicy {
+      max_file_size_bytes: Some(catnip.len()),
+      ..RemoteUploadPolicy::default()
+    })
+    .unwrap()
+    .ensure_remote_has_recursive(vec![testdir.digest()])
+    .await
+    .expect("Error uploading directory");
+
+  assert_eq!(
+    cas.blobs.lock().get(&testdir.fingerprint()),
+    Some(&testdir.bytes())
+  );
+  assert_eq!(
+    cas.blobs.lock().get(&catnip.fingerprint()),
+    Some(&catnip.bytes())
+  );
+  assert_eq!(cas.blobs.lock().get(&roland.fingerprint()), None);
+}
+
 #[tokio::test]
 async fn uploads_files_recursively_when_under_three_digests_ignoring_items_already_in_cas() {
   let dir = TempDir::new().unwrap();
@@ -864,6 +1157,74 @@ async fn upload_succeeds_for_digests_which_only_exist_remotely() {
     .unwrap();
 }
 
+#[tokio::test]
+async fn upload_skips_digests_recently_found_remotely() {
+  let dir = TempDir::new().unwrap();
+  let immutable_inputs_dir = TempDir::new().unwrap();
+  let cas = new_empty_cas();
+
+  let testdata = TestData::roland();
+  cas
+    .blobs
+    .lock()
+    .insert(testdata.fingerprint(), testdata.bytes());
+
+  let new_store_with_ttl = |dir: &Path, remote_presence_ttl| {
+    Store::local_only_with_options(
+      task_executor::Executor::new(),
+      dir,
+      immutable_inputs_dir.path(),
+      LocalOptions {
+        remote_presence_ttl,
+        ..LocalOptions::default()
+      },
+    )
+    .unwrap()
+    .into_with_remote(
+      &cas.address(),
+      None,
+      tls::Config::default(),
+      BTreeMap::new(),
+      None,
+      10 * MEGABYTES,
+      Duration::from_secs(1),
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
+      256,
+      None,
+      STORE_BATCH_API_SIZE_LIMIT,
+    )
+    .unwrap()
+  };
+
+  // The data only exists remotely, so the remote store is asked whether it has it.
+  let store = new_store_with_ttl(dir.path(), Some(Duration::from_secs(60)));
+  store
+    .ensure_remote_has_recursive(vec![testdata.digest()])
+    .await
+    .unwrap();
+
+  // Once it is recorded as being present remotely, the remote store is not asked again.
+  cas.remove(testdata.fingerprint());
+  store
+    .ensure_remote_has_recursive(vec![testdata.digest()])
+    .await
+    .unwrap();
+
+  // Whereas a store without the record asks again.
+  let other_dir = TempDir::new().unwrap();
+  let error = new_store_with_ttl(other_dir.path(), None)
+    .ensure_remote_has_recursive(vec![testdata.digest()])
+    .await
+    .expect_err("Want error");
+  assert!(
+    matches!(error, StoreError::MissingDigest { .. }),
+    "Bad error: {error}"
+  );
+}
+
 #[tokio::test]
 async fn upload_missing_file_in_directory() {
   let dir = TempDir::new().unwrap();
@@ -944,9 +1305,13 @@ async fn instance_name_upload() {
       Some("dark-tower".to_owned()),
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * MEGABYTES,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
       256,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -975,9 +1340,13 @@ async fn instance_name_download() {
       Some("dark-tower".to_owned()),
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * MEGABYTES,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
       256,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1026,9 +1395,13 @@ async fn auth_upload() {
       None,
       tls::Config::default(),
       headers,
+      None,
       10 * MEGABYTES,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
       256,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1059,9 +1432,13 @@ async fn auth_download() {
       None,
       tls::Config::default(),
       headers,
+      None,
       10 * MEGABYTES,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+        attempts: 2,
+        ..RetryPolicy::default()
+      },
       256,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1121,6 +1498,128 @@ async fn materialize_file() {
   assert!(!is_executable(&file));
 }
 
+#[tokio::test]
+async fn materialize_empty_file_and_empty_directory() {
+  let materialize_dir = TempDir::new().unwrap();
+  let file = materialize_dir.path().join("file");
+  let dir = materialize_dir.path().join("dir");
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  let empty_digest = TestData::empty().digest();
+  assert_eq!(empty_digest, TestDirectory::empty().digest());
+
+  store
+    .materialize(
+      file.clone(),
+      empty_digest,
+      EntryType::File,
+      Permissions::Writable,
+      true,
+    )
+    .await
+    .expect("Error materializing empty file");
+  store
+    .materialize(
+      dir.clone(),
+      empty_digest,
+      EntryType::Directory,
+      Permissions::Writable,
+      false,
+    )
+    .await
+    .expect("Error materializing empty directory");
+
+  let file_metadata = std::fs::metadata(&file).unwrap();
+  assert!(file_metadata.is_file());
+  assert_eq!(0, file_metadata.len());
+  assert!(is_executable(&file));
+
+  assert!(std::fs::metadata(&dir).unwrap().is_dir());
+  assert!(list_dir(&dir).is_empty());
+}
+
+#[tokio::test]
+async fn materialize_many_deduplicates_files() {
+  let materialize_dir = TempDir::new().unwrap();
+  let first = materialize_dir.path().join("first");
+  let same_mode = materialize_dir.path().join("same_mode");
+  let other_mode = materialize_dir.path().join("other_mode");
+  let dir = materialize_dir.path().join("dir");
+
+  let testdata = TestData::roland();
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .store_file_bytes(testdata.bytes(), false)
+    .await
+    .expect("Error saving bytes");
+
+  store
+    .materialize_many(vec![
+      (EntryType::File, testdata.digest(), first.clone(), 0o444),
+      (
+        EntryType::Directory,
+        TestDirectory::empty().digest(),
+        dir.clone(),
+        0o755,
+      ),
+      (EntryType::File, testdata.digest(), same_mode.clone(), 0o444),
+      (
+        EntryType::File,
+        testdata.digest(),
+        other_mode.clone(),
+        0o644,
+      ),
+    ])
+    .await
+    .expect("Error materializing");
+
+  for path in [&first, &same_mode, &other_mode] {
+    assert_eq!(file_contents(path), testdata.bytes());
+  }
+  let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+  let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+  assert_eq!(inode(&first), inode(&same_mode));
+  assert_ne!(inode(&first), inode(&other_mode));
+  assert_eq!(mode(&first), 0o444);
+  assert_eq!(mode(&other_mode), 0o644);
+  assert!(std::fs::metadata(&dir).unwrap().is_dir());
+}
+
+#[tokio::test]
+async fn materialize_many_non_utf8_paths() {
+  let materialize_dir = TempDir::new().unwrap();
+  let first = materialize_dir
+    .path()
+    .join(OsStr::from_bytes(b"first-\xff"));
+  let second = materialize_dir
+    .path()
+    .join(OsStr::from_bytes(b"second-\xff"));
+
+  let testdata = TestData::roland();
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .store_file_bytes(testdata.bytes(), false)
+    .await
+    .expect("Error saving bytes");
+
+  store
+    .materialize_many(vec![
+      (EntryType::File, testdata.digest(), first.clone(), 0o444),
+      (EntryType::File, testdata.digest(), second.clone(), 0o444),
+    ])
+    .await
+    .expect("Error materializing");
+
+  assert_eq!(file_contents(&second), testdata.bytes());
+  assert_eq!(
+    std::fs::metadata(&first).unwrap().ino(),
+    std::fs::metadata(&second).unwrap().ino()
+  );
+}
+
 #[tokio::test]
 async fn materialize_missing_directory() {
   let materialize_dir = TempDir::new().unwrap();
@@ -1226,6 +1725,309 @@ async fn materialize_directory_readonly_executable() {
   materialize_directory(Permissions::Writable, true).await
 }
 
+#[tokio::test]
+async fn materialization_strategy() {
+  let large = TestData::new("123456789".repeat(1000 * 512).as_str());
+  let testdir = TestDirectory {
+    directory: remexec::Directory {
+      files: vec![remexec::FileNode {
+        name: "large".to_owned(),
+        digest: Some((&large.digest()).into()),
+        ..Default::default()
+      }],
+      ..Default::default()
+    },
+  };
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error saving Directory");
+  store
+    .store_file_bytes(large.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  let materialize = |store: Store| {
+    let directory_digest = testdir.directory_digest();
+    let expected_bytes = large.bytes();
+    async move {
+      let materialize_dir = TempDir::new().unwrap();
+      store
+        .materialize_directory(
+          materialize_dir.path().to_owned(),
+          directory_digest,
+          false,
+          &BTreeSet::new(),
+          Permissions::Writable,
+        )
+        .await
+        .expect("Error materializing");
+      let path = materialize_dir.path().join("large");
+      assert_eq!(file_contents(&path), expected_bytes);
+      (materialize_dir, std::fs::metadata(&path).unwrap().ino())
+    }
+  };
+
+  // By default, large files are linked from the store, so every materialization shares them.
+  let (_first_dir, first) = materialize(store.clone()).await;
+  let (_second_dir, second) = materialize(store.clone()).await;
+  assert_eq!(first, second);
+
+  // Otherwise, they are copied.
+  let copying_store = store.into_with_materialization_strategy(MaterializationStrategy::Copy);
+  let (_copied_dir, copied) = materialize(copying_store).await;
+  assert_ne!(first, copied);
+}
+
+#[tokio::test]
+async fn load_and_record_tree() {
+  let inner = TestDirectory::containing_roland();
+  let outer = TestDirectory::recursive();
+  let tree = remexec::Tree {
+    root: Some(outer.directory()),
+    children: vec![inner.directory()],
+  };
+  let tree_bytes = tree.to_bytes();
+
+  let dir = TempDir::new().unwrap();
+  let store = new_local_store(dir.path());
+  let tree_digest = store
+    .store_file_bytes(tree_bytes, false)
+    .await
+    .expect("Error saving Tree");
+
+  let directory_digest = store.load_and_record_tree(tree_digest).await.unwrap();
+  assert_eq!(directory_digest.as_digest(), outer.digest());
+
+  // Both Directories were recorded, and so can be loaded without the Tree.
+  for directory in [&inner, &outer] {
+    assert_eq!(
+      store.load_directory(directory.digest()).await.unwrap(),
+      directory.directory()
+    );
+  }
+
+  // A Tree which is missing a child Directory is rejected without recording anything.
+  let incomplete_dir = TempDir::new().unwrap();
+  let incomplete_store = new_local_store(incomplete_dir.path());
+  let incomplete = remexec::Tree {
+    root: Some(outer.directory()),
+    children: vec![],
+  };
+  assert!(incomplete_store
+    .record_tree(incomplete, false)
+    .await
+    .is_err());
+  assert!(incomplete_store
+    .load_directory(outer.digest())
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn materialize_directory_unicode_normalization() {
+  let roland = TestData::roland();
+  let nfc = "caf\u{e9}";
+  let nfd = "cafe\u{301}";
+  let directory = remexec::Directory {
+    files: vec![remexec::FileNode {
+      name: nfd.to_owned(),
+      digest: Some((&roland.digest()).into()),
+      ..remexec::FileNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  store
+    .store_file_bytes(roland.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+  let digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+
+  let materialize = |store: Store| async move {
+    let materialize_dir = TempDir::new().unwrap();
+    store
+      .materialize_directory(
+        materialize_dir.path().to_owned(),
+        DirectoryDigest::from_persisted_digest(digest),
+        false,
+        &BTreeSet::new(),
+        Permissions::Writable,
+      )
+      .await
+      .unwrap();
+    std::fs::read_dir(materialize_dir.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>()
+  };
+
+  // By default, names are materialized as they were stored.
+  assert_eq!(materialize(store.clone()).await, vec![nfd.to_owned()]);
+  assert_eq!(
+    materialize(store.into_with_unicode_normalization(UnicodeNormalization::Nfc)).await,
+    vec![nfc.to_owned()]
+  );
+}
+
+#[tokio::test]
+async fn immutable_inputs_symlink_farm() {
+  let roland = TestData::roland();
+  let catnip = TestData::catnip();
+  let inner = TestDirectory::containing_roland();
+  let outer = TestDirectory::recursive();
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  for directory in [&inner, &outer] {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .expect("Error saving Directory");
+  }
+  for data in [&roland, &catnip] {
+    store
+      .store_file_bytes(data.bytes(), false)
+      .await
+      .expect("Error saving file bytes");
+  }
+
+  let base = TempDir::new().unwrap();
+  let immutable_inputs =
+    ImmutableInputs::new_with_mode(store, base.path(), ImmutableInputsMode::SymlinkFarm).unwrap();
+  let outer_path = immutable_inputs
+    .path_for_dir(outer.directory_digest())
+    .await
+    .unwrap();
+  let inner_path = immutable_inputs
+    .path_for_dir(inner.directory_digest())
+    .await
+    .unwrap();
+
+  // Files are symlinks into the shared cache, so a file in both directories is extracted once.
+  let outer_roland = outer_path.join("cats").join("roland.ext");
+  let inner_roland = inner_path.join("roland.ext");
+  assert!(std::fs::symlink_metadata(&outer_roland)
+    .unwrap()
+    .file_type()
+    .is_symlink());
+  assert_eq!(
+    std::fs::read_link(&outer_roland).unwrap(),
+    std::fs::read_link(&inner_roland).unwrap()
+  );
+  assert_eq!(file_contents(&outer_roland), roland.bytes());
+  assert_eq!(
+    file_contents(&outer_path.join("treats.ext")),
+    catnip.bytes()
+  );
+
+  // The directories themselves are real, and read-only.
+  let cats = std::fs::symlink_metadata(outer_path.join("cats")).unwrap();
+  assert!(cats.is_dir());
+  assert_eq!(cats.permissions().mode() & 0o777, 0o555);
+}
+
+#[tokio::test]
+async fn directory_stats() {
+  let store_dir = TempDir::new().unwrap();
+  let immutable_inputs_dir = TempDir::new().unwrap();
+  let store = Store::local_only_with_options(
+    task_executor::Executor::new(),
+    store_dir.path(),
+    immutable_inputs_dir.path(),
+    LocalOptions {
+      directory_stats_cache: true,
+      ..LocalOptions::default()
+    },
+  )
+  .unwrap();
+
+  let testdir = TestDirectory::containing_roland();
+  let recursive_testdir = TestDirectory::recursive();
+  store
+    .record_directory(&recursive_testdir.directory(), false)
+    .await
+    .expect("Error saving recursive Directory");
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error saving Directory");
+
+  let expected = DirectoryStats {
+    total_bytes: TestData::catnip().len() + TestData::roland().len(),
+    file_count: 2,
+    max_depth: 1,
+  };
+  assert_eq!(
+    store
+      .directory_stats(recursive_testdir.directory_digest())
+      .await,
+    Ok(expected)
+  );
+
+  // Once cached, the stats are available without loading the tree.
+  store
+    .local
+    .remove(EntryType::Directory, testdir.digest())
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .directory_stats(recursive_testdir.directory_digest())
+      .await,
+    Ok(expected)
+  );
+
+  // But they are removed along with their directory.
+  store
+    .local
+    .remove(EntryType::Directory, recursive_testdir.digest())
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .local
+      .load_directory_stats(recursive_testdir.fingerprint())
+      .await,
+    Ok(None)
+  );
+}
+
+#[tokio::test]
+async fn directory_stats_uncached() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+
+  let testdir = TestDirectory::containing_roland();
+  store
+    .record_directory(&testdir.directory(), false)
+    .await
+    .expect("Error saving Directory");
+  assert_eq!(
+    store.directory_stats(testdir.directory_digest()).await,
+    Ok(DirectoryStats {
+      total_bytes: TestData::roland().len(),
+      file_count: 1,
+      max_depth: 0,
+    })
+  );
+  assert_eq!(
+    store
+      .local
+      .load_directory_stats(testdir.fingerprint())
+      .await,
+    Ok(None)
+  );
+}
+
 #[tokio::test]
 async fn contents_for_directory_empty() {
   let store_dir = TempDir::new().unwrap();
@@ -0,0 +1,129 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use protos::gen::google::bytestream::WriteRequest;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+///
+/// Limits on the transfer of blobs to and from a remote store: on the number of concurrent
+/// ByteStream reads and writes, and on the aggregate rate (in bytes per second) at which blob
+/// content is sent and received, which is shared by all transfers.
+///
+#[derive(Debug, Default)]
+pub(crate) struct TransferLimits {
+  read_permits: Option<Semaphore>,
+  write_permits: Option<Semaphore>,
+  bandwidth: Option<TokenBucket>,
+}
+
+impl TransferLimits {
+  pub fn new(
+    read_concurrency_limit: Option<usize>,
+    write_concurrency_limit: Option<usize>,
+    bytes_per_second: Option<usize>,
+  ) -> Self {
+    Self {
+      read_permits: read_concurrency_limit.map(|limit| Semaphore::new(limit.max(1))),
+      write_permits: write_concurrency_limit.map(|limit| Semaphore::new(limit.max(1))),
+      bandwidth: bytes_per_second.map(|rate| TokenBucket::new(rate.max(1))),
+    }
+  }
+
+  ///
+  /// Waits until another ByteStream read is allowed, which it is until the returned permit (if
+  /// any) is dropped.
+  ///
+  pub async fn read_permit(&self) -> Option<SemaphorePermit<'_>> {
+    Self::permit(self.read_permits.as_ref()).await
+  }
+
+  ///
+  /// Waits until another ByteStream write is allowed, which it is until the returned permit (if
+  /// any) is dropped.
+  ///
+  pub async fn write_permit(&self) -> Option<SemaphorePermit<'_>> {
+    Self::permit(self.write_permits.as_ref()).await
+  }
+
+  async fn permit(permits: Option<&Semaphore>) -> Option<SemaphorePermit<'_>> {
+    match permits {
+      // NB: The semaphores are never closed.
+      Some(permits) => permits.acquire().await.ok(),
+      None => None,
+    }
+  }
+
+  ///
+  /// Waits until the bandwidth limit allows the given number of bytes to be transferred.
+  ///
+  pub async fn consume(&self, bytes: usize) {
+    if let Some(bandwidth) = &self.bandwidth {
+      let delay = bandwidth.reserve(bytes);
+      if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+      }
+    }
+  }
+}
+
+///
+/// Delays each of the given requests until the bandwidth limit allows its data to be sent.
+///
+pub(crate) fn throttle_writes(
+  limits: Arc<TransferLimits>,
+  requests: impl Stream<Item = WriteRequest>,
+) -> impl Stream<Item = WriteRequest> {
+  requests.then(move |request| {
+    let limits = limits.clone();
+    async move {
+      limits.consume(request.data.len()).await;
+      request
+    }
+  })
+}
+
+///
+/// A token bucket which refills at the given rate, up to one second's worth of tokens.
+///
+/// Reservations may overdraw the bucket: the caller must then wait until the debt would have been
+/// repaid, and later reservations queue up behind it. This allows transfers which are larger than
+/// the capacity of the bucket, while keeping the aggregate rate at the limit.
+///
+#[derive(Debug)]
+struct TokenBucket {
+  bytes_per_second: f64,
+  state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+  fn new(bytes_per_second: usize) -> Self {
+    let bytes_per_second = bytes_per_second as f64;
+    Self {
+      bytes_per_second,
+      state: Mutex::new((bytes_per_second, Instant::now())),
+    }
+  }
+
+  ///
+  /// Takes the given number of tokens, and returns how long to wait before using them.
+  ///
+  fn reserve(&self, bytes: usize) -> Duration {
+    let mut state = self.state.lock();
+    let (available, last_refill) = &mut *state;
+    let now = Instant::now();
+    *available = (*available
+      + now.duration_since(*last_refill).as_secs_f64() * self.bytes_per_second)
+      .min(self.bytes_per_second);
+    *last_refill = now;
+    *available -= bytes as f64;
+    if *available >= 0.0 {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64(-*available / self.bytes_per_second)
+    }
+  }
+}
@@ -0,0 +1,56 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::time::{Duration, Instant};
+
+use crate::transfer_limits::TransferLimits;
+
+#[tokio::test]
+async fn unlimited_by_default() {
+  let limits = TransferLimits::default();
+  let start = Instant::now();
+  let _read_permits = (limits.read_permit().await, limits.read_permit().await);
+  let _write_permits = (limits.write_permit().await, limits.write_permit().await);
+  limits.consume(100 * 1024 * 1024).await;
+  limits.consume(100 * 1024 * 1024).await;
+  assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn concurrency_is_limited() {
+  let limits = TransferLimits::new(Some(1), Some(2), None);
+
+  let read_permit = limits.read_permit().await;
+  assert!(
+    tokio::time::timeout(Duration::from_millis(50), limits.read_permit())
+      .await
+      .is_err()
+  );
+  drop(read_permit);
+  assert!(
+    tokio::time::timeout(Duration::from_millis(50), limits.read_permit())
+      .await
+      .is_ok()
+  );
+
+  let _write_permits = (limits.write_permit().await, limits.write_permit().await);
+  assert!(
+    tokio::time::timeout(Duration::from_millis(50), limits.write_permit())
+      .await
+      .is_err()
+  );
+}
+
+#[tokio::test]
+async fn bandwidth_is_limited() {
+  let limits = TransferLimits::new(None, None, Some(1000));
+  let start = Instant::now();
+
+  // The bucket starts full, so the first second's worth of bytes is not delayed...
+  limits.consume(1000).await;
+  assert!(start.elapsed() < Duration::from_millis(200));
+
+  // ...but later bytes are, including those which overdraw the bucket.
+  limits.consume(500).await;
+  limits.consume(500).await;
+  assert!(start.elapsed() >= Duration::from_millis(900));
+}
@@ -0,0 +1,184 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use http::HeaderValue;
+
+///
+/// Where to get a bearer token for authenticating to remote servers.
+///
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+  /// A file which holds the token.
+  File(PathBuf),
+  /// A command (and its arguments) which prints the token to stdout.
+  Command(Vec<String>),
+}
+
+impl fmt::Display for TokenSource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TokenSource::File(path) => write!(f, "token file {path:?}"),
+      TokenSource::Command(argv) => write!(f, "token command {argv:?}"),
+    }
+  }
+}
+
+impl TokenSource {
+  ///
+  /// Reads the token (by running the command, if this is one), as the value of an `authorization`
+  /// header.
+  ///
+  fn load(&self) -> Result<HeaderValue, String> {
+    let token = match self {
+      TokenSource::File(path) => {
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read {self}: {err}"))?
+      }
+      TokenSource::Command(argv) => {
+        let (program, args) = argv
+          .split_first()
+          .ok_or_else(|| "The token command must not be empty.".to_owned())?;
+        let output = std::process::Command::new(program)
+          .args(args)
+          .output()
+          .map_err(|err| format!("Failed to run {self}: {err}"))?;
+        if !output.status.success() {
+          return Err(format!(
+            "The {self} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+          ));
+        }
+        String::from_utf8(output.stdout)
+          .map_err(|err| format!("The {self} printed a non-UTF-8 token: {err}"))?
+      }
+    };
+    let mut header = HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+      .map_err(|err| format!("The {self} holds an invalid token: {err}"))?;
+    header.set_sensitive(true);
+    Ok(header)
+  }
+}
+
+///
+/// Provides the bearer token with which requests are authenticated, and gets a new one from its
+/// source when the server rejects the current one (because it has expired, for example).
+///
+pub struct TokenProvider {
+  source: TokenSource,
+  header: Mutex<HeaderValue>,
+  // Held while refreshing, so that concurrent rejections of a token only refresh it once.
+  refreshing: tokio::sync::Mutex<()>,
+}
+
+impl fmt::Debug for TokenProvider {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "TokenProvider({})", self.source)
+  }
+}
+
+impl TokenProvider {
+  ///
+  /// Creates a provider for the given source, failing if it does not currently hold a valid token.
+  ///
+  pub fn new(source: TokenSource) -> Result<Self, String> {
+    let header = source.load()?;
+    Ok(Self {
+      source,
+      header: Mutex::new(header),
+      refreshing: tokio::sync::Mutex::new(()),
+    })
+  }
+
+  ///
+  /// The current value of the `authorization` header.
+  ///
+  pub fn header(&self) -> HeaderValue {
+    self.header.lock().unwrap().clone()
+  }
+
+  ///
+  /// Gets a new token from the source, given the `authorization` header which was rejected by a
+  /// server. Returns true if the header has changed since, and so the request is worth retrying.
+  ///
+  pub async fn refresh(&self, rejected: &HeaderValue) -> bool {
+    let _refreshing = self.refreshing.lock().await;
+    if self.header() != rejected {
+      // Another request has already refreshed the token.
+      return true;
+    }
+
+    let source = self.source.clone();
+    match tokio::task::spawn_blocking(move || source.load()).await {
+      Ok(Ok(header)) if header != rejected => {
+        *self.header.lock().unwrap() = header;
+        true
+      }
+      // NB: If the token has not changed (or cannot be loaded), the rejection is final.
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use http::HeaderValue;
+  use tempfile::TempDir;
+
+  use super::{TokenProvider, TokenSource};
+
+  #[tokio::test]
+  async fn refreshes_from_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("token");
+    std::fs::write(&path, "first\n").unwrap();
+
+    let provider = TokenProvider::new(TokenSource::File(path.clone())).unwrap();
+    let first = provider.header();
+    assert_eq!(first, "Bearer first");
+
+    // An unchanged token is not worth retrying with.
+    assert!(!provider.refresh(&first).await);
+
+    std::fs::write(&path, "second\n").unwrap();
+    assert!(provider.refresh(&first).await);
+    assert_eq!(provider.header(), "Bearer second");
+    // A refresh from a stale rejection does not reload the token.
+    std::fs::write(&path, "third\n").unwrap();
+    assert!(provider.refresh(&first).await);
+    assert_eq!(provider.header(), "Bearer second");
+  }
+
+  #[tokio::test]
+  async fn loads_from_command() {
+    let provider = TokenProvider::new(TokenSource::Command(vec![
+      "/bin/echo".to_owned(),
+      "from-command".to_owned(),
+    ]))
+    .unwrap();
+    assert_eq!(
+      provider.header(),
+      HeaderValue::from_static("Bearer from-command")
+    );
+  }
+
+  #[test]
+  fn invalid_sources() {
+    let err = TokenProvider::new(TokenSource::File(PathBuf::from("/does/not/exist")))
+      .err()
+      .unwrap();
+    assert!(
+      err.starts_with("Failed to read token file"),
+      "Bad error: {err}"
+    );
+
+    let err = TokenProvider::new(TokenSource::Command(vec!["/bin/false".to_owned()]))
+      .err()
+      .unwrap();
+    assert!(err.contains("failed with"), "Bad error: {err}");
+  }
+}
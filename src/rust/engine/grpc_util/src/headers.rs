@@ -2,21 +2,42 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 use std::fmt;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use http::header::HeaderMap;
-use http::Request;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use http::{Request, Response};
+use tonic::Code;
 use tower_layer::Layer;
 use tower_service::Service;
 
+use crate::auth::TokenProvider;
+
+const GRPC_STATUS: &str = "grpc-status";
+
 #[derive(Debug)]
 pub struct SetRequestHeadersLayer {
   headers: HeaderMap,
+  token_provider: Option<Arc<TokenProvider>>,
 }
 
 impl SetRequestHeadersLayer {
   pub fn new(headers: HeaderMap) -> Self {
-    SetRequestHeadersLayer { headers }
+    SetRequestHeadersLayer {
+      headers,
+      token_provider: None,
+    }
+  }
+
+  ///
+  /// Also sets the `authorization` header to the token from the given provider (overriding any
+  /// static value), and refreshes the token when a server rejects it. See `SetRequestHeaders`.
+  ///
+  pub fn with_token_provider(mut self, token_provider: Option<Arc<TokenProvider>>) -> Self {
+    self.token_provider = token_provider;
+    self
   }
 }
 
@@ -27,19 +48,32 @@ impl<S> Layer<S> for SetRequestHeadersLayer {
     SetRequestHeaders {
       inner,
       headers: self.headers.clone(),
+      token_provider: self.token_provider.clone(),
     }
   }
 }
 
+///
+/// Sets the given headers on each request.
+///
+/// If there is a token provider, an UNAUTHENTICATED response causes its token to be refreshed: if
+/// that changes the token, the response is reported as UNAVAILABLE instead, so that the usual
+/// retry policies will retry the request with the new token.
+///
 #[derive(Clone)]
 pub struct SetRequestHeaders<S> {
   inner: S,
   headers: HeaderMap,
+  token_provider: Option<Arc<TokenProvider>>,
 }
 
 impl<S> SetRequestHeaders<S> {
   pub fn new(inner: S, headers: HeaderMap) -> Self {
-    SetRequestHeaders { inner, headers }
+    SetRequestHeaders {
+      inner,
+      headers,
+      token_provider: None,
+    }
   }
 }
 
@@ -51,17 +85,21 @@ where
     f.debug_struct("SetRequestHeaders")
       .field("inner", &self.inner)
       .field("headers", &self.headers)
+      .field("token_provider", &self.token_provider)
       .finish()
   }
 }
 
-impl<ReqBody, S> Service<Request<ReqBody>> for SetRequestHeaders<S>
+impl<ReqBody, ResBody, S> Service<Request<ReqBody>> for SetRequestHeaders<S>
 where
-  S: Service<Request<ReqBody>>,
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+  S::Error: Send + 'static,
+  S::Future: Send + 'static,
+  ResBody: Send + 'static,
 {
   type Response = S::Response;
   type Error = S::Error;
-  type Future = S::Future;
+  type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
   #[inline]
   fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -76,6 +114,28 @@ where
       }
     }
 
-    self.inner.call(req)
+    let token = self.token_provider.as_ref().map(|token_provider| {
+      let header = token_provider.header();
+      req.headers_mut().insert(AUTHORIZATION, header.clone());
+      (token_provider.clone(), header)
+    });
+
+    let response = self.inner.call(req);
+    async move {
+      let mut response = response.await?;
+      if let Some((token_provider, header)) = token {
+        // NB: Servers reject requests before sending any messages, so the status is in the headers
+        // (rather than the trailers) of the response.
+        let unauthenticated = response.headers().get(GRPC_STATUS)
+          == Some(&HeaderValue::from(Code::Unauthenticated as i32));
+        if unauthenticated && token_provider.refresh(&header).await {
+          response
+            .headers_mut()
+            .insert(GRPC_STATUS, HeaderValue::from(Code::Unavailable as i32));
+        }
+      }
+      Ok(response)
+    }
+    .boxed()
   }
 }
@@ -48,9 +48,11 @@ use tower::ServiceBuilder;
 use tower_service::Service;
 use workunit_store::{get_workunit_store_handle, Metric, ObservationMetric};
 
+use crate::auth::TokenProvider;
 use crate::headers::{SetRequestHeaders, SetRequestHeadersLayer};
 use crate::metrics::{NetworkMetrics, NetworkMetricsLayer};
 
+pub mod auth;
 pub mod headers;
 pub mod hyper;
 pub mod metrics;
@@ -68,13 +70,14 @@ pub fn layered_service(
   channel: Channel,
   concurrency_limit: usize,
   http_headers: HeaderMap,
+  token_provider: Option<Arc<TokenProvider>>,
   timeout: Option<(Duration, Metric)>,
 ) -> LayeredService {
   let (timeout, metric) = timeout
     .map(|(t, m)| (t, Some(m)))
     .unwrap_or_else(|| (Duration::from_secs(60 * 60), None));
   ServiceBuilder::new()
-    .layer(SetRequestHeadersLayer::new(http_headers))
+    .layer(SetRequestHeadersLayer::new(http_headers).with_token_provider(token_provider))
     .concurrency_limit(concurrency_limit)
     .layer(NetworkMetricsLayer::new(&METRIC_FOR_REAPI_PATH))
     .layer_fn(|service| CountErrorsService { service, metric })
@@ -199,14 +202,19 @@ mod tests {
   }
 
   use std::collections::BTreeMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
 
   use async_trait::async_trait;
   use futures::FutureExt;
+  use http::HeaderMap;
   use tokio::sync::oneshot;
   use tonic::transport::{Channel, Server};
   use tonic::{Request, Response, Status};
 
+  use crate::auth::{TokenProvider, TokenSource};
   use crate::hyper::AddrIncomingWithStream;
+  use crate::retry::{retry_call, status_is_retryable};
 
   #[tokio::test]
   async fn user_agent_is_set_correctly() {
@@ -275,4 +283,75 @@ mod tests {
       panic!("test failed: {}", err.message());
     }
   }
+
+  #[tokio::test]
+  async fn rejected_token_is_refreshed() {
+    #[derive(Clone, Default)]
+    struct TokenChecker {
+      calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl gen::test_server::Test for TokenChecker {
+      async fn call(&self, request: Request<gen::Input>) -> Result<Response<gen::Output>, Status> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match request.metadata().get("authorization") {
+          Some(token) if token == "Bearer fresh" => Ok(Response::new(gen::Output {})),
+          _ => Err(Status::unauthenticated("Expired token.")),
+        }
+      }
+    }
+
+    let addr = "127.0.0.1:0".parse().expect("failed to parse IP address");
+    let incoming = hyper::server::conn::AddrIncoming::bind(&addr).expect("failed to bind port");
+    let local_addr = incoming.local_addr();
+    let incoming = AddrIncomingWithStream(incoming);
+
+    let (_shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    let token_checker = TokenChecker::default();
+    tokio::spawn({
+      let token_checker = token_checker.clone();
+      async move {
+        let mut server = Server::builder();
+        let router = server.add_service(gen::test_server::TestServer::new(token_checker));
+        router
+          .serve_with_incoming_shutdown(incoming, shutdown_receiver.map(drop))
+          .await
+          .unwrap();
+      }
+    });
+
+    let token_path =
+      std::env::temp_dir().join(format!("rejected_token_test_{}", std::process::id()));
+    std::fs::write(&token_path, "expired").unwrap();
+    let token_provider =
+      Arc::new(TokenProvider::new(TokenSource::File(token_path.clone())).unwrap());
+    std::fs::write(&token_path, "fresh").unwrap();
+
+    let endpoint = super::create_endpoint(
+      &format!("grpc://127.0.0.1:{}", local_addr.port()),
+      None,
+      &mut BTreeMap::new(),
+    )
+    .unwrap();
+    let channel = super::layered_service(
+      Channel::balance_list(vec![endpoint].into_iter()),
+      1,
+      HeaderMap::new(),
+      Some(token_provider),
+      None,
+    );
+
+    let client = gen::test_client::TestClient::new(channel);
+    let result = retry_call(
+      client,
+      |mut client| async move { client.call(gen::Input {}).await },
+      status_is_retryable,
+    )
+    .await;
+    std::fs::remove_file(&token_path).unwrap();
+
+    assert!(result.is_ok(), "Unexpected error: {result:?}");
+    assert_eq!(token_checker.calls.load(Ordering::SeqCst), 2);
+  }
 }
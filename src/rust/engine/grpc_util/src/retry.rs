@@ -7,62 +7,146 @@ use futures::Future;
 use rand::{thread_rng, Rng};
 use tonic::{Code, Status};
 
+/// The status codes which are retried by default.
+const DEFAULT_RETRYABLE_CODES: [Code; 6] = [
+  Code::Aborted,
+  Code::Cancelled,
+  Code::Internal,
+  Code::ResourceExhausted,
+  Code::Unavailable,
+  Code::Unknown,
+];
+
 pub fn status_is_retryable(status: &Status) -> bool {
-  matches!(
-    status.code(),
-    Code::Aborted
-      | Code::Cancelled
-      | Code::Internal
-      | Code::ResourceExhausted
-      | Code::Unavailable
-      | Code::Unknown
-  )
+  DEFAULT_RETRYABLE_CODES.contains(&status.code())
 }
 
-/// Retry a gRPC client operation using exponential back-off to delay between attempts.
-#[inline]
-pub async fn retry_call<T, E, C, F, G, Fut>(client: C, mut f: F, is_retryable: G) -> Result<T, E>
-where
-  C: Clone,
-  F: FnMut(C) -> Fut,
-  G: Fn(&E) -> bool,
-  Fut: Future<Output = Result<T, E>>,
-{
-  const INTERVAL_DURATION: Duration = Duration::from_millis(20);
-  const MAX_RETRIES: u32 = 3;
-  const MAX_BACKOFF_DURATION: Duration = Duration::from_secs(5);
-
-  let mut num_retries = 0;
-  let last_error = loop {
-    // Delay before the next send attempt if this is a retry.
-    if num_retries > 0 {
-      let multiplier = thread_rng().gen_range(0..2_u32.pow(num_retries) + 1);
-      let sleep_time = INTERVAL_DURATION * multiplier;
-      let sleep_time = sleep_time.min(MAX_BACKOFF_DURATION);
-      tokio::time::sleep(sleep_time).await;
+///
+/// How to retry a failed RPC: how many attempts to make in total, how long to wait between them,
+/// and which status codes are worth retrying.
+///
+/// Before the nth retry, a random delay of up to `initial_backoff * 2^n` (capped at
+/// `max_backoff`) is taken.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+  pub attempts: usize,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+  pub retryable_codes: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      attempts: 3,
+      initial_backoff: Duration::from_millis(20),
+      max_backoff: Duration::from_secs(5),
+      retryable_codes: DEFAULT_RETRYABLE_CODES.to_vec(),
     }
+  }
+}
 
-    let client2 = client.clone();
-    let result_fut = f(client2);
-    let last_error = match result_fut.await {
-      Ok(r) => return Ok(r),
-      Err(err) => {
-        if is_retryable(&err) {
-          err
-        } else {
-          return Err(err);
+impl RetryPolicy {
+  pub fn status_is_retryable(&self, status: &Status) -> bool {
+    self.retryable_codes.contains(&status.code())
+  }
+
+  ///
+  /// The (randomized) delay before the given retry, counting from 1.
+  ///
+  pub fn backoff(&self, retry: u32) -> Duration {
+    let multiplier = thread_rng().gen_range(0..2_u32.saturating_pow(retry).saturating_add(1));
+    self
+      .initial_backoff
+      .saturating_mul(multiplier)
+      .min(self.max_backoff)
+  }
+
+  /// Retry a gRPC client operation using exponential back-off to delay between attempts.
+  pub async fn retry_call<T, E, C, F, G, Fut>(
+    &self,
+    client: C,
+    mut f: F,
+    is_retryable: G,
+  ) -> Result<T, E>
+  where
+    C: Clone,
+    F: FnMut(C) -> Fut,
+    G: Fn(&E) -> bool,
+    Fut: Future<Output = Result<T, E>>,
+  {
+    let mut num_retries = 0;
+    let last_error = loop {
+      // Delay before the next send attempt if this is a retry.
+      if num_retries > 0 {
+        tokio::time::sleep(self.backoff(num_retries)).await;
+      }
+
+      let client2 = client.clone();
+      let result_fut = f(client2);
+      let last_error = match result_fut.await {
+        Ok(r) => return Ok(r),
+        Err(err) => {
+          if is_retryable(&err) {
+            err
+          } else {
+            return Err(err);
+          }
         }
+      };
+
+      num_retries += 1;
+
+      if num_retries as usize >= self.attempts {
+        break last_error;
       }
     };
 
-    num_retries += 1;
+    Err(last_error)
+  }
+}
 
-    if num_retries >= MAX_RETRIES {
-      break last_error;
-    }
+///
+/// Parses the name of a gRPC status code, in the `SCREAMING_SNAKE_CASE` form used by the gRPC
+/// specification (e.g. `UNAVAILABLE`).
+///
+pub fn parse_code(name: &str) -> Result<Code, String> {
+  let code = match name {
+    "OK" => Code::Ok,
+    "CANCELLED" => Code::Cancelled,
+    "UNKNOWN" => Code::Unknown,
+    "INVALID_ARGUMENT" => Code::InvalidArgument,
+    "DEADLINE_EXCEEDED" => Code::DeadlineExceeded,
+    "NOT_FOUND" => Code::NotFound,
+    "ALREADY_EXISTS" => Code::AlreadyExists,
+    "PERMISSION_DENIED" => Code::PermissionDenied,
+    "RESOURCE_EXHAUSTED" => Code::ResourceExhausted,
+    "FAILED_PRECONDITION" => Code::FailedPrecondition,
+    "ABORTED" => Code::Aborted,
+    "OUT_OF_RANGE" => Code::OutOfRange,
+    "UNIMPLEMENTED" => Code::Unimplemented,
+    "INTERNAL" => Code::Internal,
+    "UNAVAILABLE" => Code::Unavailable,
+    "DATA_LOSS" => Code::DataLoss,
+    "UNAUTHENTICATED" => Code::Unauthenticated,
+    _ => return Err(format!("Unknown gRPC status code: `{name}`.")),
   };
+  Ok(code)
+}
 
-  Err(last_error)
+/// Retry a gRPC client operation using the default `RetryPolicy`.
+#[inline]
+pub async fn retry_call<T, E, C, F, G, Fut>(client: C, f: F, is_retryable: G) -> Result<T, E>
+where
+  C: Clone,
+  F: FnMut(C) -> Fut,
+  G: Fn(&E) -> bool,
+  Fut: Future<Output = Result<T, E>>,
+{
+  RetryPolicy::default()
+    .retry_call(client, f, is_retryable)
+    .await
 }
 
 #[cfg(test)]
@@ -72,7 +156,11 @@ mod tests {
 
   use parking_lot::Mutex;
 
-  use super::retry_call;
+  use std::time::Duration;
+
+  use tonic::Code;
+
+  use super::{parse_code, retry_call, RetryPolicy};
 
   #[derive(Clone, Debug)]
   struct MockClient<T> {
@@ -142,4 +230,48 @@ mod tests {
     assert_eq!(result, Err(MockError(true, "third")));
     assert_eq!(client.values.lock().len(), 1);
   }
+
+  #[tokio::test]
+  async fn retry_call_respects_policy() {
+    let policy = RetryPolicy {
+      attempts: 5,
+      initial_backoff: Duration::ZERO,
+      ..RetryPolicy::default()
+    };
+    let client = MockClient::new(vec![
+      Err(MockError(true, "first")),
+      Err(MockError(true, "second")),
+      Err(MockError(true, "third")),
+      Err(MockError(true, "fourth")),
+      Ok(5_isize),
+    ]);
+    let result = policy
+      .retry_call(
+        client.clone(),
+        |client| async move { client.next().await },
+        |err| err.0,
+      )
+      .await;
+    assert_eq!(result, Ok(5_isize));
+    assert!(client.values.lock().is_empty());
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    let policy = RetryPolicy {
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(2),
+      ..RetryPolicy::default()
+    };
+    for retry in [1, 10, 100] {
+      assert!(policy.backoff(retry) <= Duration::from_secs(2));
+    }
+  }
+
+  #[test]
+  fn parses_codes() {
+    assert_eq!(parse_code("UNAVAILABLE"), Ok(Code::Unavailable));
+    assert_eq!(parse_code("DEADLINE_EXCEEDED"), Ok(Code::DeadlineExceeded));
+    assert!(parse_code("Unavailable").is_err());
+  }
 }
@@ -1,11 +1,17 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use rustls::{ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+  ClientConfig, ResolvesClientCert, RootCertStore, ServerCertVerified, ServerCertVerifier,
+  SignatureScheme, TLSError,
+};
 use webpki::DNSNameRef;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Config {
   pub root_ca_certs: Option<Vec<u8>>,
   pub mtls: Option<MtlsConfig>,
@@ -60,13 +66,23 @@ impl TryFrom<Config> for ClientConfig {
       }
     }
 
-    if let Some(MtlsConfig { key, cert_chain }) = config.mtls {
-      tls_config
-        .set_single_client_cert(
-          cert_chain_from_pem_bytes(cert_chain)?,
-          der_key_from_pem_bytes(key)?,
-        )
-        .map_err(|err| format!("Error creating MTLS config: {err:?}"))?;
+    match config.mtls {
+      Some(MtlsConfig::Static { key, cert_chain }) => {
+        tls_config
+          .set_single_client_cert(
+            cert_chain_from_pem_bytes(cert_chain)?,
+            der_key_from_pem_bytes(key)?,
+          )
+          .map_err(|err| format!("Error creating MTLS config: {err:?}"))?;
+      }
+      Some(MtlsConfig::Files {
+        key_path,
+        cert_chain_path,
+      }) => {
+        tls_config.client_auth_cert_resolver =
+          Arc::new(ReloadingClientCert::new(key_path, cert_chain_path)?);
+      }
+      None => (),
     }
 
     if let CertificateCheck::DangerouslyDisabled = config.certificate_check {
@@ -79,13 +95,24 @@ impl TryFrom<Config> for ClientConfig {
   }
 }
 
-pub struct MtlsConfig {
-  /// PEM bytes of the private key used for MTLS.
-  pub key: Vec<u8>,
-  /// PEM bytes of the certificate used for MTLS.
-  pub cert_chain: Vec<u8>,
+#[derive(Clone)]
+pub enum MtlsConfig {
+  Static {
+    /// PEM bytes of the private key used for MTLS.
+    key: Vec<u8>,
+    /// PEM bytes of the certificate used for MTLS.
+    cert_chain: Vec<u8>,
+  },
+  /// PEM files holding the private key and certificate chain used for MTLS, which are read again
+  /// whenever either of them is modified, so that short-lived certificates (such as SPIFFE SVIDs)
+  /// may be rotated without restarting. Rotated certificates are only used by new connections.
+  Files {
+    key_path: PathBuf,
+    cert_chain_path: PathBuf,
+  },
 }
 
+#[derive(Clone)]
 pub enum CertificateCheck {
   Enabled,
   DangerouslyDisabled,
@@ -97,6 +124,70 @@ impl Default for CertificateCheck {
   }
 }
 
+///
+/// Presents the client certificate held in the given files, reloading them when they are modified.
+///
+struct ReloadingClientCert {
+  key_path: PathBuf,
+  cert_chain_path: PathBuf,
+  // The modification times of the files when they were last loaded, and what was loaded.
+  loaded: Mutex<((Option<SystemTime>, Option<SystemTime>), CertifiedKey)>,
+}
+
+impl ReloadingClientCert {
+  fn new(key_path: PathBuf, cert_chain_path: PathBuf) -> Result<Self, String> {
+    let modified = Self::modified(&key_path, &cert_chain_path);
+    let certified_key = Self::load(&key_path, &cert_chain_path)?;
+    Ok(Self {
+      key_path,
+      cert_chain_path,
+      loaded: Mutex::new((modified, certified_key)),
+    })
+  }
+
+  fn modified(key_path: &Path, cert_chain_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(key_path), modified(cert_chain_path))
+  }
+
+  fn load(key_path: &Path, cert_chain_path: &Path) -> Result<CertifiedKey, String> {
+    let key = std::fs::read(key_path)
+      .map_err(|err| format!("Failed to read MTLS client key from {key_path:?}: {err}"))?;
+    let cert_chain = std::fs::read(cert_chain_path).map_err(|err| {
+      format!("Failed to read MTLS client certificate chain from {cert_chain_path:?}: {err}")
+    })?;
+    let key = sign::any_supported_type(&der_key_from_pem_bytes(key)?)
+      .map_err(|()| format!("Unsupported type of MTLS client key in {key_path:?}"))?;
+    Ok(CertifiedKey::new(
+      cert_chain_from_pem_bytes(cert_chain)?,
+      Arc::new(key),
+    ))
+  }
+}
+
+impl ResolvesClientCert for ReloadingClientCert {
+  fn resolve(
+    &self,
+    _acceptable_issuers: &[&[u8]],
+    _sigschemes: &[SignatureScheme],
+  ) -> Option<CertifiedKey> {
+    let mut loaded = self.loaded.lock().unwrap();
+    let modified = Self::modified(&self.key_path, &self.cert_chain_path);
+    if modified != loaded.0 {
+      // NB: If the files cannot be loaded (e.g. because they are midway through being rotated),
+      // the previous certificate is used, and loading is attempted again by the next handshake.
+      if let Ok(certified_key) = Self::load(&self.key_path, &self.cert_chain_path) {
+        *loaded = (modified, certified_key);
+      }
+    }
+    Some(loaded.1.clone())
+  }
+
+  fn has_certs(&self) -> bool {
+    true
+  }
+}
+
 struct NoVerifier;
 
 impl ServerCertVerifier for NoVerifier {
@@ -136,3 +227,45 @@ fn der_key_from_pem_bytes(pem_bytes: Vec<u8>) -> Result<rustls::PrivateKey, Stri
   };
   Ok(rustls::PrivateKey(key))
 }
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use rustls::ClientConfig;
+
+  use super::{Config, MtlsConfig};
+
+  fn config_with_mtls(mtls: MtlsConfig) -> Config {
+    Config {
+      root_ca_certs: Some(Vec::new()),
+      mtls: Some(mtls),
+      ..Config::default()
+    }
+  }
+
+  #[test]
+  fn mtls_files_must_exist() {
+    let err = ClientConfig::try_from(config_with_mtls(MtlsConfig::Files {
+      key_path: PathBuf::from("/does/not/exist/key.pem"),
+      cert_chain_path: PathBuf::from("/does/not/exist/cert.pem"),
+    }))
+    .err()
+    .unwrap();
+    assert!(
+      err.contains("Failed to read MTLS client key from \"/does/not/exist/key.pem\""),
+      "Bad error: {err}"
+    );
+  }
+
+  #[test]
+  fn mtls_key_must_be_present() {
+    let err = ClientConfig::try_from(config_with_mtls(MtlsConfig::Static {
+      key: b"not a key".to_vec(),
+      cert_chain: Vec::new(),
+    }))
+    .err()
+    .unwrap();
+    assert_eq!(err, "No private key found in PEM file");
+  }
+}
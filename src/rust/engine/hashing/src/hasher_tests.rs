@@ -55,3 +55,50 @@ async fn async_hashes() {
     .unwrap();
   assert_eq!("meep".as_bytes().to_vec(), contents);
 }
+
+#[test]
+fn hashes_with_blake3() {
+  let digest = super::Digest::of_bytes_with(super::DigestFunction::Blake3, b"");
+  assert_eq!(
+    digest,
+    super::Digest::new(
+      super::Fingerprint::from_hex_string(
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+      )
+      .unwrap(),
+      0,
+    )
+  );
+  assert_eq!(
+    super::DigestFunction::Blake3.empty_fingerprint(),
+    digest.hash
+  );
+  assert_eq!(
+    super::DigestFunction::Sha256.empty_fingerprint(),
+    super::EMPTY_FINGERPRINT
+  );
+
+  // Streaming produces the same digest as hashing in one pass.
+  let mut src = "meep".as_bytes();
+  let mut hasher = super::WriterHasher::new_with(super::DigestFunction::Blake3, Vec::new());
+  std::io::copy(&mut src, &mut hasher).unwrap();
+  assert_eq!(
+    hasher.finish().0,
+    super::Digest::of_bytes_with(super::DigestFunction::Blake3, b"meep")
+  );
+  assert_ne!(
+    super::Digest::of_bytes(b"meep"),
+    super::Digest::of_bytes_with(super::DigestFunction::Blake3, b"meep")
+  );
+}
+
+#[test]
+fn digest_function_parsing() {
+  for digest_function in [super::DigestFunction::Sha256, super::DigestFunction::Blake3] {
+    assert_eq!(
+      digest_function.to_string().parse::<super::DigestFunction>(),
+      Ok(digest_function)
+    );
+  }
+  assert!("md5".parse::<super::DigestFunction>().is_err());
+}
@@ -175,6 +175,51 @@ impl TryFrom<&str> for Fingerprint {
   }
 }
 
+///
+/// A function which may be used to compute Fingerprints. Because every supported function produces
+/// fingerprints of the same size, nothing in a Fingerprint or Digest records which function
+/// computed it: callers must agree on the function out of band.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DigestFunction {
+  /// SHA-256: the default, and the only function which all remote stores support.
+  Sha256,
+  /// BLAKE3, which is considerably cheaper to compute than SHA-256 for large inputs.
+  Blake3,
+}
+
+impl DigestFunction {
+  ///
+  /// The fingerprint of empty content under this function.
+  ///
+  pub fn empty_fingerprint(self) -> Fingerprint {
+    Digest::of_bytes_with(self, &[]).hash
+  }
+}
+
+impl fmt::Display for DigestFunction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DigestFunction::Sha256 => write!(f, "sha256"),
+      DigestFunction::Blake3 => write!(f, "blake3"),
+    }
+  }
+}
+
+impl FromStr for DigestFunction {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "sha256" => Ok(DigestFunction::Sha256),
+      "blake3" => Ok(DigestFunction::Blake3),
+      _ => Err(format!(
+        "Unknown digest function {s:?}: expected `sha256` or `blake3`."
+      )),
+    }
+  }
+}
+
 #[derive(Eq, PartialEq, Ord, PartialOrd)]
 pub struct AgedFingerprint {
   // expired_seconds_ago must be the first field for the Ord implementation.
@@ -270,37 +315,60 @@ impl Digest {
   }
 
   pub fn of_bytes(bytes: &[u8]) -> Self {
-    let mut hasher = Sha256::default();
-    hasher.update(bytes);
+    Self::of_bytes_with(DigestFunction::Sha256, bytes)
+  }
 
-    Digest::new(Fingerprint::from_bytes(hasher.finalize()), bytes.len())
+  pub fn of_bytes_with(digest_function: DigestFunction, bytes: &[u8]) -> Self {
+    let mut hasher = Hasher::new_with(digest_function);
+    hasher.update(bytes);
+    hasher.finish()
   }
 }
 
-/// A thin wrapper around a Sha256 hasher to preserve the length as well.
+enum HasherState {
+  Sha256(Sha256),
+  // NB: Boxed because the BLAKE3 hasher keeps a large stack of chaining values.
+  Blake3(Box<blake3::Hasher>),
+}
+
+/// A thin wrapper around a hasher for a `DigestFunction` to preserve the length as well.
 pub struct Hasher {
-  hasher: Sha256,
+  hasher: HasherState,
   byte_count: usize,
 }
 
 impl Hasher {
   pub fn new() -> Self {
+    Self::new_with(DigestFunction::Sha256)
+  }
+
+  pub fn new_with(digest_function: DigestFunction) -> Self {
+    let hasher = match digest_function {
+      DigestFunction::Sha256 => HasherState::Sha256(Sha256::default()),
+      DigestFunction::Blake3 => HasherState::Blake3(Box::default()),
+    };
     Self {
-      hasher: Sha256::default(),
+      hasher,
       byte_count: 0,
     }
   }
 
   pub fn update(&mut self, buf: &[u8]) {
-    self.hasher.update(buf);
+    match &mut self.hasher {
+      HasherState::Sha256(hasher) => hasher.update(buf),
+      HasherState::Blake3(hasher) => {
+        hasher.update(buf);
+      }
+    }
     self.byte_count += buf.len();
   }
 
   pub fn finish(self) -> Digest {
-    Digest::new(
-      Fingerprint::from_bytes(self.hasher.finalize()),
-      self.byte_count,
-    )
+    let fingerprint = match self.hasher {
+      HasherState::Sha256(hasher) => Fingerprint::from_bytes(hasher.finalize()),
+      HasherState::Blake3(hasher) => Fingerprint(hasher.finalize().into()),
+    };
+    Digest::new(fingerprint, self.byte_count)
   }
 }
 
@@ -314,8 +382,12 @@ pub struct WriterHasher<T> {
 
 impl<T> WriterHasher<T> {
   pub fn new(inner: T) -> WriterHasher<T> {
+    Self::new_with(DigestFunction::Sha256, inner)
+  }
+
+  pub fn new_with(digest_function: DigestFunction, inner: T) -> WriterHasher<T> {
     WriterHasher {
-      hasher: Hasher::new(),
+      hasher: Hasher::new_with(digest_function),
       inner: inner,
     }
   }
@@ -379,7 +451,22 @@ where
   R: io::Read,
   W: io::Write,
 {
-  let mut hasher = WriterHasher::new(writer);
+  sync_copy_and_hash_with(DigestFunction::Sha256, reader, writer)
+}
+
+///
+/// As `sync_copy_and_hash`, but with the given digest function.
+///
+pub fn sync_copy_and_hash_with<R: ?Sized, W: ?Sized>(
+  digest_function: DigestFunction,
+  reader: &mut R,
+  writer: &mut W,
+) -> io::Result<Digest>
+where
+  R: io::Read,
+  W: io::Write,
+{
+  let mut hasher = WriterHasher::new_with(digest_function, writer);
   let _ = io::copy(reader, &mut hasher)?;
   Ok(hasher.finish().0)
 }
@@ -393,6 +480,29 @@ pub fn sync_verified_copy<R: ?Sized, W: ?Sized>(
   reader: &mut R,
   writer: &mut W,
 ) -> io::Result<bool>
+where
+  R: io::Read,
+  W: io::Write,
+{
+  sync_verified_copy_with(
+    DigestFunction::Sha256,
+    expected_digest,
+    data_is_immutable,
+    reader,
+    writer,
+  )
+}
+
+///
+/// As `sync_verified_copy`, but with the given digest function.
+///
+pub fn sync_verified_copy_with<R: ?Sized, W: ?Sized>(
+  digest_function: DigestFunction,
+  expected_digest: Digest,
+  data_is_immutable: bool,
+  reader: &mut R,
+  writer: &mut W,
+) -> io::Result<bool>
 where
   R: io::Read,
   W: io::Write,
@@ -402,7 +512,7 @@ where
     let copied = io::copy(reader, writer)?;
     Ok(copied as usize == expected_digest.size_bytes)
   } else {
-    Ok(expected_digest == sync_copy_and_hash(reader, writer)?)
+    Ok(expected_digest == sync_copy_and_hash_with(digest_function, reader, writer)?)
   }
 }
 
@@ -415,7 +525,22 @@ where
   R: AsyncRead + Unpin + ?Sized,
   W: AsyncWrite + Unpin + ?Sized,
 {
-  let mut hasher = WriterHasher::new(writer);
+  async_copy_and_hash_with(DigestFunction::Sha256, reader, writer).await
+}
+
+///
+/// As `async_copy_and_hash`, but with the given digest function.
+///
+pub async fn async_copy_and_hash_with<R, W>(
+  digest_function: DigestFunction,
+  reader: &mut R,
+  writer: &mut W,
+) -> tokio::io::Result<Digest>
+where
+  R: AsyncRead + Unpin + ?Sized,
+  W: AsyncWrite + Unpin + ?Sized,
+{
+  let mut hasher = WriterHasher::new_with(digest_function, writer);
   let _ = tokio::io::copy(reader, &mut hasher).await?;
   Ok(hasher.finish().0)
 }
@@ -429,6 +554,30 @@ pub async fn async_verified_copy<R, W>(
   reader: &mut R,
   writer: &mut W,
 ) -> tokio::io::Result<bool>
+where
+  R: AsyncRead + Unpin + ?Sized,
+  W: AsyncWrite + Unpin + ?Sized,
+{
+  async_verified_copy_with(
+    DigestFunction::Sha256,
+    expected_digest,
+    data_is_immutable,
+    reader,
+    writer,
+  )
+  .await
+}
+
+///
+/// As `async_verified_copy`, but with the given digest function.
+///
+pub async fn async_verified_copy_with<R, W>(
+  digest_function: DigestFunction,
+  expected_digest: Digest,
+  data_is_immutable: bool,
+  reader: &mut R,
+  writer: &mut W,
+) -> tokio::io::Result<bool>
 where
   R: AsyncRead + Unpin + ?Sized,
   W: AsyncWrite + Unpin + ?Sized,
@@ -438,7 +587,7 @@ where
     let copied = tokio::io::copy(reader, writer).await?;
     Ok(copied as usize == expected_digest.size_bytes)
   } else {
-    Ok(expected_digest == async_copy_and_hash(reader, writer).await?)
+    Ok(expected_digest == async_copy_and_hash_with(digest_function, reader, writer).await?)
   }
 }
 
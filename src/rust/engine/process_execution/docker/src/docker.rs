@@ -716,7 +716,10 @@ impl Command {
     let child_outputs = self.spawn(docker, container_id).await?;
     let mut stdout = BytesMut::with_capacity(8192);
     let mut stderr = BytesMut::with_capacity(8192);
-    let exit_code = collect_child_outputs(&mut stdout, &mut stderr, child_outputs).await?;
+    // NB: Containers do not run in the cgroups of local processes, so they are not reported as
+    // exceeding memory limits.
+    let exit_code =
+      collect_child_outputs(&mut stdout, &mut stderr, &mut None, child_outputs).await?;
     Ok((exit_code, stdout.freeze(), stderr.freeze()))
   }
 }
@@ -0,0 +1,114 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use workunit_store::{get_workunit_store_handle, Metric};
+
+///
+/// Bypasses a degraded remote cache, so that every process does not wait out its errors and
+/// timeouts.
+///
+/// The breaker trips (opens) after the given number of consecutive failed requests, and then
+/// bypasses requests, other than one request per probe interval which checks whether the cache has
+/// recovered. A successful request closes the breaker again.
+///
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+  failure_threshold: usize,
+  probe_interval: Duration,
+  state: Mutex<State>,
+}
+
+#[derive(Debug)]
+enum State {
+  Closed {
+    consecutive_failures: usize,
+  },
+  /// Requests are bypassed until the given time, when one is allowed in order to probe the cache.
+  Open {
+    next_probe: Instant,
+  },
+}
+
+impl CircuitBreaker {
+  pub fn new(failure_threshold: usize, probe_interval: Duration) -> Self {
+    Self {
+      failure_threshold: failure_threshold.max(1),
+      probe_interval,
+      state: Mutex::new(State::Closed {
+        consecutive_failures: 0,
+      }),
+    }
+  }
+
+  ///
+  /// True if a request should be made, or false if it should be bypassed.
+  ///
+  pub fn allow_request(&self) -> bool {
+    let mut state = self.state.lock();
+    match &mut *state {
+      State::Closed { .. } => true,
+      State::Open { next_probe } => {
+        let now = Instant::now();
+        if now >= *next_probe {
+          // NB: The next probe is scheduled immediately, so that a probe which never completes
+          // (because it was cancelled, for example) does not keep the breaker open forever.
+          *next_probe = now + self.probe_interval;
+          true
+        } else {
+          increment_counter(Metric::RemoteCacheCircuitBreakerBypasses);
+          false
+        }
+      }
+    }
+  }
+
+  pub fn record_success(&self) {
+    let mut state = self.state.lock();
+    if let State::Open { .. } = *state {
+      log::info!("The remote cache has recovered: no longer bypassing it.");
+      increment_counter(Metric::RemoteCacheCircuitBreakerRecoveries);
+    }
+    *state = State::Closed {
+      consecutive_failures: 0,
+    };
+  }
+
+  pub fn record_failure(&self) {
+    let mut state = self.state.lock();
+    let consecutive_failures = match &mut *state {
+      State::Closed {
+        consecutive_failures,
+      } => {
+        *consecutive_failures += 1;
+        *consecutive_failures
+      }
+      // A probe failed (or a request which was made before the breaker tripped did): the next
+      // probe is already scheduled.
+      State::Open { .. } => return,
+    };
+    if consecutive_failures >= self.failure_threshold {
+      log::warn!(
+        "The remote cache failed {consecutive_failures} consecutive requests: bypassing it, and \
+        checking whether it has recovered every {:?}.",
+        self.probe_interval
+      );
+      increment_counter(Metric::RemoteCacheCircuitBreakerTrips);
+      *state = State::Open {
+        next_probe: Instant::now() + self.probe_interval,
+      };
+    }
+  }
+
+  #[cfg(test)]
+  pub fn is_open(&self) -> bool {
+    matches!(*self.state.lock(), State::Open { .. })
+  }
+}
+
+fn increment_counter(metric: Metric) {
+  if let Some(mut workunit_store_handle) = get_workunit_store_handle() {
+    workunit_store_handle.store.increment_counter(metric, 1)
+  }
+}
@@ -0,0 +1,47 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::time::Duration;
+
+use crate::circuit_breaker::CircuitBreaker;
+
+#[test]
+fn trips_after_consecutive_failures() {
+  let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+
+  breaker.record_failure();
+  breaker.record_failure();
+  // A success resets the count of consecutive failures.
+  breaker.record_success();
+  breaker.record_failure();
+  breaker.record_failure();
+  assert!(!breaker.is_open());
+  assert!(breaker.allow_request());
+
+  breaker.record_failure();
+  assert!(breaker.is_open());
+  assert!(!breaker.allow_request());
+}
+
+#[test]
+fn probes_for_recovery() {
+  let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+  breaker.record_failure();
+  assert!(!breaker.allow_request());
+
+  // Once the interval has elapsed, a single request is allowed to probe the cache...
+  std::thread::sleep(Duration::from_millis(60));
+  assert!(breaker.allow_request());
+  assert!(!breaker.allow_request());
+
+  // ...and if it fails, the breaker stays open until the next probe.
+  breaker.record_failure();
+  assert!(breaker.is_open());
+  assert!(!breaker.allow_request());
+
+  std::thread::sleep(Duration::from_millis(60));
+  assert!(breaker.allow_request());
+  breaker.record_success();
+  assert!(!breaker.is_open());
+  assert!(breaker.allow_request());
+  assert!(breaker.allow_request());
+}
@@ -25,6 +25,10 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod circuit_breaker;
+#[cfg(test)]
+mod circuit_breaker_tests;
+
 pub mod remote;
 #[cfg(test)]
 pub mod remote_tests;
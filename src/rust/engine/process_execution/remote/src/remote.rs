@@ -19,7 +19,6 @@ use protos::gen::google::longrunning::{
   operations_client::OperationsClient, CancelOperationRequest, Operation,
 };
 use protos::gen::google::rpc::{PreconditionFailure, Status as StatusProto};
-use rand::{thread_rng, Rng};
 use remexec::{
   capabilities_client::CapabilitiesClient, execution_client::ExecutionClient,
   execution_stage::Value as ExecutionStageValue, Action, Command, ExecuteRequest, ExecuteResponse,
@@ -32,6 +31,7 @@ use concrete_time::TimeSpan;
 use fs::{self, DirectoryDigest, EMPTY_DIRECTORY_DIGEST};
 use grpc_util::headers_to_http_header_map;
 use grpc_util::prost::MessageExt;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::{layered_service, status_to_str, LayeredService};
 use hashing::{Digest, Fingerprint};
 use store::{Store, StoreError};
@@ -92,7 +92,7 @@ pub struct CommandRunner {
   execution_client: Arc<ExecutionClient<LayeredService>>,
   operations_client: Arc<OperationsClient<LayeredService>>,
   overall_deadline: Duration,
-  retry_interval_duration: Duration,
+  retry_policy: RetryPolicy,
   capabilities_cell: Arc<OnceCell<ServerCapabilities>>,
   capabilities_client: Arc<CapabilitiesClient<LayeredService>>,
 }
@@ -158,25 +158,45 @@ impl Drop for RunningOperation {
 }
 
 impl CommandRunner {
+  ///
+  /// The policy with which execution requests are retried by default, which is more patient than
+  /// the default for other RPCs, since executions are comparatively expensive to restart.
+  ///
+  pub fn default_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+      attempts: 6,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(10),
+      retryable_codes: vec![
+        Code::Aborted,
+        Code::Internal,
+        Code::ResourceExhausted,
+        Code::Unavailable,
+        Code::Unknown,
+      ],
+    }
+  }
+
   /// Construct a new CommandRunner
   pub fn new(
     execution_address: &str,
     instance_name: Option<String>,
     process_cache_namespace: Option<String>,
     append_only_caches_base_path: Option<String>,
-    root_ca_certs: Option<Vec<u8>>,
+    tls_config: grpc_util::tls::Config,
     headers: BTreeMap<String, String>,
+    token_provider: Option<Arc<grpc_util::auth::TokenProvider>>,
     store: Store,
     executor: Executor,
     overall_deadline: Duration,
-    retry_interval_duration: Duration,
+    retry_policy: RetryPolicy,
     execution_concurrency_limit: usize,
     capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
   ) -> Result<Self, String> {
     let execution_use_tls = execution_address.starts_with("https://");
 
     let tls_client_config = if execution_use_tls {
-      Some(grpc_util::tls::Config::new_without_mtls(root_ca_certs).try_into()?)
+      Some(tls_config.try_into()?)
     } else {
       None
     };
@@ -192,6 +212,7 @@ impl CommandRunner {
       tonic::transport::Channel::balance_list(vec![execution_endpoint].into_iter()),
       execution_concurrency_limit,
       execution_http_headers,
+      token_provider,
       None,
     );
     let execution_client = Arc::new(ExecutionClient::new(execution_channel.clone()));
@@ -207,7 +228,7 @@ impl CommandRunner {
       store,
       executor,
       overall_deadline,
-      retry_interval_duration,
+      retry_policy,
       capabilities_cell: capabilities_cell_opt.unwrap_or_else(|| Arc::new(OnceCell::new())),
       capabilities_client,
     };
@@ -684,11 +705,9 @@ impl CommandRunner {
         Err(self.extract_missing_digests(&precondition_failure))
       }
 
-      Code::Aborted
-      | Code::Internal
-      | Code::ResourceExhausted
-      | Code::Unavailable
-      | Code::Unknown => Err(ExecutionError::Retryable(status.message)),
+      code if self.retry_policy.retryable_codes.contains(&code) => {
+        Err(ExecutionError::Retryable(status.message))
+      }
       code => Err(ExecutionError::Fatal(
         format!(
           "Error from remote execution: {:?}: {:?}",
@@ -714,9 +733,6 @@ impl CommandRunner {
     context: &Context,
     workunit: &mut RunningWorkunit,
   ) -> Result<FallibleProcessResultWithPlatform, ProcessError> {
-    const MAX_RETRIES: u32 = 5;
-    const MAX_BACKOFF_DURATION: Duration = Duration::from_secs(10);
-
     let start_time = Instant::now();
 
     let mut running_operation = RunningOperation::new(
@@ -732,9 +748,7 @@ impl CommandRunner {
       if num_retries > 0 {
         workunit.increment_counter(Metric::RemoteExecutionRPCRetries, 1);
 
-        let multiplier = thread_rng().gen_range(0..2_u32.pow(num_retries) + 1);
-        let sleep_time = self.retry_interval_duration * multiplier;
-        let sleep_time = sleep_time.min(MAX_BACKOFF_DURATION);
+        let sleep_time = self.retry_policy.backoff(num_retries);
         debug!("delaying {:?} before retry", sleep_time);
         tokio::time::sleep(sleep_time).await;
       }
@@ -802,7 +816,7 @@ impl CommandRunner {
               // Check if the number of request attempts sent thus far have exceeded the number
               // of retries allowed since the last successful connection. (There is no point in
               // continually submitting a request if ultimately futile.)
-              if num_retries >= MAX_RETRIES {
+              if num_retries as usize + 1 >= self.retry_policy.attempts {
                 workunit.increment_counter(Metric::RemoteExecutionRPCErrors, 1);
                 return Err(
                   "Too many failures from server. The last event was the server disconnecting with no error given.".to_owned().into(),
@@ -849,7 +863,7 @@ impl CommandRunner {
             // of retries allowed since the last successful connection. (There is no point in
             // continually submitting a request if ultimately futile.)
             trace!("retryable error: {}", e);
-            if num_retries >= MAX_RETRIES {
+            if num_retries as usize + 1 >= self.retry_policy.attempts {
               workunit.increment_counter(Metric::RemoteExecutionRPCErrors, 1);
               return Err(format!("Too many failures from server. The last error was: {e}").into());
             } else {
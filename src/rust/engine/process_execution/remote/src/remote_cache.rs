@@ -20,9 +20,11 @@ use remexec::action_cache_client::ActionCacheClient;
 use remexec::{ActionResult, Command, Tree};
 use store::{Store, StoreError};
 use workunit_store::{
-  in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, WorkunitMetadata,
+  get_workunit_store_handle, in_workunit, Level, Metric, ObservationMetric, RunningWorkunit,
+  WorkunitMetadata,
 };
 
+use crate::circuit_breaker::CircuitBreaker;
 use crate::remote::apply_headers;
 use process_execution::{
   check_cache_content, populate_fallible_execution_result, CacheContentBehavior, Context,
@@ -60,9 +62,11 @@ pub struct CommandRunner {
   cache_read: bool,
   cache_write: bool,
   cache_content_behavior: CacheContentBehavior,
+  validate_action_results: bool,
   warnings_behavior: RemoteCacheWarningsBehavior,
   read_errors_counter: Arc<Mutex<BTreeMap<String, usize>>>,
   write_errors_counter: Arc<Mutex<BTreeMap<String, usize>>>,
+  circuit_breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl CommandRunner {
@@ -73,8 +77,9 @@ impl CommandRunner {
     executor: task_executor::Executor,
     store: Store,
     action_cache_address: &str,
-    root_ca_certs: Option<Vec<u8>>,
+    tls_config: grpc_util::tls::Config,
     mut headers: BTreeMap<String, String>,
+    token_provider: Option<Arc<grpc_util::auth::TokenProvider>>,
     cache_read: bool,
     cache_write: bool,
     warnings_behavior: RemoteCacheWarningsBehavior,
@@ -84,7 +89,7 @@ impl CommandRunner {
     append_only_caches_base_path: Option<String>,
   ) -> Result<Self, String> {
     let tls_client_config = if action_cache_address.starts_with("https://") {
-      Some(grpc_util::tls::Config::new_without_mtls(root_ca_certs).try_into()?)
+      Some(tls_config.try_into()?)
     } else {
       None
     };
@@ -99,6 +104,7 @@ impl CommandRunner {
       tonic::transport::Channel::balance_list(vec![endpoint].into_iter()),
       concurrency_limit,
       http_headers,
+      token_provider,
       Some((read_timeout, Metric::RemoteCacheRequestTimeouts)),
     );
     let action_cache_client = Arc::new(ActionCacheClient::new(channel));
@@ -114,12 +120,62 @@ impl CommandRunner {
       cache_read,
       cache_write,
       cache_content_behavior,
+      validate_action_results: false,
       warnings_behavior,
       read_errors_counter: Arc::new(Mutex::new(BTreeMap::new())),
       write_errors_counter: Arc::new(Mutex::new(BTreeMap::new())),
+      circuit_breaker: None,
     })
   }
 
+  ///
+  /// Bypasses the remote cache (for both reads and writes) after the given number of consecutive
+  /// failed requests, until a probe request (made once per the given interval) succeeds. See
+  /// `CircuitBreaker`.
+  ///
+  pub fn with_circuit_breaker(
+    mut self,
+    failure_threshold: usize,
+    probe_interval: Duration,
+  ) -> Self {
+    self.circuit_breaker = Some(Arc::new(CircuitBreaker::new(
+      failure_threshold,
+      probe_interval,
+    )));
+    self
+  }
+
+  ///
+  /// Checks that the blobs which an `ActionResult` directly references (its stdout, stderr, output
+  /// files and output trees) exist, with a single FindMissingBlobs request, before accepting it as
+  /// a hit. An `ActionResult` which references missing blobs is treated as a miss, rather than
+  /// failing later when the blobs are fetched.
+  ///
+  /// This is independent of the `CacheContentBehavior`, which may additionally fetch or validate
+  /// the contents of output directories.
+  ///
+  pub fn with_action_result_validation(mut self) -> Self {
+    self.validate_action_results = true;
+    self
+  }
+
+  fn allow_cache_request(&self) -> bool {
+    self
+      .circuit_breaker
+      .as_ref()
+      .map_or(true, |circuit_breaker| circuit_breaker.allow_request())
+  }
+
+  fn record_cache_request<T, E>(&self, result: &Result<T, E>) {
+    if let Some(circuit_breaker) = &self.circuit_breaker {
+      if result.is_ok() {
+        circuit_breaker.record_success();
+      } else {
+        circuit_breaker.record_failure();
+      }
+    }
+  }
+
   /// Create a REAPI `Tree` protobuf for an output directory by traversing down from a Pants
   /// merged final output directory to find the specific path to extract. (REAPI requires
   /// output directories to be stored as `Tree` protos that contain all of the `Directory`
@@ -277,6 +333,9 @@ impl CommandRunner {
   ) -> Result<(FallibleProcessResultWithPlatform, bool), ProcessError> {
     // A future to read from the cache and log the results accordingly.
     let mut cache_read_future = async {
+      if !self.allow_cache_request() {
+        return None;
+      }
       let response = check_action_cache(
         action_digest,
         &request.description,
@@ -286,8 +345,10 @@ impl CommandRunner {
         self.action_cache_client.clone(),
         self.store.clone(),
         self.cache_content_behavior,
+        self.validate_action_results,
       )
       .await;
+      self.record_cache_request(&response);
       match response {
         Ok(cached_response_opt) => match &cached_response_opt {
           Some(cached_response) if cached_response.exit_code == 0 || failures_cached => {
@@ -526,6 +587,7 @@ impl process_execution::CommandRunner for CommandRunner {
       && (result.exit_code == 0 || failures_cached)
       && self.cache_write
       && use_remote_cache
+      && self.allow_cache_request()
     {
       let command_runner = self.clone();
       let result = result.clone();
@@ -540,6 +602,7 @@ impl process_execution::CommandRunner for CommandRunner {
             command_digest,
           )
           .await;
+        command_runner.record_cache_request(&write_result);
         match write_result {
           Ok(_) => workunit.increment_counter(Metric::RemoteCacheWriteSuccesses, 1),
           Err(err) => {
@@ -564,6 +627,64 @@ impl process_execution::CommandRunner for CommandRunner {
   }
 }
 
+///
+/// Returns true if all of the blobs which the given `ActionResult` directly references exist. See
+/// `CommandRunner::with_action_result_validation`.
+///
+async fn action_result_is_complete(
+  store: &Store,
+  action_result: &ActionResult,
+) -> Result<bool, Status> {
+  let digests = [
+    action_result.stdout_digest.as_ref(),
+    action_result.stderr_digest.as_ref(),
+  ]
+  .into_iter()
+  .flatten()
+  .chain(
+    action_result
+      .output_files
+      .iter()
+      .filter_map(|output_file| output_file.digest.as_ref()),
+  )
+  .chain(
+    action_result
+      .output_directories
+      .iter()
+      .filter_map(|output_directory| output_directory.tree_digest.as_ref()),
+  )
+  .map(|digest| require_digest(Some(digest)))
+  .collect::<Result<Vec<_>, _>>()
+  .map_err(|e| Status::unavailable(format!("Output digests could not be parsed: {e}")))?;
+
+  let missing = in_workunit!(
+    "validate_action_result",
+    Level::Trace,
+    |_workunit| async move {
+      store
+        .missing_digests(digests.into_iter().filter(|digest| digest.size_bytes > 0))
+        .await
+    }
+  )
+  .await
+  .map_err(|e| Status::unavailable(format!("Output digests could not be validated: {e}")))?;
+  if missing.is_empty() {
+    return Ok(true);
+  }
+
+  log::debug!(
+    "Ignoring a remote cache hit which references {} missing blobs, including {:?}.",
+    missing.len(),
+    missing.iter().next().unwrap()
+  );
+  if let Some(mut workunit_store_handle) = get_workunit_store_handle() {
+    workunit_store_handle
+      .store
+      .increment_counter(Metric::RemoteCacheIncompleteResults, 1);
+  }
+  Ok(false)
+}
+
 /// Check the remote Action Cache for a cached result of running the given `command` and the Action
 /// with the given `action_digest`.
 ///
@@ -580,6 +701,7 @@ async fn check_action_cache(
   action_cache_client: Arc<ActionCacheClient<LayeredService>>,
   store: Store,
   cache_content_behavior: CacheContentBehavior,
+  validate_action_results: bool,
 ) -> Result<Option<FallibleProcessResultWithPlatform>, ProcessError> {
   in_workunit!(
     "check_action_cache",
@@ -605,6 +727,9 @@ async fn check_action_cache(
       )
       .and_then(|action_result| async move {
         let action_result = action_result.into_inner();
+        if validate_action_results && !action_result_is_complete(&store, &action_result).await? {
+          return Err(Status::not_found(""));
+        }
         let response = populate_fallible_execution_result(
           store.clone(),
           context.run_id,
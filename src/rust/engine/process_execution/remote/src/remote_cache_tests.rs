@@ -12,6 +12,7 @@ use tempfile::TempDir;
 use tokio::time::sleep;
 
 use fs::{DirectoryDigest, RelativePath, EMPTY_DIRECTORY_DIGEST};
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls;
 use hashing::{Digest, EMPTY_DIGEST};
 use mock::StubCAS;
@@ -110,9 +111,13 @@ impl StoreSetup {
         None,
         tls::Config::default(),
         BTreeMap::new(),
+        None,
         10 * 1024 * 1024,
         Duration::from_secs(1),
-        1,
+        RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
         256,
         None,
         4 * 1024 * 1024,
@@ -153,8 +158,9 @@ fn create_cached_runner(
       store_setup.executor.clone(),
       store_setup.store.clone(),
       &store_setup.cas.address(),
-      None,
+      tls::Config::default(),
       BTreeMap::default(),
+      None,
       true,
       true,
       RemoteCacheWarningsBehavior::FirstOnly,
@@ -244,6 +250,58 @@ async fn cache_read_skipped_on_action_cache_errors() {
   assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 1);
 }
 
+/// After repeated errors, the cache should be bypassed rather than waited on.
+#[tokio::test]
+async fn cache_read_bypassed_after_repeated_errors() {
+  let (workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+  let store_setup = StoreSetup::new();
+  let (local_runner, local_runner_call_counter) = create_local_runner(1, 0);
+  let cache_runner = crate::remote_cache::CommandRunner::new(
+    local_runner.into(),
+    None,
+    None,
+    store_setup.executor.clone(),
+    store_setup.store.clone(),
+    &store_setup.cas.address(),
+    tls::Config::default(),
+    BTreeMap::default(),
+    None,
+    true,
+    true,
+    RemoteCacheWarningsBehavior::FirstOnly,
+    CacheContentBehavior::Defer,
+    256,
+    CACHE_READ_TIMEOUT,
+    None,
+  )
+  .expect("caching command runner")
+  .with_circuit_breaker(2, Duration::from_secs(60));
+
+  let (process, _action_digest) = create_process(&store_setup).await;
+  store_setup
+    .cas
+    .action_cache
+    .always_errors
+    .store(true, Ordering::SeqCst);
+
+  for _ in 0..3 {
+    let result = cache_runner
+      .run(Context::default(), &mut workunit, process.clone())
+      .await
+      .unwrap();
+    assert_eq!(result.exit_code, 1);
+  }
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 3);
+
+  let metrics = workunit_store.get_metrics();
+  assert_eq!(metrics.get("remote_cache_read_errors"), Some(&2));
+  assert_eq!(metrics.get("remote_cache_circuit_breaker_trips"), Some(&1));
+  assert_eq!(
+    metrics.get("remote_cache_circuit_breaker_bypasses"),
+    Some(&1)
+  );
+}
+
 /// If the cache cannot find a digest during a read from the store during fetch, we should gracefully
 /// fallback to the local runner.
 #[tokio::test]
@@ -327,6 +385,74 @@ async fn cache_read_eager_fetch() {
   assert_eq!(eager_local_call_count, 1);
 }
 
+/// With action result validation enabled, we should skip the remote cache if any of the digests
+/// which the action result references are missing from the store, even when content loading is
+/// deferred.
+#[tokio::test]
+async fn cache_read_validates_action_results() {
+  let (workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+
+  async fn run_process(cas: StubCAS, workunit: &mut RunningWorkunit) -> (i32, usize) {
+    let store_setup = StoreSetup::new_with_stub_cas(cas);
+    let (local_runner, local_runner_call_counter) = create_local_runner(1, 500);
+    let cache_runner = crate::remote_cache::CommandRunner::new(
+      local_runner.into(),
+      None,
+      None,
+      store_setup.executor.clone(),
+      store_setup.store.clone(),
+      &store_setup.cas.address(),
+      tls::Config::default(),
+      BTreeMap::default(),
+      None,
+      true,
+      true,
+      RemoteCacheWarningsBehavior::FirstOnly,
+      CacheContentBehavior::Defer,
+      256,
+      CACHE_READ_TIMEOUT,
+      None,
+    )
+    .expect("caching command runner")
+    .with_action_result_validation();
+
+    let (process, action_digest) = create_process(&store_setup).await;
+    store_setup.cas.action_cache.insert(
+      action_digest,
+      0,
+      TestData::roland().digest(),
+      EMPTY_DIGEST,
+    );
+
+    let result = cache_runner
+      .run(Context::default(), workunit, process)
+      .await
+      .unwrap();
+    (
+      result.exit_code,
+      local_runner_call_counter.load(Ordering::SeqCst),
+    )
+  }
+
+  let complete = StubCAS::builder().file(&TestData::roland()).build();
+  assert_eq!(run_process(complete, &mut workunit).await, (0, 0));
+  assert_eq!(
+    workunit_store
+      .get_metrics()
+      .get("remote_cache_incomplete_results"),
+    None
+  );
+
+  let incomplete = StubCAS::builder().build();
+  assert_eq!(run_process(incomplete, &mut workunit).await, (1, 1));
+  assert_eq!(
+    workunit_store
+      .get_metrics()
+      .get("remote_cache_incomplete_results"),
+    Some(&1)
+  );
+}
+
 #[tokio::test]
 async fn cache_read_speculation() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();
@@ -738,8 +864,9 @@ async fn make_action_result_basic() {
     executor.clone(),
     store.clone(),
     &cas.address(),
-    None,
+    tls::Config::default(),
     BTreeMap::default(),
+    None,
     true,
     true,
     RemoteCacheWarningsBehavior::FirstOnly,
@@ -6,6 +6,7 @@ use std::time::Duration;
 
 use bytes::Bytes;
 use grpc_util::prost::MessageExt;
+use grpc_util::retry::RetryPolicy;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint};
 use maplit::{btreemap, hashset};
@@ -28,18 +29,24 @@ use fs::{DirectoryDigest, RelativePath, SymlinkBehavior, EMPTY_DIRECTORY_DIGEST}
 use process_execution::{
   CacheName, CommandRunner as CommandRunnerTrait, Context, EntireExecuteRequest,
   FallibleProcessResultWithPlatform, InputDigests, Platform, Process, ProcessCacheScope,
-  ProcessError, ProcessExecutionEnvironment, ProcessExecutionStrategy,
+  ProcessError, ProcessExecutionEnvironment, ProcessExecutionStrategy, ResourceLimits,
 };
 use std::any::type_name;
 use std::io::Cursor;
 use tonic::{Code, Status};
 
 const OVERALL_DEADLINE_SECS: Duration = Duration::from_secs(10 * 60);
-const RETRY_INTERVAL: Duration = Duration::from_micros(0);
 const STORE_CONCURRENCY_LIMIT: usize = 256;
 const STORE_BATCH_API_SIZE_LIMIT: usize = 4 * 1024 * 1024;
 const EXEC_CONCURRENCY_LIMIT: usize = 256;
 
+fn retry_policy() -> RetryPolicy {
+  RetryPolicy {
+    initial_backoff: Duration::ZERO,
+    ..CommandRunner::default_retry_policy()
+  }
+}
+
 #[derive(Debug, PartialEq)]
 struct RemoteTestResult {
   original: FallibleProcessResultWithPlatform,
@@ -102,6 +109,7 @@ async fn make_execute_request() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: make_environment(Platform::Linux_x86_64),
     remote_cache_speculation_delay: std::time::Duration::from_millis(0),
@@ -194,6 +202,7 @@ async fn make_execute_request_with_instance_name() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: ProcessExecutionEnvironment {
       name: None,
@@ -306,6 +315,7 @@ async fn make_execute_request_with_cache_key_gen_version() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: make_environment(Platform::Linux_x86_64),
     remote_cache_speculation_delay: std::time::Duration::from_millis(0),
@@ -579,6 +589,7 @@ async fn make_execute_request_with_timeout() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: make_environment(Platform::Linux_x86_64),
     remote_cache_speculation_delay: std::time::Duration::from_millis(0),
@@ -678,6 +689,7 @@ async fn make_execute_request_with_append_only_caches() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: make_environment(Platform::Linux_x86_64),
     remote_cache_speculation_delay: std::time::Duration::from_millis(0),
@@ -832,6 +844,7 @@ async fn make_execute_request_using_immutable_inputs() {
     jdk_home: None,
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment: make_environment(Platform::Linux_x86_64),
     remote_cache_speculation_delay: std::time::Duration::from_millis(0),
@@ -1273,9 +1286,13 @@ async fn sends_headers() {
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * 1024 * 1024,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
       STORE_CONCURRENCY_LIMIT,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1314,15 +1331,16 @@ async fn sends_headers() {
     None,
     None,
     None,
-    None,
+    tls::Config::default(),
     btreemap! {
       String::from("cat") => String::from("roland"),
       String::from("authorization") => String::from("Bearer catnip-will-get-you-anywhere"),
     },
+    None,
     store,
     task_executor::Executor::new(),
     OVERALL_DEADLINE_SECS,
-    RETRY_INTERVAL,
+    retry_policy(),
     EXEC_CONCURRENCY_LIMIT,
     None,
   )
@@ -1437,9 +1455,13 @@ async fn ensure_inline_stdio_is_stored() {
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * 1024 * 1024,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
       STORE_CONCURRENCY_LIMIT,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1480,12 +1502,13 @@ async fn ensure_inline_stdio_is_stored() {
     None,
     None,
     None,
-    None,
+    tls::Config::default(),
     BTreeMap::new(),
+    None,
     store.clone(),
     task_executor::Executor::new(),
     OVERALL_DEADLINE_SECS,
-    RETRY_INTERVAL,
+    retry_policy(),
     EXEC_CONCURRENCY_LIMIT,
     None,
   )
@@ -1790,9 +1813,13 @@ async fn execute_missing_file_uploads_if_known() {
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * 1024 * 1024,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
       STORE_CONCURRENCY_LIMIT,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1860,12 +1887,13 @@ async fn execute_missing_file_uploads_if_known() {
     None,
     None,
     None,
-    None,
+    tls::Config::default(),
     BTreeMap::new(),
+    None,
     store.clone(),
     task_executor::Executor::new(),
     OVERALL_DEADLINE_SECS,
-    RETRY_INTERVAL,
+    retry_policy(),
     EXEC_CONCURRENCY_LIMIT,
     None,
   )
@@ -1907,9 +1935,13 @@ async fn execute_missing_file_errors_if_unknown() {
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * 1024 * 1024,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
       STORE_CONCURRENCY_LIMIT,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -1921,12 +1953,13 @@ async fn execute_missing_file_errors_if_unknown() {
     None,
     None,
     None,
-    None,
+    tls::Config::default(),
     BTreeMap::new(),
+    None,
     store,
     task_executor::Executor::new(),
     OVERALL_DEADLINE_SECS,
-    RETRY_INTERVAL,
+    retry_policy(),
     EXEC_CONCURRENCY_LIMIT,
     None,
   )
@@ -2624,12 +2657,13 @@ fn create_command_runner(execution_address: String, cas: &mock::StubCAS) -> (Com
     None,
     None,
     None,
-    None,
+    tls::Config::default(),
     BTreeMap::new(),
+    None,
     store.clone(),
     task_executor::Executor::new(),
     OVERALL_DEADLINE_SECS,
-    RETRY_INTERVAL,
+    retry_policy(),
     EXEC_CONCURRENCY_LIMIT,
     None,
   )
@@ -2681,9 +2715,13 @@ fn make_store(store_dir: &Path, cas: &mock::StubCAS, executor: task_executor::Ex
       None,
       tls::Config::default(),
       BTreeMap::new(),
+      None,
       10 * 1024 * 1024,
       Duration::from_secs(1),
-      1,
+      RetryPolicy {
+      attempts: 2,
+      ..RetryPolicy::default()
+    },
       STORE_CONCURRENCY_LIMIT,
       None,
       STORE_BATCH_API_SIZE_LIMIT,
@@ -0,0 +1,367 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::ffi::CString;
+use std::fmt;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use deepsize::DeepSizeOf;
+use serde::Serialize;
+use workunit_store::{get_workunit_store_handle, Metric};
+
+const CGROUP_FS: &str = "/sys/fs/cgroup";
+
+/// The controllers which must be available in order to apply `ResourceLimits`.
+const CONTROLLERS: &str = "+cpu +memory +pids";
+
+/// The period (in microseconds) of the CPU bandwidth limits which are written to `cpu.max`.
+const CPU_PERIOD_MICROS: usize = 100_000;
+
+/// Bounds how long the removal of a cgroup waits for the (killed) processes in it to exit.
+const REMOVAL_ATTEMPTS: usize = 50;
+const REMOVAL_INTERVAL: Duration = Duration::from_millis(10);
+
+///
+/// Limits on the resources which may be used by a process (including all of its descendants), or
+/// (as budgets) by all of the processes which run locally. Unset limits are unlimited.
+///
+#[derive(Clone, Copy, Debug, Default, DeepSizeOf, Eq, Hash, PartialEq, Serialize)]
+pub struct ResourceLimits {
+  pub memory_bytes: Option<usize>,
+  /// In thousandths of a CPU, so that fractional CPUs may be requested.
+  pub cpu_millis: Option<usize>,
+  pub pids: Option<usize>,
+}
+
+impl ResourceLimits {
+  fn apply(&self, cgroup: &Path) -> Result<(), String> {
+    write_limit(
+      cgroup,
+      "memory.max",
+      self.memory_bytes.map(|bytes| bytes.to_string()),
+    )?;
+    if self.memory_bytes.is_some() {
+      // A process which exceeds its memory limit should be killed, rather than swapping. The file
+      // does not exist if swap accounting is disabled, in which case there is nothing to do.
+      match std::fs::write(cgroup.join("memory.swap.max"), "0") {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+          return Err(format!("Failed to disable swap for {cgroup:?}: {e}"))
+        }
+        _ => (),
+      }
+    }
+    write_limit(
+      cgroup,
+      "cpu.max",
+      Some(match self.cpu_millis {
+        Some(millis) => format!(
+          "{} {CPU_PERIOD_MICROS}",
+          (millis * CPU_PERIOD_MICROS / 1000).max(1000)
+        ),
+        None => format!("max {CPU_PERIOD_MICROS}"),
+      }),
+    )?;
+    write_limit(cgroup, "pids.max", self.pids.map(|pids| pids.to_string()))
+  }
+}
+
+///
+/// The memory limit which a process exceeded, causing it to be killed.
+///
+#[derive(Clone, Copy, Debug, DeepSizeOf, Eq, Hash, PartialEq)]
+pub enum MemoryLimitExceeded {
+  /// The memory limit (in bytes) which the process requested.
+  Process(usize),
+  /// The total memory budget (in bytes) of all local processes.
+  Budget(usize),
+  /// The memory which was available to the engine.
+  Available,
+}
+
+impl fmt::Display for MemoryLimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Process(limit) => write!(f, "memory limit of {limit} bytes"),
+      Self::Budget(budget) => write!(
+        f,
+        "total memory limit of {budget} bytes for local processes"
+      ),
+      Self::Available => write!(f, "available memory"),
+    }
+  }
+}
+
+fn write_limit(cgroup: &Path, file: &str, value: Option<String>) -> Result<(), String> {
+  let path = cgroup.join(file);
+  std::fs::write(&path, value.as_deref().unwrap_or("max"))
+    .map_err(|e| format!("Failed to write {path:?}: {e}"))
+}
+
+///
+/// Applies `ResourceLimits` to locally executed processes using cgroups (v2), which are only
+/// available on Linux.
+///
+/// A cgroup is created beneath the cgroup of this process which applies the given budgets to all
+/// processes, and each process runs in a cgroup of its own (beneath that one) which applies the
+/// limits that it requested. The cgroup of this process must have been delegated to the user that
+/// it runs as: by running it via `systemd-run --user --scope -p Delegate=yes`, for example.
+///
+/// If this process had to move into a leaf cgroup of its own in order to do so, it moves back
+/// (and removes the leaf) when this is dropped.
+///
+#[derive(Debug)]
+pub struct Cgroups {
+  parent: PathBuf,
+  leaf: Option<PathBuf>,
+  root: PathBuf,
+  budgets: ResourceLimits,
+  next_id: AtomicUsize,
+}
+
+impl Cgroups {
+  pub fn new(budgets: ResourceLimits) -> Result<Self, String> {
+    let own_cgroup = std::fs::read_to_string("/proc/self/cgroup")
+      .map_err(|e| format!("Failed to read the cgroup of this process: {e}"))?;
+    let own_cgroup = Path::new(CGROUP_FS).join(parse_own_cgroup(&own_cgroup)?);
+    if !own_cgroup.join("cgroup.controllers").exists() {
+      return Err(format!(
+        "{own_cgroup:?} is not a cgroup v2 hierarchy, which is required to apply resource limits \
+        to processes."
+      ));
+    }
+    Self::new_in(&own_cgroup, budgets)
+  }
+
+  ///
+  /// Creates the cgroup which holds the cgroups of processes beneath the given cgroup.
+  ///
+  pub(crate) fn new_in(parent: &Path, budgets: ResourceLimits) -> Result<Self, String> {
+    let mut cgroups = Self {
+      parent: parent.to_owned(),
+      leaf: None,
+      root: parent.join(format!("pants-processes-{}", std::process::id())),
+      budgets,
+      next_id: AtomicUsize::new(0),
+    };
+    // NB: From here on, any cgroups which were created are cleaned up by `Drop` on failure.
+    if let Err(e) = enable_controllers(parent) {
+      if e.raw_os_error() != Some(libc::EBUSY) {
+        return Err(format!("Failed to enable controllers for {parent:?}: {e}"));
+      }
+      // A cgroup which holds processes cannot distribute resources to its children (the "no
+      // internal processes" rule), so this process moves into a leaf cgroup of its own.
+      let leaf = parent.join(format!("pants-engine-{}", std::process::id()));
+      create_cgroup(&leaf)?;
+      cgroups.leaf = Some(leaf.clone());
+      enter_leaf(parent, &leaf)?;
+    }
+
+    let root = cgroups.root.clone();
+    create_cgroup(&root)?;
+    budgets.apply(&root)?;
+    enable_controllers(&root)
+      .map_err(|e| format!("Failed to enable controllers for {root:?}: {e}"))?;
+    Ok(cgroups)
+  }
+
+  ///
+  /// Creates the cgroup for a process which requested the given limits.
+  ///
+  pub fn create(&self, limits: ResourceLimits) -> Result<ProcessCgroup, String> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let path = self.root.join(format!("process-{id}"));
+    create_cgroup(&path)?;
+    let cgroup = ProcessCgroup {
+      procs: CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+        .map_err(|e| format!("Failed to create the cgroup of a process: {e}"))?,
+      path,
+      memory_limit: limits.memory_bytes,
+      memory_budget: self.budgets.memory_bytes,
+    };
+    limits.apply(&cgroup.path)?;
+    Ok(cgroup)
+  }
+}
+
+impl Drop for Cgroups {
+  fn drop(&mut self) {
+    if let Err(e) = std::fs::remove_dir(&self.root) {
+      log::debug!("Failed to remove {:?}: {e}", self.root);
+    }
+    if let Some(leaf) = self.leaf.take() {
+      leave_leaf(&self.parent, &leaf);
+    }
+  }
+}
+
+///
+/// Moves this process into the given leaf cgroup, and then enables controllers in its parent.
+///
+pub(crate) fn enter_leaf(parent: &Path, leaf: &Path) -> Result<(), String> {
+  std::fs::write(leaf.join("cgroup.procs"), "0")
+    .map_err(|e| format!("Failed to move this process into {leaf:?}: {e}"))?;
+  enable_controllers(parent)
+    .map_err(|e| format!("Failed to enable controllers for {parent:?}: {e}"))
+}
+
+///
+/// Undoes `enter_leaf`: moves this process back into the parent cgroup, and removes the leaf.
+///
+/// The controllers must be disabled in the parent before this process may rejoin it: if they
+/// cannot be (because other cgroups beneath the parent still use them), this process stays in the
+/// leaf.
+///
+pub(crate) fn leave_leaf(parent: &Path, leaf: &Path) {
+  let moved_back =
+    disable_controllers(parent).and_then(|()| std::fs::write(parent.join("cgroup.procs"), "0"));
+  if let Err(e) = moved_back {
+    log::debug!("Failed to move this process out of {leaf:?}: {e}");
+    return;
+  }
+  if let Err(e) = std::fs::remove_dir(leaf) {
+    log::debug!("Failed to remove {leaf:?}: {e}");
+  }
+}
+
+///
+/// The cgroup of a single process, which is removed (after killing any processes which remain in
+/// it) when it is dropped.
+///
+#[derive(Debug)]
+pub struct ProcessCgroup {
+  path: PathBuf,
+  /// The path of the `cgroup.procs` file, which is prepared in advance so that it can be used
+  /// between `fork` and `exec`.
+  procs: CString,
+  memory_limit: Option<usize>,
+  memory_budget: Option<usize>,
+}
+
+impl ProcessCgroup {
+  ///
+  /// Moves the calling process into this cgroup. Intended to be called (via `pre_exec`) in a child
+  /// process between `fork` and `exec`, and so only makes async-signal-safe calls.
+  ///
+  pub fn join(&self) -> std::io::Result<()> {
+    unsafe {
+      let fd = libc::open(self.procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+      if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      // Writing "0" moves the writing process.
+      let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+      let result = if written == 1 {
+        Ok(())
+      } else {
+        Err(std::io::Error::last_os_error())
+      };
+      libc::close(fd);
+      result
+    }
+  }
+
+  ///
+  /// If a process in this cgroup was killed because it exceeded a memory limit, returns the limit
+  /// that it exceeded.
+  ///
+  pub fn memory_limit_exceeded(&self) -> Option<MemoryLimitExceeded> {
+    let events = std::fs::read_to_string(self.path.join("memory.events")).ok()?;
+    let event_count = |name: &str| {
+      events
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(event, _)| *event == name)
+        .and_then(|(_, count)| count.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+    };
+    if event_count("oom_kill") == 0 {
+      return None;
+    }
+    if let Some(mut workunit_store_handle) = get_workunit_store_handle() {
+      workunit_store_handle
+        .store
+        .increment_counter(Metric::LocalProcessMemoryLimitExceeded, 1);
+    }
+    // The `oom` event counts the times that the limit of this cgroup was reached: otherwise, the
+    // total memory budget of all processes was.
+    Some(match (self.memory_limit, self.memory_budget) {
+      (Some(limit), _) if event_count("oom") > 0 => MemoryLimitExceeded::Process(limit),
+      (_, Some(budget)) => MemoryLimitExceeded::Budget(budget),
+      _ => MemoryLimitExceeded::Available,
+    })
+  }
+
+  ///
+  /// Kills any processes which remain in this cgroup, and then removes it once they have exited.
+  ///
+  pub async fn remove(self) {
+    self.kill();
+    for _ in 0..REMOVAL_ATTEMPTS {
+      match std::fs::remove_dir(&self.path) {
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+          tokio::time::sleep(REMOVAL_INTERVAL).await
+        }
+        _ => break,
+      }
+    }
+  }
+
+  fn kill(&self) {
+    // NB: `cgroup.kill` is only available in Linux 5.14 and newer: otherwise, orphaned processes
+    // are left to run, as they are without cgroups.
+    let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+  }
+}
+
+impl Drop for ProcessCgroup {
+  fn drop(&mut self) {
+    if !self.path.exists() {
+      return;
+    }
+    // NB: If the process was cancelled, the removal may fail while its processes are exiting, in
+    // which case the (empty) cgroup is removed along with the root when the engine exits.
+    self.kill();
+    if let Err(e) = std::fs::remove_dir(&self.path) {
+      log::debug!("Failed to remove {:?}: {e}", self.path);
+    }
+  }
+}
+
+///
+/// Parses the (cgroup v2) path of a process from the content of its `/proc/<pid>/cgroup` file,
+/// relative to the root of the cgroup filesystem.
+///
+pub(crate) fn parse_own_cgroup(content: &str) -> Result<PathBuf, String> {
+  content
+    .lines()
+    .find_map(|line| line.strip_prefix("0::"))
+    .map(|path| PathBuf::from(path.trim_start_matches('/')))
+    .ok_or_else(|| {
+      "This process is not in a cgroup v2 hierarchy, which is required to apply resource limits \
+      to processes."
+        .to_owned()
+    })
+}
+
+fn create_cgroup(path: &Path) -> Result<(), String> {
+  match std::fs::create_dir(path) {
+    Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+      Err(format!("Failed to create cgroup {path:?}: {e}"))
+    }
+    _ => Ok(()),
+  }
+}
+
+fn enable_controllers(cgroup: &Path) -> std::io::Result<()> {
+  std::fs::write(cgroup.join("cgroup.subtree_control"), CONTROLLERS)
+}
+
+fn disable_controllers(cgroup: &Path) -> std::io::Result<()> {
+  std::fs::write(
+    cgroup.join("cgroup.subtree_control"),
+    CONTROLLERS.replace('+', "-"),
+  )
+}
@@ -0,0 +1,129 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use crate::cgroups::{
+  enter_leaf, leave_leaf, parse_own_cgroup, Cgroups, MemoryLimitExceeded, ResourceLimits,
+};
+
+// NB: These tests use a plain directory in place of the cgroup filesystem, whose files hold the
+// values which were written to them.
+
+fn read(path: PathBuf) -> String {
+  std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {path:?}: {e}"))
+}
+
+fn root(parent: &Path) -> PathBuf {
+  parent.join(format!("pants-processes-{}", std::process::id()))
+}
+
+#[test]
+fn parses_own_cgroup() {
+  assert_eq!(
+    parse_own_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n").unwrap(),
+    PathBuf::from("user.slice/user-1000.slice/session-2.scope")
+  );
+  // Processes in (only) cgroup v1 hierarchies list controllers for each hierarchy.
+  assert!(parse_own_cgroup("12:memory:/user.slice\n11:cpu,cpuacct:/user.slice\n").is_err());
+}
+
+#[test]
+fn applies_budgets_and_limits() {
+  let parent = TempDir::new().unwrap();
+  let cgroups = Cgroups::new_in(
+    parent.path(),
+    ResourceLimits {
+      memory_bytes: Some(1 << 30),
+      cpu_millis: Some(4000),
+      pids: None,
+    },
+  )
+  .unwrap();
+  let root = root(parent.path());
+  assert_eq!(read(root.join("memory.max")), "1073741824");
+  assert_eq!(read(root.join("cpu.max")), "400000 100000");
+  assert_eq!(read(root.join("pids.max")), "max");
+  assert_eq!(
+    read(root.join("cgroup.subtree_control")),
+    "+cpu +memory +pids"
+  );
+
+  let _cgroup = cgroups
+    .create(ResourceLimits {
+      memory_bytes: None,
+      cpu_millis: Some(500),
+      pids: Some(64),
+    })
+    .unwrap();
+  let process = root.join("process-0");
+  assert_eq!(read(process.join("memory.max")), "max");
+  assert_eq!(read(process.join("cpu.max")), "50000 100000");
+  assert_eq!(read(process.join("pids.max")), "64");
+}
+
+#[test]
+fn detects_memory_limit_kills() {
+  let parent = TempDir::new().unwrap();
+  let cgroups = Cgroups::new_in(
+    parent.path(),
+    ResourceLimits {
+      memory_bytes: Some(4096),
+      ..ResourceLimits::default()
+    },
+  )
+  .unwrap();
+  let cgroup = cgroups
+    .create(ResourceLimits {
+      memory_bytes: Some(1024),
+      ..ResourceLimits::default()
+    })
+    .unwrap();
+  let events = root(parent.path()).join("process-0").join("memory.events");
+
+  std::fs::write(&events, "low 0\nhigh 0\nmax 3\noom 0\noom_kill 0\n").unwrap();
+  assert_eq!(cgroup.memory_limit_exceeded(), None);
+
+  // The total budget was exceeded.
+  std::fs::write(&events, "low 0\nhigh 0\nmax 0\noom 0\noom_kill 1\n").unwrap();
+  assert_eq!(
+    cgroup.memory_limit_exceeded(),
+    Some(MemoryLimitExceeded::Budget(4096))
+  );
+
+  // The limit of the process was exceeded.
+  std::fs::write(&events, "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n").unwrap();
+  assert_eq!(
+    cgroup.memory_limit_exceeded(),
+    Some(MemoryLimitExceeded::Process(1024))
+  );
+  assert_eq!(
+    MemoryLimitExceeded::Process(1024).to_string(),
+    "memory limit of 1024 bytes"
+  );
+}
+
+#[test]
+fn leaves_leaf() {
+  let parent = TempDir::new().unwrap();
+  let leaf = parent.path().join("pants-engine");
+  std::fs::create_dir(&leaf).unwrap();
+
+  enter_leaf(parent.path(), &leaf).unwrap();
+  assert_eq!(read(leaf.join("cgroup.procs")), "0");
+  assert_eq!(
+    read(parent.path().join("cgroup.subtree_control")),
+    "+cpu +memory +pids"
+  );
+
+  // NB: Unlike a cgroup, the leaf cannot be removed while it holds the `cgroup.procs` file.
+  std::fs::remove_file(leaf.join("cgroup.procs")).unwrap();
+  leave_leaf(parent.path(), &leaf);
+  assert_eq!(read(parent.path().join("cgroup.procs")), "0");
+  assert_eq!(
+    read(parent.path().join("cgroup.subtree_control")),
+    "-cpu -memory -pids"
+  );
+  assert!(!leaf.exists());
+}
@@ -70,6 +70,10 @@ pub mod cache;
 #[cfg(test)]
 mod cache_tests;
 
+pub mod cgroups;
+#[cfg(test)]
+mod cgroups_tests;
+
 pub mod switched;
 
 pub mod children;
@@ -82,6 +86,7 @@ pub mod named_caches;
 
 extern crate uname;
 
+pub use crate::cgroups::{MemoryLimitExceeded, ResourceLimits};
 pub use crate::children::ManagedChild;
 pub use crate::named_caches::{CacheName, NamedCaches};
 
@@ -135,7 +140,7 @@ impl From<StoreError> for ProcessError {
   fn from(err: StoreError) -> Self {
     match err {
       StoreError::MissingDigest(s, d) => Self::MissingDigest(s, d),
-      StoreError::Unclassified(s) => Self::Unclassified(s),
+      StoreError::DeadlineExceeded(s) | StoreError::Unclassified(s) => Self::Unclassified(s),
     }
   }
 }
@@ -545,6 +550,13 @@ pub struct Process {
   /// started or finished).
   pub concurrency_available: usize,
 
+  ///
+  /// Limits on the resources which this process (including its descendants) may use. Currently
+  /// only applied by the `local::CommandRunner`, and only when it has been configured to use
+  /// cgroups.
+  ///
+  pub resource_limits: ResourceLimits,
+
   #[derivative(PartialEq = "ignore", Hash = "ignore")]
   pub description: String,
 
@@ -611,6 +623,7 @@ impl Process {
       jdk_home: None,
       execution_slot_variable: None,
       concurrency_available: 0,
+      resource_limits: ResourceLimits::default(),
       cache_scope: ProcessCacheScope::Successful,
       execution_environment: ProcessExecutionEnvironment {
         name: None,
@@ -743,6 +756,8 @@ pub struct ProcessResultMetadata {
   /// The RunId of the Session in which the `ProcessResultSource` was accurate. In further runs
   /// within the same process, the source of the process implicitly becomes memoization.
   pub source_run_id: RunId,
+  /// The memory limit which the process exceeded, if it was killed for exceeding one.
+  pub memory_limit_exceeded: Option<MemoryLimitExceeded>,
 }
 
 impl ProcessResultMetadata {
@@ -758,6 +773,7 @@ impl ProcessResultMetadata {
       source,
       environment,
       source_run_id,
+      memory_limit_exceeded: None,
     }
   }
 
@@ -1433,10 +1449,7 @@ pub fn extract_output_files(
         // of the output directory needed to construct the series of `Directory` protos needed
         // for the final merge of the output directories.
         let tree_digest: Digest = require_digest(dir.tree_digest.as_ref())?;
-        let directory_digest = store
-          .load_tree_from_remote(tree_digest)
-          .await?
-          .ok_or_else(|| format!("Tree with digest {tree_digest:?} was not in remote"))?;
+        let directory_digest = store.load_and_record_tree(tree_digest).await?;
 
         store
           .add_prefix(directory_digest, &RelativePath::new(dir.path)?)
@@ -34,8 +34,11 @@ use tokio::process::{Child, Command};
 use tokio::sync::RwLock;
 use tokio::time::{timeout, Duration};
 use tokio_util::codec::{BytesCodec, FramedRead};
-use workunit_store::{in_workunit, Level, Metric, RunningWorkunit};
+use workunit_store::{
+  expect_workunit_store_handle, in_workunit, Level, Metric, RunningWorkunit, WorkunitStoreHandle,
+};
 
+use crate::cgroups::{Cgroups, MemoryLimitExceeded, ProcessCgroup};
 use crate::{
   Context, FallibleProcessResultWithPlatform, NamedCaches, Process, ProcessError,
   ProcessResultMetadata, ProcessResultSource,
@@ -43,6 +46,10 @@ use crate::{
 
 pub const USER_EXECUTABLE_MODE: u32 = 0o100755;
 
+/// When output is streamed, partial lines which grow beyond this length are forwarded without
+/// waiting for the rest of the line.
+pub(crate) const MAX_STREAMED_LINE_BYTES: usize = 64 * 1024;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, strum_macros::EnumString)]
 #[strum(serialize_all = "snake_case")]
 pub enum KeepSandboxes {
@@ -59,6 +66,8 @@ pub struct CommandRunner {
   immutable_inputs: ImmutableInputs,
   keep_sandboxes: KeepSandboxes,
   spawn_lock: RwLock<()>,
+  cgroups: Option<Arc<Cgroups>>,
+  stream_output: bool,
 }
 
 impl CommandRunner {
@@ -78,9 +87,30 @@ impl CommandRunner {
       immutable_inputs,
       keep_sandboxes,
       spawn_lock: RwLock::new(()),
+      cgroups: None,
+      stream_output: false,
     }
   }
 
+  ///
+  /// Runs each process in a cgroup which applies the resource limits that it requested, beneath
+  /// the given cgroups (which apply the budgets for all processes).
+  ///
+  pub fn with_cgroups(mut self, cgroups: Arc<Cgroups>) -> Self {
+    self.cgroups = Some(cgroups);
+    self
+  }
+
+  ///
+  /// Logs the lines of output of each process (at the level of the process) as they are written,
+  /// rather than making them available only once it has completed. All output is still captured
+  /// in the result of the process.
+  ///
+  pub fn with_streamed_output(mut self) -> Self {
+    self.stream_output = true;
+    self
+  }
+
   async fn construct_output_snapshot(
     store: Store,
     posix_fs: Arc<fs::PosixFS>,
@@ -193,6 +223,14 @@ impl HermeticCommand {
     self
   }
 
+  fn cgroup(&mut self, cgroup: Arc<ProcessCgroup>) -> &mut HermeticCommand {
+    // SAFETY: Joining the cgroup only makes async-signal-safe calls.
+    unsafe {
+      self.inner.pre_exec(move || cgroup.join());
+    }
+    self
+  }
+
   fn spawn<O: Into<Stdio>, E: Into<Stdio>>(
     &mut self,
     stdout: O,
@@ -214,15 +252,125 @@ impl HermeticCommand {
 pub enum ChildOutput {
   Stdout(Bytes),
   Stderr(Bytes),
+  /// The process was killed because it exceeded a memory limit. Precedes the end of the stream.
+  MemoryLimitExceeded(MemoryLimitExceeded),
   Exit(ExitCode),
 }
 
+///
+/// Splits chunks of the output of a process into lines, so that they may be forwarded while it
+/// runs.
+///
+#[derive(Default)]
+pub(crate) struct LineBuffer {
+  buf: BytesMut,
+}
+
+impl LineBuffer {
+  ///
+  /// Adds a chunk of output, and returns the lines which it completed.
+  ///
+  pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+    self.buf.extend_from_slice(bytes);
+    let mut lines = Vec::new();
+    while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+      let line = self.buf.split_to(end + 1);
+      lines.push(decode_line(&line[..end]));
+    }
+    while self.buf.len() > MAX_STREAMED_LINE_BYTES {
+      // Avoid splitting a UTF-8 encoded character (of at most four bytes) across lines.
+      let mut end = MAX_STREAMED_LINE_BYTES;
+      while end > MAX_STREAMED_LINE_BYTES - 3 && is_utf8_continuation(self.buf[end]) {
+        end -= 1;
+      }
+      lines.push(decode_line(&self.buf.split_to(end)));
+    }
+    lines
+  }
+
+  ///
+  /// Returns the final (unterminated) line of output, if any.
+  ///
+  pub fn finish(&mut self) -> Option<String> {
+    if self.buf.is_empty() {
+      None
+    } else {
+      Some(decode_line(&self.buf.split()))
+    }
+  }
+}
+
+fn is_utf8_continuation(byte: u8) -> bool {
+  byte & 0b1100_0000 == 0b1000_0000
+}
+
+fn decode_line(bytes: &[u8]) -> String {
+  let line = String::from_utf8_lossy(bytes);
+  line.strip_suffix('\r').unwrap_or(&line).to_owned()
+}
+
+///
+/// Records the lines of the stdout and stderr of a process as they are written, as messages of its
+/// workunit (see `WorkunitStore::add_workunit_message`). Any unterminated lines are recorded when
+/// it is dropped.
+///
+struct OutputForwarder {
+  workunit_store_handle: WorkunitStoreHandle,
+  description: String,
+  level: log::Level,
+  stdout: LineBuffer,
+  stderr: LineBuffer,
+}
+
+impl OutputForwarder {
+  fn new(description: String, level: log::Level) -> Self {
+    Self {
+      workunit_store_handle: expect_workunit_store_handle(),
+      description,
+      level,
+      stdout: LineBuffer::default(),
+      stderr: LineBuffer::default(),
+    }
+  }
+
+  fn forward(&mut self, output: &ChildOutput) {
+    let lines = match output {
+      ChildOutput::Stdout(bytes) => self.stdout.push(bytes),
+      ChildOutput::Stderr(bytes) => self.stderr.push(bytes),
+      ChildOutput::MemoryLimitExceeded(_) | ChildOutput::Exit(_) => return,
+    };
+    for line in lines {
+      self.record(line);
+    }
+  }
+
+  fn record(&self, line: String) {
+    self.workunit_store_handle.store.add_workunit_message(
+      "process_output",
+      self.level,
+      self.workunit_store_handle.parent_id,
+      self.description.clone(),
+      line,
+    );
+  }
+}
+
+impl Drop for OutputForwarder {
+  fn drop(&mut self) {
+    let lines = [self.stdout.finish(), self.stderr.finish()];
+    for line in lines.into_iter().flatten() {
+      self.record(line);
+    }
+  }
+}
+
 ///
 /// Collect the outputs of a child process.
 ///
 pub async fn collect_child_outputs<'a, 'b>(
   stdout: &'a mut BytesMut,
   stderr: &'a mut BytesMut,
+  memory_limit_exceeded: &'a mut Option<MemoryLimitExceeded>,
   mut stream: BoxStream<'b, Result<ChildOutput, String>>,
 ) -> Result<i32, String> {
   let mut exit_code = 1;
@@ -231,6 +379,7 @@ pub async fn collect_child_outputs<'a, 'b>(
     match child_output_res? {
       ChildOutput::Stdout(bytes) => stdout.extend_from_slice(&bytes),
       ChildOutput::Stderr(bytes) => stderr.extend_from_slice(&bytes),
+      ChildOutput::MemoryLimitExceeded(limit) => *memory_limit_exceeded = Some(limit),
       ChildOutput::Exit(code) => exit_code = code.0,
     };
   }
@@ -350,6 +499,13 @@ impl CapturedWorkdir for CommandRunner {
     };
     let mut command = HermeticCommand::new(&req.argv[0]);
     command.args(&req.argv[1..]).current_dir(cwd).envs(&req.env);
+    let cgroup = if let Some(cgroups) = &self.cgroups {
+      let cgroup = Arc::new(cgroups.create(req.resource_limits)?);
+      command.cgroup(cgroup.clone());
+      Some(cgroup)
+    } else {
+      None
+    };
 
     // See the documentation of the `CapturedWorkdir::run_in_workdir` method, but `exclusive_spawn`
     // indicates the binary we're spawning was written out by the current thread, and, as such,
@@ -436,9 +592,35 @@ impl CapturedWorkdir for CommandRunner {
     .boxed();
     let result_stream =
       futures::stream::select_all(vec![stdout_stream, stderr_stream, exit_stream]);
+    let result_stream = if self.stream_output {
+      let mut forwarder = OutputForwarder::new(req.description.clone(), req.level);
+      result_stream
+        .inspect(move |output| {
+          if let Ok(output) = output {
+            forwarder.forward(output);
+          }
+        })
+        .boxed()
+    } else {
+      result_stream.boxed()
+    };
+    // Once all output has been consumed, report whether the process was killed for exceeding a
+    // memory limit. NB: This is not written to stderr, which would change the digest of the result.
+    let cgroup_stream = async move {
+      let cgroup = match cgroup.and_then(|cgroup| Arc::try_unwrap(cgroup).ok()) {
+        Some(cgroup) => cgroup,
+        None => return None,
+      };
+      let limit = cgroup.memory_limit_exceeded();
+      cgroup.remove().await;
+      limit.map(|limit| Ok::<_, std::io::Error>(ChildOutput::MemoryLimitExceeded(limit)))
+    }
+    .into_stream()
+    .filter_map(futures::future::ready);
 
     Ok(
       result_stream
+        .chain(cgroup_stream)
         .map_err(|e| format!("Failed to consume process outputs: {e:?}"))
         .boxed(),
     )
@@ -462,6 +644,7 @@ pub trait CapturedWorkdir {
     let start_time = Instant::now();
     let mut stdout = BytesMut::with_capacity(8192);
     let mut stderr = BytesMut::with_capacity(8192);
+    let mut memory_limit_exceeded = None;
 
     // Spawn the process.
     // NB: We fully buffer the `Stream` into the stdout/stderr buffers, but the idea going forward
@@ -472,6 +655,7 @@ pub trait CapturedWorkdir {
       let exit_code_future = collect_child_outputs(
         &mut stdout,
         &mut stderr,
+        &mut memory_limit_exceeded,
         self
           .run_in_workdir(
             &context,
@@ -512,22 +696,26 @@ pub trait CapturedWorkdir {
           },
         )?,
       );
-      CommandRunner::construct_output_snapshot(
-        store.clone(),
-        posix_fs,
-        req.output_files,
-        req.output_directories,
+      store::scope_task_origin(
+        store::Origin::Process(req.description.clone()),
+        CommandRunner::construct_output_snapshot(
+          store.clone(),
+          posix_fs,
+          req.output_files,
+          req.output_directories,
+        ),
       )
       .await?
     };
 
     let elapsed = start_time.elapsed();
-    let result_metadata = ProcessResultMetadata::new(
+    let mut result_metadata = ProcessResultMetadata::new(
       Some(elapsed.into()),
       ProcessResultSource::Ran,
       req.execution_environment,
       context.run_id,
     );
+    result_metadata.memory_limit_exceeded = memory_limit_exceeded;
 
     match exit_code_result {
       Ok(exit_code) => {
@@ -15,7 +15,7 @@ use store::{ImmutableInputs, Store};
 use testutil::data::{TestData, TestDirectory};
 use testutil::path::{find_bash, which};
 use testutil::{owned_string_vec, relative_paths};
-use workunit_store::{RunningWorkunit, WorkunitStore};
+use workunit_store::{Level, RunningWorkunit, WorkunitStore};
 
 use crate::{
   local, local::KeepSandboxes, CacheName, CommandRunner as CommandRunnerTrait, Context,
@@ -573,6 +573,110 @@ async fn timeout() {
   assert_that(&stderr).contains("sleepy-cat");
 }
 
+#[test]
+fn line_buffer() {
+  let mut buffer = local::LineBuffer::default();
+  assert!(buffer.push(b"Calcu").is_empty());
+  assert_eq!(
+    buffer.push(b"lating...\r\nEuropean\n\nBurm"),
+    vec!["Calculating...", "European", ""]
+  );
+  assert_eq!(buffer.finish(), Some("Burm".to_owned()));
+  assert_eq!(buffer.finish(), None);
+
+  // Long lines are split, but not within a character.
+  let prefix = "a".repeat(local::MAX_STREAMED_LINE_BYTES - 1);
+  assert!(buffer.push(prefix.as_bytes()).is_empty());
+  assert_eq!(buffer.push("éb".as_bytes()), vec![prefix]);
+  assert_eq!(buffer.finish(), Some("éb".to_owned()));
+}
+
+///
+/// The lines of output which have been recorded as messages of the workunits of processes, as
+/// (description, line) pairs.
+///
+fn streamed_lines(workunit_store: &WorkunitStore, lines: &mut Vec<(String, String)>) {
+  let (_, completed) = workunit_store.latest_workunits(Level::Trace);
+  lines.extend(
+    completed
+      .into_iter()
+      .filter(|workunit| workunit.name == "process_output")
+      .filter_map(|workunit| {
+        let metadata = workunit.metadata?;
+        Some((metadata.desc?, metadata.message?))
+      }),
+  );
+}
+
+#[tokio::test]
+async fn streamed_output_is_captured() {
+  let (workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let work_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let (_caches_dir, named_caches, immutable_inputs) =
+    named_caches_and_immutable_inputs(store.clone());
+  let runner = local::CommandRunner::new(
+    store.clone(),
+    executor,
+    work_dir.path().to_owned(),
+    named_caches,
+    immutable_inputs,
+    KeepSandboxes::Never,
+  )
+  .with_streamed_output();
+
+  // The process waits for its first lines to be recorded before it exits, which the test signals
+  // by creating a file.
+  let logged_dir = TempDir::new().unwrap();
+  let logged = logged_dir.path().join("logged");
+  let mut process = Process::new(vec![
+    find_bash(),
+    "-c".to_owned(),
+    format!(
+      "echo foo; echo >&2 bar; \
+      for _ in {{1..500}}; do [ -e {0} ] && break; /bin/sleep 0.01; done; \
+      [ -e {0} ] || exit 1; echo -n baz",
+      str::from_utf8(&bash::escape(&logged)).unwrap()
+    ),
+  ]);
+  process.description = "streamed".to_owned();
+  let line = |line: &str| ("streamed".to_owned(), line.to_owned());
+  let mut lines = Vec::new();
+  let signal_logged = async {
+    for _ in 0..500 {
+      streamed_lines(&workunit_store, &mut lines);
+      if lines.contains(&line("foo")) && lines.contains(&line("bar")) {
+        std::fs::write(&logged, "").unwrap();
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  };
+
+  let (result, ()) = tokio::join!(
+    runner.run(Context::default(), &mut workunit, process),
+    signal_logged
+  );
+  let result = result.unwrap();
+  // The unterminated final line is recorded once the process exits.
+  streamed_lines(&workunit_store, &mut lines);
+  assert!(lines.contains(&line("baz")));
+
+  let stdout = store
+    .load_file_bytes_with(result.stdout_digest, |bytes| bytes.to_vec())
+    .await
+    .unwrap();
+  let stderr = store
+    .load_file_bytes_with(result.stderr_digest, |bytes| bytes.to_vec())
+    .await
+    .unwrap();
+  assert_eq!(stdout, b"foo\nbaz");
+  assert_eq!(stderr, b"bar\n");
+  assert_eq!(result.exit_code, 0);
+}
+
 #[tokio::test]
 async fn working_directory() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();
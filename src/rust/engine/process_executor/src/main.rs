@@ -35,10 +35,11 @@ use std::time::Duration;
 
 use clap::StructOpt;
 use fs::{DirectoryDigest, Permissions, RelativePath};
+use grpc_util::retry::RetryPolicy;
 use hashing::{Digest, Fingerprint};
 use process_execution::{
   local::KeepSandboxes, CacheContentBehavior, Context, InputDigests, NamedCaches, Platform,
-  ProcessCacheScope, ProcessExecutionEnvironment, ProcessExecutionStrategy,
+  ProcessCacheScope, ProcessExecutionEnvironment, ProcessExecutionStrategy, ResourceLimits,
 };
 use prost::Message;
 use protos::gen::build::bazel::remote::execution::v2::{Action, Command};
@@ -256,9 +257,13 @@ async fn main() {
         args.remote_instance_name.clone(),
         grpc_util::tls::Config::new_without_mtls(root_ca_certs),
         headers,
+        None,
         args.upload_chunk_bytes,
         Duration::from_secs(30),
-        args.store_rpc_retries,
+        RetryPolicy {
+          attempts: args.store_rpc_retries + 1,
+          ..RetryPolicy::default()
+        },
         args.store_rpc_concurrency,
         None,
         args.store_batch_api_size_limit,
@@ -284,9 +289,11 @@ async fn main() {
 
   let runner: Box<dyn process_execution::CommandRunner> = match args.server {
     Some(address) => {
-      let root_ca_certs = args
-        .execution_root_ca_cert_file
-        .map(|path| std::fs::read(path).expect("Error reading root CA certs file"));
+      let tls_config = grpc_util::tls::Config::new_without_mtls(
+        args
+          .execution_root_ca_cert_file
+          .map(|path| std::fs::read(path).expect("Error reading root CA certs file")),
+      );
 
       if let Some(oauth_path) = args.execution_oauth_bearer_token_path {
         let token =
@@ -302,12 +309,13 @@ async fn main() {
         process_metadata.instance_name.clone(),
         process_metadata.cache_key_gen_version.clone(),
         None,
-        root_ca_certs.clone(),
+        tls_config.clone(),
         headers.clone(),
+        None,
         store.clone(),
         executor.clone(),
         Duration::from_secs(args.overall_deadline_secs),
-        Duration::from_millis(100),
+        remote::remote::CommandRunner::default_retry_policy(),
         args.execution_rpc_concurrency,
         None,
       )
@@ -322,8 +330,9 @@ async fn main() {
             executor,
             store.clone(),
             &address,
-            root_ca_certs,
+            tls_config,
             headers,
+            None,
             true,
             true,
             remote::remote_cache::RemoteCacheWarningsBehavior::Backoff,
@@ -499,6 +508,7 @@ async fn make_request_from_flat_args(
     jdk_home: args.command.jdk.clone(),
     execution_slot_variable: None,
     concurrency_available: args.command.concurrency_available.unwrap_or(0),
+    resource_limits: ResourceLimits::default(),
     cache_scope: ProcessCacheScope::Always,
     execution_environment,
     remote_cache_speculation_delay: Duration::from_millis(0),
@@ -584,6 +594,7 @@ async fn extract_request_from_action_digest(
     }),
     execution_slot_variable: None,
     concurrency_available: 0,
+    resource_limits: ResourceLimits::default(),
     description: "".to_string(),
     level: Level::Error,
     append_only_caches: BTreeMap::new(),
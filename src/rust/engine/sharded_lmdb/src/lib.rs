@@ -25,6 +25,7 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
@@ -34,7 +35,9 @@ use std::sync::Arc;
 use std::time::{self, Duration};
 
 use bytes::{BufMut, Bytes};
-use hashing::{sync_verified_copy, AgedFingerprint, Digest, Fingerprint, FINGERPRINT_SIZE};
+use hashing::{
+  sync_verified_copy_with, AgedFingerprint, Digest, DigestFunction, Fingerprint, FINGERPRINT_SIZE,
+};
 use lmdb::{
   self, Cursor, Database, DatabaseFlags, Environment, EnvironmentCopyFlags, EnvironmentFlags,
   RwTransaction, Transaction, WriteFlags,
@@ -67,6 +70,10 @@ impl VersionedFingerprint {
     VersionedFingerprint(buf)
   }
 
+  pub fn get_version(&self) -> u8 {
+    self.0[FINGERPRINT_SIZE]
+  }
+
   pub fn get_fingerprint(&self) -> Fingerprint {
     let mut buf = [0; FINGERPRINT_SIZE];
     buf.copy_from_slice(&self.0[0..FINGERPRINT_SIZE]);
@@ -104,6 +111,29 @@ impl AsRef<[u8]> for VersionedFingerprint {
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 struct EnvironmentId(u8);
 
+///
+/// A function which is called with each Fingerprint that is added to or removed from the content
+/// database of a `ShardedLmdb`, after the change has been committed.
+///
+#[derive(Clone)]
+pub struct ContentObserver(Arc<dyn Fn(Fingerprint) + Send + Sync>);
+
+impl ContentObserver {
+  pub fn new(f: impl Fn(Fingerprint) + Send + Sync + 'static) -> Self {
+    Self(Arc::new(f))
+  }
+
+  pub fn notify(&self, fingerprint: Fingerprint) {
+    (self.0)(fingerprint)
+  }
+}
+
+impl Debug for ContentObserver {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("ContentObserver")
+  }
+}
+
 // Each LMDB directory can have at most one concurrent writer.
 // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
 // fingerprint being stored, so that we can write to them in parallel.
@@ -119,6 +149,14 @@ pub struct ShardedLmdb {
   lease_time: Duration,
   shard_count: u8,
   shard_fingerprint_mask: u8,
+  // See `Self::with_value_checksums`.
+  value_checksums: bool,
+  // See `Self::with_compression_level`.
+  compression_level: Option<i32>,
+  // See `Self::with_content_observer`.
+  content_observer: Option<ContentObserver>,
+  // See `Self::with_digest_function`.
+  digest_function: DigestFunction,
 }
 
 impl ShardedLmdb {
@@ -128,7 +166,22 @@ impl ShardedLmdb {
   // we actually store in the database. This way, data stored with a version
   // of pants on one schema version will not conflict with data stored
   // with a different version of pants on a different schema version.
-  pub const SCHEMA_VERSION: u8 = 2;
+  pub const SCHEMA_VERSION: u8 = 3;
+
+  // Every value begins with a header byte which describes how its content is encoded, so that
+  // values are decoded the same way regardless of whether compression or checksums are currently
+  // enabled.
+  const HEADER_SIZE: usize = 1;
+  // If this bit of the header byte is set, the value is followed by an xxh3 checksum of the header
+  // and the encoded content. The remaining bits describe how the content is encoded.
+  const CHECKSUMMED_HEADER_FLAG: u8 = 0x80;
+  const CHECKSUM_SIZE: usize = 8;
+  // The header byte of a value whose content follows it uncompressed.
+  const UNCOMPRESSED_HEADER: u8 = 0;
+  // The header byte of a value whose content follows it as a zstd frame, preceded by the length of
+  // the uncompressed content as a little-endian u64.
+  const ZSTD_HEADER: u8 = 1;
+  const ZSTD_HEADER_SIZE: usize = 1 + 8;
 
   // max_size is the maximum size the databases together will be allowed to grow to.
   // When calling this function, we will attempt to allocate that much virtual (not resident) memory
@@ -141,6 +194,42 @@ impl ShardedLmdb {
     executor: task_executor::Executor,
     lease_time: Duration,
     shard_count: u8,
+  ) -> Result<ShardedLmdb, String> {
+    Self::open(
+      root_path,
+      max_size,
+      executor,
+      lease_time,
+      shard_count,
+      false,
+    )
+  }
+
+  ///
+  /// As `Self::new`, but opens existing databases without creating (or ever writing to) any of
+  /// their files, so that they may live on a read-only filesystem. Every write transaction against
+  /// the returned instance will fail.
+  ///
+  /// NB: Because the databases are opened without locking, they must not be written to by any
+  /// other process while they are open.
+  ///
+  pub fn new_read_only(
+    root_path: PathBuf,
+    max_size: usize,
+    executor: task_executor::Executor,
+    lease_time: Duration,
+    shard_count: u8,
+  ) -> Result<ShardedLmdb, String> {
+    Self::open(root_path, max_size, executor, lease_time, shard_count, true)
+  }
+
+  fn open(
+    root_path: PathBuf,
+    max_size: usize,
+    executor: task_executor::Executor,
+    lease_time: Duration,
+    shard_count: u8,
+    read_only: bool,
   ) -> Result<ShardedLmdb, String> {
     if shard_count.count_ones() != 1 {
       return Err(format!(
@@ -167,14 +256,19 @@ impl ShardedLmdb {
     let mut lmdbs = HashMap::new();
 
     for (env, dir, environment_id) in
-      ShardedLmdb::envs(&root_path, max_size_per_shard, shard_count)?
+      ShardedLmdb::envs(&root_path, max_size_per_shard, shard_count, read_only)?
     {
-      let content_database = env
-        .create_db(Some("content-versioned"), DatabaseFlags::empty())
+      let open_db = |name: &str| {
+        if read_only {
+          env.open_db(Some(name))
+        } else {
+          env.create_db(Some(name), DatabaseFlags::empty())
+        }
+      };
+      let content_database = open_db("content-versioned")
         .map_err(|e| format!("Error creating/opening content database at {dir:?}: {e}"))?;
 
-      let lease_database = env
-        .create_db(Some("leases-versioned"), DatabaseFlags::empty())
+      let lease_database = open_db("leases-versioned")
         .map_err(|e| format!("Error creating/opening content database at {dir:?}: {e}"))?;
 
       lmdbs.insert(
@@ -197,9 +291,191 @@ impl ShardedLmdb {
       lease_time,
       shard_count,
       shard_fingerprint_mask,
+      value_checksums: false,
+      compression_level: None,
+      content_observer: None,
+      digest_function: DigestFunction::Sha256,
     })
   }
 
+  ///
+  /// If enabled, each value is stored followed by an xxh3 checksum of its content, which is
+  /// verified when it is loaded. This detects corruption of a value which preserves its length.
+  ///
+  /// Whether a value has a checksum is recorded in its header, so toggling this setting only
+  /// affects values which are stored afterwards.
+  ///
+  pub fn with_value_checksums(mut self, value_checksums: bool) -> Self {
+    self.value_checksums = value_checksums;
+    self
+  }
+
+  ///
+  /// If set, each value is compressed with zstd at the given level when it is stored. Values which
+  /// do not shrink when compressed are stored uncompressed.
+  ///
+  /// Every value records how it was encoded, and is decoded accordingly when it is loaded, so
+  /// toggling this setting only affects values which are stored afterwards. Sizes reported for
+  /// stored values are always those of their uncompressed content.
+  ///
+  pub fn with_compression_level(mut self, compression_level: Option<i32>) -> Self {
+    self.compression_level = compression_level;
+    self
+  }
+
+  ///
+  /// Sets a function to be notified of each Fingerprint which is added to or removed from the
+  /// content database by this instance. Storing a Fingerprint which is already present does not
+  /// notify the observer.
+  ///
+  pub fn with_content_observer(mut self, content_observer: ContentObserver) -> Self {
+    self.content_observer = Some(content_observer);
+    self
+  }
+
+  ///
+  /// Sets the function which is used to verify the content passed to `Self::store` against its
+  /// expected digest. Defaults to SHA-256.
+  ///
+  pub fn with_digest_function(mut self, digest_function: DigestFunction) -> Self {
+    self.digest_function = digest_function;
+    self
+  }
+
+  fn notify_content_observer(&self, fingerprints: impl IntoIterator<Item = Fingerprint>) {
+    if let Some(content_observer) = &self.content_observer {
+      for fingerprint in fingerprints {
+        content_observer.notify(fingerprint);
+      }
+    }
+  }
+
+  fn checksum(content: &[u8]) -> [u8; Self::CHECKSUM_SIZE] {
+    xxhash_rust::xxh3::xxh3_64(content).to_le_bytes()
+  }
+
+  ///
+  /// Splits the given stored value into its header and encoded content, and its checksum (if its
+  /// header says that it has one).
+  ///
+  fn split_checksum(value: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match value.first() {
+      Some(header)
+        if header & Self::CHECKSUMMED_HEADER_FLAG != 0
+          && value.len() >= Self::HEADER_SIZE + Self::CHECKSUM_SIZE =>
+      {
+        let (encoded, checksum) = value.split_at(value.len() - Self::CHECKSUM_SIZE);
+        (encoded, Some(checksum))
+      }
+      _ => (value, None),
+    }
+  }
+
+  ///
+  /// Returns the length of the content of a value stored under the given key.
+  ///
+  /// Values which were stored by an older schema version cannot be decoded, and so report the
+  /// length of the stored value: which is what removing them frees.
+  ///
+  fn content_len(key: &VersionedFingerprint, value: &[u8]) -> usize {
+    if key.get_version() != Self::SCHEMA_VERSION {
+      return value.len();
+    }
+    let (encoded, _) = Self::split_checksum(value);
+    match encoded
+      .first()
+      .map(|header| header & !Self::CHECKSUMMED_HEADER_FLAG)
+    {
+      Some(Self::ZSTD_HEADER) if encoded.len() >= Self::ZSTD_HEADER_SIZE => {
+        let mut array = [0_u8; 8];
+        array.copy_from_slice(&encoded[1..Self::ZSTD_HEADER_SIZE]);
+        u64::from_le_bytes(array) as usize
+      }
+      _ => encoded.len().saturating_sub(Self::HEADER_SIZE),
+    }
+  }
+
+  ///
+  /// Encodes the given content to be stored under the current schema version: prefixing it with a
+  /// header which describes whether it was compressed (if enabled), and then following it with a
+  /// checksum (if enabled).
+  ///
+  fn encoded_value(&self, content: Bytes) -> Result<Bytes, String> {
+    let mut value =
+      Vec::with_capacity(content.len() + Self::ZSTD_HEADER_SIZE + Self::CHECKSUM_SIZE);
+    if let Some(compression_level) = self.compression_level {
+      let compressed = zstd::bulk::compress(&content, compression_level)
+        .map_err(|e| format!("Failed to compress value: {e}"))?;
+      if compressed.len() + Self::ZSTD_HEADER_SIZE < content.len() + 1 {
+        value.push(Self::ZSTD_HEADER);
+        value.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        value.extend_from_slice(&compressed);
+      } else {
+        value.push(Self::UNCOMPRESSED_HEADER);
+        value.extend_from_slice(&content);
+      }
+    } else {
+      value.push(Self::UNCOMPRESSED_HEADER);
+      value.extend_from_slice(&content);
+    }
+    if self.value_checksums {
+      value[0] |= Self::CHECKSUMMED_HEADER_FLAG;
+      let checksum = Self::checksum(&value);
+      value.extend_from_slice(&checksum);
+    }
+    Ok(Bytes::from(value))
+  }
+
+  ///
+  /// Returns the content of the given stored value, after verifying its checksum and decompressing
+  /// it (if its header says that it has a checksum, or was compressed).
+  ///
+  fn decoded_content<'a>(
+    key: &VersionedFingerprint,
+    value: &'a [u8],
+  ) -> Result<Cow<'a, [u8]>, String> {
+    let encoded = Self::verified_content(key, value)?;
+    match encoded
+      .split_first()
+      .map(|(header, content)| (header & !Self::CHECKSUMMED_HEADER_FLAG, content))
+    {
+      Some((Self::UNCOMPRESSED_HEADER, content)) => Ok(Cow::Borrowed(content)),
+      Some((Self::ZSTD_HEADER, _)) if encoded.len() >= Self::ZSTD_HEADER_SIZE => {
+        let content_len = Self::content_len(key, value);
+        zstd::bulk::decompress(&encoded[Self::ZSTD_HEADER_SIZE..], content_len)
+          .map(Cow::Owned)
+          .map_err(|e| {
+            format!(
+              "Failed to decompress value for versioned key {:?}: {e}: probable lmdb corruption.",
+              key.to_hex()
+            )
+          })
+      }
+      _ => Err(format!(
+        "Value for versioned key {:?} has an invalid header: probable lmdb corruption.",
+        key.to_hex()
+      )),
+    }
+  }
+
+  ///
+  /// Returns the header and encoded content of the given stored value, after verifying its
+  /// checksum (if it has one).
+  ///
+  fn verified_content<'a>(key: &VersionedFingerprint, value: &'a [u8]) -> Result<&'a [u8], String> {
+    let checksummed = value
+      .first()
+      .map_or(false, |header| header & Self::CHECKSUMMED_HEADER_FLAG != 0);
+    match Self::split_checksum(value) {
+      (encoded, Some(checksum)) if checksum == Self::checksum(encoded) => Ok(encoded),
+      (encoded, None) if !checksummed => Ok(encoded),
+      _ => Err(format!(
+        "Checksum mismatch for versioned key {:?}: probable lmdb corruption.",
+        key.to_hex()
+      )),
+    }
+  }
+
   ///
   /// Return the left shift value that will place the relevant portion of a byte (for the given
   /// shard count, which is asserted in the constructor to be a power of two) into the high order
@@ -214,17 +490,20 @@ impl ShardedLmdb {
     root_path: &Path,
     max_size_per_shard: usize,
     shard_count: u8,
+    read_only: bool,
   ) -> Result<Vec<(Environment, PathBuf, EnvironmentId)>, String> {
     let shard_shift = Self::shard_shift(shard_count);
 
     let mut envs = Vec::with_capacity(shard_count as usize);
     for b in 0..shard_count {
       let dir = root_path.join(format!("{b:x}"));
-      fs::safe_create_dir_all(&dir)
-        .map_err(|err| format!("Error making directory for store at {dir:?}: {err:?}"))?;
+      if !read_only {
+        fs::safe_create_dir_all(&dir)
+          .map_err(|err| format!("Error making directory for store at {dir:?}: {err:?}"))?;
+      }
       let fingerprint_prefix = b.rotate_left(shard_shift as u32);
       envs.push((
-        ShardedLmdb::make_env(&dir, max_size_per_shard)?,
+        ShardedLmdb::make_env(&dir, max_size_per_shard, read_only)?,
         dir,
         EnvironmentId(fingerprint_prefix),
       ));
@@ -232,7 +511,20 @@ impl ShardedLmdb {
     Ok(envs)
   }
 
-  fn make_env(dir: &Path, max_size_per_shard: usize) -> Result<Environment, String> {
+  fn make_env(
+    dir: &Path,
+    max_size_per_shard: usize,
+    read_only: bool,
+  ) -> Result<Environment, String> {
+    let mut flags = EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS;
+    if read_only {
+      // READ_ONLY and NO_LOCK
+      // =====================
+      //
+      // Open an existing environment without writing to it: not even to its lock file, which
+      // may not be writable. See `Self::new_read_only`.
+      flags |= EnvironmentFlags::READ_ONLY | EnvironmentFlags::NO_LOCK;
+    }
     Environment::new()
       // NO_SYNC
       // =======
@@ -264,7 +556,7 @@ impl ShardedLmdb {
       // The only down-side is that you need to make sure that any individual OS thread must
       // not try to perform multiple write transactions concurrently. Fortunately, this
       // property holds for us.
-      .set_flags(EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS)
+      .set_flags(flags)
       // 2 DBs; one for file contents, one for leases.
       .set_max_dbs(2)
       .set_map_size(max_size_per_shard)
@@ -299,34 +591,108 @@ impl ShardedLmdb {
       .executor
       .spawn_blocking(
         move || {
-          let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
           let (env, db, lease_database) = store.get(&fingerprint);
-          let del_res = env.begin_rw_txn().and_then(|mut txn| {
-            txn.del(db, &effective_key, None)?;
-            txn
-              .del(lease_database, &effective_key, None)
-              .or_else(|err| match err {
-                lmdb::Error::NotFound => Ok(()),
-                err => Err(err),
-              })?;
-            txn.commit()
-          });
-
-          match del_res {
-            Ok(()) => Ok(true),
-            Err(lmdb::Error::NotFound) => Ok(false),
-            Err(err) => Err(format!(
-              "Error removing versioned key {:?}: {}",
-              effective_key.to_hex(),
-              err
-            )),
+          let removed = env
+            .begin_rw_txn()
+            .and_then(|mut txn| {
+              let removed = Self::del_all_versions(&mut txn, db, fingerprint)?;
+              Self::del_all_versions(&mut txn, lease_database, fingerprint)?;
+              txn.commit()?;
+              Ok(removed)
+            })
+            .map_err(|e| format!("Error removing fingerprint {:?}: {e}", fingerprint.to_hex()))?;
+          if removed {
+            store.notify_content_observer([fingerprint]);
           }
+          Ok(removed)
         },
         |e| Err(format!("`remove` task failed: {e}")),
       )
       .await
   }
 
+  ///
+  /// Removes the given fingerprints in a single write transaction per shard, returning the
+  /// fingerprints which were present.
+  ///
+  pub async fn remove_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashSet<Fingerprint>, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          // Group the fingerprints by the Environment that they will be removed from.
+          let mut fingerprints_by_env = HashMap::new();
+          for fingerprint in fingerprints {
+            let (env_id, _, env, db, lease_database) = store.get_raw(&fingerprint.0);
+            let (_, _, _, batch) = fingerprints_by_env
+              .entry(*env_id)
+              .or_insert_with(|| (env.clone(), *db, *lease_database, vec![]));
+            batch.push(fingerprint);
+          }
+
+          let mut removed = HashSet::new();
+          for (_, (env, db, lease_database, batch)) in fingerprints_by_env {
+            let mut removed_from_env = vec![];
+            env
+              .begin_rw_txn()
+              .and_then(|mut txn| {
+                for fingerprint in &batch {
+                  if Self::del_all_versions(&mut txn, db, *fingerprint)? {
+                    removed_from_env.push(*fingerprint);
+                    Self::del_all_versions(&mut txn, lease_database, *fingerprint)?;
+                  }
+                }
+                txn.commit()
+              })
+              .map_err(|e| {
+                format!(
+                  "Error removing fingerprints {:?}: {e}",
+                  batch.iter().map(|key| key.to_hex()).collect::<Vec<_>>()
+                )
+              })?;
+            store.notify_content_observer(removed_from_env.iter().copied());
+            removed.extend(removed_from_env);
+          }
+          Ok(removed)
+        },
+        |e| Err(format!("`remove_batch` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Deletes the entries for all versions of the given Fingerprint from the given database,
+  /// returning true if there were any. Entries which were stored under an older schema version are
+  /// never loaded, but are listed by `Self::all_fingerprints`, and so must be removable.
+  ///
+  fn del_all_versions(
+    txn: &mut RwTransaction<'_>,
+    database: Database,
+    fingerprint: Fingerprint,
+  ) -> Result<bool, lmdb::Error> {
+    let keys = {
+      let mut cursor = txn.open_ro_cursor(database)?;
+      let mut keys = Vec::new();
+      // Keys are sorted, so all versions of a Fingerprint are adjacent.
+      for key_res in cursor.iter_from(&fingerprint.0[..]) {
+        let (key, _) = key_res?;
+        if !key.starts_with(&fingerprint.0[..]) {
+          break;
+        }
+        keys.push(key.to_vec());
+      }
+      keys
+    };
+    for key in &keys {
+      txn.del(database, key, None)?;
+    }
+    Ok(!keys.is_empty())
+  }
+
   ///
   /// Singular form of `Self::exists_batch`. When checking the existence of more than one item,
   /// prefer `Self::exists_batch`.
@@ -404,16 +770,125 @@ impl ShardedLmdb {
   /// Returns all fingerprints and their ages.
   ///
   pub async fn all_fingerprints(&self) -> Result<Vec<AgedFingerprint>, String> {
+    self.aged_fingerprints_in(self.all_lmdbs()).await
+  }
+
+  ///
+  /// Returns the ids of all shards, which are the masked high order bits of the fingerprints that
+  /// they contain.
+  ///
+  pub fn shard_ids(&self) -> Vec<u8> {
+    let mut shard_ids = self.lmdbs.keys().map(|id| id.0).collect::<Vec<_>>();
+    shard_ids.sort_unstable();
+    shard_ids
+  }
+
+  ///
+  /// Returns all fingerprints and their ages in the given shard. See `Self::shard_ids`.
+  ///
+  pub async fn all_fingerprints_in_shard(
+    &self,
+    shard_id: u8,
+  ) -> Result<Vec<AgedFingerprint>, String> {
+    let (_, _, env, db1, db2) = self
+      .lmdbs
+      .get(&EnvironmentId(shard_id))
+      .ok_or_else(|| format!("No such LMDB shard: {shard_id}"))?;
+    self
+      .aged_fingerprints_in(vec![(env.clone(), *db1, *db2)])
+      .await
+  }
+
+  ///
+  /// As `Self::all_fingerprints`, but blocking.
+  ///
+  /// NB: This method is blocking, and should be called on a blocking thread.
+  ///
+  pub fn all_fingerprints_blocking(&self) -> Result<Vec<AgedFingerprint>, String> {
+    Self::aged_fingerprints_in_blocking(&self.all_lmdbs())
+  }
+
+  async fn aged_fingerprints_in(
+    &self,
+    lmdbs: Vec<(Arc<Environment>, Database, Database)>,
+  ) -> Result<Vec<AgedFingerprint>, String> {
+    self
+      .executor
+      .spawn_blocking(
+        move || Self::aged_fingerprints_in_blocking(&lmdbs),
+        |e| Err(format!("`all_fingerprints` task failed: {e}")),
+      )
+      .await
+  }
+
+  fn aged_fingerprints_in_blocking(
+    lmdbs: &[(Arc<Environment>, Database, Database)],
+  ) -> Result<Vec<AgedFingerprint>, String> {
+    let mut fingerprints = Vec::new();
+    for (env, database, lease_database) in lmdbs {
+      let txn = env
+        .begin_ro_txn()
+        .map_err(|err| format!("Error beginning transaction to garbage collect: {err}"))?;
+      let mut cursor = txn
+        .open_ro_cursor(*database)
+        .map_err(|err| format!("Failed to open lmdb read cursor: {err}"))?;
+      for key_res in cursor.iter() {
+        let (key, bytes) =
+          key_res.map_err(|err| format!("Failed to advance lmdb read cursor: {err}"))?;
+
+        // Random access into the lease_database is slower than iterating, but hopefully garbage
+        // collection is rare enough that we can get away with this, rather than do two passes
+        // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
+        // when we delete from lmdb to track how much we've freed).
+        let lease_until_unix_timestamp = txn
+          .get(*lease_database, &key)
+          .map(|b| {
+            let mut array = [0_u8; 8];
+            array.copy_from_slice(b);
+            u64::from_le_bytes(array)
+          })
+          .unwrap_or_else(|e| match e {
+            lmdb::Error::NotFound => 0,
+            e => panic!("Error reading lease, probable lmdb corruption: {e:?}"),
+          });
+
+        let leased_until = time::UNIX_EPOCH + Duration::from_secs(lease_until_unix_timestamp);
+
+        let expired_seconds_ago = time::SystemTime::now()
+          .duration_since(leased_until)
+          .map(|t| t.as_secs())
+          // 0 indicates unexpired.
+          .unwrap_or(0);
+
+        let v = VersionedFingerprint::from_bytes_unsafe(key);
+        let fingerprint = v.get_fingerprint();
+        fingerprints.push(AgedFingerprint {
+          expired_seconds_ago,
+          fingerprint,
+          size_bytes: ShardedLmdb::content_len(&v, bytes),
+        });
+      }
+    }
+    Ok(fingerprints)
+  }
+
+  ///
+  /// Returns the Digests of all entries whose lease was last set or extended after `since`.
+  ///
+  /// The time that an entry was last touched is derived from its stored lease expiration, so an
+  /// entry which has never been leased is treated as if it were touched at the unix epoch.
+  ///
+  pub async fn modified_since(&self, since: time::SystemTime) -> Result<Vec<Digest>, String> {
     let store = self.clone();
     self
       .executor
       .spawn_blocking(
         move || {
-          let mut fingerprints = Vec::new();
+          let mut digests = Vec::new();
           for (env, database, lease_database) in &store.all_lmdbs() {
             let txn = env
               .begin_ro_txn()
-              .map_err(|err| format!("Error beginning transaction to garbage collect: {err}"))?;
+              .map_err(|err| format!("Error beginning transaction to list entries: {err}"))?;
             let mut cursor = txn
               .open_ro_cursor(*database)
               .map_err(|err| format!("Failed to open lmdb read cursor: {err}"))?;
@@ -421,42 +896,34 @@ impl ShardedLmdb {
               let (key, bytes) =
                 key_res.map_err(|err| format!("Failed to advance lmdb read cursor: {err}"))?;
 
-              // Random access into the lease_database is slower than iterating, but hopefully garbage
-              // collection is rare enough that we can get away with this, rather than do two passes
-              // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
-              // when we delete from lmdb to track how much we've freed).
-              let lease_until_unix_timestamp = txn
-                .get(*lease_database, &key)
-                .map(|b| {
+              let modified = match txn.get(*lease_database, &key) {
+                Ok(b) => {
                   let mut array = [0_u8; 8];
                   array.copy_from_slice(b);
-                  u64::from_le_bytes(array)
-                })
-                .unwrap_or_else(|e| match e {
-                  lmdb::Error::NotFound => 0,
-                  e => panic!("Error reading lease, probable lmdb corruption: {e:?}"),
-                });
-
-              let leased_until = time::UNIX_EPOCH + Duration::from_secs(lease_until_unix_timestamp);
-
-              let expired_seconds_ago = time::SystemTime::now()
-                .duration_since(leased_until)
-                .map(|t| t.as_secs())
-                // 0 indicates unexpired.
-                .unwrap_or(0);
-
-              let v = VersionedFingerprint::from_bytes_unsafe(key);
-              let fingerprint = v.get_fingerprint();
-              fingerprints.push(AgedFingerprint {
-                expired_seconds_ago,
-                fingerprint,
-                size_bytes: bytes.len(),
-              });
+                  (time::UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(array)))
+                    .checked_sub(store.lease_time)
+                    .unwrap_or(time::UNIX_EPOCH)
+                }
+                Err(lmdb::Error::NotFound) => time::UNIX_EPOCH,
+                Err(e) => {
+                  return Err(format!(
+                    "Error reading lease, probable lmdb corruption: {e}"
+                  ))
+                }
+              };
+
+              if modified > since {
+                let v = VersionedFingerprint::from_bytes_unsafe(key);
+                digests.push(Digest::new(
+                  v.get_fingerprint(),
+                  ShardedLmdb::content_len(&v, bytes),
+                ));
+              }
             }
           }
-          Ok(fingerprints)
+          Ok(digests)
         },
-        |e| Err(format!("`all_fingerprints` task failed: {e}")),
+        |e| Err(format!("`modified_since` task failed: {e}")),
       )
       .await
   }
@@ -503,20 +970,21 @@ impl ShardedLmdb {
             let (_, _, _, batch) = items_by_env
               .entry(*env_id)
               .or_insert_with(|| (env.clone(), *db, *lease_database, vec![]));
-            batch.push((effective_key, bytes));
+            batch.push((effective_key, store.encoded_value(bytes)?));
             fingerprints.push(fingerprint);
           }
 
           // Open and commit a Transaction per Environment. Since we never have more than one
           // Transaction open at a time, we don't have to worry about ordering.
           for (_, (env, db, lease_database, batch)) in items_by_env {
+            let mut added = Vec::new();
             env
               .begin_rw_txn()
               .and_then(|mut txn| {
                 for (effective_key, bytes) in &batch {
                   let put_res = txn.put(db, &effective_key, &bytes, WriteFlags::NO_OVERWRITE);
                   match put_res {
-                    Ok(()) => (),
+                    Ok(()) => added.push(effective_key.get_fingerprint()),
                     Err(lmdb::Error::KeyExist) => continue,
                     Err(err) => return Err(err),
                   }
@@ -541,6 +1009,7 @@ impl ShardedLmdb {
                   e
                 )
               })?;
+            store.notify_content_observer(added);
           }
 
           Ok(())
@@ -582,21 +1051,61 @@ impl ShardedLmdb {
               .begin_rw_txn()
               .map_err(StoreError::Lmdb)
               .and_then(|mut txn| {
-                // Second pass: copy into the reserved memory.
-                let mut writer = txn
-                  .reserve(
+                let mut read = data_provider().map_err(|e| format!("Failed to read: {e}"))?;
+                let should_retry = if store.compression_level.is_some() {
+                  // Compressed values cannot be written directly into reserved memory, because
+                  // their size is not known in advance: copy the content into memory, and then
+                  // encode it.
+                  let mut content = Vec::with_capacity(expected_digest.size_bytes);
+                  let should_retry = !sync_verified_copy_with(
+                    store.digest_function,
+                    expected_digest,
+                    data_is_immutable,
+                    &mut read,
+                    &mut content,
+                  )
+                  .map_err(|e| format!("Failed to copy from {read:?}: {e:?}"))?;
+                  if !should_retry {
+                    let value = store.encoded_value(Bytes::from(content))?;
+                    txn.put(db, &effective_key, &value, WriteFlags::NO_OVERWRITE)?;
+                  }
+                  should_retry
+                } else {
+                  // Second pass: copy into the reserved memory.
+                  let checksum_size = if store.value_checksums {
+                    ShardedLmdb::CHECKSUM_SIZE
+                  } else {
+                    0
+                  };
+                  let reserved = txn.reserve(
                     db,
                     &effective_key,
-                    expected_digest.size_bytes,
+                    ShardedLmdb::HEADER_SIZE + expected_digest.size_bytes + checksum_size,
                     WriteFlags::NO_OVERWRITE,
-                  )?
-                  .writer();
-                let mut read = data_provider().map_err(|e| format!("Failed to read: {e}"))?;
-                let should_retry =
-                  !sync_verified_copy(expected_digest, data_is_immutable, &mut read, &mut writer)
-                    .map_err(|e| {
+                  )?;
+                  let (encoded, checksum) =
+                    reserved.split_at_mut(ShardedLmdb::HEADER_SIZE + expected_digest.size_bytes);
+                  encoded[0] = if store.value_checksums {
+                    ShardedLmdb::UNCOMPRESSED_HEADER | ShardedLmdb::CHECKSUMMED_HEADER_FLAG
+                  } else {
+                    ShardedLmdb::UNCOMPRESSED_HEADER
+                  };
+                  let mut writer = (&mut encoded[ShardedLmdb::HEADER_SIZE..]).writer();
+                  let should_retry = !sync_verified_copy_with(
+                    store.digest_function,
+                    expected_digest,
+                    data_is_immutable,
+                    &mut read,
+                    &mut writer,
+                  )
+                  .map_err(|e| {
                     format!("Failed to copy from {read:?} or store in {env:?}: {e:?}")
                   })?;
+                  if !should_retry && store.value_checksums {
+                    checksum.copy_from_slice(&ShardedLmdb::checksum(encoded));
+                  }
+                  should_retry
+                };
 
                 if should_retry {
                   let msg = format!("Input {read:?} changed while reading.");
@@ -617,7 +1126,10 @@ impl ShardedLmdb {
               });
 
             match put_res {
-              Ok(()) => return Ok(()),
+              Ok(()) => {
+                store.notify_content_observer([expected_digest.hash]);
+                return Ok(());
+              }
               Err(StoreError::Retry(msg)) => {
                 // Input changed during reading: maybe retry.
                 if attempts > 10 {
@@ -668,6 +1180,261 @@ impl ShardedLmdb {
       .await
   }
 
+  ///
+  /// Extends the leases of all of the given Fingerprints, using one write transaction per
+  /// Environment rather than one per Fingerprint.
+  ///
+  pub async fn lease_batch(&self, fingerprints: Vec<Fingerprint>) -> Result<(), String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let until_secs_since_epoch: u64 = store.lease_until_secs_since_epoch();
+
+          // Group the fingerprints by the Environment that they will be leased in.
+          let mut fingerprints_by_env = HashMap::new();
+          for fingerprint in fingerprints {
+            let (env_id, _, env, _, lease_database) = store.get_raw(&fingerprint.0);
+            let (_, _, batch) = fingerprints_by_env
+              .entry(*env_id)
+              .or_insert_with(|| (env.clone(), *lease_database, vec![]));
+            batch.push(VersionedFingerprint::new(
+              fingerprint,
+              ShardedLmdb::SCHEMA_VERSION,
+            ));
+          }
+
+          for (_, (env, lease_database, batch)) in fingerprints_by_env {
+            env
+              .begin_rw_txn()
+              .and_then(|mut txn| {
+                for versioned_fingerprint in &batch {
+                  store.lease_inner(
+                    lease_database,
+                    versioned_fingerprint,
+                    until_secs_since_epoch,
+                    &mut txn,
+                  )?;
+                }
+                txn.commit()
+              })
+              .map_err(|e| {
+                format!(
+                  "Error leasing fingerprints {:?}: {e}",
+                  batch.iter().map(|key| key.to_hex()).collect::<Vec<_>>()
+                )
+              })?;
+          }
+          Ok(())
+        },
+        |e| Err(format!("`lease_batch` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Returns the times until which the given Fingerprints are leased, omitting Fingerprints which
+  /// have never been leased. Unlike `Self::is_leased`, the Fingerprints need not be stored, so this
+  /// may be used to track the leases of content which is stored elsewhere.
+  ///
+  pub async fn leased_until_batch(
+    &self,
+    fingerprints: Vec<Fingerprint>,
+  ) -> Result<HashMap<Fingerprint, time::SystemTime>, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          // Group the fingerprints by the Environment that they are leased in.
+          let mut fingerprints_by_env = HashMap::new();
+          for fingerprint in fingerprints {
+            let (env_id, _, env, _, lease_database) = store.get_raw(&fingerprint.0);
+            let (_, _, batch) = fingerprints_by_env
+              .entry(*env_id)
+              .or_insert_with(|| (env.clone(), *lease_database, vec![]));
+            batch.push(fingerprint);
+          }
+
+          let mut leased_until = HashMap::new();
+          for (_, (env, lease_database, batch)) in fingerprints_by_env {
+            let txn = env
+              .begin_ro_txn()
+              .map_err(|err| format!("Failed to begin read transaction: {err}"))?;
+            for fingerprint in batch {
+              let effective_key =
+                VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
+              match txn.get(lease_database, &effective_key) {
+                Ok(b) => {
+                  let mut array = [0_u8; 8];
+                  array.copy_from_slice(b);
+                  leased_until.insert(
+                    fingerprint,
+                    time::UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(array)),
+                  );
+                }
+                Err(lmdb::Error::NotFound) => (),
+                Err(err) => return Err(format!("Error reading lease for {fingerprint:?}: {err}")),
+              }
+            }
+          }
+          Ok(leased_until)
+        },
+        |e| Err(format!("`leased_until_batch` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Removes the leases of the given Fingerprints (but not their values), using one write
+  /// transaction per Environment.
+  ///
+  pub async fn remove_leases(&self, fingerprints: Vec<Fingerprint>) -> Result<(), String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let mut fingerprints_by_env = HashMap::new();
+          for fingerprint in fingerprints {
+            let (env_id, _, env, _, lease_database) = store.get_raw(&fingerprint.0);
+            let (_, _, batch) = fingerprints_by_env
+              .entry(*env_id)
+              .or_insert_with(|| (env.clone(), *lease_database, vec![]));
+            batch.push(VersionedFingerprint::new(
+              fingerprint,
+              ShardedLmdb::SCHEMA_VERSION,
+            ));
+          }
+
+          for (_, (env, lease_database, batch)) in fingerprints_by_env {
+            env
+              .begin_rw_txn()
+              .and_then(|mut txn| {
+                for versioned_fingerprint in &batch {
+                  match txn.del(lease_database, versioned_fingerprint, None) {
+                    Ok(()) | Err(lmdb::Error::NotFound) => (),
+                    Err(err) => return Err(err),
+                  }
+                }
+                txn.commit()
+              })
+              .map_err(|e| format!("Error removing leases: {e}"))?;
+          }
+          Ok(())
+        },
+        |e| Err(format!("`remove_leases` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Flushes the buffers of the Environment which holds the given Fingerprint to disk.
+  ///
+  /// Environments are opened with `NO_SYNC`, so committed writes are not otherwise guaranteed to
+  /// have reached the disk.
+  ///
+  pub async fn sync(&self, fingerprint: Fingerprint) -> Result<(), String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let (env, _, _) = store.get(&fingerprint);
+          env
+            .sync(true)
+            .map_err(|e| format!("Error syncing {fingerprint:?}: {e}"))
+        },
+        |e| Err(format!("`sync` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Atomically sets a lease of the given duration on the given Fingerprint if it does not already
+  /// have an unexpired lease, returning true if the lease was set.
+  ///
+  /// Because the check and the write happen in a single write transaction (of which LMDB allows
+  /// only one at a time per Environment), at most one concurrent caller will succeed. The
+  /// Fingerprint need not be present in the content database.
+  ///
+  pub async fn try_lease(
+    &self,
+    fingerprint: Fingerprint,
+    lease_time: Duration,
+  ) -> Result<bool, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
+          let (env, _, lease_database) = store.get(&fingerprint);
+          let now_since_epoch = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("Surely you're not before the unix epoch?");
+          env
+            .begin_rw_txn()
+            .and_then(|mut txn| {
+              match txn.get(lease_database, &effective_key) {
+                Ok(b) => {
+                  let mut array = [0_u8; 8];
+                  array.copy_from_slice(b);
+                  if u64::from_le_bytes(array) > now_since_epoch.as_secs() {
+                    return Ok(false);
+                  }
+                }
+                Err(lmdb::Error::NotFound) => (),
+                Err(err) => return Err(err),
+              }
+              store.lease_inner(
+                lease_database,
+                &effective_key,
+                (now_since_epoch + lease_time).as_secs(),
+                &mut txn,
+              )?;
+              txn.commit()?;
+              Ok(true)
+            })
+            .map_err(|e| format!("Error leasing {fingerprint:?}: {e}"))
+        },
+        |e| Err(format!("`try_lease` task failed: {e}")),
+      )
+      .await
+  }
+
+  ///
+  /// Returns true if the given Fingerprint has a lease which has not yet expired.
+  ///
+  pub async fn is_leased(&self, fingerprint: Fingerprint) -> Result<bool, String> {
+    let store = self.clone();
+    self
+      .executor
+      .spawn_blocking(
+        move || {
+          let effective_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION);
+          let (env, _, lease_database) = store.get(&fingerprint);
+          let txn = env
+            .begin_ro_txn()
+            .map_err(|err| format!("Failed to begin read transaction: {err}"))?;
+          let lease_until_unix_timestamp = match txn.get(lease_database, &effective_key) {
+            Ok(b) => {
+              let mut array = [0_u8; 8];
+              array.copy_from_slice(b);
+              u64::from_le_bytes(array)
+            }
+            Err(lmdb::Error::NotFound) => return Ok(false),
+            Err(err) => return Err(format!("Error reading lease for {fingerprint:?}: {err}")),
+          };
+          let leased_until = time::UNIX_EPOCH + Duration::from_secs(lease_until_unix_timestamp);
+          Ok(leased_until > time::SystemTime::now())
+        },
+        |e| Err(format!("`is_leased` task failed: {e}")),
+      )
+      .await
+  }
+
   fn lease_inner(
     &self,
     database: Database,
@@ -709,7 +1476,7 @@ impl ShardedLmdb {
             .begin_ro_txn()
             .map_err(|err| format!("Failed to begin read transaction: {err}"))?;
           match ro_txn.get(db, &effective_key) {
-            Ok(bytes) => f(bytes).map(Some),
+            Ok(bytes) => f(&ShardedLmdb::decoded_content(&effective_key, bytes)?).map(Some),
             Err(lmdb::Error::NotFound) => Ok(None),
             Err(err) => Err(format!(
               "Error loading versioned key {:?}: {}",
@@ -725,9 +1492,12 @@ impl ShardedLmdb {
 
   #[allow(clippy::useless_conversion)] // False positive: https://github.com/rust-lang/rust-clippy/issues/3913
   pub fn compact(&self) -> Result<(), String> {
-    for (env, old_dir, _) in
-      ShardedLmdb::envs(&self.root_path, self.max_size_per_shard, self.shard_count)?
-    {
+    for (env, old_dir, _) in ShardedLmdb::envs(
+      &self.root_path,
+      self.max_size_per_shard,
+      self.shard_count,
+      false,
+    )? {
       let new_dir = TempDir::new_in(old_dir.parent().unwrap()).expect("TODO");
       env
         .copy(new_dir.path(), EnvironmentCopyFlags::COMPACT)
@@ -1,6 +1,6 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use bytes::{Buf, Bytes};
 use hashing::Digest;
@@ -8,7 +8,9 @@ use parking_lot::Mutex;
 use task_executor::Executor;
 use tempfile::TempDir;
 
-use crate::{ShardedLmdb, DEFAULT_LEASE_TIME};
+use lmdb::{Transaction, WriteFlags};
+
+use crate::{ShardedLmdb, VersionedFingerprint, DEFAULT_LEASE_TIME};
 
 fn new_store(shard_count: u8) -> (ShardedLmdb, TempDir) {
   let tempdir = TempDir::new().unwrap();
@@ -98,3 +100,312 @@ async fn store_failure() {
 fn bytes(content: u8) -> Bytes {
   Bytes::from(vec![content; 100])
 }
+
+#[tokio::test]
+async fn value_checksums() {
+  let (s, tempdir) = new_store(1);
+  let s = s.with_value_checksums(true);
+  let content = Bytes::from("checksummed content. ".repeat(5));
+  let streamed_content = Bytes::from("streamed content. ".repeat(5));
+  let fingerprint = Digest::of_bytes(&content).hash;
+  s.store_bytes(fingerprint, content.clone(), false)
+    .await
+    .unwrap();
+  let streamed_digest = Digest::of_bytes(&streamed_content);
+  s.store(false, true, streamed_digest, {
+    let streamed_content = streamed_content.clone();
+    move || Ok(streamed_content.clone().reader())
+  })
+  .await
+  .unwrap();
+
+  // The checksum is not included in loaded values or in their sizes.
+  let load =
+    |fingerprint| s.load_bytes_with(fingerprint, |bytes| Ok(Bytes::copy_from_slice(bytes)));
+  assert_eq!(load(fingerprint).await, Ok(Some(content.clone())));
+  assert_eq!(
+    load(streamed_digest.hash).await,
+    Ok(Some(streamed_content.clone()))
+  );
+  let mut sizes = s
+    .all_fingerprints()
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|aged_fingerprint| aged_fingerprint.size_bytes)
+    .collect::<Vec<_>>();
+  sizes.sort();
+  assert_eq!(sizes, vec![streamed_content.len(), content.len()]);
+
+  // Corrupt a byte of the content without changing its length.
+  let data_path = std::fs::read_dir(tempdir.path())
+    .unwrap()
+    .map(|entry| entry.unwrap().path().join("data.mdb"))
+    .find(|path| path.exists())
+    .unwrap();
+  let mut data = std::fs::read(&data_path).unwrap();
+  // LMDB is copy-on-write, so stale copies of the page may also exist: corrupt all of them.
+  let offsets = data
+    .windows(content.len())
+    .enumerate()
+    .filter(|(_, window)| *window == &content[..])
+    .map(|(offset, _)| offset)
+    .collect::<Vec<_>>();
+  assert!(!offsets.is_empty());
+  for offset in offsets {
+    data[offset + 10] ^= 0xff;
+  }
+  std::fs::write(&data_path, data).unwrap();
+
+  let err = load(fingerprint).await.unwrap_err();
+  assert!(err.contains("Checksum mismatch"), "{err}");
+  assert_eq!(
+    load(streamed_digest.hash).await,
+    Ok(Some(streamed_content.clone()))
+  );
+
+  // Toggling checksums only affects values which are stored afterwards.
+  let unchecksummed = s.clone().with_value_checksums(false);
+  let unchecksummed_content = Bytes::from("unchecksummed content. ".repeat(5));
+  let unchecksummed_fingerprint = Digest::of_bytes(&unchecksummed_content).hash;
+  unchecksummed
+    .store_bytes(
+      unchecksummed_fingerprint,
+      unchecksummed_content.clone(),
+      false,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    unchecksummed
+      .load_bytes_with(streamed_digest.hash, |bytes| Ok(Bytes::copy_from_slice(
+        bytes
+      )))
+      .await,
+    Ok(Some(streamed_content))
+  );
+  assert_eq!(
+    load(unchecksummed_fingerprint).await,
+    Ok(Some(unchecksummed_content))
+  );
+}
+
+#[tokio::test]
+async fn compression() {
+  let (s, tempdir) = new_store(1);
+  let s = s.with_compression_level(Some(3)).with_value_checksums(true);
+  let compressible = Bytes::from("compressible content. ".repeat(100));
+  let incompressible = Bytes::from((0..=255_u8).collect::<Vec<_>>());
+  let streamed = Bytes::from("streamed content. ".repeat(100));
+  s.store_bytes_batch(
+    vec![
+      (Digest::of_bytes(&compressible).hash, compressible.clone()),
+      (
+        Digest::of_bytes(&incompressible).hash,
+        incompressible.clone(),
+      ),
+    ],
+    false,
+  )
+  .await
+  .unwrap();
+  let streamed_digest = Digest::of_bytes(&streamed);
+  s.store(false, true, streamed_digest, {
+    let streamed = streamed.clone();
+    move || Ok(streamed.clone().reader())
+  })
+  .await
+  .unwrap();
+
+  // Values are transparently decompressed, and report their uncompressed sizes.
+  for content in [&compressible, &incompressible, &streamed] {
+    assert_eq!(
+      s.load_bytes_with(Digest::of_bytes(content).hash, |bytes| Ok(
+        Bytes::copy_from_slice(bytes)
+      ))
+      .await,
+      Ok(Some(content.clone()))
+    );
+  }
+  let mut sizes = s
+    .all_fingerprints()
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|aged_fingerprint| aged_fingerprint.size_bytes)
+    .collect::<Vec<_>>();
+  sizes.sort();
+  assert_eq!(
+    sizes,
+    vec![incompressible.len(), streamed.len(), compressible.len()]
+  );
+
+  // The compressible content was not stored verbatim.
+  let data = std::fs::read_dir(tempdir.path())
+    .unwrap()
+    .map(|entry| entry.unwrap().path().join("data.mdb"))
+    .find(|path| path.exists())
+    .map(|path| std::fs::read(path).unwrap())
+    .unwrap();
+  assert!(!data
+    .windows(compressible.len())
+    .any(|window| window == &compressible[..]));
+
+  // Toggling compression only affects values which are stored afterwards: values which were
+  // stored with and without it remain loadable.
+  let (uncompressed, _tempdir) = new_store(1);
+  let content = Bytes::from("uncompressed content. ".repeat(100));
+  let fingerprint = Digest::of_bytes(&content).hash;
+  uncompressed
+    .store_bytes(fingerprint, content.clone(), false)
+    .await
+    .unwrap();
+  let compressed = uncompressed.clone().with_compression_level(Some(3));
+  let compressed_content = Bytes::from("compressed content. ".repeat(100));
+  let compressed_fingerprint = Digest::of_bytes(&compressed_content).hash;
+  compressed
+    .store_bytes(compressed_fingerprint, compressed_content.clone(), false)
+    .await
+    .unwrap();
+  for (s, fingerprint, content) in [
+    (&compressed, fingerprint, &content),
+    (&uncompressed, compressed_fingerprint, &compressed_content),
+  ] {
+    assert_eq!(
+      s.load_bytes_with(fingerprint, |bytes| Ok(Bytes::copy_from_slice(bytes)))
+        .await,
+      Ok(Some(content.clone()))
+    );
+  }
+}
+
+#[tokio::test]
+async fn remove_old_versions() {
+  let (s, _tempdir) = new_store(1);
+  let content = bytes(0);
+  let fingerprint = Digest::of_bytes(&content).hash;
+
+  // Store a value under an older schema version, as an older version of Pants would have.
+  let (env, db, lease_database) = s.get(&fingerprint);
+  let old_key = VersionedFingerprint::new(fingerprint, ShardedLmdb::SCHEMA_VERSION - 1);
+  let mut txn = env.begin_rw_txn().unwrap();
+  txn
+    .put(db, &old_key, &content, WriteFlags::empty())
+    .unwrap();
+  txn
+    .put(
+      lease_database,
+      &old_key,
+      &0_u64.to_le_bytes(),
+      WriteFlags::empty(),
+    )
+    .unwrap();
+  txn.commit().unwrap();
+
+  // It cannot be loaded, but is listed (with the size that removing it frees) so that it can be
+  // garbage collected.
+  assert!(!s.exists(fingerprint).await.unwrap());
+  let aged_fingerprints = s.all_fingerprints().await.unwrap();
+  assert_eq!(aged_fingerprints.len(), 1);
+  assert_eq!(aged_fingerprints[0].fingerprint, fingerprint);
+  assert_eq!(aged_fingerprints[0].size_bytes, content.len());
+
+  s.store_bytes(fingerprint, content, true).await.unwrap();
+  assert_eq!(s.all_fingerprints().await.unwrap().len(), 2);
+  assert_eq!(
+    s.remove_batch(vec![fingerprint]).await,
+    Ok(vec![fingerprint].into_iter().collect())
+  );
+  assert!(s.all_fingerprints().await.unwrap().is_empty());
+  assert_eq!(
+    s.leased_until_batch(vec![fingerprint]).await,
+    Ok(HashMap::new())
+  );
+}
+
+#[tokio::test]
+async fn lease_batch() {
+  let (s, _tempdir) = new_store(16);
+  let fingerprints = (0..64_u8)
+    .map(|i| Digest::of_bytes(&bytes(i)).hash)
+    .collect::<Vec<_>>();
+  s.store_bytes_batch(
+    (0..64_u8)
+      .zip(&fingerprints)
+      .map(|(i, fingerprint)| (*fingerprint, bytes(i)))
+      .collect(),
+    false,
+  )
+  .await
+  .unwrap();
+  for fingerprint in &fingerprints {
+    assert!(!s.is_leased(*fingerprint).await.unwrap());
+  }
+
+  s.lease_batch(fingerprints.clone()).await.unwrap();
+  for fingerprint in fingerprints {
+    assert!(s.is_leased(fingerprint).await.unwrap());
+  }
+}
+
+#[tokio::test]
+async fn remove_batch() {
+  let (s, _tempdir) = new_store(16);
+  let fingerprints = (0..64_u8)
+    .map(|i| Digest::of_bytes(&bytes(i)).hash)
+    .collect::<Vec<_>>();
+  s.store_bytes_batch(
+    (0..32_u8)
+      .zip(&fingerprints)
+      .map(|(i, fingerprint)| (*fingerprint, bytes(i)))
+      .collect(),
+    false,
+  )
+  .await
+  .unwrap();
+
+  // Only the fingerprints which were stored are reported as removed.
+  let removed = s.remove_batch(fingerprints.clone()).await.unwrap();
+  assert_eq!(
+    removed,
+    fingerprints[..32].iter().copied().collect::<HashSet<_>>()
+  );
+  for fingerprint in fingerprints {
+    assert!(!s.exists(fingerprint).await.unwrap());
+  }
+}
+
+#[tokio::test]
+async fn leases_without_values() {
+  let (s, _tempdir) = new_store(4);
+  let fingerprints = (0..8_u8)
+    .map(|i| Digest::of_bytes(&bytes(i)).hash)
+    .collect::<Vec<_>>();
+
+  // Fingerprints may be leased without storing values for them.
+  assert_eq!(
+    s.leased_until_batch(fingerprints.clone()).await,
+    Ok(HashMap::new())
+  );
+  s.lease_batch(fingerprints[..4].to_vec()).await.unwrap();
+  let leased_until = s.leased_until_batch(fingerprints.clone()).await.unwrap();
+  assert_eq!(
+    leased_until.keys().copied().collect::<HashSet<_>>(),
+    fingerprints[..4].iter().copied().collect::<HashSet<_>>()
+  );
+  assert!(leased_until
+    .values()
+    .all(|until| *until > std::time::SystemTime::now()));
+  assert!(s.is_leased(fingerprints[0]).await.unwrap());
+
+  s.remove_leases(fingerprints[..2].to_vec()).await.unwrap();
+  assert_eq!(
+    s.leased_until_batch(fingerprints.clone())
+      .await
+      .unwrap()
+      .into_keys()
+      .collect::<HashSet<_>>(),
+    fingerprints[2..4].iter().copied().collect::<HashSet<_>>()
+  );
+  assert!(!s.is_leased(fingerprints[0]).await.unwrap());
+}
@@ -4,8 +4,10 @@
 use std::cmp::max;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::{Into, TryInto};
+use std::fmt;
 use std::future::Future;
 use std::io::Read;
+use std::net::Ipv4Addr;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -23,6 +25,7 @@ use cache::PersistentCache;
 use fs::{safe_create_dir_all_ioerror, GitignoreStyleExcludes, PosixFS};
 use futures::FutureExt;
 use graph::{self, EntryId, Graph, InvalidationResult, NodeContext};
+use grpc_util::retry::RetryPolicy;
 use hashing::Digest;
 use log::info;
 use parking_lot::Mutex;
@@ -30,7 +33,8 @@ use parking_lot::Mutex;
 use docker::docker;
 use process_execution::switched::SwitchedCommandRunner;
 use process_execution::{
-  self, bounded, local, CacheContentBehavior, CommandRunner, NamedCaches, ProcessExecutionStrategy,
+  self, bounded, cgroups::Cgroups, local, CacheContentBehavior, CommandRunner, NamedCaches,
+  ProcessExecutionStrategy, ResourceLimits,
 };
 use protos::gen::build::bazel::remote::execution::v2::ServerCapabilities;
 use regex::Regex;
@@ -91,22 +95,59 @@ pub struct RemotingOptions {
   pub execution_process_cache_namespace: Option<String>,
   pub instance_name: Option<String>,
   pub root_ca_certs_path: Option<PathBuf>,
+  pub client_certs_path: Option<PathBuf>,
+  pub client_key_path: Option<PathBuf>,
+  pub oauth_bearer_token_path: Option<PathBuf>,
+  pub oauth_bearer_token_command: Option<Vec<String>>,
   pub store_headers: BTreeMap<String, String>,
   pub store_chunk_bytes: usize,
   pub store_chunk_upload_timeout: Duration,
-  pub store_rpc_retries: usize,
+  pub store_retry_policy: RetryPolicy,
+  pub store_write_retry_policy: RetryPolicy,
   pub store_rpc_concurrency: usize,
   pub store_batch_api_size_limit: usize,
+  pub store_small_blob_cache_address: Option<String>,
+  pub store_small_blob_cache_max_size_bytes: usize,
+  pub store_lan_cache: Option<LanCacheOptions>,
+  pub store_read_concurrency_limit: Option<usize>,
+  pub store_write_concurrency_limit: Option<usize>,
+  pub store_bandwidth_limit: Option<usize>,
+  pub store_upload_policy: store::RemoteUploadPolicy,
+  pub store_presence_cache_ttl: Option<Duration>,
   pub cache_warnings_behavior: RemoteCacheWarningsBehavior,
   pub cache_content_behavior: CacheContentBehavior,
+  pub cache_validate_action_results: bool,
   pub cache_rpc_concurrency: usize,
   pub cache_read_timeout: Duration,
+  pub cache_circuit_breaker_threshold: Option<usize>,
+  pub cache_circuit_breaker_probe_interval: Duration,
   pub execution_headers: BTreeMap<String, String>,
   pub execution_overall_deadline: Duration,
+  pub execution_retry_policy: RetryPolicy,
   pub execution_rpc_concurrency: usize,
   pub append_only_caches_base_path: Option<String>,
 }
 
+///
+/// The options of a LAN cache: see `Store::into_with_peer_cache`.
+///
+#[derive(Clone)]
+pub struct LanCacheOptions {
+  pub address: Ipv4Addr,
+  pub port: u16,
+  pub secret: String,
+}
+
+impl fmt::Debug for LanCacheOptions {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("LanCacheOptions")
+      .field("address", &self.address)
+      .field("port", &self.port)
+      .field("secret", &"<redacted>")
+      .finish()
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct ExecutionStrategyOptions {
   pub local_parallelism: usize,
@@ -119,6 +160,10 @@ pub struct ExecutionStrategyOptions {
   pub child_max_memory: usize,
   pub child_default_memory: usize,
   pub graceful_shutdown_timeout: Duration,
+  /// If set, local processes are run in cgroups which apply their requested resource limits, and
+  /// these budgets for all of them.
+  pub local_resource_limits: Option<ResourceLimits>,
+  pub local_stream_output: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -129,6 +174,10 @@ pub struct LocalStoreOptions {
   pub directories_max_size_bytes: usize,
   pub lease_time: Duration,
   pub shard_count: u8,
+  pub large_file_size_limit: usize,
+  /// Prewarmed stores which are opened read-only beneath the store in `store_dir`: see
+  /// `Store::into_with_local_base_layers`.
+  pub base_layer_dirs: Vec<PathBuf>,
 }
 
 impl From<&LocalStoreOptions> for store::LocalOptions {
@@ -138,6 +187,8 @@ impl From<&LocalStoreOptions> for store::LocalOptions {
       directories_max_size_bytes: lso.directories_max_size_bytes,
       lease_time: lso.lease_time,
       shard_count: lso.shard_count,
+      large_file_size_limit: lso.large_file_size_limit,
+      ..Self::default()
     }
   }
 }
@@ -150,31 +201,83 @@ impl Core {
     enable_remote: bool,
     remoting_opts: &RemotingOptions,
     remote_store_address: &Option<String>,
-    root_ca_certs: &Option<Vec<u8>>,
+    tls_config: &grpc_util::tls::Config,
+    token_provider: &Option<Arc<grpc_util::auth::TokenProvider>>,
     capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
   ) -> Result<Store, String> {
     let local_only = Store::local_only_with_options(
       executor.clone(),
       local_store_options.store_dir.clone(),
       local_execution_root_dir,
-      local_store_options.into(),
+      store::LocalOptions {
+        remote_presence_ttl: remoting_opts
+          .store_presence_cache_ttl
+          .filter(|_| enable_remote),
+        ..local_store_options.into()
+      },
     )?;
+    let local_only = if local_store_options.base_layer_dirs.is_empty() {
+      local_only
+    } else {
+      let base_layers = local_store_options
+        .base_layer_dirs
+        .iter()
+        .map(|base_layer_dir| {
+          Store::local_only_with_options(
+            executor.clone(),
+            base_layer_dir,
+            local_execution_root_dir,
+            store::LocalOptions {
+              read_only: true,
+              ..local_store_options.into()
+            },
+          )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      local_only.into_with_local_base_layers(&base_layers)
+    };
     if enable_remote {
       let remote_store_address = remote_store_address
         .as_ref()
         .ok_or("Remote store required, but none configured")?;
-      local_only.into_with_remote(
-        remote_store_address,
-        remoting_opts.instance_name.clone(),
-        grpc_util::tls::Config::new_without_mtls(root_ca_certs.clone()),
-        remoting_opts.store_headers.clone(),
-        remoting_opts.store_chunk_bytes,
-        remoting_opts.store_chunk_upload_timeout,
-        remoting_opts.store_rpc_retries,
-        remoting_opts.store_rpc_concurrency,
-        capabilities_cell_opt,
-        remoting_opts.store_batch_api_size_limit,
-      )
+      let store = local_only
+        .into_with_remote(
+          remote_store_address,
+          remoting_opts.instance_name.clone(),
+          tls_config.clone(),
+          remoting_opts.store_headers.clone(),
+          token_provider.clone(),
+          remoting_opts.store_chunk_bytes,
+          remoting_opts.store_chunk_upload_timeout,
+          remoting_opts.store_retry_policy.clone(),
+          remoting_opts.store_rpc_concurrency,
+          capabilities_cell_opt,
+          remoting_opts.store_batch_api_size_limit,
+        )?
+        .into_with_remote_write_retry_policy(remoting_opts.store_write_retry_policy.clone())?
+        .into_with_remote_transfer_limits(
+          remoting_opts.store_read_concurrency_limit,
+          remoting_opts.store_write_concurrency_limit,
+          remoting_opts.store_bandwidth_limit,
+        )?
+        .into_with_remote_upload_policy(remoting_opts.store_upload_policy)?;
+      let store = match &remoting_opts.store_small_blob_cache_address {
+        Some(address) => store.into_with_small_blob_cache(
+          address,
+          remoting_opts.store_small_blob_cache_max_size_bytes,
+          remoting_opts.cache_read_timeout,
+        )?,
+        None => store,
+      };
+      match &remoting_opts.store_lan_cache {
+        Some(lan_cache) => store.into_with_peer_cache(
+          lan_cache.address,
+          lan_cache.port,
+          lan_cache.secret.clone(),
+          remoting_opts.cache_read_timeout,
+        ),
+        None => Ok(store),
+      }
     } else {
       Ok(local_only)
     }
@@ -193,7 +296,8 @@ impl Core {
     named_caches: &NamedCaches,
     instance_name: Option<String>,
     process_cache_namespace: Option<String>,
-    root_ca_certs: &Option<Vec<u8>>,
+    tls_config: &grpc_util::tls::Config,
+    token_provider: &Option<Arc<grpc_util::auth::TokenProvider>>,
     exec_strategy_opts: &ExecutionStrategyOptions,
     remoting_opts: &RemotingOptions,
     capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
@@ -206,6 +310,21 @@ impl Core {
       immutable_inputs.clone(),
       exec_strategy_opts.local_keep_sandboxes,
     );
+    let local_command_runner = match exec_strategy_opts.local_resource_limits {
+      Some(budgets) => match Cgroups::new(budgets) {
+        Ok(cgroups) => local_command_runner.with_cgroups(Arc::new(cgroups)),
+        Err(e) => {
+          log::warn!("Resource limits will not be applied to local processes: {e}");
+          local_command_runner
+        }
+      },
+      None => local_command_runner,
+    };
+    let local_command_runner = if exec_strategy_opts.local_stream_output {
+      local_command_runner.with_streamed_output()
+    } else {
+      local_command_runner
+    };
 
     let runner: Box<dyn CommandRunner> = if exec_strategy_opts.local_enable_nailgun {
       // We set the nailgun pool size to the number of instances that fit within the memory
@@ -273,12 +392,13 @@ impl Core {
         instance_name,
         process_cache_namespace,
         remoting_opts.append_only_caches_base_path.clone(),
-        root_ca_certs.clone(),
+        tls_config.clone(),
         remoting_opts.execution_headers.clone(),
+        token_provider.clone(),
         full_store.clone(),
         executor.clone(),
         remoting_opts.execution_overall_deadline,
-        Duration::from_millis(100),
+        remoting_opts.execution_retry_policy.clone(),
         remoting_opts.execution_rpc_concurrency,
         capabilities_cell_opt,
       )?);
@@ -315,7 +435,8 @@ impl Core {
     local_cache: &PersistentCache,
     instance_name: Option<String>,
     process_cache_namespace: Option<String>,
-    root_ca_certs: &Option<Vec<u8>>,
+    tls_config: &grpc_util::tls::Config,
+    token_provider: &Option<Arc<grpc_util::auth::TokenProvider>>,
     remoting_opts: &RemotingOptions,
     remote_cache_read: bool,
     remote_cache_write: bool,
@@ -323,15 +444,16 @@ impl Core {
     local_cache_write: bool,
   ) -> Result<Arc<dyn CommandRunner>, String> {
     if remote_cache_read || remote_cache_write {
-      runner = Arc::new(remote_cache::CommandRunner::new(
+      let remote_cache_runner = remote_cache::CommandRunner::new(
         runner,
         instance_name,
         process_cache_namespace.clone(),
         executor.clone(),
         full_store.clone(),
         remoting_opts.store_address.as_ref().unwrap(),
-        root_ca_certs.clone(),
+        tls_config.clone(),
         remoting_opts.store_headers.clone(),
+        token_provider.clone(),
         remote_cache_read,
         remote_cache_write,
         remoting_opts.cache_warnings_behavior,
@@ -339,7 +461,19 @@ impl Core {
         remoting_opts.cache_rpc_concurrency,
         remoting_opts.cache_read_timeout,
         remoting_opts.append_only_caches_base_path.clone(),
-      )?);
+      )?;
+      let remote_cache_runner = if remoting_opts.cache_validate_action_results {
+        remote_cache_runner.with_action_result_validation()
+      } else {
+        remote_cache_runner
+      };
+      runner = Arc::new(match remoting_opts.cache_circuit_breaker_threshold {
+        Some(failure_threshold) => remote_cache_runner.with_circuit_breaker(
+          failure_threshold,
+          remoting_opts.cache_circuit_breaker_probe_interval,
+        ),
+        None => remote_cache_runner,
+      });
     }
 
     if local_cache_read || local_cache_write {
@@ -369,7 +503,8 @@ impl Core {
     named_caches: &NamedCaches,
     instance_name: Option<String>,
     process_cache_namespace: Option<String>,
-    root_ca_certs: &Option<Vec<u8>>,
+    tls_config: &grpc_util::tls::Config,
+    token_provider: &Option<Arc<grpc_util::auth::TokenProvider>>,
     exec_strategy_opts: &ExecutionStrategyOptions,
     remoting_opts: &RemotingOptions,
     capabilities_cell_opt: Option<Arc<OnceCell<ServerCapabilities>>>,
@@ -383,7 +518,8 @@ impl Core {
       named_caches,
       instance_name.clone(),
       process_cache_namespace.clone(),
-      root_ca_certs,
+      tls_config,
+      token_provider,
       exec_strategy_opts,
       remoting_opts,
       capabilities_cell_opt,
@@ -401,7 +537,8 @@ impl Core {
         local_cache,
         instance_name.clone(),
         process_cache_namespace.clone(),
-        root_ca_certs,
+        tls_config,
+        token_provider,
         remoting_opts,
         remote_cache_read && should_cache_read,
         remote_cache_write,
@@ -479,11 +616,58 @@ impl Core {
     } else {
       None
     };
+    // The client certificate is re-read whenever it changes on disk, since it may be short-lived.
+    let mtls = match (
+      &remoting_opts.client_certs_path,
+      &remoting_opts.client_key_path,
+    ) {
+      (Some(cert_chain_path), Some(key_path)) => Some(grpc_util::tls::MtlsConfig::Files {
+        key_path: key_path.clone(),
+        cert_chain_path: cert_chain_path.clone(),
+      }),
+      (None, None) => None,
+      _ => {
+        return Err(
+          "Both or neither of `[GLOBAL].remote_client_certs_path` and \
+          `[GLOBAL].remote_client_key_path` must be set."
+            .to_owned(),
+        )
+      }
+    };
+    let tls_config = grpc_util::tls::Config {
+      root_ca_certs,
+      mtls,
+      certificate_check: grpc_util::tls::CertificateCheck::Enabled,
+    };
 
     let need_remote_store = remoting_opts.execution_enable
       || exec_strategy_opts.remote_cache_read
       || exec_strategy_opts.remote_cache_write;
 
+    // A token which is read from a file or command is refreshed when it is rejected, since it may
+    // expire during a run.
+    let token_source = match (
+      &remoting_opts.oauth_bearer_token_path,
+      &remoting_opts.oauth_bearer_token_command,
+    ) {
+      (Some(path), None) => Some(grpc_util::auth::TokenSource::File(path.clone())),
+      (None, Some(command)) => Some(grpc_util::auth::TokenSource::Command(command.clone())),
+      (None, None) => None,
+      (Some(_), Some(_)) => {
+        return Err(
+          "Only one of `[GLOBAL].remote_oauth_bearer_token_path` and \
+          `[GLOBAL].remote_oauth_bearer_token_command` may be set."
+            .to_owned(),
+        )
+      }
+    };
+    let token_provider = match token_source {
+      Some(token_source) if need_remote_store => {
+        Some(Arc::new(grpc_util::auth::TokenProvider::new(token_source)?))
+      }
+      _ => None,
+    };
+
     // If the remote store and remote execution server are the same (address and headers),
     // then share the capabilities cache between them to avoid duplicate GetCapabilities calls.
     let capabilities_cell_opt = if need_remote_store
@@ -508,7 +692,8 @@ impl Core {
       need_remote_store,
       &remoting_opts,
       &remoting_opts.store_address,
-      &root_ca_certs,
+      &tls_config,
+      &token_provider,
       capabilities_cell_opt.clone(),
     )
     .map_err(|e| format!("Could not initialize Store: {e:?}"))?;
@@ -550,7 +735,8 @@ impl Core {
       &named_caches,
       remoting_opts.instance_name.clone(),
       remoting_opts.execution_process_cache_namespace.clone(),
-      &root_ca_certs,
+      &tls_config,
+      &token_provider,
       &exec_strategy_opts,
       &remoting_opts,
       capabilities_cell_opt,
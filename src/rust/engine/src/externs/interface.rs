@@ -24,12 +24,13 @@ use fnv::FnvHasher;
 use fs::DirectoryDigest;
 use futures::future::{self, FutureExt};
 use futures::Future;
+use grpc_util::retry::{parse_code, RetryPolicy};
 use hashing::Digest;
 use log::{self, debug, error, warn, Log};
 use logging::logger::PANTS_LOGGER;
 use logging::{Logger, PythonLogLevel};
 use petgraph::graph::{DiGraph, Graph};
-use process_execution::CacheContentBehavior;
+use process_execution::{CacheContentBehavior, ResourceLimits};
 use pyo3::exceptions::{PyException, PyIOError, PyKeyboardInterrupt, PyValueError};
 use pyo3::prelude::{
   pyclass, pyfunction, pymethods, pymodule, wrap_pyfunction, PyModule, PyObject,
@@ -50,8 +51,8 @@ use crate::externs::fs::{possible_store_missing_digest, PyFileDigest};
 use crate::externs::process::PyProcessExecutionEnvironment;
 use crate::{
   externs, nodes, Context, Core, ExecutionRequest, ExecutionStrategyOptions, ExecutionTermination,
-  Failure, Function, Intrinsic, Intrinsics, Key, LocalStoreOptions, Params, RemotingOptions, Rule,
-  Scheduler, Session, Tasks, TypeId, Types, Value,
+  Failure, Function, Intrinsic, Intrinsics, Key, LanCacheOptions, LocalStoreOptions, Params,
+  RemotingOptions, Rule, Scheduler, Session, Tasks, TypeId, Types, Value,
 };
 
 #[pymodule]
@@ -119,6 +120,8 @@ fn native_engine(py: Python, m: &PyModule) -> PyO3Result<()> {
   m.add_function(wrap_pyfunction!(nailgun_server_await_shutdown, m)?)?;
 
   m.add_function(wrap_pyfunction!(garbage_collect_store, m)?)?;
+  m.add_function(wrap_pyfunction!(start_background_store_gc, m)?)?;
+  m.add_function(wrap_pyfunction!(stop_background_store_gc, m)?)?;
   m.add_function(wrap_pyfunction!(lease_files_in_graph, m)?)?;
   m.add_function(wrap_pyfunction!(check_invalidation_watcher_liveness, m)?)?;
 
@@ -184,6 +187,7 @@ impl PyTypes {
     path_globs: &PyType,
     create_digest: &PyType,
     digest_subset: &PyType,
+    archive_digest: &PyType,
     native_download_file: &PyType,
     platform: &PyType,
     process: &PyType,
@@ -216,6 +220,7 @@ impl PyTypes {
       remove_prefix: TypeId::new(py.get_type::<externs::fs::PyRemovePrefix>()),
       create_digest: TypeId::new(create_digest),
       digest_subset: TypeId::new(digest_subset),
+      archive_digest: TypeId::new(archive_digest),
       native_download_file: TypeId::new(native_download_file),
       platform: TypeId::new(platform),
       process: TypeId::new(process),
@@ -264,6 +269,11 @@ impl PyExecutionStrategyOptions {
     child_default_memory: usize,
     child_max_memory: usize,
     graceful_shutdown_timeout: usize,
+    local_resource_limits: bool,
+    local_total_memory_limit: Option<usize>,
+    local_total_cpu_limit: Option<f64>,
+    local_total_pids_limit: Option<usize>,
+    local_stream_output: bool,
   ) -> Self {
     Self(ExecutionStrategyOptions {
       local_parallelism,
@@ -279,6 +289,12 @@ impl PyExecutionStrategyOptions {
       child_default_memory,
       child_max_memory,
       graceful_shutdown_timeout: Duration::from_secs(graceful_shutdown_timeout.try_into().unwrap()),
+      local_resource_limits: local_resource_limits.then_some(ResourceLimits {
+        memory_bytes: local_total_memory_limit,
+        cpu_millis: local_total_cpu_limit.map(|cpus| (cpus * 1000.0).round() as usize),
+        pids: local_total_pids_limit,
+      }),
+      local_stream_output,
     })
   }
 }
@@ -297,47 +313,149 @@ impl PyRemotingOptions {
     execution_process_cache_namespace: Option<String>,
     instance_name: Option<String>,
     root_ca_certs_path: Option<PathBuf>,
+    client_certs_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+    oauth_bearer_token_path: Option<PathBuf>,
+    oauth_bearer_token_command: Option<Vec<String>>,
     store_headers: BTreeMap<String, String>,
     store_chunk_bytes: usize,
     store_chunk_upload_timeout: u64,
     store_rpc_retries: usize,
+    store_write_rpc_retries: Option<usize>,
+    store_rpc_retry_initial_backoff_millis: u64,
+    store_rpc_retry_max_backoff_millis: u64,
+    store_rpc_retryable_status_codes: Vec<String>,
     store_rpc_concurrency: usize,
     store_batch_api_size_limit: usize,
+    store_small_blob_cache_address: Option<String>,
+    store_small_blob_cache_max_size_bytes: usize,
+    store_lan_cache: bool,
+    store_lan_cache_address: Option<String>,
+    store_lan_cache_port: u16,
+    store_lan_cache_secret: Option<String>,
+    store_read_concurrency_limit: Option<usize>,
+    store_write_concurrency_limit: Option<usize>,
+    store_bandwidth_limit: Option<usize>,
+    store_upload_files: bool,
+    store_upload_directories: bool,
+    store_max_file_upload_size: Option<usize>,
+    store_presence_cache_ttl_secs: Option<u64>,
     cache_warnings_behavior: String,
     cache_content_behavior: String,
+    cache_validate_action_results: bool,
     cache_rpc_concurrency: usize,
     cache_read_timeout_millis: u64,
+    cache_circuit_breaker_threshold: Option<usize>,
+    cache_circuit_breaker_probe_interval_secs: u64,
     execution_headers: BTreeMap<String, String>,
     execution_overall_deadline_secs: u64,
+    execution_rpc_retries: usize,
+    execution_rpc_retry_initial_backoff_millis: u64,
+    execution_rpc_retry_max_backoff_millis: u64,
+    execution_rpc_retryable_status_codes: Vec<String>,
     execution_rpc_concurrency: usize,
     append_only_caches_base_path: Option<String>,
-  ) -> Self {
-    Self(RemotingOptions {
+  ) -> PyO3Result<Self> {
+    let store_retry_policy = |retries| {
+      retry_policy(
+        retries,
+        store_rpc_retry_initial_backoff_millis,
+        store_rpc_retry_max_backoff_millis,
+        &store_rpc_retryable_status_codes,
+      )
+    };
+    let store_lan_cache = if store_lan_cache {
+      let address = store_lan_cache_address
+        .ok_or_else(|| {
+          PyValueError::new_err("A LAN cache requires the address of an interface to serve on.")
+        })?
+        .parse()
+        .map_err(|e| PyValueError::new_err(format!("Invalid LAN cache address: {e}")))?;
+      let secret = store_lan_cache_secret
+        .ok_or_else(|| PyValueError::new_err("A LAN cache requires a shared secret."))?;
+      Some(LanCacheOptions {
+        address,
+        port: store_lan_cache_port,
+        secret,
+      })
+    } else {
+      None
+    };
+    Ok(Self(RemotingOptions {
       execution_enable,
       store_address,
       execution_address,
       execution_process_cache_namespace,
       instance_name,
       root_ca_certs_path,
+      client_certs_path,
+      client_key_path,
+      oauth_bearer_token_path,
+      oauth_bearer_token_command,
       store_headers,
       store_chunk_bytes,
       store_chunk_upload_timeout: Duration::from_secs(store_chunk_upload_timeout),
-      store_rpc_retries,
+      store_retry_policy: store_retry_policy(store_rpc_retries)?,
+      store_write_retry_policy: store_retry_policy(
+        store_write_rpc_retries.unwrap_or(store_rpc_retries),
+      )?,
       store_rpc_concurrency,
       store_batch_api_size_limit,
+      store_small_blob_cache_address,
+      store_small_blob_cache_max_size_bytes,
+      store_lan_cache,
+      store_read_concurrency_limit,
+      store_write_concurrency_limit,
+      store_bandwidth_limit,
+      store_upload_policy: store::RemoteUploadPolicy {
+        files: store_upload_files,
+        directories: store_upload_directories,
+        max_file_size_bytes: store_max_file_upload_size,
+      },
+      store_presence_cache_ttl: store_presence_cache_ttl_secs.map(Duration::from_secs),
       cache_warnings_behavior: RemoteCacheWarningsBehavior::from_str(&cache_warnings_behavior)
         .unwrap(),
       cache_content_behavior: CacheContentBehavior::from_str(&cache_content_behavior).unwrap(),
+      cache_validate_action_results,
       cache_rpc_concurrency,
       cache_read_timeout: Duration::from_millis(cache_read_timeout_millis),
+      cache_circuit_breaker_threshold,
+      cache_circuit_breaker_probe_interval: Duration::from_secs(
+        cache_circuit_breaker_probe_interval_secs,
+      ),
       execution_headers,
       execution_overall_deadline: Duration::from_secs(execution_overall_deadline_secs),
+      execution_retry_policy: retry_policy(
+        execution_rpc_retries,
+        execution_rpc_retry_initial_backoff_millis,
+        execution_rpc_retry_max_backoff_millis,
+        &execution_rpc_retryable_status_codes,
+      )?,
       execution_rpc_concurrency,
       append_only_caches_base_path,
-    })
+    }))
   }
 }
 
+fn retry_policy(
+  retries: usize,
+  initial_backoff_millis: u64,
+  max_backoff_millis: u64,
+  retryable_status_codes: &[String],
+) -> PyO3Result<RetryPolicy> {
+  let retryable_codes = retryable_status_codes
+    .iter()
+    .map(|name| parse_code(name))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(PyValueError::new_err)?;
+  Ok(RetryPolicy {
+    attempts: retries + 1,
+    initial_backoff: Duration::from_millis(initial_backoff_millis),
+    max_backoff: Duration::from_millis(max_backoff_millis),
+    retryable_codes,
+  })
+}
+
 #[pyclass]
 struct PyLocalStoreOptions(LocalStoreOptions);
 
@@ -351,6 +469,8 @@ impl PyLocalStoreOptions {
     directories_max_size_bytes: usize,
     lease_time_millis: u64,
     shard_count: u8,
+    large_file_size_limit: usize,
+    base_layer_dirs: Vec<PathBuf>,
   ) -> PyO3Result<Self> {
     if shard_count.count_ones() != 1 {
       return Err(PyValueError::new_err(format!(
@@ -364,6 +484,8 @@ impl PyLocalStoreOptions {
       directories_max_size_bytes,
       lease_time: Duration::from_millis(lease_time_millis),
       shard_count,
+      large_file_size_limit,
+      base_layer_dirs,
     }))
   }
 }
@@ -1464,6 +1586,50 @@ fn garbage_collect_store(
   })
 }
 
+#[pyfunction]
+fn start_background_store_gc(
+  py: Python,
+  py_scheduler: &PyScheduler,
+  py_session: &PySession,
+  target_size_bytes: usize,
+  interval_millis: u64,
+  batch_size: usize,
+) -> PyO3Result<()> {
+  let core = &py_scheduler.0.core;
+  // NB: Each pass of collection is reported as a workunit of the given session, which is scoped to
+  // the spawned collection task rather than installed on the calling thread.
+  let workunit_store_handle = WorkunitStoreHandle {
+    store: py_session.0.workunit_store(),
+    parent_id: None,
+  };
+  core.executor.enter(|| {
+    py.allow_threads(|| {
+      core
+        .executor
+        .block_on(workunit_store::scope_task_workunit_store_handle(
+          Some(workunit_store_handle),
+          core
+            .store()
+            .start_background_gc(store::BackgroundGcOptions {
+              target_bytes: target_size_bytes,
+              interval: Duration::from_millis(interval_millis),
+              batch_size,
+            }),
+        ))
+    });
+    Ok(())
+  })
+}
+
+#[pyfunction]
+fn stop_background_store_gc(py: Python, py_scheduler: &PyScheduler) -> PyO3Result<()> {
+  let core = &py_scheduler.0.core;
+  core.executor.enter(|| {
+    py.allow_threads(|| core.executor.block_on(core.store().stop_background_gc()));
+    Ok(())
+  })
+}
+
 #[pyfunction]
 fn lease_files_in_graph(
   py: Python,
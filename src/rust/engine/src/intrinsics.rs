@@ -36,7 +36,7 @@ use process_execution::local::{
 use process_execution::{ManagedChild, Platform, ProcessExecutionStrategy};
 use rule_graph::DependencyKey;
 use stdio::TryCloneAsFile;
-use store::{SnapshotOps, SubsetParams};
+use store::{ArchiveFormat, SnapshotOps, SubsetParams};
 
 use workunit_store::{in_workunit, Level};
 
@@ -105,6 +105,10 @@ impl Intrinsics {
       Intrinsic::new(types.directory_digest, types.digest_subset),
       Box::new(digest_subset_to_digest),
     );
+    intrinsics.insert(
+      Intrinsic::new(types.directory_digest, types.archive_digest),
+      Box::new(archive_digest_to_digest),
+    );
     intrinsics.insert(
       Intrinsic {
         product: types.session_values,
@@ -216,6 +220,11 @@ fn process_request_to_process_result(
             ),
             externs::store_utf8(py, result.metadata.source.into()),
             externs::store_u64(py, result.metadata.source_run_id.0.into()),
+            result
+              .metadata
+              .memory_limit_exceeded
+              .map(|limit| externs::store_utf8(py, &limit.to_string()))
+              .unwrap_or_else(|| Value::from(py.None())),
           ],
         ),
       ],
@@ -506,6 +515,51 @@ fn digest_subset_to_digest(
   .boxed()
 }
 
+fn archive_digest_to_digest(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let (digest, output_path, format) = Python::with_gil(|py| {
+      let py_archive_digest = (*args[0]).as_ref(py);
+      let py_digest = externs::getattr(py_archive_digest, "digest").unwrap();
+      let output_path: String = externs::getattr(py_archive_digest, "output_path").unwrap();
+      let py_format: &PyAny = externs::getattr(py_archive_digest, "format").unwrap();
+      let format: String = externs::getattr(py_format, "value").unwrap();
+      let res: NodeResult<_> = Ok((
+        lift_directory_digest(py_digest)?,
+        RelativePath::new(PathBuf::from(output_path))?,
+        format.parse::<ArchiveFormat>()?,
+      ));
+      res
+    })?;
+
+    // The archive is streamed from the store into a temporary file, and then captured.
+    let archive = tempfile::NamedTempFile::new()
+      .map_err(|e| format!("Failed to create a temporary file for an archive: {e}"))?;
+    let file = archive
+      .reopen()
+      .map_err(|e| format!("Failed to open a temporary file for an archive: {e}"))?;
+    store.export_archive(digest, format, file).await?;
+    let file_digest = store
+      .store_file(true, false, archive.path().to_owned())
+      .await?;
+
+    let trie = DigestTrie::from_unique_paths(
+      vec![TypedPath::File {
+        path: &output_path,
+        is_executable: false,
+      }],
+      &HashMap::from([(output_path.to_path_buf(), file_digest)]),
+    )?;
+    let gil = Python::acquire_gil();
+    let value = Snapshot::store_directory_digest(gil.python(), trie.into())?;
+    Ok(value)
+  }
+  .boxed()
+}
+
 fn session_values(context: Context, _args: Vec<Value>) -> BoxFuture<'static, NodeResult<Value>> {
   async move { context.get(SessionValues).await }.boxed()
 }
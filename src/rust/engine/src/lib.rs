@@ -44,7 +44,7 @@ mod tasks;
 mod types;
 
 pub use crate::context::{
-  Context, Core, ExecutionStrategyOptions, LocalStoreOptions, RemotingOptions,
+  Context, Core, ExecutionStrategyOptions, LanCacheOptions, LocalStoreOptions, RemotingOptions,
 };
 pub use crate::intrinsics::Intrinsics;
 pub use crate::python::{Failure, Function, Key, Params, TypeId, Value};
@@ -33,7 +33,7 @@ use fs::{
 };
 use process_execution::{
   self, CacheName, InputDigests, Process, ProcessCacheScope, ProcessExecutionStrategy,
-  ProcessResultSource,
+  ProcessResultSource, ResourceLimits,
 };
 
 use crate::externs::engine_aware::{EngineAwareParameter, EngineAwareReturnType};
@@ -379,6 +379,13 @@ impl ExecuteProcess {
 
     let concurrency_available: usize = externs::getattr(value, "concurrency_available")?;
 
+    let resource_limits = ResourceLimits {
+      memory_bytes: externs::getattr(value, "memory_limit")?,
+      cpu_millis: externs::getattr::<Option<f64>>(value, "cpu_limit")?
+        .map(|cpus| (cpus * 1000.0).round() as usize),
+      pids: externs::getattr(value, "pids_limit")?,
+    };
+
     let cache_scope: ProcessCacheScope = {
       let cache_scope_enum = externs::getattr(value, "cache_scope")?;
       externs::getattr::<String>(cache_scope_enum, "name")?.try_into()?
@@ -403,6 +410,7 @@ impl ExecuteProcess {
       jdk_home,
       execution_slot_variable,
       concurrency_available,
+      resource_limits,
       cache_scope,
       execution_environment: process_config.environment,
       remote_cache_speculation_delay,
@@ -965,9 +973,11 @@ impl Snapshot {
       .map_err(|e| throw(format!("{e}")))
       .await?;
 
+    let normalization = context.core.store().unicode_normalization();
     store::Snapshot::from_path_stats(context.clone(), path_stats)
-      .map_err(|e| throw(format!("Snapshot failed: {e}")))
       .await
+      .and_then(|snapshot| snapshot.normalize_unicode(normalization))
+      .map_err(|e| throw(format!("Snapshot failed: {e}")))
   }
 }
 
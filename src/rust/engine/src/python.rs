@@ -562,7 +562,7 @@ impl From<StoreError> for Failure {
   fn from(err: StoreError) -> Self {
     match err {
       StoreError::MissingDigest(s, d) => Self::MissingDigest(s, d),
-      StoreError::Unclassified(s) => throw(s),
+      StoreError::DeadlineExceeded(s) | StoreError::Unclassified(s) => throw(s),
     }
   }
 }
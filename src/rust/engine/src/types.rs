@@ -20,6 +20,7 @@ pub struct Types {
   pub remove_prefix: TypeId,
   pub create_digest: TypeId,
   pub digest_subset: TypeId,
+  pub archive_digest: TypeId,
   pub native_download_file: TypeId,
   pub platform: TypeId,
   pub process: TypeId,
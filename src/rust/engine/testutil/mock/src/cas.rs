@@ -57,6 +57,9 @@ impl Drop for StubCAS {
 pub struct StubCASBuilder {
   ac_always_errors: bool,
   cas_always_errors: bool,
+  cas_supports_zstd: bool,
+  cas_digest_functions: Vec<i32>,
+  cas_write_interruptions: (usize, usize),
   chunk_size_bytes: Option<usize>,
   content: HashMap<Fingerprint, Bytes>,
   port: Option<u16>,
@@ -71,6 +74,9 @@ impl StubCASBuilder {
     StubCASBuilder {
       ac_always_errors: false,
       cas_always_errors: false,
+      cas_supports_zstd: false,
+      cas_digest_functions: vec![remexec::digest_function::Value::Sha256 as i32],
+      cas_write_interruptions: (0, 0),
       chunk_size_bytes: None,
       content: HashMap::new(),
       port: None,
@@ -131,6 +137,33 @@ impl StubCASBuilder {
     self
   }
 
+  ///
+  /// Advertise and support zstd compression of blobs, for both ByteStream and batch requests.
+  ///
+  pub fn cas_supports_zstd(mut self) -> Self {
+    self.cas_supports_zstd = true;
+    self
+  }
+
+  ///
+  /// Advertise the given (REAPI) digest functions, rather than only SHA-256. An empty list is
+  /// advertised by servers which predate digest function negotiation.
+  ///
+  pub fn cas_digest_functions(mut self, digest_functions: Vec<i32>) -> Self {
+    self.cas_digest_functions = digest_functions;
+    self
+  }
+
+  ///
+  /// Interrupt the given number of ByteStream writes with an `UNAVAILABLE` error after each has
+  /// received the given number of bytes. The bytes received are kept, so that the writes can be
+  /// resumed.
+  ///
+  pub fn cas_interrupts_writes(mut self, count: usize, after_bytes: usize) -> Self {
+    self.cas_write_interruptions = (count, after_bytes);
+    self
+  }
+
   pub fn ac_read_delay(mut self, duration: Duration) -> Self {
     self.ac_read_delay = duration;
     self
@@ -168,6 +201,11 @@ impl StubCASBuilder {
       always_errors: self.cas_always_errors,
       read_request_count: read_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
+      supports_zstd: self.cas_supports_zstd,
+      digest_functions: self.cas_digest_functions,
+      interrupted_writes: Arc::default(),
+      write_interruptions: Arc::new(Mutex::new(self.cas_write_interruptions.0)),
+      write_interruption_after_bytes: self.cas_write_interruptions.1,
       required_auth_header: self.required_auth_token.map(|t| format!("Bearer {t}")),
     };
 
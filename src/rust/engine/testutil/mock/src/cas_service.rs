@@ -35,6 +35,14 @@ pub(crate) struct StubCASResponder {
   pub required_auth_header: Option<String>,
   pub read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  pub supports_zstd: bool,
+  pub digest_functions: Vec<i32>,
+  /// The content received so far by interrupted writes, by resource name.
+  pub interrupted_writes: Arc<Mutex<HashMap<String, BytesMut>>>,
+  /// The number of writes which remain to be interrupted, and after how many bytes each of them
+  /// is interrupted.
+  pub write_interruptions: Arc<Mutex<usize>>,
+  pub write_interruption_after_bytes: usize,
 }
 
 macro_rules! check_auth {
@@ -174,13 +182,51 @@ fn parse_read_resource_name(resource: &str) -> Result<ParsedReadResourceName, St
   })
 }
 
+/// Rewrites a resource name which refers to the zstd-compressed form of a blob (via a
+/// `compressed-blobs/zstd` component) to refer to the blob itself, and returns whether it did.
+fn uncompressed_resource_name(resource: &str) -> Result<(String, bool), String> {
+  const COMPRESSED_BLOBS: &str = "compressed-blobs/";
+  match resource.find(COMPRESSED_BLOBS) {
+    None => Ok((resource.to_owned(), false)),
+    Some(index) => match resource[index + COMPRESSED_BLOBS.len()..].strip_prefix("zstd/") {
+      Some(rest) => Ok((format!("{}blobs/{rest}", &resource[..index]), true)),
+      None => Err("Malformed resource name: unsupported compressor".to_owned()),
+    },
+  }
+}
+
 impl StubCASResponder {
   fn instance_name(&self) -> String {
     self.instance_name.clone().unwrap_or_default()
   }
 
+  fn uncompressed_resource_name(&self, resource: &str) -> Result<(String, bool), Status> {
+    let (resource_name, compressed) =
+      uncompressed_resource_name(resource).map_err(Status::invalid_argument)?;
+    if compressed && !self.supports_zstd {
+      return Err(Status::invalid_argument(
+        "StubCAS is not configured to support compression".to_owned(),
+      ));
+    }
+    Ok((resource_name, compressed))
+  }
+
+  ///
+  /// True if a write which has received the given number of bytes should be interrupted.
+  ///
+  fn interrupts_write(&self, received_bytes: usize) -> bool {
+    let mut write_interruptions = self.write_interruptions.lock();
+    if *write_interruptions > 0 && received_bytes >= self.write_interruption_after_bytes {
+      *write_interruptions -= 1;
+      true
+    } else {
+      false
+    }
+  }
+
   fn read_internal(&self, req: &ReadRequest) -> Result<Vec<ReadResponse>, Status> {
-    let parsed_resource_name = parse_read_resource_name(&req.resource_name)
+    let (resource_name, compressed) = self.uncompressed_resource_name(&req.resource_name)?;
+    let parsed_resource_name = parse_read_resource_name(&resource_name)
       .map_err(|err| Status::invalid_argument(format!("Failed to parse resource name: {err}")))?;
 
     let digest = parsed_resource_name.hash;
@@ -191,8 +237,13 @@ impl StubCASResponder {
         "StubCAS is configured to always fail".to_owned(),
       ));
     }
-    let blobs = self.blobs.lock();
-    let maybe_bytes = blobs.get(&fingerprint);
+    let maybe_bytes = self.blobs.lock().get(&fingerprint).cloned();
+    let maybe_bytes = match maybe_bytes {
+      Some(bytes) if compressed => Some(Bytes::from(
+        zstd::bulk::compress(&bytes, 0).map_err(|e| Status::internal(e.to_string()))?,
+      )),
+      maybe_bytes => maybe_bytes,
+    };
     match maybe_bytes {
       Some(bytes) => Ok(
         bytes
@@ -247,6 +298,7 @@ impl ByteStream for StubCASResponder {
     let mut maybe_resource_name = None;
     let mut want_next_offset = 0;
     let mut bytes = BytesMut::new();
+    let mut received_bytes = 0;
 
     while let Some(req_result) = stream.next().await {
       let req = match req_result {
@@ -259,7 +311,14 @@ impl ByteStream for StubCASResponder {
       };
 
       match maybe_resource_name {
-        None => maybe_resource_name = Some(req.resource_name.clone()),
+        None => {
+          // Resume an interrupted write of the same resource, if there is one.
+          if let Some(committed) = self.interrupted_writes.lock().remove(&req.resource_name) {
+            want_next_offset = committed.len() as i64;
+            bytes = committed;
+          }
+          maybe_resource_name = Some(req.resource_name.clone())
+        }
         Some(ref resource_name) => {
           if *resource_name != req.resource_name {
             return Err(Status::invalid_argument(format!(
@@ -280,6 +339,17 @@ impl ByteStream for StubCASResponder {
       want_next_offset += req.data.len() as i64;
       write_message_sizes.lock().push(req.data.len());
       bytes.extend_from_slice(&req.data);
+
+      received_bytes += req.data.len();
+      if !req.finish_write && self.interrupts_write(received_bytes) {
+        self
+          .interrupted_writes
+          .lock()
+          .insert(req.resource_name, bytes);
+        return Err(Status::unavailable(
+          "StubCAS is configured to interrupt writes".to_owned(),
+        ));
+      }
     }
 
     let bytes = bytes.freeze();
@@ -289,6 +359,15 @@ impl ByteStream for StubCASResponder {
         "Stream saw no messages".to_owned(),
       )),
       Some(resource_name) => {
+        let (resource_name, compressed) = self.uncompressed_resource_name(&resource_name)?;
+        let bytes = if compressed {
+          Bytes::from(
+            zstd::stream::decode_all(&bytes[..])
+              .map_err(|e| Status::invalid_argument(format!("Failed to decompress: {e}")))?,
+          )
+        } else {
+          bytes
+        };
         let parsed_resource_name =
           parse_write_resource_name(&resource_name).map_err(Status::internal)?;
 
@@ -329,8 +408,9 @@ impl ByteStream for StubCASResponder {
           blobs.insert(fingerprint, bytes);
         }
 
+        // NB: The committed size of a compressed write is its compressed size.
         let response = WriteResponse {
-          committed_size: size as i64,
+          committed_size: want_next_offset,
         };
         Ok(Response::new(response))
       }
@@ -339,9 +419,34 @@ impl ByteStream for StubCASResponder {
 
   async fn query_write_status(
     &self,
-    _: Request<QueryWriteStatusRequest>,
+    request: Request<QueryWriteStatusRequest>,
   ) -> Result<Response<QueryWriteStatusResponse>, Status> {
-    Err(Status::unimplemented("".to_owned()))
+    check_auth!(self, request);
+
+    let resource_name = request.into_inner().resource_name;
+    if let Some(committed) = self.interrupted_writes.lock().get(&resource_name) {
+      return Ok(Response::new(QueryWriteStatusResponse {
+        committed_size: committed.len() as i64,
+        complete: false,
+      }));
+    }
+
+    // Otherwise, the write is complete if the blob exists.
+    let (uncompressed_resource_name, _) = self.uncompressed_resource_name(&resource_name)?;
+    let parsed_resource_name =
+      parse_write_resource_name(&uncompressed_resource_name).map_err(Status::invalid_argument)?;
+    let fingerprint = Fingerprint::from_hex_string(parsed_resource_name.hash)
+      .map_err(|e| Status::invalid_argument(format!("Bad fingerprint: {e}")))?;
+    if self.blobs.lock().contains_key(&fingerprint) {
+      Ok(Response::new(QueryWriteStatusResponse {
+        committed_size: parsed_resource_name.size as i64,
+        complete: true,
+      }))
+    } else {
+      Err(Status::not_found(format!(
+        "No write in progress for {resource_name}"
+      )))
+    }
   }
 }
 
@@ -422,13 +527,27 @@ impl ContentAddressableStorage for StubCASResponder {
       Status::ok("")
     }
 
-    for blob_request in request.requests {
+    for mut blob_request in request.requests {
       let digest = blob_request.digest.clone();
       self
         .write_message_sizes
         .lock()
         .push(blob_request.data.len());
-      let status = write_blob(blob_request, &mut blobs);
+      let status = if blob_request.compressor == remexec::compressor::Value::Identity as i32 {
+        write_blob(blob_request, &mut blobs)
+      } else if self.supports_zstd
+        && blob_request.compressor == remexec::compressor::Value::Zstd as i32
+      {
+        match zstd::stream::decode_all(&blob_request.data[..]) {
+          Ok(data) => {
+            blob_request.data = Bytes::from(data);
+            write_blob(blob_request, &mut blobs)
+          }
+          Err(e) => Status::invalid_argument(format!("Failed to decompress: {e}")),
+        }
+      } else {
+        Status::invalid_argument("Unsupported compressor")
+      };
       responses.push(remexec::batch_update_blobs_response::Response {
         digest,
         status: Some(protos::gen::google::rpc::Status {
@@ -494,17 +613,33 @@ impl ContentAddressableStorage for StubCASResponder {
       }
     }
 
+    let compressor = if self.supports_zstd
+      && request
+        .acceptable_compressors
+        .contains(&(remexec::compressor::Value::Zstd as i32))
+    {
+      remexec::compressor::Value::Zstd
+    } else {
+      remexec::compressor::Value::Identity
+    };
+
     for digest in request.digests {
       let (data_opt, status) = read_blob(digest.clone(), &blobs);
+      let data = match data_opt {
+        Some(data) if compressor == remexec::compressor::Value::Zstd => {
+          Bytes::from(zstd::bulk::compress(&data, 0).map_err(|e| Status::internal(e.to_string()))?)
+        }
+        data_opt => data_opt.unwrap_or_else(Bytes::new),
+      };
       responses.push(remexec::batch_read_blobs_response::Response {
         digest: Some(digest),
-        data: data_opt.unwrap_or_else(Bytes::new),
+        data,
         status: Some(protos::gen::google::rpc::Status {
           code: status.code() as i32,
           message: status.message().to_string(),
           ..protos::gen::google::rpc::Status::default()
         }),
-        compressor: remexec::compressor::Value::Identity as i32,
+        compressor: compressor as i32,
       });
     }
 
@@ -530,10 +665,17 @@ impl Capabilities for StubCASResponder {
     let request = request.into_inner();
     check_instance_name!(self, request);
 
+    let compressors = if self.supports_zstd {
+      vec![remexec::compressor::Value::Zstd as i32]
+    } else {
+      vec![]
+    };
     let response = ServerCapabilities {
       cache_capabilities: Some(CacheCapabilities {
-        digest_functions: vec![remexec::digest_function::Value::Sha256 as i32],
+        digest_functions: self.digest_functions.clone(),
         max_batch_total_size_bytes: 0,
+        supported_compressors: compressors.clone(),
+        supported_batch_update_compressors: compressors,
         ..CacheCapabilities::default()
       }),
       execution_capabilities: Some(ExecutionCapabilities {
@@ -556,10 +698,26 @@ impl Capabilities for StubCASResponder {
 #[cfg(test)]
 mod tests {
   use super::{
-    parse_read_resource_name, parse_write_resource_name, ParsedReadResourceName,
-    ParsedWriteResourceName,
+    parse_read_resource_name, parse_write_resource_name, uncompressed_resource_name,
+    ParsedReadResourceName, ParsedWriteResourceName,
   };
 
+  #[test]
+  fn uncompressed_resource_name_correctly() {
+    assert_eq!(
+      uncompressed_resource_name("main/blobs/abc123/12"),
+      Ok(("main/blobs/abc123/12".to_owned(), false))
+    );
+    assert_eq!(
+      uncompressed_resource_name("main/uploads/uuid-12345/compressed-blobs/zstd/abc123/12"),
+      Ok(("main/uploads/uuid-12345/blobs/abc123/12".to_owned(), true))
+    );
+    assert_eq!(
+      uncompressed_resource_name("compressed-blobs/deflate/abc123/12"),
+      Err("Malformed resource name: unsupported compressor".to_owned())
+    );
+  }
+
   #[test]
   fn parse_write_resource_name_correctly() {
     let result = parse_write_resource_name("main/uploads/uuid-12345/blobs/abc123/12").unwrap();
@@ -0,0 +1,106 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::FutureExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use parking_lot::Mutex;
+
+///
+/// A static HTTP server which serves objects by path with GET and HEAD, and (unless it is
+/// read-only) stores them with PUT.
+///
+pub struct StubHttpStore {
+  pub objects: Arc<Mutex<HashMap<String, Bytes>>>,
+  local_addr: SocketAddr,
+  shutdown_sender: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl Drop for StubHttpStore {
+  fn drop(&mut self) {
+    if let Some(s) = self.shutdown_sender.take() {
+      let _ = s.send(());
+    }
+  }
+}
+
+impl StubHttpStore {
+  pub fn new(read_only: bool) -> StubHttpStore {
+    let objects = Arc::new(Mutex::new(HashMap::new()));
+
+    let make_service = {
+      let objects = objects.clone();
+      make_service_fn(move |_| {
+        let objects = objects.clone();
+        async move {
+          Ok::<_, Infallible>(service_fn(move |request| {
+            Self::respond(objects.clone(), read_only, request)
+          }))
+        }
+      })
+    };
+
+    let addr = "127.0.0.1:0".parse().expect("failed to parse IP address");
+    let server = Server::try_bind(&addr)
+      .expect("failed to bind port")
+      .serve(make_service);
+    let local_addr = server.local_addr();
+
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+      server
+        .with_graceful_shutdown(shutdown_receiver.map(drop))
+        .await
+        .unwrap();
+    });
+
+    StubHttpStore {
+      objects,
+      local_addr,
+      shutdown_sender: Some(shutdown_sender),
+    }
+  }
+
+  async fn respond(
+    objects: Arc<Mutex<HashMap<String, Bytes>>>,
+    read_only: bool,
+    request: Request<Body>,
+  ) -> Result<Response<Body>, hyper::Error> {
+    let path = request.uri().path().to_owned();
+    let status = |status| {
+      let mut response = Response::new(Body::empty());
+      *response.status_mut() = status;
+      response
+    };
+
+    let response = match *request.method() {
+      Method::GET | Method::HEAD => {
+        let object = objects.lock().get(&path).cloned();
+        match object {
+          Some(_) if request.method() == Method::HEAD => status(StatusCode::OK),
+          Some(object) => Response::new(Body::from(object)),
+          None => status(StatusCode::NOT_FOUND),
+        }
+      }
+      Method::PUT if !read_only => {
+        let object = hyper::body::to_bytes(request.into_body()).await?;
+        objects.lock().insert(path, object);
+        status(StatusCode::CREATED)
+      }
+      _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    Ok(response)
+  }
+
+  ///
+  /// The host and port on which this server is listening over insecure HTTP.
+  ///
+  pub fn host(&self) -> String {
+    self.local_addr.to_string()
+  }
+}
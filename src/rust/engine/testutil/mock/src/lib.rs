@@ -29,6 +29,10 @@ mod action_cache_service;
 mod cas;
 mod cas_service;
 pub mod execution_server;
+mod http_store;
+mod redis;
 
 pub use crate::cas::{StubCAS, StubCASBuilder};
 pub use crate::execution_server::MockExecution;
+pub use crate::http_store::StubHttpStore;
+pub use crate::redis::StubRedis;
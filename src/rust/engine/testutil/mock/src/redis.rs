@@ -0,0 +1,119 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+///
+/// A Redis server which supports only the `GET`, `SET`, `AUTH` and `SELECT` commands, and which
+/// holds its values in memory.
+///
+pub struct StubRedis {
+  pub values: Arc<Mutex<HashMap<Bytes, Bytes>>>,
+  local_addr: SocketAddr,
+  shutdown_sender: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl Drop for StubRedis {
+  fn drop(&mut self) {
+    if let Some(s) = self.shutdown_sender.take() {
+      let _ = s.send(());
+    }
+  }
+}
+
+impl StubRedis {
+  pub async fn new() -> StubRedis {
+    let values = Arc::new(Mutex::new(HashMap::new()));
+    let listener = TcpListener::bind("127.0.0.1:0")
+      .await
+      .expect("failed to bind port");
+    let local_addr = listener.local_addr().unwrap();
+
+    let (shutdown_sender, mut shutdown_receiver) = tokio::sync::oneshot::channel();
+    tokio::spawn({
+      let values = values.clone();
+      async move {
+        loop {
+          tokio::select! {
+            accepted = listener.accept() => {
+              let (stream, _) = accepted.unwrap();
+              tokio::spawn(Self::serve(values.clone(), stream));
+            }
+            _ = &mut shutdown_receiver => break,
+          }
+        }
+      }
+    });
+
+    StubRedis {
+      values,
+      local_addr,
+      shutdown_sender: Some(shutdown_sender),
+    }
+  }
+
+  async fn serve(values: Arc<Mutex<HashMap<Bytes, Bytes>>>, stream: TcpStream) {
+    let mut stream = BufReader::new(stream);
+    while let Some(command) = Self::read_command(&mut stream).await {
+      let reply = match command
+        .first()
+        .map(|name| name.to_ascii_uppercase())
+        .as_deref()
+      {
+        Some(b"GET") if command.len() == 2 => {
+          let value = values.lock().get(&command[1]).cloned();
+          match value {
+            Some(value) => {
+              let mut reply = format!("${}\r\n", value.len()).into_bytes();
+              reply.extend_from_slice(&value);
+              reply.extend_from_slice(b"\r\n");
+              reply
+            }
+            None => b"$-1\r\n".to_vec(),
+          }
+        }
+        Some(b"SET") if command.len() == 3 => {
+          values.lock().insert(command[1].clone(), command[2].clone());
+          b"+OK\r\n".to_vec()
+        }
+        Some(b"AUTH") | Some(b"SELECT") => b"+OK\r\n".to_vec(),
+        _ => b"-ERR unsupported command\r\n".to_vec(),
+      };
+      if stream.get_mut().write_all(&reply).await.is_err() {
+        return;
+      }
+    }
+  }
+
+  async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<Bytes>> {
+    async fn read_line(stream: &mut BufReader<TcpStream>) -> Option<String> {
+      let mut line = String::new();
+      stream.read_line(&mut line).await.ok()?;
+      line.strip_suffix("\r\n").map(str::to_owned)
+    }
+
+    let count: usize = read_line(stream).await?.strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+      let len: usize = read_line(stream).await?.strip_prefix('$')?.parse().ok()?;
+      let mut arg = vec![0; len + 2];
+      stream.read_exact(&mut arg).await.ok()?;
+      arg.truncate(len);
+      args.push(Bytes::from(arg));
+    }
+    Some(args)
+  }
+
+  ///
+  /// The address of this server, including its scheme.
+  ///
+  pub fn address(&self) -> String {
+    format!("redis://{}", self.local_addr)
+  }
+}
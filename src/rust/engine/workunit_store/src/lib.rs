@@ -712,6 +712,45 @@ impl WorkunitStore {
     self.complete_workunit_impl(workunit, end_time);
   }
 
+  ///
+  /// Records a message which was emitted by the given (running) workunit, such as a line of the
+  /// output of a process, as a completed child workunit with the given desc and message. Streaming
+  /// workunit handlers receive it like any other workunit, and it is logged as `{desc}: {message}`.
+  ///
+  pub fn add_workunit_message(
+    &self,
+    name: &'static str,
+    level: Level,
+    parent_id: Option<SpanId>,
+    desc: String,
+    message: String,
+  ) {
+    if self.max_level < level {
+      return;
+    }
+    log!(level, "{desc}: {message}");
+
+    let now = SystemTime::now();
+    let span_id = SpanId::new();
+    let metadata = WorkunitMetadata {
+      desc: Some(desc),
+      message: Some(message),
+      ..WorkunitMetadata::default()
+    };
+    self.send(StoreMsg::Started(Workunit {
+      name,
+      level,
+      span_id,
+      parent_ids: parent_id.into_iter().collect(),
+      state: WorkunitState::Started {
+        start_time: now,
+        blocked: Arc::new(AtomicBool::new(false)),
+      },
+      metadata: Some(metadata.clone()),
+    }));
+    self.send(StoreMsg::Completed(span_id, level, Some(metadata), now));
+  }
+
   pub fn latest_workunits(&self, max_verbosity: log::Level) -> (Vec<Workunit>, Vec<Workunit>) {
     self
       .streaming_workunit_data
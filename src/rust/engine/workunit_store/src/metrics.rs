@@ -27,6 +27,8 @@ pub enum Metric {
   /// processes directly.
   LocalCacheTotalTimeSavedMs,
   LocalExecutionRequests,
+  /// The number of local processes which were killed because they exceeded a memory limit.
+  LocalProcessMemoryLimitExceeded,
   RemoteProcessTotalTimeRunMs,
   RemoteCacheRequests,
   RemoteCacheRequestsCached,
@@ -41,6 +43,15 @@ pub enum Metric {
   /// processes directly.
   RemoteCacheTotalTimeSavedMs,
   RemoteCacheRequestTimeouts,
+  /// The number of times the remote cache was bypassed after failing repeatedly.
+  RemoteCacheCircuitBreakerTrips,
+  /// The number of remote cache reads and writes which were skipped while it was bypassed.
+  RemoteCacheCircuitBreakerBypasses,
+  /// The number of times the remote cache stopped being bypassed, because it had recovered.
+  RemoteCacheCircuitBreakerRecoveries,
+  /// The number of remote cache hits which were treated as misses, because they referenced blobs
+  /// which were missing from the remote store.
+  RemoteCacheIncompleteResults,
   RemoteExecutionErrors,
   RemoteExecutionRequests,
   RemoteExecutionRPCErrors,
@@ -50,6 +61,59 @@ pub enum Metric {
   RemoteExecutionSuccess,
   RemoteExecutionTimeouts,
   RemoteStoreMissingDigest,
+  /// Number of small blobs which were (or were not) found in the small blob cache of the remote
+  /// store.
+  RemoteStoreSmallBlobCacheHits,
+  RemoteStoreSmallBlobCacheMisses,
+  /// Number of blobs which were (or were not) loaded from a peer on the LAN.
+  RemoteStorePeerCacheHits,
+  RemoteStorePeerCacheMisses,
+  /// Number of blobs which were (or were not) found when reading from the remote store.
+  RemoteStoreReadBlobHits,
+  RemoteStoreReadBlobMisses,
+  /// Total number of bytes of blobs read from (or written to) the remote store.
+  RemoteStoreBytesRead,
+  RemoteStoreBytesWritten,
+  /// Number of digests which FindMissingBlobs reported as already present in (or missing from) the
+  /// remote store.
+  RemoteStoreDigestsPresent,
+  RemoteStoreDigestsMissing,
+  /// Number of blobs which were not uploaded to the remote store, because the remote upload policy
+  /// excludes their class or size.
+  RemoteStoreUploadsExcluded,
+  /// Number of local store operations which took longer than the configured slow op threshold.
+  LocalStoreSlowOperations,
+  /// Number of local store loads into a file which were performed by the filesystem (for example,
+  /// via `copy_file_range`), rather than by copying through memory.
+  LocalStoreFilesystemCopies,
+  /// Number of immutable large files which were stored into the local store by cloning them with
+  /// the filesystem, rather than by copying them.
+  LocalStoreFilesystemClones,
+  /// Number of bytes of expired entries which were evicted from the local store by garbage
+  /// collection.
+  LocalStoreEvictedBytes,
+  /// Number of blobs written to the local store which were stored as files on disk.
+  LocalStoreFsdbWrites,
+  /// Number of blobs written to the local store which were stored in LMDB.
+  LocalStoreLmdbWrites,
+  /// Number of local store entries whose leases were extended.
+  LocalStoreLeaseExtensions,
+  /// Number of local store loads which were served by its in-memory cache.
+  LocalStoreMemoryCacheHits,
+  /// Number of local store loads which missed in its in-memory cache.
+  LocalStoreMemoryCacheMisses,
+  /// Number of corrupt local store entries which were moved into quarantine when they were loaded.
+  LocalStoreQuarantinedEntries,
+  /// Number of local store loads whose content did not match the requested digest, when reads are
+  /// verified. See `LocalOptions::verify_reads`.
+  LocalStoreReadVerificationFailures,
+  /// Number of shard directories of large files in the local store which were scanned to list their
+  /// entries (for example, by garbage collection).
+  LocalStoreShardsScanned,
+  /// Number of files stored by streaming Snapshot captures.
+  StreamingCaptureFiles,
+  /// Number of directories stored by streaming Snapshot captures.
+  StreamingCaptureDirectories,
   /// Number of times that we backtracked due to missing digests.
   BacktrackAttempts,
   DockerExecutionRequests,
@@ -70,10 +134,18 @@ pub enum ObservationMetric {
   LocalProcessTimeRunMs,
   LocalStoreReadBlobSize,
   LocalStoreReadBlobTimeMicros,
+  LocalStoreWriteBlobSize,
+  /// The time taken (in microseconds) by each write to the local store, which may store a batch of
+  /// blobs.
+  LocalStoreWriteBlobTimeMicros,
   RemoteProcessTimeRunMs,
   RemoteExecutionRPCFirstResponseTimeMicros,
   RemoteStoreTimeToFirstByteMicros,
   RemoteStoreReadBlobTimeMicros,
+  /// Time to write a blob (or a batch of blobs) to a remote CAS.
+  RemoteStoreWriteBlobTimeMicros,
+  /// Time for a FindMissingBlobs request to a remote CAS.
+  RemoteStoreFindMissingBlobsTimeMicros,
   /// Total number of bytes of blobs downloaded from a remote CAS.
   RemoteStoreBlobBytesDownloaded,
   /// Total number of bytes of blobs uploaded to a remote CAS.
@@ -126,6 +126,40 @@ async fn workunit_escalation_is_recorded() {
   );
 }
 
+#[test]
+fn workunit_messages_are_recorded_as_children() {
+  let ws = WorkunitStore::new(false, Level::Info);
+  ws._start_workunit(
+    SpanId(0),
+    "parent",
+    Level::Info,
+    None,
+    Some(WorkunitMetadata::default()),
+  );
+
+  // Messages below the level of the store are not recorded.
+  for (level, message) in [(Level::Info, "recorded"), (Level::Debug, "ignored")] {
+    ws.add_workunit_message(
+      "message",
+      level,
+      Some(SpanId(0)),
+      "parent".to_owned(),
+      message.to_owned(),
+    );
+  }
+
+  let (_, completed) = ws.latest_workunits(Level::Trace);
+  assert_eq!(completed.len(), 1);
+  assert_eq!(completed[0].name, "message");
+  assert_eq!(
+    completed[0].parent_ids,
+    vec![SpanId(0)].into_iter().collect::<ParentIds>()
+  );
+  let metadata = completed[0].metadata.clone().unwrap();
+  assert_eq!(metadata.desc, Some("parent".to_owned()));
+  assert_eq!(metadata.message, Some("recorded".to_owned()));
+}
+
 #[test]
 fn workunit_span_id_has_16_digits_len_hex_format() {
   let number: u64 = 1;